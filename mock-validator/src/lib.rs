@@ -0,0 +1,355 @@
+//! Scripted mock CometBFT validator (the privval endpoint the signers connect to)
+//! for the end-to-end integration tests of the providers: it accepts the signer's connection
+//! over TCP (secret connection), a Unix domain socket or vsock (e.g. the loopback),
+//! sends it the scripted requests and returns the signer's responses.
+
+use ed25519_consensus::SigningKey;
+use prost::Message as _;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::time::Duration;
+use tendermint::chain;
+use tendermint_proto::google::protobuf::Timestamp;
+use tendermint_proto::privval::{
+    message::Sum, Message as PrivMessage, PingRequest, PubKeyRequest, RemoteSignerError,
+    SignProposalRequest, SignVoteRequest,
+};
+use tendermint_proto::types::{BlockId, PartSetHeader, Proposal, SignedMsgType, Vote};
+use tmkms_light::connection::{secret_connection, Connection, PlainConnection, ProtocolVersion};
+use vsock::{VsockAddr, VsockListener};
+
+/// any local CID (e.g. the loopback one with `vsock_loopback`)
+const VMADDR_CID_ANY: u32 = 0xFFFFFFFF;
+
+/// the maximum message size (the data size of a secret connection frame)
+const MAX_MESSAGE_LEN: usize = 1024;
+
+/// code of the double-sign refusals
+const DOUBLE_SIGN_CODE: i32 = 2;
+
+/// kind of a vote request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoteType {
+    Prevote,
+    Precommit,
+}
+
+/// a request of the mock validator
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MockRequest {
+    Ping,
+    ShowPublicKey,
+    /// a vote for the block (derived from the byte) or `<nil>`
+    Vote {
+        vote_type: VoteType,
+        height: i64,
+        round: i32,
+        block: Option<u8>,
+    },
+    /// a proposal of the block (derived from the byte)
+    Proposal {
+        height: i64,
+        round: i32,
+        block: u8,
+    },
+}
+
+/// the signer's response to a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Pong,
+    PublicKey(Vec<u8>),
+    Signed(Vec<u8>),
+    Refused { code: i32, description: String },
+}
+
+impl Outcome {
+    /// the signer refused a double sign
+    pub fn is_double_sign(&self) -> bool {
+        matches!(self, Outcome::Refused { code, .. } if *code == DOUBLE_SIGN_CODE)
+    }
+
+    /// the signature (if signed)
+    pub fn signature(&self) -> Option<&[u8]> {
+        match self {
+            Outcome::Signed(signature) => Some(signature),
+            _ => None,
+        }
+    }
+}
+
+/// common scripts
+pub mod scripts {
+    use super::{MockRequest, VoteType};
+
+    /// a proposal and the prevote and precommit for it at the height
+    pub fn consensus_height(height: i64) -> Vec<MockRequest> {
+        vec![
+            MockRequest::Proposal {
+                height,
+                round: 0,
+                block: 1,
+            },
+            MockRequest::Vote {
+                vote_type: VoteType::Prevote,
+                height,
+                round: 0,
+                block: Some(1),
+            },
+            MockRequest::Vote {
+                vote_type: VoteType::Precommit,
+                height,
+                round: 0,
+                block: Some(1),
+            },
+        ]
+    }
+
+    /// a prevote followed by a conflicting prevote (for another block) at the same height
+    /// and round, which the signer must refuse
+    pub fn double_sign_probe(height: i64) -> Vec<MockRequest> {
+        let prevote = |block| MockRequest::Vote {
+            vote_type: VoteType::Prevote,
+            height,
+            round: 0,
+            block: Some(block),
+        };
+        vec![prevote(1), prevote(2)]
+    }
+}
+
+fn block_id(block: u8) -> BlockId {
+    BlockId {
+        hash: vec![block; 32],
+        part_set_header: Some(PartSetHeader {
+            total: 1,
+            hash: vec![block; 32],
+        }),
+    }
+}
+
+fn timestamp() -> Timestamp {
+    // a fixed time, so that the re-requested messages are identical
+    Timestamp {
+        seconds: 1_600_000_000,
+        nanos: 0,
+    }
+}
+
+/// the length-delimited privval message of the request
+pub fn encode_request(request: &MockRequest, chain_id: &chain::Id) -> Vec<u8> {
+    let sum = match request {
+        MockRequest::Ping => Sum::PingRequest(PingRequest {}),
+        MockRequest::ShowPublicKey => Sum::PubKeyRequest(PubKeyRequest {
+            chain_id: chain_id.to_string(),
+        }),
+        MockRequest::Vote {
+            vote_type,
+            height,
+            round,
+            block,
+        } => Sum::SignVoteRequest(SignVoteRequest {
+            vote: Some(Vote {
+                r#type: match vote_type {
+                    VoteType::Prevote => SignedMsgType::Prevote as i32,
+                    VoteType::Precommit => SignedMsgType::Precommit as i32,
+                },
+                height: *height,
+                round: *round,
+                block_id: block.map(block_id),
+                timestamp: Some(timestamp()),
+                validator_address: vec![0u8; 20],
+                validator_index: 0,
+                signature: vec![],
+            }),
+            chain_id: chain_id.to_string(),
+        }),
+        MockRequest::Proposal {
+            height,
+            round,
+            block,
+        } => Sum::SignProposalRequest(SignProposalRequest {
+            proposal: Some(Proposal {
+                r#type: SignedMsgType::Proposal as i32,
+                height: *height,
+                round: *round,
+                pol_round: -1,
+                block_id: Some(block_id(*block)),
+                timestamp: Some(timestamp()),
+                signature: vec![],
+            }),
+            chain_id: chain_id.to_string(),
+        }),
+    };
+    PrivMessage { sum: Some(sum) }.encode_length_delimited_to_vec()
+}
+
+/// reads a length-delimited privval message (in a single read, as the signers do,
+/// since a secret connection can't be read in smaller chunks than its frames)
+pub fn read_message<R: Read>(reader: &mut R) -> io::Result<PrivMessage> {
+    let mut buf = vec![0u8; MAX_MESSAGE_LEN];
+    let len = reader.read(&mut buf)?;
+    PrivMessage::decode_length_delimited(&buf[..len])
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn outcome(response: PrivMessage) -> io::Result<Outcome> {
+    let refused = |error: RemoteSignerError| Outcome::Refused {
+        code: error.code,
+        description: error.description,
+    };
+    let outcome = match response.sum {
+        Some(Sum::PingResponse(_)) => Outcome::Pong,
+        Some(Sum::PubKeyResponse(r)) => match (r.error, r.pub_key.and_then(|pk| pk.sum)) {
+            (Some(error), _) => refused(error),
+            (None, Some(tendermint_proto::crypto::public_key::Sum::Ed25519(pk))) => {
+                Outcome::PublicKey(pk)
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "no public key")),
+        },
+        Some(Sum::SignedVoteResponse(r)) => match (r.error, r.vote) {
+            (Some(error), _) => refused(error),
+            (None, Some(vote)) => Outcome::Signed(vote.signature),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "no vote")),
+        },
+        Some(Sum::SignedProposalResponse(r)) => match (r.error, r.proposal) {
+            (Some(error), _) => refused(error),
+            (None, Some(proposal)) => Outcome::Signed(proposal.signature),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "no proposal")),
+        },
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected response",
+            ))
+        }
+    };
+    Ok(outcome)
+}
+
+enum Listener {
+    Unix(UnixListener),
+    /// with the identity key of the secret connections
+    Tcp(TcpListener, Box<SigningKey>, ProtocolVersion),
+    Vsock(VsockListener),
+}
+
+/// the mock validator waiting for the signer's connections
+pub struct MockValidator {
+    listener: Listener,
+    chain_id: chain::Id,
+    timeout: Option<Duration>,
+}
+
+impl MockValidator {
+    fn new(listener: Listener, chain_id: chain::Id) -> Self {
+        Self {
+            listener,
+            chain_id,
+            timeout: Some(Duration::from_secs(10)),
+        }
+    }
+
+    /// listens on the Unix domain socket (plain connections)
+    pub fn unix<P: AsRef<Path>>(path: P, chain_id: chain::Id) -> io::Result<Self> {
+        Ok(Self::new(
+            Listener::Unix(UnixListener::bind(path)?),
+            chain_id,
+        ))
+    }
+
+    /// listens on the TCP address (secret connections with the identity key)
+    pub fn tcp(
+        addr: SocketAddr,
+        chain_id: chain::Id,
+        identity_key: SigningKey,
+        version: ProtocolVersion,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        Ok(Self::new(
+            Listener::Tcp(listener, Box::new(identity_key), version),
+            chain_id,
+        ))
+    }
+
+    /// listens on the vsock port of any local CID (plain connections)
+    pub fn vsock(port: u32, chain_id: chain::Id) -> io::Result<Self> {
+        let listener = VsockListener::bind(&VsockAddr::new(VMADDR_CID_ANY, port))?;
+        Ok(Self::new(Listener::Vsock(listener), chain_id))
+    }
+
+    /// read/write timeout of the accepted connections (10s by default)
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// the TCP address (e.g. when bound to port 0)
+    pub fn tcp_addr(&self) -> Option<SocketAddr> {
+        match &self.listener {
+            Listener::Tcp(listener, _, _) => listener.local_addr().ok(),
+            _ => None,
+        }
+    }
+
+    /// accepts the signer's connection (and completes the secret connection handshake)
+    pub fn accept(&self) -> io::Result<SignerConnection> {
+        let conn: Box<dyn Connection> = match &self.listener {
+            Listener::Unix(listener) => {
+                let (socket, _) = listener.accept()?;
+                socket.set_read_timeout(self.timeout)?;
+                socket.set_write_timeout(self.timeout)?;
+                Box::new(PlainConnection::new(socket))
+            }
+            Listener::Tcp(listener, identity_key, version) => {
+                let (socket, _) = listener.accept()?;
+                socket.set_read_timeout(self.timeout)?;
+                socket.set_write_timeout(self.timeout)?;
+                let conn = secret_connection(socket, identity_key, None, false, *version)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                Box::new(conn)
+            }
+            Listener::Vsock(listener) => {
+                let (socket, _) = listener.accept()?;
+                socket.set_read_timeout(self.timeout)?;
+                socket.set_write_timeout(self.timeout)?;
+                Box::new(PlainConnection::new(socket))
+            }
+        };
+        Ok(SignerConnection {
+            conn,
+            chain_id: self.chain_id.clone(),
+        })
+    }
+}
+
+/// a connected signer (dropping it disconnects the signer, e.g. to test its reconnection)
+pub struct SignerConnection {
+    conn: Box<dyn Connection>,
+    chain_id: chain::Id,
+}
+
+impl SignerConnection {
+    /// sends the request and waits for the signer's response
+    pub fn send(&mut self, request: &MockRequest) -> io::Result<Outcome> {
+        self.conn
+            .write_all(&encode_request(request, &self.chain_id))?;
+        outcome(read_message(&mut self.conn)?)
+    }
+
+    /// sends the requests in order (stops at the first connection error)
+    pub fn run(&mut self, script: &[MockRequest]) -> io::Result<Vec<Outcome>> {
+        script.iter().map(|request| self.send(request)).collect()
+    }
+
+    /// sends a request for another chain
+    pub fn send_for_chain(
+        &mut self,
+        request: &MockRequest,
+        chain_id: &chain::Id,
+    ) -> io::Result<Outcome> {
+        self.conn.write_all(&encode_request(request, chain_id))?;
+        outcome(read_message(&mut self.conn)?)
+    }
+}
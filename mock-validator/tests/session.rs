@@ -0,0 +1,506 @@
+//! end-to-end tests of the signing session against the mock validator
+
+use ed25519_consensus::{Signature, SigningKey};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tendermint::chain;
+use tmkms_light::approval::{ApprovalRequest, ApprovalToken, Approvals, Approver};
+use tmkms_light::audit::{AuditSink, RefusedMessage, SignedMessage};
+use tmkms_light::chain::state::{consensus, PersistStateSync, State, StateError};
+use tmkms_light::config::validator::ValidatorConfig;
+use tmkms_light::connection::{secret_connection, Connection, PlainConnection, ProtocolVersion};
+use tmkms_light::error::Error;
+use tmkms_light::policy::{ApprovalPolicy, SigningPolicy};
+use tmkms_light::session::Session;
+use tmkms_light_mock_validator::{scripts, MockRequest, MockValidator, Outcome, VoteType};
+
+struct NoopSync;
+
+impl PersistStateSync for NoopSync {
+    fn load_state(&mut self) -> Result<State, StateError> {
+        Ok(State::from(consensus::State::default()))
+    }
+
+    fn persist_state(&mut self, _new_state: &consensus::State) -> Result<(), StateError> {
+        Ok(())
+    }
+}
+
+fn chain_id() -> chain::Id {
+    "testchain-1".parse().unwrap()
+}
+
+/// runs a signer that (re)connects with `connect` after every session error
+fn spawn_signer<F>(signing_key: SigningKey, connect: F)
+where
+    F: Fn() -> Option<Box<dyn Connection>> + Send + 'static,
+{
+    thread::spawn(move || {
+        let config = ValidatorConfig {
+            chain_id: chain_id(),
+            max_height: None,
+            signing_policy: Default::default(),
+        };
+        let mut session = Session::new(
+            config,
+            connect().expect("first connection"),
+            signing_key,
+            State::from(consensus::State::default()),
+            NoopSync,
+        );
+        while session.request_loop().is_err() {
+            match connect() {
+                Some(conn) => session.reset_connection(conn),
+                None => return,
+            }
+        }
+    });
+}
+
+fn socket_path() -> PathBuf {
+    std::env::temp_dir().join(format!("mock-validator-{}.sock", std::process::id()))
+}
+
+#[test]
+fn unix_signer_signs_refuses_double_signs_and_reconnects() {
+    let path = socket_path();
+    let _ = std::fs::remove_file(&path);
+    let validator = MockValidator::unix(&path, chain_id()).unwrap();
+    let signing_key = SigningKey::from([7u8; 32]);
+    let public_key = signing_key.verification_key().to_bytes().to_vec();
+    let signer_path = path.clone();
+    spawn_signer(signing_key, move || {
+        let socket = UnixStream::connect(&signer_path).ok()?;
+        socket
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .ok()?;
+        Some(Box::new(PlainConnection::new(socket)))
+    });
+
+    let mut conn = validator.accept().unwrap();
+    assert_eq!(conn.send(&MockRequest::Ping).unwrap(), Outcome::Pong);
+    assert_eq!(
+        conn.send(&MockRequest::ShowPublicKey).unwrap(),
+        Outcome::PublicKey(public_key)
+    );
+    let outcomes = conn.run(&scripts::consensus_height(2)).unwrap();
+    assert!(outcomes.iter().all(|o| o.signature().is_some()));
+
+    let outcomes = conn.run(&scripts::double_sign_probe(3)).unwrap();
+    let signature = outcomes[0].signature().unwrap().to_vec();
+    assert!(outcomes[1].is_double_sign());
+
+    let wrong_chain = conn
+        .send_for_chain(&MockRequest::Ping, &"otherchain-1".parse().unwrap())
+        .unwrap();
+    assert_eq!(wrong_chain, Outcome::Pong);
+
+    // the signer reconnects and returns the same signature for the re-requested prevote
+    drop(conn);
+    let mut conn = validator.accept().unwrap();
+    let outcome = conn
+        .send(&MockRequest::Vote {
+            vote_type: VoteType::Prevote,
+            height: 3,
+            round: 0,
+            block: Some(1),
+        })
+        .unwrap();
+    assert_eq!(outcome.signature(), Some(signature.as_slice()));
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn tcp_signer_completes_secret_connection() {
+    let validator = MockValidator::tcp(
+        "127.0.0.1:0".parse().unwrap(),
+        chain_id(),
+        SigningKey::from([1u8; 32]),
+        ProtocolVersion::V0_34,
+    )
+    .unwrap();
+    let addr = validator.tcp_addr().unwrap();
+    spawn_signer(SigningKey::from([7u8; 32]), move || {
+        let socket = TcpStream::connect(addr).ok()?;
+        let identity_key = SigningKey::from([2u8; 32]);
+        let conn =
+            secret_connection(socket, &identity_key, None, false, ProtocolVersion::V0_34).ok()?;
+        Some(Box::new(conn))
+    });
+
+    let mut conn = validator.accept().unwrap();
+    let outcomes = conn.run(&scripts::consensus_height(2)).unwrap();
+    assert!(outcomes.iter().all(|o| o.signature().is_some()));
+    let wrong_chain = conn
+        .send_for_chain(
+            &MockRequest::Vote {
+                vote_type: VoteType::Prevote,
+                height: 3,
+                round: 0,
+                block: None,
+            },
+            &"otherchain-1".parse().unwrap(),
+        )
+        .unwrap();
+    assert!(matches!(wrong_chain, Outcome::Refused { code: 1, .. }));
+}
+
+/// fails the test if the dry-run signer persists a state
+struct UnreachableSync;
+
+impl PersistStateSync for UnreachableSync {
+    fn load_state(&mut self) -> Result<State, StateError> {
+        Ok(State::from(consensus::State::default()))
+    }
+
+    fn persist_state(&mut self, _new_state: &consensus::State) -> Result<(), StateError> {
+        panic!("the dry-run signer persisted a state");
+    }
+}
+
+#[test]
+fn dry_run_signer_refuses_and_still_detects_double_signs() {
+    let path = std::env::temp_dir().join(format!("mock-validator-dry-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let validator = MockValidator::unix(&path, chain_id()).unwrap();
+    let signer_path = path.clone();
+    thread::spawn(move || {
+        let socket = UnixStream::connect(signer_path).unwrap();
+        let config = ValidatorConfig {
+            chain_id: chain_id(),
+            max_height: None,
+            signing_policy: Default::default(),
+        };
+        let mut session = Session::new(
+            config,
+            Box::new(PlainConnection::new(socket)),
+            SigningKey::from([7u8; 32]),
+            State::from(consensus::State::default()),
+            UnreachableSync,
+        );
+        session.set_dry_run(true);
+        let _ = session.request_loop();
+    });
+
+    let mut conn = validator.accept().unwrap();
+    assert_eq!(conn.send(&MockRequest::Ping).unwrap(), Outcome::Pong);
+    let outcomes = conn.run(&scripts::double_sign_probe(3)).unwrap();
+    assert!(
+        matches!(&outcomes[0], Outcome::Refused { description, .. } if description.contains("dry run"))
+    );
+    assert!(outcomes[1].is_double_sign());
+    let _ = std::fs::remove_file(&path);
+}
+
+/// sends the recorded refusals to the test
+struct RefusalSink(mpsc::Sender<RefusedMessage>);
+
+impl AuditSink for RefusalSink {
+    fn record(&mut self, _msg: &SignedMessage) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn record_refusal(&mut self, msg: &RefusedMessage) -> Result<(), Error> {
+        let _ = self.0.send(msg.clone());
+        Ok(())
+    }
+}
+
+#[test]
+fn refused_sign_bytes_are_recorded() {
+    let path =
+        std::env::temp_dir().join(format!("mock-validator-dump-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let validator = MockValidator::unix(&path, chain_id()).unwrap();
+    let signer_path = path.clone();
+    let (sender, refusals) = mpsc::channel();
+    let signing_key = SigningKey::from([7u8; 32]);
+    let verification_key = signing_key.verification_key();
+    thread::spawn(move || {
+        let socket = UnixStream::connect(signer_path).unwrap();
+        let config = ValidatorConfig {
+            chain_id: chain_id(),
+            max_height: None,
+            signing_policy: Default::default(),
+        };
+        let mut session = Session::new(
+            config,
+            Box::new(PlainConnection::new(socket)),
+            signing_key,
+            State::from(consensus::State::default()),
+            NoopSync,
+        );
+        session.set_audit_sink(Box::new(RefusalSink(sender)));
+        session.set_dump_refused_sign_bytes(true);
+        let _ = session.request_loop();
+    });
+
+    let mut conn = validator.accept().unwrap();
+    let outcomes = conn.run(&scripts::double_sign_probe(3)).unwrap();
+    assert!(outcomes[1].is_double_sign());
+    let refused = refusals.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(refused.height, 3);
+    assert!(refused.reason.contains("double signing"));
+    // the sign-bytes of the conflicting prevote (not the signed one)
+    let signature = Signature::try_from(outcomes[0].signature().unwrap()).unwrap();
+    assert!(verification_key
+        .verify(&signature, &refused.sign_bytes)
+        .is_err());
+    assert!(refusals.try_recv().is_err());
+    let _ = std::fs::remove_file(&path);
+}
+
+/// fails the first signature's audit and sends the recorded signatures to the test
+struct FlakyAuditSink {
+    failed: bool,
+    records: mpsc::Sender<SignedMessage>,
+}
+
+impl AuditSink for FlakyAuditSink {
+    fn record(&mut self, msg: &SignedMessage) -> Result<(), Error> {
+        if !self.failed {
+            self.failed = true;
+            return Err(Error::io_error(
+                "audit log unreachable".into(),
+                std::io::Error::from(std::io::ErrorKind::BrokenPipe),
+            ));
+        }
+        let _ = self.records.send(msg.clone());
+        Ok(())
+    }
+
+    fn record_refusal(&mut self, _msg: &RefusedMessage) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[test]
+fn rerequested_signatures_are_audited() {
+    let path =
+        std::env::temp_dir().join(format!("mock-validator-audit-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let validator = MockValidator::unix(&path, chain_id()).unwrap();
+    let signer_path = path.clone();
+    let (sender, records) = mpsc::channel();
+    thread::spawn(move || {
+        let connect = || -> Box<dyn Connection> {
+            Box::new(PlainConnection::new(
+                UnixStream::connect(&signer_path).unwrap(),
+            ))
+        };
+        let config = ValidatorConfig {
+            chain_id: chain_id(),
+            max_height: None,
+            signing_policy: Default::default(),
+        };
+        let mut session = Session::new(
+            config,
+            connect(),
+            SigningKey::from([7u8; 32]),
+            State::from(consensus::State::default()),
+            NoopSync,
+        );
+        session.set_audit_sink(Box::new(FlakyAuditSink {
+            failed: false,
+            records: sender,
+        }));
+        while session.request_loop().is_err() {
+            session.reset_connection(connect());
+        }
+    });
+
+    let prevote = MockRequest::Vote {
+        vote_type: VoteType::Prevote,
+        height: 3,
+        round: 0,
+        block: Some(1),
+    };
+    // the audit fails: no signature is released
+    let mut conn = validator.accept().unwrap();
+    assert!(conn.send(&prevote).is_err());
+    // the re-requested prevote isn't answered from the cache without an audit record
+    let mut conn = validator.accept().unwrap();
+    let outcome = conn.send(&prevote).unwrap();
+    let record = records.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(record.height, 3);
+    assert_eq!(Some(record.signature.as_slice()), outcome.signature());
+    // once audited, it is
+    assert_eq!(conn.send(&prevote).unwrap(), outcome);
+    assert!(records.try_recv().is_err());
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn height_jumps_are_refused_unless_allowed() {
+    let path =
+        std::env::temp_dir().join(format!("mock-validator-jump-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let validator = MockValidator::unix(&path, chain_id()).unwrap();
+    let signer_path = path.clone();
+    let (sender, controls) = mpsc::channel();
+    thread::spawn(move || {
+        let socket = UnixStream::connect(signer_path).unwrap();
+        let config = ValidatorConfig {
+            chain_id: chain_id(),
+            max_height: None,
+            signing_policy: SigningPolicy {
+                max_height_jump: Some(10),
+                ..Default::default()
+            },
+        };
+        let mut session = Session::new(
+            config,
+            Box::new(PlainConnection::new(socket)),
+            SigningKey::from([7u8; 32]),
+            State::from(consensus::State::default()),
+            NoopSync,
+        );
+        sender.send(session.control()).unwrap();
+        let _ = session.request_loop();
+    });
+    let control = controls.recv_timeout(Duration::from_secs(10)).unwrap();
+
+    let prevote = |height| MockRequest::Vote {
+        vote_type: VoteType::Prevote,
+        height,
+        round: 0,
+        block: Some(1),
+    };
+    let mut conn = validator.accept().unwrap();
+    // a fresh state isn't checked
+    assert!(conn.send(&prevote(5)).unwrap().signature().is_some());
+    assert!(matches!(
+        conn.send(&prevote(100)).unwrap(),
+        Outcome::Refused { description, .. } if description.contains("height jump")
+    ));
+    control.allow_next_height_jump();
+    assert!(conn.send(&prevote(100)).unwrap().signature().is_some());
+    assert!(conn.send(&prevote(110)).unwrap().signature().is_some());
+    assert!(conn.send(&prevote(200)).unwrap().signature().is_none());
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn committed_heights_are_not_signed() {
+    let path = std::env::temp_dir().join(format!(
+        "mock-validator-committed-{}.sock",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let validator = MockValidator::unix(&path, chain_id()).unwrap();
+    let signer_path = path.clone();
+    thread::spawn(move || {
+        let socket = UnixStream::connect(signer_path).unwrap();
+        let config = ValidatorConfig {
+            chain_id: chain_id(),
+            max_height: None,
+            signing_policy: Default::default(),
+        };
+        let mut session = Session::new(
+            config,
+            Box::new(PlainConnection::new(socket)),
+            SigningKey::from([7u8; 32]),
+            State::from(consensus::State::default()),
+            NoopSync,
+        );
+        session.set_committed_height(20);
+        let _ = session.request_loop();
+    });
+
+    let mut conn = validator.accept().unwrap();
+    let prevote = |height| MockRequest::Vote {
+        vote_type: VoteType::Prevote,
+        height,
+        round: 0,
+        block: Some(1),
+    };
+    // the stale state would allow it, but the chain already committed it
+    assert!(matches!(
+        conn.send(&prevote(20)).unwrap(),
+        Outcome::Refused { description, .. } if description.contains("committed height")
+    ));
+    assert!(conn.send(&prevote(21)).unwrap().signature().is_some());
+    let _ = std::fs::remove_file(&path);
+}
+
+/// approves the requests it hasn't approved a conflicting message for (as the approval service)
+struct TestApprover {
+    key: SigningKey,
+    approvals: Approvals,
+}
+
+impl Approver for TestApprover {
+    fn request_approval(&mut self, request: &ApprovalRequest) -> Result<ApprovalToken, String> {
+        self.approvals.approve(request)?;
+        Ok(ApprovalToken::sign(&self.key, request))
+    }
+}
+
+#[test]
+fn sensitive_signatures_need_the_approval() {
+    let path = std::env::temp_dir().join(format!(
+        "mock-validator-approval-{}.sock",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let validator = MockValidator::unix(&path, chain_id()).unwrap();
+    let signer_path = path.clone();
+    thread::spawn(move || {
+        let socket = UnixStream::connect(signer_path).unwrap();
+        let config = ValidatorConfig {
+            chain_id: chain_id(),
+            max_height: None,
+            signing_policy: SigningPolicy {
+                approval: Some(ApprovalPolicy {
+                    min_height: Some(10),
+                    proposals: true,
+                }),
+                ..Default::default()
+            },
+        };
+        let mut session = Session::new(
+            config,
+            Box::new(PlainConnection::new(socket)),
+            SigningKey::from([7u8; 32]),
+            State::from(consensus::State::default()),
+            NoopSync,
+        );
+        let approver_key = SigningKey::from([9u8; 32]);
+        let verification_key = approver_key.verification_key();
+        session.set_approver(
+            Box::new(TestApprover {
+                key: approver_key,
+                approvals: Approvals::default(),
+            }),
+            verification_key,
+        );
+        let _ = session.request_loop();
+    });
+
+    let mut conn = validator.accept().unwrap();
+    let proposal = |block| MockRequest::Proposal {
+        height: 6,
+        round: 0,
+        block,
+    };
+    let prevote = |height| MockRequest::Vote {
+        vote_type: VoteType::Prevote,
+        height,
+        round: 0,
+        block: Some(1),
+    };
+    assert!(conn.send(&proposal(1)).unwrap().signature().is_some());
+    // the approver refuses the conflicting proposal (before the signer's own check)
+    assert!(matches!(
+        conn.send(&proposal(2)).unwrap(),
+        Outcome::Refused { description, .. } if description.contains("not approved")
+    ));
+    // below `min_height`, the votes aren't sent to the approver
+    assert!(conn.send(&prevote(8)).unwrap().signature().is_some());
+    assert!(conn.send(&prevote(10)).unwrap().signature().is_some());
+    let _ = std::fs::remove_file(&path);
+}
@@ -2,16 +2,61 @@ use tracing::Level;
 use tracing::{error, info, warn};
 use tracing_subscriber::fmt;
 use tracing_subscriber::layer::SubscriberExt;
-use vsock::{VsockAddr, VsockListener};
+use tracing_subscriber::Layer as _;
 
-use tmkms_nitro_helper::tracing_layer::Layer;
+use std::path::PathBuf;
+use tmkms_nitro_helper::channel::{enable_dev_tcp, PortListener};
+use tmkms_nitro_helper::enclave_policy::DEFAULT_POLICY_PATH;
+use tmkms_nitro_helper::span_export::{SpanLayer, VsockSpanSink};
+use tmkms_nitro_helper::tracing_layer::{level_override, Layer};
 use tmkms_nitro_helper::VSOCK_HOST_CID;
-use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::filter::{self, LevelFilter};
 
 mod nitro;
 
 fn main() {
-    let mut env_args = std::env::args();
+    // `--dev-plaintext` runs the signer outside an enclave: the channels are TCP connections
+    // on the loopback interface and the keys aren't sealed (see `nitro::platform`)
+    let dev_plaintext = std::env::args().any(|x| x == "--dev-plaintext");
+    // `--policy <path>` reads the enclave policy from another file in the image
+    // (the command line is in the image as well, so it's measured like the policy)
+    let mut args: Vec<String> = std::env::args()
+        .filter(|x| x != "--dev-plaintext")
+        .collect();
+    let policy_path = match args.iter().position(|x| x == "--policy") {
+        Some(i) if i + 1 < args.len() => {
+            let path = args.remove(i + 1);
+            args.remove(i);
+            PathBuf::from(path)
+        }
+        Some(_) => {
+            eprintln!("`--policy` requires a path");
+            std::process::exit(1);
+        }
+        None => PathBuf::from(DEFAULT_POLICY_PATH),
+    };
+    // `--log-format <text|json>`: `json` for structured console output
+    // (the logs forwarded to the host keep their fields in either case)
+    let json_format = match args.iter().position(|x| x == "--log-format") {
+        Some(i) if i + 1 < args.len() => {
+            let format = args.remove(i + 1);
+            args.remove(i);
+            match format.as_str() {
+                "text" => false,
+                "json" => true,
+                _ => {
+                    eprintln!("unknown log format `{}` (`text` or `json`)", format);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(_) => {
+            eprintln!("`--log-format` requires `text` or `json`");
+            std::process::exit(1);
+        }
+        None => false,
+    };
+    let mut env_args = args.into_iter();
     let port = env_args
         .next()
         .and_then(|x| x.parse::<u32>().ok())
@@ -32,31 +77,71 @@ fn main() {
             }
         })
         .unwrap_or_else(|| Level::INFO);
-    let log_layer = LevelFilter::from(log_level);
+    if dev_plaintext {
+        enable_dev_tcp();
+        nitro::platform::enable_dev_plaintext();
+    } else if nitro::platform::dev_plaintext() {
+        eprintln!("built without the `nsm` feature, it can only run with `--dev-plaintext`");
+        std::process::exit(1);
+    }
+    // the console output is filtered with the enclave's level;
+    // the logs are forwarded to the helper at the level it logs (which it sends when connecting);
+    // a level set at runtime (`SetLogLevel`) applies to both
+    let startup_level = LevelFilter::from(log_level);
+    let log_layer =
+        filter::filter_fn(move |meta| *meta.level() <= level_override().unwrap_or(startup_level));
     let layer = Layer::new(VSOCK_HOST_CID, log_server_port);
-    let fmt_layer = fmt::layer().with_target(false);
+    let (fmt_layer, json_layer) = if json_format {
+        (
+            None,
+            Some(
+                fmt::layer()
+                    .json()
+                    .flatten_event(true)
+                    .with_filter(log_layer),
+            ),
+        )
+    } else {
+        (
+            Some(fmt::layer().with_target(false).with_filter(log_layer)),
+            None,
+        )
+    };
     let layered = tracing_subscriber::registry()
-        .with(log_layer)
         .with(fmt_layer)
-        .with(layer);
+        .with(json_layer)
+        .with(layer)
+        .with(SpanLayer::new(VsockSpanSink::default()));
 
     tracing::subscriber::set_global_default(layered).expect("setting default subscriber failed");
 
+    if dev_plaintext {
+        warn!("development mode: the keys are NOT sealed and the attestations are NOT signed");
+    }
+    if let Err(e) = nitro::policy::load(&policy_path) {
+        error!("failed to load the enclave policy: {}", e);
+        std::process::exit(1);
+    }
     const VMADDR_CID_ANY: u32 = 0xFFFFFFFF;
-    let addr = VsockAddr::new(VMADDR_CID_ANY, port);
-    let listener = VsockListener::bind(&addr).expect("bind address");
+    let listener = PortListener::bind(VMADDR_CID_ANY, port).expect("bind address");
+    let addr = listener.local_addr().expect("bound address");
     info!("waiting for config to be pushed on {}", addr);
-    for conn in listener.incoming() {
-        if aws_ne_sys::seed_entropy(512).is_err() {
+    loop {
+        let conn = listener.accept();
+        if nitro::platform::seed_entropy(512).is_err() {
             error!("failed to seed initial entropy!");
             std::process::exit(1);
         }
         match conn {
-            Ok(stream) => {
-                info!("got connection on {:?}", addr);
-                if let Err(e) = nitro::entry(stream) {
-                    error!("io error {}", e);
-                }
+            Ok((stream, _)) => {
+                info!("got connection on {}", addr);
+                // each connection is served in its own thread,
+                // so that a running signer doesn't block other requests
+                std::thread::spawn(move || {
+                    if let Err(e) = nitro::entry(stream) {
+                        error!("io error {}", e);
+                    }
+                });
             }
             Err(e) => {
                 warn!("connection error {}", e);
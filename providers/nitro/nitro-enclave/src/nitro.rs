@@ -1,191 +1,1057 @@
+/// runtime attestation helper
+mod attestation;
+/// signature audit helper
+mod audit;
+/// start config encrypted to an attested one-time key
+mod config_push;
+/// authentication of the helper's requests
+mod control;
+/// AWS credentials refreshed by the helper
+mod credentials;
+/// metrics events helper
+mod metrics;
+/// NSM and AWS KMS calls (or their development mode stand-ins)
+pub mod platform;
+/// what the enclave image allows to be done with the consensus key
+pub mod policy;
+/// one-time provisioning of the consensus key from a mnemonic or backup shares
+mod provisioning;
+/// registry of the running chain sessions
+mod sessions;
 /// state persistence helper;
 mod state;
+/// clock synchronized from the host
+mod time_sync;
 
 use aws_nitro_enclaves_nsm_api::api::{Request, Response};
-use aws_nitro_enclaves_nsm_api::driver::{nsm_exit, nsm_init, nsm_process_request};
 use ed25519_consensus as ed25519;
 use ed25519_consensus::SigningKey;
-use rand_core::OsRng;
+use rand_core::{OsRng, RngCore};
+use serde::Serialize;
 use serde_bytes::ByteBuf;
 use std::io;
 use std::os::unix::io::AsRawFd;
+use std::sync::mpsc;
 use std::thread;
-use std::time::Duration;
-use subtle::ConstantTimeEq;
-use tendermint::node::Id;
-use tendermint_p2p::secret_connection::{self, PublicKey, SecretConnection};
-use tmkms_light::chain::state::PersistStateSync;
+use std::time::{Duration, Instant};
+use tendermint_p2p::secret_connection::PublicKey;
+use tmkms_light::approval::RemoteApprover;
+use tmkms_light::chain::state::{
+    AntiRollbackStateSync, DualStateSync, LeaseStateSync, PersistStateSync, StateMacKey,
+    WatermarkStateSync,
+};
 use tmkms_light::config::validator::ValidatorConfig;
-use tmkms_light::connection::{Connection, PlainConnection};
-use tmkms_light::error::{io_error_wrap, Error};
-use tmkms_light::utils::{read_u16_payload, write_u16_payload};
+use tmkms_light::connection::{self, noise, tls, Connection, PlainConnection, Transport};
+use tmkms_light::error::{io_error_wrap, Error, ErrorDetail};
+use tmkms_light::possession::sign_proof_of_possession;
+use tmkms_light::session::{SessionControl, SessionStatus};
+use tmkms_light::utils::{read_u16_payload_with_limits, write_u16_payload};
+use tmkms_nitro_helper::backoff::Backoff;
+use tmkms_nitro_helper::backup::{split_secret, KeyBackup};
+use tmkms_nitro_helper::channel::ChannelStream;
+use tmkms_nitro_helper::codec;
+use tmkms_nitro_helper::entropy::{mix_entropy, EntropySource};
+use tmkms_nitro_helper::key_shares::split_signing_key;
+use tmkms_nitro_helper::mux::connect_channel;
+use tmkms_nitro_helper::slip10::{derive_ed25519, DerivationPath};
+use tmkms_nitro_helper::span_export::enable_span_relay;
+use tmkms_nitro_helper::tracing_layer::set_level_override;
 use tmkms_nitro_helper::{
-    NitroConfig, NitroKeygenResponse, NitroRequest, NitroResponse, VSOCK_HOST_CID,
+    NitroAttestResult, NitroBackupConfig, NitroBackupResponse, NitroBackupResult,
+    NitroChainStatusResult, NitroConfig, NitroDeriveConfig, NitroDeriveResponse, NitroDeriveResult,
+    NitroError, NitroErrorCode, NitroHello, NitroHelloResult, NitroKeySharesConfig,
+    NitroKeySharesResponse, NitroKeySharesResult, NitroKeygenResponse, NitroMigrateStateClaim,
+    NitroMigrateStateConfig, NitroMigrateStateResponse, NitroMigrateStateResult, NitroRequest,
+    NitroResponse, NitroRewrapClaim, NitroRewrapConfig, NitroRewrapResponse, NitroRewrapResult,
+    NitroSetLogLevelResult, NitroShutdownResult, NitroSignPayloadConfig, NitroSignPayloadResponse,
+    NitroSignPayloadResult, ValidatorConn, PROTOCOL_VERSION,
 };
-use tracing::{error, info, trace, warn};
-use vsock::{VsockAddr, VsockStream};
+use tracing::level_filters::LevelFilter;
+use tracing::{debug, error, info, info_span, trace, warn};
 use zeroize::{Zeroize, Zeroizing};
 
+use platform::{nsm_exit, nsm_init, nsm_process_request};
+
+/// how long the helper's request can take to arrive
+const REQUEST_READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// how long a control connection can stay idle between the helper's requests
+const CONTROL_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+/// how long the shutdown waits for the sessions to stop
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+/// how often a session waiting to reconnect checks whether it was stopped
+/// (well within `SHUTDOWN_TIMEOUT`)
+const RECONNECT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// how long the started session can take to load the consensus key and the state
+const STARTUP_ATTESTATION_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// the startup attestation requested with `StartSealed` (sent back on its connection)
+struct StartupAttestation {
+    nonce: Vec<u8>,
+    response: mpsc::Sender<NitroAttestResult>,
+}
+
+/// connects to the vsock port of the validator connection (with the configured timeouts)
+fn connect_tendermint_vsock(config: &NitroConfig, vsock_port: u32) -> io::Result<ChannelStream> {
+    let socket = connect_channel(config.enclave_mux_port, vsock_port)?;
+    socket.set_read_timeout(config.timeouts.read())?;
+    socket.set_write_timeout(config.timeouts.write())?;
+    Ok(socket)
+}
+
 fn get_secret_connection(
-    vsock_port: u32,
+    config: &NitroConfig,
+    validator: &ValidatorConn,
     identity_key: &ed25519::SigningKey,
-    peer_id: Option<Id>,
 ) -> io::Result<Box<dyn Connection>> {
-    let addr = VsockAddr::new(VSOCK_HOST_CID, vsock_port);
-    let socket = vsock::VsockStream::connect(&addr)?;
+    let vsock_port = validator.enclave_tendermint_conn;
+    let socket = connect_tendermint_vsock(config, vsock_port)?;
     info!("KMS node ID: {}", PublicKey::from(identity_key));
-    let identity_key = identity_key.clone();
-    let connection = SecretConnection::new(socket, identity_key, secret_connection::Version::V0_34)
-        .map_err(|e| {
-            error!("secret connection failed: {}", e);
-            io::Error::from(io::ErrorKind::Other)
-        })?;
-    let actual_peer_id = connection.remote_pubkey().peer_id();
-
-    // TODO: https://github.com/informalsystems/tendermint-rs/issues/786
-    if let Some(expected_peer_id) = peer_id {
-        if expected_peer_id.ct_eq(&actual_peer_id).unwrap_u8() == 0 {
-            error!(
-                "(vsock port {}): validator peer ID mismatch! (expected {}, got {})",
-                vsock_port, expected_peer_id, actual_peer_id
+    let connection: Result<Box<dyn Connection>, Error> = match config.transport {
+        Transport::SecretConnection => connection::secret_connection(
+            socket,
+            identity_key,
+            validator.peer_id,
+            config.require_peer_id,
+            config.protocol_version,
+        )
+        .map(|c| Box::new(c) as Box<dyn Connection>),
+        Transport::Noise => {
+            info!(
+                "KMS noise public key: {}",
+                String::from_utf8(subtle_encoding::base64::encode(noise::static_public_key(
+                    identity_key
+                )))
+                .unwrap()
             );
-            return Err(io::Error::from(io::ErrorKind::Other));
+            noise::noise_connection(
+                socket,
+                identity_key,
+                config.noise_remote_key.as_deref(),
+                config.require_peer_id,
+            )
+            .map(|c| Box::new(c) as Box<dyn Connection>)
         }
-    }
+        // the helper sets the server name (to the validator host by default)
+        Transport::Tls => match &config.tls {
+            Some(credentials) => tls::tls_connection(
+                socket,
+                credentials,
+                credentials.server_name.as_deref().unwrap_or_default(),
+            )
+            .map(|c| Box::new(c) as Box<dyn Connection>),
+            None => Err(Error::invalid_tls_config_error(
+                "missing TLS credentials".into(),
+            )),
+        },
+    };
+    let connection = connection.map_err(|e| {
+        error!("(vsock port {}): {}", vsock_port, e);
+        io::Error::from(io::ErrorKind::Other)
+    })?;
     info!("connected to validator successfully");
 
-    if peer_id.is_none() {
-        // TODO: https://github.com/informalsystems/tendermint-rs/issues/786
-        warn!(
-            "unverified validator peer ID! ({})",
-            connection.remote_pubkey().peer_id()
-        );
+    Ok(connection)
+}
+
+/// the primary validator connection followed by the failover ones
+fn validator_conns(config: &NitroConfig) -> impl Iterator<Item = ValidatorConn> + '_ {
+    std::iter::once(ValidatorConn {
+        enclave_tendermint_conn: config.enclave_tendermint_conn,
+        peer_id: config.peer_id,
+        allowed_msg_types: config.validator_msg_types.clone(),
+    })
+    .chain(config.failover_conns.iter().cloned())
+}
+
+fn connect(
+    config: &NitroConfig,
+    validator: &ValidatorConn,
+    id_keypair: Option<&ed25519::SigningKey>,
+) -> io::Result<Box<dyn Connection>> {
+    if let Some(ikp) = id_keypair {
+        return get_secret_connection(config, validator, ikp);
+    }
+    match connect_tendermint_vsock(config, validator.enclave_tendermint_conn) {
+        Ok(socket) => {
+            trace!(
+                "tendermint vsock port: {}",
+                validator.enclave_tendermint_conn
+            );
+            trace!("tendermint peer addr: {:?}", socket.peer_addr());
+            trace!("tendermint local addr: {:?}", socket.local_addr());
+            trace!("tendermint fd: {}", socket.as_raw_fd());
+            info!("connected to validator successfully");
+            let plain_conn = PlainConnection::new(socket);
+            Ok(Box::new(plain_conn))
+        }
+        Err(e) => {
+            warn!("vsock failed to connect to validator");
+            Err(e)
+        }
     }
+}
 
-    Ok(Box::new(connection))
+/// whether the (registered) session was stopped, e.g. by the operator or the enclave's shutdown
+fn is_stopped(control: Option<&SessionControl>) -> bool {
+    control.map_or(false, |control| control.status() == SessionStatus::Stopped)
 }
 
-/// keeps retrying with approx. 1 sec sleep until it manages to connect to tendermint privval endpoint
+/// keeps retrying with the configured backoff until it manages to connect to tendermint privval endpoint
+/// (`None` if the retry budget is exhausted or the session's `control` is stopped while waiting;
+/// there's none before the session is registered); `on_alert` is called when the alert threshold is reached.
+/// Each attempt tries the primary validator connection first and then the failover ones in order
+/// (the session's state is the same whichever validator is connected, so it can't double sign);
+/// returns the connection with the validator it's to
 pub fn get_connection(
     config: &NitroConfig,
     id_keypair: Option<&ed25519::SigningKey>,
-) -> Box<dyn Connection> {
+    backoff: &mut Backoff,
+    on_alert: &dyn Fn(u32),
+    control: Option<&SessionControl>,
+) -> Option<(Box<dyn Connection>, ValidatorConn)> {
     loop {
-        let conn: io::Result<Box<dyn Connection>> = if let Some(ikp) = id_keypair {
-            get_secret_connection(config.enclave_tendermint_conn, ikp, config.peer_id)
+        if is_stopped(control) {
+            return None;
+        }
+        for validator in validator_conns(config) {
+            match connect(config, &validator, id_keypair) {
+                Ok(conn) => {
+                    backoff.reset();
+                    return Some((conn, validator));
+                }
+                Err(e) => {
+                    error!(
+                        "tendermint connection error (vsock port {}) {:?}",
+                        validator.enclave_tendermint_conn, e
+                    );
+                }
+            }
+        }
+        let delay = backoff.next_delay(&mut OsRng)?;
+        if backoff.should_alert() {
+            on_alert(backoff.failures());
+        }
+        // in short slices, so a stop or shutdown doesn't wait for the (up to minutes long) delay
+        let retry_at = Instant::now() + delay;
+        loop {
+            if is_stopped(control) {
+                return None;
+            }
+            let now = Instant::now();
+            if now >= retry_at {
+                break;
+            }
+            thread::sleep((retry_at - now).min(RECONNECT_POLL_INTERVAL));
+        }
+    }
+}
+
+/// decrypts the AWS KMS-encrypted secret (with the latest credentials)
+/// in `aws_region` or, if that fails, in the regions of the multi-Region key replicas
+fn decrypt_secret(
+    aws_region: &str,
+    failover_regions: &[String],
+    ciphertext: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, Error> {
+    let credentials = credentials::current().ok_or_else(Error::access_error)?;
+    let regions = std::iter::once(aws_region).chain(failover_regions.iter().map(String::as_str));
+    let mut last_error = None;
+    let mut unavailable = false;
+    for region in regions {
+        let _span = info_span!("kms_decrypt", region).entered();
+        match platform::kms_decrypt(
+            region.as_bytes(),
+            credentials.aws_key_id.as_bytes(),
+            credentials.aws_secret_key.as_bytes(),
+            credentials.aws_session_token.as_bytes(),
+            ciphertext,
+        ) {
+            Ok(secret) => return Ok(Zeroizing::new(secret)),
+            Err(e) => {
+                warn!("KMS decryption in {} failed: {}", region, e);
+                unavailable |= e.is_unavailable();
+                last_error = Some(format!("{}: {}", region, e));
+            }
+        }
+    }
+    let last_error = last_error.unwrap_or_default();
+    error!(
+        alert = true,
+        "KMS decryption failed in all regions (last in {})", last_error
+    );
+    if unavailable {
+        Err(Error::kms_unavailable(last_error))
+    } else {
+        Err(Error::kms_error(last_error))
+    }
+}
+
+/// the Ed25519 key of the secret (with a derivation path, the secret is a master seed
+/// the key is derived from)
+fn signing_key(
+    secret: &[u8],
+    derivation_path: Option<&DerivationPath>,
+) -> Result<SigningKey, Error> {
+    match derivation_path {
+        Some(path) => Ok(SigningKey::from(*derive_ed25519(secret, path))),
+        None => ed25519::SigningKey::try_from(secret).map_err(|_e| Error::invalid_key_error()),
+    }
+}
+
+/// the secret of a generated key: the OS RNG's bytes mixed with the NSM's random bytes
+/// (if available), with the sources that were used
+fn keygen_entropy(nsm_fd: i32) -> (Zeroizing<[u8; 32]>, Vec<EntropySource>) {
+    let mut os = Zeroizing::new([0u8; 32]);
+    OsRng.fill_bytes(&mut os[..]);
+    match platform::nsm_get_random(nsm_fd).map(Zeroizing::new) {
+        Some(nsm) => (
+            mix_entropy(&[(EntropySource::Os, &os[..]), (EntropySource::Nsm, &nsm)]),
+            vec![EntropySource::Os, EntropySource::Nsm],
+        ),
+        None => {
+            warn!("no NSM random bytes, the key is generated with the OS RNG only");
+            (
+                mix_entropy(&[(EntropySource::Os, &os[..])]),
+                vec![EntropySource::Os],
+            )
+        }
+    }
+}
+
+/// decrypts the AWS KMS-encrypted Ed25519 key (or the master seed it's derived from)
+fn decrypt_key(
+    aws_region: &str,
+    failover_regions: &[String],
+    ciphertext: &[u8],
+    derivation_path: Option<&DerivationPath>,
+) -> Result<SigningKey, Error> {
+    let secret = decrypt_secret(aws_region, failover_regions, ciphertext)?;
+    signing_key(&secret, derivation_path)
+}
+
+/// the enclave error of a failed key decryption
+fn key_error(e: Error) -> NitroError {
+    let code = match e.detail() {
+        ErrorDetail::AccessError(_) | ErrorDetail::KmsError(_) => NitroErrorCode::KmsAccessDenied,
+        ErrorDetail::KmsUnavailable(_) => NitroErrorCode::KmsUnavailable,
+        ErrorDetail::InvalidKeyError(_) => NitroErrorCode::BadKey,
+        _ => NitroErrorCode::Internal,
+    };
+    NitroError::new(code, e.to_string())
+}
+
+/// the enclave error of a failure that isn't the request's fault
+fn internal_error(e: impl std::fmt::Debug) -> NitroError {
+    NitroError::new(NitroErrorCode::Internal, format!("{:?}", e))
+}
+
+/// the enclave error of a failed KMS encryption
+fn kms_encrypt_error(e: platform::KmsError) -> NitroError {
+    let code = if e.is_unavailable() {
+        NitroErrorCode::KmsUnavailable
+    } else {
+        NitroErrorCode::KmsEncryptFailed
+    };
+    NitroError::new(code, e.to_string())
+}
+
+/// splits the consensus key into threshold shares encrypted to the cosigners' keys
+fn key_shares(nsm_fd: i32, config: &NitroKeySharesConfig) -> NitroKeySharesResult {
+    let recipients = policy::check_key_shares(config.threshold, &config.recipient_attestations)?;
+    credentials::set(config.credentials.clone());
+    let secret = decrypt_key(
+        &config.aws_region,
+        &config.kms_failover_regions,
+        &config.sealed_consensus_key,
+        config.consensus_key_derivation.as_ref(),
+    )
+    .map_err(key_error)?;
+    let public = secret.verification_key();
+    let key_shares =
+        split_signing_key(&mut OsRng, secret.as_bytes(), config.threshold, &recipients)
+            .map_err(|e| NitroError::new(NitroErrorCode::InvalidRequest, format!("{:?}", e)))?;
+    let pubkeyb64 =
+        String::from_utf8(subtle_encoding::base64::encode(public)).map_err(internal_error)?;
+    let digestb64 = String::from_utf8(subtle_encoding::base64::encode(key_shares.digest()))
+        .map_err(internal_error)?;
+    let claim = format!(
+        "{{\"pubkey\":\"{}\",\"threshold\":{},\"shares\":\"{}\"}}",
+        pubkeyb64, config.threshold, digestb64
+    );
+    let req = Request::Attestation {
+        user_data: Some(ByteBuf::from(claim)),
+        nonce: None,
+        public_key: None,
+    };
+    match nsm_process_request(nsm_fd, req) {
+        Response::Attestation { document } => Ok(NitroKeySharesResponse {
+            public_key: public.as_bytes().to_vec(),
+            key_shares,
+            attestation_doc: document,
+        }),
+        _ => Err(NitroError::attestation_failed()),
+    }
+}
+
+/// signs the payload (domain-separated) as a proof of possession of the consensus key
+/// (if the policy allows it)
+fn sign_payload(config: &NitroSignPayloadConfig) -> NitroSignPayloadResult {
+    policy::check_payload_signing()?;
+    credentials::set(config.credentials.clone());
+    let secret = decrypt_key(
+        &config.aws_region,
+        &config.kms_failover_regions,
+        &config.sealed_consensus_key,
+        config.consensus_key_derivation.as_ref(),
+    )
+    .map_err(key_error)?;
+    let signature = sign_proof_of_possession(&secret, &config.payload);
+    info!(
+        "signed a proof-of-possession payload ({} bytes)",
+        config.payload.len()
+    );
+    Ok(NitroSignPayloadResponse {
+        public_key: secret.verification_key().as_bytes().to_vec(),
+        signature: signature.to_bytes().to_vec(),
+    })
+}
+
+/// authenticates a state without a MAC (if the policy allows it, once per chain and enclave run)
+/// and attests it
+fn migrate_state(nsm_fd: i32, config: &NitroMigrateStateConfig) -> NitroMigrateStateResult {
+    policy::check_state_migration()?;
+    credentials::set(config.credentials.clone());
+    let secret = decrypt_key(
+        &config.aws_region,
+        &config.kms_failover_regions,
+        &config.sealed_consensus_key,
+        config.consensus_key_derivation.as_ref(),
+    )
+    .map_err(key_error)?;
+    state::begin_migration(&config.chain_id)
+        .map_err(|e| NitroError::new(NitroErrorCode::InvalidRequest, e))?;
+    let maced_state = StateMacKey::new(&secret, &config.chain_id)
+        .sign(&config.state)
+        .map_err(internal_error)?;
+    let claim = NitroMigrateStateClaim::new(&config.chain_id, &maced_state)
+        .and_then(|claim| serde_json::to_vec(&claim).map_err(|e| format!("{:?}", e)))
+        .map_err(internal_error)?;
+    warn!(
+        "authenticated the {} state at {} (migration)",
+        config.chain_id, config.state
+    );
+    let req = Request::Attestation {
+        user_data: Some(ByteBuf::from(claim)),
+        nonce: Some(ByteBuf::from(config.nonce.clone())),
+        public_key: None,
+    };
+    match nsm_process_request(nsm_fd, req) {
+        Response::Attestation { document } => Ok(NitroMigrateStateResponse {
+            state: maced_state,
+            attestation_doc: document,
+        }),
+        _ => Err(NitroError::attestation_failed()),
+    }
+}
+
+/// splits the consensus key (or its master seed) into Shamir shares encrypted to the operators' keys
+fn backup(nsm_fd: i32, config: &NitroBackupConfig) -> NitroBackupResult {
+    policy::check_backup(config.threshold, &config.recipients)?;
+    credentials::set(config.credentials.clone());
+    let secret = decrypt_secret(
+        &config.aws_region,
+        &config.kms_failover_regions,
+        &config.sealed_consensus_key,
+    )
+    .map_err(key_error)?;
+    let public = signing_key(&secret, config.consensus_key_derivation.as_ref())
+        .map_err(key_error)?
+        .verification_key();
+    let shares = split_secret(&mut OsRng, &secret, config.threshold, &config.recipients)
+        .map_err(|e| NitroError::new(NitroErrorCode::InvalidRequest, format!("{:?}", e)))?;
+    let backup = KeyBackup {
+        threshold: config.threshold,
+        public_key: public.to_bytes(),
+        derivation_path: config.consensus_key_derivation.clone(),
+        shares,
+    };
+    let pubkeyb64 =
+        String::from_utf8(subtle_encoding::base64::encode(public)).map_err(internal_error)?;
+    let digestb64 = String::from_utf8(subtle_encoding::base64::encode(backup.digest()))
+        .map_err(internal_error)?;
+    let claim = format!(
+        "{{\"pubkey\":\"{}\",\"threshold\":{},\"backup\":\"{}\"}}",
+        pubkeyb64, config.threshold, digestb64
+    );
+    let req = Request::Attestation {
+        user_data: Some(ByteBuf::from(claim)),
+        nonce: Some(ByteBuf::from(config.nonce.clone())),
+        public_key: None,
+    };
+    match nsm_process_request(nsm_fd, req) {
+        Response::Attestation { document } => Ok(NitroBackupResponse {
+            backup,
+            attestation_doc: document,
+        }),
+        _ => Err(NitroError::attestation_failed()),
+    }
+}
+
+/// re-encrypts the sealed key with another AWS KMS key
+/// (and attests the old and new ciphertexts of the key)
+fn rewrap(nsm_fd: i32, config: &NitroRewrapConfig) -> NitroRewrapResult {
+    credentials::set(config.credentials.clone());
+    let secret = decrypt_secret(
+        &config.aws_region,
+        &config.kms_failover_regions,
+        &config.sealed_key,
+    )
+    .map_err(key_error)?;
+    let encrypted_secret = platform::kms_encrypt(
+        config.aws_region.as_bytes(),
+        config.credentials.aws_key_id.as_bytes(),
+        config.credentials.aws_secret_key.as_bytes(),
+        config.credentials.aws_session_token.as_bytes(),
+        config.kms_key_id.as_bytes(),
+        &secret,
+    );
+    let mut key = signing_key(&secret, config.derivation_path.as_ref()).map_err(key_error)?;
+    let public_key = key.verification_key().as_bytes().to_vec();
+    key.zeroize();
+    let encrypted_secret = encrypted_secret.map_err(kms_encrypt_error)?;
+    let claim = NitroRewrapClaim::new(
+        &public_key,
+        &config.kms_key_id,
+        &config.sealed_key,
+        &encrypted_secret,
+    );
+    let req = Request::Attestation {
+        user_data: Some(ByteBuf::from(
+            serde_json::to_vec(&claim).map_err(internal_error)?,
+        )),
+        nonce: Some(ByteBuf::from(config.nonce.clone())),
+        public_key: None,
+    };
+    match nsm_process_request(nsm_fd, req) {
+        Response::Attestation { document } => Ok(NitroRewrapResponse {
+            encrypted_secret,
+            public_key,
+            attestation_doc: document,
+        }),
+        _ => Err(NitroError::attestation_failed()),
+    }
+}
+
+/// attests the public key derived from the sealed master seed
+fn derive(nsm_fd: i32, config: &NitroDeriveConfig) -> NitroDeriveResult {
+    credentials::set(config.credentials.clone());
+    let mut secret = decrypt_key(
+        &config.aws_region,
+        &config.kms_failover_regions,
+        &config.sealed_seed,
+        Some(&config.derivation_path),
+    )
+    .map_err(key_error)?;
+    let public = secret.verification_key();
+    secret.zeroize();
+    let pubkeyb64 =
+        String::from_utf8(subtle_encoding::base64::encode(public)).map_err(internal_error)?;
+    let claim = format!(
+        "{{\"pubkey\":\"{}\",\"path\":\"{}\"}}",
+        pubkeyb64, config.derivation_path
+    );
+    let req = Request::Attestation {
+        user_data: Some(ByteBuf::from(claim)),
+        nonce: Some(ByteBuf::from(config.nonce.clone())),
+        public_key: None,
+    };
+    match nsm_process_request(nsm_fd, req) {
+        Response::Attestation { document } => Ok(NitroDeriveResponse {
+            public_key: public.as_bytes().to_vec(),
+            attestation_doc: document,
+        }),
+        _ => Err(NitroError::attestation_failed()),
+    }
+}
+
+/// runs the signing session of the pushed config (until it's stopped or the validator is unreachable)
+fn run_session(config: Box<NitroConfig>, startup: Option<StartupAttestation>) -> Result<(), Error> {
+    if !config.chain_allowed() {
+        error!(
+            chain_id = %config.chain_id,
+            alert = true,
+            "[{}] chain isn't in the allowed chain IDs, refusing to start",
+            &config.chain_id
+        );
+        return Err(Error::chain_id_error(config.chain_id.to_string()));
+    }
+    if let Some(port) = config.enclave_trace_port {
+        enable_span_relay(config.enclave_mux_port, port);
+    }
+    credentials::set(config.credentials.clone());
+    let secret = decrypt_key(
+        &config.aws_region,
+        &config.kms_failover_regions,
+        &config.sealed_consensus_key,
+        config.consensus_key_derivation.as_ref(),
+    )?;
+    let id_keypair = if let Some(ref ciphertext) = config.sealed_id_key {
+        Some(decrypt_key(
+            &config.aws_region,
+            &config.kms_failover_regions,
+            ciphertext,
+            None,
+        )?)
+    } else {
+        None
+    };
+    let mac_key = StateMacKey::new(&secret, &config.chain_id);
+    let state_holder = state::StateHolder::new(
+        config.enclave_state_port,
+        config.enclave_mux_port,
+        &config.timeouts,
+        mac_key.clone(),
+    )
+    .map_err(|e| Error::io_error("failed get state connection".into(), e))?;
+    let mut state_holder: Box<dyn PersistStateSync> =
+        if let Some(port) = config.enclave_remote_state_port {
+            let remote_state_holder = state::StateHolder::new(
+                port,
+                config.enclave_mux_port,
+                &config.timeouts,
+                mac_key.clone(),
+            )
+            .map_err(|e| Error::io_error("failed get remote state connection".into(), e))?;
+            Box::new(DualStateSync::new(
+                state_holder,
+                remote_state_holder,
+                config.state_durability,
+            ))
         } else {
-            let addr = VsockAddr::new(VSOCK_HOST_CID, config.enclave_tendermint_conn);
-            if let Ok(socket) = vsock::VsockStream::connect(&addr) {
-                trace!("tendermint vsock port: {}", config.enclave_tendermint_conn);
-                trace!("tendermint peer addr: {:?}", socket.peer_addr());
-                trace!("tendermint local addr: {:?}", socket.local_addr());
-                trace!("tendermint fd: {}", socket.as_raw_fd());
-                info!("connected to validator successfully");
-                let plain_conn = PlainConnection::new(socket);
-                Ok(Box::new(plain_conn))
-            } else {
-                warn!("vsock failed to connect to validator");
-                Err(io::ErrorKind::Other.into())
+            Box::new(state_holder)
+        };
+    if let Some(port) = config.enclave_monotonic_port {
+        let service_key = policy::monotonic_service_key().map_err(|e| {
+            error!("the monotonic state service can't be used: {}", e);
+            Error::access_error()
+        })?;
+        let monotonic_conn =
+            state::host_connection(port, config.enclave_mux_port, &config.timeouts)
+                .map_err(|e| Error::io_error("failed get monotonic state connection".into(), e))?;
+        state_holder = Box::new(AntiRollbackStateSync::new(
+            state_holder,
+            monotonic_conn,
+            config.chain_id.to_string(),
+            mac_key,
+            service_key,
+        ));
+    }
+    if let Some(port) = config.enclave_watermark_port {
+        let watermark_conn =
+            state::host_connection(port, config.enclave_mux_port, &config.timeouts)
+                .map_err(|e| Error::io_error("failed get watermark connection".into(), e))?;
+        state_holder = Box::new(WatermarkStateSync::new(
+            state_holder,
+            watermark_conn,
+            config.chain_id.to_string(),
+            config.replica_id.clone(),
+        ));
+    }
+    if let Some(port) = config.enclave_lease_port {
+        let lease_conn = state::host_connection(port, config.enclave_mux_port, &config.timeouts)
+            .map_err(|e| Error::io_error("failed get lease connection".into(), e))?;
+        state_holder = Box::new(LeaseStateSync::new(state_holder, lease_conn));
+    }
+    let watermark = if config.enclave_attestation_port.is_some() {
+        let tracked = attestation::TrackedStateSync::new(state_holder);
+        let watermark = tracked.watermark();
+        state_holder = Box::new(tracked);
+        Some(watermark)
+    } else {
+        None
+    };
+    let state = state_holder
+        .load_state()
+        .map_err(|e| io_error_wrap("failed to load initial state".into(), e))?;
+    if let Some(startup) = startup {
+        let response = attestation::attest_startup(
+            &config.chain_id,
+            &secret.verification_key(),
+            state.consensus_state(),
+            startup.nonce,
+        );
+        // the helper may have stopped waiting for it
+        let _ = startup.response.send(response);
+    }
+    let mut backoff = Backoff::new(config.reconnect_backoff.clone());
+    let chain_id = config.chain_id.clone();
+    let on_alert = move |failures: u32| {
+        error!(
+            chain_id = %chain_id,
+            consecutive_failures = failures,
+            alert = true,
+            "[{}] validator unreachable after {} attempts",
+            chain_id,
+            failures
+        );
+    };
+    let (conn, validator) =
+        match get_connection(&config, id_keypair.as_ref(), &mut backoff, &on_alert, None) {
+            Some(connected) => connected,
+            None => {
+                error!("[{}] giving up connecting to validator", &config.chain_id);
+                return Ok(());
             }
         };
-        if let Err(e) = conn {
-            error!("tendermint connection error {:?}", e);
-            thread::sleep(Duration::new(1, 0));
-        } else {
-            return conn.unwrap();
+    let public_key = secret.verification_key();
+    // the audit anchors are signed with the consensus key
+    let audit_holder = if let Some(port) = config.enclave_audit_port {
+        Some(
+            audit::AuditHolder::new(port, config.enclave_mux_port, secret.clone())
+                .map_err(|e| Error::io_error("failed get audit connection".into(), e))?,
+        )
+    } else {
+        None
+    };
+    let mut signing_policy = config.signing_policy.clone();
+    signing_policy.approval = policy::approval(signing_policy.approval.as_ref());
+    let mut session = tmkms_light::session::Session::new(
+        ValidatorConfig {
+            chain_id: config.chain_id.clone(),
+            max_height: config.max_height,
+            signing_policy,
+        },
+        conn,
+        secret,
+        state,
+        state_holder,
+    );
+    if let Some(audit_holder) = audit_holder {
+        session.set_audit_sink(Box::new(audit_holder));
+    }
+    if let Some(port) = config.enclave_metrics_port {
+        let metrics_holder = metrics::MetricsHolder::new(port, config.enclave_mux_port)
+            .map_err(|e| Error::io_error("failed get metrics connection".into(), e))?;
+        session.set_metrics_sink(Box::new(metrics_holder));
+    }
+    if let Some(time_sync) = config.time_sync.clone() {
+        session.set_clock(time_sync::launch(time_sync, config.enclave_mux_port));
+    }
+    if let Some(idle_timeout) = config.timeouts.idle() {
+        session.set_idle_timeout(idle_timeout);
+    }
+    session.set_connection_msg_types(validator.allowed_msg_types);
+    if config.dry_run {
+        warn!("dry-run mode: the signing requests are refused");
+        session.set_dry_run(true);
+    }
+    if config.dump_refused_sign_bytes {
+        warn!("debug: the sign-bytes of the refused requests are sent to the audit log");
+        session.set_dump_refused_sign_bytes(true);
+    }
+    if let Some(committed_height) = config.committed_height {
+        info!(
+            "refusing to sign at or below the committed height {}",
+            committed_height
+        );
+        session.set_committed_height(committed_height);
+    }
+    if let Some(approval) = &config.approval {
+        let approver_key = policy::approver_key().map_err(|e| {
+            error!("the approval service can't be used: {}", e);
+            Error::access_error()
+        })?;
+        let approval_conn =
+            state::host_connection(approval.port, config.enclave_mux_port, &config.timeouts)
+                .map_err(|e| Error::io_error("failed get approval connection".into(), e))?;
+        session.set_approver(Box::new(RemoteApprover::new(approval_conn)), approver_key);
+    }
+    let control = session.control();
+    if let Err(e) = sessions::register(&config.chain_id, control.clone()) {
+        error!("{}", e);
+        return Ok(());
+    }
+    if let (Some(port), Some(watermark)) = (config.enclave_attestation_port, watermark) {
+        attestation::launch_reattestation(
+            port,
+            config.enclave_mux_port,
+            Duration::from_secs(config.attestation_interval_secs),
+            config.chain_id.clone(),
+            public_key,
+            watermark,
+            control.clone(),
+        );
+    }
+    loop {
+        if let Err(e) = session.request_loop() {
+            if let ErrorDetail::ExceedMaxHeight(ref detail) = e.detail() {
+                if let Some(port) = config.enclave_halt_port {
+                    if let Err(e) = attestation::report_halt(
+                        port,
+                        config.enclave_mux_port,
+                        &config.chain_id,
+                        &public_key,
+                        detail.max_height,
+                        session.consensus_state(),
+                    ) {
+                        warn!("[{}] failed to report the halt: {}", &config.chain_id, e);
+                    }
+                }
+                break;
+            }
+            if e.is_fatal() {
+                error!(
+                    "[{}] fatal request error, stopping the session: {}",
+                    &config.chain_id, e
+                );
+                break;
+            }
+            warn!("[{}] request error, reconnecting: {}", &config.chain_id, e);
+        }
+        if control.status() == SessionStatus::Stopped {
+            break;
+        }
+        match get_connection(
+            &config,
+            id_keypair.as_ref(),
+            &mut backoff,
+            &on_alert,
+            Some(&control),
+        ) {
+            Some((conn, validator)) => {
+                session.reset_connection(conn);
+                session.set_connection_msg_types(validator.allowed_msg_types);
+            }
+            None if control.status() == SessionStatus::Stopped => {
+                info!("[{}] session stopped while reconnecting", &config.chain_id);
+                break;
+            }
+            None => {
+                error!("[{}] giving up reconnecting to validator", &config.chain_id);
+                break;
+            }
         }
     }
+    // the session zeroizes the consensus key when it's dropped
+    drop(session);
+    if let Some(mut id_keypair) = id_keypair {
+        id_keypair.zeroize();
+    }
+    sessions::unregister(&config.chain_id);
+    Ok(())
+}
+
+/// sends the (encoded) response to the helper
+fn write_response<T: Serialize>(
+    stream: &mut ChannelStream,
+    response: &T,
+    what: &str,
+) -> Result<(), Error> {
+    let response_raw = codec::encode(response)
+        .map_err(|e| io_error_wrap(format!("failed to encode the {} response", what), e))?;
+    write_u16_payload(stream, &response_raw)
+        .map_err(|e| Error::io_error(format!("failed to send the {} response", what), e))
 }
 
-/// a simple req-rep handling loop
-pub fn entry(mut stream: VsockStream) -> Result<(), Error> {
+/// serves the helper's requests on the connection (one after another) until it's closed
+pub fn entry(mut stream: ChannelStream) -> Result<(), Error> {
     let nsm_fd = nsm_init();
-    let json_raw = read_u16_payload(&mut stream)?;
-    let request: Result<NitroRequest, _> = serde_json::from_slice(&json_raw);
+    // the helper sends its (first) request right after connecting
+    stream
+        .set_read_timeout(Some(REQUEST_READ_TIMEOUT))
+        .map_err(|e| Error::io_error("failed to set the request timeout".into(), e))?;
+    let limits = policy::request_limits();
+    let mut served = 0u64;
+    let result = loop {
+        let request_raw = match read_u16_payload_with_limits(&mut stream, &limits) {
+            Ok(request_raw) => request_raw,
+            // the helper closed the connection (or left it idle)
+            Err(e) if served > 0 => {
+                debug!("control connection closed after {} requests: {}", served, e);
+                break Ok(());
+            }
+            Err(e) => break Err(e),
+        };
+        served += 1;
+        let request: Result<NitroRequest, _> = codec::decode(&request_raw);
+        if request.is_err() && codec::is_legacy_json(&request_raw) {
+            error!("the helper uses the JSON encoding of the older releases");
+            // in the encoding the older helper understands
+            let response: NitroResponse = Err(NitroError::new(
+                NitroErrorCode::ProtocolMismatch,
+                format!(
+                    "the enclave speaks the protocol version {} (upgrade the helper)",
+                    PROTOCOL_VERSION
+                ),
+            ));
+            let json = serde_json::to_string(&response).map_err(Error::serialization_error)?;
+            write_u16_payload(&mut stream, json.as_bytes())
+                .map_err(|e| Error::io_error("failed to send error response".into(), e))?;
+            break Ok(());
+        }
+        let request = match request.map(control::authorize) {
+            Ok(Err(e)) => {
+                error!(alert = true, "refused a request: {}", e);
+                let response: NitroResponse = Err(e);
+                write_response(&mut stream, &response, "error")?;
+                // the connection isn't trusted anymore
+                break Ok(());
+            }
+            Ok(Ok(request)) => Ok(request),
+            Err(e) => Err(e),
+        };
+        if served == 1 && !matches!(request, Ok(NitroRequest::Hello(_))) {
+            error!("the helper didn't negotiate the protocol version (older than the enclave?)");
+            let response: NitroResponse = Err(NitroError::new(
+                NitroErrorCode::ProtocolMismatch,
+                format!(
+                    "the enclave requires the protocol version {} negotiation (upgrade the helper)",
+                    PROTOCOL_VERSION
+                ),
+            ));
+            write_response(&mut stream, &response, "error")?;
+            break Ok(());
+        }
+        if let Err(e) = handle_request(nsm_fd, &mut stream, request) {
+            break Err(e);
+        }
+        if let Err(e) = stream.set_read_timeout(Some(CONTROL_IDLE_TIMEOUT)) {
+            break Err(Error::io_error("failed to set the idle timeout".into(), e));
+        }
+    };
+    nsm_exit(nsm_fd);
+    result
+}
+
+/// handles a request of the helper (and writes its response)
+fn handle_request(
+    nsm_fd: i32,
+    stream: &mut ChannelStream,
+    request: Result<NitroRequest, codec::Error>,
+) -> Result<(), Error> {
+    let (request, startup_nonce) = match request {
+        Ok(NitroRequest::StartSealed { nonce }) => (
+            Ok(NitroRequest::Start(config_push::receive(
+                nsm_fd,
+                stream,
+                nonce.clone(),
+            )?)),
+            Some(nonce),
+        ),
+        Ok(NitroRequest::Sealed { nonce }) => (
+            Ok(config_push::receive_request(nsm_fd, stream, nonce)?),
+            None,
+        ),
+        request => (request, None),
+    };
     match request {
         Ok(NitroRequest::Start(config)) => {
-            let key_bytes = Zeroizing::new(
-                aws_ne_sys::kms_decrypt(
-                    config.aws_region.as_bytes(),
-                    config.credentials.aws_key_id.as_bytes(),
-                    config.credentials.aws_secret_key.as_bytes(),
-                    config.credentials.aws_session_token.as_bytes(),
-                    config.sealed_consensus_key.as_ref(),
-                )
-                .map_err(|_e| Error::access_error())?,
-            );
-            let secret = ed25519::SigningKey::try_from(key_bytes.as_slice())
-                .map_err(|_e| Error::invalid_key_error())?;
-            let id_keypair = if let Some(ref ciphertext) = config.sealed_id_key {
-                let id_key_bytes = Zeroizing::new(
-                    aws_ne_sys::kms_decrypt(
-                        config.aws_region.as_bytes(),
-                        config.credentials.aws_key_id.as_bytes(),
-                        config.credentials.aws_secret_key.as_bytes(),
-                        config.credentials.aws_session_token.as_bytes(),
-                        ciphertext.as_ref(),
-                    )
-                    .map_err(|_e| Error::access_error())?,
-                );
-                let id_secret = ed25519::SigningKey::try_from(id_key_bytes.as_slice())
-                    .map_err(|_e| Error::invalid_key_error())?;
-                Some(id_secret)
-            } else {
-                None
+            let (startup, startup_rx) = match startup_nonce {
+                Some(nonce) => {
+                    let (response, rx) = mpsc::channel();
+                    (Some(StartupAttestation { nonce, response }), Some(rx))
+                }
+                None => (None, None),
             };
-            let mut state_holder = state::StateHolder::new(config.enclave_state_port)
-                .map_err(|e| Error::io_error("failed get state connection".into(), e))?;
-            let state = state_holder
-                .load_state()
-                .map_err(|e| io_error_wrap("failed to load initial state".into(), e))?;
-            let conn: Box<dyn Connection> = get_connection(&config, id_keypair.as_ref());
-            let mut session = tmkms_light::session::Session::new(
-                ValidatorConfig {
-                    chain_id: config.chain_id.clone(),
-                    max_height: config.max_height,
-                },
-                conn,
-                secret,
-                state,
-                state_holder,
-            );
-            loop {
-                if let Err(e) = session.request_loop() {
-                    error!("request error: {}", e);
+            // the session runs in its own thread, so that the connection can serve other requests
+            std::thread::spawn(move || {
+                let chain_id = config.chain_id.clone();
+                if let Err(e) = run_session(config, startup) {
+                    error!("[{}] session error: {}", chain_id, e);
                 }
-                let conn: Box<dyn Connection> = get_connection(&config, id_keypair.as_ref());
-                session.reset_connection(conn);
+            });
+            if let Some(startup_rx) = startup_rx {
+                let response = match startup_rx.recv_timeout(STARTUP_ATTESTATION_TIMEOUT) {
+                    Ok(response) => response,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => Err(NitroError::new(
+                        NitroErrorCode::Internal,
+                        "the session failed to start (see the enclave's logs)",
+                    )),
+                    Err(mpsc::RecvTimeoutError::Timeout) => Err(NitroError::new(
+                        NitroErrorCode::Internal,
+                        "the session didn't load its key and state in time",
+                    )),
+                };
+                write_response(stream, &response, "startup attestation")?;
             }
         }
         Ok(NitroRequest::Keygen(keygen_config)) => {
-            let csprng = OsRng {};
-            let mut keypair = SigningKey::new(csprng);
+            let (seed, entropy_sources) = keygen_entropy(nsm_fd);
+            // the sealed secret: the key itself or the master seed it's derived from
+            let (mut keypair, secret) = match &keygen_config.derivation_path {
+                Some(path) => (
+                    SigningKey::from(*derive_ed25519(&seed[..], path)),
+                    Zeroizing::new(seed.to_vec()),
+                ),
+                None => {
+                    let keypair = SigningKey::from(*seed);
+                    let secret = Zeroizing::new(keypair.as_bytes().to_vec());
+                    (keypair, secret)
+                }
+            };
             let public = keypair.verification_key();
             let pubkeyb64 = String::from_utf8(subtle_encoding::base64::encode(public))
                 .map_err(|e| io_error_wrap("base64 encoding error".into(), e))?;
-            let keyidb64 =
-                String::from_utf8(subtle_encoding::base64::encode(&keygen_config.kms_key_id))
-                    .map_err(|e| io_error_wrap("base64 encoding error".into(), e))?;
-
-            let claim = format!(
-                "{{\"pubkey\":\"{}\",\"key_id\":\"{}\"}}",
-                pubkeyb64, keyidb64
+            // with a multi-Region key, the ciphertext of any replica can be decrypted in all regions
+            let replicas = std::iter::once((
+                keygen_config.aws_region.as_str(),
+                keygen_config.kms_key_id.as_str(),
+            ))
+            .chain(
+                keygen_config
+                    .kms_replicas
+                    .iter()
+                    .map(|replica| (replica.aws_region.as_str(), replica.kms_key_id.as_str())),
             );
-            let user_data = Some(ByteBuf::from(claim));
-            let response: NitroResponse = match aws_ne_sys::kms_encrypt(
-                keygen_config.aws_region.as_bytes(),
-                keygen_config.credentials.aws_key_id.as_bytes(),
-                keygen_config.credentials.aws_secret_key.as_bytes(),
-                keygen_config.credentials.aws_session_token.as_bytes(),
-                keygen_config.kms_key_id.as_bytes(),
-                keypair.as_bytes(),
-            ) {
-                Ok(encrypted_secret) => {
+            let mut encrypted = Err(NitroError::new(
+                NitroErrorCode::KmsEncryptFailed,
+                "no KMS key",
+            ));
+            for (region, kms_key_id) in replicas {
+                match platform::kms_encrypt(
+                    region.as_bytes(),
+                    keygen_config.credentials.aws_key_id.as_bytes(),
+                    keygen_config.credentials.aws_secret_key.as_bytes(),
+                    keygen_config.credentials.aws_session_token.as_bytes(),
+                    kms_key_id.as_bytes(),
+                    &secret,
+                ) {
+                    Ok(encrypted_secret) => {
+                        encrypted = Ok((encrypted_secret, kms_key_id));
+                        break;
+                    }
+                    Err(e) => {
+                        warn!("KMS encryption in {} failed: {}", region, e);
+                        encrypted = Err(kms_encrypt_error(e));
+                    }
+                }
+            }
+            let response: NitroResponse = match encrypted {
+                Ok((encrypted_secret, kms_key_id)) => {
+                    let keyidb64 = String::from_utf8(subtle_encoding::base64::encode(kms_key_id))
+                        .map_err(|e| io_error_wrap("base64 encoding error".into(), e))?;
+                    let path = keygen_config
+                        .derivation_path
+                        .as_ref()
+                        .map(|path| format!(",\"path\":\"{}\"", path))
+                        .unwrap_or_default();
+                    let entropy = entropy_sources
+                        .iter()
+                        .map(|source| format!("\"{}\"", source.as_str()))
+                        .collect::<Vec<_>>()
+                        .join(",");
+                    let claim = format!(
+                        "{{\"pubkey\":\"{}\",\"key_id\":\"{}\",\"purpose\":\"{}\"{},\"entropy\":[{}]}}",
+                        pubkeyb64,
+                        keyidb64,
+                        keygen_config.purpose.as_str(),
+                        path,
+                        entropy
+                    );
                     let req = Request::Attestation {
-                        user_data,
-                        // as this is one-off attestation on generation,
-                        // no need here (this may useful in other scenarios)
-                        nonce: None,
+                        user_data: Some(ByteBuf::from(claim)),
+                        // the helper checks it's the one it requested
+                        nonce: Some(ByteBuf::from(keygen_config.nonce.clone())),
                         // this field is meant for encryptions (e.g. when AWS KMS
                         // sends a response to the enclave),
                         // so it's used in `aws_ne_sys`, but not here
@@ -198,21 +1064,151 @@ pub fn entry(mut stream: VsockStream) -> Result<(), Error> {
                             public_key: public.as_bytes().to_vec(),
                             attestation_doc: document,
                         }),
-                        _ => Err("failed to obtain an attestation document".to_owned()),
+                        _ => Err(NitroError::attestation_failed()),
                     }
                 }
-                Err(e) => Err(format!("{:?}", e)),
+                Err(e) => Err(e),
             };
             keypair.zeroize();
-            let json = serde_json::to_string(&response).map_err(Error::serialization_error)?;
-            write_u16_payload(&mut stream, json.as_bytes())
-                .map_err(|e| Error::io_error("failed to send keypair response".into(), e))?;
+            write_response(stream, &response, "keypair")?;
+        }
+        Ok(NitroRequest::KeyShares(config)) => {
+            let response = key_shares(nsm_fd, &config);
+            if let Err(ref e) = response {
+                error!("failed to split the consensus key: {}", e);
+            }
+            write_response(stream, &response, "key shares")?;
+        }
+        Ok(NitroRequest::Rewrap(config)) => {
+            let response = rewrap(nsm_fd, &config);
+            if let Err(ref e) = response {
+                error!("failed to re-encrypt the sealed key: {}", e);
+            }
+            write_response(stream, &response, "rewrap")?;
+        }
+        Ok(NitroRequest::Derive(config)) => {
+            let response = derive(nsm_fd, &config);
+            if let Err(ref e) = response {
+                error!("failed to derive the key: {}", e);
+            }
+            write_response(stream, &response, "derive")?;
+        }
+        Ok(NitroRequest::ChainControl(request)) => {
+            let response = sessions::control(&request);
+            write_response(stream, &response, "chain control")?;
+        }
+        Ok(NitroRequest::ChainStatus) => {
+            let response: NitroChainStatusResult = Ok(sessions::statuses());
+            write_response(stream, &response, "chain status")?;
+        }
+        Ok(NitroRequest::Shutdown) => {
+            info!("shutting down");
+            let response: NitroShutdownResult = sessions::shutdown(SHUTDOWN_TIMEOUT);
+            write_response(stream, &response, "shutdown")?;
+            nsm_exit(nsm_fd);
+            info!("enclave shut down");
+            std::process::exit(0);
+        }
+        Ok(NitroRequest::SetLogLevel(level)) => {
+            set_level_override(level.map(LevelFilter::from));
+            match level {
+                Some(level) => info!("log level set to {:?}", level),
+                None => info!("log level reset"),
+            }
+            let response: NitroSetLogLevelResult = Ok(());
+            write_response(stream, &response, "log level")?;
+        }
+        Ok(NitroRequest::RefreshCredentials(aws_credentials)) => {
+            let response = credentials::refresh(aws_credentials);
+            write_response(stream, &response, "credentials")?;
+        }
+        Ok(NitroRequest::Hello(hello)) => {
+            let response: NitroHelloResult = if hello.version == PROTOCOL_VERSION {
+                Ok(NitroHello::current())
+            } else {
+                error!(
+                    "the helper speaks the protocol version {}, but the enclave {}",
+                    hello.version, PROTOCOL_VERSION
+                );
+                Err(NitroError::new(
+                    NitroErrorCode::ProtocolMismatch,
+                    format!(
+                        "the helper speaks the protocol version {}, but the enclave {}: upgrade the older one",
+                        hello.version, PROTOCOL_VERSION
+                    ),
+                ))
+            };
+            write_response(stream, &response, "negotiation")?;
+        }
+        Ok(NitroRequest::BindControl { nonce }) => {
+            control::bind(nsm_fd, stream, nonce)?;
+        }
+        Ok(
+            NitroRequest::StartSealed { .. }
+            | NitroRequest::Sealed { .. }
+            | NitroRequest::Authenticated(_),
+        ) => {
+            unreachable!("the requests are unwrapped above")
+        }
+        Ok(NitroRequest::Attest { nonce }) => {
+            let req = Request::Attestation {
+                user_data: None,
+                nonce: Some(ByteBuf::from(nonce)),
+                public_key: None,
+            };
+            let response: NitroAttestResult = match nsm_process_request(nsm_fd, req) {
+                Response::Attestation { document } => Ok(ByteBuf::from(document)),
+                _ => Err(NitroError::attestation_failed()),
+            };
+            write_response(stream, &response, "attestation")?;
+        }
+        Ok(NitroRequest::ProvisionBegin { nonce }) => {
+            let response = provisioning::begin(nsm_fd, nonce);
+            write_response(stream, &response, "provisioning")?;
+        }
+        Ok(NitroRequest::ProvisionMnemonic(config)) => {
+            let response = provisioning::finish(nsm_fd, &config);
+            if let Err(ref e) = response {
+                error!("failed to provision the mnemonic: {}", e);
+            }
+            write_response(stream, &response, "provisioning")?;
+        }
+        Ok(NitroRequest::SignPayload(config)) => {
+            let response = sign_payload(&config);
+            if let Err(ref e) = response {
+                error!("failed to sign the payload: {}", e);
+            }
+            write_response(stream, &response, "signature")?;
+        }
+        Ok(NitroRequest::Backup(config)) => {
+            let response = backup(nsm_fd, &config);
+            if let Err(ref e) = response {
+                error!("failed to back up the consensus key: {}", e);
+            }
+            write_response(stream, &response, "backup")?;
+        }
+        Ok(NitroRequest::Restore(config)) => {
+            let response = provisioning::restore(nsm_fd, &config);
+            if let Err(ref e) = response {
+                error!("failed to restore the consensus key: {}", e);
+            }
+            write_response(stream, &response, "restore")?;
+        }
+        Ok(NitroRequest::MigrateState(config)) => {
+            let response = migrate_state(nsm_fd, &config);
+            if let Err(ref e) = response {
+                error!("failed to migrate the state: {}", e);
+            }
+            write_response(stream, &response, "state migration")?;
         }
         Err(e) => {
             error!("config error: {}", e);
+            let response: NitroResponse = Err(NitroError::new(
+                NitroErrorCode::InvalidRequest,
+                format!("invalid request: {}", e),
+            ));
+            write_response(stream, &response, "error")?;
         }
     }
-    nsm_exit(nsm_fd);
-
     Ok(())
 }
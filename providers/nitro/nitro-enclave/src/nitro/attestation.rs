@@ -0,0 +1,194 @@
+use super::platform::{nsm_exit, nsm_init, nsm_process_request};
+use aws_nitro_enclaves_nsm_api::api::{Request, Response};
+use ed25519_consensus::{Signature, VerificationKey};
+use serde_bytes::ByteBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tendermint::chain;
+use tmkms_light::chain::state::{consensus, PersistStateSync, State, StateError};
+use tmkms_light::session::{SessionControl, SessionStatus};
+use tmkms_light::utils::write_u16_payload;
+use tmkms_nitro_helper::mux::connect_channel;
+use tmkms_nitro_helper::{
+    NitroAttestResult, NitroError, NitroHaltReport, NitroReattestation, NitroWatermarkClaim,
+};
+use tracing::{debug, info, warn};
+
+/// how often the session status is checked between the attestations
+const STATUS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// keeps the last persisted state (the watermark included in the runtime attestations)
+pub struct TrackedStateSync {
+    inner: Box<dyn PersistStateSync>,
+    watermark: Arc<Mutex<consensus::State>>,
+}
+
+impl TrackedStateSync {
+    pub fn new(inner: Box<dyn PersistStateSync>) -> Self {
+        Self {
+            inner,
+            watermark: Arc::new(Mutex::new(consensus::State::default())),
+        }
+    }
+
+    /// the last persisted state
+    pub fn watermark(&self) -> Arc<Mutex<consensus::State>> {
+        self.watermark.clone()
+    }
+
+    fn set_watermark(&self, state: &consensus::State) {
+        *self.watermark.lock().unwrap_or_else(|e| e.into_inner()) = state.clone();
+    }
+}
+
+impl PersistStateSync for TrackedStateSync {
+    fn load_state(&mut self) -> Result<State, StateError> {
+        let state = self.inner.load_state()?;
+        self.set_watermark(state.consensus_state());
+        Ok(state)
+    }
+
+    fn persist_state(&mut self, new_state: &consensus::State) -> Result<(), StateError> {
+        self.inner.persist_state(new_state)?;
+        self.set_watermark(new_state);
+        Ok(())
+    }
+
+    fn persist_signed_state(
+        &mut self,
+        new_state: &consensus::State,
+        sign_bytes: &[u8],
+        signature: &Signature,
+    ) -> Result<(), StateError> {
+        self.inner
+            .persist_signed_state(new_state, sign_bytes, signature)?;
+        self.set_watermark(new_state);
+        Ok(())
+    }
+}
+
+/// an attestation document binding the consensus public key and the watermark
+/// (and the helper's nonce, if any)
+fn attest(
+    chain_id: &chain::Id,
+    public_key: &VerificationKey,
+    watermark: &consensus::State,
+    nonce: Option<Vec<u8>>,
+) -> Result<Vec<u8>, String> {
+    let claim = NitroWatermarkClaim {
+        pubkey: String::from_utf8_lossy(&subtle_encoding::base64::encode(public_key)).into_owned(),
+        chain_id: chain_id.to_string(),
+        height: watermark.height.value(),
+        round: watermark.round.value(),
+        step: watermark.step,
+    };
+    let req = Request::Attestation {
+        user_data: Some(ByteBuf::from(
+            serde_json::to_vec(&claim).map_err(|e| e.to_string())?,
+        )),
+        nonce: nonce.map(ByteBuf::from),
+        public_key: None,
+    };
+    let nsm_fd = nsm_init();
+    let response = nsm_process_request(nsm_fd, req);
+    nsm_exit(nsm_fd);
+    match response {
+        Response::Attestation { document } => Ok(document),
+        _ => Err("failed to obtain an attestation document".to_owned()),
+    }
+}
+
+/// attests the session's loaded consensus key and watermark for the helper (with its nonce)
+pub fn attest_startup(
+    chain_id: &chain::Id,
+    public_key: &VerificationKey,
+    watermark: &consensus::State,
+    nonce: Vec<u8>,
+) -> NitroAttestResult {
+    attest(chain_id, public_key, watermark, Some(nonce))
+        .map(ByteBuf::from)
+        .map_err(|e| {
+            warn!("[{}] startup attestation failed: {}", chain_id, e);
+            NitroError::attestation_failed()
+        })
+}
+
+fn push_attestation(
+    port: u32,
+    mux_port: Option<u32>,
+    report: &NitroReattestation,
+) -> Result<(), String> {
+    let json_raw = serde_json::to_vec(report).map_err(|e| e.to_string())?;
+    let mut conn = connect_channel(mux_port, port).map_err(|e| e.to_string())?;
+    write_u16_payload(&mut conn, &json_raw).map_err(|e| e.to_string())
+}
+
+/// periodically pushes fresh attestations to the host (until the session is stopped)
+pub fn launch_reattestation(
+    port: u32,
+    mux_port: Option<u32>,
+    interval: Duration,
+    chain_id: chain::Id,
+    public_key: VerificationKey,
+    watermark: Arc<Mutex<consensus::State>>,
+    control: SessionControl,
+) {
+    thread::spawn(move || loop {
+        let mut waited = Duration::ZERO;
+        while waited < interval {
+            if control.status() == SessionStatus::Stopped {
+                return;
+            }
+            thread::sleep(STATUS_POLL_INTERVAL);
+            waited += STATUS_POLL_INTERVAL;
+        }
+        let watermark = watermark.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let result = attest(&chain_id, &public_key, &watermark, None).and_then(|attestation_doc| {
+            push_attestation(
+                port,
+                mux_port,
+                &NitroReattestation {
+                    chain_id: chain_id.clone(),
+                    attestation_doc,
+                },
+            )
+        });
+        match result {
+            Ok(()) => debug!("[{}] runtime attestation pushed", chain_id),
+            Err(e) => warn!("[{}] runtime attestation failed: {}", chain_id, e),
+        }
+    });
+}
+
+/// reports the final watermark of the session stopped at `max_height` to the host
+/// (attested, unless the attestation document can't be obtained)
+pub fn report_halt(
+    port: u32,
+    mux_port: Option<u32>,
+    chain_id: &chain::Id,
+    public_key: &VerificationKey,
+    max_height: u64,
+    watermark: &consensus::State,
+) -> Result<(), String> {
+    let attestation_doc = match attest(chain_id, public_key, watermark, None) {
+        Ok(doc) => Some(doc),
+        Err(e) => {
+            warn!("[{}] final watermark attestation failed: {}", chain_id, e);
+            None
+        }
+    };
+    let report = NitroHaltReport {
+        chain_id: chain_id.clone(),
+        max_height,
+        height: watermark.height.value(),
+        round: watermark.round.value(),
+        step: watermark.step,
+        attestation_doc,
+    };
+    let json_raw = serde_json::to_vec(&report).map_err(|e| e.to_string())?;
+    let mut conn = connect_channel(mux_port, port).map_err(|e| e.to_string())?;
+    write_u16_payload(&mut conn, &json_raw).map_err(|e| e.to_string())?;
+    info!("[{}] final watermark {} reported", chain_id, watermark);
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use ed25519_consensus::SigningKey;
+use std::io;
+use std::time::{Duration, Instant};
+use tmkms_light::audit::{
+    chain_head, AuditAnchor, AuditEntry, AuditSink, RefusedMessage, SignedMessage,
+};
+use tmkms_light::error::Error;
+use tmkms_light::utils::{read_u16_payload, write_u16_payload};
+use tmkms_nitro_helper::channel::ChannelStream;
+use tmkms_nitro_helper::mux::connect_channel;
+use tracing::{info, warn};
+
+/// the chain's head is anchored after this many records...
+const ANCHOR_RECORDS: u64 = 100;
+/// ... or this long after the previous anchor (if there were records since)
+const ANCHOR_INTERVAL: Duration = Duration::from_secs(60);
+
+/// sends the signature records to be appended to the audit log on the host,
+/// and periodically the signed head of its chain of the sent records (so the host
+/// can't alter or drop the anchored records without it being detected)
+pub struct AuditHolder {
+    vsock_port: u32,
+    mux_port: Option<u32>,
+    /// reconnected on the next record after an I/O error
+    audit_conn: Option<ChannelStream>,
+    signing_key: SigningKey,
+    /// the head of the previous anchor (zeros on a new connection)
+    start: [u8; 32],
+    head: [u8; 32],
+    /// the records since the previous anchor
+    records: u64,
+    last_anchor: Instant,
+}
+
+impl AuditHolder {
+    /// connects to the host via the vsock port specified in the configuration;
+    /// the anchors are signed with the key
+    pub fn new(
+        vsock_port: u32,
+        mux_port: Option<u32>,
+        signing_key: SigningKey,
+    ) -> io::Result<Self> {
+        let audit_conn = connect_channel(mux_port, vsock_port)?;
+        Ok(Self {
+            vsock_port,
+            mux_port,
+            audit_conn: Some(audit_conn),
+            signing_key,
+            start: [0; 32],
+            head: [0; 32],
+            records: 0,
+            last_anchor: Instant::now(),
+        })
+    }
+
+    /// the connection to the host (reconnected with a new chain if it was lost)
+    fn connection(&mut self) -> Result<&mut ChannelStream, Error> {
+        if self.audit_conn.is_none() {
+            let audit_conn = connect_channel(self.mux_port, self.vsock_port)
+                .map_err(|e| Error::io_error("failed to reconnect the audit log".into(), e))?;
+            info!("audit connection re-established");
+            self.start = [0; 32];
+            self.head = [0; 32];
+            self.records = 0;
+            self.last_anchor = Instant::now();
+            self.audit_conn = Some(audit_conn);
+        }
+        Ok(self.audit_conn.as_mut().expect("connected"))
+    }
+
+    /// waits for the host to acknowledge the entry (the connection is dropped on errors)
+    fn send_entry(&mut self, entry: &AuditEntry) -> Result<(), Error> {
+        let result = Self::exchange(self.connection()?, entry);
+        if result.is_err() {
+            self.audit_conn = None;
+        }
+        result
+    }
+
+    fn exchange(audit_conn: &mut ChannelStream, entry: &AuditEntry) -> Result<(), Error> {
+        let json_raw = serde_json::to_vec(entry).map_err(Error::serialization_error)?;
+        write_u16_payload(audit_conn, &json_raw)
+            .map_err(|e| Error::io_error("failed to send the audit record".into(), e))?;
+        let ack_raw = read_u16_payload(audit_conn)?;
+        let ack: Result<(), String> =
+            serde_json::from_slice(&ack_raw).map_err(Error::serialization_error)?;
+        ack.map_err(|e| {
+            Error::io_error(
+                "the audit record wasn't persisted".into(),
+                io::Error::new(io::ErrorKind::Other, e),
+            )
+        })
+    }
+
+    /// sends the record and chains it; anchors the chain's head when it's due
+    fn send(&mut self, entry: AuditEntry, digest: [u8; 32]) -> Result<(), Error> {
+        self.send_entry(&entry)?;
+        self.head = chain_head(&self.head, &digest);
+        self.records += 1;
+        if self.records >= ANCHOR_RECORDS || self.last_anchor.elapsed() >= ANCHOR_INTERVAL {
+            let anchor = AuditAnchor::sign(&self.signing_key, self.start, self.head, self.records);
+            // the record itself was persisted: it's reported as not anchored
+            match self.send_entry(&AuditEntry::Anchor(anchor)) {
+                Ok(()) => {
+                    self.start = self.head;
+                    self.records = 0;
+                    self.last_anchor = Instant::now();
+                }
+                Err(e) => warn!("failed to anchor the audit records: {}", e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AuditSink for AuditHolder {
+    fn record(&mut self, msg: &SignedMessage) -> Result<(), Error> {
+        self.send(AuditEntry::Signed(msg.clone()), msg.digest())
+    }
+
+    fn record_refusal(&mut self, msg: &RefusedMessage) -> Result<(), Error> {
+        self.send(AuditEntry::Refused(msg.clone()), msg.digest())
+    }
+}
@@ -0,0 +1,81 @@
+use super::platform::nsm_process_request;
+use super::write_response;
+use aws_nitro_enclaves_nsm_api::api::{Request, Response};
+use rand_core::{OsRng, RngCore};
+use serde_bytes::ByteBuf;
+use tmkms_light::error::{io_error_wrap, Error};
+use tmkms_light::utils::read_u16_payload_with_limits;
+use tmkms_nitro_helper::channel::ChannelStream;
+use tmkms_nitro_helper::codec;
+use tmkms_nitro_helper::config_push::{open_config, SealedConfig};
+use tmkms_nitro_helper::key_shares::x25519_public_key;
+use tmkms_nitro_helper::{NitroAttestResult, NitroConfig, NitroError, NitroRequest};
+use tracing::{error, info};
+use zeroize::Zeroizing;
+
+/// attests a one-time X25519 key (in the attestation's `public_key`) and receives
+/// the payload encrypted to it (the key never leaves this request's handling)
+pub fn receive_sealed(
+    nsm_fd: i32,
+    stream: &mut ChannelStream,
+    nonce: Vec<u8>,
+) -> Result<Zeroizing<Vec<u8>>, Error> {
+    let mut secret = Zeroizing::new([0u8; 32]);
+    OsRng.fill_bytes(&mut secret[..]);
+    let req = Request::Attestation {
+        user_data: None,
+        nonce: Some(ByteBuf::from(nonce)),
+        public_key: Some(ByteBuf::from(x25519_public_key(&secret).to_vec())),
+    };
+    let response: NitroAttestResult = match nsm_process_request(nsm_fd, req) {
+        Response::Attestation { document } => Ok(ByteBuf::from(document)),
+        _ => Err(NitroError::attestation_failed()),
+    };
+    let attested = response.is_ok();
+    write_response(stream, &response, "one-time key attestation")?;
+    if !attested {
+        error!("failed to attest the one-time key");
+        return Err(Error::access_error());
+    }
+    let sealed_raw = read_u16_payload_with_limits(stream, &super::policy::request_limits())?;
+    let sealed: SealedConfig = codec::decode(&sealed_raw)
+        .map_err(|e| io_error_wrap("invalid encrypted payload".into(), e))?;
+    open_config(&sealed, &secret).map_err(|e| {
+        error!("{}", e);
+        Error::access_error()
+    })
+}
+
+/// receives the start config encrypted to an attested one-time key
+pub fn receive(
+    nsm_fd: i32,
+    stream: &mut ChannelStream,
+    nonce: Vec<u8>,
+) -> Result<Box<NitroConfig>, Error> {
+    let config_raw = receive_sealed(nsm_fd, stream, nonce)?;
+    info!("received the encrypted config");
+    codec::decode(&config_raw).map_err(|e| io_error_wrap("invalid config".into(), e))
+}
+
+/// receives a request encrypted to an attested one-time key
+/// (not the connection's negotiation, binding or another wrapped request)
+pub fn receive_request(
+    nsm_fd: i32,
+    stream: &mut ChannelStream,
+    nonce: Vec<u8>,
+) -> Result<NitroRequest, Error> {
+    let request_raw = receive_sealed(nsm_fd, stream, nonce)?;
+    let request: NitroRequest =
+        codec::decode(&request_raw).map_err(|e| io_error_wrap("invalid request".into(), e))?;
+    match request {
+        NitroRequest::Hello(_)
+        | NitroRequest::StartSealed { .. }
+        | NitroRequest::Sealed { .. }
+        | NitroRequest::BindControl { .. }
+        | NitroRequest::Authenticated(_) => {
+            error!("refused a sealed request that can't be sealed");
+            Err(Error::access_error())
+        }
+        request => Ok(request),
+    }
+}
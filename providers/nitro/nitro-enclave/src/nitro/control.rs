@@ -0,0 +1,41 @@
+use super::config_push::receive_sealed;
+use super::write_response;
+use std::sync::Mutex;
+use tmkms_light::error::Error;
+use tmkms_nitro_helper::channel::ChannelStream;
+use tmkms_nitro_helper::control::ControlGuard;
+use tmkms_nitro_helper::{
+    NitroAttestResult, NitroBindControlResult, NitroError, NitroErrorCode, NitroRequest,
+};
+use tracing::{error, info};
+
+/// the control key bound by the helper (and the seen sequence numbers)
+static GUARD: Mutex<Option<ControlGuard>> = Mutex::new(None);
+
+fn with_guard<T>(f: impl FnOnce(&mut ControlGuard) -> T) -> T {
+    let mut guard = GUARD.lock().unwrap_or_else(|e| e.into_inner());
+    f(guard.get_or_insert_with(ControlGuard::default))
+}
+
+/// checks the request is authenticated (once a control key is bound) and unwraps it
+pub fn authorize(request: NitroRequest) -> Result<NitroRequest, NitroError> {
+    with_guard(|guard| guard.authorize(request))
+}
+
+/// binds the control key sent encrypted to an attested one-time key
+pub fn bind(nsm_fd: i32, stream: &mut ChannelStream, nonce: Vec<u8>) -> Result<(), Error> {
+    if with_guard(|guard| guard.is_bound()) {
+        let response: NitroAttestResult = Err(NitroError::new(
+            NitroErrorCode::AlreadyBound,
+            "the control key is already bound",
+        ));
+        return write_response(stream, &response, "bind");
+    }
+    let key = receive_sealed(nsm_fd, stream, nonce)?;
+    let response: NitroBindControlResult = with_guard(|guard| guard.bind(key));
+    match &response {
+        Ok(()) => info!("control key bound: the requests need to be authenticated"),
+        Err(e) => error!(alert = true, "failed to bind the control key: {}", e),
+    }
+    write_response(stream, &response, "bind")
+}
@@ -0,0 +1,35 @@
+use std::sync::Mutex;
+use tmkms_nitro_helper::{
+    AwsCredentials, NitroError, NitroErrorCode, NitroRefreshCredentialsResult,
+};
+use tracing::info;
+
+/// the latest AWS credentials pushed by the helper
+/// (the ones in the start-up configuration expire)
+static CREDENTIALS: Mutex<Option<AwsCredentials>> = Mutex::new(None);
+
+/// replaces the credentials used by the KMS calls
+pub fn set(credentials: AwsCredentials) {
+    *CREDENTIALS.lock().unwrap_or_else(|e| e.into_inner()) = Some(credentials);
+}
+
+/// the latest credentials (if any was pushed)
+pub fn current() -> Option<AwsCredentials> {
+    CREDENTIALS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+/// handles the helper's refresh request
+pub fn refresh(credentials: AwsCredentials) -> NitroRefreshCredentialsResult {
+    if credentials.aws_key_id.is_empty() || credentials.aws_secret_key.is_empty() {
+        return Err(NitroError::new(
+            NitroErrorCode::InvalidRequest,
+            "empty AWS credentials",
+        ));
+    }
+    set(credentials);
+    info!("AWS credentials refreshed");
+    Ok(())
+}
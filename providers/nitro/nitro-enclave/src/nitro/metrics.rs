@@ -0,0 +1,32 @@
+use std::io;
+use tmkms_light::metrics::{MetricsEvent, MetricsSink};
+use tmkms_light::utils::write_u16_payload;
+use tmkms_nitro_helper::channel::ChannelStream;
+use tmkms_nitro_helper::mux::connect_channel;
+use tracing::warn;
+
+/// sends the metrics events to the host (without waiting for an acknowledgement)
+pub struct MetricsHolder {
+    metrics_conn: ChannelStream,
+}
+
+impl MetricsHolder {
+    /// connects to the host via the vsock port specified in the configuration
+    pub fn new(vsock_port: u32, mux_port: Option<u32>) -> io::Result<Self> {
+        let metrics_conn = connect_channel(mux_port, vsock_port)?;
+        Ok(Self { metrics_conn })
+    }
+}
+
+impl MetricsSink for MetricsHolder {
+    fn record(&mut self, event: &MetricsEvent) {
+        let result = serde_json::to_vec(event)
+            .map_err(|e| e.to_string())
+            .and_then(|json_raw| {
+                write_u16_payload(&mut self.metrics_conn, &json_raw).map_err(|e| e.to_string())
+            });
+        if let Err(e) = result {
+            warn!("failed to send a metrics event: {}", e);
+        }
+    }
+}
@@ -0,0 +1,355 @@
+//! The Nitro platform calls (NSM and AWS KMS via `aws-ne-sys`) or their stand-ins
+//! in the local development mode (`--dev-plaintext`): the "sealed" secrets are written
+//! to the key files in plaintext (behind a marker) and the attestation documents are unsigned.
+//! Without the `nsm` feature, only the development mode is available.
+
+use aws_nitro_enclaves_nsm_api::api::{AttestationDoc, Digest, ErrorCode, Request, Response};
+use rand_core::OsRng;
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tmkms_nitro_helper::backoff::{Backoff, BackoffConfig};
+use tracing::warn;
+
+/// prefixes the "sealed" secrets of the development mode
+/// (so that they're never mistaken for KMS ciphertexts and vice versa)
+const DEV_SEALED_MARKER: &[u8] = b"tmkms-dev-plaintext:";
+
+/// module ID of the development mode's attestation documents
+const DEV_MODULE_ID: &str = "dev-plaintext";
+
+/// retries of the failed AWS KMS calls (about 3 seconds in total before giving up)
+#[cfg_attr(not(feature = "nsm"), allow(dead_code))]
+const KMS_RETRY_BACKOFF: BackoffConfig = BackoffConfig {
+    initial_delay_ms: 200,
+    max_delay_ms: 1600,
+    multiplier: 2,
+    jitter_percent: 20,
+    max_retries: Some(4),
+    alert_after: None,
+};
+
+/// whether the development mode was enabled
+static DEV_PLAINTEXT: AtomicBool = AtomicBool::new(false);
+
+/// switches to the development mode (no NSM, no KMS)
+pub fn enable_dev_plaintext() {
+    DEV_PLAINTEXT.store(true, Ordering::Relaxed);
+}
+
+/// the development mode is on (always without the `nsm` feature)
+pub fn dev_plaintext() -> bool {
+    !cfg!(feature = "nsm") || DEV_PLAINTEXT.load(Ordering::Relaxed)
+}
+
+/// seeds the entropy of the AWS SDK from the NSM
+pub fn seed_entropy(bytes_to_seed: usize) -> Result<(), ()> {
+    #[cfg(feature = "nsm")]
+    if !dev_plaintext() {
+        return aws_ne_sys::seed_entropy(bytes_to_seed);
+    }
+    let _ = bytes_to_seed;
+    Ok(())
+}
+
+/// opens the NSM device (-1 in the development mode)
+pub fn nsm_init() -> i32 {
+    if dev_plaintext() {
+        -1
+    } else {
+        aws_nitro_enclaves_nsm_api::driver::nsm_init()
+    }
+}
+
+pub fn nsm_exit(fd: i32) {
+    if !dev_plaintext() {
+        aws_nitro_enclaves_nsm_api::driver::nsm_exit(fd)
+    }
+}
+
+/// random bytes of the NSM (none in the development mode or if the request fails)
+pub fn nsm_get_random(fd: i32) -> Option<Vec<u8>> {
+    if dev_plaintext() {
+        return None;
+    }
+    match aws_nitro_enclaves_nsm_api::driver::nsm_process_request(fd, Request::GetRandom) {
+        Response::GetRandom { random } if !random.is_empty() => Some(random),
+        _ => None,
+    }
+}
+
+/// processes the NSM request (in the development mode, only the attestations are supported
+/// and their documents are unsigned with zeroed PCRs, so they never match pinned measurements)
+pub fn nsm_process_request(fd: i32, request: Request) -> Response {
+    if !dev_plaintext() {
+        return aws_nitro_enclaves_nsm_api::driver::nsm_process_request(fd, request);
+    }
+    match request {
+        Request::Attestation {
+            user_data,
+            nonce,
+            public_key,
+        } => Response::Attestation {
+            document: dev_attestation_doc(user_data, nonce, public_key),
+        },
+        _ => Response::Error(ErrorCode::InvalidOperation),
+    }
+}
+
+/// an unsigned COSE_Sign1 structure with the attestation document
+fn dev_attestation_doc(
+    user_data: Option<ByteBuf>,
+    nonce: Option<ByteBuf>,
+    public_key: Option<ByteBuf>,
+) -> Vec<u8> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default();
+    let pcrs = (0..3).map(|i| (i, vec![0u8; 48])).collect();
+    let doc = AttestationDoc::new(
+        DEV_MODULE_ID.to_owned(),
+        Digest::SHA384,
+        timestamp,
+        pcrs,
+        vec![],
+        vec![],
+        user_data.map(ByteBuf::into_vec),
+        nonce.map(ByteBuf::into_vec),
+        public_key.map(ByteBuf::into_vec),
+    );
+    let cose_sign1 = (
+        ByteBuf::new(),
+        BTreeMap::<u8, u8>::new(),
+        ByteBuf::from(doc.to_binary()),
+        ByteBuf::new(),
+    );
+    serde_cbor::to_vec(&cose_sign1).expect("attestation document encoding")
+}
+
+/// a failed AWS KMS call
+#[derive(Debug)]
+#[cfg_attr(not(feature = "nsm"), allow(dead_code))]
+pub enum KmsError {
+    /// the SDK couldn't be set up with the call's parameters
+    /// (or, in the development mode, the key file isn't one of its own): not retried
+    Invalid(String),
+    /// the KMS client couldn't be created: retried
+    Unavailable(String),
+    /// KMS didn't fulfil the call: throttled, a 5xx or denied access alike
+    /// (the SDK doesn't report which), so it's retried even if it can't succeed
+    Rejected(String),
+    /// the retry budget was exhausted
+    Exhausted { attempts: u32, last: Box<KmsError> },
+}
+
+impl KmsError {
+    /// `aws_ne_sys` only returns which step failed (it drops the SDK's error code and the HTTP status),
+    /// so a denied decryption or encryption can't be told apart from a throttled one
+    #[cfg(feature = "nsm")]
+    fn from_sdk(e: aws_ne_sys::Error) -> Self {
+        use aws_ne_sys::Error;
+        match e {
+            Error::SdkKmsClientError => KmsError::Unavailable(format!("{:?}", e)),
+            Error::SdkKmsDecryptError | Error::SdkKmsEncryptError => {
+                KmsError::Rejected(format!("{:?}", e))
+            }
+            _ => KmsError::Invalid(format!("{:?}", e)),
+        }
+    }
+
+    /// the failure may be transient
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, KmsError::Unavailable(_) | KmsError::Rejected(_))
+    }
+
+    /// KMS couldn't be reached or kept failing the call until the retries were exhausted
+    /// (as opposed to a call that couldn't be made)
+    pub fn is_unavailable(&self) -> bool {
+        self.is_retryable() || matches!(self, KmsError::Exhausted { .. })
+    }
+}
+
+impl fmt::Display for KmsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KmsError::Invalid(e) => write!(f, "invalid KMS call: {}", e),
+            KmsError::Unavailable(e) => write!(f, "KMS unavailable: {}", e),
+            KmsError::Rejected(e) => write!(f, "KMS call failed: {}", e),
+            KmsError::Exhausted { attempts, last } => write!(
+                f,
+                "{} (gave up after {} attempts: if it persists, check that the key policy allows \
+                 the credentials and the enclave's PCRs; otherwise the KMS requests are throttled \
+                 or KMS is unavailable in the region)",
+                last, attempts
+            ),
+        }
+    }
+}
+
+/// makes the KMS call until it succeeds, fails with a non-retryable error
+/// or the retry budget is exhausted
+#[cfg_attr(not(feature = "nsm"), allow(dead_code))]
+fn with_retries<T>(
+    config: BackoffConfig,
+    operation: &str,
+    mut call: impl FnMut() -> Result<T, KmsError>,
+) -> Result<T, KmsError> {
+    let mut backoff = Backoff::new(config);
+    loop {
+        match call() {
+            Ok(result) => return Ok(result),
+            Err(e) if e.is_retryable() => match backoff.next_delay(&mut OsRng) {
+                Some(delay) => {
+                    warn!("{} failed ({}), retrying in {:?}", operation, e, delay);
+                    thread::sleep(delay);
+                }
+                None => {
+                    return Err(KmsError::Exhausted {
+                        attempts: backoff.failures(),
+                        last: Box::new(e),
+                    })
+                }
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// encrypts the secret with the AWS KMS key (or marks it as plaintext in the development mode)
+pub fn kms_encrypt(
+    aws_region: &[u8],
+    aws_key_id: &[u8],
+    aws_secret_key: &[u8],
+    aws_session_token: &[u8],
+    aws_kms_key_id: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, KmsError> {
+    #[cfg(feature = "nsm")]
+    if !dev_plaintext() {
+        return with_retries(KMS_RETRY_BACKOFF, "KMS encryption", || {
+            aws_ne_sys::kms_encrypt(
+                aws_region,
+                aws_key_id,
+                aws_secret_key,
+                aws_session_token,
+                aws_kms_key_id,
+                plaintext,
+            )
+            .map_err(KmsError::from_sdk)
+        });
+    }
+    let _ = (
+        aws_region,
+        aws_key_id,
+        aws_secret_key,
+        aws_session_token,
+        aws_kms_key_id,
+    );
+    Ok([DEV_SEALED_MARKER, plaintext].concat())
+}
+
+/// decrypts the AWS KMS ciphertext (or unmarks the plaintext in the development mode)
+pub fn kms_decrypt(
+    aws_region: &[u8],
+    aws_key_id: &[u8],
+    aws_secret_key: &[u8],
+    aws_session_token: &[u8],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, KmsError> {
+    #[cfg(feature = "nsm")]
+    if !dev_plaintext() {
+        return with_retries(KMS_RETRY_BACKOFF, "KMS decryption", || {
+            aws_ne_sys::kms_decrypt(
+                aws_region,
+                aws_key_id,
+                aws_secret_key,
+                aws_session_token,
+                ciphertext,
+            )
+            .map_err(KmsError::from_sdk)
+        });
+    }
+    let _ = (aws_region, aws_key_id, aws_secret_key, aws_session_token);
+    ciphertext
+        .strip_prefix(DEV_SEALED_MARKER)
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| KmsError::Invalid("not a development mode key file".to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dev_sealing_and_attestation() {
+        enable_dev_plaintext();
+        let sealed = kms_encrypt(b"", b"", b"", b"", b"key", b"secret").unwrap();
+        assert_ne!(sealed, b"secret");
+        assert_eq!(kms_decrypt(b"", b"", b"", b"", &sealed).unwrap(), b"secret");
+        assert!(kms_decrypt(b"", b"", b"", b"", b"secret").is_err());
+
+        let response = nsm_process_request(
+            nsm_init(),
+            Request::Attestation {
+                user_data: Some(ByteBuf::from(b"claim".to_vec())),
+                nonce: Some(ByteBuf::from(vec![1u8; 32])),
+                public_key: None,
+            },
+        );
+        let document = match response {
+            Response::Attestation { document } => document,
+            _ => panic!("expected an attestation"),
+        };
+        let (_, _, payload, _): (ByteBuf, serde_cbor::Value, ByteBuf, ByteBuf) =
+            serde_cbor::from_slice(&document).unwrap();
+        let doc = AttestationDoc::from_binary(&payload).unwrap();
+        assert_eq!(doc.module_id, DEV_MODULE_ID);
+        assert_eq!(doc.user_data.unwrap().as_slice(), b"claim");
+        assert_eq!(doc.nonce.unwrap().as_slice(), &[1u8; 32]);
+    }
+
+    #[test]
+    fn kms_calls_are_retried_until_the_budget_is_exhausted() {
+        let config = BackoffConfig {
+            initial_delay_ms: 1,
+            max_delay_ms: 1,
+            max_retries: Some(2),
+            ..KMS_RETRY_BACKOFF
+        };
+        let mut calls = 0;
+        let result = with_retries(config.clone(), "test", || {
+            calls += 1;
+            if calls < 3 {
+                Err(KmsError::Rejected("SdkKmsDecryptError".to_owned()))
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        let mut calls = 0;
+        let result: Result<(), _> = with_retries(config.clone(), "test", || {
+            calls += 1;
+            Err(KmsError::Unavailable("no connection".to_owned()))
+        });
+        assert!(matches!(
+            result,
+            Err(KmsError::Exhausted { attempts: 3, .. })
+        ));
+        assert!(result.unwrap_err().is_unavailable());
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let result: Result<(), _> = with_retries(config, "test", || {
+            calls += 1;
+            Err(KmsError::Invalid("bad region".to_owned()))
+        });
+        assert!(matches!(result, Err(KmsError::Invalid(_))));
+        assert!(!result.unwrap_err().is_unavailable());
+        assert_eq!(calls, 1);
+    }
+}
@@ -0,0 +1,98 @@
+use ed25519_consensus::VerificationKey;
+use std::path::Path;
+use std::sync::Mutex;
+use tmkms_light::policy::ApprovalPolicy;
+use tmkms_light::utils::PayloadLimits;
+use tmkms_nitro_helper::enclave_policy::EnclavePolicy;
+use tmkms_nitro_helper::{NitroError, NitroErrorCode};
+use tracing::info;
+
+/// the policy baked into the enclave image (loaded at startup)
+static POLICY: Mutex<Option<EnclavePolicy>> = Mutex::new(None);
+
+/// loads the policy file (at startup)
+pub fn load(path: &Path) -> Result<(), String> {
+    let policy = EnclavePolicy::load(path)?;
+    info!(
+        "enclave policy {} ({} backup operators, key shares {})",
+        policy.digest(),
+        policy.backup_operators.len(),
+        if policy.key_shares.is_some() {
+            "allowed"
+        } else {
+            "not allowed"
+        }
+    );
+    *POLICY.lock().unwrap_or_else(|e| e.into_inner()) = Some(policy);
+    Ok(())
+}
+
+/// the loaded policy (the default one, which allows nothing, if it wasn't loaded)
+pub fn current() -> EnclavePolicy {
+    POLICY
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+        .unwrap_or_default()
+}
+
+/// the limits of the helper's requests read on the control connections
+pub fn request_limits() -> PayloadLimits {
+    current().request_limits()
+}
+
+/// the limits of the states and acknowledgements read from the host
+pub fn state_limits() -> PayloadLimits {
+    current().state_limits()
+}
+
+/// the pinned key of the monotonic state service
+pub fn monotonic_service_key() -> Result<VerificationKey, String> {
+    current().monotonic_service_key()
+}
+
+/// the pinned key of the approver
+pub fn approver_key() -> Result<VerificationKey, String> {
+    current().approver_key()
+}
+
+/// the approval rule of the policy combined with the signing policy's one
+/// (the host can only require more approvals)
+pub fn approval(signing_policy: Option<&ApprovalPolicy>) -> Option<ApprovalPolicy> {
+    match (current().approval, signing_policy) {
+        (Some(pinned), Some(other)) => Some(pinned.union(other)),
+        (pinned, other) => pinned.or_else(|| other.cloned()),
+    }
+}
+
+/// checks the backup of the consensus key is allowed
+pub fn check_backup(threshold: u8, recipients: &[[u8; 32]]) -> Result<(), NitroError> {
+    current()
+        .check_backup(threshold, recipients)
+        .map_err(|e| NitroError::new(NitroErrorCode::PolicyDenied, e))
+}
+
+/// verifies the cosigner enclaves' attestations and checks the split into threshold shares
+/// for them is allowed; returns their attested keys
+pub fn check_key_shares<A: AsRef<[u8]>>(
+    threshold: u16,
+    recipient_attestations: &[A],
+) -> Result<Vec<[u8; 32]>, NitroError> {
+    current()
+        .check_key_shares(threshold, recipient_attestations)
+        .map_err(|e| NitroError::new(NitroErrorCode::PolicyDenied, e))
+}
+
+/// checks the signing of a (domain-separated) payload is allowed
+pub fn check_payload_signing() -> Result<(), NitroError> {
+    current()
+        .check_payload_signing()
+        .map_err(|e| NitroError::new(NitroErrorCode::PolicyDenied, e))
+}
+
+/// checks the migration of a state without a MAC is allowed
+pub fn check_state_migration() -> Result<(), NitroError> {
+    current()
+        .check_state_migration()
+        .map_err(|e| NitroError::new(NitroErrorCode::PolicyDenied, e))
+}
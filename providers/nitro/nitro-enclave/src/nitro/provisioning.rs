@@ -0,0 +1,183 @@
+use super::platform::{self, nsm_process_request};
+use aws_nitro_enclaves_nsm_api::api::{Request, Response};
+use ed25519_consensus::SigningKey;
+use rand_core::{OsRng, RngCore};
+use serde_bytes::ByteBuf;
+use std::sync::Mutex;
+use tmkms_nitro_helper::backup::{combine_shares, decrypt_share};
+use tmkms_nitro_helper::key_shares::x25519_public_key;
+use tmkms_nitro_helper::provisioning::open_mnemonic;
+use tmkms_nitro_helper::slip10::derive_ed25519;
+use tmkms_nitro_helper::{
+    NitroAttestResult, NitroError, NitroErrorCode, NitroKeygenResponse, NitroMnemonicConfig,
+    NitroResponse, NitroRestoreConfig,
+};
+use tracing::info;
+use zeroize::{Zeroize, Zeroizing};
+
+/// the X25519 secret of the pending provisioning (used once)
+static PENDING: Mutex<Option<Zeroizing<[u8; 32]>>> = Mutex::new(None);
+
+/// generates the one-time key and attests it (in the attestation's `public_key`)
+pub fn begin(nsm_fd: i32, nonce: Vec<u8>) -> NitroAttestResult {
+    let mut secret = Zeroizing::new([0u8; 32]);
+    OsRng.fill_bytes(&mut secret[..]);
+    let public_key = x25519_public_key(&secret);
+    let req = Request::Attestation {
+        user_data: None,
+        nonce: Some(ByteBuf::from(nonce)),
+        public_key: Some(ByteBuf::from(public_key.to_vec())),
+    };
+    match nsm_process_request(nsm_fd, req) {
+        Response::Attestation { document } => {
+            *PENDING.lock().unwrap_or_else(|e| e.into_inner()) = Some(secret);
+            info!("mnemonic provisioning started");
+            Ok(ByteBuf::from(document))
+        }
+        _ => Err(NitroError::attestation_failed()),
+    }
+}
+
+/// the pending provisioning's X25519 secret (it can only be used once)
+fn take_pending() -> Result<Zeroizing<[u8; 32]>, NitroError> {
+    PENDING
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .take()
+        .ok_or_else(|| {
+            NitroError::new(
+                NitroErrorCode::InvalidRequest,
+                "no provisioning in progress",
+            )
+        })
+}
+
+/// decrypts the mnemonic, seals its seed and attests the consensus key derived from it
+pub fn finish(nsm_fd: i32, config: &NitroMnemonicConfig) -> NitroResponse {
+    let secret = take_pending()?;
+    if x25519_public_key(&secret) != config.sealed_mnemonic.recipient {
+        return Err(NitroError::new(
+            NitroErrorCode::InvalidRequest,
+            "the mnemonic isn't encrypted to the provisioning key",
+        ));
+    }
+    let seed = open_mnemonic(&config.sealed_mnemonic, &secret).map_err(|e| {
+        NitroError::new(
+            NitroErrorCode::BadKey,
+            format!("failed to open the mnemonic: {:?}", e),
+        )
+    })?;
+    let mut keypair = SigningKey::from(*derive_ed25519(&seed[..], &config.derivation_path));
+    let public = keypair.verification_key();
+    keypair.zeroize();
+    let encrypted_secret = platform::kms_encrypt(
+        config.aws_region.as_bytes(),
+        config.credentials.aws_key_id.as_bytes(),
+        config.credentials.aws_secret_key.as_bytes(),
+        config.credentials.aws_session_token.as_bytes(),
+        config.kms_key_id.as_bytes(),
+        &seed[..],
+    )
+    .map_err(super::kms_encrypt_error)?;
+    let pubkeyb64 = String::from_utf8(subtle_encoding::base64::encode(public))
+        .map_err(super::internal_error)?;
+    let keyidb64 = String::from_utf8(subtle_encoding::base64::encode(&config.kms_key_id))
+        .map_err(super::internal_error)?;
+    let claim = format!(
+        "{{\"pubkey\":\"{}\",\"key_id\":\"{}\",\"purpose\":\"consensus\",\"path\":\"{}\"}}",
+        pubkeyb64, keyidb64, config.derivation_path
+    );
+    let req = Request::Attestation {
+        user_data: Some(ByteBuf::from(claim)),
+        nonce: Some(ByteBuf::from(config.nonce.clone())),
+        public_key: None,
+    };
+    match nsm_process_request(nsm_fd, req) {
+        Response::Attestation { document } => Ok(NitroKeygenResponse {
+            encrypted_secret,
+            public_key: public.as_bytes().to_vec(),
+            attestation_doc: document,
+        }),
+        _ => Err(NitroError::attestation_failed()),
+    }
+}
+
+/// reassembles the consensus key (or master seed) from the backup shares, checks it's
+/// the backed up key, seals it and attests it
+pub fn restore(nsm_fd: i32, config: &NitroRestoreConfig) -> NitroResponse {
+    let secret = take_pending()?;
+    let own_key = x25519_public_key(&secret);
+    let shares = config
+        .shares
+        .iter()
+        .map(|share| {
+            if share.recipient != own_key {
+                return Err(NitroError::new(
+                    NitroErrorCode::InvalidRequest,
+                    format!(
+                        "share {} isn't encrypted to the provisioning key",
+                        share.index
+                    ),
+                ));
+            }
+            decrypt_share(share, &secret)
+                .map(|plaintext| (share.index, plaintext))
+                .map_err(|e| {
+                    NitroError::new(
+                        NitroErrorCode::BadKey,
+                        format!("failed to open share {}: {:?}", share.index, e),
+                    )
+                })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let restored = combine_shares(config.backup.threshold, &shares).map_err(|e| {
+        NitroError::new(
+            NitroErrorCode::BadKey,
+            format!("failed to combine the shares: {:?}", e),
+        )
+    })?;
+    let mut keypair = super::signing_key(&restored, config.backup.derivation_path.as_ref())
+        .map_err(super::key_error)?;
+    let public = keypair.verification_key();
+    keypair.zeroize();
+    if public.to_bytes() != config.backup.public_key {
+        return Err(NitroError::new(
+            NitroErrorCode::BadKey,
+            "the shares don't restore the backed up key",
+        ));
+    }
+    let encrypted_secret = platform::kms_encrypt(
+        config.aws_region.as_bytes(),
+        config.credentials.aws_key_id.as_bytes(),
+        config.credentials.aws_secret_key.as_bytes(),
+        config.credentials.aws_session_token.as_bytes(),
+        config.kms_key_id.as_bytes(),
+        &restored,
+    )
+    .map_err(super::kms_encrypt_error)?;
+    let pubkeyb64 = String::from_utf8(subtle_encoding::base64::encode(public))
+        .map_err(super::internal_error)?;
+    let keyidb64 = String::from_utf8(subtle_encoding::base64::encode(&config.kms_key_id))
+        .map_err(super::internal_error)?;
+    let path = match &config.backup.derivation_path {
+        Some(path) => format!(",\"path\":\"{}\"", path),
+        None => String::new(),
+    };
+    let claim = format!(
+        "{{\"pubkey\":\"{}\",\"key_id\":\"{}\",\"purpose\":\"consensus\"{}}}",
+        pubkeyb64, keyidb64, path
+    );
+    let req = Request::Attestation {
+        user_data: Some(ByteBuf::from(claim)),
+        nonce: Some(ByteBuf::from(config.nonce.clone())),
+        public_key: None,
+    };
+    match nsm_process_request(nsm_fd, req) {
+        Response::Attestation { document } => Ok(NitroKeygenResponse {
+            encrypted_secret,
+            public_key: public.as_bytes().to_vec(),
+            attestation_doc: document,
+        }),
+        _ => Err(NitroError::attestation_failed()),
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use tendermint::chain;
+use tmkms_light::session::{SessionControl, SessionStatus};
+use tmkms_nitro_helper::{
+    ChainControlAction, NitroChainControl, NitroChainStatus, NitroChainStatusResult, NitroError,
+    NitroErrorCode, NitroShutdownResult,
+};
+use tracing::{info, warn};
+
+/// how long a drained session's in-flight request is waited for
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// controls of the chain sessions running in the enclave
+static SESSIONS: Mutex<BTreeMap<chain::Id, SessionControl>> = Mutex::new(BTreeMap::new());
+
+fn sessions() -> MutexGuard<'static, BTreeMap<chain::Id, SessionControl>> {
+    SESSIONS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// registers the session of the chain (fails if the chain already has an active session)
+pub fn register(chain_id: &chain::Id, control: SessionControl) -> Result<(), String> {
+    let mut sessions = sessions();
+    if sessions.contains_key(chain_id) {
+        return Err(format!("chain {} already has a session", chain_id));
+    }
+    sessions.insert(chain_id.clone(), control);
+    Ok(())
+}
+
+/// whether the chain has an active session
+pub fn is_registered(chain_id: &chain::Id) -> bool {
+    sessions().contains_key(chain_id)
+}
+
+/// removes the stopped session of the chain
+pub fn unregister(chain_id: &chain::Id) {
+    sessions().remove(chain_id);
+}
+
+/// pauses, resumes, stops, drains or puts into maintenance the chain's session
+/// (or allows its next height jump)
+pub fn control(request: &NitroChainControl) -> NitroChainStatusResult {
+    // not holding the sessions' lock while a session is drained
+    let control = sessions().get(&request.chain_id).cloned().ok_or_else(|| {
+        NitroError::new(
+            NitroErrorCode::UnknownChain,
+            format!("chain {} has no session", request.chain_id),
+        )
+    })?;
+    let status = match request.action {
+        ChainControlAction::Pause | ChainControlAction::Drain => SessionStatus::Paused,
+        ChainControlAction::Maintenance => SessionStatus::Maintenance,
+        ChainControlAction::Resume => SessionStatus::Running,
+        ChainControlAction::Stop => SessionStatus::Stopped,
+        ChainControlAction::AllowHeightJump => control.status(),
+    };
+    if control.status() == SessionStatus::Stopped {
+        return Err(NitroError::new(
+            NitroErrorCode::UnknownChain,
+            format!("chain {} session is stopping", request.chain_id),
+        ));
+    }
+    if let ChainControlAction::Drain = request.action {
+        if !control.drain(DRAIN_TIMEOUT) {
+            return Err(NitroError::new(
+                NitroErrorCode::Internal,
+                format!(
+                    "chain {} session is paused, but its in-flight request wasn't handled in time",
+                    request.chain_id
+                ),
+            ));
+        }
+        info!("[{}] session drained", request.chain_id);
+    }
+    if let ChainControlAction::AllowHeightJump = request.action {
+        control.allow_next_height_jump();
+        warn!("[{}] the next height jump is allowed", request.chain_id);
+    }
+    control.set_status(status);
+    info!("[{}] session status: {:?}", request.chain_id, status);
+    Ok(vec![NitroChainStatus {
+        chain_id: request.chain_id.clone(),
+        status,
+    }])
+}
+
+/// status of all chains' sessions
+pub fn statuses() -> Vec<NitroChainStatus> {
+    sessions()
+        .iter()
+        .map(|(chain_id, control)| NitroChainStatus {
+            chain_id: chain_id.clone(),
+            status: control.status(),
+        })
+        .collect()
+}
+
+/// stops all sessions and waits until they've finished (their in-flight requests)
+/// and unregistered
+pub fn shutdown(timeout: Duration) -> NitroShutdownResult {
+    for (chain_id, control) in sessions().iter() {
+        info!("[{}] stopping the session", chain_id);
+        control.set_status(SessionStatus::Stopped);
+    }
+    let started = Instant::now();
+    loop {
+        let remaining = sessions().len();
+        if remaining == 0 {
+            return Ok(());
+        }
+        if started.elapsed() >= timeout {
+            warn!("{} sessions didn't stop in time", remaining);
+            return Err(NitroError::new(
+                NitroErrorCode::Internal,
+                format!("{} sessions didn't stop in time", remaining),
+            ));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
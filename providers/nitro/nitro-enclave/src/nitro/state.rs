@@ -1,53 +1,135 @@
+use super::{policy, sessions};
+use ed25519_consensus::Signature;
+use std::collections::BTreeSet;
 use std::io;
 use std::os::unix::io::AsRawFd;
-use tmkms_light::chain::state::{consensus, PersistStateSync, State, StateError};
-use tmkms_light::utils::{read_u16_payload, write_u16_payload};
-use tmkms_nitro_helper::VSOCK_HOST_CID;
+use std::sync::Mutex;
+use tendermint::chain;
+use tmkms_light::chain::state::{
+    consensus, MacedState, PersistStateSync, State, StateError, StateMacKey,
+};
+use tmkms_light::connection::ConnectionTimeouts;
+use tmkms_light::utils::{read_u16_payload_into, write_u16_payload, PayloadLimits};
+use tmkms_nitro_helper::channel::ChannelStream;
+use tmkms_nitro_helper::mux::connect_channel;
 use tracing::{debug, trace};
-use vsock::{VsockAddr, VsockStream};
+
+/// connects to the host via the provided vsock port (or its channel of the multiplexed port)
+pub fn host_connection(
+    vsock_port: u32,
+    mux_port: Option<u32>,
+    timeouts: &ConnectionTimeouts,
+) -> io::Result<ChannelStream> {
+    let conn = connect_channel(mux_port, vsock_port)?;
+    conn.set_read_timeout(timeouts.read())?;
+    conn.set_write_timeout(timeouts.write())?;
+    Ok(conn)
+}
+
+/// the chains whose state was migrated in this enclave run
+static MIGRATED: Mutex<BTreeSet<chain::Id>> = Mutex::new(BTreeSet::new());
+
+/// records the chain's state migration (refused if it already was migrated in this enclave run
+/// or if the chain has an active session)
+pub fn begin_migration(chain_id: &chain::Id) -> Result<(), String> {
+    let mut migrated = MIGRATED.lock().unwrap_or_else(|e| e.into_inner());
+    if sessions::is_registered(chain_id) {
+        return Err(format!("chain {} has an active session", chain_id));
+    }
+    if !migrated.insert(chain_id.clone()) {
+        return Err(format!("the state of {} was already migrated", chain_id));
+    }
+    Ok(())
+}
 
 /// as the state needs to be persisted outside of NE,
 /// this is a helper that communicates with the host to load the latest state
 /// on the start up + to update it after each signing
-#[derive(Debug, Clone)]
+/// (the states are authenticated with a MAC, so the host can't alter them)
+#[derive(Debug)]
 pub struct StateHolder {
-    state_conn: VsockStream,
+    state_conn: ChannelStream,
+    mac_key: StateMacKey,
+    /// the enclave policy's limits of the states and acknowledgements read from the host
+    limits: PayloadLimits,
+    /// reused buffers of the state updates and acknowledgements
+    write_buf: Vec<u8>,
+    read_buf: Vec<u8>,
 }
 
 impl StateHolder {
     /// connects to the host via the vsock port specified in the configuration
-    pub fn new(vsock_port: u32) -> io::Result<Self> {
-        let addr = VsockAddr::new(VSOCK_HOST_CID, vsock_port);
-        let state_conn = vsock::VsockStream::connect(&addr)?;
+    pub fn new(
+        vsock_port: u32,
+        mux_port: Option<u32>,
+        timeouts: &ConnectionTimeouts,
+        mac_key: StateMacKey,
+    ) -> io::Result<Self> {
+        let state_conn = host_connection(vsock_port, mux_port, timeouts)?;
         trace!("state vsock port: {}", vsock_port);
         trace!("state peer addr: {:?}", state_conn.peer_addr());
         trace!("state local addr: {:?}", state_conn.local_addr());
         trace!("state fd: {}", state_conn.as_raw_fd());
-        Ok(Self { state_conn })
+        Ok(Self {
+            state_conn,
+            mac_key,
+            limits: policy::state_limits(),
+            write_buf: Vec::new(),
+            read_buf: Vec::new(),
+        })
     }
 }
 
 impl PersistStateSync for StateHolder {
-    /// loads the initial state
+    /// loads the initial state (and checks its MAC)
     fn load_state(&mut self) -> Result<State, StateError> {
-        let json_raw = read_u16_payload(&mut self.state_conn)
+        read_u16_payload_into(&mut self.state_conn, &self.limits, &mut self.read_buf)
             .map_err(|e| StateError::sync_other_error(e.to_string()))?;
-        let consensus_state: consensus::State = serde_json::from_slice(&json_raw)
+        let maced_state: MacedState = serde_json::from_slice(&self.read_buf)
             .map_err(|e| StateError::sync_enc_dec_error("vsock".into(), e))?;
-        Ok(State::from(consensus_state))
+        self.mac_key.verify(&maced_state)?;
+        Ok(State::from(maced_state))
     }
 
     /// sends the update state to be persisted on the host
     fn persist_state(&mut self, new_state: &consensus::State) -> Result<(), StateError> {
+        let maced_state = self.mac_key.sign(new_state)?;
+        self.send_state(&maced_state)
+    }
+
+    /// sends the update state with the signed message to be persisted on the host
+    fn persist_signed_state(
+        &mut self,
+        new_state: &consensus::State,
+        sign_bytes: &[u8],
+        signature: &Signature,
+    ) -> Result<(), StateError> {
+        let maced_state = self
+            .mac_key
+            .sign(new_state)?
+            .with_message(sign_bytes, signature);
+        self.send_state(&maced_state)
+    }
+}
+
+impl StateHolder {
+    fn send_state(&mut self, maced_state: &MacedState) -> Result<(), StateError> {
         trace!("writing new consensus state to state conn");
         trace!("state peer addr: {:?}", self.state_conn.peer_addr());
         trace!("state local addr: {:?}", self.state_conn.local_addr());
         trace!("state fd: {}", self.state_conn.as_raw_fd());
-        let json_raw = serde_json::to_vec(&new_state)
+        self.write_buf.clear();
+        serde_json::to_writer(&mut self.write_buf, maced_state)
             .map_err(|e| StateError::sync_enc_dec_error("vsock".into(), e))?;
 
-        write_u16_payload(&mut self.state_conn, &json_raw)
+        write_u16_payload(&mut self.state_conn, &self.write_buf)
             .map_err(|e| StateError::sync_error("vsock".into(), e))?;
+        // the state only counts as persisted once the sink acknowledges it
+        read_u16_payload_into(&mut self.state_conn, &self.limits, &mut self.read_buf)
+            .map_err(|e| StateError::sync_other_error(e.to_string()))?;
+        let ack: Result<(), String> = serde_json::from_slice(&self.read_buf)
+            .map_err(|e| StateError::sync_enc_dec_error("vsock".into(), e))?;
+        ack.map_err(StateError::sync_other_error)?;
 
         debug!("successfully wrote new consensus state to state connection");
 
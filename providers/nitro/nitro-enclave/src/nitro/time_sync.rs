@@ -0,0 +1,68 @@
+use rand_core::{OsRng, RngCore};
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tmkms_light::utils::{read_u16_payload, write_u16_payload};
+use tmkms_nitro_helper::channel::ChannelStream;
+use tmkms_nitro_helper::mux::connect_channel;
+use tmkms_nitro_helper::time_sync::{
+    SyncedClock, TimeSyncRequest, TimeSyncResponse, MAX_ROUND_TRIP,
+};
+use tmkms_nitro_helper::NitroTimeSync;
+use tracing::{debug, warn};
+
+/// requests the host's time and checks the answer
+fn sync(conn: &mut ChannelStream, key: &[u8], clock: &SyncedClock) -> Result<(), String> {
+    let mut request = TimeSyncRequest { nonce: [0u8; 16] };
+    OsRng.fill_bytes(&mut request.nonce);
+    let json_raw = serde_json::to_vec(&request).map_err(|e| format!("{:?}", e))?;
+    let sent = Instant::now();
+    write_u16_payload(conn, &json_raw).map_err(|e| format!("{:?}", e))?;
+    let json_raw = read_u16_payload(conn).map_err(|e| format!("{}", e))?;
+    let received = Instant::now();
+    let response: TimeSyncResponse = serde_json::from_slice(&json_raw)
+        .map_err(|e| format!("invalid time sync response: {:?}", e))?;
+    response.verify(key, &request)?;
+    clock.update(response.unix_nanos, sent, received)
+}
+
+fn connect(config: &NitroTimeSync, mux_port: Option<u32>) -> io::Result<ChannelStream> {
+    let conn = connect_channel(mux_port, config.port)?;
+    conn.set_read_timeout(Some(MAX_ROUND_TRIP))?;
+    Ok(conn)
+}
+
+/// synchronizes the clock once (so that it's known when the session starts, if the host answers)
+/// and then periodically in a separate thread
+pub fn launch(config: NitroTimeSync, mux_port: Option<u32>) -> Arc<SyncedClock> {
+    let clock = Arc::new(SyncedClock::default());
+    let synced = clock.clone();
+    let mut conn = None;
+    let mut sync_once = move || {
+        let result = match conn.as_mut() {
+            Some(c) => sync(c, &config.key, &synced),
+            None => connect(&config, mux_port)
+                .map_err(|e| format!("failed to connect: {:?}", e))
+                .and_then(|mut c| {
+                    let result = sync(&mut c, &config.key, &synced);
+                    conn = Some(c);
+                    result
+                }),
+        };
+        match result {
+            Ok(()) => debug!("clock synchronized"),
+            Err(e) => {
+                warn!("time synchronization failed: {}", e);
+                conn = None;
+            }
+        }
+        Duration::from_secs(config.interval_secs)
+    };
+    let interval = sync_once();
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        sync_once();
+    });
+    clock
+}
@@ -0,0 +1,282 @@
+//! admin control socket: operators pause, resume, drain the signing sessions (e.g. for maintenance)
+//! or get their statuses via a unix socket of the running helper, which relays the commands to the enclave.
+//! The peer's user (`SO_PEERCRED`) needs to be root, the helper's user or one of `allowed_uids`.
+
+use crate::command::chain::{chain_statuses_v1, request_chains, CHAIN_REQUEST_TIMEOUT};
+use crate::shared::{ChainControlAction, NitroChainControl, NitroRequest};
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use tendermint::chain;
+use tmkms_light::socket_activation::{self, ActivatedListener};
+use tmkms_nitro_helper::schema::ChainStatusV1;
+use tracing::{error, info, warn};
+
+/// how long a client has to send its command
+const COMMAND_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// settings of the admin control socket
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// path of the unix socket (created with mode 0660)
+    pub socket_path: PathBuf,
+    /// users allowed to send commands besides root and the helper's user
+    #[serde(default)]
+    pub allowed_uids: Vec<u32>,
+}
+
+/// admin commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum AdminCommand {
+    /// refuse signing requests
+    Pause,
+    /// resume signing
+    Resume,
+    /// get the sessions' statuses
+    Status,
+    /// pause and wait until the in-flight request is handled
+    Drain,
+    /// refuse signing requests with a maintenance error (pings are still answered)
+    Maintenance,
+}
+
+/// a command sent to the admin socket (one JSON line)
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminRequest {
+    pub command: AdminCommand,
+    /// the chain's session (all sessions if not set)
+    #[serde(default)]
+    pub chain_id: Option<chain::Id>,
+}
+
+/// the sessions' statuses after the command (one JSON line)
+pub type AdminResponse = Result<Vec<ChainStatusV1>, String>;
+
+/// the user of the socket's peer
+fn peer_uid(stream: &UnixStream) -> Result<u32, String> {
+    getsockopt(stream.as_raw_fd(), PeerCredentials)
+        .map(|credentials| credentials.uid())
+        .map_err(|e| format!("failed to get the peer credentials: {:?}", e))
+}
+
+/// root, the helper's user and the configured ones are allowed
+fn uid_allowed(uid: u32, own_uid: u32, allowed_uids: &[u32]) -> bool {
+    uid == 0 || uid == own_uid || allowed_uids.contains(&uid)
+}
+
+/// relays the admin commands to the enclave
+pub struct AdminServer {
+    listener: UnixListener,
+    allowed_uids: Vec<u32>,
+    enclave_cid: u32,
+    enclave_config_port: u32,
+}
+
+impl AdminServer {
+    /// binds the socket (or uses the `admin` socket passed by systemd socket activation)
+    pub fn new(
+        config: &AdminConfig,
+        enclave_cid: u32,
+        enclave_config_port: u32,
+    ) -> Result<Self, String> {
+        let listener = match socket_activation::take_listener("admin") {
+            Some(ActivatedListener::Unix(listener)) => {
+                info!("using the activated admin socket");
+                listener
+            }
+            Some(ActivatedListener::Tcp(_)) => {
+                return Err("the activated admin socket needs to be a unix socket".to_owned())
+            }
+            None => bind(&config.socket_path)?,
+        };
+        Ok(Self {
+            listener,
+            allowed_uids: config.allowed_uids.clone(),
+            enclave_cid,
+            enclave_config_port,
+        })
+    }
+
+    fn control(&self, chain_id: chain::Id, action: ChainControlAction) -> AdminResponse {
+        let request = NitroRequest::ChainControl(NitroChainControl { chain_id, action });
+        request_chains(
+            self.enclave_cid,
+            self.enclave_config_port,
+            &request,
+            CHAIN_REQUEST_TIMEOUT,
+        )
+        .map(chain_statuses_v1)
+        .map_err(String::from)
+    }
+
+    fn handle(&self, request: &AdminRequest) -> AdminResponse {
+        let action = match request.command {
+            AdminCommand::Pause => ChainControlAction::Pause,
+            AdminCommand::Resume => ChainControlAction::Resume,
+            AdminCommand::Drain => ChainControlAction::Drain,
+            AdminCommand::Maintenance => ChainControlAction::Maintenance,
+            AdminCommand::Status => {
+                let statuses = request_chains(
+                    self.enclave_cid,
+                    self.enclave_config_port,
+                    &NitroRequest::ChainStatus,
+                    CHAIN_REQUEST_TIMEOUT,
+                )?;
+                return Ok(chain_statuses_v1(statuses)
+                    .into_iter()
+                    .filter(|status| {
+                        request
+                            .chain_id
+                            .as_ref()
+                            .map_or(true, |id| status.chain_id == id.as_str())
+                    })
+                    .collect());
+            }
+        };
+        let chain_ids = match &request.chain_id {
+            Some(chain_id) => vec![chain_id.clone()],
+            None => request_chains(
+                self.enclave_cid,
+                self.enclave_config_port,
+                &NitroRequest::ChainStatus,
+                CHAIN_REQUEST_TIMEOUT,
+            )?
+            .into_iter()
+            .map(|status| status.chain_id)
+            .collect(),
+        };
+        let mut statuses = vec![];
+        for chain_id in chain_ids {
+            statuses.extend(self.control(chain_id, action)?);
+        }
+        Ok(statuses)
+    }
+
+    fn serve(&self, mut stream: UnixStream) -> Result<(), String> {
+        let uid = peer_uid(&stream)?;
+        let own_uid = nix::unistd::getuid().as_raw();
+        let response = if uid_allowed(uid, own_uid, &self.allowed_uids) {
+            stream
+                .set_read_timeout(Some(COMMAND_READ_TIMEOUT))
+                .map_err(|e| format!("failed to set the command timeout: {:?}", e))?;
+            let mut line = String::new();
+            BufReader::new(&stream)
+                .read_line(&mut line)
+                .map_err(|e| format!("failed to read the command: {:?}", e))?;
+            match serde_json::from_str::<AdminRequest>(&line) {
+                Ok(request) => {
+                    info!(
+                        "admin command {:?} (chain: {:?}) from uid {}",
+                        request.command, request.chain_id, uid
+                    );
+                    self.handle(&request)
+                }
+                Err(e) => Err(format!("invalid command: {}", e)),
+            }
+        } else {
+            warn!("admin connection from uid {} refused", uid);
+            Err(format!("uid {} isn't allowed", uid))
+        };
+        if let Err(e) = &response {
+            warn!("admin command failed: {}", e);
+        }
+        let mut json = serde_json::to_vec(&response)
+            .map_err(|e| format!("failed to serialize the response: {:?}", e))?;
+        json.push(b'\n');
+        stream
+            .write_all(&json)
+            .map_err(|e| format!("failed to send the response: {:?}", e))
+    }
+
+    /// serves the commands (one at a time)
+    pub fn launch(self) {
+        thread::spawn(move || {
+            for stream in self.listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = self.serve(stream) {
+                            warn!("admin connection failed: {}", e);
+                        }
+                    }
+                    Err(e) => warn!("admin connection failed: {}", e),
+                }
+            }
+            error!("admin socket listener failed");
+        });
+    }
+}
+
+/// binds the socket (replacing a stale one) and restricts it to the owner and group
+fn bind(path: &Path) -> Result<UnixListener, String> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(format!("{} exists and isn't a socket", path.display()));
+        }
+        fs::remove_file(path)
+            .map_err(|e| format!("failed to remove the stale admin socket: {:?}", e))?;
+    }
+    let listener = UnixListener::bind(path).map_err(|e| {
+        format!(
+            "failed to bind the admin socket {}: {:?}",
+            path.display(),
+            e
+        )
+    })?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o660))
+        .map_err(|e| format!("failed to restrict the admin socket: {:?}", e))?;
+    info!("admin socket listening on {}", path.display());
+    Ok(listener)
+}
+
+/// sends the command to the helper's admin socket
+pub fn admin_request(socket_path: &Path, request: &AdminRequest) -> AdminResponse {
+    let mut stream = UnixStream::connect(socket_path).map_err(|e| {
+        format!(
+            "failed to connect to the admin socket {}: {:?}",
+            socket_path.display(),
+            e
+        )
+    })?;
+    let mut json = serde_json::to_vec(request)
+        .map_err(|e| format!("failed to serialize the command: {:?}", e))?;
+    json.push(b'\n');
+    stream
+        .write_all(&json)
+        .map_err(|e| format!("failed to send the command: {:?}", e))?;
+    let mut line = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut line)
+        .map_err(|e| format!("failed to read the response: {:?}", e))?;
+    serde_json::from_str(&line).map_err(|e| format!("invalid response: {:?}", e))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authenticates_peer_user() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        let own_uid = nix::unistd::getuid().as_raw();
+        assert_eq!(peer_uid(&a).unwrap(), own_uid);
+        assert!(uid_allowed(own_uid, own_uid, &[]));
+        assert!(uid_allowed(0, 1000, &[]));
+        assert!(uid_allowed(1001, 1000, &[1001]));
+        assert!(!uid_allowed(1002, 1000, &[1001]));
+
+        let request: AdminRequest =
+            serde_json::from_str(r#"{"command":"drain","chain_id":"testchain-1"}"#).unwrap();
+        assert_eq!(request.command, AdminCommand::Drain);
+        assert!(serde_json::from_str::<AdminRequest>(r#"{"command":"stop"}"#).is_err());
+    }
+}
@@ -0,0 +1,311 @@
+//! webhook alerts: the events logged with `alert=true` by the helper or the enclave
+//! (e.g. refused double-sign attempts, KMS failures or refused watermarks) are posted
+//! to the configured webhooks (deduplicated within a window and retried)
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+/// how long a webhook has to answer
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// how many alerts can be queued for the webhooks (the newer ones are dropped)
+const ALERT_QUEUE_LEN: usize = 64;
+
+/// payload format of a webhook
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookFormat {
+    /// the alert as a JSON object
+    #[default]
+    Generic,
+    /// Slack incoming webhook
+    Slack,
+    /// PagerDuty Events API v2
+    PagerDuty,
+}
+
+/// a webhook the alerts are posted to
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    #[serde(default)]
+    pub format: WebhookFormat,
+    /// integration key of the PagerDuty service (required with the `pagerduty` format)
+    pub routing_key: Option<String>,
+}
+
+/// settings of the webhook alerts
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertingConfig {
+    pub webhooks: Vec<WebhookConfig>,
+    /// the same alert isn't sent again within this period
+    #[serde(default = "default_dedup_window_secs")]
+    pub dedup_window_secs: u64,
+    /// how many times a failed delivery is retried
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// delay before the first retry (doubled with every retry)
+    #[serde(default = "default_retry_delay_secs")]
+    pub retry_delay_secs: u64,
+}
+
+fn default_dedup_window_secs() -> u64 {
+    300
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_retry_delay_secs() -> u64 {
+    2
+}
+
+impl AlertingConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        for webhook in self.webhooks.iter() {
+            if webhook.format == WebhookFormat::PagerDuty && webhook.routing_key.is_none() {
+                return Err(format!(
+                    "the PagerDuty webhook {} requires `routing_key`",
+                    webhook.url
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// an event logged with `alert=true`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Alert {
+    /// `helper` or `enclave`
+    pub source: &'static str,
+    pub chain_id: Option<String>,
+    pub message: String,
+}
+
+impl Alert {
+    /// identical alerts are deduplicated
+    fn dedup_key(&self) -> String {
+        format!(
+            "{}/{}/{}",
+            self.source,
+            self.chain_id.as_deref().unwrap_or_default(),
+            self.message
+        )
+    }
+}
+
+/// the alert's payload in the webhook's format
+fn payload(webhook: &WebhookConfig, alert: &Alert) -> serde_json::Value {
+    let chain_id = alert.chain_id.as_deref().unwrap_or("-");
+    match webhook.format {
+        WebhookFormat::Generic => serde_json::json!({
+            "source": alert.source,
+            "chain_id": alert.chain_id,
+            "message": alert.message,
+            "timestamp": SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        }),
+        WebhookFormat::Slack => serde_json::json!({
+            "text": format!("tmkms ({}, chain {}): {}", alert.source, chain_id, alert.message),
+        }),
+        WebhookFormat::PagerDuty => serde_json::json!({
+            "routing_key": webhook.routing_key,
+            "event_action": "trigger",
+            "dedup_key": alert.dedup_key(),
+            "payload": {
+                "summary": format!("chain {}: {}", chain_id, alert.message),
+                "source": format!("tmkms-nitro-helper ({})", alert.source),
+                "severity": "critical",
+            },
+        }),
+    }
+}
+
+/// the alerts sent within the window
+#[derive(Debug)]
+pub struct Deduplicator {
+    window: Duration,
+    sent: HashMap<String, Instant>,
+}
+
+impl Deduplicator {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            sent: HashMap::new(),
+        }
+    }
+
+    /// registers the alert at `now` (`false` if the same one was sent within the window)
+    pub fn should_send(&mut self, key: &str, now: Instant) -> bool {
+        let window = self.window;
+        self.sent
+            .retain(|_, sent| now.duration_since(*sent) < window);
+        if self.sent.contains_key(key) {
+            return false;
+        }
+        self.sent.insert(key.to_owned(), now);
+        true
+    }
+}
+
+/// queue of the alerts for the webhooks (if the alerting is enabled)
+static ALERTS: Mutex<Option<SyncSender<Alert>>> = Mutex::new(None);
+
+/// queues the alert for the webhooks (if the alerting is enabled)
+pub fn send_alert(alert: Alert) {
+    if let Some(sender) = ALERTS.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        // `try_send`, so that logging never blocks on the webhooks
+        let _ = sender.try_send(alert);
+    }
+}
+
+/// posts the queued alerts to the webhooks
+pub struct Alerter {
+    config: AlertingConfig,
+}
+
+impl Alerter {
+    pub fn new(config: AlertingConfig) -> Result<Self, String> {
+        config.validate()?;
+        Ok(Self { config })
+    }
+
+    fn post(&self, webhook: &WebhookConfig, alert: &Alert) -> Result<(), String> {
+        let body = payload(webhook, alert).to_string();
+        let mut delay = Duration::from_secs(self.config.retry_delay_secs);
+        let mut attempt = 0;
+        loop {
+            let result = ureq::post(&webhook.url)
+                .timeout(WEBHOOK_TIMEOUT)
+                .set("Content-Type", "application/json")
+                .send_string(&body);
+            match result {
+                Ok(_) => return Ok(()),
+                Err(e) if attempt < self.config.max_retries => {
+                    // not logged as an alert (it'd be queued again)
+                    tracing::warn!("webhook {} failed, retrying: {}", webhook.url, e);
+                    thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(e) => return Err(format!("webhook {} failed: {}", webhook.url, e)),
+            }
+        }
+    }
+
+    fn run(self, alerts: Receiver<Alert>) {
+        let mut dedup = Deduplicator::new(Duration::from_secs(self.config.dedup_window_secs));
+        for alert in alerts {
+            if !dedup.should_send(&alert.dedup_key(), Instant::now()) {
+                tracing::debug!("duplicate alert not sent: {}", alert.message);
+                continue;
+            }
+            for webhook in self.config.webhooks.iter() {
+                if let Err(e) = self.post(webhook, &alert) {
+                    tracing::warn!("{}", e);
+                }
+            }
+        }
+    }
+
+    /// posts the alerts in a separate thread
+    pub fn launch(self) {
+        let (sender, receiver) = sync_channel(ALERT_QUEUE_LEN);
+        *ALERTS.lock().unwrap_or_else(|e| e.into_inner()) = Some(sender);
+        thread::spawn(move || self.run(receiver));
+    }
+}
+
+/// collects the alert fields of an event
+#[derive(Default)]
+struct AlertVisitor {
+    alert: bool,
+    chain_id: Option<String>,
+    message: String,
+}
+
+impl Visit for AlertVisitor {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == "alert" {
+            self.alert = value;
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "chain_id" {
+            self.chain_id = Some(value.to_owned());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => self.message = format!("{:?}", value),
+            "chain_id" => self.chain_id = Some(format!("{:?}", value).trim_matches('"').to_owned()),
+            _ => {}
+        }
+    }
+}
+
+/// queues the helper's events logged with `alert=true`
+pub struct AlertLayer;
+
+impl<S: Subscriber> Layer<S> for AlertLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut visitor = AlertVisitor::default();
+        event.record(&mut visitor);
+        if visitor.alert {
+            send_alert(Alert {
+                source: "helper",
+                chain_id: visitor.chain_id,
+                message: visitor.message,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deduplicates_within_window() {
+        let mut dedup = Deduplicator::new(Duration::from_secs(60));
+        let start = Instant::now();
+        assert!(dedup.should_send("a", start));
+        assert!(!dedup.should_send("a", start + Duration::from_secs(30)));
+        assert!(dedup.should_send("b", start + Duration::from_secs(30)));
+        assert!(dedup.should_send("a", start + Duration::from_secs(60)));
+
+        let webhook = WebhookConfig {
+            url: "https://events.pagerduty.com/v2/enqueue".to_owned(),
+            format: WebhookFormat::PagerDuty,
+            routing_key: Some("key".to_owned()),
+        };
+        let alert = Alert {
+            source: "enclave",
+            chain_id: Some("testchain-1".to_owned()),
+            message: "attempted double sign".to_owned(),
+        };
+        let body = payload(&webhook, &alert);
+        assert_eq!(body["routing_key"], "key");
+        assert_eq!(
+            body["dedup_key"],
+            "enclave/testchain-1/attempted double sign"
+        );
+    }
+}
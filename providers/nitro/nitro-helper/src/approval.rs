@@ -0,0 +1,170 @@
+//! two-person rule: the approval service (run on a separate host or the operator's device)
+//! holding the approver key, and the relay of the enclave's approval requests to it
+
+use crate::key_utils::load_or_generate_signing_key;
+use ed25519_consensus::SigningKey;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tempfile::NamedTempFile;
+use tmkms_light::approval::{ApprovalRequest, ApprovalToken, Approvals};
+use tmkms_light::utils::{read_u16_payload, write_u16_payload};
+use tracing::{error, info, warn};
+
+/// settings of the approvals required by the signing policy's `approval` rule
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApprovalConfig {
+    /// address (`host:port`) of the approval service
+    pub server_addr: String,
+    /// vsock port relayed to the approval service
+    #[serde(default = "default_enclave_approval_port")]
+    pub enclave_approval_port: u32,
+}
+
+fn default_enclave_approval_port() -> u32 {
+    5566
+}
+
+/// the approvals granted to the enclaves (and the file they're persisted to)
+struct ApprovalStore {
+    path: PathBuf,
+    approvals: Approvals,
+    key: SigningKey,
+}
+
+impl ApprovalStore {
+    /// records the approval before returning the token
+    fn approve(&mut self, request: &ApprovalRequest) -> Result<ApprovalToken, String> {
+        let mut approvals = self.approvals.clone();
+        approvals.approve(request)?;
+        persist(&self.path, &approvals)?;
+        self.approvals = approvals;
+        Ok(ApprovalToken::sign(&self.key, request))
+    }
+}
+
+/// write the approvals into a file
+fn persist(path: &Path, approvals: &Approvals) -> Result<(), String> {
+    let json = serde_json::to_vec(approvals).map_err(|e| format!("{:?}", e))?;
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut file = NamedTempFile::new_in(dir)
+        .map_err(|e| format!("failed to write {}: {:?}", path.display(), e))?;
+    file.write_all(&json)
+        .map_err(|e| format!("failed to write {}: {:?}", path.display(), e))?;
+    file.persist(path)
+        .map_err(|e| format!("failed to write {}: {:?}", path.display(), e.error))?;
+    Ok(())
+}
+
+/// approves the signatures of the enclaves, but never conflicting ones
+/// (at most one block ID per height, round and step)
+pub struct ApprovalServer {
+    store: Arc<Mutex<ApprovalStore>>,
+    listener: TcpListener,
+}
+
+impl ApprovalServer {
+    /// loads the key and the previous approvals (if any) and binds the listener
+    pub fn new(path: PathBuf, key_path: &Path, listen_addr: SocketAddr) -> Result<Self, String> {
+        let key = load_or_generate_signing_key(key_path, "approver key")?;
+        info!(
+            "approver public key: {}",
+            String::from_utf8_lossy(&subtle_encoding::base64::encode(
+                key.verification_key().to_bytes()
+            ))
+        );
+        let approvals = match fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map_err(|e| format!("invalid approval file {}: {:?}", path.display(), e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Approvals::default(),
+            Err(e) => return Err(format!("failed to read {}: {:?}", path.display(), e)),
+        };
+        let listener = TcpListener::bind(listen_addr)
+            .map_err(|e| format!("failed to bind the approval listener: {:?}", e))?;
+        Ok(Self {
+            store: Arc::new(Mutex::new(ApprovalStore {
+                path,
+                approvals,
+                key,
+            })),
+            listener,
+        })
+    }
+
+    /// handles an approval request and returns the token (or the refusal)
+    fn handle(store: &Mutex<ApprovalStore>, stream: &mut TcpStream) -> Result<(), String> {
+        let json_raw = read_u16_payload(stream).map_err(|e| format!("{}", e))?;
+        let result = serde_json::from_slice::<ApprovalRequest>(&json_raw)
+            .map_err(|e| format!("invalid approval request: {:?}", e))
+            .and_then(|request| {
+                info!(
+                    "{} {} requested at h/r/s {}",
+                    request.chain_id,
+                    request.msg_type.as_str(),
+                    request.state
+                );
+                store
+                    .lock()
+                    .map_err(|_| "approval store poisoned".to_string())?
+                    .approve(&request)
+            });
+        if let Err(ref e) = result {
+            error!(alert = true, "approval refused: {}", e);
+        }
+        let response = serde_json::to_vec(&result).map_err(|e| format!("{:?}", e))?;
+        write_u16_payload(stream, &response).map_err(|e| format!("{:?}", e))
+    }
+
+    /// serves the connections (each in a separate thread)
+    pub fn run(self) -> Result<(), String> {
+        for stream in self.listener.incoming() {
+            match stream {
+                Ok(mut stream) => {
+                    info!("approval connection from {:?}", stream.peer_addr());
+                    let store = self.store.clone();
+                    thread::spawn(move || {
+                        while let Ok(()) = Self::handle(&store, &mut stream) {}
+                        warn!("approval connection lost");
+                    });
+                }
+                Err(e) => error!("approval connection failed: {}", e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tmkms_nitro_helper::enclave_policy::EnclavePolicy;
+
+    #[test]
+    fn approver_key_is_generated_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("approver.key");
+        let key = load_or_generate_signing_key(&path, "approver key").unwrap();
+        assert_eq!(
+            load_or_generate_signing_key(&path, "approver key")
+                .unwrap()
+                .verification_key(),
+            key.verification_key()
+        );
+        // the logged public key is pinned in the enclave policy
+        let policy = EnclavePolicy {
+            approver_key: Some(
+                String::from_utf8(subtle_encoding::base64::encode(
+                    key.verification_key().to_bytes(),
+                ))
+                .unwrap(),
+            ),
+            ..Default::default()
+        };
+        assert_eq!(policy.approver_key().unwrap(), key.verification_key());
+    }
+}
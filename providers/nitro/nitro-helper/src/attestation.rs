@@ -0,0 +1,283 @@
+use crate::attestation_archive::{archive, AttestationKind};
+use crate::command::CommandError;
+use crate::shared::{
+    NitroAttestResult, NitroHello, NitroRequest, NitroWatermarkClaim, CAPABILITY_SEALED_REQUESTS,
+};
+use aws_nitro_enclaves_nsm_api::api::AttestationDoc;
+use rand_core::{OsRng, RngCore};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use tmkms_light::utils::{read_u16_payload, write_u16_payload};
+use tmkms_nitro_helper::channel::ChannelStream;
+use tmkms_nitro_helper::codec;
+use tmkms_nitro_helper::config_push::seal_config;
+use tmkms_nitro_helper::control::{encode_request, negotiate};
+use zeroize::Zeroizing;
+
+pub use tmkms_nitro_helper::attestation_doc::{
+    attested_public_key, parse_attestation_doc, verify_attestation_doc,
+};
+
+/// reads a file with a base64-encoded attestation document (e.g. one printed by `init`)
+/// and verifies it
+pub fn read_attestation_doc(path: &Path) -> Result<AttestationDoc, String> {
+    let encoded = fs::read_to_string(path)
+        .map_err(|e| format!("failed to read `{}`: {:?}", path.display(), e))?;
+    let doc = subtle_encoding::base64::decode(encoded.trim())
+        .map_err(|e| format!("invalid attestation `{}`: {:?}", path.display(), e))?;
+    verify_attestation_doc(&doc).map_err(|e| format!("`{}`: {}", path.display(), e))
+}
+
+/// the pinned PCR0 (enclave image), PCR1 (kernel) and PCR2 (application) values (hex-encoded)
+#[derive(Clone, Debug, Default)]
+pub struct ExpectedPcrs(pub [Option<String>; 3]);
+
+impl ExpectedPcrs {
+    /// the PCR0, PCR1 and PCR2 of an attestation document
+    pub fn from_doc(doc: &AttestationDoc) -> Self {
+        let pcr = |index| doc.pcrs.get(&index).map(|pcr| hex_pcr(pcr));
+        Self([pcr(0), pcr(1), pcr(2)])
+    }
+
+    /// no measurement is pinned
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(Option::is_none)
+    }
+
+    /// checks the attested measurements against the pinned ones
+    pub fn verify(&self, doc: &AttestationDoc) -> Result<(), String> {
+        self.verify_pcrs(&doc.pcrs)
+    }
+
+    /// checks the (attested or computed) measurements against the pinned ones
+    pub fn verify_pcrs<P: AsRef<[u8]>>(&self, pcrs: &BTreeMap<usize, P>) -> Result<(), String> {
+        for (index, expected) in self.0.iter().enumerate() {
+            if let Some(expected) = expected {
+                let expected = subtle_encoding::hex::decode(expected.trim().to_ascii_lowercase())
+                    .map_err(|e| format!("invalid `expected_pcr{}`: {:?}", index, e))?;
+                match pcrs.get(&index).map(AsRef::as_ref) {
+                    Some(pcr) if pcr == expected.as_slice() => {}
+                    Some(pcr) => {
+                        return Err(format!(
+                            "enclave PCR{} mismatch (expected {}, got {})",
+                            index,
+                            hex_pcr(&expected),
+                            hex_pcr(pcr)
+                        ))
+                    }
+                    None => return Err(format!("enclave attestation has no PCR{}", index)),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// the hex-encoded PCR (as printed by `nitro-cli describe-enclaves`)
+pub fn hex_pcr(pcr: &[u8]) -> String {
+    String::from_utf8(subtle_encoding::hex::encode(pcr)).unwrap()
+}
+
+/// requests a fresh attestation (with a random nonce) from the running enclave
+/// and checks its measurements against the pinned ones
+pub fn attest_enclave(
+    cid: u32,
+    port: u32,
+    expected: &ExpectedPcrs,
+) -> Result<AttestationDoc, CommandError> {
+    let mut nonce = vec![0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    let mut socket = ChannelStream::connect(cid, port)
+        .map_err(|e| format!("failed to connect to the enclave to attest it: {:?}", e))?;
+    negotiate(&mut socket)?;
+    let request_raw = encode_request(&NitroRequest::Attest {
+        nonce: nonce.clone(),
+    })?;
+    write_u16_payload(&mut socket, &request_raw)
+        .map_err(|e| format!("failed to write the attestation request: {:?}", e))?;
+    let response_raw = read_u16_payload(&mut socket)
+        .map_err(|e| format!("failed to read the attestation response: {:?}", e))?;
+    let response: NitroAttestResult = codec::decode(&response_raw)
+        .map_err(|e| format!("invalid attestation response: {:?}", e))?;
+    let attestation = response?;
+    archive(AttestationKind::Attest, None, &attestation);
+    let doc = verify_attestation_doc(&attestation)?;
+    if doc.nonce.as_ref().map(|n| n.as_slice()) != Some(nonce.as_slice()) {
+        return Err("enclave attestation doesn't have the requested nonce"
+            .to_owned()
+            .into());
+    }
+    expected.verify(&doc)?;
+    Ok(doc)
+}
+
+/// the one-time X25519 key of the enclave's attestation (verified, with the requested nonce
+/// and the pinned measurements)
+pub fn attested_key(
+    attestation: &[u8],
+    nonce: &[u8],
+    expected: &ExpectedPcrs,
+    kind: AttestationKind,
+) -> Result<[u8; 32], String> {
+    archive(kind, None, attestation);
+    let doc = verify_attestation_doc(attestation)?;
+    if doc.nonce.as_ref().map(|n| n.as_slice()) != Some(nonce) {
+        return Err("the enclave key attestation doesn't have the requested nonce".to_owned());
+    }
+    expected.verify(&doc)?;
+    attested_public_key(&doc)
+}
+
+/// sends the request (with credentials or secrets) encrypted to a one-time key the enclave
+/// attests to on the same connection, so that only an enclave with the pinned measurements
+/// can read it (its response is then read as usual)
+pub fn send_sealed(
+    socket: &mut ChannelStream,
+    enclave: &NitroHello,
+    expected: &ExpectedPcrs,
+    request: &NitroRequest,
+) -> Result<(), String> {
+    if !enclave.supports(CAPABILITY_SEALED_REQUESTS) {
+        return Err("the enclave doesn't accept sealed requests (older EIF)".to_owned());
+    }
+    let mut nonce = vec![0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    let request_raw = encode_request(&NitroRequest::Sealed {
+        nonce: nonce.clone(),
+    })?;
+    write_u16_payload(socket, &request_raw)
+        .map_err(|e| format!("failed to write the sealed request: {:?}", e))?;
+    let response_raw = read_u16_payload(socket)
+        .map_err(|e| format!("failed to read the request key attestation: {:?}", e))?;
+    let response: NitroAttestResult = codec::decode(&response_raw)
+        .map_err(|e| format!("invalid request key attestation: {:?}", e))?;
+    let attestation = response.map_err(|e| format!("{}", e))?;
+    let recipient = attested_key(&attestation, &nonce, expected, AttestationKind::Attest)?;
+    let sealed_request = Zeroizing::new(
+        codec::encode(request).map_err(|e| format!("failed to encode the request: {:?}", e))?,
+    );
+    let sealed = seal_config(&mut OsRng, &sealed_request, &recipient)?;
+    let sealed_raw = codec::encode(&sealed)
+        .map_err(|e| format!("failed to encode the encrypted request: {:?}", e))?;
+    write_u16_payload(socket, &sealed_raw)
+        .map_err(|e| format!("failed to write the encrypted request: {:?}", e))
+}
+
+/// connects to the enclave, sends it the sealed request (see `send_sealed`)
+/// and reads its response
+pub fn sealed_request<T: serde::de::DeserializeOwned>(
+    cid: u32,
+    port: u32,
+    expected: &ExpectedPcrs,
+    request: &NitroRequest,
+) -> Result<T, String> {
+    let mut socket = ChannelStream::connect(cid, port)
+        .map_err(|e| format!("failed to connect to the enclave: {:?}", e))?;
+    let enclave = negotiate(&mut socket)?;
+    send_sealed(&mut socket, &enclave, expected, request)?;
+    let response_raw = read_u16_payload(&mut socket)
+        .map_err(|e| format!("failed to read the enclave's response: {:?}", e))?;
+    codec::decode(&response_raw).map_err(|e| format!("invalid response from the enclave: {:?}", e))
+}
+
+/// checks the enclave's startup attestation (with the requested nonce and the pinned
+/// measurements) is of the session's chain and the expected consensus key (if pinned)
+pub fn check_startup_attestation(
+    doc: &AttestationDoc,
+    nonce: &[u8],
+    expected: &ExpectedPcrs,
+    chain_id: &str,
+    expected_pubkey: Option<&str>,
+) -> Result<NitroWatermarkClaim, String> {
+    if doc.nonce.as_ref().map(|n| n.as_slice()) != Some(nonce) {
+        return Err("the startup attestation doesn't have the requested nonce".to_owned());
+    }
+    expected.verify(doc)?;
+    let claim: NitroWatermarkClaim = serde_json::from_slice(
+        doc.user_data
+            .as_ref()
+            .ok_or_else(|| "the startup attestation has no user data".to_owned())?,
+    )
+    .map_err(|e| format!("invalid startup attestation claim: {}", e))?;
+    if claim.chain_id != chain_id {
+        return Err(format!(
+            "the startup attestation is of the chain {} (expected {})",
+            claim.chain_id, chain_id
+        ));
+    }
+    if let Some(expected_pubkey) = expected_pubkey {
+        if claim.pubkey != expected_pubkey.trim() {
+            return Err(format!(
+                "the enclave unsealed the consensus key {} (expected {})",
+                claim.pubkey,
+                expected_pubkey.trim()
+            ));
+        }
+    }
+    Ok(claim)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_nitro_enclaves_nsm_api::api::Digest;
+
+    #[test]
+    fn checks_pinned_pcrs() {
+        let mut pcrs = BTreeMap::new();
+        pcrs.insert(0, vec![0xab; 48]);
+        pcrs.insert(1, vec![0x01; 48]);
+        let doc = AttestationDoc::new(
+            "i-0".to_owned(),
+            Digest::SHA384,
+            0,
+            pcrs,
+            vec![],
+            Default::default(),
+            None,
+            None,
+            None,
+        );
+        let pcr0 = hex_pcr(&[0xab; 48]);
+        assert!(ExpectedPcrs::default().verify(&doc).is_ok());
+        assert!(ExpectedPcrs([Some(pcr0.to_uppercase()), None, None])
+            .verify(&doc)
+            .is_ok());
+        assert!(ExpectedPcrs([None, Some(pcr0.clone()), None])
+            .verify(&doc)
+            .is_err());
+        assert!(ExpectedPcrs([None, None, Some(pcr0)]).verify(&doc).is_err());
+    }
+
+    #[test]
+    fn startup_attestation_binds_the_chain_and_key() {
+        let claim = NitroWatermarkClaim {
+            pubkey: "AAAA".to_owned(),
+            chain_id: "testchain-1".to_owned(),
+            height: 100,
+            round: 1,
+            step: 3,
+        };
+        let doc = AttestationDoc::new(
+            "i-0".to_owned(),
+            Digest::SHA384,
+            0,
+            BTreeMap::new(),
+            vec![],
+            Default::default(),
+            Some(serde_json::to_vec(&claim).unwrap()),
+            Some(vec![7; 32]),
+            None,
+        );
+        let expected = ExpectedPcrs::default();
+        let check = |nonce: &[u8], chain_id: &str, pubkey: Option<&str>| {
+            check_startup_attestation(&doc, nonce, &expected, chain_id, pubkey)
+        };
+        assert_eq!(check(&[7; 32], "testchain-1", None).unwrap(), claim);
+        assert!(check(&[7; 32], "testchain-1", Some("AAAA")).is_ok());
+        assert!(check(&[7; 32], "testchain-1", Some("BBBB")).is_err());
+        assert!(check(&[7; 32], "testchain-2", None).is_err());
+        assert!(check(&[8; 32], "testchain-1", None).is_err());
+    }
+}
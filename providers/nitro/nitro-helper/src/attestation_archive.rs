@@ -0,0 +1,350 @@
+//! Archive of the enclave's attestation documents received by the helper (keygen, startup,
+//! runtime and halt attestations), and the signed bundles of them exported for the auditors
+//! and delegators (who check the documents' signatures out-of-band, e.g. with
+//! `script/tmkms-nitro/verify.py`, and the bundle's signature with the published key)
+
+use crate::attestation::{hex_pcr, parse_attestation_doc};
+use ed25519_consensus::{Signature, SigningKey, VerificationKey};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tempfile::NamedTempFile;
+use tracing::{debug, warn};
+
+/// domain separation of the bundle signatures
+const BUNDLE_DOMAIN: &[u8] = b"tmkms-attestation-bundle-v1";
+
+/// where the received attestation documents are archived (if set)
+static ARCHIVE_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// sets the archive directory of the received attestation documents
+pub fn set_archive_dir(dir: Option<PathBuf>) {
+    *ARCHIVE_DIR.lock().unwrap_or_else(|e| e.into_inner()) = dir;
+}
+
+/// what the attestation was requested for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AttestationKind {
+    /// a generated (or provisioned) key
+    Keygen,
+    /// the one-time key the start config (or the control key) is encrypted to
+    Startup,
+    /// the enclave's measurements (requested by the helper)
+    Attest,
+    /// the periodic attestation of the consensus key and the last persisted state
+    Runtime,
+    /// the final watermark of a session stopped at `max_height`
+    Halt,
+    /// a sealed key re-encrypted with another KMS key
+    Rewrap,
+}
+
+impl AttestationKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AttestationKind::Keygen => "keygen",
+            AttestationKind::Startup => "startup",
+            AttestationKind::Attest => "attest",
+            AttestationKind::Runtime => "runtime",
+            AttestationKind::Halt => "halt",
+            AttestationKind::Rewrap => "rewrap",
+        }
+    }
+}
+
+/// an archived attestation document with its metadata
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ArchivedAttestation {
+    pub kind: AttestationKind,
+    /// chain ID of the session (for the runtime and halt attestations)
+    pub chain_id: Option<String>,
+    /// when the helper received it (UNIX seconds)
+    pub received_at: u64,
+    /// the document's timestamp (UNIX milliseconds, set by the NSM)
+    pub timestamp: u64,
+    /// ID of the enclave's NSM module
+    pub module_id: String,
+    /// the (hex-encoded) PCR0, PCR1 and PCR2
+    pub pcrs: BTreeMap<usize, String>,
+    /// the enclave's claim (if any)
+    pub user_data: Option<String>,
+    /// base64-encoded attestation document (COSE_Sign1)
+    pub attestation_doc: String,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl ArchivedAttestation {
+    /// the attestation document with the metadata extracted from it
+    pub fn new(
+        kind: AttestationKind,
+        chain_id: Option<String>,
+        received_at: u64,
+        cose_sign1: &[u8],
+    ) -> Result<Self, String> {
+        let doc = parse_attestation_doc(cose_sign1)?;
+        Ok(Self {
+            kind,
+            chain_id,
+            received_at,
+            timestamp: doc.timestamp,
+            module_id: doc.module_id,
+            pcrs: (0..3)
+                .filter_map(|index| doc.pcrs.get(&index).map(|pcr| (index, hex_pcr(pcr))))
+                .collect(),
+            user_data: doc
+                .user_data
+                .map(|data| String::from_utf8_lossy(&data).into_owned()),
+            attestation_doc: String::from_utf8(subtle_encoding::base64::encode(cose_sign1))
+                .map_err(|e| format!("encoding attestation doc: {:?}", e))?,
+        })
+    }
+
+    /// checks the metadata is the one of the attestation document
+    pub fn check(&self) -> Result<(), String> {
+        let cose_sign1 = subtle_encoding::base64::decode(&self.attestation_doc)
+            .map_err(|e| format!("invalid attestation doc: {}", e))?;
+        let expected = Self::new(
+            self.kind,
+            self.chain_id.clone(),
+            self.received_at,
+            &cose_sign1,
+        )?;
+        if &expected != self {
+            return Err(format!(
+                "the metadata of the {} attestation received at {} doesn't match its document",
+                self.kind.as_str(),
+                self.received_at
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// archives the received attestation document (if the archive directory is set);
+/// a failure is only logged, as the archival never blocks the enclave's operation
+pub fn archive(kind: AttestationKind, chain_id: Option<&str>, cose_sign1: &[u8]) {
+    let dir = ARCHIVE_DIR
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone();
+    if let Some(dir) = dir {
+        archive_to(&dir, kind, chain_id, cose_sign1);
+    }
+}
+
+fn archive_to(dir: &Path, kind: AttestationKind, chain_id: Option<&str>, cose_sign1: &[u8]) {
+    let received_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let result = ArchivedAttestation::new(
+        kind,
+        chain_id.map(str::to_owned),
+        received_at.as_secs(),
+        cose_sign1,
+    )
+    .and_then(|archived| {
+        let path = dir.join(format!("{}-{}.json", received_at.as_nanos(), kind.as_str()));
+        write_json(&path, &archived)?;
+        Ok(path)
+    });
+    match result {
+        Ok(path) => debug!(
+            "archived the {} attestation: {}",
+            kind.as_str(),
+            path.display()
+        ),
+        Err(e) => warn!("failed to archive the {} attestation: {}", kind.as_str(), e),
+    }
+}
+
+/// writes the JSON file atomically
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), String> {
+    let json = serde_json::to_vec_pretty(value).map_err(|e| format!("{:?}", e))?;
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    fs::create_dir_all(dir).map_err(|e| format!("failed to create {}: {:?}", dir.display(), e))?;
+    let mut file = NamedTempFile::new_in(dir)
+        .map_err(|e| format!("failed to write {}: {:?}", path.display(), e))?;
+    file.write_all(&json)
+        .map_err(|e| format!("failed to write {}: {:?}", path.display(), e))?;
+    file.persist(path)
+        .map_err(|e| format!("failed to write {}: {:?}", path.display(), e.error))?;
+    Ok(())
+}
+
+/// the archived attestations (received at or after `since`, oldest first)
+pub fn read_archive(dir: &Path, since: Option<u64>) -> Result<Vec<ArchivedAttestation>, String> {
+    let mut paths = fs::read_dir(dir)
+        .and_then(|entries| {
+            entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .map_err(|e| format!("failed to read {}: {:?}", dir.display(), e))?;
+    // the file names start with the reception time (in nanoseconds)
+    paths.sort();
+    let mut attestations = Vec::new();
+    for path in paths {
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+        let json =
+            fs::read(&path).map_err(|e| format!("failed to read {}: {:?}", path.display(), e))?;
+        let archived: ArchivedAttestation = serde_json::from_slice(&json)
+            .map_err(|e| format!("invalid archived attestation {}: {}", path.display(), e))?;
+        if since.map_or(true, |since| archived.received_at >= since) {
+            attestations.push(archived);
+        }
+    }
+    Ok(attestations)
+}
+
+/// the archived attestations signed by the helper's publisher key
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AttestationBundle {
+    /// when the bundle was exported (UNIX seconds)
+    pub created_at: u64,
+    pub attestations: Vec<ArchivedAttestation>,
+    /// base64-encoded Ed25519 public key of the publisher
+    pub public_key: String,
+    /// base64-encoded signature of the creation time and the attestations
+    pub signature: String,
+}
+
+fn bundle_message(
+    created_at: u64,
+    attestations: &[ArchivedAttestation],
+) -> Result<Vec<u8>, String> {
+    let mut message = BUNDLE_DOMAIN.to_vec();
+    serde_json::to_writer(&mut message, &(created_at, attestations))
+        .map_err(|e| format!("{:?}", e))?;
+    Ok(message)
+}
+
+fn base64(bytes: &[u8]) -> String {
+    String::from_utf8(subtle_encoding::base64::encode(bytes)).expect("base64 is ASCII")
+}
+
+impl AttestationBundle {
+    /// signs the attestations with the publisher key
+    pub fn sign(
+        publisher_key: &SigningKey,
+        attestations: Vec<ArchivedAttestation>,
+    ) -> Result<Self, String> {
+        let created_at = now_secs();
+        let signature = publisher_key.sign(&bundle_message(created_at, &attestations)?);
+        Ok(Self {
+            created_at,
+            attestations,
+            public_key: base64(&publisher_key.verification_key().to_bytes()),
+            signature: base64(&signature.to_bytes()),
+        })
+    }
+
+    /// checks the bundle is signed by the publisher key (the bundle's one if not given)
+    /// and the metadata matches the attestation documents
+    pub fn verify(&self, publisher_key: Option<&str>) -> Result<(), String> {
+        let public_key = publisher_key.unwrap_or(&self.public_key);
+        if public_key != self.public_key {
+            return Err("the bundle isn't signed by the expected publisher key".to_owned());
+        }
+        let public_key = subtle_encoding::base64::decode(public_key)
+            .map_err(|e| format!("invalid publisher key: {}", e))
+            .and_then(|key| {
+                VerificationKey::try_from(key.as_slice())
+                    .map_err(|e| format!("invalid publisher key: {}", e))
+            })?;
+        let signature = subtle_encoding::base64::decode(&self.signature)
+            .map_err(|e| format!("invalid bundle signature: {}", e))
+            .and_then(|signature| {
+                Signature::try_from(signature.as_slice())
+                    .map_err(|e| format!("invalid bundle signature: {}", e))
+            })?;
+        public_key
+            .verify(
+                &signature,
+                &bundle_message(self.created_at, &self.attestations)?,
+            )
+            .map_err(|_| "invalid bundle signature".to_owned())?;
+        self.attestations.iter().try_for_each(|a| a.check())
+    }
+
+    /// writes the bundle as JSON
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        write_json(path, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_nitro_enclaves_nsm_api::api::{AttestationDoc, Digest};
+    use serde_bytes::ByteBuf;
+
+    fn cose_sign1(pcr0: u8) -> Vec<u8> {
+        let mut pcrs = BTreeMap::new();
+        pcrs.insert(0, vec![pcr0; 48]);
+        let doc = AttestationDoc::new(
+            "i-0".to_owned(),
+            Digest::SHA384,
+            1_700_000_000_000,
+            pcrs,
+            vec![],
+            Default::default(),
+            Some(b"{\"pubkey\":\"a\"}".to_vec()),
+            None,
+            None,
+        );
+        serde_cbor::to_vec(&(
+            ByteBuf::new(),
+            serde_cbor::Value::Null,
+            ByteBuf::from(doc.to_binary()),
+            ByteBuf::new(),
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn archived_attestations_are_exported_in_a_signed_bundle() {
+        let dir = tempfile::tempdir().unwrap();
+        archive_to(dir.path(), AttestationKind::Keygen, None, &cose_sign1(1));
+        archive_to(
+            dir.path(),
+            AttestationKind::Runtime,
+            Some("testchain-1"),
+            &cose_sign1(2),
+        );
+
+        let attestations = read_archive(dir.path(), None).unwrap();
+        assert_eq!(attestations.len(), 2);
+        assert_eq!(attestations[0].kind, AttestationKind::Keygen);
+        assert_eq!(attestations[1].chain_id.as_deref(), Some("testchain-1"));
+        assert_eq!(attestations[1].pcrs[&0], hex_pcr(&[2; 48]));
+
+        let key = SigningKey::from([5u8; 32]);
+        let bundle = AttestationBundle::sign(&key, attestations).unwrap();
+        assert!(bundle.verify(None).is_ok());
+        assert!(bundle.verify(Some(&bundle.public_key)).is_ok());
+        let other_key = base64(&SigningKey::from([6u8; 32]).verification_key().to_bytes());
+        assert!(bundle.verify(Some(&other_key)).is_err());
+
+        let mut tampered = bundle.clone();
+        tampered.attestations.remove(0);
+        assert!(tampered.verify(None).is_err());
+        let mut tampered = bundle;
+        tampered.attestations[1].pcrs.insert(0, hex_pcr(&[1; 48]));
+        assert!(tampered.verify(None).is_err());
+    }
+}
@@ -0,0 +1,232 @@
+//! Verification of the Nitro attestation documents: the COSE_Sign1 (ES384) signature
+//! with the document's certificate, and the certificate's chain (the document's CA bundle)
+//! to the pinned AWS Nitro Enclaves root CA, valid at the document's timestamp.
+//! In the local development mode (`--dev-plaintext`), the documents aren't signed
+//! and only the development mode's (unsigned) documents are accepted.
+
+use crate::channel::dev_tcp;
+use aws_nitro_enclaves_nsm_api::api::{AttestationDoc, Digest};
+use serde_bytes::ByteBuf;
+
+/// the AWS Nitro Enclaves root CA (G1), from
+/// https://aws-nitro-enclaves.amazonaws.com/AWS_NitroEnclaves_Root-G1.zip
+const AWS_NITRO_ROOT_CA: &str = "\
+MIICETCCAZagAwIBAgIRAPkxdWgbkK/hHUbMtOTn+FYwCgYIKoZIzj0EAwMwSTEL\
+MAkGA1UEBhMCVVMxDzANBgNVBAoMBkFtYXpvbjEMMAoGA1UECwwDQVdTMRswGQYD\
+VQQDDBJhd3Mubml0cm8tZW5jbGF2ZXMwHhcNMTkxMDI4MTMyODA1WhcNNDkxMDI4\
+MTQyODA1WjBJMQswCQYDVQQGEwJVUzEPMA0GA1UECgwGQW1hem9uMQwwCgYDVQQL\
+DANBV1MxGzAZBgNVBAMMEmF3cy5uaXRyby1lbmNsYXZlczB2MBAGByqGSM49AgEG\
+BSuBBAAiA2IABPwCVOumCMHzaHDimtqQvkY4MpJzbolL//Zy2YlES1BR5TSksfbb\
+48C8WBoyt7F2Bw7eEtaaP+ohG2bnUs990d0JX28TcPQXCEPZ3BABIeTPYwEoCWZE\
+h8l5YoQwTcU/9KNCMEAwDwYDVR0TAQH/BAUwAwEB/zAdBgNVHQ4EFgQUkCW1DdkF\
+R+eWw5b6cp3PmanfS5YwDgYDVR0PAQH/BAQDAgGGMAoGCCqGSM49BAMDA2kAMGYC\
+MQCjfy+Rocm9Xue4YnwWmNJVA44fA0P5W2OpYow9OYCVRaEevL8uO1XYru5xtMPW\
+rfMCMQCi85sWBbJwKKXdS6BptQFuZbT73o/gBh1qUxl/nNr12UO8Yfwr6wPLb+6N\
+IwLz3/Y=";
+
+/// the COSE algorithm of the Nitro attestation documents (ECDSA with SHA-384)
+const COSE_ALG_ES384: i128 = -35;
+/// the COSE header parameter of the algorithm
+const COSE_HEADER_ALG: i128 = 1;
+/// module ID of the development mode's attestation documents
+const DEV_MODULE_ID: &str = "dev-plaintext";
+
+/// the DER-encoded AWS Nitro Enclaves root CA
+pub fn aws_nitro_root_ca() -> Vec<u8> {
+    subtle_encoding::base64::decode(AWS_NITRO_ROOT_CA).expect("valid root CA encoding")
+}
+
+/// the COSE_Sign1 structure's protected header, payload and signature
+fn decode_cose_sign1(cose_sign1: &[u8]) -> Result<(ByteBuf, ByteBuf, ByteBuf), String> {
+    let (protected, _unprotected, payload, signature): (
+        ByteBuf,
+        serde_cbor::Value,
+        ByteBuf,
+        ByteBuf,
+    ) = serde_cbor::from_slice(cose_sign1)
+        .map_err(|e| format!("invalid COSE_Sign1 structure: {:?}", e))?;
+    Ok((protected, payload, signature))
+}
+
+/// Extracts the attestation document payload from its COSE_Sign1 envelope.
+/// NOTE: this doesn't verify the signature or the certificate chain
+/// (only for the documents that were already verified, e.g. archived ones)
+pub fn parse_attestation_doc(cose_sign1: &[u8]) -> Result<AttestationDoc, String> {
+    let (_protected, payload, _signature) = decode_cose_sign1(cose_sign1)?;
+    AttestationDoc::from_binary(&payload)
+        .map_err(|e| format!("invalid attestation document: {:?}", e))
+}
+
+/// the X25519 public key bound in the attestation document
+pub fn attested_public_key(doc: &AttestationDoc) -> Result<[u8; 32], String> {
+    let public_key = doc
+        .public_key
+        .as_ref()
+        .ok_or_else(|| "attestation document has no public key".to_owned())?;
+    <[u8; 32]>::try_from(public_key.as_slice())
+        .map_err(|_| "attested public key is not a 32-byte X25519 key".to_owned())
+}
+
+/// the fixed-size (r || s) ECDSA signature in the ASN.1 DER encoding
+fn ecdsa_signature_der(signature: &[u8]) -> Result<Vec<u8>, String> {
+    if signature.is_empty() || signature.len() % 2 != 0 || signature.len() > 2 * 48 {
+        return Err("invalid attestation document signature length".to_owned());
+    }
+    let integer = |bytes: &[u8]| {
+        let start = bytes
+            .iter()
+            .position(|b| *b != 0)
+            .unwrap_or(bytes.len() - 1);
+        let mut value = Vec::with_capacity(bytes.len() + 3);
+        value.push(0x02);
+        let pad = bytes[start] & 0x80 != 0;
+        value.push((bytes.len() - start + usize::from(pad)) as u8);
+        if pad {
+            value.push(0);
+        }
+        value.extend_from_slice(&bytes[start..]);
+        value
+    };
+    let (r, s) = signature.split_at(signature.len() / 2);
+    let (r, s) = (integer(r), integer(s));
+    let mut der = vec![0x30, (r.len() + s.len()) as u8];
+    der.extend_from_slice(&r);
+    der.extend_from_slice(&s);
+    Ok(der)
+}
+
+/// verifies the attestation document's signature and certificate chain to the root CA
+/// (DER-encoded) and returns the document
+pub fn verify_attestation_doc_with_root(
+    cose_sign1: &[u8],
+    root_ca: &[u8],
+) -> Result<AttestationDoc, String> {
+    let (protected, payload, signature) = decode_cose_sign1(cose_sign1)?;
+    let header: serde_cbor::Value = serde_cbor::from_slice(&protected)
+        .map_err(|e| format!("invalid COSE_Sign1 protected header: {:?}", e))?;
+    let alg = match header {
+        serde_cbor::Value::Map(header) => header
+            .get(&serde_cbor::Value::Integer(COSE_HEADER_ALG))
+            .cloned(),
+        _ => None,
+    };
+    if alg != Some(serde_cbor::Value::Integer(COSE_ALG_ES384)) {
+        return Err("the attestation document isn't signed with ES384".to_owned());
+    }
+    let doc = AttestationDoc::from_binary(&payload)
+        .map_err(|e| format!("invalid attestation document: {:?}", e))?;
+    if doc.digest != Digest::SHA384 {
+        return Err("the attestation document's PCRs aren't SHA-384".to_owned());
+    }
+
+    let anchor = webpki::TrustAnchor::try_from_cert_der(root_ca)
+        .map_err(|e| format!("invalid root CA: {:?}", e))?;
+    let certificate = webpki::EndEntityCert::try_from(doc.certificate.as_slice())
+        .map_err(|e| format!("invalid attestation document certificate: {:?}", e))?;
+    let intermediates: Vec<&[u8]> = doc.cabundle.iter().map(|c| c.as_slice()).collect();
+    let time = webpki::Time::from_seconds_since_unix_epoch(doc.timestamp / 1000);
+    certificate
+        .verify_is_valid_tls_server_cert(
+            &[&webpki::ECDSA_P384_SHA384],
+            &webpki::TlsServerTrustAnchors(&[anchor]),
+            &intermediates,
+            time,
+        )
+        .map_err(|e| format!("invalid attestation document certificate chain: {:?}", e))?;
+
+    // Sig_structure = ["Signature1", protected, external_aad, payload]
+    let signed = serde_cbor::to_vec(&("Signature1", protected, ByteBuf::new(), payload))
+        .map_err(|e| format!("failed to encode the signed structure: {:?}", e))?;
+    certificate
+        .verify_signature(
+            &webpki::ECDSA_P384_SHA384,
+            &signed,
+            &ecdsa_signature_der(&signature)?,
+        )
+        .map_err(|e| format!("invalid attestation document signature: {:?}", e))?;
+    Ok(doc)
+}
+
+/// verifies the attestation document's signature and certificate chain to the AWS Nitro
+/// Enclaves root CA (in the development mode, it only accepts the unsigned development
+/// mode's documents) and returns the document
+pub fn verify_attestation_doc(cose_sign1: &[u8]) -> Result<AttestationDoc, String> {
+    if dev_tcp() {
+        let doc = parse_attestation_doc(cose_sign1)?;
+        if doc.module_id != DEV_MODULE_ID {
+            return Err("not a development mode attestation document".to_owned());
+        }
+        return Ok(doc);
+    }
+    verify_attestation_doc_with_root(cose_sign1, &aws_nitro_root_ca())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, ECDSA_P384_SHA384_FIXED_SIGNING};
+    use std::collections::BTreeMap;
+
+    const ROOT: &[u8] = include_bytes!("../testdata/nitro_root.der");
+    const INTERMEDIATE: &[u8] = include_bytes!("../testdata/nitro_intermediate.der");
+    const LEAF: &[u8] = include_bytes!("../testdata/nitro_leaf.der");
+    const LEAF_KEY: &[u8] = include_bytes!("../testdata/nitro_leaf.pk8");
+    /// 2023-01-01T01:00:00Z (the test leaf certificate is valid for 3 hours from midnight)
+    const TIMESTAMP: u64 = 1_672_534_800_000;
+
+    fn signed_doc(timestamp: u64, nonce: &[u8], tamper: bool) -> Vec<u8> {
+        let doc = AttestationDoc::new(
+            "i-0-enc0".to_owned(),
+            Digest::SHA384,
+            timestamp,
+            (0..3).map(|i| (i, vec![i as u8; 48])).collect(),
+            LEAF.to_vec(),
+            vec![ROOT.to_vec(), INTERMEDIATE.to_vec()],
+            None,
+            Some(nonce.to_vec()),
+            None,
+        );
+        let mut header = BTreeMap::new();
+        header.insert(COSE_HEADER_ALG as i64, COSE_ALG_ES384 as i64);
+        let protected = ByteBuf::from(serde_cbor::to_vec(&header).unwrap());
+        let payload = ByteBuf::from(doc.to_binary());
+        let signed =
+            serde_cbor::to_vec(&("Signature1", &protected, ByteBuf::new(), &payload)).unwrap();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P384_SHA384_FIXED_SIGNING, LEAF_KEY).unwrap();
+        let signature = key.sign(&SystemRandom::new(), &signed).unwrap();
+        let mut payload = payload.into_vec();
+        if tamper {
+            let last = payload.len() - 1;
+            payload[last] ^= 1;
+        }
+        serde_cbor::to_vec(&(
+            protected,
+            BTreeMap::<u8, u8>::new(),
+            ByteBuf::from(payload),
+            ByteBuf::from(signature.as_ref().to_vec()),
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn verifies_the_signature_and_chain() {
+        let doc =
+            verify_attestation_doc_with_root(&signed_doc(TIMESTAMP, b"n", false), ROOT).unwrap();
+        assert_eq!(doc.nonce.unwrap().as_slice(), b"n");
+        assert_eq!(doc.pcrs[&1].as_slice(), &[1u8; 48][..]);
+        // the payload was changed after it was signed
+        assert!(
+            verify_attestation_doc_with_root(&signed_doc(TIMESTAMP, b"n", true), ROOT).is_err()
+        );
+        // the certificate wasn't valid at the document's time
+        assert!(verify_attestation_doc_with_root(
+            &signed_doc(TIMESTAMP + 4 * 3600 * 1000, b"n", false),
+            ROOT
+        )
+        .is_err());
+        // not anchored to the AWS root
+        assert!(verify_attestation_doc(&signed_doc(TIMESTAMP, b"n", false)).is_err());
+        assert!(ecdsa_signature_der(&[0xff; 96]).is_ok());
+    }
+}
@@ -0,0 +1,103 @@
+use crate::attestation::verify_attestation_doc;
+use crate::attestation_archive::{archive, AttestationKind};
+use crate::mux_server::ChannelListener;
+use crate::shared::NitroReattestation;
+use serde::Serialize;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tmkms_light::utils::read_u16_payload;
+use tracing::{error, info, warn};
+
+/// the latest runtime attestation of the enclave
+#[derive(Clone, Debug, Serialize)]
+pub struct LatestAttestation {
+    pub chain_id: String,
+    /// base64-encoded attestation document
+    pub attestation_doc: String,
+    /// when it was received (UNIX seconds)
+    pub received_at: u64,
+}
+
+/// receives the enclave's runtime attestations, writes the latest one to a file (if set)
+/// and keeps it for the health endpoint
+pub struct AttestationServer {
+    path: Option<PathBuf>,
+    latest: Arc<Mutex<Option<LatestAttestation>>>,
+    vsock_listener: ChannelListener,
+}
+
+impl AttestationServer {
+    /// binds a listener for the enclave on the provided port
+    pub fn new(
+        path: Option<PathBuf>,
+        latest: Arc<Mutex<Option<LatestAttestation>>>,
+        vsock_port: u32,
+    ) -> Result<Self, String> {
+        let vsock_listener = ChannelListener::bind(vsock_port)
+            .map_err(|e| format!("failed to bind the attestation listener: {:?}", e))?;
+        Ok(Self {
+            path,
+            latest,
+            vsock_listener,
+        })
+    }
+
+    fn store(&self, report: NitroReattestation) -> Result<(), String> {
+        let doc = verify_attestation_doc(&report.attestation_doc)?;
+        archive(
+            AttestationKind::Runtime,
+            Some(report.chain_id.as_str()),
+            &report.attestation_doc,
+        );
+        let claim = doc
+            .user_data
+            .map(|data| String::from_utf8_lossy(&data).into_owned())
+            .unwrap_or_default();
+        let encoded = String::from_utf8(subtle_encoding::base64::encode(&report.attestation_doc))
+            .map_err(|e| format!("encoding attestation doc: {:?}", e))?;
+        if let Some(path) = &self.path {
+            // replaced atomically, so readers never see a partial document
+            let tmp_path = path.with_extension("tmp");
+            fs::write(&tmp_path, &encoded)
+                .and_then(|_| fs::rename(&tmp_path, path))
+                .map_err(|e| format!("couldn't write `{}`: {:?}", path.display(), e))?;
+        }
+        info!(
+            "[{}] runtime attestation received: {}",
+            report.chain_id, claim
+        );
+        let received_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        *self.latest.lock().unwrap_or_else(|e| e.into_inner()) = Some(LatestAttestation {
+            chain_id: report.chain_id.to_string(),
+            attestation_doc: encoded,
+            received_at,
+        });
+        Ok(())
+    }
+
+    /// serves the enclave connections in a separate thread
+    pub fn launch(self) {
+        thread::spawn(move || {
+            info!("listening for enclave runtime attestations");
+            while let Ok((mut stream, _)) = self.vsock_listener.accept() {
+                let result = read_u16_payload(&mut stream)
+                    .map_err(|e| format!("{}", e))
+                    .and_then(|json_raw| {
+                        serde_json::from_slice::<NitroReattestation>(&json_raw)
+                            .map_err(|e| format!("invalid runtime attestation: {:?}", e))
+                    })
+                    .and_then(|report| self.store(report));
+                if let Err(e) = result {
+                    warn!("runtime attestation: {}", e);
+                }
+            }
+            error!("attestation listener failed");
+        });
+    }
+}
@@ -0,0 +1,395 @@
+//! Tamper-evident audit log of the produced signatures (and the refused requests, if enabled):
+//! JSON lines of [`AuditRecord`]s where each record contains the hash of the previous line,
+//! and the enclave periodically anchors the records it sent with a signature of its own chain's head
+
+use crate::schema::{AuditAnchorV1, AuditRecord, AuditRecordV1, AuditRefusalV1};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use tmkms_light::audit::{
+    chain_head, refused_digest, signed_digest, AuditAnchor, RefusedMessage, SignedMessage,
+};
+
+/// hex-encoded SHA-256 of the log line (without the newline)
+fn line_hash(line: &str) -> String {
+    hex(Sha256::digest(line.as_bytes()))
+}
+
+fn hex(bytes: impl AsRef<[u8]>) -> String {
+    String::from_utf8(subtle_encoding::hex::encode(bytes)).expect("hex is valid UTF-8")
+}
+
+fn base64(bytes: impl AsRef<[u8]>) -> String {
+    String::from_utf8(subtle_encoding::base64::encode(bytes)).expect("base64 is valid UTF-8")
+}
+
+/// a hex-encoded head of the enclave's chain
+fn decode_head(head: &str) -> Result<[u8; 32], String> {
+    subtle_encoding::hex::decode(head)
+        .ok()
+        .and_then(|head| <[u8; 32]>::try_from(head.as_slice()).ok())
+        .ok_or_else(|| format!("invalid anchor head `{}`", head))
+}
+
+/// the result of a successful verification
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct AuditSummary {
+    /// number of records in the log
+    pub records: u64,
+    /// hash of the last record (empty if there are no records)
+    pub last_hash: String,
+    /// number of the enclave's anchors
+    pub anchors: u64,
+    /// number of the signature and refusal records no anchor covers (yet)
+    pub unanchored: u64,
+    /// base64-encoded public keys the anchors were signed with
+    pub anchor_keys: BTreeSet<String>,
+}
+
+/// digest of a signature record, as chained by the enclave
+fn signed_record_digest(record: &AuditRecordV1) -> Result<[u8; 32], String> {
+    let block_id_hash = record
+        .block_id_hash
+        .as_ref()
+        .map(subtle_encoding::hex::decode)
+        .transpose()
+        .map_err(|e| format!("invalid block ID hash: {}", e))?;
+    let signature = subtle_encoding::base64::decode(&record.signature)
+        .map_err(|e| format!("invalid signature: {}", e))?;
+    Ok(signed_digest(
+        &record.chain_id,
+        &record.msg_type,
+        record.height,
+        record.round,
+        block_id_hash.as_deref(),
+        &signature,
+    ))
+}
+
+/// digest of a refusal record, as chained by the enclave
+fn refused_record_digest(record: &AuditRefusalV1) -> Result<[u8; 32], String> {
+    let sign_bytes = subtle_encoding::hex::decode(&record.sign_bytes)
+        .map_err(|e| format!("invalid sign-bytes: {}", e))?;
+    Ok(refused_digest(
+        &record.chain_id,
+        &record.msg_type,
+        record.height,
+        record.round,
+        &record.reason,
+        &sign_bytes,
+    ))
+}
+
+/// checks the anchor's signature (with the expected key, if any) and that it chains
+/// the covered (not yet anchored) records from zeros or the head of a previous anchor; returns its head
+fn verify_anchor(
+    anchor: &AuditAnchorV1,
+    anchor_key: Option<&[u8]>,
+    unanchored: &mut BTreeMap<u64, [u8; 32]>,
+    heads: &HashSet<[u8; 32]>,
+) -> Result<[u8; 32], String> {
+    let public_key = subtle_encoding::base64::decode(&anchor.public_key)
+        .map_err(|e| format!("invalid anchor public key: {}", e))?;
+    if anchor_key.map_or(false, |key| key != public_key.as_slice()) {
+        return Err(format!(
+            "the anchor is signed with an unexpected key {}",
+            anchor.public_key
+        ));
+    }
+    let start = decode_head(&anchor.start)?;
+    let head = decode_head(&anchor.head)?;
+    if start != [0; 32] && !heads.contains(&start) {
+        return Err("the anchor doesn't continue a previous anchor".to_owned());
+    }
+    let mut chained = start;
+    for sequence in anchor.covers.iter() {
+        let digest = unanchored
+            .remove(sequence)
+            .ok_or_else(|| format!("anchored record {} is missing or anchored twice", sequence))?;
+        chained = chain_head(&chained, &digest);
+    }
+    if chained != head {
+        return Err("the anchored records don't match the anchor".to_owned());
+    }
+    AuditAnchor {
+        start,
+        head,
+        records: anchor.covers.len() as u64,
+        public_key,
+        signature: subtle_encoding::base64::decode(&anchor.signature)
+            .map_err(|e| format!("invalid anchor signature: {}", e))?,
+    }
+    .verify()?;
+    Ok(head)
+}
+
+/// Checks the hash chain and the sequence numbers of all the records, and the enclave's anchors
+/// (which must be signed with `anchor_key`, if set)
+pub fn verify(reader: impl BufRead, anchor_key: Option<&[u8]>) -> Result<AuditSummary, String> {
+    let mut summary = AuditSummary::default();
+    // the digests of the records no anchor covers yet, and the heads of the anchors
+    let mut unanchored = BTreeMap::new();
+    let mut heads = HashSet::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| format!("failed to read the audit log: {:?}", e))?;
+        let record: AuditRecord = serde_json::from_str(&line)
+            .map_err(|e| format!("record {}: invalid audit record: {}", i, e))?;
+        if record.sequence() != summary.records {
+            return Err(format!(
+                "record {}: unexpected sequence number {}",
+                i,
+                record.sequence()
+            ));
+        }
+        if record.prev_hash() != summary.last_hash {
+            return Err(format!("record {}: broken hash chain", i));
+        }
+        match &record {
+            AuditRecord::V1(signed) => {
+                let digest =
+                    signed_record_digest(signed).map_err(|e| format!("record {}: {}", i, e))?;
+                unanchored.insert(signed.sequence, digest);
+            }
+            AuditRecord::RefusalV1(refused) => {
+                let digest =
+                    refused_record_digest(refused).map_err(|e| format!("record {}: {}", i, e))?;
+                unanchored.insert(refused.sequence, digest);
+            }
+            AuditRecord::AnchorV1(anchor) => {
+                let head = verify_anchor(anchor, anchor_key, &mut unanchored, &heads)
+                    .map_err(|e| format!("record {}: {}", i, e))?;
+                heads.insert(head);
+                summary.anchors += 1;
+                summary.anchor_keys.insert(anchor.public_key.clone());
+            }
+        }
+        summary.records += 1;
+        summary.last_hash = line_hash(&line);
+    }
+    summary.unanchored = unanchored.len() as u64;
+    Ok(summary)
+}
+
+/// append-only writer of the audit log
+pub struct AuditLog {
+    file: File,
+    next: AuditSummary,
+}
+
+impl AuditLog {
+    /// opens (or creates) the log at the path
+    /// (the existing records are verified and their hash chain is continued)
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let next = if path.exists() {
+            let file = File::open(path)
+                .map_err(|e| format!("failed to open `{}`: {:?}", path.display(), e))?;
+            verify(BufReader::new(file), None)?
+        } else {
+            AuditSummary::default()
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("failed to open `{}`: {:?}", path.display(), e))?;
+        Ok(Self { file, next })
+    }
+
+    /// appends the record of the signature and syncs it to disk; returns its sequence number
+    pub fn append(&mut self, msg: &SignedMessage, timestamp: String) -> Result<u64, String> {
+        self.append_record(AuditRecord::V1(AuditRecordV1 {
+            sequence: self.next.records,
+            timestamp,
+            chain_id: msg.chain_id.to_string(),
+            msg_type: msg.msg_type.as_str().to_owned(),
+            height: msg.height,
+            round: msg.round,
+            block_id_hash: msg.block_id_hash.as_ref().map(hex),
+            signature: base64(&msg.signature),
+            prev_hash: self.next.last_hash.clone(),
+        }))
+    }
+
+    /// appends the record of the refused request and syncs it to disk; returns its sequence number
+    pub fn append_refusal(
+        &mut self,
+        msg: &RefusedMessage,
+        timestamp: String,
+    ) -> Result<u64, String> {
+        self.append_record(AuditRecord::RefusalV1(AuditRefusalV1 {
+            sequence: self.next.records,
+            timestamp,
+            chain_id: msg.chain_id.to_string(),
+            msg_type: msg.msg_type.as_str().to_owned(),
+            height: msg.height,
+            round: msg.round,
+            reason: msg.reason.clone(),
+            sign_bytes: hex(&msg.sign_bytes),
+            prev_hash: self.next.last_hash.clone(),
+        }))
+    }
+
+    /// appends the enclave's anchor of the records (by their sequence numbers) and syncs it to disk
+    pub fn append_anchor(
+        &mut self,
+        anchor: &AuditAnchor,
+        covers: Vec<u64>,
+        timestamp: String,
+    ) -> Result<u64, String> {
+        self.append_record(AuditRecord::AnchorV1(AuditAnchorV1 {
+            sequence: self.next.records,
+            timestamp,
+            covers,
+            start: hex(anchor.start),
+            head: hex(anchor.head),
+            public_key: base64(&anchor.public_key),
+            signature: base64(&anchor.signature),
+            prev_hash: self.next.last_hash.clone(),
+        }))
+    }
+
+    fn append_record(&mut self, record: AuditRecord) -> Result<u64, String> {
+        let line = serde_json::to_string(&record)
+            .map_err(|e| format!("failed to serialize the audit record: {:?}", e))?;
+        writeln!(self.file, "{}", line)
+            .and_then(|_| self.file.sync_data())
+            .map_err(|e| format!("failed to write the audit record: {:?}", e))?;
+        let sequence = self.next.records;
+        self.next.records += 1;
+        self.next.last_hash = line_hash(&line);
+        Ok(sequence)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tmkms_light::audit::SignedMsgKind;
+
+    fn signed(height: i64) -> SignedMessage {
+        SignedMessage {
+            chain_id: "testnet-croeseid-4".parse().unwrap(),
+            msg_type: SignedMsgKind::Prevote,
+            height,
+            round: 0,
+            block_id_hash: Some(vec![1; 32]),
+            signature: vec![2; 64],
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn chain_continues_after_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let mut log = AuditLog::open(&path).unwrap();
+        log.append(&signed(1), "2021-01-01T00:00:00Z".into())
+            .unwrap();
+        drop(log);
+        let mut log = AuditLog::open(&path).unwrap();
+        log.append(&signed(2), "2021-01-01T00:00:01Z".into())
+            .unwrap();
+        log.append_refusal(
+            &RefusedMessage {
+                chain_id: "testnet-croeseid-4".parse().unwrap(),
+                msg_type: SignedMsgKind::Prevote,
+                height: 2,
+                round: 0,
+                reason: "double signing requested at height: 2".into(),
+                sign_bytes: vec![3; 16],
+            },
+            "2021-01-01T00:00:02Z".into(),
+        )
+        .unwrap();
+        let summary = verify(BufReader::new(File::open(&path).unwrap()), None).unwrap();
+        assert_eq!(summary.records, 3);
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let mut log = AuditLog::open(&path).unwrap();
+        log.append(&signed(1), "2021-01-01T00:00:00Z".into())
+            .unwrap();
+        log.append(&signed(2), "2021-01-01T00:00:01Z".into())
+            .unwrap();
+        let tampered =
+            std::fs::read_to_string(&path)
+                .unwrap()
+                .replacen("\"height\":1", "\"height\":3", 1);
+        assert!(verify(tampered.as_bytes(), None).is_err());
+        let truncated: String = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .collect();
+        assert!(verify(truncated.as_bytes(), None).is_err());
+    }
+
+    #[test]
+    fn anchors_cover_the_enclave_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let key = ed25519_consensus::SigningKey::new(rand_core::OsRng);
+        let mut log = AuditLog::open(&path).unwrap();
+        let mut head = [0; 32];
+        let mut covers = Vec::new();
+        for height in 1..=2 {
+            let msg = signed(height);
+            covers.push(log.append(&msg, "2021-01-01T00:00:00Z".into()).unwrap());
+            head = chain_head(&head, &msg.digest());
+        }
+        let anchor = AuditAnchor::sign(&key, [0; 32], head, 2);
+        log.append_anchor(&anchor, covers, "2021-01-01T00:00:01Z".into())
+            .unwrap();
+        let msg = signed(3);
+        let sequence = log.append(&msg, "2021-01-01T00:00:02Z".into()).unwrap();
+        let anchor = AuditAnchor::sign(&key, head, chain_head(&head, &msg.digest()), 1);
+        log.append_anchor(&anchor, vec![sequence], "2021-01-01T00:00:03Z".into())
+            .unwrap();
+        log.append(&signed(4), "2021-01-01T00:00:04Z".into())
+            .unwrap();
+        drop(log);
+        let content = std::fs::read_to_string(&path).unwrap();
+        let public_key = key.verification_key().to_bytes();
+        let summary = verify(content.as_bytes(), Some(&public_key)).unwrap();
+        assert_eq!(
+            (summary.records, summary.anchors, summary.unanchored),
+            (6, 2, 1)
+        );
+        // the anchors are only trusted with the validator's key
+        let other_key = ed25519_consensus::SigningKey::new(rand_core::OsRng)
+            .verification_key()
+            .to_bytes();
+        assert!(verify(content.as_bytes(), Some(&other_key)).is_err());
+        // the host rewrites the log: an anchored record is altered...
+        let mut log = AuditLog::open(dir.path().join("rewritten.log")).unwrap();
+        let mut tampered = signed(1);
+        tampered.signature = vec![3; 64];
+        let first = log
+            .append(&tampered, "2021-01-01T00:00:00Z".into())
+            .unwrap();
+        let second = log
+            .append(&signed(2), "2021-01-01T00:00:00Z".into())
+            .unwrap();
+        let anchor = AuditAnchor::sign(&key, [0; 32], head, 2);
+        log.append_anchor(&anchor, vec![first, second], "2021-01-01T00:00:01Z".into())
+            .unwrap();
+        drop(log);
+        let rewritten = std::fs::read_to_string(dir.path().join("rewritten.log")).unwrap();
+        assert!(verify(rewritten.as_bytes(), None).is_err());
+        // ... or the first anchor with its records is dropped
+        let mut log = AuditLog::open(dir.path().join("dropped.log")).unwrap();
+        let sequence = log.append(&msg, "2021-01-01T00:00:02Z".into()).unwrap();
+        let anchor = AuditAnchor::sign(&key, head, chain_head(&head, &msg.digest()), 1);
+        log.append_anchor(&anchor, vec![sequence], "2021-01-01T00:00:03Z".into())
+            .unwrap();
+        drop(log);
+        let dropped = std::fs::read_to_string(dir.path().join("dropped.log")).unwrap();
+        assert!(verify(dropped.as_bytes(), None).is_err());
+    }
+}
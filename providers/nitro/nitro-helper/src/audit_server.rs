@@ -0,0 +1,90 @@
+use crate::mux_server::ChannelListener;
+use std::path::Path;
+use std::thread;
+use tendermint::Time;
+use tmkms_light::audit::AuditEntry;
+use tmkms_light::utils::{read_u16_payload, write_u16_payload};
+use tmkms_nitro_helper::audit::AuditLog;
+use tmkms_nitro_helper::channel::ChannelStream;
+use tracing::{error, info, warn};
+
+/// receives the signature (and refusal) records from the enclave and appends them to the audit log
+/// (the enclave only releases the signature after it's acknowledged), together with the enclave's
+/// anchors of the records sent on the connection
+pub struct AuditServer {
+    audit_log: AuditLog,
+    vsock_listener: ChannelListener,
+}
+
+impl AuditServer {
+    /// opens the audit log and binds a listener for the enclave on the provided port
+    pub fn new(path: impl AsRef<Path>, vsock_port: u32) -> Result<Self, String> {
+        let audit_log = AuditLog::open(path)?;
+        let vsock_listener = ChannelListener::bind(vsock_port)
+            .map_err(|e| format!("failed to bind the audit listener: {:?}", e))?;
+        Ok(Self {
+            audit_log,
+            vsock_listener,
+        })
+    }
+
+    /// records a signature (or an anchor of the `covered` records) and acknowledges
+    /// the result to the enclave
+    fn record(&mut self, stream: &mut ChannelStream, covered: &mut Vec<u64>) -> Result<(), String> {
+        let json_raw = read_u16_payload(stream).map_err(|e| format!("{}", e))?;
+        let result = serde_json::from_slice::<AuditEntry>(&json_raw)
+            .map_err(|e| format!("invalid signature record: {:?}", e))
+            .and_then(|entry| match entry {
+                // the enclave's (synchronized) time of the signature if it's known
+                AuditEntry::Signed(msg) => {
+                    let timestamp = msg.timestamp.unwrap_or_else(Time::now);
+                    let sequence = self.audit_log.append(&msg, timestamp.to_rfc3339())?;
+                    covered.push(sequence);
+                    Ok(())
+                }
+                AuditEntry::Refused(msg) => {
+                    let sequence = self
+                        .audit_log
+                        .append_refusal(&msg, Time::now().to_rfc3339())?;
+                    covered.push(sequence);
+                    Ok(())
+                }
+                // the enclave anchors the records it sent since its previous anchor
+                AuditEntry::Anchor(anchor) => {
+                    if anchor.records != covered.len() as u64 {
+                        return Err(format!(
+                            "the anchor covers {} records, {} were recorded",
+                            anchor.records,
+                            covered.len()
+                        ));
+                    }
+                    self.audit_log.append_anchor(
+                        &anchor,
+                        std::mem::take(covered),
+                        Time::now().to_rfc3339(),
+                    )?;
+                    Ok(())
+                }
+            });
+        if let Err(ref e) = result {
+            error!("failed to record a signature: {}", e);
+        }
+        let ack = serde_json::to_vec(&result).map_err(|e| format!("{:?}", e))?;
+        write_u16_payload(stream, &ack).map_err(|e| format!("{:?}", e))
+    }
+
+    /// serves the enclave connections in a separate thread
+    pub fn launch(mut self) {
+        thread::spawn(move || {
+            info!("listening for enclave signature records");
+            while let Ok((mut stream, _)) = self.vsock_listener.accept() {
+                info!("vsock audit connection established");
+                // the enclave starts a new chain on each connection
+                let mut covered = Vec::new();
+                while let Ok(()) = self.record(&mut stream, &mut covered) {}
+                warn!("vsock audit connection lost");
+            }
+            error!("audit listener failed");
+        });
+    }
+}
@@ -0,0 +1,137 @@
+//! Exponential backoff with jitter for reconnections
+
+use rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// reconnection backoff settings
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct BackoffConfig {
+    /// delay after the first failure
+    pub initial_delay_ms: u64,
+    /// upper bound of the delay
+    pub max_delay_ms: u64,
+    /// delay multiplier after each consecutive failure
+    pub multiplier: u32,
+    /// random deviation of the delay (in percent)
+    pub jitter_percent: u8,
+    /// give up after this many consecutive failures (retry forever if not set)
+    pub max_retries: Option<u32>,
+    /// alert after every this many consecutive failures (if set)
+    pub alert_after: Option<u32>,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay_ms: 1000,
+            max_delay_ms: 30000,
+            multiplier: 2,
+            jitter_percent: 20,
+            max_retries: None,
+            alert_after: Some(10),
+        }
+    }
+}
+
+/// consecutive failure tracking
+#[derive(Debug)]
+pub struct Backoff {
+    config: BackoffConfig,
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(config: BackoffConfig) -> Self {
+        Self {
+            config,
+            failures: 0,
+        }
+    }
+
+    /// to be called after a successful connection
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    /// number of consecutive failures
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// the alert threshold was reached with the last failure
+    pub fn should_alert(&self) -> bool {
+        matches!(self.config.alert_after, Some(n) if n > 0 && self.failures > 0 && self.failures % n == 0)
+    }
+
+    /// Registers a failure and returns the delay before the next attempt
+    /// (`None` if the retry budget is exhausted)
+    pub fn next_delay<R: RngCore>(&mut self, rng: &mut R) -> Option<Duration> {
+        self.failures = self.failures.saturating_add(1);
+        if matches!(self.config.max_retries, Some(max) if self.failures > max) {
+            return None;
+        }
+        let factor = u64::from(self.config.multiplier.max(1))
+            .checked_pow(self.failures - 1)
+            .unwrap_or(u64::MAX);
+        let delay = self
+            .config
+            .initial_delay_ms
+            .saturating_mul(factor)
+            .min(self.config.max_delay_ms);
+        let jitter = delay * u64::from(self.config.jitter_percent.min(100)) / 100;
+        let delay = if jitter > 0 {
+            delay - jitter + rng.next_u64() % (2 * jitter + 1)
+        } else {
+            delay
+        };
+        Some(Duration::from_millis(delay))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_core::OsRng;
+
+    #[test]
+    fn delays_grow_until_budget_is_exhausted() {
+        let mut backoff = Backoff::new(BackoffConfig {
+            initial_delay_ms: 100,
+            max_delay_ms: 300,
+            multiplier: 2,
+            jitter_percent: 0,
+            max_retries: Some(3),
+            alert_after: Some(2),
+        });
+        assert_eq!(
+            backoff.next_delay(&mut OsRng),
+            Some(Duration::from_millis(100))
+        );
+        assert!(!backoff.should_alert());
+        assert_eq!(
+            backoff.next_delay(&mut OsRng),
+            Some(Duration::from_millis(200))
+        );
+        assert!(backoff.should_alert());
+        assert_eq!(
+            backoff.next_delay(&mut OsRng),
+            Some(Duration::from_millis(300))
+        );
+        assert_eq!(backoff.next_delay(&mut OsRng), None);
+        backoff.reset();
+        assert_eq!(backoff.failures(), 0);
+    }
+
+    #[test]
+    fn jitter_stays_in_bounds() {
+        let mut backoff = Backoff::new(BackoffConfig {
+            initial_delay_ms: 1000,
+            jitter_percent: 20,
+            ..Default::default()
+        });
+        let delay = backoff.next_delay(&mut OsRng).unwrap();
+        assert!(delay >= Duration::from_millis(800) && delay <= Duration::from_millis(1200));
+    }
+}
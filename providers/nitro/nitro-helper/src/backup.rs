@@ -0,0 +1,355 @@
+//! Shamir secret-sharing backup of the sealed consensus key (or master seed): the enclave splits
+//! the secret into shares encrypted to the operators' X25519 keys, and any `threshold` of them
+//! can be re-encrypted (by their operators) to a new enclave that reassembles and seals it.
+//! The shares are over GF(2^8) byte-wise, so secrets of any length can be split.
+
+use crate::key_shares::{x25519_public_key, x25519_scalar};
+use crate::slip10::DerivationPath;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use hkdf::Hkdf;
+use rand_core::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+use zeroize::{Zeroize, Zeroizing};
+
+/// domain separation for deriving the share encryption keys
+const BACKUP_KDF_INFO: &[u8] = b"tmkms-light backup share v1";
+
+/// `k/n`: any `threshold` of the `shares` restore the key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShamirThreshold {
+    pub threshold: u8,
+    pub shares: u8,
+}
+
+impl FromStr for ShamirThreshold {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (threshold, shares) = s
+            .split_once('/')
+            .ok_or_else(|| format!("`{}` isn't `k/n`", s))?;
+        let threshold: u8 = threshold
+            .parse()
+            .map_err(|_| format!("invalid threshold `{}`", threshold))?;
+        let shares: u8 = shares
+            .parse()
+            .map_err(|_| format!("invalid number of shares `{}`", shares))?;
+        if threshold == 0 || threshold > shares {
+            return Err(format!("invalid threshold {}/{}", threshold, shares));
+        }
+        Ok(Self { threshold, shares })
+    }
+}
+
+impl fmt::Display for ShamirThreshold {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.threshold, self.shares)
+    }
+}
+
+/// a share encrypted to an X25519 key (an operator's, or the restoring enclave's)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupShare {
+    /// evaluation point of the share (1-based)
+    pub index: u8,
+    /// X25519 public key of the recipient
+    pub recipient: [u8; 32],
+    /// ephemeral X25519 public key used for this share
+    pub ephemeral_public_key: [u8; 32],
+    /// ChaCha20Poly1305 nonce
+    pub nonce: [u8; 12],
+    /// the encrypted share
+    pub ciphertext: Vec<u8>,
+}
+
+/// the backup of the consensus key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyBackup {
+    /// number of shares needed to restore the key
+    pub threshold: u8,
+    /// the consensus public key (checked by the restoring enclave)
+    pub public_key: [u8; 32],
+    /// path of the consensus key if the secret is a master seed
+    pub derivation_path: Option<DerivationPath>,
+    /// one share per operator
+    pub shares: Vec<BackupShare>,
+}
+
+/// Possible errors in backing up or restoring a key
+#[derive(Debug, PartialEq, Eq)]
+pub enum BackupError {
+    /// threshold is zero or more than the number of (distinct) recipients
+    InvalidThreshold,
+    /// AEAD encryption or decryption failed
+    EncryptionError,
+    /// fewer than the threshold of shares, duplicates or shares of different lengths
+    InvalidShares,
+}
+
+impl KeyBackup {
+    /// digest of the backup (to be bound in an attestation)
+    pub fn digest(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update([self.threshold]);
+        hasher.update(self.public_key);
+        if let Some(path) = &self.derivation_path {
+            hasher.update(path.to_string());
+        }
+        for share in self.shares.iter() {
+            hasher.update([share.index]);
+            hasher.update(share.recipient);
+            hasher.update(share.ephemeral_public_key);
+            hasher.update(share.nonce);
+            hasher.update(&share.ciphertext);
+        }
+        hasher.finalize().into()
+    }
+}
+
+/// multiplication in GF(2^8) (the AES polynomial), without secret-dependent branches
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0u8;
+    for _ in 0..8 {
+        product ^= a & 0u8.wrapping_sub(b & 1);
+        let carry = 0u8.wrapping_sub(a >> 7);
+        a = (a << 1) ^ (0x1b & carry);
+        b >>= 1;
+    }
+    product
+}
+
+/// inverse in GF(2^8) (a^254)
+fn gf_inv(a: u8) -> u8 {
+    let mut result = 1u8;
+    let mut base = a;
+    let mut exponent = 254u8;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = gf_mul(result, base);
+        }
+        base = gf_mul(base, base);
+        exponent >>= 1;
+    }
+    result
+}
+
+/// derives the share encryption key from the X25519 shared secret
+fn share_key(shared_secret: &MontgomeryPoint, ephemeral: &[u8; 32], recipient: &[u8; 32]) -> Key {
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(ephemeral);
+    salt[32..].copy_from_slice(recipient);
+    let hk = Hkdf::<Sha256>::new(Some(&salt), shared_secret.as_bytes());
+    let mut okm = Zeroizing::new([0u8; 32]);
+    hk.expand(BACKUP_KDF_INFO, &mut okm[..])
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    *Key::from_slice(&okm[..])
+}
+
+fn share_aad(index: u8, recipient: &[u8; 32]) -> [u8; 33] {
+    let mut aad = [0u8; 33];
+    aad[0] = index;
+    aad[1..].copy_from_slice(recipient);
+    aad
+}
+
+/// encrypts the share to the recipient's X25519 key
+fn encrypt_share<R: RngCore + CryptoRng>(
+    csprng: &mut R,
+    index: u8,
+    share: &[u8],
+    recipient: &[u8; 32],
+) -> Result<BackupShare, BackupError> {
+    let mut ephemeral_secret = [0u8; 32];
+    csprng.fill_bytes(&mut ephemeral_secret);
+    let ephemeral_public_key = x25519_public_key(&ephemeral_secret);
+    let shared_secret = MontgomeryPoint(*recipient) * x25519_scalar(ephemeral_secret);
+    ephemeral_secret.zeroize();
+    let key = share_key(&shared_secret, &ephemeral_public_key, recipient);
+    let mut nonce = [0u8; 12];
+    csprng.fill_bytes(&mut nonce);
+    let ciphertext = ChaCha20Poly1305::new(&key)
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: share,
+                aad: &share_aad(index, recipient),
+            },
+        )
+        .map_err(|_| BackupError::EncryptionError)?;
+    Ok(BackupShare {
+        index,
+        recipient: *recipient,
+        ephemeral_public_key,
+        nonce,
+        ciphertext,
+    })
+}
+
+/// Decrypts the share with the recipient's X25519 secret
+pub fn decrypt_share(
+    share: &BackupShare,
+    recipient_secret: &[u8; 32],
+) -> Result<Zeroizing<Vec<u8>>, BackupError> {
+    let shared_secret =
+        MontgomeryPoint(share.ephemeral_public_key) * x25519_scalar(*recipient_secret);
+    let key = share_key(
+        &shared_secret,
+        &share.ephemeral_public_key,
+        &share.recipient,
+    );
+    ChaCha20Poly1305::new(&key)
+        .decrypt(
+            Nonce::from_slice(&share.nonce),
+            Payload {
+                msg: &share.ciphertext,
+                aad: &share_aad(share.index, &share.recipient),
+            },
+        )
+        .map(Zeroizing::new)
+        .map_err(|_| BackupError::EncryptionError)
+}
+
+/// Splits the secret into `recipients.len()` shares (any `threshold` of which restore it)
+/// and encrypts each to its recipient
+pub fn split_secret<R: RngCore + CryptoRng>(
+    csprng: &mut R,
+    secret: &[u8],
+    threshold: u8,
+    recipients: &[[u8; 32]],
+) -> Result<Vec<BackupShare>, BackupError> {
+    let distinct = recipients
+        .iter()
+        .enumerate()
+        .all(|(i, r)| !recipients[..i].contains(r));
+    if threshold == 0
+        || threshold as usize > recipients.len()
+        || recipients.len() > 255
+        || !distinct
+    {
+        return Err(BackupError::InvalidThreshold);
+    }
+    // one random polynomial per secret byte (its constant term)
+    let mut coefficients = Zeroizing::new(vec![0u8; secret.len() * threshold as usize]);
+    for (i, byte) in secret.iter().enumerate() {
+        let poly = &mut coefficients[i * threshold as usize..(i + 1) * threshold as usize];
+        poly[0] = *byte;
+        csprng.fill_bytes(&mut poly[1..]);
+    }
+    let mut shares = Vec::with_capacity(recipients.len());
+    for (i, recipient) in recipients.iter().enumerate() {
+        let index = (i + 1) as u8;
+        // Horner evaluation of each polynomial at `index`
+        let share = Zeroizing::new(
+            coefficients
+                .chunks(threshold as usize)
+                .map(|poly| poly.iter().rev().fold(0, |acc, c| gf_mul(acc, index) ^ c))
+                .collect::<Vec<u8>>(),
+        );
+        shares.push(encrypt_share(csprng, index, &share, recipient)?);
+    }
+    Ok(shares)
+}
+
+/// Decrypts an operator's share and re-encrypts it to the restoring enclave's
+/// (attested) X25519 key -- the operator side of a restore
+pub fn reencrypt_share<R: RngCore + CryptoRng>(
+    csprng: &mut R,
+    share: &BackupShare,
+    operator_secret: &[u8; 32],
+    enclave_public_key: &[u8; 32],
+) -> Result<BackupShare, BackupError> {
+    let plaintext = decrypt_share(share, operator_secret)?;
+    encrypt_share(csprng, share.index, &plaintext, enclave_public_key)
+}
+
+/// Reassembles the secret from (at least `threshold`) decrypted shares
+/// (Lagrange interpolation at zero)
+pub fn combine_shares(
+    threshold: u8,
+    shares: &[(u8, Zeroizing<Vec<u8>>)],
+) -> Result<Zeroizing<Vec<u8>>, BackupError> {
+    let shares = shares
+        .get(..threshold as usize)
+        .ok_or(BackupError::InvalidShares)?;
+    let len = shares.first().map(|s| s.1.len()).unwrap_or(0);
+    let valid = shares.iter().enumerate().all(|(i, (index, share))| {
+        *index != 0 && share.len() == len && shares[..i].iter().all(|(j, _)| j != index)
+    });
+    if threshold == 0 || !valid {
+        return Err(BackupError::InvalidShares);
+    }
+    let mut secret = Zeroizing::new(vec![0u8; len]);
+    for (i, (xi, yi)) in shares.iter().enumerate() {
+        let mut basis = 1u8;
+        for (j, (xj, _)) in shares.iter().enumerate() {
+            if i != j {
+                // in GF(2^8), subtraction is xor
+                basis = gf_mul(basis, gf_mul(*xj, gf_inv(xj ^ xi)));
+            }
+        }
+        for (s, y) in secret.iter_mut().zip(yi.iter()) {
+            *s ^= gf_mul(basis, *y);
+        }
+    }
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_core::OsRng;
+
+    fn recipient(csprng: &mut OsRng) -> ([u8; 32], [u8; 32]) {
+        let mut secret = [0u8; 32];
+        csprng.fill_bytes(&mut secret);
+        (secret, x25519_public_key(&secret))
+    }
+
+    #[test]
+    fn threshold_of_shares_restores_the_secret() {
+        let mut csprng = OsRng;
+        let mut secret = [0u8; 64];
+        csprng.fill_bytes(&mut secret);
+        let operators: Vec<_> = (0..3).map(|_| recipient(&mut csprng)).collect();
+        let pubkeys: Vec<_> = operators.iter().map(|r| r.1).collect();
+        let shares = split_secret(&mut csprng, &secret, 2, &pubkeys).unwrap();
+        let (enclave_secret, enclave_public) = recipient(&mut csprng);
+
+        let reencrypted: Vec<_> = [2, 0]
+            .iter()
+            .map(|&i| {
+                reencrypt_share(&mut csprng, &shares[i], &operators[i].0, &enclave_public).unwrap()
+            })
+            .collect();
+        let decrypted: Vec<_> = reencrypted
+            .iter()
+            .map(|share| (share.index, decrypt_share(share, &enclave_secret).unwrap()))
+            .collect();
+        assert_eq!(&combine_shares(2, &decrypted).unwrap()[..], &secret[..]);
+        assert_eq!(
+            combine_shares(2, &decrypted[..1]).unwrap_err(),
+            BackupError::InvalidShares
+        );
+        assert_eq!(
+            decrypt_share(&shares[0], &operators[1].0).unwrap_err(),
+            BackupError::EncryptionError
+        );
+        assert_eq!(
+            split_secret(&mut csprng, &secret, 2, &[pubkeys[0], pubkeys[0]]).unwrap_err(),
+            BackupError::InvalidThreshold
+        );
+        assert_eq!(
+            "2/3".parse::<ShamirThreshold>().unwrap(),
+            ShamirThreshold {
+                threshold: 2,
+                shares: 3
+            }
+        );
+        assert!("4/3".parse::<ShamirThreshold>().is_err());
+    }
+}
@@ -0,0 +1,145 @@
+//! Queries of a CometBFT node's RPC: the latest block height (to rebuild a corrupted state)
+//! and the last height committed with the validator's signature (an independent backstop
+//! to the persisted state, checked before the enclave's first signature after a restart)
+
+use crate::http::http_request;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{info, warn};
+
+/// `block_id_flag` of the commit signatures that are absent
+const BLOCK_ID_FLAG_ABSENT: u64 = 1;
+
+/// settings of the cross-check with the chain
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChainRpcCheckConfig {
+    /// the node's RPC (`host:port`)
+    pub rpc_addr: String,
+    /// hex-encoded address of the validator (by default, the node's own validator's from `/status`)
+    pub validator_address: Option<String>,
+    /// how many blocks are searched back for the validator's last signature
+    #[serde(default = "default_max_lookback")]
+    pub max_lookback: u64,
+    /// refuse to start if the node can't be queried (otherwise, the check is skipped)
+    #[serde(default)]
+    pub required: bool,
+}
+
+fn default_max_lookback() -> u64 {
+    10
+}
+
+fn rpc_get(rpc_addr: &str, path: &str) -> Result<Value, String> {
+    let (status, body) = http_request(rpc_addr, "GET", path, &[], &[])?;
+    if status != 200 {
+        return Err(format!(
+            "{} answered {} with HTTP {}",
+            rpc_addr, path, status
+        ));
+    }
+    serde_json::from_slice(&body).map_err(|e| format!("invalid RPC answer to {}: {}", path, e))
+}
+
+fn height_at(json: &Value, pointer: &str) -> Result<u64, String> {
+    json.pointer(pointer)
+        .and_then(|h| h.as_str())
+        .and_then(|h| h.parse().ok())
+        .ok_or_else(|| format!("no height at {} in the RPC answer", pointer))
+}
+
+/// the latest block height reported by a node's RPC
+pub fn latest_block_height(rpc_addr: &str) -> Result<u64, String> {
+    height_at(
+        &rpc_get(rpc_addr, "/status")?,
+        "/result/sync_info/latest_block_height",
+    )
+}
+
+/// the height of the block's last commit if it includes the validator's signature
+fn signed_commit_height(block: &Value, validator_address: &str) -> Result<Option<u64>, String> {
+    let signatures = block
+        .pointer("/result/block/last_commit/signatures")
+        .and_then(|s| s.as_array())
+        .ok_or_else(|| "no last commit in the RPC block".to_owned())?;
+    let signed = signatures.iter().any(|signature| {
+        signature["validator_address"]
+            .as_str()
+            .map_or(false, |a| a.eq_ignore_ascii_case(validator_address))
+            && signature["block_id_flag"].as_u64() != Some(BLOCK_ID_FLAG_ABSENT)
+    });
+    if signed {
+        height_at(block, "/result/block/last_commit/height").map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+impl ChainRpcCheckConfig {
+    /// the last height (within `max_lookback` blocks) committed with the validator's signature
+    pub fn committed_height(&self) -> Result<Option<u64>, String> {
+        let status = rpc_get(&self.rpc_addr, "/status")?;
+        let validator_address = match &self.validator_address {
+            Some(address) => address.clone(),
+            None => status
+                .pointer("/result/validator_info/address")
+                .and_then(|a| a.as_str())
+                .ok_or_else(|| "no validator address in the RPC status".to_owned())?
+                .to_owned(),
+        };
+        let latest = height_at(&status, "/result/sync_info/latest_block_height")?;
+        let oldest = latest.saturating_sub(self.max_lookback).max(2);
+        for height in (oldest..=latest).rev() {
+            let block = rpc_get(&self.rpc_addr, &format!("/block?height={}", height))?;
+            if let Some(committed) = signed_commit_height(&block, &validator_address)? {
+                return Ok(Some(committed));
+            }
+        }
+        Ok(None)
+    }
+
+    /// the height the enclave mustn't sign at or below (if the check succeeded and found one)
+    pub fn check(&self) -> Result<Option<u64>, String> {
+        match self.committed_height() {
+            Ok(Some(height)) => {
+                info!("the chain committed height {} with our signature", height);
+                Ok(Some(height))
+            }
+            Ok(None) => {
+                warn!(
+                    "no signature of the validator in the last {} blocks",
+                    self.max_lookback
+                );
+                Ok(None)
+            }
+            Err(e) if self.required => Err(format!("the chain RPC check failed: {}", e)),
+            Err(e) => {
+                warn!("the chain RPC check is skipped: {}", e);
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_validator_signature() {
+        let block: Value = serde_json::from_str(
+            r#"{"result": {"block": {"last_commit": {
+                "height": "41",
+                "signatures": [
+                    {"block_id_flag": 2, "validator_address": "AAAA"},
+                    {"block_id_flag": 1, "validator_address": ""},
+                    {"block_id_flag": 3, "validator_address": "BBBB"}
+                ]
+            }}}}"#,
+        )
+        .unwrap();
+        assert_eq!(signed_commit_height(&block, "aaaa").unwrap(), Some(41));
+        assert_eq!(signed_commit_height(&block, "BBBB").unwrap(), Some(41));
+        assert_eq!(signed_commit_height(&block, "CCCC").unwrap(), None);
+    }
+}
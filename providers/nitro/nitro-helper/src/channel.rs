@@ -0,0 +1,188 @@
+//! Streams between the enclave and the host: vsock, or TCP on the loopback interface
+//! in the local development mode (`--dev-plaintext`), where the vsock ports are used as TCP ports
+//! and the CIDs are ignored, so that the signer can be run without Nitro hardware.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use vsock::{VsockAddr, VsockListener, VsockStream};
+
+/// whether the channels are TCP connections on the loopback interface
+static DEV_TCP: AtomicBool = AtomicBool::new(false);
+
+/// switches the channels to TCP on the loopback interface (the local development mode)
+pub fn enable_dev_tcp() {
+    DEV_TCP.store(true, Ordering::Relaxed);
+}
+
+/// the channels are TCP connections on the loopback interface
+pub fn dev_tcp() -> bool {
+    DEV_TCP.load(Ordering::Relaxed)
+}
+
+fn loopback_addr(port: u32) -> io::Result<SocketAddr> {
+    let port = u16::try_from(port).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("port {} can't be used with TCP", port),
+        )
+    })?;
+    Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+}
+
+/// address of a channel's end
+#[derive(Debug, Clone, Copy)]
+pub enum ChannelAddr {
+    Vsock(VsockAddr),
+    Tcp(SocketAddr),
+}
+
+impl fmt::Display for ChannelAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelAddr::Vsock(addr) => write!(f, "{}", addr),
+            ChannelAddr::Tcp(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+/// a connection between the enclave and the host
+#[derive(Debug)]
+pub enum ChannelStream {
+    Vsock(VsockStream),
+    Tcp(TcpStream),
+}
+
+impl ChannelStream {
+    /// connects to the port of the CID (or of the loopback interface in the development mode)
+    pub fn connect(cid: u32, port: u32) -> io::Result<Self> {
+        if dev_tcp() {
+            TcpStream::connect(loopback_addr(port)?).map(ChannelStream::Tcp)
+        } else {
+            VsockStream::connect(&VsockAddr::new(cid, port)).map(ChannelStream::Vsock)
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            ChannelStream::Vsock(stream) => stream.set_read_timeout(timeout),
+            ChannelStream::Tcp(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            ChannelStream::Vsock(stream) => stream.set_write_timeout(timeout),
+            ChannelStream::Tcp(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<ChannelAddr> {
+        match self {
+            ChannelStream::Vsock(stream) => stream.peer_addr().map(ChannelAddr::Vsock),
+            ChannelStream::Tcp(stream) => stream.peer_addr().map(ChannelAddr::Tcp),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<ChannelAddr> {
+        match self {
+            ChannelStream::Vsock(stream) => stream.local_addr().map(ChannelAddr::Vsock),
+            ChannelStream::Tcp(stream) => stream.local_addr().map(ChannelAddr::Tcp),
+        }
+    }
+}
+
+impl Read for ChannelStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ChannelStream::Vsock(stream) => stream.read(buf),
+            ChannelStream::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for ChannelStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ChannelStream::Vsock(stream) => stream.write(buf),
+            ChannelStream::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ChannelStream::Vsock(stream) => stream.flush(),
+            ChannelStream::Tcp(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsRawFd for ChannelStream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            ChannelStream::Vsock(stream) => stream.as_raw_fd(),
+            ChannelStream::Tcp(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+/// a listener for the connections between the enclave and the host
+#[derive(Debug)]
+pub enum PortListener {
+    Vsock(VsockListener),
+    Tcp(TcpListener),
+}
+
+impl PortListener {
+    /// binds the port of the CID (or of the loopback interface in the development mode)
+    pub fn bind(cid: u32, port: u32) -> io::Result<Self> {
+        if dev_tcp() {
+            TcpListener::bind(loopback_addr(port)?).map(PortListener::Tcp)
+        } else {
+            VsockListener::bind(&VsockAddr::new(cid, port)).map(PortListener::Vsock)
+        }
+    }
+
+    pub fn accept(&self) -> io::Result<(ChannelStream, ChannelAddr)> {
+        match self {
+            PortListener::Vsock(listener) => listener
+                .accept()
+                .map(|(stream, addr)| (ChannelStream::Vsock(stream), ChannelAddr::Vsock(addr))),
+            PortListener::Tcp(listener) => listener
+                .accept()
+                .map(|(stream, addr)| (ChannelStream::Tcp(stream), ChannelAddr::Tcp(addr))),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<ChannelAddr> {
+        match self {
+            PortListener::Vsock(listener) => listener.local_addr().map(ChannelAddr::Vsock),
+            PortListener::Tcp(listener) => listener.local_addr().map(ChannelAddr::Tcp),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dev_tcp_channel_roundtrip() {
+        enable_dev_tcp();
+        let listener = PortListener::bind(crate::VSOCK_HOST_CID, 0).unwrap();
+        let port = match listener.local_addr().unwrap() {
+            ChannelAddr::Tcp(addr) => addr.port(),
+            ChannelAddr::Vsock(_) => panic!("expected a TCP listener"),
+        };
+        let mut client = ChannelStream::connect(16, port.into()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        assert!(ChannelStream::connect(16, 70_000).is_err());
+    }
+}
@@ -0,0 +1,71 @@
+//! Encoding of the helper<->enclave requests and responses: CBOR (with the keys, nonces
+//! and attestation documents as byte strings), so that the attestation-bearing messages stay
+//! well below the framing limit. The unknown fields are ignored, so that a newer peer's
+//! additional fields don't break the older one.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+pub use serde_cbor::Error;
+
+/// encodes a request or a response
+pub fn encode<T: Serialize>(message: &T) -> Result<Vec<u8>, Error> {
+    serde_cbor::to_vec(message)
+}
+
+/// decodes a request or a response
+pub fn decode<T: DeserializeOwned>(message: &[u8]) -> Result<T, Error> {
+    serde_cbor::from_slice(message)
+}
+
+/// whether the (undecodable) message is JSON, i.e. sent by a peer of the protocol version 1
+pub fn is_legacy_json(message: &[u8]) -> bool {
+    serde_json::from_slice::<serde_json::Value>(message).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::{NitroHello, NitroKeygenResponse, NitroRequest, NitroResponse};
+
+    #[test]
+    fn messages_are_compact_and_tolerate_new_fields() {
+        let response: NitroResponse = Ok(NitroKeygenResponse {
+            encrypted_secret: vec![0xab; 200],
+            public_key: vec![0xcd; 32],
+            attestation_doc: vec![0xef; 4000],
+        });
+        let encoded = encode(&response).unwrap();
+        assert!(encoded.len() < 4300);
+        assert!(encoded.len() * 3 < serde_json::to_vec(&response).unwrap().len());
+        let decoded: NitroResponse = decode(&encoded).unwrap();
+        assert_eq!(decoded.unwrap().attestation_doc, vec![0xef; 4000]);
+
+        // a newer helper's hello (with an additional field)
+        let newer = serde_cbor::Value::Map(
+            [(
+                serde_cbor::Value::Text("Hello".to_owned()),
+                serde_cbor::Value::Map(
+                    [
+                        ("version", serde_cbor::Value::Integer(2)),
+                        ("capabilities", serde_cbor::Value::Array(vec![])),
+                        ("compression", serde_cbor::Value::Bool(true)),
+                    ]
+                    .into_iter()
+                    .map(|(k, v)| (serde_cbor::Value::Text(k.to_owned()), v))
+                    .collect(),
+                ),
+            )]
+            .into_iter()
+            .collect(),
+        );
+        let request: NitroRequest = decode(&encode(&newer).unwrap()).unwrap();
+        assert!(matches!(
+            request,
+            NitroRequest::Hello(NitroHello { version: 2, .. })
+        ));
+
+        assert!(decode::<NitroRequest>(br#"{"Hello":{"version":1}}"#).is_err());
+        assert!(is_legacy_json(br#"{"Hello":{"version":1}}"#));
+    }
+}
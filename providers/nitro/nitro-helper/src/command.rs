@@ -1,39 +1,170 @@
+pub mod backup;
+pub mod bench;
+pub mod chain;
+pub mod derive;
+pub mod key_shares;
 pub mod launch_all;
+pub mod migrate_state;
 pub mod nitro_enclave;
+pub mod provision;
+pub mod replay;
+pub mod rewrap;
+pub mod sign_payload;
+pub mod verify_sig;
 
-use std::sync::mpsc::Receiver;
+use rand_core::{OsRng, RngCore};
+use std::fmt;
+use std::io::Write;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 use std::{fs, path::PathBuf};
 use sysinfo::{ProcessExt, SystemExt};
+use tempfile::NamedTempFile;
 use tendermint_config::net;
-use tmkms_light::utils::write_u16_payload;
+use tmkms_light::connection::{ConnectionMode, Transport};
+use tmkms_light::metrics::SigningMetrics;
 use tmkms_light::utils::{print_pubkey, PubkeyDisplay};
-use vsock::VsockAddr;
+use tmkms_light::utils::{read_u16_payload, write_u16_payload};
+use tmkms_nitro_helper::channel::{dev_tcp, ChannelStream};
 
+use crate::admin::AdminServer;
+use crate::alerting::Alerter;
+use crate::approval::ApprovalServer;
+use crate::attestation::{
+    attest_enclave, attested_key, check_startup_attestation, hex_pcr, read_attestation_doc,
+    verify_attestation_doc, ExpectedPcrs,
+};
+use crate::attestation_archive::{
+    archive, read_archive, set_archive_dir, AttestationBundle, AttestationKind,
+};
+use crate::attestation_server::AttestationServer;
+use crate::audit_server::AuditServer;
+use crate::command::chain::CHAIN_REQUEST_TIMEOUT;
 use crate::command::nitro_enclave::describe_enclave;
-use crate::config::{EnclaveConfig, EnclaveOpt, NitroSignOpt, VSockProxyOpt};
-use crate::key_utils::{credential, generate_key};
-use crate::proxy::Proxy;
-use crate::shared::{NitroConfig, NitroRequest};
-use crate::state::StateSyncer;
+use crate::config::{
+    ChainControlOpt, EnclaveConfig, EnclaveOpt, KmsKeyOpt, NitroSignOpt, VSockProxyOpt,
+};
+use crate::credential_refresh::CredentialRefresher;
+use crate::eif::describe_file;
+use crate::ha::HaNode;
+use crate::halt_server::HaltServer;
+use crate::health::{HealthServer, HealthState};
+use crate::http::http_request;
+use crate::key_utils::{credential, generate_key, load_or_generate_signing_key};
+use crate::kms_policy;
+use crate::kms_proxy::KmsProxy;
+use crate::lease::{LeaseKeeper, LeaseServer};
+use crate::metrics_server::MetricsServer;
+use crate::monotonic_server::MonotonicServer;
+use crate::mux_server::launch_mux;
+use crate::otlp::{OtlpExporter, TraceServer};
+use crate::peer_auth::PeerKey;
+use crate::proxy::{Proxy, Remote};
+use crate::shared::{
+    KeyPurpose, LogLevel, NitroApproval, NitroAttestResult, NitroBindControlResult, NitroConfig,
+    NitroError, NitroErrorCode, NitroKeygenConfig, NitroRequest, NitroSetLogLevelResult,
+    NitroShutdownResult, NitroTimeSync, CAPABILITY_CONTROL_KEY, CAPABILITY_STARTUP_ATTESTATION,
+};
+use crate::state::{FreshStateGuard, StateSyncer};
+use crate::state_store::JsonFileStore;
+use crate::supervisor::{EnclaveLaunch, Supervisor};
+use crate::systemd;
+use crate::time_server::TimeServer;
+use crate::watermark_server::WatermarkServer;
+use tmkms_nitro_helper::audit::verify;
+use tmkms_nitro_helper::codec;
+use tmkms_nitro_helper::config_push::seal_config;
+use tmkms_nitro_helper::control::{encode_request, negotiate, CONTROL_KEY_LEN};
+use tmkms_nitro_helper::schema::SigningStatus;
+use tmkms_nitro_helper::slip10::DerivationPath;
+use zeroize::Zeroizing;
+
+/// a failed command: the enclave's typed error (whose code is the exit status)
+/// or a helper-side one
+#[derive(Debug)]
+pub enum CommandError {
+    Enclave(NitroError),
+    Helper(String),
+}
+
+impl CommandError {
+    /// the process exit status for the error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CommandError::Enclave(e) => e.code.code().into(),
+            CommandError::Helper(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Enclave(e) => write!(f, "enclave error: {}", e),
+            CommandError::Helper(e) => f.write_str(e),
+        }
+    }
+}
+
+impl From<NitroError> for CommandError {
+    fn from(e: NitroError) -> Self {
+        CommandError::Enclave(e)
+    }
+}
+
+impl From<String> for CommandError {
+    fn from(e: String) -> Self {
+        CommandError::Helper(e)
+    }
+}
+
+impl From<CommandError> for String {
+    fn from(e: CommandError) -> Self {
+        e.to_string()
+    }
+}
 
 /// write tmkms.toml + enclave.toml + generate keys
 /// config_dir: the directory that put the generated config file
+/// kms: the KMS keys that seal the generated keys
 pub fn init(
     config_dir: PathBuf,
     pubkey_display: Option<PubkeyDisplay>,
     bech32_prefix: Option<String>,
-    aws_region: String,
-    kms_key_id: String,
+    kms: KmsKeyOpt,
+    derivation_path: Option<DerivationPath>,
     cid: Option<u32>,
+    expected_pcrs: ExpectedPcrs,
 ) -> Result<(), String> {
+    let KmsKeyOpt {
+        aws_region,
+        kms_key_id,
+        id_kms_key_id,
+        kms_replicas,
+    } = kms;
     if !config_dir.is_dir() || !config_dir.exists() {
         return Err("config path is not a directory or not exists".to_string());
     }
     let cp_helper = config_dir.join("tmkms.toml");
     let cp_enclave = config_dir.join("enclave.toml");
 
+    let [expected_pcr0, expected_pcr1, expected_pcr2] = expected_pcrs.0;
     let nitro_sign_opt = NitroSignOpt {
         aws_region: aws_region.clone(),
+        id_kms_key_id,
+        kms_replicas,
+        derivation_path,
+        expected_pcr0,
+        expected_pcr1,
+        expected_pcr2,
+        dev_plaintext: dev_tcp(),
+        attestation_archive_dir: Some(config_dir.join("attestations")),
         ..Default::default()
     };
     let enclave_opt = EnclaveOpt::default();
@@ -53,16 +184,13 @@ pub fn init(
     fs::write(cp_enclave, t_enclave_config)
         .map_err(|e| format!("failed to write a launch all config: {:?}", e))?;
     let config = nitro_sign_opt;
+    set_archive_dir(config.attestation_archive_dir.clone());
     let (cid, port) = if let Some(cid) = cid {
         (cid, config.enclave_config_port)
     } else {
         (config.enclave_config_cid, config.enclave_config_port)
     };
-    let credentials = if let Some(credentials) = config.credentials {
-        credentials
-    } else {
-        credential::get_credentials()?
-    };
+    let credentials = credential::CredentialsSource::new(&config).credentials()?;
     fs::create_dir_all(
         config
             .sealed_consensus_key_path
@@ -79,23 +207,34 @@ pub fn init(
     .map_err(|e| format!("failed to create dirs for state storage: {:?}", e))?;
 
     // check if enclave and vsock proxy is running
-    if !describe_enclave()?
-        .into_iter()
-        .any(|x| x.enclave_cid == cid as u64)
+    // (in the development mode, the enclave binary is run on the host)
+    if !config.dev_plaintext
+        && !describe_enclave()?
+            .into_iter()
+            .any(|x| x.enclave_cid == cid as u64)
     {
         return Err("can't find running enclave with matched cid. Please use tmkms-nitro-helper run command".to_owned());
     }
-    if !check_vsock_proxy() {
-        return Err("vsock proxy is not running, Please run vsock-proxy 8000 kms.{{ kms_region }}.amazonaws.com 443 &".to_owned());
+    if !config.dev_plaintext && !check_vsock_proxy() {
+        tracing::info!("vsock proxy is not running, using the built-in KMS proxy");
+        KmsProxy::for_regions(&config.aws_region, &config.kms_failover_regions())?.launch();
     }
 
+    let expected = config.required_pcrs()?;
     let (pubkey, attestation_doc) = generate_key(
         cid,
         port,
+        &expected,
         config.sealed_consensus_key_path,
-        &config.aws_region,
-        credentials.clone(),
-        kms_key_id.clone(),
+        NitroKeygenConfig {
+            credentials: credentials.clone(),
+            kms_key_id: kms_key_id.clone(),
+            aws_region: config.aws_region.clone(),
+            nonce: vec![],
+            kms_replicas: config.kms_replicas.clone(),
+            purpose: KeyPurpose::Consensus,
+            derivation_path: config.derivation_path.clone(),
+        },
     )
     .map_err(|e| format!("failed to generate a key: {:?}", e))?;
     print_pubkey(bech32_prefix, pubkey_display, pubkey);
@@ -104,15 +243,36 @@ pub fn init(
     println!("Nitro Enclave attestation:\n{}", &encoded_attdoc);
 
     if let Some(id_path) = config.sealed_id_key_path {
-        generate_key(
+        // the consensus key's replicas are of another key
+        let (id_kms_key_id, id_kms_replicas) = match config.id_kms_key_id {
+            Some(id_kms_key_id) => (id_kms_key_id, vec![]),
+            None => (kms_key_id, config.kms_replicas),
+        };
+        let (id_pubkey, id_attestation_doc) = generate_key(
             cid,
             port,
+            &expected,
             id_path,
-            &config.aws_region,
-            credentials,
-            kms_key_id,
+            NitroKeygenConfig {
+                credentials,
+                kms_key_id: id_kms_key_id,
+                aws_region: config.aws_region,
+                nonce: vec![],
+                kms_replicas: id_kms_replicas,
+                purpose: KeyPurpose::Identity,
+                derivation_path: None,
+            },
         )
         .map_err(|e| format!("failed to generate a sealed id key: {:?}", e))?;
+        let id_pubkey = tendermint::public_key::Ed25519::try_from(id_pubkey.as_bytes().as_slice())
+            .map_err(|e| format!("invalid id key: {:?}", e))?;
+        println!("node id: {}", tendermint::node::Id::from(id_pubkey));
+        let encoded_attdoc = String::from_utf8(subtle_encoding::base64::encode(id_attestation_doc))
+            .map_err(|e| format!("enconding attestation doc: {:?}", e))?;
+        println!(
+            "Nitro Enclave attestation (identity key):\n{}",
+            &encoded_attdoc
+        );
     }
     Ok(())
 }
@@ -126,25 +286,711 @@ pub fn check_vsock_proxy() -> bool {
     })
 }
 
+/// verifies the hash chain of the audit log and that the enclave's anchors,
+/// signed with the expected (base64-encoded) consensus public key, cover its records
+pub fn audit_verify(
+    path: PathBuf,
+    public_key: &str,
+    allow_unanchored_tail: bool,
+) -> Result<(), String> {
+    let public_key = subtle_encoding::base64::decode(public_key.trim())
+        .map_err(|e| format!("invalid consensus public key: {}", e))?;
+    let file = fs::File::open(&path)
+        .map_err(|e| format!("failed to open `{}`: {:?}", path.display(), e))?;
+    let summary = verify(std::io::BufReader::new(file), Some(&public_key))?;
+    if summary.anchors == 0 {
+        return Err(format!(
+            "no enclave anchor in the audit log ({} records): the host could have rewritten it",
+            summary.records
+        ));
+    }
+    if summary.unanchored > 0 && !allow_unanchored_tail {
+        return Err(format!(
+            "{} records aren't anchored by the enclave (pass `--allow-unanchored-tail` to accept the records after the last anchor)",
+            summary.unanchored
+        ));
+    }
+    println!(
+        "hash chain OK: {} records, last hash: {}",
+        summary.records, summary.last_hash
+    );
+    println!(
+        "{} enclave anchors signed by the consensus key, {} records not anchored",
+        summary.anchors, summary.unanchored
+    );
+    if summary.unanchored == 0 {
+        println!("audit log OK");
+    }
+    Ok(())
+}
+
+/// checks an enclave's attestation against the PCRs pinned in the config
+/// (the attestation is requested from the running enclave unless `attestation_path`
+/// has a base64-encoded attestation document, e.g. one printed by `init`)
+pub fn attestation_verify(
+    config: &NitroSignOpt,
+    attestation_path: Option<PathBuf>,
+    cid: Option<u32>,
+) -> Result<(), String> {
+    let expected = config.expected_pcrs();
+    let doc = if let Some(path) = attestation_path {
+        read_attestation_doc(&path)?
+    } else {
+        attest_enclave(
+            cid.unwrap_or(config.enclave_config_cid),
+            config.enclave_config_port,
+            &ExpectedPcrs::default(),
+        )?
+    };
+    for index in 0..3 {
+        if let Some(pcr) = doc.pcrs.get(&index) {
+            println!("PCR{}: {}", index, hex_pcr(pcr));
+        }
+    }
+    if expected.is_empty() {
+        return Err("no `expected_pcr0`, `expected_pcr1` or `expected_pcr2` is set".to_owned());
+    }
+    expected.verify(&doc)?;
+    println!("attestation OK: the enclave measurements match the pinned PCRs");
+    Ok(())
+}
+
+/// prints the measurements of the enclave image file
+/// (and checks them against the pinned ones, if the config is given)
+pub fn eif_describe(path: &Path, config: Option<&NitroSignOpt>) -> Result<(), String> {
+    let description = describe_file(path)?;
+    println!("EIF version: {}", description.version);
+    for (section_type, size) in description.sections.iter() {
+        println!("section {:?}: {} bytes", section_type, size);
+    }
+    if let Some(metadata) = &description.metadata {
+        println!("metadata: {}", metadata);
+    }
+    for (index, pcr) in description.pcrs.iter() {
+        println!("PCR{}: {}", index, hex_pcr(pcr));
+    }
+    if description.is_signed() {
+        println!("the image is signed (its PCR8 isn't computed)");
+    }
+    if let Some(config) = config {
+        let expected = config.expected_pcrs();
+        if expected.is_empty() {
+            return Err("no `expected_pcr0`, `expected_pcr1` or `expected_pcr2` is set".to_owned());
+        }
+        expected.verify_pcrs(&description.pcrs)?;
+        println!("EIF OK: the image measurements match the pinned PCRs");
+    }
+    Ok(())
+}
+
+/// signs the archived attestation documents (received at or after `since`)
+/// with the publisher key and writes them as a bundle
+pub fn attestation_export(
+    config: &NitroSignOpt,
+    output: &Path,
+    key_path: &Path,
+    since: Option<u64>,
+) -> Result<(), String> {
+    let dir = config
+        .attestation_archive_dir
+        .as_ref()
+        .ok_or_else(|| "no `attestation_archive_dir` is set".to_owned())?;
+    let attestations = read_archive(dir, since)?;
+    let publisher_key = load_or_generate_signing_key(key_path, "publisher key")?;
+    let bundle = AttestationBundle::sign(&publisher_key, attestations)?;
+    bundle.write(output)?;
+    println!(
+        "exported {} attestations to {} (publisher key: {})",
+        bundle.attestations.len(),
+        output.display(),
+        bundle.public_key
+    );
+    Ok(())
+}
+
+/// checks the bundle's signature (by the given publisher key) and metadata
+pub fn attestation_verify_bundle(path: &Path, public_key: Option<String>) -> Result<(), String> {
+    let json =
+        fs::read(path).map_err(|e| format!("failed to read `{}`: {:?}", path.display(), e))?;
+    let bundle: AttestationBundle = serde_json::from_slice(&json)
+        .map_err(|e| format!("invalid bundle `{}`: {}", path.display(), e))?;
+    bundle.verify(public_key.as_deref())?;
+    if public_key.is_none() {
+        println!(
+            "WARNING: the publisher key isn't given, the bundle's one is trusted: {}",
+            bundle.public_key
+        );
+    }
+    for attestation in bundle.attestations.iter() {
+        println!(
+            "{} {} {}",
+            attestation.received_at,
+            attestation.kind.as_str(),
+            attestation.chain_id.as_deref().unwrap_or("-")
+        );
+    }
+    println!(
+        "bundle OK: {} attestations signed by the publisher key",
+        bundle.attestations.len()
+    );
+    Ok(())
+}
+
+/// prints the AWS KMS key policy pinning the enclave measurements
+/// (from an attestation document, or the given or configured PCRs)
+pub fn kms_policy_generate(
+    attestation_path: Option<PathBuf>,
+    pcrs: ExpectedPcrs,
+    config_path: Option<PathBuf>,
+    role_arn: String,
+    admin_arn: Option<String>,
+) -> Result<(), String> {
+    let pcrs = if let Some(path) = attestation_path {
+        if !pcrs.is_empty() {
+            return Err("either an attestation document or PCRs can be given".to_owned());
+        }
+        ExpectedPcrs::from_doc(&read_attestation_doc(&path)?)
+    } else if let (true, Some(config_path)) = (pcrs.is_empty(), config_path) {
+        NitroSignOpt::from_file(config_path)?.expected_pcrs()
+    } else {
+        pcrs
+    };
+    let policy = kms_policy::generate(&pcrs, &role_arn, admin_arn.as_deref())?;
+    let json = serde_json::to_string_pretty(&policy)
+        .map_err(|e| format!("failed to serialize the key policy: {:?}", e))?;
+    println!("{}", json);
+    Ok(())
+}
+
+/// persist the states relayed from another helper's enclave
+/// stop_rx: when get data from it, the server will be finished
+pub fn state_server(
+    state_file_path: PathBuf,
+    listen_addr: SocketAddr,
+    stop_rx: Receiver<()>,
+) -> Result<(), String> {
+    let state_syncer = StateSyncer::new_tcp(
+        Box::new(JsonFileStore::new(state_file_path)),
+        listen_addr,
+        Arc::new(HealthState::new(false)),
+    )
+    .map_err(|e| format!("failed to start the state server: {:?}", e))?;
+    tracing::info!("state server listening on {}", listen_addr);
+    state_syncer
+        .launch_syncer(stop_rx)
+        .join()
+        .map_err(|_| "join thread error".to_string())
+}
+
+/// print the running helper's status (from its `/status` endpoint)
+pub fn signing_status(mut addr: SocketAddr) -> Result<(), String> {
+    if addr.ip().is_unspecified() {
+        addr.set_ip(if addr.is_ipv4() {
+            Ipv4Addr::LOCALHOST.into()
+        } else {
+            Ipv6Addr::LOCALHOST.into()
+        });
+    }
+    let (status, body) = http_request(&addr.to_string(), "GET", "/status", &[], &[])?;
+    if status != 200 {
+        return Err(format!("the status endpoint answered with HTTP {}", status));
+    }
+    let status: SigningStatus =
+        serde_json::from_slice(&body).map_err(|e| format!("invalid status: {}", e))?;
+    let s = serde_json::to_string_pretty(&status)
+        .map_err(|e| format!("failed to serialize the status: {:?}", e))?;
+    println!("{}", s);
+    Ok(())
+}
+
+/// serve the high watermarks shared by redundant signers
+pub fn watermark_server(
+    watermark_file_path: PathBuf,
+    peer_key_path: PathBuf,
+    listen_addr: SocketAddr,
+) -> Result<(), String> {
+    let server = WatermarkServer::new(watermark_file_path, &peer_key_path, listen_addr)?;
+    tracing::info!("watermark server listening on {}", listen_addr);
+    server.run()
+}
+
+/// approve the signatures required by the enclaves' signing policies (two-person rule)
+pub fn approval_server(
+    approval_file_path: PathBuf,
+    key_path: PathBuf,
+    listen_addr: SocketAddr,
+) -> Result<(), String> {
+    let server = ApprovalServer::new(approval_file_path, &key_path, listen_addr)?;
+    tracing::info!("approval server listening on {}", listen_addr);
+    server.run()
+}
+
+/// grant the lease signers must hold to sign
+pub fn lease_server(
+    listen_addr: SocketAddr,
+    peer_key_path: PathBuf,
+    max_lease_secs: u64,
+) -> Result<(), String> {
+    let server = LeaseServer::new(listen_addr, &peer_key_path, max_lease_secs)?;
+    tracing::info!("lease server listening on {}", listen_addr);
+    server.run()
+}
+
+/// keep the latest states of the enclaves (to detect rolled back state files)
+pub fn monotonic_server(
+    state_file_path: PathBuf,
+    key_path: PathBuf,
+    peer_key_path: PathBuf,
+    listen_addr: SocketAddr,
+) -> Result<(), String> {
+    let server = MonotonicServer::new(state_file_path, &key_path, &peer_key_path, listen_addr)?;
+    tracing::info!("monotonic state server listening on {}", listen_addr);
+    server.run()
+}
+
+/// how long the enclave's shutdown is waited for
+/// (it waits up to 10 seconds for its sessions to stop)
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// asks the enclave to stop its sessions (after their in-flight requests),
+/// zeroize the keys and exit
+pub fn shutdown_enclave(config: &NitroSignOpt, cid: Option<u32>) -> Result<(), CommandError> {
+    let mut socket = ChannelStream::connect(
+        cid.unwrap_or(config.enclave_config_cid),
+        config.enclave_config_port,
+    )
+    .map_err(|e| format!("failed to connect to the enclave: {:?}", e))?;
+    socket
+        .set_read_timeout(Some(SHUTDOWN_TIMEOUT))
+        .map_err(|e| format!("failed to set the shutdown timeout: {:?}", e))?;
+    negotiate(&mut socket)?;
+    let request_raw = encode_request(&NitroRequest::Shutdown)?;
+    write_u16_payload(&mut socket, &request_raw)
+        .map_err(|e| format!("failed to write the shutdown request: {:?}", e))?;
+    let response_raw = read_u16_payload(&mut socket)
+        .map_err(|e| format!("failed to read the shutdown response: {:?}", e))?;
+    let response: NitroShutdownResult =
+        codec::decode(&response_raw).map_err(|e| format!("invalid shutdown response: {:?}", e))?;
+    Ok(response?)
+}
+
+/// changes the log level of the running enclave (`None` resets it)
+pub fn set_enclave_log_level(
+    opt: &ChainControlOpt,
+    level: Option<LogLevel>,
+) -> Result<(), CommandError> {
+    let config = NitroSignOpt::from_file(opt.config_path.clone())?;
+    let mut socket = ChannelStream::connect(
+        opt.cid.unwrap_or(config.enclave_config_cid),
+        config.enclave_config_port,
+    )
+    .map_err(|e| format!("failed to connect to the enclave: {:?}", e))?;
+    socket
+        .set_read_timeout(Some(CHAIN_REQUEST_TIMEOUT))
+        .map_err(|e| format!("failed to set the request timeout: {:?}", e))?;
+    negotiate(&mut socket)?;
+    let request_raw = encode_request(&NitroRequest::SetLogLevel(level))?;
+    write_u16_payload(&mut socket, &request_raw)
+        .map_err(|e| format!("failed to write the log level request: {:?}", e))?;
+    let response_raw = read_u16_payload(&mut socket)
+        .map_err(|e| format!("failed to read the log level response: {:?}", e))?;
+    let response: NitroSetLogLevelResult =
+        codec::decode(&response_raw).map_err(|e| format!("invalid log level response: {:?}", e))?;
+    Ok(response?)
+}
+
+/// binds a new control key to the enclave (encrypted to a one-time key it attested to);
+/// the key file is only replaced if the enclave isn't bound yet
+fn bind_control(
+    config: &NitroSignOpt,
+    socket: &mut ChannelStream,
+    key_path: &Path,
+) -> Result<(), String> {
+    let mut nonce = vec![0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    let request_raw = encode_request(&NitroRequest::BindControl {
+        nonce: nonce.clone(),
+    })?;
+    write_u16_payload(socket, &request_raw)
+        .map_err(|e| format!("failed to write the bind request: {:?}", e))?;
+    let response_raw = read_u16_payload(socket)
+        .map_err(|e| format!("failed to read the control key attestation: {:?}", e))?;
+    let response: NitroAttestResult = codec::decode(&response_raw)
+        .map_err(|e| format!("invalid control key attestation: {:?}", e))?;
+    let attestation = match response {
+        Err(e) if e.code == NitroErrorCode::AlreadyBound && key_path.exists() => {
+            tracing::info!("the enclave's control key is already bound");
+            return Ok(());
+        }
+        Err(e) => return Err(format!("{}", e)),
+        Ok(attestation) => attestation,
+    };
+    let recipient = attested_key(
+        &attestation,
+        &nonce,
+        &config.required_pcrs()?,
+        AttestationKind::Startup,
+    )?;
+    let mut key = Zeroizing::new([0u8; CONTROL_KEY_LEN]);
+    OsRng.fill_bytes(&mut key[..]);
+    let encoded = Zeroizing::new(subtle_encoding::base64::encode(&key[..]));
+    let dir = key_path.parent().unwrap_or_else(|| Path::new("."));
+    let mut file = NamedTempFile::new_in(dir)
+        .and_then(|file| {
+            file.as_file()
+                .set_permissions(fs::Permissions::from_mode(0o600))?;
+            Ok(file)
+        })
+        .map_err(|e| format!("failed to write `{}`: {:?}", key_path.display(), e))?;
+    file.write_all(&encoded)
+        .map_err(|e| format!("failed to write `{}`: {:?}", key_path.display(), e))?;
+    let sealed = seal_config(&mut OsRng, &key[..], &recipient)?;
+    let sealed_raw = codec::encode(&sealed)
+        .map_err(|e| format!("failed to encode the encrypted control key: {:?}", e))?;
+    write_u16_payload(socket, &sealed_raw)
+        .map_err(|e| format!("failed to write the control key: {:?}", e))?;
+    let response_raw = read_u16_payload(socket)
+        .map_err(|e| format!("failed to read the bind response: {:?}", e))?;
+    let response: NitroBindControlResult =
+        codec::decode(&response_raw).map_err(|e| format!("invalid bind response: {:?}", e))?;
+    response.map_err(|e| format!("{}", e))?;
+    file.persist(key_path)
+        .map_err(|e| format!("failed to write `{}`: {:?}", key_path.display(), e.error))?;
+    tracing::info!("bound the control key {}", key_path.display());
+    Ok(())
+}
+
+/// pushes the start config to the enclave, encrypted to a one-time key it attested to
+/// (after checking the attested measurements against the pinned ones)
+pub fn push_config(
+    config: &NitroSignOpt,
+    cid: Option<u32>,
+    enclave_config: &NitroConfig,
+) -> Result<(), String> {
+    let mut socket = ChannelStream::connect(
+        cid.unwrap_or(config.enclave_config_cid),
+        config.enclave_config_port,
+    )
+    .map_err(|e| {
+        format!(
+            "failed to connect to the enclave to push its config: {:?}",
+            e
+        )
+    })?;
+    let expected = config.required_pcrs()?;
+    let enclave = negotiate(&mut socket)?;
+    // the enclave serves both requests on the same connection
+    if let Some(key_path) = &config.control_key_path {
+        if !enclave.supports(CAPABILITY_CONTROL_KEY) {
+            return Err(
+                "the enclave doesn't support the control key (`control_key_path`)".to_owned(),
+            );
+        }
+        bind_control(config, &mut socket, key_path)?;
+    }
+    let mut nonce = vec![0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    let request_raw = encode_request(&NitroRequest::StartSealed {
+        nonce: nonce.clone(),
+    })?;
+    write_u16_payload(&mut socket, &request_raw)
+        .map_err(|e| format!("failed to write the start request: {:?}", e))?;
+    let response_raw = read_u16_payload(&mut socket)
+        .map_err(|e| format!("failed to read the config key attestation: {:?}", e))?;
+    let response: NitroAttestResult = codec::decode(&response_raw)
+        .map_err(|e| format!("invalid config key attestation: {:?}", e))?;
+    let recipient = attested_key(
+        &response.map_err(|e| format!("{}", e))?,
+        &nonce,
+        &expected,
+        AttestationKind::Startup,
+    )?;
+    let config_raw = Zeroizing::new(
+        codec::encode(enclave_config)
+            .map_err(|e| format!("failed to encode the config: {:?}", e))?,
+    );
+    let sealed = seal_config(&mut OsRng, &config_raw, &recipient)?;
+    let sealed_raw = codec::encode(&sealed)
+        .map_err(|e| format!("failed to encode the encrypted config: {:?}", e))?;
+    write_u16_payload(&mut socket, &sealed_raw)
+        .map_err(|e| format!("failed to write the config: {:?}", e))?;
+    if !enclave.supports(CAPABILITY_STARTUP_ATTESTATION) {
+        tracing::warn!("the enclave doesn't attest the consensus key it loaded (older EIF)");
+        return Ok(());
+    }
+    // the session is only wired to the validator once it's known to have the expected key
+    let response_raw = read_u16_payload(&mut socket)
+        .map_err(|e| format!("failed to read the startup attestation: {:?}", e))?;
+    let response: NitroAttestResult = codec::decode(&response_raw)
+        .map_err(|e| format!("invalid startup attestation: {:?}", e))?;
+    let attestation = response.map_err(|e| format!("the enclave session didn't start: {}", e))?;
+    let chain_id = enclave_config.chain_id.as_str();
+    archive(AttestationKind::Startup, Some(chain_id), &attestation);
+    let claim = check_startup_attestation(
+        &verify_attestation_doc(&attestation)?,
+        &nonce,
+        &expected,
+        chain_id,
+        config.expected_consensus_pubkey.as_deref(),
+    )?;
+    if config.expected_consensus_pubkey.is_none() {
+        tracing::warn!(
+            "no `expected_consensus_pubkey` is set, the attested consensus key isn't checked"
+        );
+    }
+    tracing::info!(
+        "[{}] the enclave loaded the consensus key {} at the watermark h/r/s {}/{}/{}",
+        chain_id,
+        claim.pubkey,
+        claim.height,
+        claim.round,
+        claim.step
+    );
+    Ok(())
+}
+
 /// push config to enclave, start up a proxy (if needed) + state syncer
-/// stop_sync_rx: when get data from it, the sync thread will be finished
+/// launch: the enclave run by the helper (supervised if `supervisor` is set)
+/// stop_sync_rx: when get data from it, the enclave is shut down and the sync thread finished
 pub fn start(
     config: &NitroSignOpt,
     cid: Option<u32>,
+    launch: Option<EnclaveLaunch>,
     stop_sync_rx: Receiver<()>,
 ) -> Result<(), String> {
     tracing::debug!("start helper with config: {:?}, cid: {:?}", config, cid);
-    let credentials = if let Some(credentials) = &config.credentials {
-        credentials.clone()
-    } else {
-        credential::get_credentials()?
-    };
+    if let Some(alerting) = &config.alerting {
+        Alerter::new(alerting.clone())?.launch();
+    }
+    let mut credentials_source = credential::CredentialsSource::new(config);
+    let credentials = credentials_source.credentials()?;
     let peer_id = match config.address {
         net::Address::Tcp { peer_id, .. } => peer_id,
         _ => None,
     };
-    let state_syncer = StateSyncer::new(config.state_file_path.clone(), config.enclave_state_port)
-        .map_err(|e| format!("failed to get a state syncing helper: {:?}", e))?;
+    if config.require_peer_id && config.transport == Transport::Noise {
+        if config.noise_remote_key.is_none() {
+            return Err("`require_peer_id` is set, but `noise_remote_key` is missing".to_owned());
+        }
+    } else if config.require_peer_id && peer_id.is_none() {
+        if let net::Address::Tcp { .. } = config.address {
+            return Err(
+                "`require_peer_id` is set, but the validator address has no peer ID".to_owned(),
+            );
+        }
+    }
+    let failover_conns = config.failover_conns()?;
+    let noise_remote_key = config
+        .noise_remote_key
+        .as_ref()
+        .map(subtle_encoding::base64::decode)
+        .transpose()
+        .map_err(|e| format!("invalid noise remote key: {:?}", e))?;
+    let tls = match (config.transport, &config.tls) {
+        (Transport::Tls, Some(tls)) => {
+            let mut credentials = tls
+                .load()
+                .map_err(|e| format!("failed to load the TLS credentials: {:?}", e))?;
+            if let (None, net::Address::Tcp { host, .. }) =
+                (&credentials.server_name, &config.address)
+            {
+                credentials.server_name = Some(host.clone());
+            }
+            Some(credentials)
+        }
+        (Transport::Tls, None) => {
+            return Err("`transport = \"tls\"` requires a `tls` section".to_owned())
+        }
+        _ => None,
+    };
+    let health = Arc::new(HealthState::new(
+        matches!(config.address, net::Address::Unix { .. })
+            || config.connection_mode == ConnectionMode::Listen,
+    ));
+    let enclave_metrics_port = match (config.health_listen_addr, config.metrics) {
+        (Some(_), true) => Some(config.enclave_metrics_port),
+        (None, true) => return Err("`metrics` requires `health_listen_addr`".to_owned()),
+        _ => None,
+    };
+    let enclave_attestation_port = match (
+        config.attestation_interval_secs,
+        config.health_listen_addr,
+        &config.attestation_path,
+    ) {
+        (Some(0), _, _) => return Err("`attestation_interval_secs` must be positive".to_owned()),
+        (Some(_), None, None) => {
+            return Err(
+                "`attestation_interval_secs` requires `attestation_path` or `health_listen_addr`"
+                    .to_owned(),
+            )
+        }
+        (Some(_), _, _) => Some(config.enclave_attestation_port),
+        (None, _, _) => None,
+    };
+    // the channel listeners below are registered with the multiplexed port (if enabled)
+    if let Some(port) = config.enclave_mux_port {
+        launch_mux(port)?;
+    }
+    let latest_attestation = Arc::new(Mutex::new(None));
+    if let Some(port) = enclave_attestation_port {
+        AttestationServer::new(
+            config.attestation_path.clone(),
+            latest_attestation.clone(),
+            port,
+        )?
+        .launch();
+    }
+    if let Some(addr) = config.health_listen_addr {
+        let mut health_server = HealthServer::new(addr, health.clone());
+        if let Some(port) = enclave_metrics_port {
+            let metrics = Arc::new(Mutex::new(SigningMetrics::default()));
+            MetricsServer::new(metrics.clone(), port)?.launch();
+            health_server.set_metrics(metrics);
+        }
+        if enclave_attestation_port.is_some() {
+            health_server.set_attestation(latest_attestation);
+        }
+        health_server.launch()?;
+    }
+    if let Some(admin_config) = &config.admin {
+        AdminServer::new(
+            admin_config,
+            cid.unwrap_or(config.enclave_config_cid),
+            config.enclave_config_port,
+        )?
+        .launch();
+    }
+    systemd::launch_watchdog(health.clone());
+    let store = config
+        .state_backend
+        .open(&config.state_file_path)
+        .map_err(|e| format!("failed to open the state store: {:?}", e))?;
+    let mut guard = FreshStateGuard::new(
+        &config.state_file_path,
+        config.require_existing_state,
+        config.force_fresh_state,
+    );
+    guard.set_recovery(config.state_recovery.clone());
+    let mut state_syncer =
+        StateSyncer::new(store, config.enclave_state_port, health.clone(), &guard)
+            .map_err(|e| format!("failed to get a state syncing helper: {:?}", e))?;
+    state_syncer.check_authenticated()?;
+    let peer_key = || match &config.peer_key_path {
+        Some(path) => PeerKey::load(path),
+        None => Err(
+            "`peer_key_path` is required with `ha`, `lease`, `watermark_addr` or `monotonic_addr`"
+                .to_owned(),
+        ),
+    };
+    let ha = if let Some(ha_config) = &config.ha {
+        let ha = HaNode::new(ha_config.clone(), peer_key()?)?;
+        ha.clone().launch()?;
+        state_syncer.set_ha(ha.clone());
+        Some(ha)
+    } else {
+        None
+    };
+    let enclave_trace_port = if let Some(otlp) = &config.otlp {
+        OtlpExporter::new(otlp.clone())?.launch();
+        TraceServer::new(config.enclave_trace_port)?.launch();
+        Some(config.enclave_trace_port)
+    } else {
+        None
+    };
+    let enclave_halt_port = if config.max_height.is_some() {
+        HaltServer::new(config.halt_report_path.clone(), config.enclave_halt_port)?.launch();
+        Some(config.enclave_halt_port)
+    } else {
+        None
+    };
+    let enclave_audit_port = if let Some(path) = &config.audit_log_path {
+        AuditServer::new(path, config.enclave_audit_port)?.launch();
+        Some(config.enclave_audit_port)
+    } else {
+        if config.dump_refused_sign_bytes {
+            tracing::warn!("`dump_refused_sign_bytes` is ignored without `audit_log_path`");
+        }
+        None
+    };
+    let time_sync = match config.time_sync_interval_secs {
+        Some(0) => return Err("`time_sync_interval_secs` must be positive".to_owned()),
+        Some(interval_secs) => {
+            let server = TimeServer::new(config.enclave_time_port)?;
+            let key = server.key();
+            server.launch();
+            Some(NitroTimeSync {
+                port: config.enclave_time_port,
+                key,
+                interval_secs,
+            })
+        }
+        None => None,
+    };
+    let enclave_remote_state_port = if let Some(addr) = &config.remote_state_addr {
+        Proxy::new(
+            config.enclave_remote_state_port,
+            Remote::Tcp(addr.clone()),
+            Arc::new(HealthState::new(false)),
+        )
+        .launch_proxy();
+        Some(config.enclave_remote_state_port)
+    } else {
+        None
+    };
+    let enclave_watermark_port = if let Some(addr) = &config.watermark_addr {
+        if config.replica_id.is_none() {
+            return Err("`replica_id` is required with `watermark_addr`".to_owned());
+        }
+        let mut proxy = Proxy::new(
+            config.enclave_watermark_port,
+            Remote::Tcp(addr.clone()),
+            Arc::new(HealthState::new(false)),
+        );
+        proxy.set_peer_key(peer_key()?);
+        proxy.launch_proxy();
+        Some(config.enclave_watermark_port)
+    } else {
+        None
+    };
+    let enclave_monotonic_port = if let Some(addr) = &config.monotonic_addr {
+        let mut proxy = Proxy::new(
+            config.enclave_monotonic_port,
+            Remote::Tcp(addr.clone()),
+            Arc::new(HealthState::new(false)),
+        );
+        proxy.set_peer_key(peer_key()?);
+        proxy.launch_proxy();
+        Some(config.enclave_monotonic_port)
+    } else {
+        None
+    };
+    let enclave_lease_port = if let Some(lease_config) = &config.lease {
+        LeaseKeeper::new(
+            lease_config.clone(),
+            config.chain_id.to_string(),
+            peer_key()?,
+        )
+        .launch()?;
+        Some(lease_config.enclave_lease_port)
+    } else {
+        None
+    };
+    let approval = if let Some(approval_config) = &config.approval {
+        Proxy::new(
+            approval_config.enclave_approval_port,
+            Remote::Tcp(approval_config.server_addr.clone()),
+            Arc::new(HealthState::new(false)),
+        )
+        .launch_proxy();
+        Some(NitroApproval {
+            port: approval_config.enclave_approval_port,
+        })
+    } else {
+        if config.signing_policy.approval.is_some() {
+            tracing::warn!("no `approval` service: the signatures requiring approval are refused");
+        }
+        None
+    };
     let sealed_consensus_key = fs::read(config.sealed_consensus_key_path.clone())
         .map_err(|e| format!("failed to read a sealed consensus key: {:?}", e))?;
     let sealed_id_key = if let Some(p) = &config.sealed_id_key_path {
@@ -159,35 +1005,101 @@ pub fn start(
     } else {
         None
     };
+    let committed_height = match &config.chain_rpc_check {
+        Some(check) => check.check()?,
+        None => None,
+    };
     let enclave_config = NitroConfig {
         chain_id: config.chain_id.clone(),
         max_height: config.max_height,
+        signing_policy: config.signing_policy.clone(),
+        allowed_chain_ids: config.allowed_chain_ids.clone(),
         sealed_consensus_key,
+        consensus_key_derivation: config.derivation_path.clone(),
         sealed_id_key,
         peer_id,
+        validator_msg_types: config.validator_msg_types.clone(),
+        timeouts: config.timeouts,
+        protocol_version: config.protocol_version,
+        require_peer_id: config.require_peer_id,
+        transport: config.transport,
+        noise_remote_key,
+        tls,
         enclave_state_port: config.enclave_state_port,
+        enclave_mux_port: config.enclave_mux_port,
+        enclave_remote_state_port,
+        state_durability: config.state_durability,
+        dry_run: config.dry_run,
+        dump_refused_sign_bytes: config.dump_refused_sign_bytes,
+        committed_height,
+        enclave_watermark_port,
+        replica_id: config.replica_id.clone().unwrap_or_default(),
+        enclave_lease_port,
+        enclave_monotonic_port,
         enclave_tendermint_conn: config.enclave_tendermint_conn,
-        credentials,
+        failover_conns,
+        enclave_audit_port,
+        enclave_metrics_port,
+        enclave_attestation_port,
+        attestation_interval_secs: config.attestation_interval_secs.unwrap_or_default(),
+        enclave_halt_port,
+        enclave_trace_port,
+        time_sync,
+        approval,
+        reconnect_backoff: config.reconnect_backoff.clone(),
+        credentials: credentials.clone(),
         aws_region: config.aws_region.clone(),
+        kms_failover_regions: config.kms_failover_regions(),
     };
-    let addr = if let Some(cid) = cid {
-        VsockAddr::new(cid, config.enclave_config_port)
-    } else {
-        VsockAddr::new(config.enclave_config_cid, config.enclave_config_port)
-    };
-    let mut socket = vsock::VsockStream::connect(&addr).map_err(|e| {
-        format!(
-            "failed to connect to the enclave to push its config: {:?}",
-            e
+    // the enclave loads its state (before it attests its startup) through the state syncing
+    let (stop_tx, stop_rx) = channel();
+    let state_syncing = state_syncer.launch_syncer(stop_rx);
+    push_config(config, cid, &enclave_config)?;
+    let stopping = Arc::new(AtomicBool::new(false));
+    match (&config.supervisor, launch) {
+        (Some(supervisor_config), Some(launch)) => Supervisor::new(
+            supervisor_config.clone(),
+            config.clone(),
+            launch,
+            cid.unwrap_or(config.enclave_config_cid),
+            enclave_config,
+            stopping.clone(),
         )
-    })?;
-    let request = NitroRequest::Start(enclave_config);
-    let config_raw = serde_json::to_vec(&request)
-        .map_err(|e| format!("failed to serialize the config: {:?}", e))?;
-    write_u16_payload(&mut socket, &config_raw)
-        .map_err(|e| format!("failed to write the config: {:?}", e))?;
-    let proxy = match &config.address {
-        net::Address::Unix { path } => {
+        .launch(),
+        (Some(_), None) => {
+            tracing::warn!("the enclave is only supervised when it's run by `enclave start`")
+        }
+        _ => {}
+    }
+    if (config.credentials.is_none() || config.assume_role.is_some())
+        && config.credentials_refresh_secs > 0
+        && !config.dev_plaintext
+    {
+        CredentialRefresher::new(
+            cid.unwrap_or(config.enclave_config_cid),
+            config.enclave_config_port,
+            Duration::from_secs(config.credentials_refresh_secs),
+            config.required_pcrs()?,
+            credentials,
+            credentials_source,
+        )
+        .launch();
+    }
+    let proxy = match (config.connection_mode, &config.address) {
+        (ConnectionMode::Listen, address) => {
+            tracing::debug!(
+                "{}: Creating a proxy listening on {}...",
+                &config.chain_id,
+                &config.address
+            );
+
+            Some(Proxy::new(
+                config.enclave_tendermint_conn,
+                Remote::listen(address)?,
+                health.clone(),
+            ))
+        }
+        (ConnectionMode::Dial, net::Address::Unix { path }) => {
             tracing::debug!(
                 "{}: Creating a proxy {}...",
                 &config.chain_id,
@@ -196,19 +1108,68 @@ pub fn start(
 
             Some(Proxy::new(
                 config.enclave_tendermint_conn,
-                PathBuf::from(path),
+                Remote::Unix(PathBuf::from(path)),
+                health.clone(),
             ))
         }
         _ => None,
     };
-    if let Some(p) = proxy {
+    if let Some(mut p) = proxy {
+        if let Some(ha) = &ha {
+            p.set_ha(ha.clone());
+        }
+        if let Some(path) = &config.privval_capture_path {
+            tracing::warn!("recording the privval traffic to {}", path.display());
+            p.set_capture(path.clone());
+        }
         p.launch_proxy();
     }
+    // `tcp://` failover validators are dialed via `vsock-proxy` (as the primary one)
+    for validator in config.failover_validators.iter() {
+        if let net::Address::Unix { path } = &validator.address {
+            let mut p = Proxy::new(
+                validator.enclave_tendermint_conn,
+                Remote::Unix(PathBuf::from(path)),
+                health.clone(),
+            );
+            if let Some(ha) = &ha {
+                p.set_ha(ha.clone());
+            }
+            p.launch_proxy();
+        }
+    }
 
+    // the enclave is shut down before the state syncing stops
+    // (so its in-flight states are still persisted)
+    let shutdown_config = config.clone();
+    thread::spawn(move || {
+        if stop_sync_rx.recv().is_ok() {
+            stopping.store(true, Ordering::SeqCst);
+            tracing::info!("shutting down the enclave");
+            systemd::notify("STOPPING=1");
+            match shutdown_enclave(&shutdown_config, cid) {
+                Ok(()) => tracing::info!("enclave shut down"),
+                Err(e) => tracing::warn!("enclave shutdown: {}", e),
+            }
+        }
+        let _ = stop_tx.send(());
+    });
     // state syncing runs in an infinite loop (so does the proxy)
-    state_syncer
-        .launch_syncer(stop_sync_rx)
+    state_syncing
         .join()
         .map_err(|_| "join thread error".to_string())?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_code_of_enclave_errors() {
+        let err: CommandError = NitroError::attestation_failed().into();
+        assert_eq!(err.exit_code(), 30);
+        let err: CommandError = "vsock proxy not started".to_owned().into();
+        assert_eq!(err.exit_code(), 1);
+    }
+}
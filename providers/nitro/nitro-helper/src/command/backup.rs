@@ -0,0 +1,259 @@
+use rand_core::{OsRng, RngCore};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use tmkms_light::utils::{print_pubkey, PubkeyDisplay};
+use tmkms_nitro_helper::backup::{reencrypt_share, BackupShare, ShamirThreshold};
+use tmkms_nitro_helper::key_shares::x25519_public_key;
+use zeroize::Zeroizing;
+
+use super::provision::enclave_request;
+use crate::attestation::{attested_public_key, verify_attestation_doc};
+use crate::command::CommandError;
+use crate::config::NitroSignOpt;
+use crate::key_utils::{credential, seal_key_response};
+use crate::shared::{
+    KeyPurpose, NitroBackupConfig, NitroBackupResponse, NitroBackupResult, NitroRequest,
+    NitroResponse, NitroRestoreConfig,
+};
+
+/// parses a base64-encoded X25519 key (an operator's public key or secret)
+pub fn parse_x25519_key(key: &str) -> Result<[u8; 32], String> {
+    let bytes = subtle_encoding::base64::decode(key.trim())
+        .map_err(|e| format!("invalid base64: {:?}", e))?;
+    <[u8; 32]>::try_from(bytes.as_slice()).map_err(|_| "not a 32-byte X25519 key".to_owned())
+}
+
+fn read_backup(path: &Path) -> Result<NitroBackupResponse, String> {
+    let json =
+        fs::read(path).map_err(|e| format!("failed to read `{}`: {:?}", path.display(), e))?;
+    serde_json::from_slice(&json)
+        .map_err(|e| format!("invalid backup `{}`: {:?}", path.display(), e))
+}
+
+/// generates an operator's X25519 key for the backup shares
+/// (the secret is written base64-encoded to `output`)
+pub fn operator_keygen(output: PathBuf) -> Result<(), String> {
+    let mut secret = Zeroizing::new([0u8; 32]);
+    OsRng.fill_bytes(&mut secret[..]);
+    let encoded = Zeroizing::new(subtle_encoding::base64::encode(&secret[..]));
+    OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .mode(0o600)
+        .open(&output)
+        .and_then(|mut file| file.write_all(&encoded))
+        .map_err(|e| format!("couldn't write `{}`: {:?}", output.display(), e))?;
+    println!(
+        "operator public key: {}",
+        String::from_utf8_lossy(&subtle_encoding::base64::encode(x25519_public_key(&secret)))
+    );
+    Ok(())
+}
+
+/// splits the consensus key (in the enclave) into Shamir shares encrypted to the operators' keys
+/// and writes them together with the enclave attestation to `output`
+pub fn backup(
+    config: &NitroSignOpt,
+    shamir: ShamirThreshold,
+    recipients: Vec<[u8; 32]>,
+    output: PathBuf,
+    cid: Option<u32>,
+) -> Result<(), CommandError> {
+    if recipients.len() != shamir.shares as usize {
+        return Err(format!(
+            "{} shares need {} operator keys ({} given)",
+            shamir,
+            shamir.shares,
+            recipients.len()
+        )
+        .into());
+    }
+    let credentials = credential::CredentialsSource::new(config).credentials()?;
+    let sealed_consensus_key = fs::read(config.sealed_consensus_key_path.clone())
+        .map_err(|e| format!("failed to read a sealed consensus key: {:?}", e))?;
+    let mut nonce = vec![0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    let response: NitroBackupResult = enclave_request(
+        config,
+        cid,
+        &NitroRequest::Backup(NitroBackupConfig {
+            credentials,
+            aws_region: config.aws_region.clone(),
+            kms_failover_regions: config.kms_failover_regions(),
+            sealed_consensus_key,
+            consensus_key_derivation: config.derivation_path.clone(),
+            threshold: shamir.threshold,
+            recipients: recipients.clone(),
+            nonce: nonce.clone(),
+        }),
+    )?;
+    let response = response?;
+
+    let backup = &response.backup;
+    if backup.threshold != shamir.threshold
+        || backup.shares.len() != recipients.len()
+        || backup
+            .shares
+            .iter()
+            .zip(recipients.iter())
+            .any(|(share, recipient)| &share.recipient != recipient)
+    {
+        return Err("backup shares don't match the requested operators"
+            .to_owned()
+            .into());
+    }
+    let doc = verify_attestation_doc(&response.attestation_doc)?;
+    if doc.nonce.as_ref().map(|n| n.as_slice()) != Some(nonce.as_slice()) {
+        return Err("backup attestation doesn't have the requested nonce"
+            .to_owned()
+            .into());
+    }
+    config.required_pcrs()?.verify(&doc)?;
+    let claim: serde_json::Value = serde_json::from_slice(
+        &doc.user_data
+            .ok_or_else(|| "backup attestation has no user data".to_owned())?,
+    )
+    .map_err(|e| format!("invalid backup attestation claim: {:?}", e))?;
+    let pubkeyb64 = String::from_utf8(subtle_encoding::base64::encode(backup.public_key))
+        .map_err(|e| format!("encoding the public key: {:?}", e))?;
+    let digest = String::from_utf8(subtle_encoding::base64::encode(backup.digest()))
+        .map_err(|e| format!("encoding backup digest: {:?}", e))?;
+    if claim["pubkey"].as_str() != Some(pubkeyb64.as_str())
+        || claim["backup"].as_str() != Some(digest.as_str())
+    {
+        return Err("enclave attestation doesn't match the backup"
+            .to_owned()
+            .into());
+    }
+
+    let json = serde_json::to_string_pretty(&response)
+        .map_err(|e| format!("failed to serialize the backup: {:?}", e))?;
+    fs::write(&output, json)
+        .map_err(|e| format!("couldn't write `{}`: {:?}", output.display(), e))?;
+    println!(
+        "{} backup shares of {} written to {}",
+        shamir,
+        pubkeyb64,
+        output.display()
+    );
+    Ok(())
+}
+
+/// re-encrypts the operator's share to the restoring enclave's attested one-time key
+/// -- meant to be run offline by each operator
+pub fn restore_share(
+    config: &NitroSignOpt,
+    backup: PathBuf,
+    attestation: PathBuf,
+    operator_key: PathBuf,
+    output: PathBuf,
+) -> Result<(), String> {
+    let backup = read_backup(&backup)?;
+    let encoded = fs::read_to_string(&attestation)
+        .map_err(|e| format!("failed to read `{}`: {:?}", attestation.display(), e))?;
+    let attestation_doc = subtle_encoding::base64::decode(encoded.trim())
+        .map_err(|e| format!("invalid attestation `{}`: {:?}", attestation.display(), e))?;
+    let expected = config.required_pcrs()?;
+    let doc = verify_attestation_doc(&attestation_doc)?;
+    expected.verify(&doc)?;
+    let enclave_key = attested_public_key(&doc)?;
+    let encoded_secret = Zeroizing::new(
+        fs::read_to_string(&operator_key)
+            .map_err(|e| format!("failed to read `{}`: {:?}", operator_key.display(), e))?,
+    );
+    let secret = Zeroizing::new(
+        parse_x25519_key(&encoded_secret).map_err(|e| format!("invalid operator key: {}", e))?,
+    );
+    let own_key = x25519_public_key(&secret);
+    let share = backup
+        .backup
+        .shares
+        .iter()
+        .find(|share| share.recipient == own_key)
+        .ok_or_else(|| "the backup has no share for this operator key".to_owned())?;
+    let share = reencrypt_share(&mut OsRng, share, &secret, &enclave_key)
+        .map_err(|e| format!("failed to re-encrypt the share: {:?}", e))?;
+    let json = serde_json::to_vec(&share)
+        .map_err(|e| format!("failed to serialize the share: {:?}", e))?;
+    fs::write(&output, json)
+        .map_err(|e| format!("couldn't write `{}`: {:?}", output.display(), e))?;
+    println!("share {} written to `{}`", share.index, output.display());
+    Ok(())
+}
+
+/// sends the re-encrypted shares to the enclave, which reassembles the consensus key,
+/// seals it (at `sealed_consensus_key_path`) and attests it
+pub fn restore_finish(
+    config: &NitroSignOpt,
+    backup: PathBuf,
+    shares: Vec<PathBuf>,
+    kms_key_id: String,
+    pubkey_display: Option<PubkeyDisplay>,
+    bech32_prefix: Option<String>,
+    cid: Option<u32>,
+) -> Result<(), CommandError> {
+    let path = &config.sealed_consensus_key_path;
+    if path.exists() {
+        return Err(format!("`{}` already exists", path.display()).into());
+    }
+    let backup = read_backup(&backup)?.backup;
+    let shares = shares
+        .iter()
+        .map(|share| {
+            let json = fs::read(share)
+                .map_err(|e| format!("failed to read `{}`: {:?}", share.display(), e))?;
+            serde_json::from_slice(&json)
+                .map_err(|e| format!("invalid share `{}`: {:?}", share.display(), e))
+        })
+        .collect::<Result<Vec<BackupShare>, String>>()?;
+    if shares.len() < backup.threshold as usize {
+        return Err(format!(
+            "the backup needs {} shares ({} given)",
+            backup.threshold,
+            shares.len()
+        )
+        .into());
+    }
+    let credentials = credential::CredentialsSource::new(config).credentials()?;
+    let mut nonce = vec![0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    let derivation_path = backup.derivation_path.clone();
+    let expected_key = backup.public_key;
+    let response: NitroResponse = enclave_request(
+        config,
+        cid,
+        &NitroRequest::Restore(NitroRestoreConfig {
+            credentials,
+            kms_key_id,
+            aws_region: config.aws_region.clone(),
+            backup,
+            shares,
+            nonce: nonce.clone(),
+        }),
+    )?;
+    let response = response?;
+    if response.public_key != expected_key {
+        return Err("the restored key isn't the backed up one".to_owned().into());
+    }
+    let (public_key, attestation_doc) = seal_key_response(
+        path,
+        response,
+        &nonce,
+        &config.required_pcrs()?,
+        KeyPurpose::Consensus,
+        derivation_path.as_ref(),
+    )?;
+    print_pubkey(bech32_prefix, pubkey_display, public_key);
+    let encoded_attdoc = String::from_utf8(subtle_encoding::base64::encode(attestation_doc))
+        .map_err(|e| format!("enconding attestation doc: {:?}", e))?;
+    println!("Nitro Enclave attestation:\n{}", &encoded_attdoc);
+    if let Some(path) = derivation_path {
+        println!(
+            "set `derivation_path = \"{}\"` in the config to sign with this key",
+            path
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,292 @@
+use prost::Message as _;
+use rand_core::{OsRng, RngCore};
+use serde::Serialize;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tendermint::chain;
+use tendermint_proto::google::protobuf::Timestamp;
+use tendermint_proto::privval::{
+    message::Sum, Message as PrivMessage, PingRequest, SignProposalRequest, SignVoteRequest,
+};
+use tendermint_proto::types::{BlockId, PartSetHeader, Proposal, SignedMsgType, Vote};
+
+/// kind of a simulated validator request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BenchRequest {
+    Prevote,
+    Precommit,
+    Proposal,
+    Ping,
+}
+
+/// weights of the request kinds in the workload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageMix(Vec<(BenchRequest, u32)>);
+
+impl FromStr for MessageMix {
+    type Err = String;
+
+    /// e.g. `prevote=45,precommit=45,proposal=5,ping=5`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut weights = vec![];
+        for entry in s.split(',') {
+            let (kind, weight) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("invalid mix entry `{}` (expected `kind=weight`)", entry))?;
+            let kind = match kind.trim() {
+                "prevote" => BenchRequest::Prevote,
+                "precommit" => BenchRequest::Precommit,
+                "proposal" => BenchRequest::Proposal,
+                "ping" => BenchRequest::Ping,
+                other => return Err(format!("unknown request kind `{}`", other)),
+            };
+            let weight = weight
+                .trim()
+                .parse()
+                .map_err(|e| format!("invalid weight of `{}`: {}", entry, e))?;
+            weights.push((kind, weight));
+        }
+        if weights.iter().all(|(_, weight)| *weight == 0) {
+            return Err("the mix needs a positive weight".to_owned());
+        }
+        Ok(Self(weights))
+    }
+}
+
+impl MessageMix {
+    /// the kind with the cumulative weight covering `point` (in `0..total weight`)
+    fn pick(&self, mut point: u32) -> BenchRequest {
+        for (kind, weight) in self.0.iter() {
+            if point < *weight {
+                return *kind;
+            }
+            point -= weight;
+        }
+        BenchRequest::Ping
+    }
+
+    fn pick_random(&self) -> BenchRequest {
+        let total: u32 = self.0.iter().map(|(_, weight)| weight).sum();
+        self.pick(OsRng.next_u32() % total)
+    }
+}
+
+fn block_id() -> BlockId {
+    let mut hash = vec![0u8; 32];
+    OsRng.fill_bytes(&mut hash);
+    BlockId {
+        hash: hash.clone(),
+        part_set_header: Some(PartSetHeader { total: 1, hash }),
+    }
+}
+
+fn now() -> Timestamp {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Timestamp {
+        seconds: now.as_secs() as i64,
+        nanos: now.subsec_nanos() as i32,
+    }
+}
+
+/// the privval request (the signing ones are at increasing heights, so they're never double signs)
+fn encode_request(kind: BenchRequest, chain_id: &chain::Id, height: i64) -> Vec<u8> {
+    let vote = |vote_type: SignedMsgType| {
+        Sum::SignVoteRequest(SignVoteRequest {
+            vote: Some(Vote {
+                r#type: vote_type as i32,
+                height,
+                round: 0,
+                block_id: Some(block_id()),
+                timestamp: Some(now()),
+                validator_address: vec![0u8; 20],
+                validator_index: 0,
+                signature: vec![],
+            }),
+            chain_id: chain_id.to_string(),
+        })
+    };
+    let sum = match kind {
+        BenchRequest::Prevote => vote(SignedMsgType::Prevote),
+        BenchRequest::Precommit => vote(SignedMsgType::Precommit),
+        BenchRequest::Proposal => Sum::SignProposalRequest(SignProposalRequest {
+            proposal: Some(Proposal {
+                r#type: SignedMsgType::Proposal as i32,
+                height,
+                round: 0,
+                pol_round: -1,
+                block_id: Some(block_id()),
+                timestamp: Some(now()),
+                signature: vec![],
+            }),
+            chain_id: chain_id.to_string(),
+        }),
+        BenchRequest::Ping => Sum::PingRequest(PingRequest {}),
+    };
+    PrivMessage { sum: Some(sum) }.encode_length_delimited_to_vec()
+}
+
+/// reads a length-delimited privval message
+pub fn read_response<R: Read>(stream: &mut R) -> io::Result<PrivMessage> {
+    let mut len = 0usize;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte)?;
+        len |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+    let mut msg = vec![0u8; len];
+    stream.read_exact(&mut msg)?;
+    PrivMessage::decode(msg.as_slice()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// the signer refused the request
+fn is_refusal(response: &PrivMessage) -> bool {
+    match &response.sum {
+        Some(Sum::SignedVoteResponse(r)) => r.error.is_some(),
+        Some(Sum::SignedProposalResponse(r)) => r.error.is_some(),
+        Some(Sum::PingResponse(_)) => false,
+        _ => true,
+    }
+}
+
+/// latencies of the benchmark (in milliseconds)
+#[derive(Debug, Serialize, PartialEq)]
+pub struct BenchReport {
+    pub requests: u64,
+    /// requests refused by the signer (e.g. by the signing policy)
+    pub refused: u64,
+    pub requests_per_sec: f64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+impl BenchReport {
+    fn new(mut latencies: Vec<Duration>, refused: u64, elapsed: Duration) -> Self {
+        latencies.sort();
+        let percentile = |p: usize| {
+            // nearest rank
+            let rank = (latencies.len() * p + 99) / 100;
+            latencies
+                .get(rank.saturating_sub(1))
+                .map_or(0.0, |d| d.as_secs_f64() * 1000.0)
+        };
+        Self {
+            requests: latencies.len() as u64,
+            refused,
+            requests_per_sec: latencies.len() as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+            p50_ms: percentile(50),
+            p90_ms: percentile(90),
+            p99_ms: percentile(99),
+            max_ms: percentile(100),
+        }
+    }
+}
+
+/// settings of the benchmark
+#[derive(Debug)]
+pub struct BenchOpt {
+    pub socket: PathBuf,
+    pub chain_id: chain::Id,
+    pub rate: u32,
+    pub requests: u64,
+    pub mix: MessageMix,
+    pub start_height: i64,
+}
+
+/// acts as the validator on the Unix socket the signer connects to, sends it the synthetic
+/// requests at the rate and prints the latency percentiles
+pub fn bench(opt: BenchOpt) -> Result<(), String> {
+    if opt.rate == 0 {
+        return Err("the rate must be positive".to_owned());
+    }
+    let _ = fs::remove_file(&opt.socket);
+    let listener = UnixListener::bind(&opt.socket)
+        .map_err(|e| format!("failed to bind `{}`: {:?}", opt.socket.display(), e))?;
+    tracing::info!(
+        "waiting for the signer to connect to {}",
+        opt.socket.display()
+    );
+    let (mut stream, _) = listener
+        .accept()
+        .map_err(|e| format!("failed to accept the signer's connection: {:?}", e))?;
+    tracing::info!(
+        "signer connected, sending {} requests at {}/s",
+        opt.requests,
+        opt.rate
+    );
+    let interval = Duration::from_secs(1) / opt.rate;
+    let mut latencies = Vec::with_capacity(opt.requests as usize);
+    let mut refused = 0;
+    let mut height = opt.start_height;
+    let started = Instant::now();
+    for i in 0..opt.requests {
+        let scheduled = started + interval * i as u32;
+        if let Some(wait) = scheduled.checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+        let kind = opt.mix.pick_random();
+        if kind != BenchRequest::Ping {
+            height += 1;
+        }
+        let request = encode_request(kind, &opt.chain_id, height);
+        let sent = Instant::now();
+        stream
+            .write_all(&request)
+            .map_err(|e| format!("failed to send request: {:?}", e))?;
+        let response =
+            read_response(&mut stream).map_err(|e| format!("failed to read response: {:?}", e))?;
+        latencies.push(sent.elapsed());
+        if is_refusal(&response) {
+            refused += 1;
+        }
+    }
+    let report = BenchReport::new(latencies, refused, started.elapsed());
+    let s = serde_json::to_string_pretty(&report)
+        .map_err(|e| format!("failed to serialize the report: {:?}", e))?;
+    println!("{}", s);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_mix_and_reports_percentiles() {
+        let mix: MessageMix = "prevote=2,precommit=0,ping=1".parse().unwrap();
+        assert_eq!(mix.pick(0), BenchRequest::Prevote);
+        assert_eq!(mix.pick(1), BenchRequest::Prevote);
+        assert_eq!(mix.pick(2), BenchRequest::Ping);
+        assert!("prevote=0".parse::<MessageMix>().is_err());
+        assert!("vote=1".parse::<MessageMix>().is_err());
+
+        let latencies = (1..=100).map(Duration::from_millis).collect();
+        let report = BenchReport::new(latencies, 3, Duration::from_secs(10));
+        assert_eq!(report.requests, 100);
+        assert_eq!(report.requests_per_sec, 10.0);
+        assert_eq!(report.p50_ms, 50.0);
+        assert_eq!(report.p99_ms, 99.0);
+        assert_eq!(report.max_ms, 100.0);
+
+        let request = encode_request(BenchRequest::Prevote, &"testchain-1".parse().unwrap(), 7);
+        let decoded = read_response(&mut request.as_slice()).unwrap();
+        match decoded.sum {
+            Some(Sum::SignVoteRequest(req)) => {
+                let req = tendermint::vote::SignVoteRequest::try_from(req).unwrap();
+                assert_eq!(req.vote.height.value(), 7);
+            }
+            _ => panic!("unexpected request"),
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::time::Duration;
+use tmkms_light::utils::{read_u16_payload, write_u16_payload};
+use tmkms_nitro_helper::channel::ChannelStream;
+use tmkms_nitro_helper::codec;
+use tmkms_nitro_helper::control::{encode_request, negotiate};
+
+use crate::command::CommandError;
+use crate::config::{ChainControlOpt, NitroSignOpt};
+use crate::shared::{NitroChainStatus, NitroChainStatusResult, NitroRequest};
+use tmkms_light::session::SessionStatus;
+use tmkms_nitro_helper::schema::{ChainStatusV1, Status, StatusV1};
+
+/// how long the enclave has to answer a chain control or status request
+/// (it waits up to 10 seconds for a drained session's in-flight request)
+pub const CHAIN_REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// sends a chain control or status request to the enclave (at the cid and config port)
+pub fn request_chains(
+    cid: u32,
+    port: u32,
+    request: &NitroRequest,
+    timeout: Duration,
+) -> Result<Vec<NitroChainStatus>, CommandError> {
+    let mut socket = ChannelStream::connect(cid, port)
+        .map_err(|e| format!("failed to connect to the enclave: {:?}", e))?;
+    socket
+        .set_read_timeout(Some(timeout))
+        .and_then(|_| socket.set_write_timeout(Some(timeout)))
+        .map_err(|e| format!("failed to set the request timeout: {:?}", e))?;
+    negotiate(&mut socket)?;
+    let request_raw = encode_request(request)?;
+    write_u16_payload(&mut socket, &request_raw)
+        .map_err(|e| format!("failed to write the chain request: {:?}", e))?;
+    let response_raw = read_u16_payload(&mut socket)
+        .map_err(|e| format!("failed to read the chain response: {:?}", e))?;
+    let response: NitroChainStatusResult = codec::decode(&response_raw)
+        .map_err(|e| format!("failed to get chain response from enclave: {:?}", e))?;
+    Ok(response?)
+}
+
+/// the chain statuses in the versioned status schema
+pub fn chain_statuses_v1(chains: Vec<NitroChainStatus>) -> Vec<ChainStatusV1> {
+    chains
+        .into_iter()
+        .map(|chain| ChainStatusV1 {
+            chain_id: chain.chain_id.to_string(),
+            status: match chain.status {
+                SessionStatus::Running => "running",
+                SessionStatus::Paused => "paused",
+                SessionStatus::Maintenance => "maintenance",
+                SessionStatus::Stopped => "stopped",
+            }
+            .to_owned(),
+        })
+        .collect()
+}
+
+/// sends a chain control or status request to the enclave and prints the chain statuses
+/// (in the versioned status schema)
+pub fn chain_control(opt: &ChainControlOpt, request: NitroRequest) -> Result<(), String> {
+    let config = NitroSignOpt::from_file(opt.config_path.clone())?;
+    let chains = request_chains(
+        opt.cid.unwrap_or(config.enclave_config_cid),
+        config.enclave_config_port,
+        &request,
+        CHAIN_REQUEST_TIMEOUT,
+    )?;
+    let status = Status::V1(StatusV1 {
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        chains: chain_statuses_v1(chains),
+    });
+    let s = serde_json::to_string_pretty(&status)
+        .map_err(|e| format!("failed to serialize chain statuses: {:?}", e))?;
+    println!("{}", s);
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use rand_core::{OsRng, RngCore};
+use std::fs;
+use tmkms_light::utils::{print_pubkey, PubkeyDisplay};
+use tmkms_nitro_helper::slip10::DerivationPath;
+
+use super::provision::enclave_request;
+use crate::attestation::verify_attestation_doc;
+use crate::command::CommandError;
+use crate::config::NitroSignOpt;
+use crate::key_utils::credential;
+use crate::shared::{NitroDeriveConfig, NitroDeriveResult, NitroRequest};
+use ed25519_consensus::VerificationKey;
+
+/// prints the consensus key derived at `derivation_path` from the sealed master seed
+/// (e.g. to register a validator on another chain) with the enclave attestation for it
+pub fn derive(
+    config: &NitroSignOpt,
+    derivation_path: DerivationPath,
+    pubkey_display: Option<PubkeyDisplay>,
+    bech32_prefix: Option<String>,
+    cid: Option<u32>,
+) -> Result<(), CommandError> {
+    let sealed_seed = fs::read(&config.sealed_consensus_key_path)
+        .map_err(|e| format!("failed to read a sealed master seed: {:?}", e))?;
+    let credentials = credential::CredentialsSource::new(config).credentials()?;
+    let mut nonce = vec![0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    let request = NitroRequest::Derive(NitroDeriveConfig {
+        credentials,
+        aws_region: config.aws_region.clone(),
+        kms_failover_regions: config.kms_failover_regions(),
+        sealed_seed,
+        derivation_path: derivation_path.clone(),
+        nonce: nonce.clone(),
+    });
+    let response: NitroDeriveResult = enclave_request(config, cid, &request)?;
+    let response = response?;
+
+    let doc = verify_attestation_doc(&response.attestation_doc)?;
+    if doc.nonce.as_ref().map(|n| n.as_slice()) != Some(nonce.as_slice()) {
+        return Err("derive attestation doesn't have the requested nonce"
+            .to_owned()
+            .into());
+    }
+    config.required_pcrs()?.verify(&doc)?;
+    let claim: serde_json::Value = serde_json::from_slice(
+        &doc.user_data
+            .ok_or_else(|| "derive attestation has no user data".to_owned())?,
+    )
+    .map_err(|e| format!("invalid derive attestation claim: {:?}", e))?;
+    let pubkeyb64 = String::from_utf8(subtle_encoding::base64::encode(&response.public_key))
+        .map_err(|e| format!("encoding the public key: {:?}", e))?;
+    if claim["pubkey"].as_str() != Some(pubkeyb64.as_str())
+        || claim["path"].as_str() != Some(derivation_path.to_string().as_str())
+    {
+        return Err("derive attestation doesn't match the derived public key"
+            .to_owned()
+            .into());
+    }
+    let public_key = VerificationKey::try_from(response.public_key.as_slice())
+        .map_err(|e| format!("invalid pubkey: {:?}", e))?;
+    println!("derivation path: {}", derivation_path);
+    print_pubkey(bech32_prefix, pubkey_display, public_key);
+    let encoded_attdoc =
+        String::from_utf8(subtle_encoding::base64::encode(&response.attestation_doc))
+            .map_err(|e| format!("enconding attestation doc: {:?}", e))?;
+    println!("Nitro Enclave attestation:\n{}", &encoded_attdoc);
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use std::{fs, path::PathBuf};
+
+use super::provision::enclave_request;
+use crate::attestation::{attested_public_key, verify_attestation_doc};
+use crate::command::CommandError;
+use crate::config::NitroSignOpt;
+use crate::key_utils::credential;
+use crate::shared::{NitroKeySharesConfig, NitroKeySharesResult, NitroRequest};
+use ed25519_consensus::VerificationKey;
+use serde_bytes::ByteBuf;
+
+/// splits the consensus key into threshold shares encrypted to the future cosigner enclaves
+/// (their base64-encoded attestation documents are in `recipients` files; one share for each)
+/// and writes them together with the enclave attestation to `output`
+pub fn key_shares(
+    config: &NitroSignOpt,
+    threshold: u16,
+    recipients: Vec<PathBuf>,
+    output: PathBuf,
+    cid: Option<u32>,
+) -> Result<(), CommandError> {
+    let recipient_attestations = recipients
+        .iter()
+        .map(|path| {
+            let encoded = fs::read_to_string(path)
+                .map_err(|e| format!("failed to read `{}`: {:?}", path.display(), e))?;
+            subtle_encoding::base64::decode(encoded.trim())
+                .map_err(|e| format!("invalid attestation `{}`: {:?}", path.display(), e))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    // the enclave checks them against its policy as well
+    let recipients = recipient_attestations
+        .iter()
+        .zip(recipients.iter())
+        .map(|(doc, path)| {
+            verify_attestation_doc(doc)
+                .and_then(|doc| attested_public_key(&doc))
+                .map_err(|e| format!("`{}`: {}", path.display(), e))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let credentials = credential::CredentialsSource::new(config).credentials()?;
+    let sealed_consensus_key = fs::read(config.sealed_consensus_key_path.clone())
+        .map_err(|e| format!("failed to read a sealed consensus key: {:?}", e))?;
+    let request = NitroRequest::KeyShares(NitroKeySharesConfig {
+        credentials,
+        aws_region: config.aws_region.clone(),
+        kms_failover_regions: config.kms_failover_regions(),
+        sealed_consensus_key,
+        consensus_key_derivation: config.derivation_path.clone(),
+        threshold,
+        recipient_attestations: recipient_attestations
+            .into_iter()
+            .map(ByteBuf::from)
+            .collect(),
+    });
+    let response: NitroKeySharesResult = enclave_request(config, cid, &request)?;
+    let response = response?;
+
+    let public_key = VerificationKey::try_from(response.public_key.as_slice())
+        .map_err(|e| format!("invalid pubkey: {:?}", e))?;
+    if !response.key_shares.commits_to(&public_key) {
+        return Err("key shares don't commit to the consensus public key"
+            .to_owned()
+            .into());
+    }
+    if response.key_shares.shares.len() != recipients.len()
+        || response
+            .key_shares
+            .shares
+            .iter()
+            .zip(recipients.iter())
+            .any(|(share, recipient)| &share.recipient != recipient)
+    {
+        return Err("key shares don't match the requested recipients"
+            .to_owned()
+            .into());
+    }
+    let doc = verify_attestation_doc(&response.attestation_doc)?;
+    config.required_pcrs()?.verify(&doc)?;
+    let attested_claim = doc
+        .user_data
+        .ok_or_else(|| "enclave attestation has no user data".to_owned())?;
+    let claim: serde_json::Value = serde_json::from_slice(&attested_claim)
+        .map_err(|e| format!("invalid enclave attestation claim: {:?}", e))?;
+    let digest = String::from_utf8(subtle_encoding::base64::encode(
+        response.key_shares.digest(),
+    ))
+    .map_err(|e| format!("encoding key shares digest: {:?}", e))?;
+    if claim["shares"].as_str() != Some(digest.as_str()) {
+        return Err("enclave attestation doesn't match the key shares"
+            .to_owned()
+            .into());
+    }
+
+    let json = serde_json::to_string_pretty(&response)
+        .map_err(|e| format!("failed to serialize key shares: {:?}", e))?;
+    fs::write(&output, json)
+        .map_err(|e| format!("couldn't write `{}`: {:?}", output.display(), e))?;
+    println!(
+        "{}-of-{} key shares for {} written to {}",
+        threshold,
+        recipients.len(),
+        String::from_utf8_lossy(&subtle_encoding::base64::encode(public_key)),
+        output.display()
+    );
+    Ok(())
+}
@@ -1,6 +1,6 @@
 use crate::command::nitro_enclave::run_vsock_proxy;
 use crate::command::nitro_enclave::{describe_enclave, run_enclave};
-use crate::command::start;
+use crate::command::{shutdown_enclave, start};
 use crate::config::{EnclaveConfig, NitroSignOpt};
 use std::sync::mpsc::{channel, Sender};
 use std::thread::{self, sleep};
@@ -102,7 +102,7 @@ impl Launcher {
             let tmkms_config = self.tmkms_config.clone();
             let stop_senders = self.stop_senders.clone();
             let t3 = thread::spawn(move || {
-                if let Err(e) = start(&tmkms_config, cid, rx3) {
+                if let Err(e) = start(&tmkms_config, cid, None, rx3) {
                     tracing::error!("{}", e);
                     for tx in stop_senders {
                         if let Err(e) = tx.send(()) {
@@ -117,8 +117,16 @@ impl Launcher {
 
         // when get the ctrlc signal, send stop signal
         let stop_senders = self.stop_senders.clone();
+        let tmkms_config = self.tmkms_config.clone();
         ctrlc::set_handler(move || {
             tracing::debug!("get Ctrl-C signal, send close enclave signal");
+            crate::systemd::notify("STOPPING=1");
+            // the enclave is shut down gracefully before it's terminated
+            if cid.is_some() {
+                if let Err(e) = shutdown_enclave(&tmkms_config, cid) {
+                    tracing::warn!("enclave shutdown: {}", e);
+                }
+            }
             for tx in stop_senders.iter() {
                 if let Err(e) = tx.send(()) {
                     tracing::error!("send stop signal error: {:?}", e);
@@ -0,0 +1,88 @@
+use rand_core::{OsRng, RngCore};
+use std::fs;
+use std::path::PathBuf;
+
+use super::provision::enclave_request;
+use crate::attestation::verify_attestation_doc;
+use crate::command::CommandError;
+use crate::config::NitroSignOpt;
+use crate::key_utils::credential;
+use crate::shared::{
+    NitroMigrateStateClaim, NitroMigrateStateConfig, NitroMigrateStateResult, NitroRequest,
+};
+
+/// has the enclave authenticate the (stopped) chain's state without a MAC, checks the enclave's
+/// attestation of it, persists the authenticated state and keeps the attestation next to it
+pub fn migrate_state(
+    config: &NitroSignOpt,
+    attestation_output: PathBuf,
+    cid: Option<u32>,
+) -> Result<(), CommandError> {
+    let mut store = config
+        .state_backend
+        .open(&config.state_file_path)
+        .map_err(|e| format!("failed to open the state store: {:?}", e))?;
+    let loaded = store
+        .load()
+        .map_err(|e| format!("failed to load the state: {:?}", e))?
+        .ok_or_else(|| "there's no state to migrate".to_owned())?;
+    if loaded.mac.is_some() {
+        return Err("the state is already authenticated".to_owned().into());
+    }
+    let credentials = credential::CredentialsSource::new(config).credentials()?;
+    let sealed_consensus_key = fs::read(config.sealed_consensus_key_path.clone())
+        .map_err(|e| format!("failed to read a sealed consensus key: {:?}", e))?;
+    let mut nonce = vec![0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    let response: NitroMigrateStateResult = enclave_request(
+        config,
+        cid,
+        &NitroRequest::MigrateState(NitroMigrateStateConfig {
+            credentials,
+            aws_region: config.aws_region.clone(),
+            kms_failover_regions: config.kms_failover_regions(),
+            sealed_consensus_key,
+            consensus_key_derivation: config.derivation_path.clone(),
+            chain_id: config.chain_id.clone(),
+            state: loaded.state.clone(),
+            nonce: nonce.clone(),
+        }),
+    )?;
+    let response = response?;
+    if response.state.state != loaded.state {
+        return Err("the enclave authenticated another state".to_owned().into());
+    }
+    let doc = verify_attestation_doc(&response.attestation_doc)?;
+    if doc.nonce.as_ref().map(|n| n.as_slice()) != Some(nonce.as_slice()) {
+        return Err("migration attestation doesn't have the requested nonce"
+            .to_owned()
+            .into());
+    }
+    config.required_pcrs()?.verify(&doc)?;
+    let claim: NitroMigrateStateClaim = serde_json::from_slice(
+        &doc.user_data
+            .ok_or_else(|| "migration attestation has no user data".to_owned())?,
+    )
+    .map_err(|e| format!("invalid migration attestation claim: {:?}", e))?;
+    if claim != NitroMigrateStateClaim::new(&config.chain_id, &response.state)? {
+        return Err("enclave attestation doesn't match the state"
+            .to_owned()
+            .into());
+    }
+
+    fs::write(
+        &attestation_output,
+        subtle_encoding::base64::encode(&response.attestation_doc),
+    )
+    .map_err(|e| format!("couldn't write `{}`: {:?}", attestation_output.display(), e))?;
+    store
+        .persist(&response.state)
+        .map_err(|e| format!("failed to persist the state: {:?}", e))?;
+    println!(
+        "the {} state at {} is authenticated (attestation written to {})",
+        config.chain_id,
+        response.state.state,
+        attestation_output.display()
+    );
+    Ok(())
+}
@@ -1,13 +1,24 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // Modifications Copyright (c) 2021-present Crypto.com (licensed under the Apache License, Version 2.0)
 
-use crate::command::check_vsock_proxy;
-use crate::config::{EnclaveOpt, VSockProxyOpt};
+use crate::command::chain::{chain_statuses_v1, request_chains, CHAIN_REQUEST_TIMEOUT};
+use crate::command::{check_vsock_proxy, shutdown_enclave, start};
+use crate::config::{EnclaveOpt, NitroSignOpt, VSockProxyOpt};
 use crate::enclave_log_server::LogServer;
+use crate::kms_proxy::KmsProxy;
+use crate::shared::NitroRequest;
+use crate::supervisor::EnclaveLaunch;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::process::{Command, Output};
 use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use tmkms_nitro_helper::schema::ChainStatusV1;
+
+/// how often the enclave's config listener is polled while the enclave boots
+const READY_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 /// The information provided by a `describe-enclaves` request.
 #[derive(Clone, Serialize, Deserialize)]
@@ -38,6 +49,19 @@ pub struct EnclaveDescribeInfo {
     pub flags: String,
 }
 
+/// The enclave's information and the statuses of its chains.
+#[derive(Clone, Serialize)]
+pub struct EnclaveStatus {
+    #[serde(flatten)]
+    pub enclave: EnclaveDescribeInfo,
+    #[serde(rename = "Ready")]
+    /// The enclave answers on its config port.
+    pub ready: bool,
+    #[serde(rename = "Chains")]
+    /// The chains' signing sessions (empty before the config was pushed).
+    pub chains: Vec<ChainStatusV1>,
+}
+
 /// The information provided by a `run-enclave` request.
 #[derive(Clone, Serialize, Deserialize)]
 pub struct EnclaveRunInfo {
@@ -84,7 +108,8 @@ fn parse_output<T: DeserializeOwned>(output: Output) -> Result<T, String> {
         .map_err(|_| "command invalid output".to_string())
 }
 
-fn run_enclave_daemon(
+/// runs the enclave image (via `nitro-cli`)
+pub fn run_enclave_daemon(
     image_path: &str,
     cpu_count: usize,
     memory_mib: u64,
@@ -104,10 +129,8 @@ fn run_enclave_daemon(
     parse_output(output)
 }
 
-/// start the enclave
-/// opt: the config to start enclave
-/// stop_receiver: when receiver data, the enclave will be stopped
-pub fn run_enclave(opt: &EnclaveOpt, stop_receiver: Receiver<()>) -> Result<(), String> {
+/// checks no enclave is running, launches the log server and runs the enclave image
+fn launch_enclave(opt: &EnclaveOpt) -> Result<EnclaveRunInfo, String> {
     // check if the enclave already running
     let enclave_info = describe_enclave()?;
     if !enclave_info.is_empty() {
@@ -119,7 +142,8 @@ pub fn run_enclave(opt: &EnclaveOpt, stop_receiver: Receiver<()>) -> Result<(),
     }
     // lauch enclave server
     tracing::info!("start enclave log server at port {}", opt.log_server_port);
-    let enclave_log_server = LogServer::new(opt.log_server_port).map_err(|e| format!("{:?}", e))?;
+    let enclave_log_server =
+        LogServer::new(opt.log_server_port, opt.log_format).map_err(|e| format!("{:?}", e))?;
 
     enclave_log_server.launch();
     // run enclave
@@ -131,12 +155,115 @@ pub fn run_enclave(opt: &EnclaveOpt, stop_receiver: Receiver<()>) -> Result<(),
     )?;
     let s = serde_json::to_string_pretty(&info).unwrap();
     tracing::info!("run enclave success:\n{}", s);
+    Ok(info)
+}
+
+/// start the enclave
+/// opt: the config to start enclave
+/// stop_receiver: when receiver data, the enclave will be stopped
+pub fn run_enclave(opt: &EnclaveOpt, stop_receiver: Receiver<()>) -> Result<(), String> {
+    let info = launch_enclave(opt)?;
     // waiting for stop signal and stop the enclave
     let _ = stop_receiver.recv();
     let _ = stop_enclave(Some(info.enclave_id));
     Ok(())
 }
 
+/// waits until the enclave's config listener answers (a chain status request)
+pub fn wait_for_enclave(cid: u32, port: u32, timeout: Duration) -> Result<(), String> {
+    let started = Instant::now();
+    loop {
+        match request_chains(cid, port, &NitroRequest::ChainStatus, CHAIN_REQUEST_TIMEOUT) {
+            Ok(_) => return Ok(()),
+            Err(e) if started.elapsed() < timeout => {
+                tracing::debug!("enclave isn't ready yet: {}", e);
+                sleep(READY_POLL_INTERVAL);
+            }
+            Err(e) => {
+                return Err(format!(
+                    "the enclave isn't ready after {:?}: {}",
+                    timeout, e
+                ))
+            }
+        }
+    }
+}
+
+/// runs the enclave, waits for its config listener and pushes the config (as `helper start`);
+/// the enclave is terminated when the helper stops (or fails to start)
+/// stop_receiver: when receiver data, the enclave will be shut down
+pub fn start_enclave(
+    config: &NitroSignOpt,
+    opt: &EnclaveOpt,
+    ready_timeout: Duration,
+    stop_receiver: Receiver<()>,
+) -> Result<(), String> {
+    let info = launch_enclave(opt)?;
+    let cid = info.enclave_cid as u32;
+    // the supervisor replaces the ID when it relaunches the enclave
+    let launch = EnclaveLaunch {
+        opt: opt.clone(),
+        enclave_id: Arc::new(Mutex::new(info.enclave_id)),
+    };
+    let result = wait_for_enclave(cid, config.enclave_config_port, ready_timeout)
+        .and_then(|_| start(config, Some(cid), Some(launch.clone()), stop_receiver));
+    let enclave_id = launch.enclave_id.lock().expect("enclave id lock").clone();
+    match stop_enclave(Some(enclave_id)) {
+        Ok(_) => tracing::info!("enclave terminated"),
+        Err(e) => tracing::error!("failed to terminate the enclave: {}", e),
+    }
+    result
+}
+
+/// stop enclave(s); with the helper config, the enclave's signing sessions are shut down
+/// gracefully before it's terminated
+pub fn stop_enclave_gracefully(
+    config: Option<&NitroSignOpt>,
+    enclave_id: Option<String>,
+) -> Result<EnclaveTerminateInfo, String> {
+    if let Some(config) = config {
+        for enclave in describe_enclave()? {
+            if enclave_id
+                .as_ref()
+                .map_or(true, |id| *id == enclave.enclave_id)
+            {
+                if let Err(e) = shutdown_enclave(config, Some(enclave.enclave_cid as u32)) {
+                    tracing::warn!(
+                        "failed to shut down enclave {} gracefully: {}",
+                        enclave.enclave_id,
+                        e
+                    );
+                }
+            }
+        }
+    }
+    stop_enclave(enclave_id)
+}
+
+/// the running enclaves with the statuses of their chains
+/// (the enclaves are asked on the config's port)
+pub fn enclave_status(config: &NitroSignOpt) -> Result<Vec<EnclaveStatus>, String> {
+    Ok(describe_enclave()?
+        .into_iter()
+        .map(|enclave| {
+            let chains = request_chains(
+                enclave.enclave_cid as u32,
+                config.enclave_config_port,
+                &NitroRequest::ChainStatus,
+                CHAIN_REQUEST_TIMEOUT,
+            );
+            if let Err(ref e) = chains {
+                tracing::debug!("enclave {} isn't reachable: {}", enclave.enclave_id, e);
+            }
+            EnclaveStatus {
+                enclave,
+                ready: chains.is_ok(),
+                chains: chains.map(chain_statuses_v1).unwrap_or_default(),
+            }
+        })
+        .collect())
+}
+
 /// stop enclave: if cid is None, stop all enclave
 pub fn stop_enclave(cid: Option<String>) -> Result<EnclaveTerminateInfo, String> {
     let mut cmd = Command::new("nitro-cli");
@@ -166,6 +293,19 @@ pub fn describe_enclave() -> Result<Vec<EnclaveDescribeInfo>, String> {
 /// stop_receiver: when receive a data, the vsock proxy will exit
 pub fn run_vsock_proxy(opt: &VSockProxyOpt, stop_receiver: Receiver<()>) -> Result<(), String> {
     tracing::debug!("run vsock proxy with config: {:?}", opt);
+    if opt.builtin {
+        KmsProxy::new(
+            opt.local_port,
+            &opt.remote_addr,
+            opt.remote_port,
+            opt.num_workers,
+            &opt.allowed_endpoints,
+        )?
+        .launch();
+        let _ = stop_receiver.recv();
+        tracing::info!("vsock proxy stopped");
+        return Ok(());
+    }
     if check_vsock_proxy() {
         tracing::warn!("vsock proxy is already running, ignore this start");
         return Ok(());
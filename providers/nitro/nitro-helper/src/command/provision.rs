@@ -0,0 +1,159 @@
+use rand_core::{OsRng, RngCore};
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::PathBuf;
+use tmkms_light::utils::{print_pubkey, PubkeyDisplay};
+use tmkms_nitro_helper::provisioning::{seal_mnemonic, SealedMnemonic};
+use tmkms_nitro_helper::slip10::DerivationPath;
+use zeroize::Zeroizing;
+
+use crate::attestation::{attested_public_key, sealed_request, verify_attestation_doc};
+use crate::command::CommandError;
+use crate::config::NitroSignOpt;
+use crate::key_utils::{credential, seal_key_response};
+use crate::shared::{
+    KeyPurpose, NitroAttestResult, NitroMnemonicConfig, NitroRequest, NitroResponse,
+};
+
+/// sends the request to the enclave (encrypted to a one-time key it attests to, so that
+/// only an enclave with the pinned measurements can read it) and reads its response
+pub(crate) fn enclave_request<T: serde::de::DeserializeOwned>(
+    config: &NitroSignOpt,
+    cid: Option<u32>,
+    request: &NitroRequest,
+) -> Result<T, String> {
+    sealed_request(
+        cid.unwrap_or(config.enclave_config_cid),
+        config.enclave_config_port,
+        &config.required_pcrs()?,
+        request,
+    )
+}
+
+/// asks the enclave for a one-time (attested) key to encrypt the mnemonic to
+/// and writes its base64-encoded attestation document
+pub fn provision_begin(
+    config: &NitroSignOpt,
+    output: PathBuf,
+    cid: Option<u32>,
+) -> Result<(), CommandError> {
+    if config.sealed_consensus_key_path.exists() {
+        return Err(format!(
+            "`{}` already exists",
+            config.sealed_consensus_key_path.display()
+        )
+        .into());
+    }
+    let mut nonce = vec![0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    let response: NitroAttestResult = enclave_request(
+        config,
+        cid,
+        &NitroRequest::ProvisionBegin {
+            nonce: nonce.clone(),
+        },
+    )?;
+    let attestation_doc = response?;
+    let doc = verify_attestation_doc(&attestation_doc)?;
+    if doc.nonce.as_ref().map(|n| n.as_slice()) != Some(nonce.as_slice()) {
+        return Err("provisioning attestation doesn't have the requested nonce"
+            .to_owned()
+            .into());
+    }
+    config.required_pcrs()?.verify(&doc)?;
+    attested_public_key(&doc)?;
+    fs::write(&output, subtle_encoding::base64::encode(&attestation_doc))
+        .map_err(|e| format!("couldn't write `{}`: {:?}", output.display(), e))?;
+    println!(
+        "provisioning attestation written to `{}` (copy it to the offline machine with the mnemonic)",
+        output.display()
+    );
+    Ok(())
+}
+
+/// encrypts the mnemonic (read from the standard input) to the attested one-time key
+/// -- meant to be run offline
+pub fn provision_seal(
+    config: &NitroSignOpt,
+    attestation: PathBuf,
+    output: PathBuf,
+) -> Result<(), String> {
+    let encoded = fs::read_to_string(&attestation)
+        .map_err(|e| format!("failed to read `{}`: {:?}", attestation.display(), e))?;
+    let attestation_doc = subtle_encoding::base64::decode(encoded.trim())
+        .map_err(|e| format!("invalid attestation `{}`: {:?}", attestation.display(), e))?;
+    // the mnemonic is only encrypted to a genuine enclave with the pinned measurements
+    let expected = config.required_pcrs()?;
+    let doc = verify_attestation_doc(&attestation_doc)?;
+    expected.verify(&doc)?;
+    let recipient = attested_public_key(&doc)?;
+    eprintln!("enter the mnemonic:");
+    let mut phrase = Zeroizing::new(String::new());
+    io::stdin()
+        .lock()
+        .read_line(&mut phrase)
+        .map_err(|e| format!("failed to read the mnemonic: {:?}", e))?;
+    let sealed = seal_mnemonic(&mut OsRng, phrase.trim(), &recipient)
+        .map_err(|e| format!("failed to encrypt the mnemonic: {:?}", e))?;
+    let json = serde_json::to_vec(&sealed)
+        .map_err(|e| format!("failed to serialize the encrypted mnemonic: {:?}", e))?;
+    fs::write(&output, json)
+        .map_err(|e| format!("couldn't write `{}`: {:?}", output.display(), e))?;
+    println!("encrypted mnemonic written to `{}`", output.display());
+    Ok(())
+}
+
+/// sends the encrypted mnemonic to the enclave, which seals its seed
+/// (at `sealed_consensus_key_path`) and attests the consensus key derived from it
+pub fn provision_finish(
+    config: &NitroSignOpt,
+    sealed_mnemonic: PathBuf,
+    kms_key_id: String,
+    derivation_path: DerivationPath,
+    pubkey_display: Option<PubkeyDisplay>,
+    bech32_prefix: Option<String>,
+    cid: Option<u32>,
+) -> Result<(), CommandError> {
+    let path = &config.sealed_consensus_key_path;
+    if path.exists() {
+        return Err(format!("`{}` already exists", path.display()).into());
+    }
+    let sealed_mnemonic: SealedMnemonic = serde_json::from_slice(
+        &fs::read(&sealed_mnemonic)
+            .map_err(|e| format!("failed to read `{}`: {:?}", sealed_mnemonic.display(), e))?,
+    )
+    .map_err(|e| format!("invalid encrypted mnemonic: {:?}", e))?;
+    let credentials = credential::CredentialsSource::new(config).credentials()?;
+    let mut nonce = vec![0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    let response: NitroResponse = enclave_request(
+        config,
+        cid,
+        &NitroRequest::ProvisionMnemonic(NitroMnemonicConfig {
+            credentials,
+            kms_key_id,
+            aws_region: config.aws_region.clone(),
+            sealed_mnemonic,
+            derivation_path: derivation_path.clone(),
+            nonce: nonce.clone(),
+        }),
+    )?;
+    // the sealed seed is only written if the attestation matches
+    let (public_key, attestation_doc) = seal_key_response(
+        path,
+        response?,
+        &nonce,
+        &config.required_pcrs()?,
+        KeyPurpose::Consensus,
+        Some(&derivation_path),
+    )?;
+    print_pubkey(bech32_prefix, pubkey_display, public_key);
+    let encoded_attdoc = String::from_utf8(subtle_encoding::base64::encode(attestation_doc))
+        .map_err(|e| format!("enconding attestation doc: {:?}", e))?;
+    println!("Nitro Enclave attestation:\n{}", &encoded_attdoc);
+    println!(
+        "set `derivation_path = \"{}\"` in the config to sign with this key",
+        derivation_path
+    );
+    Ok(())
+}
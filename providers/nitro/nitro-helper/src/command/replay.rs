@@ -0,0 +1,188 @@
+use crate::command::bench::read_response;
+use crate::config::NitroSignOpt;
+use crate::recorder::{read_capture, CapturedMessage, Direction};
+use ed25519_consensus::SigningKey;
+use prost::Message as _;
+use rand_core::OsRng;
+use serde::Serialize;
+use std::fs;
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use tendermint_proto::privval::{message::Sum, Message as PrivMessage};
+use tmkms_light::chain::state::{consensus, MacedState, PersistStateSync, State, StateError};
+use tmkms_light::config::validator::ValidatorConfig;
+use tmkms_light::connection::PlainConnection;
+use tmkms_light::session::{Session, DRY_RUN_REFUSAL};
+
+/// how long a response of the dry-run signer is waited for
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// the state of the dry-run signer (which never persists it)
+struct DryRunSync(consensus::State);
+
+impl PersistStateSync for DryRunSync {
+    fn load_state(&mut self) -> Result<State, StateError> {
+        Ok(State::from(self.0.clone()))
+    }
+
+    fn persist_state(&mut self, _new_state: &consensus::State) -> Result<(), StateError> {
+        Ok(())
+    }
+}
+
+/// the kind of response (the dry-run signer's refusals are the would-be signatures)
+fn outcome(response: &PrivMessage) -> String {
+    let signed = |error: &Option<tendermint_proto::privval::RemoteSignerError>| match error {
+        Some(e) if e.description.ends_with(DRY_RUN_REFUSAL) => "signed".to_owned(),
+        Some(e) => format!("refused: {}", e.description),
+        None => "signed".to_owned(),
+    };
+    match &response.sum {
+        Some(Sum::SignedVoteResponse(r)) => signed(&r.error),
+        Some(Sum::SignedProposalResponse(r)) => signed(&r.error),
+        Some(Sum::PubKeyResponse(r)) => match &r.error {
+            Some(e) => format!("refused: {}", e.description),
+            None => "public key".to_owned(),
+        },
+        Some(Sum::PingResponse(_)) => "pong".to_owned(),
+        _ => "unexpected response".to_owned(),
+    }
+}
+
+/// a request whose replayed response differs from the recorded one
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct Divergence {
+    /// position of the request in the capture
+    pub request: usize,
+    pub timestamp_ms: u64,
+    /// the recorded response (if it was captured)
+    pub recorded: Option<String>,
+    pub replayed: String,
+}
+
+/// summary of the replay
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct ReplayReport {
+    pub requests: usize,
+    pub divergences: Vec<Divergence>,
+}
+
+/// a captured request, its message and the outcome of its recorded response
+type ReplayedRequest<'a> = (&'a CapturedMessage, PrivMessage, Option<String>);
+
+/// pairs the captured requests with the responses recorded after them (in order)
+fn requests_with_responses(captured: &[CapturedMessage]) -> Result<Vec<ReplayedRequest>, String> {
+    let mut responses = captured
+        .iter()
+        .filter(|m| m.direction == Direction::Response)
+        .map(|m| m.decode().map(|r| outcome(&r)));
+    captured
+        .iter()
+        .filter(|m| m.direction == Direction::Request)
+        .map(|m| Ok((m, m.decode()?, responses.next().transpose()?)))
+        .collect()
+}
+
+/// feeds the captured requests to a dry-run signer (with the config's chain ID and signing policy,
+/// a throwaway key and the given state, which is never persisted) and reports the requests
+/// it answers differently than the recorded ones
+pub fn replay(
+    config_path: PathBuf,
+    capture_path: PathBuf,
+    state_path: Option<PathBuf>,
+) -> Result<(), String> {
+    let config = NitroSignOpt::from_file(config_path)?;
+    let captured = read_capture(&capture_path)?;
+    let requests = requests_with_responses(&captured)?;
+    let state = match state_path {
+        Some(path) => {
+            let state_json = fs::read_to_string(&path)
+                .map_err(|e| format!("failed to read `{}`: {:?}", path.display(), e))?;
+            serde_json::from_str::<MacedState>(&state_json)
+                .map_err(|e| format!("invalid state `{}`: {}", path.display(), e))?
+                .state
+        }
+        None => consensus::State::default(),
+    };
+    let (mut validator, signer) =
+        UnixStream::pair().map_err(|e| format!("failed to create a socket pair: {:?}", e))?;
+    validator
+        .set_read_timeout(Some(REPLAY_TIMEOUT))
+        .map_err(|e| format!("failed to set the read timeout: {:?}", e))?;
+    let validator_config = ValidatorConfig {
+        chain_id: config.chain_id,
+        max_height: config.max_height,
+        signing_policy: config.signing_policy,
+    };
+    thread::spawn(move || {
+        let mut session = Session::new(
+            validator_config,
+            Box::new(PlainConnection::new(signer)),
+            SigningKey::new(OsRng),
+            State::from(state.clone()),
+            DryRunSync(state),
+        );
+        session.set_dry_run(true);
+        // ends when the replay closes the connection
+        let _ = session.request_loop();
+    });
+    let mut divergences = vec![];
+    for (i, (captured, request, recorded)) in requests.iter().enumerate() {
+        validator
+            .write_all(&request.encode_length_delimited_to_vec())
+            .map_err(|e| format!("failed to send request {}: {:?}", i, e))?;
+        let replayed = read_response(&mut validator)
+            .map(|response| outcome(&response))
+            .map_err(|e| format!("no response to request {}: {:?}", i, e))?;
+        if recorded.as_ref() != Some(&replayed) {
+            divergences.push(Divergence {
+                request: i,
+                timestamp_ms: captured.timestamp_ms,
+                recorded: recorded.clone(),
+                replayed,
+            });
+        }
+    }
+    let report = ReplayReport {
+        requests: requests.len(),
+        divergences,
+    };
+    let s = serde_json::to_string_pretty(&report)
+        .map_err(|e| format!("failed to serialize the report: {:?}", e))?;
+    println!("{}", s);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tendermint_proto::privval::{PingRequest, PingResponse};
+
+    #[test]
+    fn pairs_requests_with_recorded_responses() {
+        let capture = |direction, message: PrivMessage| CapturedMessage {
+            timestamp_ms: 1,
+            direction,
+            message: String::from_utf8(subtle_encoding::base64::encode(message.encode_to_vec()))
+                .unwrap(),
+        };
+        let ping = PrivMessage {
+            sum: Some(Sum::PingRequest(PingRequest {})),
+        };
+        let pong = PrivMessage {
+            sum: Some(Sum::PingResponse(PingResponse {})),
+        };
+        let captured = vec![
+            capture(Direction::Request, ping.clone()),
+            capture(Direction::Response, pong),
+            capture(Direction::Request, ping),
+        ];
+        let pairs = requests_with_responses(&captured).unwrap();
+        assert_eq!(pairs.len(), 2);
+        assert_eq!(pairs[0].2.as_deref(), Some("pong"));
+        assert_eq!(pairs[1].2, None);
+    }
+}
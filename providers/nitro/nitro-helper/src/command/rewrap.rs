@@ -0,0 +1,159 @@
+use aws_nitro_enclaves_nsm_api::api::AttestationDoc;
+use clap::ValueEnum;
+use rand_core::{OsRng, RngCore};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+
+use super::provision::enclave_request;
+use crate::attestation::{verify_attestation_doc, ExpectedPcrs};
+use crate::attestation_archive::{archive, AttestationKind};
+use crate::command::CommandError;
+use crate::config::NitroSignOpt;
+use crate::key_utils::credential;
+use crate::shared::{NitroRequest, NitroRewrapClaim, NitroRewrapConfig, NitroRewrapResult};
+
+/// the sealed keys
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum SealedKey {
+    /// the consensus key or its master seed (`sealed_consensus_key_path`)
+    Consensus,
+    /// the secret connection identity key (`sealed_id_key_path`)
+    Id,
+}
+
+/// checks the rewrap attestation (with the requested nonce and the pinned measurements)
+/// binds the expected old and new ciphertexts of the key
+fn check_rewrap_attestation(
+    doc: &AttestationDoc,
+    nonce: &[u8],
+    expected_pcrs: &ExpectedPcrs,
+    expected: &NitroRewrapClaim,
+) -> Result<(), String> {
+    if doc.nonce.as_ref().map(|n| n.as_slice()) != Some(nonce) {
+        return Err("rewrap attestation doesn't have the requested nonce".to_owned());
+    }
+    expected_pcrs.verify(doc)?;
+    let claim: NitroRewrapClaim = serde_json::from_slice(
+        doc.user_data
+            .as_ref()
+            .ok_or_else(|| "rewrap attestation has no user data".to_owned())?,
+    )
+    .map_err(|e| format!("invalid rewrap attestation claim: {}", e))?;
+    if &claim != expected {
+        return Err(format!(
+            "rewrap attestation claim mismatch (expected {:?}, got {:?})",
+            expected, claim
+        ));
+    }
+    Ok(())
+}
+
+/// re-encrypts the sealed key with another AWS KMS key (in the enclave)
+/// and replaces its file with the result (after checking the enclave attested
+/// the old and new ciphertexts)
+pub fn rewrap(
+    config: &NitroSignOpt,
+    key: SealedKey,
+    kms_key_id: String,
+    cid: Option<u32>,
+) -> Result<(), CommandError> {
+    let (path, derivation_path) = match key {
+        SealedKey::Consensus => (
+            config.sealed_consensus_key_path.clone(),
+            config.derivation_path.clone(),
+        ),
+        SealedKey::Id => (
+            config
+                .sealed_id_key_path
+                .clone()
+                .ok_or_else(|| "no `sealed_id_key_path` in the config".to_owned())?,
+            None,
+        ),
+    };
+    let sealed_key =
+        fs::read(&path).map_err(|e| format!("failed to read `{}`: {:?}", path.display(), e))?;
+    let credentials = credential::CredentialsSource::new(config).credentials()?;
+    let mut nonce = vec![0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    let request = NitroRequest::Rewrap(NitroRewrapConfig {
+        credentials,
+        aws_region: config.aws_region.clone(),
+        kms_failover_regions: config.kms_failover_regions(),
+        sealed_key: sealed_key.clone(),
+        derivation_path,
+        kms_key_id: kms_key_id.clone(),
+        nonce: nonce.clone(),
+    });
+    let response: NitroRewrapResult = enclave_request(config, cid, &request)?;
+    let response = response?;
+    if response.attestation_doc.is_empty() {
+        return Err("the enclave didn't attest the rewrap (older EIF)"
+            .to_owned()
+            .into());
+    }
+    archive(AttestationKind::Rewrap, None, &response.attestation_doc);
+    check_rewrap_attestation(
+        &verify_attestation_doc(&response.attestation_doc)?,
+        &nonce,
+        &config.required_pcrs()?,
+        &NitroRewrapClaim::new(
+            &response.public_key,
+            &kms_key_id,
+            &sealed_key,
+            &response.encrypted_secret,
+        ),
+    )?;
+
+    // replaced atomically, so the key is never lost halfway
+    let tmp_path = path.with_extension("tmp");
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .and_then(|mut file| file.write_all(&response.encrypted_secret))
+        .and_then(|_| fs::rename(&tmp_path, &path))
+        .map_err(|e| format!("couldn't write `{}`: {:?}", path.display(), e))?;
+    println!(
+        "{} (public key {}) re-encrypted with {}",
+        path.display(),
+        String::from_utf8_lossy(&subtle_encoding::base64::encode(&response.public_key)),
+        kms_key_id
+    );
+    println!(
+        "Nitro Enclave attestation:\n{}",
+        String::from_utf8_lossy(&subtle_encoding::base64::encode(&response.attestation_doc))
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_nitro_enclaves_nsm_api::api::Digest;
+
+    #[test]
+    fn rewrap_attestation_binds_both_ciphertexts() {
+        let claim = NitroRewrapClaim::new(&[1; 32], "new-key", b"old", b"new");
+        let doc = AttestationDoc::new(
+            "i-0".to_owned(),
+            Digest::SHA384,
+            0,
+            Default::default(),
+            vec![],
+            Default::default(),
+            Some(serde_json::to_vec(&claim).unwrap()),
+            Some(vec![7; 32]),
+            None,
+        );
+        let pcrs = ExpectedPcrs::default();
+        assert!(check_rewrap_attestation(&doc, &[7; 32], &pcrs, &claim).is_ok());
+        assert!(check_rewrap_attestation(&doc, &[8; 32], &pcrs, &claim).is_err());
+        let other = NitroRewrapClaim::new(&[1; 32], "new-key", b"old", b"other");
+        assert!(check_rewrap_attestation(&doc, &[7; 32], &pcrs, &other).is_err());
+        let other = NitroRewrapClaim::new(&[1; 32], "new-key", b"other", b"new");
+        assert!(check_rewrap_attestation(&doc, &[7; 32], &pcrs, &other).is_err());
+    }
+}
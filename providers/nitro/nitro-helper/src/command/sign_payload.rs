@@ -0,0 +1,60 @@
+use std::fs;
+use std::path::PathBuf;
+use tmkms_light::possession::verify_proof_of_possession;
+
+use super::provision::enclave_request;
+use crate::command::CommandError;
+use crate::config::NitroSignOpt;
+use crate::key_utils::credential;
+use crate::shared::{NitroRequest, NitroSignPayloadConfig, NitroSignPayloadResult};
+use ed25519_consensus::{Signature, VerificationKey};
+
+/// signs the payload (read from `file`) with the consensus key as a proof of possession
+/// (only if `allow_payload_signing` is set) and prints the base64-encoded signature
+pub fn sign_payload(
+    config: &NitroSignOpt,
+    file: PathBuf,
+    cid: Option<u32>,
+) -> Result<(), CommandError> {
+    if !config.allow_payload_signing {
+        return Err(
+            "payload signing isn't allowed (`allow_payload_signing` in the config)"
+                .to_owned()
+                .into(),
+        );
+    }
+    let payload =
+        fs::read(&file).map_err(|e| format!("failed to read `{}`: {:?}", file.display(), e))?;
+    let sealed_consensus_key = fs::read(config.sealed_consensus_key_path.clone())
+        .map_err(|e| format!("failed to read a sealed consensus key: {:?}", e))?;
+    let credentials = credential::CredentialsSource::new(config).credentials()?;
+    let response: NitroSignPayloadResult = enclave_request(
+        config,
+        cid,
+        &NitroRequest::SignPayload(NitroSignPayloadConfig {
+            credentials,
+            aws_region: config.aws_region.clone(),
+            kms_failover_regions: config.kms_failover_regions(),
+            sealed_consensus_key,
+            consensus_key_derivation: config.derivation_path.clone(),
+            payload: payload.clone(),
+        }),
+    )?;
+    let response = response?;
+    let public_key = VerificationKey::try_from(response.public_key.as_slice())
+        .map_err(|e| format!("invalid pubkey: {:?}", e))?;
+    let signature = Signature::try_from(response.signature.as_slice())
+        .map_err(|e| format!("invalid signature: {:?}", e))?;
+    if !verify_proof_of_possession(&public_key, &payload, &signature) {
+        return Err("the enclave's signature doesn't verify".to_owned().into());
+    }
+    println!(
+        "public key: {}",
+        String::from_utf8_lossy(&subtle_encoding::base64::encode(public_key))
+    );
+    println!(
+        "signature: {}",
+        String::from_utf8_lossy(&subtle_encoding::base64::encode(signature.to_bytes()))
+    );
+    Ok(())
+}
@@ -0,0 +1,199 @@
+use ed25519_consensus::{Signature, VerificationKey};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tendermint::block::{self, Height, Round};
+use tendermint::proposal::{Proposal, Type};
+use tendermint::{chain, vote::Vote, Time};
+
+/// amino prefix of the bech32-encoded ed25519 consensus pubkeys (as printed by `print_pubkey`)
+const AMINO_ED25519_PREFIX: [u8; 5] = [0x16, 0x24, 0xDE, 0x64, 0x20];
+
+/// what was signed
+#[derive(Debug)]
+pub enum SignedMessage {
+    /// a file with the raw sign-bytes
+    SignBytes(PathBuf),
+    /// a JSON-encoded vote
+    Vote(PathBuf),
+    /// a JSON-encoded proposal
+    Proposal(PathBuf),
+}
+
+/// parses a consensus pubkey (base64 or bech32 with the amino prefix)
+pub fn parse_pubkey(pubkey: &str) -> Result<VerificationKey, String> {
+    let raw = match subtle_encoding::bech32::decode(pubkey) {
+        Ok((_, data)) => data
+            .strip_prefix(&AMINO_ED25519_PREFIX[..])
+            .ok_or_else(|| "the bech32 pubkey isn't an ed25519 consensus key".to_owned())?
+            .to_vec(),
+        Err(_) => subtle_encoding::base64::decode(pubkey)
+            .map_err(|e| format!("invalid pubkey (neither bech32 nor base64): {:?}", e))?,
+    };
+    VerificationKey::try_from(raw.as_slice()).map_err(|e| format!("invalid pubkey: {:?}", e))
+}
+
+/// a JSON-encoded proposal (the same fields as the JSON-encoded votes;
+/// `tendermint::Proposal` can't be deserialized)
+#[derive(Debug, Deserialize)]
+struct ProposalJson {
+    height: Height,
+    round: i32,
+    /// -1 if there's no proof-of-lock round
+    pol_round: i32,
+    block_id: Option<block::Id>,
+    timestamp: Option<Time>,
+    /// base64-encoded signature (empty if not signed)
+    #[serde(default)]
+    signature: String,
+}
+
+impl ProposalJson {
+    fn signature(&self) -> Result<Option<Vec<u8>>, String> {
+        if self.signature.is_empty() {
+            return Ok(None);
+        }
+        subtle_encoding::base64::decode(&self.signature)
+            .map(Some)
+            .map_err(|e| format!("invalid base64 signature: {:?}", e))
+    }
+
+    fn into_proposal(self) -> Result<Proposal, String> {
+        let round = |r: i32| Round::try_from(r).map_err(|e| format!("invalid round: {}", e));
+        Ok(Proposal {
+            msg_type: Type::Proposal,
+            height: self.height,
+            round: round(self.round)?,
+            pol_round: match self.pol_round {
+                -1 => None,
+                r => Some(round(r)?),
+            },
+            block_id: self.block_id,
+            timestamp: self.timestamp,
+            signature: None,
+        })
+    }
+}
+
+fn read_json(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("failed to read `{}`: {:?}", path.display(), e))
+}
+
+/// the canonical sign-bytes of the message (and the signature included in a vote or proposal)
+pub fn sign_bytes(
+    message: &SignedMessage,
+    chain_id: Option<chain::Id>,
+) -> Result<(Vec<u8>, Option<Vec<u8>>), String> {
+    let chain_id = || {
+        chain_id
+            .clone()
+            .ok_or_else(|| "`--chain-id` is required".to_owned())
+    };
+    match message {
+        SignedMessage::SignBytes(path) => fs::read(path)
+            .map(|bytes| (bytes, None))
+            .map_err(|e| format!("failed to read `{}`: {:?}", path.display(), e)),
+        SignedMessage::Vote(path) => {
+            let vote: Vote = serde_json::from_str(&read_json(path)?)
+                .map_err(|e| format!("invalid vote `{}`: {}", path.display(), e))?;
+            let bytes = vote
+                .to_signable_vec(chain_id()?)
+                .map_err(|e| format!("failed to encode the vote: {}", e))?;
+            Ok((bytes, vote.signature.map(|s| s.as_bytes().to_vec())))
+        }
+        SignedMessage::Proposal(path) => {
+            let json: ProposalJson = serde_json::from_str(&read_json(path)?)
+                .map_err(|e| format!("invalid proposal `{}`: {}", path.display(), e))?;
+            let signature = json.signature()?;
+            let bytes = json
+                .into_proposal()?
+                .to_signable_vec(chain_id()?)
+                .map_err(|e| format!("failed to encode the proposal: {}", e))?;
+            Ok((bytes, signature))
+        }
+    }
+}
+
+/// verifies the signature of the sign-bytes
+pub fn verify_signature(
+    pubkey: &VerificationKey,
+    sign_bytes: &[u8],
+    signature: &[u8],
+) -> Result<(), String> {
+    let signature =
+        Signature::try_from(signature).map_err(|e| format!("invalid signature: {:?}", e))?;
+    pubkey
+        .verify(&signature, sign_bytes)
+        .map_err(|_| "the signature doesn't verify for these sign-bytes and pubkey".to_owned())
+}
+
+/// verifies the (base64-encoded) signature of a message with the consensus pubkey
+/// and prints the canonical sign-bytes (hex-encoded), so they can be compared with the chain's
+pub fn verify_sig(
+    message: SignedMessage,
+    chain_id: Option<chain::Id>,
+    signature: Option<String>,
+    pubkey: String,
+) -> Result<(), String> {
+    let pubkey = parse_pubkey(&pubkey)?;
+    let (bytes, included) = sign_bytes(&message, chain_id)?;
+    println!(
+        "sign bytes: {}",
+        String::from_utf8_lossy(&subtle_encoding::hex::encode(&bytes))
+    );
+    let signature = match (signature, included) {
+        (Some(s), _) => subtle_encoding::base64::decode(s)
+            .map_err(|e| format!("invalid base64 signature: {:?}", e))?,
+        (None, Some(s)) => s,
+        (None, None) => return Err("`--signature` is required".to_owned()),
+    };
+    verify_signature(&pubkey, &bytes, &signature)?;
+    println!("signature OK");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_consensus::SigningKey;
+    use rand_core::OsRng;
+
+    #[test]
+    fn verifies_vote_signatures() {
+        let key = SigningKey::new(OsRng);
+        let public = key.verification_key();
+        let bech32 = subtle_encoding::bech32::encode(
+            "cosmosvalconspub",
+            [&AMINO_ED25519_PREFIX[..], public.as_bytes()].concat(),
+        );
+        let base64 = String::from_utf8(subtle_encoding::base64::encode(public)).unwrap();
+        assert_eq!(parse_pubkey(&bech32).unwrap(), public);
+        assert_eq!(parse_pubkey(&base64).unwrap(), public);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vote.json");
+        fs::write(
+            &path,
+            r#"{
+                "type": 2,
+                "height": "10",
+                "round": 0,
+                "block_id": null,
+                "timestamp": "2021-01-01T00:00:00Z",
+                "validator_address": "0000000000000000000000000000000000000000",
+                "validator_index": 0,
+                "signature": ""
+            }"#,
+        )
+        .unwrap();
+        let message = SignedMessage::Vote(path);
+        let chain_id: chain::Id = "test-chain".parse().unwrap();
+        assert!(sign_bytes(&message, None).is_err());
+        let (bytes, included) = sign_bytes(&message, Some(chain_id)).unwrap();
+        assert_eq!(included, None);
+        let signature = key.sign(&bytes).to_bytes();
+        assert!(verify_signature(&public, &bytes, &signature).is_ok());
+        let (other, _) = sign_bytes(&message, Some("other-chain".parse().unwrap())).unwrap();
+        assert!(verify_signature(&public, &other, &signature).is_err());
+    }
+}
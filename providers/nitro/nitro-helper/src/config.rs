@@ -1,10 +1,33 @@
-use crate::shared::AwsCredentials;
-use clap::Parser;
+use crate::admin::AdminConfig;
+use crate::alerting::AlertingConfig;
+use crate::approval::ApprovalConfig;
+use crate::attestation::ExpectedPcrs;
+use crate::attestation_archive::set_archive_dir;
+use crate::chain_rpc::ChainRpcCheckConfig;
+use crate::ha::HaConfig;
+use crate::lease::LeaseConfig;
+use crate::otlp::OtlpConfig;
+use crate::shared::{AwsCredentials, KmsReplica, ValidatorConn};
+use crate::state::StateRecoveryConfig;
+use crate::state_store::StateBackend;
+use crate::sts::AssumeRoleConfig;
+use crate::supervisor::SupervisorConfig;
+use clap::{Parser, ValueEnum};
 use serde::{Deserialize, Serialize};
 use std::fs;
+use std::net::SocketAddr;
 use std::{convert::TryFrom, path::PathBuf};
 use tendermint::chain;
 use tendermint_config::net;
+use tmkms_light::audit::SignedMsgKind;
+use tmkms_light::chain::state::Durability;
+use tmkms_light::connection::tls::TlsConfig;
+use tmkms_light::connection::{ConnectionMode, ConnectionTimeouts, ProtocolVersion, Transport};
+use tmkms_light::policy::SigningPolicy;
+use tmkms_nitro_helper::backoff::BackoffConfig;
+use tmkms_nitro_helper::channel::enable_dev_tcp;
+use tmkms_nitro_helper::control::set_control_key_path;
+use tmkms_nitro_helper::slip10::DerivationPath;
 
 /// nitro options for toml configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -18,30 +41,363 @@ pub struct NitroSignOpt {
     pub max_height: Option<tendermint::block::Height>,
     /// Path to a file containing a cryptographic key
     pub sealed_consensus_key_path: PathBuf,
+    /// If set, `sealed_consensus_key_path` holds a sealed master seed
+    /// and the consensus key is derived from it at this (SLIP-0010) path
+    pub derivation_path: Option<DerivationPath>,
     /// Path to our Ed25519 identity key (if applicable)
     pub sealed_id_key_path: Option<PathBuf>,
     /// Path to chain-specific `priv_validator_state.json` file
     pub state_file_path: PathBuf,
+    /// Where the host persists the state (`state_file_path` or the history in an SQLite database)
+    #[serde(default)]
+    pub state_backend: StateBackend,
     /// Vsock cid to push config to
     pub enclave_config_cid: u32,
     /// Vsock port to push config to
     pub enclave_config_port: u32,
     /// Vsock port to listen on for state synchronization
     pub enclave_state_port: u32,
+    /// Single vsock port all the enclave's channels (state, privval, audit etc.) connect to (if set);
+    /// their own ports are then only used as the channel IDs
+    pub enclave_mux_port: Option<u32>,
+    /// Address (`host:port`) of an external state store the state is also persisted to (if set)
+    pub remote_state_addr: Option<String>,
+    /// Vsock port relayed to the external state store
+    #[serde(default = "default_enclave_remote_state_port")]
+    pub enclave_remote_state_port: u32,
+    /// Whether `any` or `all` of the state sinks need to persist a state update before signing
+    #[serde(default)]
+    pub state_durability: Durability,
+    /// Dry-run mode: the signing requests are checked (including the double signing prevention
+    /// against the in-memory state), but refused instead of signed, and the state is never persisted
+    /// (e.g. for a shadow deployment validating the config and the connectivity before a cutover)
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Debug: the canonical sign-bytes of the refused signing requests are appended
+    /// to the audit log (`audit_log_path` needs to be set)
+    #[serde(default)]
+    pub dump_refused_sign_bytes: bool,
+    /// Refuse to start from a fresh state (at height 0) if the state source has none,
+    /// even on the first start (the state needs to be provisioned, or `--force-fresh-state` used);
+    /// after the host had a state, a missing one is always refused without `--force-fresh-state`
+    #[serde(default)]
+    pub require_existing_state: bool,
+    /// Allow starting from a fresh state if the previous one is missing
+    /// (only set by `--force-fresh-state`)
+    #[serde(skip)]
+    pub force_fresh_state: bool,
+    /// Rebuild a corrupted state (that can't be parsed) at startup instead of refusing to start
+    /// (the corrupted state file is quarantined either way)
+    pub state_recovery: Option<StateRecoveryConfig>,
+    /// Query a node's RPC at startup for the last height committed with the validator's signature,
+    /// and refuse to sign at or below it (an independent backstop to the persisted state)
+    pub chain_rpc_check: Option<ChainRpcCheckConfig>,
+    /// Key file shared by the signers and the watermark, lease and monotonic state services
+    /// to authenticate their messages (required with `ha`, `lease`, `watermark_addr` or `monotonic_addr`)
+    pub peer_key_path: Option<PathBuf>,
+    /// Address (`host:port`) of the monotonic state service (if set, a rolled back
+    /// state file is detected and superseded at startup)
+    pub monotonic_addr: Option<String>,
+    /// Vsock port relayed to the monotonic state service
+    #[serde(default = "default_enclave_monotonic_port")]
+    pub enclave_monotonic_port: u32,
+    /// Address (`host:port`) of the watermark service shared by redundant signers (if set)
+    pub watermark_addr: Option<String>,
+    /// Vsock port relayed to the watermark service
+    #[serde(default = "default_enclave_watermark_port")]
+    pub enclave_watermark_port: u32,
+    /// Name of this signer instance (required with `watermark_addr`)
+    pub replica_id: Option<String>,
+    /// Leader election between redundant signers (if set, only the leader signs)
+    pub ha: Option<HaConfig>,
+    /// Lease the signer must hold to sign (if set)
+    pub lease: Option<LeaseConfig>,
+    /// Approval service of the signatures required by the signing policy's `approval` rule (if set)
+    pub approval: Option<ApprovalConfig>,
+    /// Relaunch the enclave (run by `enclave start`) if it exits or hangs (if set)
+    pub supervisor: Option<SupervisorConfig>,
+    /// Admin control socket to pause, resume or drain the signing (if set)
+    pub admin: Option<AdminConfig>,
+    /// Webhooks to post the alerts to (if set)
+    pub alerting: Option<AlertingConfig>,
+    /// OTLP/HTTP collector to export the helper's and the enclave's spans to (if set)
+    pub otlp: Option<OtlpConfig>,
+    /// Vsock port to listen on for the enclave's spans
+    #[serde(default = "default_enclave_trace_port")]
+    pub enclave_trace_port: u32,
     /// Vsock port to forward privval plain traffic to TM over UDS (or just pass to enclave if TCP/secret connection)
     pub enclave_tendermint_conn: u32,
+    /// Validators of the same chain (e.g. sentry-failover nodes) the enclave fails over to, in order,
+    /// when it can't connect to `address`
+    #[serde(default)]
+    pub failover_validators: Vec<FailoverValidator>,
+    /// Message types that can be signed over the `address` connection
+    /// (the signing policy's ones if not set)
+    pub validator_msg_types: Option<Vec<SignedMsgKind>>,
+    /// File the relayed privval messages are appended to, for `helper replay` (not recorded if unset;
+    /// only plain connections, e.g. over Unix domain sockets, can be recorded)
+    pub privval_capture_path: Option<PathBuf>,
     /// AWS credentials -- if not set, they'll be obtained from IAM
     pub credentials: Option<AwsCredentials>,
+    /// How often the credentials obtained from IAM are renewed in the running enclave
+    /// (0 disables it; not applicable to the `credentials` set in the config,
+    /// unless they are used to assume the `assume_role`)
+    #[serde(default = "default_credentials_refresh_secs")]
+    pub credentials_refresh_secs: u64,
+    /// Role to assume for the KMS access (if set), so that only its (short-lived)
+    /// credentials are pushed to the enclave
+    pub assume_role: Option<AssumeRoleConfig>,
+    /// Forward the enclave's AWS KMS traffic with the helper's built-in proxy
+    /// (instead of an external `vsock-proxy`)
+    #[serde(default)]
+    pub builtin_kms_proxy: bool,
+    /// Local development mode: the enclave binary runs on the host with `--dev-plaintext`,
+    /// the channels are TCP connections on the loopback interface and the key files
+    /// are NOT sealed (AWS KMS and the NSM aren't used)
+    #[serde(default)]
+    pub dev_plaintext: bool,
+    /// File with the key authenticating the helper's requests to the enclave
+    /// (bound to the enclave before its config is pushed); if not set, any process
+    /// with access to the enclave's vsock port can send it requests
+    #[serde(default)]
+    pub control_key_path: Option<PathBuf>,
+    /// Directory where the received attestation documents are archived
+    /// (exported with `attestation export`); not archived if not set
+    #[serde(default)]
+    pub attestation_archive_dir: Option<PathBuf>,
     /// AWS region
     pub aws_region: String,
+    /// AWS KMS key that `helper init` seals the identity key with
+    /// (if different from the consensus key's, e.g. with a more permissive key policy)
+    pub id_kms_key_id: Option<String>,
+    /// Replicas of the multi-Region KMS key in other regions, tried in order
+    /// if the `aws_region` endpoint fails (requires `builtin_kms_proxy`)
+    #[serde(default)]
+    pub kms_replicas: Vec<KmsReplica>,
+    /// Allow `key sign-payload` to sign arbitrary (domain-separated) payloads with the consensus key
+    /// (e.g. proofs of possession for a chain registration)
+    #[serde(default)]
+    pub allow_payload_signing: bool,
+    /// Address to serve the `/healthz`, `/readyz` and `/status` endpoints on (if set)
+    pub health_listen_addr: Option<SocketAddr>,
+    /// Serve the enclave's request metrics on `/metrics` of the health endpoint
+    #[serde(default)]
+    pub metrics: bool,
+    /// Vsock port to listen on for the metrics events
+    #[serde(default = "default_enclave_metrics_port")]
+    pub enclave_metrics_port: u32,
+    /// Interval of the enclave's runtime attestations of the consensus key (if set)
+    pub attestation_interval_secs: Option<u64>,
+    /// Path to write the latest runtime attestation (base64-encoded) to
+    pub attestation_path: Option<PathBuf>,
+    /// Vsock port to listen on for the runtime attestations
+    #[serde(default = "default_enclave_attestation_port")]
+    pub enclave_attestation_port: u32,
+    /// Path to write the summary of the stop at `max_height` (with the final watermark) to
+    pub halt_report_path: Option<PathBuf>,
+    /// Vsock port to listen on for the stop at `max_height`
+    #[serde(default = "default_enclave_halt_port")]
+    pub enclave_halt_port: u32,
+    /// Interval of the synchronization of the enclave's clock from the host's (if set;
+    /// used by the time-based signing policies and the audit records' timestamps)
+    pub time_sync_interval_secs: Option<u64>,
+    /// Vsock port to listen on for the enclave's time synchronization requests
+    #[serde(default = "default_enclave_time_port")]
+    pub enclave_time_port: u32,
+    /// Path to the audit log of the produced signatures (if set)
+    pub audit_log_path: Option<PathBuf>,
+    /// Vsock port to listen on for the signature audit records
+    #[serde(default = "default_enclave_audit_port")]
+    pub enclave_audit_port: u32,
+    /// Rules that signing requests must comply with
+    #[serde(default)]
+    pub signing_policy: SigningPolicy,
+    /// Chain IDs the enclave may sign for (checked inside the enclave; any if empty)
+    #[serde(default)]
+    pub allowed_chain_ids: Vec<chain::Id>,
+    /// Backoff of the enclave's reconnections to the validator
+    #[serde(default)]
+    pub reconnect_backoff: BackoffConfig,
+    /// Secret connection protocol version (`v0.34`, `v0.33` or `legacy`)
+    #[serde(default)]
+    pub protocol_version: ProtocolVersion,
+    /// Encrypted transport of `tcp://` connections (`secret_connection` or `noise`)
+    #[serde(default)]
+    pub transport: Transport,
+    /// Base64-encoded Noise static key of the remote endpoint (if applicable)
+    pub noise_remote_key: Option<String>,
+    /// Mutual TLS settings (if `transport = "tls"`)
+    pub tls: Option<TlsConfig>,
+    /// Refuse the validator connection if its peer ID can't be verified
+    /// (i.e. `peer_id` is required in `tcp://` addresses)
+    #[serde(default)]
+    pub require_peer_id: bool,
+    /// Read/write timeouts of the enclave's validator and state connections
+    #[serde(default)]
+    pub timeouts: ConnectionTimeouts,
+    /// Whether to dial the validator or listen for its connection
+    /// (in the listen mode, the helper accepts it and relays it to the enclave)
+    #[serde(default)]
+    pub connection_mode: ConnectionMode,
+    /// Expected (hex-encoded) PCR0 of the enclave image; if any `expected_pcr*` is set,
+    /// the enclave's measurements are attested before the credentials and sealed keys are pushed to it
+    pub expected_pcr0: Option<String>,
+    /// Expected (hex-encoded) PCR1 (the enclave's kernel)
+    pub expected_pcr1: Option<String>,
+    /// Expected (hex-encoded) PCR2 (the enclave's application)
+    pub expected_pcr2: Option<String>,
+    /// Expected (base64-encoded) consensus public key of the enclave's startup attestation;
+    /// if not set, the attested key is only logged
+    pub expected_consensus_pubkey: Option<String>,
+}
+
+fn default_enclave_audit_port() -> u32 {
+    5556
+}
+
+fn default_enclave_metrics_port() -> u32 {
+    5561
+}
+
+fn default_credentials_refresh_secs() -> u64 {
+    300
+}
+
+fn default_enclave_attestation_port() -> u32 {
+    5562
+}
+
+fn default_enclave_halt_port() -> u32 {
+    5563
+}
+
+fn default_enclave_trace_port() -> u32 {
+    5564
+}
+
+fn default_enclave_time_port() -> u32 {
+    5565
+}
+
+fn default_enclave_remote_state_port() -> u32 {
+    5557
+}
+
+fn default_enclave_monotonic_port() -> u32 {
+    5560
+}
+
+fn default_enclave_watermark_port() -> u32 {
+    5558
+}
+
+/// a validator the enclave fails over to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FailoverValidator {
+    /// Address of the validator (of the same kind as the primary `address`)
+    pub address: net::Address,
+    /// Vsock port of its connection
+    pub enclave_tendermint_conn: u32,
+    /// Message types that can be signed over this connection (e.g. `["prevote", "precommit"]`
+    /// for a vote-only failover validator; the signing policy's ones if not set)
+    #[serde(default)]
+    pub allowed_msg_types: Option<Vec<SignedMsgKind>>,
 }
 
 impl NitroSignOpt {
     pub fn from_file(config_path: PathBuf) -> Result<Self, String> {
         let toml_string = std::fs::read_to_string(config_path)
             .map_err(|e| format!("toml config file failed to read: {:?}", e))?;
-        toml::from_str(&toml_string)
-            .map_err(|e| format!("toml config file failed to parse: {:?}", e))
+        let config: Self = toml::from_str(&toml_string)
+            .map_err(|e| format!("toml config file failed to parse: {:?}", e))?;
+        // all the commands talk to the enclave binary run on the host
+        if config.dev_plaintext {
+            enable_dev_tcp();
+        }
+        set_control_key_path(config.control_key_path.clone());
+        set_archive_dir(config.attestation_archive_dir.clone());
+        Ok(config)
+    }
+
+    /// the regions of the KMS key replicas (in the failover order)
+    pub fn kms_failover_regions(&self) -> Vec<String> {
+        self.kms_replicas
+            .iter()
+            .map(|replica| replica.aws_region.clone())
+            .collect()
+    }
+
+    /// the failover validator connections (checked against the primary one)
+    pub fn failover_conns(&self) -> Result<Vec<ValidatorConn>, String> {
+        if !self.failover_validators.is_empty() && self.connection_mode == ConnectionMode::Listen {
+            return Err(
+                "`failover_validators` need `connection_mode = \"dial\"` (listening validators can all dial `address`)"
+                    .to_owned(),
+            );
+        }
+        let mut ports = vec![self.enclave_tendermint_conn];
+        let mut conns = Vec::with_capacity(self.failover_validators.len());
+        for validator in self.failover_validators.iter() {
+            if ports.contains(&validator.enclave_tendermint_conn) {
+                return Err(format!(
+                    "vsock port {} of {} is already used by another validator",
+                    validator.enclave_tendermint_conn, validator.address
+                ));
+            }
+            ports.push(validator.enclave_tendermint_conn);
+            let peer_id = match (&self.address, &validator.address) {
+                (net::Address::Tcp { .. }, net::Address::Tcp { peer_id, .. }) => {
+                    if peer_id.is_none() && self.require_peer_id {
+                        return Err(format!(
+                            "`require_peer_id` is set, but {} has no peer ID",
+                            validator.address
+                        ));
+                    }
+                    *peer_id
+                }
+                (net::Address::Unix { .. }, net::Address::Unix { .. }) => None,
+                _ => {
+                    return Err(format!(
+                        "failover validator {} isn't of the same kind as `address`",
+                        validator.address
+                    ))
+                }
+            };
+            conns.push(ValidatorConn {
+                enclave_tendermint_conn: validator.enclave_tendermint_conn,
+                peer_id,
+                allowed_msg_types: validator.allowed_msg_types.clone(),
+            });
+        }
+        Ok(conns)
+    }
+
+    /// the pinned enclave measurements
+    pub fn expected_pcrs(&self) -> ExpectedPcrs {
+        ExpectedPcrs([
+            self.expected_pcr0.clone(),
+            self.expected_pcr1.clone(),
+            self.expected_pcr2.clone(),
+        ])
+    }
+
+    /// the pinned enclave measurements the attestations are checked against
+    /// (before the credentials or sealed keys are sent to it): they are required,
+    /// except in the development mode, whose attestations are unsigned and can't be pinned
+    pub fn required_pcrs(&self) -> Result<ExpectedPcrs, String> {
+        let expected = self.expected_pcrs();
+        match (self.dev_plaintext, expected.is_empty()) {
+            (false, true) => Err(
+                "no `expected_pcr0`, `expected_pcr1` or `expected_pcr2` is set (the enclave's measurements need to be pinned)"
+                    .to_owned(),
+            ),
+            (true, false) => Err(
+                "the PCRs can't be pinned in the development mode (its attestations are unsigned)"
+                    .to_owned(),
+            ),
+            _ => Ok(expected),
+        }
     }
 }
 
@@ -62,6 +418,14 @@ pub struct VSockProxyOpt {
     /// "YAML file containing the services that can be forwarded.\n"
     #[arg(long, default_value = "/etc/nitro_enclaves/vsock-proxy.yaml")]
     pub config_file: String,
+    /// Run the helper's built-in proxy instead of the external `vsock-proxy`
+    #[arg(long)]
+    #[serde(default)]
+    pub builtin: bool,
+    /// Endpoints (`host:port`) the built-in proxy may forward to, besides the AWS KMS endpoints
+    #[arg(long = "allowed-endpoint")]
+    #[serde(default)]
+    pub allowed_endpoints: Vec<String>,
 }
 
 impl Default for VSockProxyOpt {
@@ -72,10 +436,63 @@ impl Default for VSockProxyOpt {
             remote_port: 443,
             remote_addr: "kms.ap-southeast-1.amazonaws.com".to_string(),
             config_file: "/etc/nitro_enclaves/vsock-proxy.yaml".to_string(),
+            builtin: false,
+            allowed_endpoints: vec![],
         }
     }
 }
 
+/// log output format
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// human-readable lines
+    #[default]
+    Text,
+    /// structured JSON lines (e.g. for Loki or CloudWatch)
+    Json,
+}
+
+fn parse_kms_replica(replica: &str) -> Result<KmsReplica, String> {
+    match replica.split_once('=') {
+        Some((aws_region, kms_key_id)) if !aws_region.is_empty() && !kms_key_id.is_empty() => {
+            Ok(KmsReplica {
+                aws_region: aws_region.to_owned(),
+                kms_key_id: kms_key_id.to_owned(),
+            })
+        }
+        _ => Err("expected `<region>=<key ARN>`".to_owned()),
+    }
+}
+
+/// AWS KMS keys that seal the generated keys
+#[derive(Parser, Clone, Debug)]
+pub struct KmsKeyOpt {
+    /// AWS region
+    #[arg(short)]
+    pub aws_region: String,
+    /// AWS KMS key of the consensus key (and the identity key, unless `--id-kms-key-id` is set)
+    #[arg(short)]
+    pub kms_key_id: String,
+    /// AWS KMS key of the identity key
+    #[arg(long)]
+    pub id_kms_key_id: Option<String>,
+    /// replica of the multi-Region key to fail over to (`<region>=<key ARN>`, can be repeated)
+    #[arg(long = "kms-replica", value_parser = parse_kms_replica)]
+    pub kms_replicas: Vec<KmsReplica>,
+}
+
+/// options for connecting to the enclave to control its chain sessions
+#[derive(Parser, Clone, Debug)]
+pub struct ChainControlOpt {
+    /// tmkms config path
+    #[arg(short, default_value = "tmkms.toml")]
+    pub config_path: PathBuf,
+    /// the enclave cid (if different from the config one)
+    #[arg(long)]
+    pub cid: Option<u32>,
+}
+
 #[derive(Parser, Clone, Serialize, Deserialize, Debug)]
 pub struct EnclaveOpt {
     /// The path to the enclave image file
@@ -93,6 +510,10 @@ pub struct EnclaveOpt {
     /// Set the enclave log server port
     #[arg(long, default_value = "6050")]
     pub log_server_port: u32,
+    /// Format of the helper logs (including the forwarded enclave logs)
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    #[serde(default)]
+    pub log_format: LogFormat,
 }
 
 impl Default for EnclaveOpt {
@@ -103,6 +524,7 @@ impl Default for EnclaveOpt {
             memory_mib: 512,
             cpu_count: 2,
             log_server_port: 6050,
+            log_format: LogFormat::Text,
         }
     }
 }
@@ -116,14 +538,78 @@ impl Default for NitroSignOpt {
             chain_id: chain::Id::try_from("testchain-1".to_owned()).expect("valid chain-id"),
             max_height: None,
             sealed_consensus_key_path: "secrets/secret.key".into(),
+            derivation_path: None,
             sealed_id_key_path: Some("secrets/id.key".into()),
             state_file_path: "state/priv_validator_state.json".into(),
+            state_backend: StateBackend::JsonFile,
             enclave_config_cid: 15,
             enclave_config_port: 5050,
             enclave_state_port: 5555,
+            enclave_mux_port: None,
+            remote_state_addr: None,
+            enclave_remote_state_port: default_enclave_remote_state_port(),
+            state_durability: Durability::All,
+            dry_run: false,
+            dump_refused_sign_bytes: false,
+            require_existing_state: false,
+            force_fresh_state: false,
+            state_recovery: None,
+            chain_rpc_check: None,
+            peer_key_path: None,
+            monotonic_addr: None,
+            enclave_monotonic_port: default_enclave_monotonic_port(),
+            watermark_addr: None,
+            enclave_watermark_port: default_enclave_watermark_port(),
+            replica_id: None,
+            ha: None,
+            lease: None,
+            approval: None,
+            supervisor: None,
+            admin: None,
+            alerting: None,
+            otlp: None,
+            enclave_trace_port: default_enclave_trace_port(),
             enclave_tendermint_conn: 5000,
+            failover_validators: vec![],
+            validator_msg_types: None,
+            privval_capture_path: None,
             credentials: None,
+            credentials_refresh_secs: default_credentials_refresh_secs(),
+            assume_role: None,
+            builtin_kms_proxy: false,
+            dev_plaintext: false,
+            control_key_path: None,
+            attestation_archive_dir: None,
             aws_region: "ap-southeast-1".to_owned(),
+            id_kms_key_id: None,
+            kms_replicas: vec![],
+            allow_payload_signing: false,
+            health_listen_addr: None,
+            metrics: false,
+            enclave_metrics_port: default_enclave_metrics_port(),
+            attestation_interval_secs: None,
+            attestation_path: None,
+            enclave_attestation_port: default_enclave_attestation_port(),
+            halt_report_path: None,
+            enclave_halt_port: default_enclave_halt_port(),
+            time_sync_interval_secs: None,
+            enclave_time_port: default_enclave_time_port(),
+            audit_log_path: None,
+            enclave_audit_port: default_enclave_audit_port(),
+            signing_policy: SigningPolicy::default(),
+            allowed_chain_ids: vec![],
+            reconnect_backoff: BackoffConfig::default(),
+            connection_mode: ConnectionMode::Dial,
+            timeouts: ConnectionTimeouts::default(),
+            protocol_version: ProtocolVersion::V0_34,
+            require_peer_id: false,
+            transport: Transport::SecretConnection,
+            noise_remote_key: None,
+            tls: None,
+            expected_pcr0: None,
+            expected_pcr1: None,
+            expected_pcr2: None,
+            expected_consensus_pubkey: None,
         }
     }
 }
@@ -146,3 +632,39 @@ impl EnclaveConfig {
             .map_err(|e| format!("toml config file failed to parse: {:?}", e))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_failover_validators() {
+        let mut config = NitroSignOpt {
+            address: "tcp://a5ec2fd7e85fdd3ab1a4bea9a1fa7d1e1aff1f07@10.0.0.1:26658"
+                .parse()
+                .unwrap(),
+            failover_validators: vec![FailoverValidator {
+                address: "tcp://10.0.0.2:26658".parse().unwrap(),
+                enclave_tendermint_conn: 5001,
+                allowed_msg_types: Some(vec![SignedMsgKind::Prevote, SignedMsgKind::Precommit]),
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            config.failover_conns().unwrap(),
+            vec![ValidatorConn {
+                enclave_tendermint_conn: 5001,
+                peer_id: None,
+                allowed_msg_types: Some(vec![SignedMsgKind::Prevote, SignedMsgKind::Precommit]),
+            }]
+        );
+        config.require_peer_id = true;
+        assert!(config.failover_conns().is_err());
+        config.require_peer_id = false;
+        config.failover_validators[0].enclave_tendermint_conn = 5000;
+        assert!(config.failover_conns().is_err());
+        config.failover_validators[0].address = "unix:///tmp/sentry.socket".parse().unwrap();
+        config.failover_validators[0].enclave_tendermint_conn = 5001;
+        assert!(config.failover_conns().is_err());
+    }
+}
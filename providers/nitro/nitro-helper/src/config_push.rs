@@ -0,0 +1,120 @@
+//! Encrypted push of the start config (with the AWS credentials): the helper encrypts it
+//! to a one-time X25519 key the enclave attested to (after checking the attested measurements),
+//! so only an enclave with the expected measurements can read it.
+
+use crate::key_shares::{x25519_public_key, x25519_scalar};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use hkdf::Hkdf;
+use rand_core::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use zeroize::{Zeroize, Zeroizing};
+
+/// domain separation for deriving the config encryption key
+const CONFIG_KDF_INFO: &[u8] = b"tmkms-light config push v1";
+
+/// the start config encrypted to the enclave's attested X25519 key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedConfig {
+    /// X25519 public key of the enclave (from its attestation document)
+    pub recipient: [u8; 32],
+    /// ephemeral X25519 public key of the helper
+    pub ephemeral_public_key: [u8; 32],
+    /// ChaCha20Poly1305 nonce
+    pub nonce: [u8; 12],
+    /// the encrypted config
+    #[serde(with = "serde_bytes")]
+    pub ciphertext: Vec<u8>,
+}
+
+/// derives the config encryption key from the X25519 shared secret
+fn config_key(shared_secret: &MontgomeryPoint, ephemeral: &[u8; 32], recipient: &[u8; 32]) -> Key {
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(ephemeral);
+    salt[32..].copy_from_slice(recipient);
+    let hk = Hkdf::<Sha256>::new(Some(&salt), shared_secret.as_bytes());
+    let mut okm = Zeroizing::new([0u8; 32]);
+    hk.expand(CONFIG_KDF_INFO, &mut okm[..])
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    *Key::from_slice(&okm[..])
+}
+
+/// encrypts the serialized config to the enclave's X25519 key
+pub fn seal_config<R: RngCore + CryptoRng>(
+    csprng: &mut R,
+    config: &[u8],
+    recipient: &[u8; 32],
+) -> Result<SealedConfig, String> {
+    let mut ephemeral_secret = [0u8; 32];
+    csprng.fill_bytes(&mut ephemeral_secret);
+    let ephemeral_public_key = x25519_public_key(&ephemeral_secret);
+    let shared_secret = MontgomeryPoint(*recipient) * x25519_scalar(ephemeral_secret);
+    ephemeral_secret.zeroize();
+    let key = config_key(&shared_secret, &ephemeral_public_key, recipient);
+    let mut nonce = [0u8; 12];
+    csprng.fill_bytes(&mut nonce);
+    let ciphertext = ChaCha20Poly1305::new(&key)
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: config,
+                aad: recipient,
+            },
+        )
+        .map_err(|_| "failed to encrypt the config".to_owned())?;
+    Ok(SealedConfig {
+        recipient: *recipient,
+        ephemeral_public_key,
+        nonce,
+        ciphertext,
+    })
+}
+
+/// decrypts the serialized config with the enclave's X25519 secret
+pub fn open_config(
+    sealed: &SealedConfig,
+    recipient_secret: &[u8; 32],
+) -> Result<Zeroizing<Vec<u8>>, String> {
+    if x25519_public_key(recipient_secret) != sealed.recipient {
+        return Err("the config isn't encrypted to the enclave's key".to_owned());
+    }
+    let shared_secret =
+        MontgomeryPoint(sealed.ephemeral_public_key) * x25519_scalar(*recipient_secret);
+    let key = config_key(
+        &shared_secret,
+        &sealed.ephemeral_public_key,
+        &sealed.recipient,
+    );
+    ChaCha20Poly1305::new(&key)
+        .decrypt(
+            Nonce::from_slice(&sealed.nonce),
+            Payload {
+                msg: &sealed.ciphertext,
+                aad: &sealed.recipient,
+            },
+        )
+        .map(Zeroizing::new)
+        .map_err(|_| "failed to decrypt the config".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_core::OsRng;
+
+    #[test]
+    fn config_round_trip() {
+        let secret = [7u8; 32];
+        let recipient = x25519_public_key(&secret);
+        let mut sealed = seal_config(&mut OsRng, b"{\"chain_id\":\"a\"}", &recipient).unwrap();
+        assert_eq!(
+            open_config(&sealed, &secret).unwrap().as_slice(),
+            b"{\"chain_id\":\"a\"}"
+        );
+        assert!(open_config(&sealed, &[8u8; 32]).is_err());
+        sealed.ciphertext[0] ^= 1;
+        assert!(open_config(&sealed, &secret).is_err());
+    }
+}
@@ -0,0 +1,326 @@
+//! Authentication of the helper's requests to the enclave: once the helper bound a control key
+//! (encrypted to a one-time key the enclave attested to), the enclave only accepts the requests
+//! with a MAC by that key and a fresh sequence number, so another process with vsock access
+//! can't inject (or replay) `Start`, `Keygen` or control requests.
+//! Every connection starts with the negotiation of the protocol version, so that mismatched
+//! helper and enclave versions fail with a clear error.
+
+use crate::codec;
+use crate::shared::{
+    NitroError, NitroErrorCode, NitroHello, NitroHelloResult, NitroRequest, PROTOCOL_VERSION,
+};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tmkms_light::utils::{read_u16_payload, write_u16_payload};
+use zeroize::Zeroizing;
+
+const MAC_DOMAIN: &[u8] = b"tmkms-nitro-control";
+
+/// length of the control key (bytes)
+pub const CONTROL_KEY_LEN: usize = 32;
+
+/// the sequence numbers are accepted this far (in nanoseconds) behind the highest one
+/// (the helper's processes send their requests concurrently)
+const SEQ_WINDOW: u64 = 60_000_000_000;
+
+/// a request with the control key's MAC
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthenticatedRequest {
+    /// nanoseconds since the Unix epoch when it was sent (unique per request)
+    pub seq: u64,
+    /// the encoded `NitroRequest`
+    #[serde(with = "serde_bytes")]
+    pub request: Vec<u8>,
+    /// HMAC-SHA256 of the sequence number and the request with the control key
+    #[serde(with = "serde_bytes")]
+    pub mac: Vec<u8>,
+}
+
+fn request_mac(key: &[u8], seq: u64, request: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(MAC_DOMAIN);
+    mac.update(&seq.to_be_bytes());
+    mac.update(request);
+    mac
+}
+
+impl AuthenticatedRequest {
+    pub fn new(key: &[u8], seq: u64, request: Vec<u8>) -> Self {
+        let mac = request_mac(key, seq, &request)
+            .finalize()
+            .into_bytes()
+            .to_vec();
+        Self { seq, request, mac }
+    }
+
+    pub fn verify(&self, key: &[u8]) -> Result<(), String> {
+        request_mac(key, self.seq, &self.request)
+            .verify_slice(&self.mac)
+            .map_err(|_| "invalid control MAC".to_owned())
+    }
+}
+
+/// where the helper keeps the control key (if the control channel is authenticated)
+static CONTROL_KEY_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// the last sequence number sent by this process
+static LAST_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// sets the control key file of the helper's requests
+pub fn set_control_key_path(path: Option<PathBuf>) {
+    *CONTROL_KEY_PATH.lock().unwrap_or_else(|e| e.into_inner()) = path;
+}
+
+/// the control key file (if set)
+pub fn control_key_path() -> Option<PathBuf> {
+    CONTROL_KEY_PATH
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+/// reads the (base64-encoded) control key
+pub fn read_control_key(path: &Path) -> Result<Zeroizing<Vec<u8>>, String> {
+    let encoded = Zeroizing::new(
+        std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read `{}`: {:?}", path.display(), e))?,
+    );
+    subtle_encoding::base64::decode(encoded.trim())
+        .map(Zeroizing::new)
+        .map_err(|e| format!("invalid control key `{}`: {}", path.display(), e))
+}
+
+/// the next sequence number: the current time, but always above the previous one
+fn next_seq() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+    let previous = LAST_SEQ
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+            Some(now.max(last + 1))
+        })
+        .unwrap_or_default();
+    now.max(previous + 1)
+}
+
+/// encodes the request (with the control key's MAC if the helper has one)
+pub fn encode_request(request: &NitroRequest) -> Result<Vec<u8>, String> {
+    let request_raw =
+        codec::encode(request).map_err(|e| format!("failed to encode the request: {:?}", e))?;
+    let path = match control_key_path() {
+        Some(path) if path.exists() => path,
+        _ => return Ok(request_raw),
+    };
+    let key = read_control_key(&path)?;
+    let authenticated = AuthenticatedRequest::new(&key, next_seq(), request_raw);
+    codec::encode(&NitroRequest::Authenticated(authenticated))
+        .map_err(|e| format!("failed to encode the request: {:?}", e))
+}
+
+/// exchanges the protocol version and capabilities with the enclave (before the connection's requests)
+pub fn negotiate<S: Read + Write>(stream: &mut S) -> Result<NitroHello, String> {
+    let request_raw = encode_request(&NitroRequest::Hello(NitroHello::current()))?;
+    write_u16_payload(stream, &request_raw)
+        .map_err(|e| format!("failed to write the protocol negotiation: {:?}", e))?;
+    let response_raw = read_u16_payload(stream)
+        .map_err(|e| format!("failed to read the protocol negotiation: {:?}", e))?;
+    let response: NitroHelloResult = match codec::decode(&response_raw) {
+        Ok(response) => response,
+        Err(_) if codec::is_legacy_json(&response_raw) => {
+            return Err(
+                "the enclave uses the JSON encoding of the older releases (its EIF is older than the helper)"
+                    .to_owned(),
+            )
+        }
+        Err(e) => {
+            return Err(format!(
+                "invalid protocol negotiation (mismatched helper and enclave versions?): {}",
+                e
+            ))
+        }
+    };
+    match response {
+        Ok(hello) if hello.version == PROTOCOL_VERSION => Ok(hello),
+        Ok(hello) => Err(format!(
+            "the enclave speaks the protocol version {}, but the helper {}: upgrade the older one",
+            hello.version, PROTOCOL_VERSION
+        )),
+        Err(e) => Err(format!("protocol negotiation failed: {}", e)),
+    }
+}
+
+fn unauthenticated(message: impl Into<String>) -> NitroError {
+    NitroError::new(NitroErrorCode::Unauthenticated, message)
+}
+
+/// the enclave's check of the requests (after the control key was bound)
+#[derive(Default)]
+pub struct ControlGuard {
+    key: Option<Zeroizing<Vec<u8>>>,
+    /// the sequence numbers seen within the window
+    seen: BTreeSet<u64>,
+}
+
+impl ControlGuard {
+    /// the control key can only be bound once (per enclave run)
+    pub fn bind(&mut self, key: Zeroizing<Vec<u8>>) -> Result<(), NitroError> {
+        if self.key.is_some() {
+            return Err(NitroError::new(
+                NitroErrorCode::AlreadyBound,
+                "the control key is already bound",
+            ));
+        }
+        if key.len() != CONTROL_KEY_LEN {
+            return Err(NitroError::new(
+                NitroErrorCode::InvalidRequest,
+                "invalid control key length",
+            ));
+        }
+        self.key = Some(key);
+        Ok(())
+    }
+
+    pub fn is_bound(&self) -> bool {
+        self.key.is_some()
+    }
+
+    /// the (unwrapped) request if it's authentic and fresh; before a control key is bound,
+    /// the requests are accepted as they are
+    pub fn authorize(&mut self, request: NitroRequest) -> Result<NitroRequest, NitroError> {
+        let authenticated = match (&self.key, request) {
+            (_, NitroRequest::Authenticated(authenticated)) => authenticated,
+            (None, request) => return Ok(request),
+            (Some(_), _) => return Err(unauthenticated("the request isn't authenticated")),
+        };
+        if let Some(key) = &self.key {
+            authenticated.verify(key).map_err(unauthenticated)?;
+            let highest = self.seen.iter().next_back().copied().unwrap_or_default();
+            if authenticated.seq <= highest.saturating_sub(SEQ_WINDOW)
+                || !self.seen.insert(authenticated.seq)
+            {
+                return Err(unauthenticated("replayed or stale request"));
+            }
+            let oldest = authenticated.seq.max(highest).saturating_sub(SEQ_WINDOW);
+            self.seen = self.seen.split_off(&oldest);
+        }
+        match codec::decode(&authenticated.request) {
+            Ok(NitroRequest::Authenticated(_)) => {
+                Err(unauthenticated("nested authenticated request"))
+            }
+            Ok(request) => Ok(request),
+            Err(e) => Err(NitroError::new(
+                NitroErrorCode::InvalidRequest,
+                format!("invalid request: {}", e),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// the enclave's (prepared) responses and the helper's requests
+    struct Conn {
+        responses: Cursor<Vec<u8>>,
+        requests: Vec<u8>,
+    }
+
+    impl Conn {
+        fn new<T: Serialize>(response: &T) -> Self {
+            Self::raw(&codec::encode(response).unwrap())
+        }
+
+        fn raw(response: &[u8]) -> Self {
+            let mut responses = Vec::new();
+            write_u16_payload(&mut responses, response).unwrap();
+            Self {
+                responses: Cursor::new(responses),
+                requests: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for Conn {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.responses.read(buf)
+        }
+    }
+
+    impl Write for Conn {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.requests.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn mismatched_versions_fail_the_negotiation() {
+        let current: NitroHelloResult = Ok(NitroHello::current());
+        assert_eq!(
+            negotiate(&mut Conn::new(&current)).unwrap(),
+            NitroHello::current()
+        );
+        let newer: NitroHelloResult = Ok(NitroHello {
+            version: PROTOCOL_VERSION + 1,
+            capabilities: vec![],
+        });
+        assert!(negotiate(&mut Conn::new(&newer))
+            .unwrap_err()
+            .contains("protocol version"));
+        // an older enclave doesn't know the request (nor the encoding)
+        let older: NitroHelloResult = Err(NitroError::new(
+            NitroErrorCode::InvalidRequest,
+            "invalid request: expected value at line 1 column 1",
+        ));
+        assert!(
+            negotiate(&mut Conn::raw(&serde_json::to_vec(&older).unwrap()))
+                .unwrap_err()
+                .contains("older than the helper")
+        );
+    }
+
+    fn authenticated(key: &[u8], seq: u64) -> NitroRequest {
+        NitroRequest::Authenticated(AuthenticatedRequest::new(
+            key,
+            seq,
+            codec::encode(&NitroRequest::ChainStatus).unwrap(),
+        ))
+    }
+
+    #[test]
+    fn only_fresh_authenticated_requests_are_accepted() {
+        let key = [1u8; 32];
+        let mut guard = ControlGuard::default();
+        assert!(guard.authorize(NitroRequest::ChainStatus).is_ok());
+        guard.bind(Zeroizing::new(key.to_vec())).unwrap();
+        assert!(guard.bind(Zeroizing::new(vec![2u8; 32])).is_err());
+
+        assert!(guard.authorize(NitroRequest::ChainStatus).is_err());
+        assert!(guard.authorize(authenticated(&[2u8; 32], 100)).is_err());
+        let seq = SEQ_WINDOW * 10;
+        assert!(matches!(
+            guard.authorize(authenticated(&key, seq)),
+            Ok(NitroRequest::ChainStatus)
+        ));
+        // replayed
+        assert!(guard.authorize(authenticated(&key, seq)).is_err());
+        // another process' request sent a bit earlier
+        assert!(guard.authorize(authenticated(&key, seq - 1)).is_ok());
+        assert!(guard
+            .authorize(authenticated(&key, seq - SEQ_WINDOW))
+            .is_err());
+    }
+}
@@ -0,0 +1,74 @@
+use crate::attestation::{sealed_request, ExpectedPcrs};
+use crate::key_utils::credential::CredentialsSource;
+use crate::shared::{AwsCredentials, NitroRefreshCredentialsResult, NitroRequest};
+use std::thread;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// polls the instance metadata service (IMDSv2) and pushes the renewed
+/// session credentials to the running enclave (they are renewed before they expire;
+/// the same goes for the credentials of the assumed role)
+pub struct CredentialRefresher {
+    cid: u32,
+    port: u32,
+    interval: Duration,
+    expected: ExpectedPcrs,
+    current: AwsCredentials,
+    source: CredentialsSource,
+}
+
+impl CredentialRefresher {
+    /// `current`: the credentials pushed with the enclave's configuration
+    /// (the renewed ones are only sent to an enclave with the `expected` measurements)
+    pub fn new(
+        cid: u32,
+        port: u32,
+        interval: Duration,
+        expected: ExpectedPcrs,
+        current: AwsCredentials,
+        source: CredentialsSource,
+    ) -> Self {
+        Self {
+            cid,
+            port,
+            interval,
+            expected,
+            current,
+            source,
+        }
+    }
+
+    fn push(&self, credentials: &AwsCredentials) -> Result<(), String> {
+        let response: NitroRefreshCredentialsResult = sealed_request(
+            self.cid,
+            self.port,
+            &self.expected,
+            &NitroRequest::RefreshCredentials(credentials.clone()),
+        )?;
+        response.map_err(|e| e.to_string())
+    }
+
+    /// refreshes the credentials in a separate thread
+    pub fn launch(mut self) {
+        thread::spawn(move || loop {
+            thread::sleep(self.interval);
+            let credentials = match self.source.credentials() {
+                Ok(credentials) => credentials,
+                Err(e) => {
+                    warn!("failed to get the AWS credentials: {}", e);
+                    continue;
+                }
+            };
+            if credentials.aws_session_token == self.current.aws_session_token {
+                continue;
+            }
+            match self.push(&credentials) {
+                Ok(()) => {
+                    info!("renewed AWS credentials pushed to the enclave");
+                    self.current = credentials;
+                }
+                Err(e) => warn!("failed to push the renewed AWS credentials: {}", e),
+            }
+        });
+    }
+}
@@ -0,0 +1,116 @@
+use crate::state_store::StateStore;
+use aws_sdk_dynamodb::{model::AttributeValue, Client, Region};
+use tmkms_light::chain::state::{MacedState, StateError};
+use tokio::runtime::{Builder, Runtime};
+use tracing::debug;
+
+/// only advances the stored state (or re-persists the same one)
+const ADVANCE_CONDITION: &str = "attribute_not_exists(id) \
+    OR height < :height \
+    OR (height = :height AND round < :round) \
+    OR (height = :height AND round = :round AND step < :step) \
+    OR (height = :height AND round = :round AND step = :step AND #state = :state)";
+
+/// the state in a DynamoDB table (with a string partition key `id`);
+/// each update is a conditional write, so an older state never replaces a more advanced one
+pub struct DynamoDbStore {
+    rt: Runtime,
+    client: Client,
+    table: String,
+    id: String,
+}
+
+impl DynamoDbStore {
+    /// the credentials and region (unless set) are from the environment
+    /// (e.g. the instance profile)
+    pub fn new(table: String, id: String, region: Option<String>) -> Result<Self, StateError> {
+        let rt = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| StateError::sync_error("tokio runtime".into(), e))?;
+        let mut loader = aws_config::from_env();
+        if let Some(region) = region {
+            loader = loader.region(Region::new(region));
+        }
+        let client = Client::new(&rt.block_on(loader.load()));
+        Ok(Self {
+            rt,
+            client,
+            table,
+            id,
+        })
+    }
+}
+
+impl StateStore for DynamoDbStore {
+    fn load(&mut self) -> Result<Option<MacedState>, StateError> {
+        let output = self
+            .rt
+            .block_on(
+                self.client
+                    .get_item()
+                    .table_name(&self.table)
+                    .key("id", AttributeValue::S(self.id.clone()))
+                    .consistent_read(true)
+                    .send(),
+            )
+            .map_err(|e| StateError::sync_other_error(format!("dynamodb: {}", e)))?;
+        match output.item().and_then(|item| item.get("state")) {
+            Some(AttributeValue::S(state_json)) => serde_json::from_str(state_json)
+                .map(Some)
+                .map_err(|e| StateError::sync_enc_dec_error(self.id.clone(), e)),
+            Some(_) => Err(StateError::sync_other_error(format!(
+                "invalid state attribute in {}",
+                self.table
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    fn persist(&mut self, new_state: &MacedState) -> Result<(), StateError> {
+        let state_json = serde_json::to_string(new_state)
+            .map_err(|e| StateError::sync_enc_dec_error(self.id.clone(), e))?;
+        let height = AttributeValue::N(new_state.state.height.to_string());
+        let round = AttributeValue::N(new_state.state.round.value().to_string());
+        let step = AttributeValue::N(new_state.state.step.to_string());
+        let state = AttributeValue::S(state_json);
+        let result = self.rt.block_on(
+            self.client
+                .put_item()
+                .table_name(&self.table)
+                .item("id", AttributeValue::S(self.id.clone()))
+                .item("height", height.clone())
+                .item("round", round.clone())
+                .item("step", step.clone())
+                .item("state", state.clone())
+                .condition_expression(ADVANCE_CONDITION)
+                .expression_attribute_names("#state", "state")
+                .expression_attribute_values(":height", height)
+                .expression_attribute_values(":round", round)
+                .expression_attribute_values(":step", step)
+                .expression_attribute_values(":state", state)
+                .send(),
+        );
+        match result {
+            Ok(_) => {
+                debug!("state written to {}", self.table);
+                Ok(())
+            }
+            Err(e) => {
+                let msg = e.to_string();
+                match e.into_service_error() {
+                    e if e.is_conditional_check_failed_exception() => {
+                        Err(StateError::sync_other_error(format!(
+                            "the state in {} is ahead of {}",
+                            self.table, new_state.state
+                        )))
+                    }
+                    e => Err(StateError::sync_other_error(format!(
+                        "dynamodb: {} ({:?})",
+                        msg, e
+                    ))),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! Measurements of an enclave image file (EIF): the PCR0 (enclave image), PCR1 (kernel and
+//! bootstrap) and PCR2 (application) of an enclave launched from it, computed as
+//! `nitro-cli build-enclave` does, so that an image can be checked against the pinned
+//! measurements before it's launched (or a KMS key policy is written for it)
+
+use sha2::{Digest, Sha384};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// the magic bytes of an EIF (`.eif`)
+const EIF_MAGIC: [u8; 4] = [0x2e, 0x65, 0x69, 0x66];
+/// the maximum number of sections in the header
+const MAX_SECTIONS: usize = 32;
+/// the (big-endian) header: magic, version, flags, default memory and CPUs, reserved,
+/// number of sections, section offsets and sizes, unused, CRC32
+const HEADER_LEN: usize = 548;
+/// the (big-endian) section header: type, flags, size
+const SECTION_HEADER_LEN: usize = 12;
+/// the sections are hashed in chunks of this size
+const CHUNK_LEN: usize = 1 << 20;
+
+/// a section of the image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SectionType {
+    Kernel,
+    Cmdline,
+    Ramdisk,
+    Signature,
+    Metadata,
+    Other(u16),
+}
+
+impl From<u16> for SectionType {
+    fn from(section_type: u16) -> Self {
+        match section_type {
+            1 => SectionType::Kernel,
+            2 => SectionType::Cmdline,
+            3 => SectionType::Ramdisk,
+            4 => SectionType::Signature,
+            5 => SectionType::Metadata,
+            other => SectionType::Other(other),
+        }
+    }
+}
+
+/// the image's sections and measurements
+#[derive(Debug)]
+pub struct EifDescription {
+    pub version: u16,
+    /// the sections' types and (data) sizes, in order
+    pub sections: Vec<(SectionType, u64)>,
+    /// PCR0, PCR1 and PCR2
+    pub pcrs: BTreeMap<usize, Vec<u8>>,
+    /// the build metadata (if the image has it)
+    pub metadata: Option<serde_json::Value>,
+}
+
+impl EifDescription {
+    /// the image is signed (its PCR8 depends on the signing certificate, which isn't computed)
+    pub fn is_signed(&self) -> bool {
+        self.sections
+            .iter()
+            .any(|(section_type, _)| *section_type == SectionType::Signature)
+    }
+}
+
+fn be_u16(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+fn be_u64(bytes: &[u8]) -> u64 {
+    let mut be = [0u8; 8];
+    be.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(be)
+}
+
+/// the PCR extended (from zeros) with the measured data's digest
+fn extend_pcr(hasher: Sha384) -> Vec<u8> {
+    let mut pcr = Sha384::new();
+    pcr.update([0u8; 48]);
+    pcr.update(hasher.finalize());
+    pcr.finalize().to_vec()
+}
+
+/// parses the image and computes its measurements
+pub fn describe<R: Read + Seek>(reader: &mut R) -> Result<EifDescription, String> {
+    let mut header = [0u8; HEADER_LEN];
+    reader
+        .read_exact(&mut header)
+        .map_err(|e| format!("failed to read the EIF header: {:?}", e))?;
+    if header[..4] != EIF_MAGIC {
+        return Err("not an enclave image file (invalid magic)".to_owned());
+    }
+    let version = be_u16(&header[4..]);
+    let num_sections = be_u16(&header[26..]) as usize;
+    if num_sections > MAX_SECTIONS {
+        return Err(format!("invalid number of EIF sections: {}", num_sections));
+    }
+    let offsets = (0..num_sections).map(|i| be_u64(&header[28 + 8 * i..]));
+
+    let mut image = Sha384::new();
+    let mut bootstrap = Sha384::new();
+    let mut application = Sha384::new();
+    let mut sections = Vec::with_capacity(num_sections);
+    let mut metadata = None;
+    let mut ramdisks = 0;
+    let mut chunk = vec![0u8; CHUNK_LEN];
+    for offset in offsets {
+        let mut section_header = [0u8; SECTION_HEADER_LEN];
+        reader
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| reader.read_exact(&mut section_header))
+            .map_err(|e| format!("failed to read the EIF section at {}: {:?}", offset, e))?;
+        let section_type = SectionType::from(be_u16(&section_header));
+        let size = be_u64(&section_header[4..]);
+        sections.push((section_type, size));
+        // the kernel, cmdline and bootstrap ramdisk are in PCR1, the other ramdisks in PCR2
+        // and all of them in PCR0
+        let mut hashers = match section_type {
+            SectionType::Kernel | SectionType::Cmdline => vec![&mut image, &mut bootstrap],
+            SectionType::Ramdisk if ramdisks == 0 => vec![&mut image, &mut bootstrap],
+            SectionType::Ramdisk => vec![&mut image, &mut application],
+            SectionType::Metadata => {
+                let mut json = Vec::new();
+                reader
+                    .take(size)
+                    .read_to_end(&mut json)
+                    .map_err(|e| format!("failed to read the EIF metadata: {:?}", e))?;
+                metadata = serde_json::from_slice(&json).ok();
+                continue;
+            }
+            _ => continue,
+        };
+        if section_type == SectionType::Ramdisk {
+            ramdisks += 1;
+        }
+        let mut remaining = size;
+        while remaining > 0 {
+            let len = remaining.min(CHUNK_LEN as u64) as usize;
+            reader
+                .read_exact(&mut chunk[..len])
+                .map_err(|e| format!("failed to read the EIF section at {}: {:?}", offset, e))?;
+            for hasher in hashers.iter_mut() {
+                hasher.update(&chunk[..len]);
+            }
+            remaining -= len as u64;
+        }
+    }
+    if ramdisks == 0 {
+        return Err("the EIF has no ramdisk".to_owned());
+    }
+    let pcrs = [image, bootstrap, application]
+        .into_iter()
+        .map(extend_pcr)
+        .enumerate()
+        .collect();
+    Ok(EifDescription {
+        version,
+        sections,
+        pcrs,
+        metadata,
+    })
+}
+
+/// parses the image file and computes its measurements
+pub fn describe_file(path: &Path) -> Result<EifDescription, String> {
+    let file =
+        File::open(path).map_err(|e| format!("failed to open `{}`: {:?}", path.display(), e))?;
+    describe(&mut BufReader::new(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn eif(sections: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut header = vec![0u8; HEADER_LEN];
+        header[..4].copy_from_slice(&EIF_MAGIC);
+        header[4..6].copy_from_slice(&4u16.to_be_bytes());
+        header[26..28].copy_from_slice(&(sections.len() as u16).to_be_bytes());
+        let mut body = Vec::new();
+        for (i, (section_type, data)) in sections.iter().enumerate() {
+            let offset = (HEADER_LEN + body.len()) as u64;
+            header[28 + 8 * i..36 + 8 * i].copy_from_slice(&offset.to_be_bytes());
+            body.extend_from_slice(&section_type.to_be_bytes());
+            body.extend_from_slice(&0u16.to_be_bytes());
+            body.extend_from_slice(&(data.len() as u64).to_be_bytes());
+            body.extend_from_slice(data);
+        }
+        header.extend_from_slice(&body);
+        header
+    }
+
+    fn pcr(data: &[&[u8]]) -> Vec<u8> {
+        let mut hasher = Sha384::new();
+        data.iter().for_each(|data| hasher.update(data));
+        extend_pcr(hasher)
+    }
+
+    #[test]
+    fn measures_the_image_sections() {
+        let image = eif(&[
+            (1, b"kernel"),
+            (2, b"console=ttyS0"),
+            (3, b"init"),
+            (3, b"app"),
+            (5, br#"{"ImageName":"tmkms"}"#),
+        ]);
+        let description = describe(&mut Cursor::new(image)).unwrap();
+        assert_eq!(description.version, 4);
+        assert_eq!(description.sections.len(), 5);
+        assert!(!description.is_signed());
+        assert_eq!(description.metadata.unwrap()["ImageName"], "tmkms");
+        assert_eq!(
+            description.pcrs[&0],
+            pcr(&[b"kernel", b"console=ttyS0", b"init", b"app"])
+        );
+        assert_eq!(
+            description.pcrs[&1],
+            pcr(&[b"kernel", b"console=ttyS0", b"init"])
+        );
+        assert_eq!(description.pcrs[&2], pcr(&[b"app"]));
+
+        assert!(describe(&mut Cursor::new(b"not an image".to_vec())).is_err());
+        assert!(describe(&mut Cursor::new(eif(&[(1, b"kernel")]))).is_err());
+    }
+}
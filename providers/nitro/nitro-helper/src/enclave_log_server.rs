@@ -1,42 +1,82 @@
+use crate::alerting::{send_alert, Alert};
+use crate::config::LogFormat;
 use crate::shared::VSOCK_HOST_CID;
-use std::io::Read;
+use std::io::Write;
+use std::sync::atomic::{AtomicU8, Ordering};
 use std::thread;
 use std::time::Duration;
-use tmkms_nitro_helper::tracing_layer::Log;
+use tmkms_nitro_helper::channel::{ChannelStream, PortListener};
+use tmkms_nitro_helper::tracing_layer::{level_filter_to_byte, read_log_frame, Log};
+use tracing::level_filters::LevelFilter;
 use tracing::Level;
 use tracing::{debug, error, info, trace, warn};
-use vsock::{VsockAddr, VsockListener};
 
 /// Configuration parameters for port listening and remote destination
 pub struct LogServer {
     cid: u32,
     local_port: u32,
+    log_format: LogFormat,
+}
+
+/// the target of the re-emitted enclave logs (which the enclave already filtered)
+pub const ENCLAVE_LOG_TARGET: &str = "enclave";
+
+/// the level the helper logs (sent to the enclave when it connects)
+static HOST_LOG_LEVEL: AtomicU8 = AtomicU8::new(3);
+
+/// sets the level the enclave forwards its logs at (unless it's changed at runtime)
+pub fn set_host_level(level: LevelFilter) {
+    HOST_LOG_LEVEL.store(level_filter_to_byte(level), Ordering::SeqCst);
+}
+
+/// re-emits the forwarded enclave log with its structured fields
+macro_rules! structured_log {
+    ($level:expr, $log:expr) => {
+        tracing::event!(
+            target: ENCLAVE_LOG_TARGET,
+            $level,
+            enclave_target = %$log.target,
+            code_file = %$log.code_file,
+            code_line = $log.code_line,
+            chain_id = $log.field("chain_id"),
+            height = $log.field("height"),
+            round = $log.field("round"),
+            step = $log.field("step"),
+            msg_type = $log.field("msg_type"),
+            "{}",
+            $log.message
+        )
+    };
 }
 
 impl LogServer {
-    pub fn new(local_port: u32) -> std::io::Result<Self> {
+    pub fn new(local_port: u32, log_format: LogFormat) -> std::io::Result<Self> {
         Ok(Self {
             cid: VSOCK_HOST_CID,
             local_port,
+            log_format,
         })
     }
 
     /// Creates a listening socket
     /// Returns the file descriptor for it or the appropriate error
-    fn sock_listen(&self) -> Result<VsockListener, String> {
+    fn sock_listen(&self) -> Result<PortListener, String> {
         info!(
             "binding enclave log server to vsock port: {}",
             self.local_port
         );
-        let sockaddr = VsockAddr::new(self.cid, self.local_port);
-        let listener = VsockListener::bind(&sockaddr)
-            .map_err(|e| format!("Could not bind to {:?}, {:?}", sockaddr, e))?;
-        info!("Bound enclave log server to {:?}", sockaddr);
+        let listener = PortListener::bind(self.cid, self.local_port).map_err(|e| {
+            format!(
+                "Could not bind to port {} of {}, {:?}",
+                self.local_port, self.cid, e
+            )
+        })?;
+        info!("Bound enclave log server to port {}", self.local_port);
         Ok(listener)
     }
 
-    /// keep listening
-    pub fn launch(mut self) {
+    /// keep listening (each enclave connection is served in its own thread)
+    pub fn launch(self) {
         thread::spawn(move || loop {
             match self.sock_listen() {
                 Ok(listener) => {
@@ -53,34 +93,63 @@ impl LogServer {
         });
     }
 
-    /// Accepts an incoming connection coming on listener and handles it on a
-    /// different thread
-    /// Returns the handle for the new thread or the appropriate error
-    fn sock_accept(&mut self, listener: &VsockListener) -> Result<(), String> {
+    /// Accepts the incoming connections and serves each on a different thread
+    fn sock_accept(&self, listener: &PortListener) -> Result<(), String> {
         loop {
-            let (mut client, client_addr) = listener
+            let (client, client_addr) = listener
                 .accept()
                 .map_err(|_| "Enclave log server could not accept connection")?;
             trace!("Accepted connection on {:?}", client_addr);
-
-            let mut tmp_buffer = [0u8; 8192];
-            let nbytes = client
-                .read(&mut tmp_buffer)
-                .map_err(|e| format!("{:?}", e))?;
-            self.process_log(&tmp_buffer[0..nbytes])?;
+            let log_format = self.log_format;
+            thread::spawn(move || {
+                if let Err(e) = serve_logs(client, log_format) {
+                    debug!("enclave log connection closed: {}", e);
+                }
+            });
         }
     }
+}
+
+/// sends the helper's log level (so the enclave forwards the events it would log)
+/// and re-emits the forwarded log records (whatever the helper's level is,
+/// as the enclave's level may have been raised at runtime)
+fn serve_logs(mut client: ChannelStream, log_format: LogFormat) -> Result<(), String> {
+    let max_level = HOST_LOG_LEVEL.load(Ordering::SeqCst);
+    client
+        .write_all(&[max_level])
+        .map_err(|e| format!("failed to send the log level: {:?}", e))?;
+    loop {
+        let raw_log = read_log_frame(&mut client).map_err(|e| format!("{:?}", e))?;
+        process_log(&raw_log, log_format)?;
+    }
+}
 
-    fn process_log(&mut self, raw_log: &[u8]) -> Result<(), String> {
-        let log = Log::from_raw(raw_log).map_err(|e| format!("{:?}", e))?;
-        let s = log.format();
+fn process_log(raw_log: &[u8], log_format: LogFormat) -> Result<(), String> {
+    let log = Log::from_raw(raw_log).map_err(|e| format!("{:?}", e))?;
+    if log.field("alert") == Some("true") {
+        send_alert(Alert {
+            source: "enclave",
+            chain_id: log.field("chain_id").map(str::to_owned),
+            message: log.message.clone(),
+        });
+    }
+    if log_format == LogFormat::Json {
         match log.level {
-            Level::TRACE => trace!("{}", s),
-            Level::DEBUG => debug!("{}", s),
-            Level::INFO => info!("{}", s),
-            Level::WARN => warn!("{}", s),
-            Level::ERROR => error!("{}", s),
+            Level::TRACE => structured_log!(Level::TRACE, log),
+            Level::DEBUG => structured_log!(Level::DEBUG, log),
+            Level::INFO => structured_log!(Level::INFO, log),
+            Level::WARN => structured_log!(Level::WARN, log),
+            Level::ERROR => structured_log!(Level::ERROR, log),
         }
-        Ok(())
+        return Ok(());
+    }
+    let s = log.format();
+    match log.level {
+        Level::TRACE => trace!(target: ENCLAVE_LOG_TARGET, "{}", s),
+        Level::DEBUG => debug!(target: ENCLAVE_LOG_TARGET, "{}", s),
+        Level::INFO => info!(target: ENCLAVE_LOG_TARGET, "{}", s),
+        Level::WARN => warn!(target: ENCLAVE_LOG_TARGET, "{}", s),
+        Level::ERROR => error!(target: ENCLAVE_LOG_TARGET, "{}", s),
     }
+    Ok(())
 }
@@ -0,0 +1,338 @@
+//! What the enclave allows to be done with the consensus key besides consensus signing.
+//! The policy is a JSON file baked into the enclave image (`/etc/tmkms/policy.json` by default),
+//! so it's covered by the image's measurements (PCR2) and can't be changed by the host,
+//! which only pushes the requests. Without the file, none of these requests is allowed.
+
+use crate::attestation_doc::{attested_public_key, verify_attestation_doc};
+use ed25519_consensus::VerificationKey;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use tmkms_light::policy::ApprovalPolicy;
+use tmkms_light::utils::PayloadLimits;
+
+/// where the enclave image has its policy
+pub const DEFAULT_POLICY_PATH: &str = "/etc/tmkms/policy.json";
+
+/// the largest helper request the enclave reads by default
+/// (a key shares request carries the cosigners' attestation documents, about 5 KiB each)
+pub const DEFAULT_MAX_REQUEST_LEN: u16 = 32 * 1024;
+
+/// the largest state (or acknowledgement) the enclave reads from the host by default
+/// (a state with its last signed message is about 1 KiB)
+pub const DEFAULT_MAX_STATE_LEN: u16 = 4 * 1024;
+
+/// the enclave's policy
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnclavePolicy {
+    /// base64-encoded X25519 keys of the operators the consensus key can be backed up to
+    /// (in Shamir shares); without any, the key can't be backed up
+    #[serde(default)]
+    pub backup_operators: Vec<String>,
+    /// the lowest number of shares a backup can be restored with
+    #[serde(default = "default_backup_min_threshold")]
+    pub backup_min_threshold: u8,
+    /// if set, the consensus key can be split into threshold shares for the cosigner enclaves
+    #[serde(default)]
+    pub key_shares: Option<KeySharesPolicy>,
+    /// base64-encoded Ed25519 key of the monotonic state service
+    /// (printed by `helper monotonic-server`); needed to use the service
+    #[serde(default)]
+    pub monotonic_service_key: Option<String>,
+    /// if set, a state without a MAC (written by an older version or rebuilt) can be
+    /// authenticated (once per chain and enclave run)
+    #[serde(default)]
+    pub state_migration: bool,
+    /// if set, arbitrary (domain-separated) payloads can be signed with the consensus key
+    /// (e.g. proofs of possession for a chain registration)
+    #[serde(default)]
+    pub payload_signing: bool,
+    /// the signatures that need the approver's token (in addition to the ones
+    /// the signing policy's `approval` rule requires it for)
+    #[serde(default)]
+    pub approval: Option<ApprovalPolicy>,
+    /// base64-encoded Ed25519 key of the approver (printed by `helper approval-server`);
+    /// the approval tokens are only verified with it
+    #[serde(default)]
+    pub approver_key: Option<String>,
+    /// the largest request (in bytes) read on the control connections
+    /// (`DEFAULT_MAX_REQUEST_LEN` if not set)
+    #[serde(default)]
+    pub max_request_len: Option<u16>,
+    /// the largest state or acknowledgement (in bytes) read on the state connections
+    /// (`DEFAULT_MAX_STATE_LEN` if not set)
+    #[serde(default)]
+    pub max_state_len: Option<u16>,
+}
+
+/// what the consensus key can be split into threshold shares for
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeySharesPolicy {
+    /// the lowest number of shares that can sign
+    #[serde(default = "default_key_shares_min_threshold")]
+    pub min_threshold: u16,
+    /// the hex-encoded PCRs (by index) of the cosigner enclaves (their attestation documents
+    /// need to have them)
+    pub cosigner_pcrs: BTreeMap<usize, String>,
+}
+
+fn default_backup_min_threshold() -> u8 {
+    2
+}
+
+fn default_key_shares_min_threshold() -> u16 {
+    2
+}
+
+impl KeySharesPolicy {
+    /// the decoded PCRs of the cosigner enclaves
+    fn cosigner_pcrs(&self) -> Result<BTreeMap<usize, Vec<u8>>, String> {
+        if self.cosigner_pcrs.is_empty() {
+            return Err("no `cosigner_pcrs` in the `key_shares` policy".to_owned());
+        }
+        self.cosigner_pcrs
+            .iter()
+            .map(|(index, pcr)| {
+                let pcr = subtle_encoding::hex::decode(pcr.trim().to_ascii_lowercase())
+                    .map_err(|e| format!("invalid cosigner PCR{}: {}", index, e))?;
+                Ok((*index, pcr))
+            })
+            .collect()
+    }
+}
+
+/// a base64-encoded Ed25519 public key
+fn ed25519_key(key: &str) -> Result<VerificationKey, String> {
+    let bytes = subtle_encoding::base64::decode(key.trim())
+        .map_err(|e| format!("invalid key `{}`: {}", key, e))?;
+    VerificationKey::try_from(bytes.as_slice())
+        .map_err(|_| format!("`{}` isn't an Ed25519 key", key))
+}
+
+/// a base64-encoded X25519 public key
+fn x25519_key(key: &str) -> Result<[u8; 32], String> {
+    let bytes = subtle_encoding::base64::decode(key.trim())
+        .map_err(|e| format!("invalid key `{}`: {}", key, e))?;
+    <[u8; 32]>::try_from(bytes.as_slice()).map_err(|_| format!("`{}` isn't an X25519 key", key))
+}
+
+impl EnclavePolicy {
+    /// reads the policy file (the default policy if the file doesn't exist)
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let json = match fs::read(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(format!("failed to read `{}`: {:?}", path.display(), e)),
+        };
+        let policy: Self = serde_json::from_slice(&json)
+            .map_err(|e| format!("invalid policy `{}`: {}", path.display(), e))?;
+        policy.backup_operator_keys()?;
+        if let Some(key_shares) = &policy.key_shares {
+            key_shares.cosigner_pcrs()?;
+        }
+        if policy.monotonic_service_key.is_some() {
+            policy.monotonic_service_key()?;
+        }
+        if policy.approver_key.is_some() || policy.approval.is_some() {
+            policy.approver_key()?;
+        }
+        Ok(policy)
+    }
+
+    /// the pinned key of the approver
+    pub fn approver_key(&self) -> Result<VerificationKey, String> {
+        let key = self
+            .approver_key
+            .as_ref()
+            .ok_or_else(|| "the enclave policy has no approver key".to_owned())?;
+        ed25519_key(key)
+    }
+
+    /// the pinned key of the monotonic state service
+    pub fn monotonic_service_key(&self) -> Result<VerificationKey, String> {
+        let key = self
+            .monotonic_service_key
+            .as_ref()
+            .ok_or_else(|| "the enclave policy has no monotonic service key".to_owned())?;
+        ed25519_key(key)
+    }
+
+    /// the operators' keys the consensus key can be backed up to
+    pub fn backup_operator_keys(&self) -> Result<Vec<[u8; 32]>, String> {
+        self.backup_operators
+            .iter()
+            .map(|key| x25519_key(key))
+            .collect()
+    }
+
+    /// checks a backup to the recipients with the threshold is allowed
+    pub fn check_backup(&self, threshold: u8, recipients: &[[u8; 32]]) -> Result<(), String> {
+        let allowed = self.backup_operator_keys()?;
+        if allowed.is_empty() {
+            return Err("the enclave policy has no backup operators".to_owned());
+        }
+        if threshold < self.backup_min_threshold.max(1) {
+            return Err(format!(
+                "the enclave policy requires a backup threshold of at least {}",
+                self.backup_min_threshold
+            ));
+        }
+        for (i, recipient) in recipients.iter().enumerate() {
+            if !allowed.contains(recipient) {
+                return Err(format!(
+                    "recipient {} isn't a backup operator of the enclave policy",
+                    i + 1
+                ));
+            }
+            if recipients[..i].contains(recipient) {
+                return Err(format!("recipient {} is given more than once", i + 1));
+            }
+        }
+        Ok(())
+    }
+
+    /// verifies the cosigner enclaves' attestation documents (their signatures, certificate chains
+    /// and pinned PCRs) and checks the split into threshold shares for them is allowed;
+    /// returns their attested keys
+    pub fn check_key_shares<A: AsRef<[u8]>>(
+        &self,
+        threshold: u16,
+        recipient_attestations: &[A],
+    ) -> Result<Vec<[u8; 32]>, String> {
+        let policy = self
+            .key_shares
+            .as_ref()
+            .ok_or_else(|| "the enclave policy doesn't allow key shares".to_owned())?;
+        if threshold < policy.min_threshold.max(1) {
+            return Err(format!(
+                "the enclave policy requires a key shares threshold of at least {}",
+                policy.min_threshold
+            ));
+        }
+        let expected = policy.cosigner_pcrs()?;
+        let mut recipients = Vec::with_capacity(recipient_attestations.len());
+        for (i, attestation) in recipient_attestations.iter().enumerate() {
+            let doc = verify_attestation_doc(attestation.as_ref())
+                .map_err(|e| format!("recipient {}: {}", i + 1, e))?;
+            for (index, pcr) in expected.iter() {
+                if doc.pcrs.get(index).map(|p| p.as_slice()) != Some(pcr.as_slice()) {
+                    return Err(format!(
+                        "recipient {} isn't a cosigner enclave (PCR{} mismatch)",
+                        i + 1,
+                        index
+                    ));
+                }
+            }
+            let recipient =
+                attested_public_key(&doc).map_err(|e| format!("recipient {}: {}", i + 1, e))?;
+            if recipients.contains(&recipient) {
+                return Err(format!("recipient {} is given more than once", i + 1));
+            }
+            recipients.push(recipient);
+        }
+        Ok(recipients)
+    }
+
+    /// checks the migration of a state without a MAC is allowed
+    pub fn check_state_migration(&self) -> Result<(), String> {
+        if !self.state_migration {
+            return Err("the enclave policy doesn't allow state migrations".to_owned());
+        }
+        Ok(())
+    }
+
+    /// checks the signing of a (domain-separated) payload is allowed
+    pub fn check_payload_signing(&self) -> Result<(), String> {
+        if !self.payload_signing {
+            return Err("the enclave policy doesn't allow payload signing".to_owned());
+        }
+        Ok(())
+    }
+
+    /// the limits of the helper's requests read on the control connections
+    pub fn request_limits(&self) -> PayloadLimits {
+        PayloadLimits {
+            max_len: self.max_request_len.unwrap_or(DEFAULT_MAX_REQUEST_LEN),
+            ..Default::default()
+        }
+    }
+
+    /// the limits of the states and acknowledgements read from the host
+    pub fn state_limits(&self) -> PayloadLimits {
+        PayloadLimits {
+            max_len: self.max_state_len.unwrap_or(DEFAULT_MAX_STATE_LEN),
+            ..Default::default()
+        }
+    }
+
+    /// hex-encoded SHA-256 digest of the policy (logged by the enclave at startup)
+    pub fn digest(&self) -> String {
+        let json = serde_json::to_vec(self).expect("policy serialization");
+        String::from_utf8(subtle_encoding::hex::encode(Sha256::digest(json))).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> String {
+        String::from_utf8(subtle_encoding::base64::encode([byte; 32])).unwrap()
+    }
+
+    #[test]
+    fn backups_are_only_allowed_to_the_pinned_operators() {
+        let policy: EnclavePolicy = serde_json::from_str(&format!(
+            r#"{{"backup_operators":["{}","{}","{}"]}}"#,
+            key(1),
+            key(2),
+            key(3)
+        ))
+        .unwrap();
+        assert_eq!(policy.backup_min_threshold, 2);
+        assert!(policy.check_backup(2, &[[1; 32], [2; 32], [3; 32]]).is_ok());
+        assert!(policy.check_backup(2, &[[1; 32], [3; 32]]).is_ok());
+        assert!(policy.check_backup(1, &[[1; 32], [2; 32]]).is_err());
+        assert!(policy.check_backup(2, &[[1; 32], [4; 32]]).is_err());
+        assert!(policy.check_backup(2, &[[1; 32], [1; 32]]).is_err());
+        assert!(EnclavePolicy::default()
+            .check_backup(2, &[[1; 32], [2; 32]])
+            .is_err());
+        assert!(serde_json::from_str::<EnclavePolicy>(r#"{"backup":[]}"#).is_err());
+    }
+
+    #[test]
+    fn key_shares_need_to_be_enabled() {
+        assert!(EnclavePolicy::default()
+            .check_key_shares::<Vec<u8>>(2, &[])
+            .is_err());
+        let policy: EnclavePolicy =
+            serde_json::from_str(r#"{"key_shares":{"cosigner_pcrs":{"0":"abcd"}}}"#).unwrap();
+        let key_shares = policy.key_shares.as_ref().unwrap();
+        assert_eq!(key_shares.min_threshold, 2);
+        assert_eq!(key_shares.cosigner_pcrs().unwrap()[&0], vec![0xab, 0xcd]);
+        assert!(policy.check_key_shares::<Vec<u8>>(1, &[]).is_err());
+        // not an attestation document
+        assert!(policy.check_key_shares(2, &[vec![1, 2, 3]]).is_err());
+        let policy: EnclavePolicy =
+            serde_json::from_str(r#"{"key_shares":{"cosigner_pcrs":{}}}"#).unwrap();
+        assert!(policy.check_key_shares::<Vec<u8>>(2, &[]).is_err());
+    }
+
+    #[test]
+    fn payload_limits_default_below_the_framing_maximum() {
+        let policy = EnclavePolicy::default();
+        assert_eq!(policy.request_limits().max_len, DEFAULT_MAX_REQUEST_LEN);
+        assert_eq!(policy.state_limits().max_len, DEFAULT_MAX_STATE_LEN);
+        let policy: EnclavePolicy =
+            serde_json::from_str(r#"{"max_request_len":60000,"max_state_len":2048}"#).unwrap();
+        assert_eq!(policy.request_limits().max_len, 60000);
+        assert_eq!(policy.state_limits().max_len, 2048);
+        assert!(serde_json::from_str::<EnclavePolicy>(r#"{"max_state_len":70000}"#).is_err());
+    }
+}
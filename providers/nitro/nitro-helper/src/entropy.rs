@@ -0,0 +1,67 @@
+//! Entropy of the keys generated in the enclave: the OS RNG's bytes mixed with the ones
+//! of additional sources (the Nitro Security Module's), so that the generated secret
+//! is as unpredictable as the best of the sources
+
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+/// domain separation of the mixed entropy
+const ENTROPY_KDF_INFO: &[u8] = b"tmkms-light keygen entropy v1";
+
+/// a source of the keygen entropy (listed in the keygen attestation)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntropySource {
+    /// the kernel's RNG (`getrandom`)
+    Os,
+    /// the Nitro Security Module's `GetRandom`
+    Nsm,
+}
+
+impl EntropySource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EntropySource::Os => "os",
+            EntropySource::Nsm => "nsm",
+        }
+    }
+}
+
+/// the 32-byte secret extracted from the sources' bytes (each one length-prefixed)
+pub fn mix_entropy(sources: &[(EntropySource, &[u8])]) -> Zeroizing<[u8; 32]> {
+    let mut ikm = Zeroizing::new(Vec::new());
+    for (source, bytes) in sources {
+        ikm.extend_from_slice(source.as_str().as_bytes());
+        ikm.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        ikm.extend_from_slice(bytes);
+    }
+    let hk = Hkdf::<Sha256>::new(None, &ikm);
+    let mut secret = Zeroizing::new([0u8; 32]);
+    hk.expand(ENTROPY_KDF_INFO, &mut secret[..])
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    secret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_source_affects_the_secret() {
+        let os = [1u8; 32];
+        let nsm = [2u8; 32];
+        let mixed = mix_entropy(&[(EntropySource::Os, &os), (EntropySource::Nsm, &nsm)]);
+        assert_eq!(
+            *mixed,
+            *mix_entropy(&[(EntropySource::Os, &os), (EntropySource::Nsm, &nsm)])
+        );
+        assert_ne!(*mixed, *mix_entropy(&[(EntropySource::Os, &os)]));
+        assert_ne!(
+            *mixed,
+            *mix_entropy(&[(EntropySource::Os, &os), (EntropySource::Nsm, &[3u8; 32])])
+        );
+        assert_ne!(*mixed, os);
+    }
+}
@@ -0,0 +1,599 @@
+//! leader election between redundant signers (helper + enclave pairs) using Raft;
+//! the replicated "log" is the last signed consensus state (i.e. the double signing watermark);
+//! the messages are authenticated with the peer key shared by the instances
+
+use crate::peer_auth::{PeerChannel, PeerKey};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::NamedTempFile;
+use tmkms_light::chain::state::{MacedState, State};
+use tracing::{debug, info, warn};
+
+/// another signer instance
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HaPeer {
+    /// its `node_id`
+    pub id: String,
+    /// its `listen_addr` (`host:port`)
+    pub addr: String,
+}
+
+/// settings of the leader election
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HaConfig {
+    /// name of this signer instance (unique in the cluster)
+    pub node_id: String,
+    /// address to listen on for the other instances' messages
+    pub listen_addr: SocketAddr,
+    /// the other signer instances
+    pub peers: Vec<HaPeer>,
+    /// path to the persisted term, vote and watermark
+    pub state_path: PathBuf,
+    /// minimum election timeout (randomized up to twice as long)
+    #[serde(default = "default_election_timeout_ms")]
+    pub election_timeout_ms: u64,
+    /// interval of the leader's heartbeats
+    #[serde(default = "default_heartbeat_ms")]
+    pub heartbeat_ms: u64,
+}
+
+fn default_election_timeout_ms() -> u64 {
+    1500
+}
+
+fn default_heartbeat_ms() -> u64 {
+    300
+}
+
+/// the state that needs to survive restarts
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+struct PersistentState {
+    term: u64,
+    voted_for: Option<String>,
+    watermark: MacedState,
+}
+
+/// messages between the signer instances
+#[derive(Clone, Debug, Deserialize, Serialize)]
+enum Message {
+    RequestVote {
+        term: u64,
+        candidate_id: String,
+        watermark: MacedState,
+    },
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    /// heartbeat or a new watermark
+    Append {
+        term: u64,
+        leader_id: String,
+        watermark: MacedState,
+    },
+    AppendAck {
+        term: u64,
+        success: bool,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// checks a peer's watermark could have been signed by an enclave before it's accepted:
+/// it needs the enclave's MAC (unless nothing was signed yet) and a valid step
+/// (the MAC itself can only be verified by the enclaves)
+fn validate_watermark(watermark: &MacedState) -> Result<(), String> {
+    if watermark.mac.is_none() && !watermark.is_initial() {
+        return Err(format!(
+            "unauthenticated watermark at height {}",
+            watermark.state.height
+        ));
+    }
+    if !(0..=2).contains(&watermark.state.step) {
+        return Err(format!("invalid watermark step {}", watermark.state.step));
+    }
+    Ok(())
+}
+
+/// the Raft state machine (without the I/O)
+struct Raft {
+    id: String,
+    persistent: PersistentState,
+    role: Role,
+    last_heard: Instant,
+}
+
+impl Raft {
+    /// steps down to a follower if the term is newer
+    fn observe_term(&mut self, term: u64) {
+        if term > self.persistent.term {
+            self.persistent.term = term;
+            self.persistent.voted_for = None;
+            self.role = Role::Follower;
+        }
+    }
+
+    /// handles a request from another instance
+    fn handle(&mut self, msg: Message) -> Option<Message> {
+        match msg {
+            Message::RequestVote {
+                term,
+                candidate_id,
+                watermark,
+            } => {
+                self.observe_term(term);
+                // the candidate needs to have signed at least as far as this instance
+                // (`>=` compares the height, round and step)
+                let granted = term == self.persistent.term
+                    && validate_watermark(&watermark).is_ok()
+                    && self
+                        .persistent
+                        .voted_for
+                        .as_ref()
+                        .map_or(true, |id| id == &candidate_id)
+                    && watermark.state >= self.persistent.watermark.state;
+                if granted {
+                    self.persistent.voted_for = Some(candidate_id);
+                    self.last_heard = Instant::now();
+                }
+                Some(Message::Vote {
+                    term: self.persistent.term,
+                    granted,
+                })
+            }
+            Message::Append {
+                term,
+                leader_id,
+                watermark,
+            } => {
+                self.observe_term(term);
+                let success = term == self.persistent.term;
+                if success {
+                    if self.role != Role::Follower {
+                        info!("{} is the leader for term {}", leader_id, term);
+                    }
+                    self.role = Role::Follower;
+                    self.last_heard = Instant::now();
+                    if watermark.state > self.persistent.watermark.state {
+                        match validate_watermark(&watermark) {
+                            Ok(()) => self.persistent.watermark = watermark,
+                            Err(e) => warn!("{}: {}", leader_id, e),
+                        }
+                    }
+                }
+                Some(Message::AppendAck {
+                    term: self.persistent.term,
+                    success,
+                })
+            }
+            Message::Vote { .. } | Message::AppendAck { .. } => None,
+        }
+    }
+}
+
+/// this signer instance in the HA cluster
+pub struct HaNode {
+    config: HaConfig,
+    raft: Mutex<Raft>,
+    peer_key: PeerKey,
+}
+
+impl HaNode {
+    /// loads the persisted term, vote and watermark (if any)
+    pub fn new(config: HaConfig, peer_key: PeerKey) -> Result<Arc<Self>, String> {
+        let persistent = match fs::read(&config.state_path) {
+            Ok(json) => serde_json::from_slice(&json).map_err(|e| {
+                format!("invalid HA state {}: {:?}", config.state_path.display(), e)
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => PersistentState::default(),
+            Err(e) => {
+                return Err(format!(
+                    "failed to read {}: {:?}",
+                    config.state_path.display(),
+                    e
+                ))
+            }
+        };
+        let raft = Raft {
+            id: config.node_id.clone(),
+            persistent,
+            role: Role::Follower,
+            last_heard: Instant::now(),
+        };
+        Ok(Arc::new(Self {
+            config,
+            raft: Mutex::new(raft),
+            peer_key,
+        }))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Raft> {
+        self.raft.lock().expect("HA state poisoned")
+    }
+
+    /// whether this instance may sign
+    pub fn is_leader(&self) -> bool {
+        self.lock().role == Role::Leader
+    }
+
+    /// the last replicated consensus state
+    pub fn watermark(&self) -> MacedState {
+        self.lock().persistent.watermark.clone()
+    }
+
+    fn majority(&self) -> usize {
+        (self.config.peers.len() + 1) / 2 + 1
+    }
+
+    fn heartbeat(&self) -> Duration {
+        Duration::from_millis(self.config.heartbeat_ms)
+    }
+
+    fn election_timeout(&self) -> Duration {
+        let base = self.config.election_timeout_ms.max(1);
+        Duration::from_millis(base + OsRng.next_u64() % base)
+    }
+
+    /// write the term, vote and watermark into a file
+    fn persist(&self, state: &PersistentState) -> Result<(), String> {
+        let path = &self.config.state_path;
+        let json = serde_json::to_vec(state).map_err(|e| format!("{:?}", e))?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut file = NamedTempFile::new_in(dir)
+            .map_err(|e| format!("failed to write {}: {:?}", path.display(), e))?;
+        file.write_all(&json)
+            .and_then(|_| file.as_file().sync_all())
+            .map_err(|e| format!("failed to write {}: {:?}", path.display(), e))?;
+        file.persist(path)
+            .map_err(|e| format!("failed to write {}: {:?}", path.display(), e.error))?;
+        // the vote is only durable once the rename is (a node mustn't vote twice in a term)
+        fs::File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .map_err(|e| format!("failed to sync {}: {:?}", dir.display(), e))?;
+        Ok(())
+    }
+
+    /// steps down if a peer's term is newer
+    fn observe_term(&self, term: u64) -> bool {
+        let mut raft = self.lock();
+        if term <= raft.persistent.term {
+            return false;
+        }
+        raft.observe_term(term);
+        if let Err(e) = self.persist(&raft.persistent) {
+            warn!("failed to persist the HA state: {}", e);
+        }
+        true
+    }
+
+    /// sends a message to a peer and waits for its response
+    fn send(&self, peer: &HaPeer, msg: &Message) -> Result<Message, String> {
+        let addr = peer
+            .addr
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| format!("invalid address: {}", peer.addr))?;
+        let stream =
+            TcpStream::connect_timeout(&addr, self.heartbeat()).map_err(|e| e.to_string())?;
+        stream
+            .set_read_timeout(Some(self.heartbeat()))
+            .and_then(|_| stream.set_write_timeout(Some(self.heartbeat())))
+            .map_err(|e| e.to_string())?;
+        let mut channel = PeerChannel::connect(stream, self.peer_key.clone())?;
+        let json_raw = serde_json::to_vec(msg).map_err(|e| format!("{:?}", e))?;
+        channel.send(&json_raw)?;
+        let json_raw = channel.recv()?;
+        serde_json::from_slice(&json_raw).map_err(|e| format!("{:?}", e))
+    }
+
+    /// sends a message to all peers (in parallel) and collects the responses
+    fn broadcast(&self, msg: &Message) -> Vec<Message> {
+        thread::scope(|s| {
+            let handles: Vec<_> = self
+                .config
+                .peers
+                .iter()
+                .map(|peer| s.spawn(move || (peer, self.send(peer, msg))))
+                .collect();
+            handles
+                .into_iter()
+                .filter_map(|h| match h.join() {
+                    Ok((_, Ok(response))) => Some(response),
+                    Ok((peer, Err(e))) => {
+                        debug!("no response from {}: {}", peer.id, e);
+                        None
+                    }
+                    Err(_) => None,
+                })
+                .collect()
+        })
+    }
+
+    fn run_election(&self) {
+        let (term, msg) = {
+            let mut raft = self.lock();
+            raft.persistent.term += 1;
+            raft.persistent.voted_for = Some(raft.id.clone());
+            raft.role = Role::Candidate;
+            raft.last_heard = Instant::now();
+            if let Err(e) = self.persist(&raft.persistent) {
+                warn!("failed to persist the HA state: {}", e);
+                raft.role = Role::Follower;
+                return;
+            }
+            let msg = Message::RequestVote {
+                term: raft.persistent.term,
+                candidate_id: raft.id.clone(),
+                watermark: raft.persistent.watermark.clone(),
+            };
+            (raft.persistent.term, msg)
+        };
+        info!("starting an election for term {}", term);
+        let mut votes = 1;
+        for response in self.broadcast(&msg) {
+            if let Message::Vote {
+                term: peer_term,
+                granted,
+            } = response
+            {
+                if self.observe_term(peer_term) {
+                    return;
+                }
+                if granted {
+                    votes += 1;
+                }
+            }
+        }
+        let mut raft = self.lock();
+        if raft.role == Role::Candidate && raft.persistent.term == term {
+            if votes >= self.majority() {
+                info!("elected the leader for term {}", term);
+                raft.role = Role::Leader;
+            } else {
+                raft.role = Role::Follower;
+            }
+        }
+    }
+
+    /// sends the heartbeats (with the current watermark) to the followers
+    fn send_heartbeats(&self) {
+        let msg = {
+            let raft = self.lock();
+            Message::Append {
+                term: raft.persistent.term,
+                leader_id: raft.id.clone(),
+                watermark: raft.persistent.watermark.clone(),
+            }
+        };
+        for response in self.broadcast(&msg) {
+            if let Message::AppendAck { term, .. } = response {
+                if self.observe_term(term) {
+                    warn!("lost the leadership (term {})", term);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// replicates the new state to the majority of the instances before it can be signed;
+    /// it needs to be the leader and the state mustn't conflict with the watermark
+    /// (e.g. if the previous leader signed at a later height)
+    pub fn replicate(&self, new_state: &MacedState) -> Result<(), String> {
+        let (term, msg) = {
+            let raft = self.lock();
+            if raft.role != Role::Leader {
+                return Err("not the leader".to_owned());
+            }
+            State::from(raft.persistent.watermark.state.clone())
+                .check_consensus_state(&new_state.state)
+                .map_err(|e| e.to_string())?;
+            let msg = Message::Append {
+                term: raft.persistent.term,
+                leader_id: raft.id.clone(),
+                watermark: new_state.clone(),
+            };
+            (raft.persistent.term, msg)
+        };
+        let mut acks = 1;
+        for response in self.broadcast(&msg) {
+            if let Message::AppendAck {
+                term: peer_term,
+                success,
+            } = response
+            {
+                if self.observe_term(peer_term) {
+                    return Err(format!("lost the leadership (term {})", peer_term));
+                }
+                if success {
+                    acks += 1;
+                }
+            }
+        }
+        if acks < self.majority() {
+            return Err(format!(
+                "the state was only replicated to {} of {} instances",
+                acks,
+                self.config.peers.len() + 1
+            ));
+        }
+        let mut raft = self.lock();
+        if raft.role != Role::Leader || raft.persistent.term != term {
+            return Err("lost the leadership".to_owned());
+        }
+        if new_state.state >= raft.persistent.watermark.state {
+            raft.persistent.watermark = new_state.clone();
+            self.persist(&raft.persistent)?;
+        }
+        Ok(())
+    }
+
+    /// handles the requests of a peer
+    fn serve(&self, channel: &mut PeerChannel<TcpStream>) -> Result<(), String> {
+        let json_raw = channel.recv()?;
+        let msg: Message = serde_json::from_slice(&json_raw).map_err(|e| format!("{:?}", e))?;
+        let response = {
+            let mut raft = self.lock();
+            let before = raft.persistent.clone();
+            let response = raft
+                .handle(msg)
+                .ok_or_else(|| "unexpected message".to_owned())?;
+            if raft.persistent != before {
+                // the vote or term must be persisted before it's sent
+                self.persist(&raft.persistent)?;
+            }
+            response
+        };
+        let json_raw = serde_json::to_vec(&response).map_err(|e| format!("{:?}", e))?;
+        channel.send(&json_raw)
+    }
+
+    /// binds the listener for the peers and runs the election timer / heartbeats
+    /// in separate threads
+    pub fn launch(self: Arc<Self>) -> Result<(), String> {
+        let listener = TcpListener::bind(self.config.listen_addr)
+            .map_err(|e| format!("failed to bind the HA listener: {:?}", e))?;
+        info!(
+            "{}: HA listening on {}",
+            self.config.node_id, self.config.listen_addr
+        );
+        let node = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let node = node.clone();
+                        thread::spawn(move || {
+                            let _ = stream.set_read_timeout(Some(node.heartbeat() * 10));
+                            match PeerChannel::accept(stream, node.peer_key.clone()) {
+                                Ok(mut channel) => while let Ok(()) = node.serve(&mut channel) {},
+                                Err(e) => debug!("HA connection failed: {}", e),
+                            }
+                        });
+                    }
+                    Err(e) => warn!("HA connection failed: {}", e),
+                }
+            }
+        });
+        thread::spawn(move || {
+            let mut timeout = self.election_timeout();
+            loop {
+                thread::sleep(self.heartbeat());
+                let (role, last_heard) = {
+                    let raft = self.lock();
+                    (raft.role, raft.last_heard)
+                };
+                if role == Role::Leader {
+                    self.send_heartbeats();
+                } else if last_heard.elapsed() >= timeout {
+                    self.run_election();
+                    timeout = self.election_timeout();
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tendermint::block;
+    use tmkms_light::chain::state::consensus;
+
+    fn watermark(height: u32) -> MacedState {
+        MacedState {
+            state: consensus::State {
+                height: block::Height::from(height),
+                ..Default::default()
+            },
+            // (only checked by the enclaves)
+            mac: Some("00".into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn votes_only_for_up_to_date_candidates() {
+        let mut raft = Raft {
+            id: "a".into(),
+            persistent: PersistentState {
+                term: 1,
+                voted_for: None,
+                watermark: watermark(10),
+            },
+            role: Role::Follower,
+            last_heard: Instant::now(),
+        };
+        let vote = |raft: &mut Raft, term, candidate: &str, height| match raft.handle(
+            Message::RequestVote {
+                term,
+                candidate_id: candidate.into(),
+                watermark: watermark(height),
+            },
+        ) {
+            Some(Message::Vote { granted, .. }) => granted,
+            _ => unreachable!(),
+        };
+        assert!(!vote(&mut raft, 2, "b", 9));
+        assert!(vote(&mut raft, 2, "c", 10));
+        assert!(!vote(&mut raft, 2, "b", 11));
+        assert!(vote(&mut raft, 3, "b", 11));
+        assert_eq!(raft.persistent.voted_for.as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn ignores_unauthenticated_watermarks() {
+        let mut raft = Raft {
+            id: "a".into(),
+            persistent: PersistentState {
+                term: 1,
+                voted_for: None,
+                watermark: watermark(10),
+            },
+            role: Role::Follower,
+            last_heard: Instant::now(),
+        };
+        let unauthenticated = MacedState {
+            mac: None,
+            ..watermark(1000)
+        };
+        raft.handle(Message::Append {
+            term: 1,
+            leader_id: "b".into(),
+            watermark: unauthenticated.clone(),
+        });
+        assert_eq!(raft.persistent.watermark, watermark(10));
+        assert!(matches!(
+            raft.handle(Message::RequestVote {
+                term: 2,
+                candidate_id: "b".into(),
+                watermark: unauthenticated,
+            }),
+            Some(Message::Vote { granted: false, .. })
+        ));
+        raft.handle(Message::Append {
+            term: 2,
+            leader_id: "b".into(),
+            watermark: watermark(11),
+        });
+        assert_eq!(raft.persistent.watermark, watermark(11));
+    }
+}
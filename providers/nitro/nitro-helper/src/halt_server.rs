@@ -0,0 +1,120 @@
+use crate::attestation_archive::{archive, AttestationKind};
+use crate::mux_server::ChannelListener;
+use crate::shared::NitroHaltReport;
+use crate::systemd;
+use serde::Serialize;
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use tmkms_light::utils::read_u16_payload;
+use tracing::{error, info, warn};
+
+/// the summary of the stop at `max_height` (written to `halt_report_path`)
+#[derive(Debug, Serialize)]
+pub struct HaltSummary {
+    pub chain_id: String,
+    pub max_height: u64,
+    pub height: u64,
+    pub round: u32,
+    pub step: i8,
+    /// base64-encoded attestation document of the final watermark (if any)
+    pub attestation_doc: Option<String>,
+}
+
+impl HaltSummary {
+    fn new(report: NitroHaltReport) -> Result<Self, String> {
+        let attestation_doc = report
+            .attestation_doc
+            .map(|doc| String::from_utf8(subtle_encoding::base64::encode(doc)))
+            .transpose()
+            .map_err(|e| format!("encoding attestation doc: {:?}", e))?;
+        Ok(Self {
+            chain_id: report.chain_id.to_string(),
+            max_height: report.max_height,
+            height: report.height,
+            round: report.round,
+            step: report.step,
+            attestation_doc,
+        })
+    }
+}
+
+/// receives the enclave's report when its session stops at `max_height`
+pub struct HaltServer {
+    path: Option<PathBuf>,
+    vsock_listener: ChannelListener,
+}
+
+impl HaltServer {
+    /// binds a listener for the enclave on the provided port
+    pub fn new(path: Option<PathBuf>, vsock_port: u32) -> Result<Self, String> {
+        let vsock_listener = ChannelListener::bind(vsock_port)
+            .map_err(|e| format!("failed to bind the halt report listener: {:?}", e))?;
+        Ok(Self {
+            path,
+            vsock_listener,
+        })
+    }
+
+    fn store(&self, report: NitroHaltReport) -> Result<(), String> {
+        if let Some(doc) = &report.attestation_doc {
+            archive(AttestationKind::Halt, Some(report.chain_id.as_str()), doc);
+        }
+        let summary = HaltSummary::new(report)?;
+        info!(
+            "[{}] session stopped at max height {}, final watermark h/r/s {}/{}/{} (attested: {})",
+            summary.chain_id,
+            summary.max_height,
+            summary.height,
+            summary.round,
+            summary.step,
+            summary.attestation_doc.is_some()
+        );
+        systemd::notify(&format!(
+            "STATUS=stopped at max height {} (final height {})",
+            summary.max_height, summary.height
+        ));
+        if let Some(path) = &self.path {
+            let json = serde_json::to_vec_pretty(&summary)
+                .map_err(|e| format!("failed to serialize the halt report: {:?}", e))?;
+            fs::write(path, json)
+                .map_err(|e| format!("couldn't write `{}`: {:?}", path.display(), e))?;
+        }
+        Ok(())
+    }
+
+    /// serves the enclave connections in a separate thread
+    pub fn launch(self) {
+        thread::spawn(move || {
+            while let Ok((mut stream, _)) = self.vsock_listener.accept() {
+                let result = read_u16_payload(&mut stream)
+                    .map_err(|e| format!("{}", e))
+                    .and_then(|json_raw| {
+                        serde_json::from_slice::<NitroHaltReport>(&json_raw)
+                            .map_err(|e| format!("invalid halt report: {:?}", e))
+                    })
+                    .and_then(|report| self.store(report));
+                if let Err(e) = result {
+                    warn!("halt report: {}", e);
+                }
+            }
+            error!("halt report listener failed");
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_halt_report() {
+        let report: NitroHaltReport = serde_json::from_str(
+            r#"{"chain_id":"testchain-1","max_height":100,"height":100,"round":0,"step":3,"attestation_doc":[1,2,3]}"#,
+        )
+        .unwrap();
+        let summary = HaltSummary::new(report).unwrap();
+        assert_eq!(summary.height, 100);
+        assert_eq!(summary.attestation_doc.as_deref(), Some("AQID"));
+    }
+}
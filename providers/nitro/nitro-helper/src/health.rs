@@ -0,0 +1,297 @@
+use crate::attestation_server::LatestAttestation;
+use serde::Serialize;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tmkms_light::metrics::SigningMetrics;
+use tmkms_light::socket_activation::{self, ActivatedListener};
+use tmkms_nitro_helper::schema::{ChainSigningStatusV1, SigningStatus, SigningStatusV1};
+use tracing::{debug, error, info, warn};
+
+/// health indicators shared between the helper components
+/// (state syncer, proxy) and the health endpoint
+#[derive(Debug, Default)]
+pub struct HealthState {
+    /// the enclave is connected to the state syncer
+    enclave_connected: AtomicBool,
+    /// the last state persistence on the host succeeded
+    state_persisted: AtomicBool,
+    /// the validator connection proxied by the helper is established
+    /// (`None` if the helper doesn't proxy the validator connection)
+    validator_connected: Option<AtomicBool>,
+}
+
+/// the health report returned by the endpoints
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    pub ready: bool,
+    pub enclave_connected: bool,
+    pub state_persisted: bool,
+    pub validator_connected: Option<bool>,
+}
+
+impl HealthState {
+    /// `proxied_validator`: if the validator connection goes via the helper's proxy
+    pub fn new(proxied_validator: bool) -> Self {
+        Self {
+            enclave_connected: AtomicBool::new(false),
+            state_persisted: AtomicBool::new(true),
+            validator_connected: proxied_validator.then(|| AtomicBool::new(false)),
+        }
+    }
+
+    pub fn set_enclave_connected(&self, connected: bool) {
+        self.enclave_connected.store(connected, Ordering::Relaxed);
+    }
+
+    pub fn set_state_persisted(&self, persisted: bool) {
+        self.state_persisted.store(persisted, Ordering::Relaxed);
+    }
+
+    pub fn set_validator_connected(&self, connected: bool) {
+        if let Some(ref v) = self.validator_connected {
+            v.store(connected, Ordering::Relaxed);
+        }
+    }
+
+    /// the current health report
+    pub fn report(&self) -> HealthReport {
+        let enclave_connected = self.enclave_connected.load(Ordering::Relaxed);
+        let state_persisted = self.state_persisted.load(Ordering::Relaxed);
+        let validator_connected = self
+            .validator_connected
+            .as_ref()
+            .map(|v| v.load(Ordering::Relaxed));
+        HealthReport {
+            ready: enclave_connected && state_persisted && validator_connected.unwrap_or(true),
+            enclave_connected,
+            state_persisted,
+            validator_connected,
+        }
+    }
+}
+
+/// a minimal HTTP server exposing `/healthz` (liveness),
+/// `/readyz` (enclave, validator and state persistence status),
+/// `/status` (the connections and signing statistics, read-only)
+/// and `/metrics` and `/attestation` (if enabled)
+pub struct HealthServer {
+    listen_addr: SocketAddr,
+    state: Arc<HealthState>,
+    metrics: Option<Arc<Mutex<SigningMetrics>>>,
+    attestation: Option<Arc<Mutex<Option<LatestAttestation>>>>,
+    started: Instant,
+}
+
+impl HealthServer {
+    pub fn new(listen_addr: SocketAddr, state: Arc<HealthState>) -> Self {
+        Self {
+            listen_addr,
+            state,
+            metrics: None,
+            attestation: None,
+            started: Instant::now(),
+        }
+    }
+
+    /// serves the enclave's request metrics (in the Prometheus text format)
+    pub fn set_metrics(&mut self, metrics: Arc<Mutex<SigningMetrics>>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// serves the latest runtime attestation of the enclave on `/attestation`
+    pub fn set_attestation(&mut self, attestation: Arc<Mutex<Option<LatestAttestation>>>) {
+        self.attestation = Some(attestation);
+    }
+
+    /// the status report (at `now`, in UNIX seconds)
+    fn status(&self, now: u64) -> SigningStatus {
+        let report = self.state.report();
+        let attestation_age_secs = self.attestation.as_ref().and_then(|attestation| {
+            attestation
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .as_ref()
+                .map(|latest| now.saturating_sub(latest.received_at))
+        });
+        let chains = self
+            .metrics
+            .as_ref()
+            .map(|metrics| metrics.lock().unwrap_or_else(|e| e.into_inner()).stats())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|stats| ChainSigningStatusV1 {
+                chain_id: stats.chain_id,
+                requests: stats.requests,
+                last_signed_height: stats.last_signed_height,
+                last_signed_round: stats.last_signed_round,
+                last_signed_step: stats.last_signed_msg_type.map(|t| t.as_str().to_owned()),
+                last_signed_timestamp: stats.last_signed_timestamp,
+            })
+            .collect();
+        SigningStatus::V1(SigningStatusV1 {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            enclave_connected: report.enclave_connected,
+            state_persisted: report.state_persisted,
+            validator_connected: report.validator_connected,
+            attestation_age_secs,
+            chains,
+        })
+    }
+
+    /// binds the listener (or uses the `metrics` socket passed by systemd socket activation)
+    /// and serves requests in a separate thread
+    pub fn launch(self) -> Result<(), String> {
+        let listener = match socket_activation::take_listener("metrics") {
+            Some(ActivatedListener::Tcp(listener)) => {
+                info!("health endpoint using the activated metrics socket");
+                listener
+            }
+            Some(ActivatedListener::Unix(_)) => {
+                return Err("the activated metrics socket must be a TCP socket".to_owned())
+            }
+            None => {
+                let listener = TcpListener::bind(self.listen_addr)
+                    .map_err(|e| format!("failed to bind health endpoint: {:?}", e))?;
+                info!("health endpoint listening on {}", self.listen_addr);
+                listener
+            }
+        };
+        thread::spawn(move || {
+            for conn in listener.incoming() {
+                match conn {
+                    Ok(stream) => {
+                        if let Err(e) = self.handle(stream) {
+                            warn!("health request failed: {}", e);
+                        }
+                    }
+                    Err(e) => {
+                        error!("health endpoint connection failed: {}", e);
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn handle(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        let mut request_line = String::new();
+        BufReader::new(&mut stream).read_line(&mut request_line)?;
+        debug!("health request: {}", request_line.trim_end());
+        let mut parts = request_line.split_whitespace();
+        let mut content_type = "application/json";
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some("/healthz")) => ("200 OK", "{\"status\":\"ok\"}".to_owned()),
+            (Some("GET"), Some("/metrics")) if self.metrics.is_some() => {
+                content_type = "text/plain; version=0.0.4";
+                let metrics = self.metrics.as_ref().expect("metrics");
+                let body = metrics.lock().unwrap_or_else(|e| e.into_inner()).render();
+                ("200 OK", body)
+            }
+            (Some("GET"), Some("/attestation")) if self.attestation.is_some() => {
+                let attestation = self.attestation.as_ref().expect("attestation");
+                match &*attestation.lock().unwrap_or_else(|e| e.into_inner()) {
+                    Some(latest) => (
+                        "200 OK",
+                        serde_json::to_string(latest).unwrap_or_else(|_| "{}".to_owned()),
+                    ),
+                    None => ("503 Service Unavailable", "{}".to_owned()),
+                }
+            }
+            (Some("GET"), Some("/status")) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default();
+                (
+                    "200 OK",
+                    serde_json::to_string(&self.status(now)).unwrap_or_else(|_| "{}".to_owned()),
+                )
+            }
+            (Some("GET"), Some("/readyz")) => {
+                let report = self.state.report();
+                let status = if report.ready {
+                    "200 OK"
+                } else {
+                    "503 Service Unavailable"
+                };
+                (
+                    status,
+                    serde_json::to_string(&report).unwrap_or_else(|_| "{}".to_owned()),
+                )
+            }
+            _ => ("404 Not Found", "{}".to_owned()),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body
+        )?;
+        stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readiness_requires_all_components() {
+        let state = HealthState::new(true);
+        assert!(!state.report().ready);
+        state.set_enclave_connected(true);
+        assert!(!state.report().ready);
+        state.set_validator_connected(true);
+        assert!(state.report().ready);
+        state.set_state_persisted(false);
+        assert!(!state.report().ready);
+    }
+
+    #[test]
+    fn unproxied_validator_is_not_checked() {
+        let state = HealthState::new(false);
+        state.set_enclave_connected(true);
+        let report = state.report();
+        assert!(report.ready);
+        assert_eq!(report.validator_connected, None);
+    }
+
+    #[test]
+    fn status_reports_signing_statistics() {
+        let state = Arc::new(HealthState::new(false));
+        state.set_enclave_connected(true);
+        let mut server = HealthServer::new("127.0.0.1:0".parse().unwrap(), state);
+        let metrics = Arc::new(Mutex::new(SigningMetrics::default()));
+        metrics.lock().unwrap().record(
+            &tmkms_light::metrics::MetricsEvent::Signed {
+                chain_id: "testchain-1".parse().unwrap(),
+                msg_type: tmkms_light::audit::SignedMsgKind::Precommit,
+                height: 10,
+                round: 0,
+            },
+            1000,
+        );
+        server.set_metrics(metrics);
+        server.set_attestation(Arc::new(Mutex::new(Some(LatestAttestation {
+            chain_id: "testchain-1".to_owned(),
+            attestation_doc: String::new(),
+            received_at: 1000,
+        }))));
+        let SigningStatus::V1(status) = server.status(1060);
+        assert!(status.enclave_connected);
+        assert_eq!(status.attestation_age_secs, Some(60));
+        assert_eq!(status.chains[0].last_signed_height, 10);
+        assert_eq!(
+            status.chains[0].last_signed_step.as_deref(),
+            Some("precommit")
+        );
+        assert_eq!(status.chains[0].requests["precommit"], 1);
+    }
+}
@@ -0,0 +1,70 @@
+//! Minimal HTTP client (for the APIs of the KV stores and the instance metadata service)
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// sends a request to an HTTP API (e.g. a KV store's) and returns the status code and body
+/// (as HTTP/1.0, so the response isn't chunked)
+pub fn http_request(
+    addr: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<(u16, Vec<u8>), String> {
+    let mut stream =
+        TcpStream::connect(addr).map_err(|e| format!("failed to connect to {}: {}", addr, e))?;
+    stream
+        .set_read_timeout(Some(HTTP_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(HTTP_TIMEOUT)))
+        .map_err(|e| format!("{}", e))?;
+    let mut request = format!(
+        "{} {} HTTP/1.0\r\nHost: {}\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        addr,
+        body.len()
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    let mut response = Vec::new();
+    stream
+        .write_all(request.as_bytes())
+        .and_then(|_| stream.write_all(body))
+        .and_then(|_| stream.read_to_end(&mut response))
+        .map_err(|e| format!("request to {} failed: {}", addr, e))?;
+    parse_http_response(&response)
+}
+
+fn parse_http_response(response: &[u8]) -> Result<(u16, Vec<u8>), String> {
+    let header_end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| "incomplete HTTP response".to_string())?;
+    let status = std::str::from_utf8(&response[..header_end])
+        .ok()
+        .and_then(|head| head.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| "invalid HTTP status line".to_string())?;
+    Ok((status, response[header_end + 4..].to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_http_responses() {
+        let response = b"HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\ntrue";
+        assert_eq!(
+            parse_http_response(response).unwrap(),
+            (200, b"true".to_vec())
+        );
+        assert!(parse_http_response(b"HTTP/1.0 200 OK\r\n").is_err());
+    }
+}
@@ -0,0 +1,147 @@
+//! Instance role credentials from the EC2 instance metadata service (IMDSv2)
+
+use crate::http::http_request;
+use crate::shared::AwsCredentials;
+use serde::Deserialize;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const IMDS_ADDR: &str = "169.254.169.254:80";
+const CREDENTIALS_PATH: &str = "/latest/meta-data/iam/security-credentials/";
+/// lifetime of the session tokens (6 hours, the maximum)
+const TOKEN_TTL: Duration = Duration::from_secs(21600);
+/// the credentials are renewed this long before their expiration
+pub(crate) const EXPIRY_MARGIN: Duration = Duration::from_secs(300);
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct RoleCredentials {
+    code: String,
+    access_key_id: String,
+    secret_access_key: String,
+    token: String,
+    expiration: String,
+}
+
+/// fetches the instance role credentials and renews them before they expire
+#[derive(Default)]
+pub struct ImdsCredentialsProvider {
+    /// the IMDSv2 session token (with when it expires)
+    token: Option<(String, Instant)>,
+    /// the last credentials (with when they expire)
+    cached: Option<(AwsCredentials, SystemTime)>,
+}
+
+impl ImdsCredentialsProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn token(&mut self) -> Result<String, String> {
+        if let Some((token, expires)) = &self.token {
+            if Instant::now() + EXPIRY_MARGIN < *expires {
+                return Ok(token.clone());
+            }
+        }
+        let ttl = TOKEN_TTL.as_secs().to_string();
+        let (status, body) = http_request(
+            IMDS_ADDR,
+            "PUT",
+            "/latest/api/token",
+            &[("X-aws-ec2-metadata-token-ttl-seconds", &ttl)],
+            &[],
+        )
+        .map_err(|e| {
+            // the token response doesn't reach a container behind an extra network hop
+            // with the default hop limit (1)
+            format!(
+                "failed to get an IMDSv2 token: {} (in a container, the instance's \
+                 `HttpPutResponseHopLimit` must be at least 2)",
+                e
+            )
+        })?;
+        if status != 200 {
+            return Err(format!(
+                "IMDSv2 token request failed with status {}",
+                status
+            ));
+        }
+        let token = String::from_utf8(body).map_err(|e| format!("invalid IMDSv2 token: {}", e))?;
+        self.token = Some((token.clone(), Instant::now() + TOKEN_TTL));
+        Ok(token)
+    }
+
+    fn get(&mut self, path: &str) -> Result<Vec<u8>, String> {
+        let token = self.token()?;
+        let (status, body) = http_request(
+            IMDS_ADDR,
+            "GET",
+            path,
+            &[("X-aws-ec2-metadata-token", &token)],
+            &[],
+        )?;
+        match status {
+            200 => Ok(body),
+            401 => {
+                // expired or invalid: a new one is requested next time
+                self.token = None;
+                Err("IMDSv2 token rejected".to_owned())
+            }
+            404 => Err("no IAM role is attached to the instance".to_owned()),
+            _ => Err(format!("IMDS request failed with status {}", status)),
+        }
+    }
+
+    /// the current credentials (renewed if they expire soon)
+    pub fn credentials(&mut self) -> Result<AwsCredentials, String> {
+        if let Some((credentials, expiration)) = &self.cached {
+            if SystemTime::now() + EXPIRY_MARGIN < *expiration {
+                return Ok(credentials.clone());
+            }
+        }
+        let roles = self.get(CREDENTIALS_PATH)?;
+        let role = String::from_utf8_lossy(&roles)
+            .lines()
+            .next()
+            .map(str::to_owned)
+            .ok_or_else(|| "no IAM role is attached to the instance".to_owned())?;
+        let body = self.get(&format!("{}{}", CREDENTIALS_PATH, role))?;
+        let (credentials, expiration) = parse_credentials(&body)?;
+        self.cached = Some((credentials.clone(), expiration));
+        Ok(credentials)
+    }
+}
+
+fn parse_credentials(body: &[u8]) -> Result<(AwsCredentials, SystemTime), String> {
+    let role: RoleCredentials =
+        serde_json::from_slice(body).map_err(|e| format!("invalid role credentials: {}", e))?;
+    if role.code != "Success" {
+        return Err(format!("role credentials unavailable: {}", role.code));
+    }
+    let expiration = tendermint::Time::parse_from_rfc3339(&role.expiration)
+        .map_err(|e| format!("invalid credentials expiration: {}", e))?;
+    let expiration = UNIX_EPOCH + Duration::from_secs(expiration.unix_timestamp().max(0) as u64);
+    Ok((
+        AwsCredentials {
+            aws_key_id: role.access_key_id,
+            aws_secret_key: role.secret_access_key,
+            aws_session_token: role.token,
+        },
+        expiration,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_role_credentials() {
+        let body = br#"{"Code":"Success","LastUpdated":"2024-01-01T00:00:00Z","Type":"AWS-HMAC",
+            "AccessKeyId":"AKIA","SecretAccessKey":"secret","Token":"token",
+            "Expiration":"2024-01-01T06:00:00Z"}"#;
+        let (credentials, expiration) = parse_credentials(body).unwrap();
+        assert_eq!(credentials.aws_session_token, "token");
+        assert_eq!(expiration, UNIX_EPOCH + Duration::from_secs(1_704_088_800));
+        assert!(parse_credentials(br#"{"Code":"Failure"}"#).is_err());
+    }
+}
@@ -0,0 +1,333 @@
+//! Threshold shares of the consensus key for a future migration to threshold signing
+//! (the secret scalar of the existing key is split, so the on-chain key doesn't change).
+
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use curve25519_dalek::constants::ED25519_BASEPOINT_TABLE;
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::Identity;
+use ed25519_consensus::VerificationKey;
+use hkdf::Hkdf;
+use rand_core::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use zeroize::Zeroize;
+
+/// domain separation for deriving the share encryption keys
+const SHARE_KDF_INFO: &[u8] = b"tmkms-light threshold key share v1";
+
+/// a threshold share encrypted to a cosigner's X25519 key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedKeyShare {
+    /// evaluation point of the share (1-based)
+    pub index: u16,
+    /// X25519 public key of the recipient cosigner
+    pub recipient: [u8; 32],
+    /// ephemeral X25519 public key used for this share
+    pub ephemeral_public_key: [u8; 32],
+    /// ChaCha20Poly1305 nonce
+    pub nonce: [u8; 12],
+    /// encrypted 32-byte share scalar
+    pub ciphertext: Vec<u8>,
+}
+
+/// threshold shares with Feldman commitments to the polynomial coefficients
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyShares {
+    /// number of shares needed to sign
+    pub threshold: u16,
+    /// commitments to the polynomial coefficients (the first one is the consensus public key)
+    pub commitments: Vec<[u8; 32]>,
+    /// one share per recipient
+    pub shares: Vec<EncryptedKeyShare>,
+}
+
+/// Possible errors in splitting or recovering key shares
+#[derive(Debug, PartialEq, Eq)]
+pub enum KeyShareError {
+    /// threshold is zero or more than the number of recipients
+    InvalidThreshold,
+    /// AEAD encryption or decryption failed
+    EncryptionError,
+    /// the decrypted share isn't a canonical scalar
+    InvalidShare,
+}
+
+impl KeyShares {
+    /// digest of the shares and commitments (e.g. to be bound in an attestation)
+    pub fn digest(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.threshold.to_le_bytes());
+        for c in self.commitments.iter() {
+            hasher.update(c);
+        }
+        for share in self.shares.iter() {
+            hasher.update(share.index.to_le_bytes());
+            hasher.update(share.recipient);
+            hasher.update(share.ephemeral_public_key);
+            hasher.update(share.nonce);
+            hasher.update(&share.ciphertext);
+        }
+        hasher.finalize().into()
+    }
+
+    /// checks the shares commit to the provided public key
+    pub fn commits_to(&self, public_key: &VerificationKey) -> bool {
+        self.commitments.first() == Some(&public_key.to_bytes())
+    }
+}
+
+/// the Ed25519 secret scalar (as used in signing) derived from the key seed
+fn secret_scalar(seed: &[u8; 32]) -> Scalar {
+    let mut h: [u8; 64] = Sha512::digest(seed).into();
+    let mut scalar_bytes = [0u8; 32];
+    scalar_bytes.copy_from_slice(&h[..32]);
+    h.zeroize();
+    scalar_bytes[0] &= 248;
+    scalar_bytes[31] &= 127;
+    scalar_bytes[31] |= 64;
+    let s = Scalar::from_bits(scalar_bytes);
+    scalar_bytes.zeroize();
+    s
+}
+
+/// derives the share encryption key from the X25519 shared secret
+fn share_key(shared_secret: &MontgomeryPoint, ephemeral: &[u8; 32], recipient: &[u8; 32]) -> Key {
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(ephemeral);
+    salt[32..].copy_from_slice(recipient);
+    let hk = Hkdf::<Sha256>::new(Some(&salt), shared_secret.as_bytes());
+    let mut okm = [0u8; 32];
+    hk.expand(SHARE_KDF_INFO, &mut okm)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    let key = *Key::from_slice(&okm);
+    okm.zeroize();
+    key
+}
+
+/// clamped X25519 secret scalar
+pub(crate) fn x25519_scalar(mut secret: [u8; 32]) -> Scalar {
+    secret[0] &= 248;
+    secret[31] &= 127;
+    secret[31] |= 64;
+    let s = Scalar::from_bits(secret);
+    secret.zeroize();
+    s
+}
+
+/// X25519 public key for the given secret
+pub fn x25519_public_key(secret: &[u8; 32]) -> [u8; 32] {
+    let s = x25519_scalar(*secret);
+    (&s * &ED25519_BASEPOINT_TABLE).to_montgomery().to_bytes()
+}
+
+/// Splits the secret scalar of the signing key seed into `recipients.len()`
+/// shares (any `threshold` of which can sign) and encrypts each to its recipient
+pub fn split_signing_key<R: RngCore + CryptoRng>(
+    csprng: &mut R,
+    seed: &[u8; 32],
+    threshold: u16,
+    recipients: &[[u8; 32]],
+) -> Result<KeyShares, KeyShareError> {
+    if threshold == 0
+        || threshold as usize > recipients.len()
+        || recipients.len() > u16::MAX as usize
+    {
+        return Err(KeyShareError::InvalidThreshold);
+    }
+    let mut coefficients = Vec::with_capacity(threshold as usize);
+    coefficients.push(secret_scalar(seed));
+    for _ in 1..threshold {
+        coefficients.push(Scalar::random(csprng));
+    }
+    let commitments = coefficients
+        .iter()
+        .map(|c| (c * &ED25519_BASEPOINT_TABLE).compress().to_bytes())
+        .collect();
+    let mut shares = Vec::with_capacity(recipients.len());
+    for (i, recipient) in recipients.iter().enumerate() {
+        let index = (i + 1) as u16;
+        // Horner evaluation of the polynomial at `index`
+        let x = Scalar::from(index as u64);
+        let mut share = coefficients
+            .iter()
+            .rev()
+            .fold(Scalar::zero(), |acc, c| acc * x + c);
+
+        let mut ephemeral_secret = [0u8; 32];
+        csprng.fill_bytes(&mut ephemeral_secret);
+        let ephemeral_public_key = x25519_public_key(&ephemeral_secret);
+        let shared_secret = MontgomeryPoint(*recipient) * x25519_scalar(ephemeral_secret);
+        ephemeral_secret.zeroize();
+        let key = share_key(&shared_secret, &ephemeral_public_key, recipient);
+        let mut nonce = [0u8; 12];
+        csprng.fill_bytes(&mut nonce);
+        let aad = index.to_le_bytes();
+        let ciphertext = ChaCha20Poly1305::new(&key)
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: share.as_bytes(),
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| KeyShareError::EncryptionError);
+        share.zeroize();
+        shares.push(EncryptedKeyShare {
+            index,
+            recipient: *recipient,
+            ephemeral_public_key,
+            nonce,
+            ciphertext: ciphertext?,
+        });
+    }
+    coefficients.iter_mut().for_each(Zeroize::zeroize);
+    Ok(KeyShares {
+        threshold,
+        commitments,
+        shares,
+    })
+}
+
+/// Decrypts the share with the recipient's X25519 secret
+/// and checks it against the commitments
+pub fn decrypt_share(
+    shares: &KeyShares,
+    share: &EncryptedKeyShare,
+    recipient_secret: &[u8; 32],
+) -> Result<[u8; 32], KeyShareError> {
+    let shared_secret =
+        MontgomeryPoint(share.ephemeral_public_key) * x25519_scalar(*recipient_secret);
+    let key = share_key(
+        &shared_secret,
+        &share.ephemeral_public_key,
+        &share.recipient,
+    );
+    let aad = share.index.to_le_bytes();
+    let mut plaintext = ChaCha20Poly1305::new(&key)
+        .decrypt(
+            Nonce::from_slice(&share.nonce),
+            Payload {
+                msg: &share.ciphertext,
+                aad: &aad,
+            },
+        )
+        .map_err(|_| KeyShareError::EncryptionError)?;
+    if plaintext.len() != 32 {
+        plaintext.zeroize();
+        return Err(KeyShareError::InvalidShare);
+    }
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(&plaintext);
+    plaintext.zeroize();
+    match Scalar::from_canonical_bytes(bytes) {
+        Some(s) if verify_share(&shares.commitments, share.index, &s) => Ok(bytes),
+        _ => {
+            bytes.zeroize();
+            Err(KeyShareError::InvalidShare)
+        }
+    }
+}
+
+/// Feldman check: share * B == sum(commitment_j * index^j)
+fn verify_share(commitments: &[[u8; 32]], index: u16, share: &Scalar) -> bool {
+    let x = Scalar::from(index as u64);
+    let mut power = Scalar::one();
+    let mut expected = curve25519_dalek::edwards::EdwardsPoint::identity();
+    for c in commitments {
+        match CompressedEdwardsY(*c).decompress() {
+            Some(point) => expected += point * power,
+            None => return false,
+        }
+        power *= x;
+    }
+    share * &ED25519_BASEPOINT_TABLE == expected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_consensus::SigningKey;
+    use rand_core::OsRng;
+
+    fn recipient(csprng: &mut OsRng) -> ([u8; 32], [u8; 32]) {
+        let mut secret = [0u8; 32];
+        csprng.fill_bytes(&mut secret);
+        (secret, x25519_public_key(&secret))
+    }
+
+    /// Lagrange interpolation at zero
+    fn interpolate(points: &[(u16, Scalar)]) -> Scalar {
+        let mut secret = Scalar::zero();
+        for (i, (xi, yi)) in points.iter().enumerate() {
+            let xi = Scalar::from(*xi as u64);
+            let mut num = Scalar::one();
+            let mut den = Scalar::one();
+            for (j, (xj, _)) in points.iter().enumerate() {
+                if i != j {
+                    let xj = Scalar::from(*xj as u64);
+                    num *= xj;
+                    den *= xj - xi;
+                }
+            }
+            secret += yi * num * den.invert();
+        }
+        secret
+    }
+
+    #[test]
+    fn shares_recover_the_public_key() {
+        let mut csprng = OsRng;
+        let sk = SigningKey::new(csprng);
+        let recipients: Vec<_> = (0..3).map(|_| recipient(&mut csprng)).collect();
+        let pubkeys: Vec<_> = recipients.iter().map(|r| r.1).collect();
+        let shares = split_signing_key(&mut csprng, sk.as_bytes(), 2, &pubkeys).unwrap();
+        assert!(shares.commits_to(&sk.verification_key()));
+
+        let points: Vec<_> = shares.shares[1..]
+            .iter()
+            .zip(recipients[1..].iter())
+            .map(|(share, (secret, _))| {
+                let s = decrypt_share(&shares, share, secret).unwrap();
+                (share.index, Scalar::from_canonical_bytes(s).unwrap())
+            })
+            .collect();
+        let recovered = interpolate(&points);
+        assert_eq!(
+            (&recovered * &ED25519_BASEPOINT_TABLE)
+                .compress()
+                .to_bytes(),
+            sk.verification_key().to_bytes()
+        );
+    }
+
+    #[test]
+    fn share_for_another_recipient_fails() {
+        let mut csprng = OsRng;
+        let sk = SigningKey::new(csprng);
+        let (_, pk1) = recipient(&mut csprng);
+        let (secret2, pk2) = recipient(&mut csprng);
+        let shares = split_signing_key(&mut csprng, sk.as_bytes(), 1, &[pk1, pk2]).unwrap();
+        assert_eq!(
+            decrypt_share(&shares, &shares.shares[0], &secret2),
+            Err(KeyShareError::EncryptionError)
+        );
+    }
+
+    #[test]
+    fn invalid_threshold() {
+        let mut csprng = OsRng;
+        let sk = SigningKey::new(csprng);
+        let (_, pk) = recipient(&mut csprng);
+        assert_eq!(
+            split_signing_key(&mut csprng, sk.as_bytes(), 2, &[pk]).unwrap_err(),
+            KeyShareError::InvalidThreshold
+        );
+        assert_eq!(
+            split_signing_key(&mut csprng, sk.as_bytes(), 0, &[pk]).unwrap_err(),
+            KeyShareError::InvalidThreshold
+        );
+    }
+}
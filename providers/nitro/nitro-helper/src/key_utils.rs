@@ -1,74 +1,193 @@
-use crate::shared::AwsCredentials;
-use crate::shared::{NitroKeygenConfig, NitroKeygenResponse, NitroRequest, NitroResponse};
+use crate::attestation::{sealed_request, verify_attestation_doc, ExpectedPcrs};
+use crate::attestation_archive::{archive, AttestationKind};
+use crate::command::CommandError;
+use crate::shared::{
+    KeyPurpose, NitroKeygenConfig, NitroKeygenResponse, NitroRequest, NitroResponse,
+};
+use tmkms_nitro_helper::slip10::DerivationPath;
 
-use ed25519_consensus::VerificationKey;
-use std::{fs::OpenOptions, io::Write, os::unix::fs::OpenOptionsExt, path::Path};
-use tmkms_light::utils::{read_u16_payload, write_u16_payload};
-use vsock::VsockAddr;
+use ed25519_consensus::{SigningKey, VerificationKey};
+use rand_core::{OsRng, RngCore};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::{os::unix::fs::OpenOptionsExt, path::Path};
+use tracing::{info, warn};
+use zeroize::Zeroizing;
 
 pub(crate) mod credential {
+    use crate::config::NitroSignOpt;
+    use crate::imds::{ImdsCredentialsProvider, EXPIRY_MARGIN};
     use crate::shared::AwsCredentials;
-    use aws_config::imds::credentials;
-    use aws_credential_types::provider::ProvideCredentials;
-    use tokio::runtime::Builder;
+    use crate::sts::AssumeRoleConfig;
+    use std::time::SystemTime;
 
-    /// get credentials from Aws Instance Metadata Service Version 2
-    /// https://docs.aws.amazon.com/AWSEC2/latest/UserGuide/configuring-instance-metadata-service.html
-    pub fn get_credentials() -> Result<AwsCredentials, String> {
-        let client = credentials::ImdsCredentialsProvider::builder().build();
-        let rt = Builder::new_current_thread()
-            .enable_all()
-            .build()
-            .map_err(|e| format!("failed to create tokio runtime: {:?}", e))?;
-        let aws_credential = rt
-            .block_on(client.provide_credentials())
-            .map_err(|e| format!("invalid credential: {:?}", e))?;
-        let credentials = AwsCredentials {
-            aws_key_id: aws_credential.access_key_id().into(),
-            aws_secret_key: aws_credential.secret_access_key().into(),
-            aws_session_token: aws_credential.session_token().unwrap().into(),
-        };
+    /// the credentials pushed to the enclave: the configured ones (or the instance role's
+    /// from Aws Instance Metadata Service Version 2
+    /// https://docs.aws.amazon.com/AWSEC2/latest/UserGuide/configuring-instance-metadata-service.html),
+    /// or the `assume_role` credentials obtained with them
+    pub struct CredentialsSource {
+        configured: Option<AwsCredentials>,
+        imds: ImdsCredentialsProvider,
+        assume_role: Option<(AssumeRoleConfig, String)>,
+        /// the last role credentials (with when they expire)
+        assumed: Option<(AwsCredentials, SystemTime)>,
+    }
+
+    impl CredentialsSource {
+        pub fn new(config: &NitroSignOpt) -> Self {
+            Self {
+                configured: match (&config.credentials, config.dev_plaintext) {
+                    // the development mode doesn't call AWS
+                    (None, true) => Some(AwsCredentials {
+                        aws_key_id: "dev".to_owned(),
+                        aws_secret_key: "dev".to_owned(),
+                        aws_session_token: String::new(),
+                    }),
+                    (credentials, _) => credentials.clone(),
+                },
+                imds: ImdsCredentialsProvider::new(),
+                assume_role: config
+                    .assume_role
+                    .clone()
+                    .map(|role| (role, config.aws_region.clone())),
+                assumed: None,
+            }
+        }
 
-        Ok(credentials)
+        /// the current credentials (the role is assumed again if they expire soon)
+        pub fn credentials(&mut self) -> Result<AwsCredentials, String> {
+            let source = match &self.configured {
+                Some(credentials) => credentials.clone(),
+                None => self.imds.credentials()?,
+            };
+            let (role, aws_region) = match &self.assume_role {
+                Some(assume_role) => assume_role,
+                None => return Ok(source),
+            };
+            if let Some((credentials, expiration)) = &self.assumed {
+                if SystemTime::now() + EXPIRY_MARGIN < *expiration {
+                    return Ok(credentials.clone());
+                }
+            }
+            let (credentials, expiration) = role.assume(aws_region, &source)?;
+            self.assumed = Some((credentials.clone(), expiration));
+            Ok(credentials)
+        }
     }
 }
 
 /// Generates a keypair and encrypts with AWS KMS at the given path
 /// and returns the public key with attestation doc for it and
 /// the used AWS KMS key id
+/// (the request's nonce is replaced with a fresh one; the request with the credentials
+/// is only sent to an enclave with the `expected` measurements)
 pub fn generate_key(
     cid: u32,
     port: u32,
+    expected: &ExpectedPcrs,
     path: impl AsRef<Path>,
-    region: &str,
-    credentials: AwsCredentials,
-    kms_key_id: String,
-) -> Result<(VerificationKey, Vec<u8>), String> {
-    let keygen_request = NitroKeygenConfig {
-        credentials,
-        kms_key_id,
-        aws_region: region.into(),
-    };
+    mut keygen_request: NitroKeygenConfig,
+) -> Result<(VerificationKey, Vec<u8>), CommandError> {
+    let mut nonce = vec![0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    keygen_request.nonce = nonce.clone();
+    let purpose = keygen_request.purpose;
+    let derivation_path = keygen_request.derivation_path.clone();
+
+    let response: NitroResponse =
+        sealed_request(cid, port, expected, &NitroRequest::Keygen(keygen_request))
+            .map_err(|e| format!("failed to get keygen response from enclave: {}", e))?;
 
-    let request = NitroRequest::Keygen(keygen_request);
-    let addr = VsockAddr::new(cid, port);
-    let mut socket = vsock::VsockStream::connect(&addr).map_err(|e| {
-        format!(
-            "failed to connect to the enclave to generate key pair: {:?}",
-            e
-        )
-    })?;
-    let request_raw = serde_json::to_vec(&request)
-        .map_err(|e| format!("failed to serialize the keygen request: {:?}", e))?;
-    write_u16_payload(&mut socket, &request_raw)
-        .map_err(|e| format!("failed to write the config: {:?}", e))?;
-    // get the response
-    let json_raw =
-        read_u16_payload(&mut socket).map_err(|_e| "failed to read config".to_string())?;
-    let response: NitroResponse = serde_json::from_slice(&json_raw)
-        .map_err(|e| format!("failed to get keygen response from enclave: {:?}", e))?;
+    Ok(seal_key_response(
+        path,
+        response?,
+        &nonce,
+        expected,
+        purpose,
+        derivation_path.as_ref(),
+    )?)
+}
+
+/// loads an Ed25519 key of the helper's services, e.g. the approver key
+/// (or generates it if the file doesn't exist)
+pub fn load_or_generate_signing_key(path: &Path, what: &str) -> Result<SigningKey, String> {
+    match fs::read(path) {
+        Ok(encoded) => {
+            let secret = Zeroizing::new(
+                subtle_encoding::base64::decode(String::from_utf8_lossy(&encoded).trim())
+                    .map_err(|e| format!("invalid {} {}: {}", what, path.display(), e))?,
+            );
+            SigningKey::try_from(secret.as_slice())
+                .map_err(|e| format!("invalid {} {}: {}", what, path.display(), e))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let mut secret = Zeroizing::new([0u8; 32]);
+            OsRng.fill_bytes(&mut secret[..]);
+            let encoded = Zeroizing::new(subtle_encoding::base64::encode(&secret[..]));
+            OpenOptions::new()
+                .create_new(true)
+                .write(true)
+                .mode(0o600)
+                .open(path)
+                .and_then(|mut file| file.write_all(&encoded))
+                .map_err(|e| format!("couldn't write `{}`: {:?}", path.display(), e))?;
+            info!("generated the {} {}", what, path.display());
+            Ok(SigningKey::from(*secret))
+        }
+        Err(e) => Err(format!("failed to read {}: {:?}", path.display(), e)),
+    }
+}
 
-    let resp: NitroKeygenResponse = response?;
+/// Checks the enclave's keygen response is attested (by an enclave with the pinned
+/// measurements) for the request (nonce, public key, purpose and derivation path),
+/// writes the sealed key at the given path and returns the public key with attestation doc for it
+pub fn seal_key_response(
+    path: impl AsRef<Path>,
+    resp: NitroKeygenResponse,
+    nonce: &[u8],
+    expected: &ExpectedPcrs,
+    purpose: KeyPurpose,
+    derivation_path: Option<&DerivationPath>,
+) -> Result<(VerificationKey, Vec<u8>), String> {
+    archive(AttestationKind::Keygen, None, &resp.attestation_doc);
+    let doc = verify_attestation_doc(&resp.attestation_doc)?;
+    if doc.nonce.as_ref().map(|n| n.as_slice()) != Some(nonce) {
+        return Err("keygen attestation doesn't have the requested nonce".to_owned());
+    }
+    expected.verify(&doc)?;
+    let claim: serde_json::Value = serde_json::from_slice(
+        &doc.user_data
+            .ok_or_else(|| "keygen attestation has no user data".to_owned())?,
+    )
+    .map_err(|e| format!("invalid keygen attestation claim: {:?}", e))?;
+    let pubkeyb64 = String::from_utf8(subtle_encoding::base64::encode(&resp.public_key))
+        .map_err(|e| format!("encoding the public key: {:?}", e))?;
+    if claim["pubkey"].as_str() != Some(pubkeyb64.as_str()) {
+        return Err("keygen attestation doesn't match the generated public key".to_owned());
+    }
+    if claim["purpose"].as_str() != Some(purpose.as_str()) {
+        return Err(format!(
+            "keygen attestation isn't for a {} key",
+            purpose.as_str()
+        ));
+    }
+    let entropy: Vec<&str> = claim["entropy"]
+        .as_array()
+        .map(|sources| sources.iter().filter_map(|s| s.as_str()).collect())
+        .unwrap_or_default();
+    if entropy.contains(&"nsm") {
+        info!("key generated with the entropy of: {}", entropy.join(", "));
+    } else {
+        warn!(
+            "key generated without the NSM's entropy (sources: {})",
+            entropy.join(", ")
+        );
+    }
+    if let Some(path) = derivation_path {
+        if claim["path"].as_str() != Some(path.to_string().as_str()) {
+            return Err(format!("keygen attestation isn't for the key at {}", path));
+        }
+    }
     OpenOptions::new()
         .create(true)
         .write(true)
@@ -0,0 +1,125 @@
+use crate::attestation::ExpectedPcrs;
+use serde_json::{json, Map, Value};
+
+/// SHA-384 PCRs
+const PCR_LEN: usize = 48;
+
+/// the key administration actions (kept for the administrator,
+/// so the key can't become unmanageable)
+const ADMIN_ACTIONS: &[&str] = &[
+    "kms:Create*",
+    "kms:Describe*",
+    "kms:Enable*",
+    "kms:List*",
+    "kms:Put*",
+    "kms:Update*",
+    "kms:Revoke*",
+    "kms:Disable*",
+    "kms:Get*",
+    "kms:Delete*",
+    "kms:TagResource",
+    "kms:UntagResource",
+    "kms:ScheduleKeyDeletion",
+    "kms:CancelKeyDeletion",
+];
+
+/// the account root (`arn:aws:iam::<account>:root`) of an IAM role or user ARN
+fn account_root(arn: &str) -> Result<String, String> {
+    match arn.split(':').collect::<Vec<_>>().as_slice() {
+        ["arn", partition, "iam", "", account, _]
+            if account.len() == 12 && account.chars().all(|c| c.is_ascii_digit()) =>
+        {
+            Ok(format!("arn:{}:iam::{}:root", partition, account))
+        }
+        _ => Err(format!("invalid IAM ARN: {}", arn)),
+    }
+}
+
+/// the key policy that only lets the enclave (with the pinned measurements
+/// and running with the instance's `role_arn`) decrypt the sealed keys;
+/// the instance can encrypt the generated keys and `admin_arn`
+/// (by default, the role's account root) can administer the key
+pub fn generate(
+    pcrs: &ExpectedPcrs,
+    role_arn: &str,
+    admin_arn: Option<&str>,
+) -> Result<Value, String> {
+    if pcrs.is_empty() {
+        return Err("no PCR to pin".to_owned());
+    }
+    let admin_arn = match admin_arn {
+        Some(arn) => arn.to_owned(),
+        None => account_root(role_arn)?,
+    };
+    let mut conditions = Map::new();
+    for (index, pcr) in pcrs.0.iter().enumerate() {
+        if let Some(pcr) = pcr {
+            let pcr = pcr.trim().to_ascii_lowercase();
+            match subtle_encoding::hex::decode(&pcr) {
+                Ok(bytes) if bytes.len() == PCR_LEN => {}
+                _ => return Err(format!("PCR{} isn't a hex-encoded SHA-384 digest", index)),
+            }
+            conditions.insert(
+                format!("kms:RecipientAttestation:PCR{}", index),
+                Value::String(pcr),
+            );
+        }
+    }
+    Ok(json!({
+        "Version": "2012-10-17",
+        "Statement": [
+            {
+                "Sid": "Enable key administration",
+                "Effect": "Allow",
+                "Principal": { "AWS": admin_arn },
+                "Action": ADMIN_ACTIONS,
+                "Resource": "*"
+            },
+            {
+                "Sid": "Allow the generated keys to be sealed",
+                "Effect": "Allow",
+                "Principal": { "AWS": role_arn },
+                "Action": "kms:Encrypt",
+                "Resource": "*"
+            },
+            {
+                "Sid": "Allow the attested enclave to unseal the keys",
+                "Effect": "Allow",
+                "Principal": { "AWS": role_arn },
+                "Action": "kms:Decrypt",
+                "Resource": "*",
+                "Condition": { "StringEqualsIgnoreCase": conditions }
+            }
+        ]
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pins_the_pcrs_for_decryption() {
+        let pcr0 = "AB".repeat(PCR_LEN);
+        let pcrs = ExpectedPcrs([Some(pcr0), None, Some("01".repeat(PCR_LEN))]);
+        let policy = generate(&pcrs, "arn:aws:iam::123456789012:role/tmkms", None).unwrap();
+        let statements = policy["Statement"].as_array().unwrap();
+        assert_eq!(
+            statements[0]["Principal"]["AWS"],
+            "arn:aws:iam::123456789012:root"
+        );
+        let conditions = &statements[2]["Condition"]["StringEqualsIgnoreCase"];
+        assert_eq!(
+            conditions["kms:RecipientAttestation:PCR0"],
+            "ab".repeat(PCR_LEN)
+        );
+        assert!(conditions.get("kms:RecipientAttestation:PCR1").is_none());
+        assert!(generate(
+            &ExpectedPcrs::default(),
+            "arn:aws:iam::123456789012:role/tmkms",
+            None
+        )
+        .is_err());
+        assert!(generate(&pcrs, "tmkms", None).is_err());
+    }
+}
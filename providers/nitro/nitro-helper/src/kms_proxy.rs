@@ -0,0 +1,255 @@
+use crate::shared::VSOCK_HOST_CID;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use tracing::{debug, error, info, warn};
+use vsock::{VsockAddr, VsockListener, VsockStream};
+
+/// the vsock port the enclave's KMS client connects to
+pub const KMS_PROXY_PORT: u32 = 8000;
+
+/// the AWS KMS endpoints (`kms.<region>.amazonaws.com` or `kms-fips.<region>.amazonaws.com` on 443)
+fn is_kms_endpoint(host: &str, port: u16) -> bool {
+    let region = host
+        .strip_prefix("kms.")
+        .or_else(|| host.strip_prefix("kms-fips."))
+        .and_then(|rest| {
+            rest.strip_suffix(".amazonaws.com")
+                .or_else(|| rest.strip_suffix(".amazonaws.com.cn"))
+        });
+    port == 443
+        && region.map_or(false, |region| {
+            !region.is_empty()
+                && region
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        })
+}
+
+/// the server name (SNI) in a TLS record with a ClientHello
+fn client_hello_server_name(record: &[u8]) -> Option<String> {
+    let u16_at = |buf: &[u8], pos: usize| -> Option<usize> {
+        Some(u16::from_be_bytes([*buf.get(pos)?, *buf.get(pos + 1)?]) as usize)
+    };
+    // record: type (22 = handshake), version, length
+    if *record.first()? != 22 {
+        return None;
+    }
+    let hello = record.get(5..)?;
+    // handshake: type (1 = ClientHello), length, version, random
+    if *hello.first()? != 1 {
+        return None;
+    }
+    let mut pos = 4 + 2 + 32;
+    // session id, cipher suites, compression methods
+    pos += 1 + *hello.get(pos)? as usize;
+    pos += 2 + u16_at(hello, pos)?;
+    pos += 1 + *hello.get(pos)? as usize;
+    let end = pos + 2 + u16_at(hello, pos)?;
+    pos += 2;
+    while pos + 4 <= end {
+        let (ext_type, ext_len) = (u16_at(hello, pos)?, u16_at(hello, pos + 2)?);
+        let ext = hello.get(pos + 4..pos + 4 + ext_len)?;
+        if ext_type == 0 {
+            // server name list: length, then type (0 = host name), length, name
+            if *ext.get(2)? != 0 {
+                return None;
+            }
+            let name = ext.get(5..5 + u16_at(ext, 3)?)?;
+            return String::from_utf8(name.to_vec()).ok();
+        }
+        pos += 4 + ext_len;
+    }
+    None
+}
+
+/// where the enclave's connections are forwarded to
+#[derive(Clone)]
+enum Route {
+    /// a single endpoint
+    Fixed(String, u16),
+    /// the KMS endpoint named in the TLS ClientHello (one of these hosts on 443,
+    /// the first one if no name is sent), so the enclave can fail over to another region
+    ServerName(Vec<String>),
+}
+
+/// forwards the enclave's connections to the AWS KMS endpoint
+/// (in place of the external `vsock-proxy`)
+pub struct KmsProxy {
+    listener: VsockListener,
+    route: Route,
+    num_workers: usize,
+    active: Arc<AtomicUsize>,
+}
+
+impl KmsProxy {
+    /// binds the listener for the enclave; the remote endpoint must be a KMS endpoint
+    /// or one of `allowed_endpoints` (`host:port`)
+    pub fn new(
+        local_port: u32,
+        remote_host: &str,
+        remote_port: u16,
+        num_workers: usize,
+        allowed_endpoints: &[String],
+    ) -> Result<Self, String> {
+        let endpoint = format!("{}:{}", remote_host, remote_port);
+        if !is_kms_endpoint(remote_host, remote_port) && !allowed_endpoints.contains(&endpoint) {
+            return Err(format!("the proxied endpoint {} isn't allowed", endpoint));
+        }
+        info!("KMS proxy: vsock port {} -> {}", local_port, endpoint);
+        Self::bind(
+            local_port,
+            Route::Fixed(remote_host.to_owned(), remote_port),
+            num_workers,
+        )
+    }
+
+    fn bind(local_port: u32, route: Route, num_workers: usize) -> Result<Self, String> {
+        let listener = VsockListener::bind(&VsockAddr::new(VSOCK_HOST_CID, local_port))
+            .map_err(|e| format!("failed to bind the KMS proxy: {:?}", e))?;
+        Ok(Self {
+            listener,
+            route,
+            num_workers: num_workers.max(1),
+            active: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// the proxy to the region's KMS endpoint and the ones of the key replicas' regions
+    pub fn for_regions(aws_region: &str, failover_regions: &[String]) -> Result<Self, String> {
+        let hosts: Vec<String> = std::iter::once(aws_region)
+            .chain(failover_regions.iter().map(String::as_str))
+            .map(|region| format!("kms.{}.amazonaws.com", region))
+            .collect();
+        if let Some(host) = hosts.iter().find(|host| !is_kms_endpoint(host, 443)) {
+            return Err(format!("the proxied endpoint {}:443 isn't allowed", host));
+        }
+        info!(
+            "KMS proxy: vsock port {} -> {}",
+            KMS_PROXY_PORT,
+            hosts.join(", ")
+        );
+        Self::bind(KMS_PROXY_PORT, Route::ServerName(hosts), 4)
+    }
+
+    /// reads the first TLS record sent by the enclave
+    fn read_record(client: &mut VsockStream) -> io::Result<Vec<u8>> {
+        let mut record = vec![0u8; 5];
+        client.read_exact(&mut record)?;
+        let len = u16::from_be_bytes([record[3], record[4]]) as usize;
+        record.resize(5 + len, 0);
+        client.read_exact(&mut record[5..])?;
+        Ok(record)
+    }
+
+    fn forward(mut client: VsockStream, route: &Route) -> io::Result<()> {
+        let (host, port, client_hello) = match route {
+            Route::Fixed(host, port) => (host.clone(), *port, vec![]),
+            Route::ServerName(hosts) => {
+                let record = Self::read_record(&mut client)?;
+                let host = match client_hello_server_name(&record) {
+                    Some(name) if hosts.contains(&name) => name,
+                    None => hosts[0].clone(),
+                    Some(name) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::PermissionDenied,
+                            format!("{} isn't allowed", name),
+                        ))
+                    }
+                };
+                (host, 443, record)
+            }
+        };
+        debug!("KMS proxy: forwarding to {}:{}", host, port);
+        let mut server = TcpStream::connect((host.as_str(), port))?;
+        server.write_all(&client_hello)?;
+        let mut client_read = client.try_clone()?;
+        let mut server_write = server.try_clone()?;
+        let upstream = thread::spawn(move || {
+            let _ = io::copy(&mut client_read, &mut server_write);
+            let _ = server_write.shutdown(Shutdown::Write);
+        });
+        let (mut server_read, mut client_write) = (server, client);
+        let _ = io::copy(&mut server_read, &mut client_write);
+        let _ = client_write.shutdown(Shutdown::Both);
+        let _ = upstream.join();
+        Ok(())
+    }
+
+    /// serves the enclave connections in a separate thread
+    /// (up to `num_workers` at a time)
+    pub fn launch(self) {
+        thread::spawn(move || {
+            for conn in self.listener.incoming() {
+                let client = match conn {
+                    Ok(client) => client,
+                    Err(e) => {
+                        error!("KMS proxy connection failed: {:?}", e);
+                        continue;
+                    }
+                };
+                if self.active.load(Ordering::SeqCst) >= self.num_workers {
+                    warn!("KMS proxy: too many connections, refusing a new one");
+                    continue;
+                }
+                self.active.fetch_add(1, Ordering::SeqCst);
+                let active = self.active.clone();
+                let route = self.route.clone();
+                thread::spawn(move || {
+                    if let Err(e) = Self::forward(client, &route) {
+                        warn!("KMS proxy: failed to forward a connection: {:?}", e);
+                    }
+                    debug!("KMS proxy connection closed");
+                    active.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+            error!("KMS proxy listener failed");
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_kms_endpoints_are_allowed() {
+        assert!(is_kms_endpoint("kms.ap-southeast-1.amazonaws.com", 443));
+        assert!(is_kms_endpoint("kms-fips.us-east-1.amazonaws.com", 443));
+        assert!(is_kms_endpoint("kms.cn-north-1.amazonaws.com.cn", 443));
+        assert!(!is_kms_endpoint("kms.ap-southeast-1.amazonaws.com", 80));
+        assert!(!is_kms_endpoint("s3.ap-southeast-1.amazonaws.com", 443));
+        assert!(!is_kms_endpoint("kms.evil.com", 443));
+        assert!(!is_kms_endpoint("kms..amazonaws.com", 443));
+    }
+
+    #[test]
+    fn routes_by_server_name() {
+        let name = b"kms.us-west-2.amazonaws.com";
+        let mut server_name = vec![0, (name.len() + 3) as u8, 0, 0, name.len() as u8];
+        server_name.extend_from_slice(name);
+        let mut extensions = vec![0, 0, 0, server_name.len() as u8];
+        extensions.extend_from_slice(&server_name);
+        let mut hello = vec![3, 3];
+        hello.extend_from_slice(&[0u8; 32]);
+        // no session id, one cipher suite, no compression
+        hello.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+        let mut handshake = vec![1, 0];
+        handshake.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+        handshake.extend_from_slice(&hello);
+        let mut record = vec![22, 3, 1];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        assert_eq!(
+            client_hello_server_name(&record).as_deref(),
+            Some("kms.us-west-2.amazonaws.com")
+        );
+        assert_eq!(client_hello_server_name(&record[..40]), None);
+        record[0] = 23;
+        assert_eq!(client_hello_server_name(&record), None);
+    }
+}
@@ -0,0 +1,222 @@
+use crate::http::http_request;
+use crate::state_store::StateStore;
+use serde::Deserialize;
+use serde_json::json;
+use std::cmp::Ordering;
+use tmkms_light::chain::state::{MacedState, StateError};
+use tracing::{debug, warn};
+
+fn base64(value: &[u8]) -> String {
+    String::from_utf8(subtle_encoding::base64::encode(value)).expect("base64 is valid UTF-8")
+}
+
+/// the KV store API
+enum KvApi {
+    /// Consul's KV API (`/v1/kv`), with an optional ACL token
+    Consul { token: Option<String> },
+    /// etcd's v3 JSON gateway (`/v3/kv`)
+    Etcd,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConsulEntry {
+    modify_index: u64,
+    value: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct EtcdRange {
+    #[serde(default)]
+    kvs: Vec<EtcdKv>,
+}
+
+#[derive(Deserialize)]
+struct EtcdKv {
+    /// int64 values are strings in the JSON gateway
+    mod_revision: String,
+    #[serde(default)]
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct EtcdTxn {
+    #[serde(default)]
+    succeeded: bool,
+}
+
+/// the state (the high watermark) in Consul or etcd, shared by the primary and standby signers;
+/// each update is a compare-and-swap, so a signer can't overwrite a more advanced state
+pub struct KvStore {
+    api: KvApi,
+    addr: String,
+    key: String,
+}
+
+impl KvStore {
+    pub fn consul(addr: String, key: String, token: Option<String>) -> Self {
+        Self {
+            api: KvApi::Consul { token },
+            addr,
+            key,
+        }
+    }
+
+    pub fn etcd(addr: String, key: String) -> Self {
+        Self {
+            api: KvApi::Etcd,
+            addr,
+            key,
+        }
+    }
+
+    /// the stored value and its modify index / revision (0 if the key doesn't exist)
+    fn get(&self) -> Result<(Option<Vec<u8>>, u64), String> {
+        let value = match &self.api {
+            KvApi::Consul { token } => {
+                let headers = Self::consul_headers(token);
+                let path = format!("/v1/kv/{}", self.key);
+                match http_request(&self.addr, "GET", &path, &headers, &[])? {
+                    (404, _) => None,
+                    (200, body) => {
+                        let entries: Vec<ConsulEntry> =
+                            serde_json::from_slice(&body).map_err(|e| format!("{:?}", e))?;
+                        entries
+                            .into_iter()
+                            .next()
+                            .map(|entry| (entry.value.unwrap_or_default(), entry.modify_index))
+                    }
+                    (status, _) => return Err(format!("consul responded with {}", status)),
+                }
+            }
+            KvApi::Etcd => {
+                let request = json!({ "key": base64(self.key.as_bytes()) });
+                let body = serde_json::to_vec(&request).map_err(|e| format!("{:?}", e))?;
+                let range: EtcdRange = match http_request(
+                    &self.addr,
+                    "POST",
+                    "/v3/kv/range",
+                    &[("Content-Type", "application/json")],
+                    &body,
+                )? {
+                    (200, body) => serde_json::from_slice(&body).map_err(|e| format!("{:?}", e))?,
+                    (status, _) => return Err(format!("etcd responded with {}", status)),
+                };
+                match range.kvs.into_iter().next() {
+                    Some(kv) => Some((
+                        kv.value,
+                        kv.mod_revision
+                            .parse()
+                            .map_err(|e| format!("invalid etcd revision: {:?}", e))?,
+                    )),
+                    None => None,
+                }
+            }
+        };
+        match value {
+            Some((encoded, index)) => {
+                let value = subtle_encoding::base64::decode(encoded)
+                    .map_err(|e| format!("invalid stored value: {:?}", e))?;
+                Ok((Some(value), index))
+            }
+            None => Ok((None, 0)),
+        }
+    }
+
+    /// sets the value if it wasn't modified since `index` (0: if the key doesn't exist)
+    fn compare_and_swap(&self, value: &[u8], index: u64) -> Result<bool, String> {
+        match &self.api {
+            KvApi::Consul { token } => {
+                let headers = Self::consul_headers(token);
+                let path = format!("/v1/kv/{}?cas={}", self.key, index);
+                match http_request(&self.addr, "PUT", &path, &headers, value)? {
+                    (200, body) => Ok(String::from_utf8_lossy(&body).trim() == "true"),
+                    (status, _) => Err(format!("consul responded with {}", status)),
+                }
+            }
+            KvApi::Etcd => {
+                let key = base64(self.key.as_bytes());
+                let request = json!({
+                    "compare": [{
+                        "key": key,
+                        "result": "EQUAL",
+                        "target": "MOD",
+                        "mod_revision": index.to_string(),
+                    }],
+                    "success": [{
+                        "request_put": { "key": key, "value": base64(value) }
+                    }],
+                });
+                let body = serde_json::to_vec(&request).map_err(|e| format!("{:?}", e))?;
+                match http_request(
+                    &self.addr,
+                    "POST",
+                    "/v3/kv/txn",
+                    &[("Content-Type", "application/json")],
+                    &body,
+                )? {
+                    (200, body) => {
+                        let txn: EtcdTxn =
+                            serde_json::from_slice(&body).map_err(|e| format!("{:?}", e))?;
+                        Ok(txn.succeeded)
+                    }
+                    (status, _) => Err(format!("etcd responded with {}", status)),
+                }
+            }
+        }
+    }
+
+    fn consul_headers(token: &Option<String>) -> Vec<(&str, &str)> {
+        token
+            .iter()
+            .map(|token| ("X-Consul-Token", token.as_str()))
+            .collect()
+    }
+
+    fn read_state(&self) -> Result<(Option<MacedState>, u64), StateError> {
+        let (value, index) = self.get().map_err(StateError::sync_other_error)?;
+        let state = value
+            .map(|value| {
+                serde_json::from_slice(&value)
+                    .map_err(|e| StateError::sync_enc_dec_error(self.key.clone(), e))
+            })
+            .transpose()?;
+        Ok((state, index))
+    }
+}
+
+impl StateStore for KvStore {
+    fn load(&mut self) -> Result<Option<MacedState>, StateError> {
+        Ok(self.read_state()?.0)
+    }
+
+    /// swaps in the new state unless another signer stored a more advanced (or conflicting) one
+    fn persist(&mut self, new_state: &MacedState) -> Result<(), StateError> {
+        let value = serde_json::to_vec(new_state)
+            .map_err(|e| StateError::sync_enc_dec_error(self.key.clone(), e))?;
+        loop {
+            let (current, index) = self.read_state()?;
+            if let Some(current) = current {
+                let behind = match current.state.cmp(&new_state.state) {
+                    Ordering::Greater => true,
+                    Ordering::Equal => current.state.block_id != new_state.state.block_id,
+                    Ordering::Less => false,
+                };
+                if behind {
+                    return Err(StateError::sync_other_error(format!(
+                        "the shared state in {} ({}) is ahead of {}",
+                        self.key, current.state, new_state.state
+                    )));
+                }
+            }
+            if self
+                .compare_and_swap(&value, index)
+                .map_err(StateError::sync_other_error)?
+            {
+                debug!("state swapped in {} (was at {})", self.key, index);
+                return Ok(());
+            }
+            warn!("{} was modified concurrently, retrying", self.key);
+        }
+    }
+}
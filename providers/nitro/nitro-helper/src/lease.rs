@@ -0,0 +1,232 @@
+//! lease-based active/passive failover: only the holder of the lease signs
+
+use crate::mux_server::ChannelListener;
+use crate::peer_auth::{PeerChannel, PeerKey};
+use serde::{Deserialize, Serialize};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tmkms_light::chain::state::{consensus, LeaseRequest, Leases};
+use tmkms_light::utils::{read_u16_payload, write_u16_payload};
+use tmkms_nitro_helper::channel::ChannelStream;
+use tracing::{debug, error, info, warn};
+
+/// settings of the lease a signer must hold to sign
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LeaseConfig {
+    /// address (`host:port`) of the lease service
+    pub server_addr: String,
+    /// name of this signer instance
+    pub holder_id: String,
+    /// lease name (the chain id by default)
+    pub name: Option<String>,
+    /// for how long the lease is acquired
+    #[serde(default = "default_duration_secs")]
+    pub duration_secs: u64,
+    /// how often the lease is renewed
+    #[serde(default = "default_renew_secs")]
+    pub renew_secs: u64,
+    /// vsock port to listen on for the enclave's lease checks
+    #[serde(default = "default_enclave_lease_port")]
+    pub enclave_lease_port: u32,
+}
+
+fn default_duration_secs() -> u64 {
+    10
+}
+
+fn default_renew_secs() -> u64 {
+    3
+}
+
+fn default_enclave_lease_port() -> u32 {
+    5559
+}
+
+/// acquires / renews the lease and answers the enclave's checks
+pub struct LeaseKeeper {
+    config: LeaseConfig,
+    name: String,
+    /// authenticates the messages with the lease service
+    peer_key: PeerKey,
+    /// until when the lease is held (measured from the sending of the request)
+    valid_until: Mutex<Option<Instant>>,
+}
+
+impl LeaseKeeper {
+    pub fn new(config: LeaseConfig, default_name: String, peer_key: PeerKey) -> Arc<Self> {
+        let name = config.name.clone().unwrap_or(default_name);
+        Arc::new(Self {
+            config,
+            name,
+            peer_key,
+            valid_until: Mutex::new(None),
+        })
+    }
+
+    fn is_held(&self) -> bool {
+        self.valid_until
+            .lock()
+            .expect("lease poisoned")
+            .map_or(false, |t| Instant::now() < t)
+    }
+
+    /// asks the lease service for the lease; returns the granted duration
+    fn acquire(&self) -> Result<u64, String> {
+        let request = LeaseRequest {
+            name: self.name.clone(),
+            holder_id: self.config.holder_id.clone(),
+            duration_secs: self.config.duration_secs,
+        };
+        let stream = TcpStream::connect(&self.config.server_addr)
+            .map_err(|e| format!("failed to connect to the lease service: {}", e))?;
+        let timeout = Some(Duration::from_secs(self.config.renew_secs.max(1)));
+        stream
+            .set_read_timeout(timeout)
+            .and_then(|_| stream.set_write_timeout(timeout))
+            .map_err(|e| e.to_string())?;
+        let mut channel = PeerChannel::connect(stream, self.peer_key.clone())?;
+        let json_raw = serde_json::to_vec(&request).map_err(|e| format!("{:?}", e))?;
+        channel.send(&json_raw)?;
+        let json_raw = channel.recv()?;
+        serde_json::from_slice::<Result<u64, String>>(&json_raw).map_err(|e| format!("{:?}", e))?
+    }
+
+    fn renew(&self) {
+        let sent = Instant::now();
+        match self.acquire() {
+            Ok(secs) => {
+                let mut valid_until = self.valid_until.lock().expect("lease poisoned");
+                if valid_until.map_or(true, |t| t <= sent) {
+                    info!("acquired the {} lease", self.name);
+                }
+                *valid_until = Some(sent + Duration::from_secs(secs));
+            }
+            Err(e) => {
+                if self.is_held() {
+                    warn!("failed to renew the {} lease: {}", self.name, e);
+                } else {
+                    debug!("{} lease not acquired: {}", self.name, e);
+                }
+            }
+        }
+    }
+
+    /// answers the enclave's lease check before it signs at `state`
+    fn check(&self, stream: &mut ChannelStream) -> Result<(), String> {
+        let json_raw = read_u16_payload(stream).map_err(|e| format!("{}", e))?;
+        let state: consensus::State =
+            serde_json::from_slice(&json_raw).map_err(|e| format!("{:?}", e))?;
+        let result = if self.is_held() {
+            Ok(())
+        } else {
+            warn!("refusing to sign at {}: the lease isn't held", state);
+            Err(format!("the {} lease isn't held", self.name))
+        };
+        let ack = serde_json::to_vec(&result).map_err(|e| format!("{:?}", e))?;
+        write_u16_payload(stream, &ack).map_err(|e| format!("{:?}", e))
+    }
+
+    /// renews the lease and serves the enclave connections in separate threads
+    pub fn launch(self: Arc<Self>) -> Result<(), String> {
+        let vsock_listener = ChannelListener::bind(self.config.enclave_lease_port)
+            .map_err(|e| format!("failed to bind the lease listener: {:?}", e))?;
+        let keeper = self.clone();
+        thread::spawn(move || loop {
+            keeper.renew();
+            thread::sleep(Duration::from_secs(keeper.config.renew_secs.max(1)));
+        });
+        thread::spawn(move || {
+            while let Ok((mut stream, _)) = vsock_listener.accept() {
+                info!("vsock lease connection established");
+                while let Ok(()) = self.check(&mut stream) {}
+                warn!("vsock lease connection lost");
+            }
+            error!("lease listener failed");
+        });
+        Ok(())
+    }
+}
+
+/// grants the leases (kept in memory) to the signers (holding the peer key)
+pub struct LeaseServer {
+    leases: Arc<Mutex<Leases>>,
+    listener: TcpListener,
+    peer_key: PeerKey,
+    started: Instant,
+    max_lease: Duration,
+}
+
+impl LeaseServer {
+    /// `max_lease_secs`: the longest lease granted; no lease is granted for as long
+    /// after the start (as a holder may still hold one granted before a restart)
+    pub fn new(
+        listen_addr: SocketAddr,
+        peer_key_path: &Path,
+        max_lease_secs: u64,
+    ) -> Result<Self, String> {
+        let peer_key = PeerKey::load_or_generate(peer_key_path)?;
+        let listener = TcpListener::bind(listen_addr)
+            .map_err(|e| format!("failed to bind the lease listener: {:?}", e))?;
+        Ok(Self {
+            leases: Arc::new(Mutex::new(Leases::default())),
+            listener,
+            peer_key,
+            started: Instant::now(),
+            max_lease: Duration::from_secs(max_lease_secs),
+        })
+    }
+
+    /// handles a lease request and returns the result (the granted seconds)
+    fn handle(
+        leases: &Mutex<Leases>,
+        started: Instant,
+        max_lease: Duration,
+        channel: &mut PeerChannel<TcpStream>,
+    ) -> Result<(), String> {
+        let json_raw = channel.recv()?;
+        let result = serde_json::from_slice::<LeaseRequest>(&json_raw)
+            .map_err(|e| format!("invalid lease request: {:?}", e))
+            .and_then(|mut request| {
+                if started.elapsed() < max_lease {
+                    return Err("the lease service has just started".to_owned());
+                }
+                request.duration_secs = request.duration_secs.min(max_lease.as_secs());
+                let secs = request.duration_secs;
+                leases
+                    .lock()
+                    .map_err(|_| "leases poisoned".to_owned())?
+                    .acquire(request, Instant::now())
+                    .map(|()| secs)
+            });
+        let ack = serde_json::to_vec(&result).map_err(|e| format!("{:?}", e))?;
+        channel.send(&ack)
+    }
+
+    /// serves the connections (each in a separate thread)
+    pub fn run(self) -> Result<(), String> {
+        for stream in self.listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let leases = self.leases.clone();
+                    let (started, max_lease) = (self.started, self.max_lease);
+                    let peer_key = self.peer_key.clone();
+                    thread::spawn(move || match PeerChannel::accept(stream, peer_key) {
+                        Ok(mut channel) => {
+                            while let Ok(()) =
+                                Self::handle(&leases, started, max_lease, &mut channel)
+                            {
+                            }
+                        }
+                        Err(e) => warn!("lease connection failed: {}", e),
+                    });
+                }
+                Err(e) => error!("lease connection failed: {}", e),
+            }
+        }
+        Ok(())
+    }
+}
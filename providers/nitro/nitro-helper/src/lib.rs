@@ -1,4 +1,21 @@
 pub use shared::*;
 
+pub mod attestation_doc;
+pub mod audit;
+pub mod backoff;
+pub mod backup;
+pub mod channel;
+pub mod codec;
+pub mod config_push;
+pub mod control;
+pub mod enclave_policy;
+pub mod entropy;
+pub mod key_shares;
+pub mod mux;
+pub mod provisioning;
+pub mod schema;
 pub mod shared;
+pub mod slip10;
+pub mod span_export;
+pub mod time_sync;
 pub mod tracing_layer;
@@ -1,23 +1,91 @@
+mod admin;
+mod alerting;
+mod approval;
+mod attestation;
+mod attestation_archive;
+mod attestation_server;
+mod audit_server;
+mod chain_rpc;
 mod command;
 mod config;
+mod credential_refresh;
+mod dynamodb_store;
+mod eif;
 mod enclave_log_server;
+mod ha;
+mod halt_server;
+mod health;
+mod http;
+mod imds;
 mod key_utils;
+mod kms_policy;
+mod kms_proxy;
+mod kv_store;
+mod lease;
+mod metrics_server;
+mod monotonic_server;
+mod mux_server;
+mod otlp;
+mod peer_auth;
 mod proxy;
-mod shared;
+mod recorder;
 mod state;
+mod state_store;
+mod sts;
+mod supervisor;
+mod systemd;
+mod time_server;
+mod watermark_server;
 
+use admin::{admin_request, AdminCommand, AdminRequest};
+use attestation::ExpectedPcrs;
+use command::backup::{backup, operator_keygen, parse_x25519_key, restore_finish, restore_share};
+use command::bench::{bench, BenchOpt, MessageMix};
+use command::chain::chain_control;
+use command::derive::derive;
+use command::key_shares::key_shares;
 use command::launch_all::launch_all;
-use command::nitro_enclave::{describe_enclave, run_enclave, stop_enclave};
-use command::{check_vsock_proxy, init, start};
-use config::{EnclaveOpt, VSockProxyOpt};
+use command::migrate_state::migrate_state;
+use command::nitro_enclave::{
+    describe_enclave, enclave_status, run_enclave, start_enclave, stop_enclave_gracefully,
+};
+use command::provision::{provision_begin, provision_finish, provision_seal};
+use command::replay::replay;
+use command::rewrap::{rewrap, SealedKey};
+use command::sign_payload::sign_payload;
+use command::verify_sig::{verify_sig, SignedMessage};
+use command::{
+    approval_server, attestation_export, attestation_verify, attestation_verify_bundle,
+    audit_verify, check_vsock_proxy, eif_describe, init, kms_policy_generate, lease_server,
+    monotonic_server, set_enclave_log_level, signing_status, start, state_server, watermark_server,
+    CommandError,
+};
+use config::{ChainControlOpt, EnclaveOpt, KmsKeyOpt, LogFormat, VSockProxyOpt};
 
+use crate::alerting::AlertLayer;
 use crate::command::nitro_enclave::run_vsock_proxy;
 use crate::config::{EnclaveConfig, NitroSignOpt};
+use crate::kms_proxy::KmsProxy;
+use crate::otlp::OtlpSink;
 use clap::Parser;
+use enclave_log_server::ENCLAVE_LOG_TARGET;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::mpsc::channel;
+use std::time::Duration;
+use tendermint::chain;
 use tmkms_light::utils::PubkeyDisplay;
+use tmkms_nitro_helper::backup::ShamirThreshold;
+use tmkms_nitro_helper::channel::enable_dev_tcp;
+use tmkms_nitro_helper::schema::{Status, StatusV1};
+use tmkms_nitro_helper::shared;
+use tmkms_nitro_helper::slip10::DerivationPath;
+use tmkms_nitro_helper::span_export::SpanLayer;
+use tmkms_nitro_helper::{ChainControlAction, LogLevel, NitroChainControl, NitroRequest};
+use tracing::level_filters::LevelFilter;
 use tracing::Level;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::FmtSubscriber;
 
 /// Helper sub-commands
@@ -31,6 +99,489 @@ enum TmkmsLight {
     Helper(CommandHelper),
     #[command(subcommand)]
     Enclave(CommandEnclave),
+    #[command(subcommand)]
+    Chain(CommandChain),
+    #[command(subcommand)]
+    Audit(CommandAudit),
+    #[command(subcommand)]
+    Attestation(CommandAttestation),
+    #[command(subcommand)]
+    Kms(CommandKms),
+    #[command(subcommand)]
+    Key(CommandKey),
+    #[command(subcommand)]
+    Eif(CommandEif),
+    #[command(
+        name = "bench",
+        about = "drive the signer with a simulated validator and report the latencies"
+    )]
+    /// acts as the validator the signer connects to (its `address` must be this Unix socket)
+    /// and sends it a synthetic workload; it signs with the consensus key,
+    /// so it's only meant for a test chain ID and state
+    Bench {
+        /// Unix socket to listen on (the signer's validator `address`)
+        #[arg(long)]
+        socket: PathBuf,
+        /// chain ID of the requests
+        #[arg(long)]
+        chain_id: chain::Id,
+        /// requests per second
+        #[arg(long, default_value_t = 10)]
+        rate: u32,
+        /// how many requests to send
+        #[arg(long, default_value_t = 1000)]
+        requests: u64,
+        /// weights of the request kinds
+        #[arg(long, default_value = "prevote=45,precommit=45,proposal=5,ping=5")]
+        mix: MessageMix,
+        /// the signing requests start above this height
+        #[arg(long, default_value_t = 0)]
+        start_height: i64,
+        /// log level, default: info, -v: info, -vv: debug, -vvv: trace
+        #[arg(short, action = clap::ArgAction::Count)]
+        v: u32,
+    },
+    #[command(
+        name = "replay",
+        about = "feed a privval capture to a dry-run signer and report the diverging responses"
+    )]
+    /// replays the requests of `privval_capture_path` to an in-process signer with the config's
+    /// chain ID and signing policy, a throwaway key and the given state (never persisted)
+    Replay {
+        /// the capture file
+        #[arg(long)]
+        capture: PathBuf,
+        /// the signer's state before the capture (the default state if not set)
+        #[arg(long)]
+        state: Option<PathBuf>,
+        #[arg(short, default_value = "tmkms.toml")]
+        config_path: PathBuf,
+        /// log level, default: info, -v: info, -vv: debug, -vvv: trace
+        #[arg(short, action = clap::ArgAction::Count)]
+        v: u32,
+    },
+    #[command(
+        name = "verify-sig",
+        about = "verify a consensus signature of sign-bytes, a vote or a proposal"
+    )]
+    VerifySig {
+        /// the raw sign-bytes
+        #[arg(long, conflicts_with_all = ["vote", "proposal"], required_unless_present_any = ["vote", "proposal"])]
+        sign_bytes: Option<PathBuf>,
+        /// a JSON-encoded vote (its canonical sign-bytes are verified)
+        #[arg(long, conflicts_with = "proposal")]
+        vote: Option<PathBuf>,
+        /// a JSON-encoded proposal (its canonical sign-bytes are verified)
+        #[arg(long)]
+        proposal: Option<PathBuf>,
+        /// the chain ID of the vote or proposal
+        #[arg(long)]
+        chain_id: Option<chain::Id>,
+        /// base64-encoded signature (the vote's or proposal's one if not set)
+        #[arg(long)]
+        signature: Option<String>,
+        /// the consensus pubkey (base64 or bech32)
+        #[arg(long)]
+        pubkey: String,
+    },
+    #[command(
+        name = "admin",
+        about = "send a command to the running helper's admin socket"
+    )]
+    Admin {
+        #[arg(value_enum)]
+        command: AdminCommand,
+        /// the chain's session (all sessions if not set)
+        #[arg(long)]
+        chain_id: Option<chain::Id>,
+        /// tmkms.toml file path (with the `[admin]` socket)
+        #[arg(short, default_value = "tmkms.toml")]
+        config_path: PathBuf,
+    },
+    #[command(
+        name = "status",
+        about = "print the running helper's connections and signing statistics"
+    )]
+    Status {
+        /// tmkms.toml file path (with `health_listen_addr`)
+        #[arg(short, default_value = "tmkms.toml")]
+        config_path: PathBuf,
+    },
+}
+
+/// sealed key sub-commands
+#[derive(Debug, Parser)]
+enum CommandKey {
+    #[command(
+        name = "rewrap",
+        about = "re-encrypt a sealed key with another AWS KMS key"
+    )]
+    Rewrap {
+        /// the sealed key to re-encrypt
+        #[arg(value_enum)]
+        key: SealedKey,
+        #[arg(short, default_value = "tmkms.toml")]
+        config_path: PathBuf,
+        /// AWS KMS key to encrypt it with
+        #[arg(short)]
+        kms_key_id: String,
+        #[arg(long)]
+        cid: Option<u32>,
+    },
+    #[command(
+        name = "derive",
+        about = "print the consensus key derived from the sealed master seed at another path"
+    )]
+    Derive {
+        #[arg(short, default_value = "tmkms.toml")]
+        config_path: PathBuf,
+        /// path of the key (e.g. `m/44'/118'/1'/0'/0'`)
+        #[arg(long)]
+        derivation_path: DerivationPath,
+        #[arg(short)]
+        pubkey_display: Option<PubkeyDisplay>,
+        #[arg(short)]
+        bech32_prefix: Option<String>,
+        #[arg(long)]
+        cid: Option<u32>,
+    },
+    #[command(subcommand)]
+    Provision(CommandKeyProvision),
+    #[command(
+        name = "operator-keygen",
+        about = "generate an operator's X25519 key for the backup shares"
+    )]
+    OperatorKeygen {
+        /// where to write the (base64-encoded) secret key
+        #[arg(short, default_value = "operator.key")]
+        output: PathBuf,
+    },
+    #[command(
+        name = "backup",
+        about = "split the consensus key into Shamir shares encrypted to the operators' keys"
+    )]
+    Backup {
+        #[arg(short, default_value = "tmkms.toml")]
+        config_path: PathBuf,
+        /// `k/n`: any k of the n shares restore the key
+        #[arg(long)]
+        shamir: ShamirThreshold,
+        /// (base64-encoded) X25519 public key of an operator (one share for each, in order)
+        #[arg(short, long = "recipient", value_parser = parse_x25519_key)]
+        recipients: Vec<[u8; 32]>,
+        #[arg(short, default_value = "backup.json")]
+        output: PathBuf,
+        #[arg(long)]
+        cid: Option<u32>,
+    },
+    #[command(subcommand)]
+    Restore(CommandKeyRestore),
+    #[command(
+        name = "sign-payload",
+        about = "sign an arbitrary payload with the consensus key (as a proof of possession)"
+    )]
+    SignPayload {
+        #[arg(short, default_value = "tmkms.toml")]
+        config_path: PathBuf,
+        /// the payload to sign (domain-separated, so it can't be a consensus message)
+        #[arg(short)]
+        file: PathBuf,
+        #[arg(long)]
+        cid: Option<u32>,
+    },
+}
+
+/// restoring the consensus key from its backup shares
+#[derive(Debug, Parser)]
+enum CommandKeyRestore {
+    #[command(
+        name = "share",
+        about = "re-encrypt an operator's share to the attested enclave key (offline)"
+    )]
+    Share {
+        /// the backup written by `key backup`
+        #[arg(long, default_value = "backup.json")]
+        backup: PathBuf,
+        /// the attestation document written by `key provision begin`
+        #[arg(short, default_value = "provisioning.b64")]
+        file: PathBuf,
+        /// the operator's secret key
+        #[arg(short, default_value = "operator.key")]
+        key: PathBuf,
+        /// config with the `expected_pcr*` to check the attestation against
+        #[arg(short, default_value = "tmkms.toml")]
+        config_path: PathBuf,
+        #[arg(short, default_value = "share.json")]
+        output: PathBuf,
+    },
+    #[command(
+        name = "finish",
+        about = "reassemble and seal the consensus key from the re-encrypted shares"
+    )]
+    Finish {
+        #[arg(short, default_value = "tmkms.toml")]
+        config_path: PathBuf,
+        /// the backup written by `key backup`
+        #[arg(long, default_value = "backup.json")]
+        backup: PathBuf,
+        /// a share written by `key restore share`
+        #[arg(short, long = "share")]
+        shares: Vec<PathBuf>,
+        /// AWS KMS key to seal the key with
+        #[arg(short)]
+        kms_key_id: String,
+        #[arg(short)]
+        pubkey_display: Option<PubkeyDisplay>,
+        #[arg(short)]
+        bech32_prefix: Option<String>,
+        #[arg(long)]
+        cid: Option<u32>,
+    },
+}
+
+/// provisioning the consensus key from a BIP-39 mnemonic
+#[derive(Debug, Parser)]
+enum CommandKeyProvision {
+    #[command(
+        name = "begin",
+        about = "get a one-time attested enclave key to encrypt the mnemonic to"
+    )]
+    Begin {
+        #[arg(short, default_value = "tmkms.toml")]
+        config_path: PathBuf,
+        /// where to write the (base64-encoded) attestation document
+        #[arg(short, default_value = "provisioning.b64")]
+        output: PathBuf,
+        #[arg(long)]
+        cid: Option<u32>,
+    },
+    #[command(
+        name = "seal",
+        about = "encrypt the mnemonic (from stdin) to the attested enclave key (offline)"
+    )]
+    Seal {
+        /// the attestation document written by `begin`
+        #[arg(short, default_value = "provisioning.b64")]
+        file: PathBuf,
+        /// config with the `expected_pcr*` to check the attestation against
+        #[arg(short, default_value = "tmkms.toml")]
+        config_path: PathBuf,
+        /// where to write the encrypted mnemonic
+        #[arg(short, default_value = "mnemonic.json")]
+        output: PathBuf,
+    },
+    #[command(
+        name = "finish",
+        about = "seal the mnemonic's seed and print the consensus key derived from it"
+    )]
+    Finish {
+        #[arg(short, default_value = "tmkms.toml")]
+        config_path: PathBuf,
+        /// the encrypted mnemonic written by `seal`
+        #[arg(short, default_value = "mnemonic.json")]
+        file: PathBuf,
+        /// AWS KMS key to seal the seed with
+        #[arg(short)]
+        kms_key_id: String,
+        /// path of the consensus key (e.g. `m/44'/118'/0'/0'/0'`)
+        #[arg(long)]
+        derivation_path: DerivationPath,
+        #[arg(short)]
+        pubkey_display: Option<PubkeyDisplay>,
+        #[arg(short)]
+        bech32_prefix: Option<String>,
+        #[arg(long)]
+        cid: Option<u32>,
+    },
+}
+
+/// AWS KMS sub-commands
+#[derive(Debug, Parser)]
+enum CommandKms {
+    #[command(subcommand)]
+    Policy(CommandKmsPolicy),
+}
+
+/// AWS KMS key policy sub-commands
+#[derive(Debug, Parser)]
+enum CommandKmsPolicy {
+    #[command(
+        name = "generate",
+        about = "print the key policy only letting the attested enclave decrypt the sealed keys"
+    )]
+    Generate {
+        /// file with a base64-encoded attestation document (its PCR0, PCR1 and PCR2 are pinned)
+        #[arg(short)]
+        file: Option<PathBuf>,
+        /// config with the `expected_pcr*` to pin (if no attestation document or PCR is given)
+        #[arg(short)]
+        config_path: Option<PathBuf>,
+        /// (hex-encoded) PCR0 to pin
+        #[arg(long)]
+        pcr0: Option<String>,
+        /// (hex-encoded) PCR1 to pin
+        #[arg(long)]
+        pcr1: Option<String>,
+        /// (hex-encoded) PCR2 to pin
+        #[arg(long)]
+        pcr2: Option<String>,
+        /// ARN of the instance's IAM role
+        #[arg(long)]
+        role_arn: String,
+        /// ARN of the key administrator (default: the role's account root)
+        #[arg(long)]
+        admin_arn: Option<String>,
+    },
+}
+
+/// enclave attestation sub-commands
+#[derive(Debug, Parser)]
+enum CommandAttestation {
+    #[command(
+        name = "verify",
+        about = "check the enclave's measurements against the pinned PCRs"
+    )]
+    Verify {
+        #[arg(short, default_value = "tmkms.toml")]
+        config_path: PathBuf,
+        /// file with a base64-encoded attestation document
+        /// (if not set, the attestation is requested from the running enclave)
+        #[arg(short)]
+        file: Option<PathBuf>,
+        #[arg(long)]
+        cid: Option<u32>,
+    },
+    #[command(
+        name = "export",
+        about = "export the archived attestation documents as a signed bundle"
+    )]
+    Export {
+        #[arg(short, default_value = "tmkms.toml")]
+        config_path: PathBuf,
+        /// bundle path
+        #[arg(short)]
+        output: PathBuf,
+        /// Ed25519 key signing the bundle (generated if it doesn't exist)
+        #[arg(long, default_value = "publisher.key")]
+        key: PathBuf,
+        /// only the attestations received at or after this time (UNIX seconds)
+        #[arg(long)]
+        since: Option<u64>,
+    },
+    #[command(
+        name = "verify-bundle",
+        about = "check the signature and metadata of an attestation bundle"
+    )]
+    VerifyBundle {
+        /// bundle path
+        #[arg(short)]
+        file: PathBuf,
+        /// base64-encoded publisher key (the bundle's one is trusted if not set)
+        #[arg(long)]
+        public_key: Option<String>,
+    },
+}
+
+/// enclave image file sub-commands
+#[derive(Debug, Parser)]
+enum CommandEif {
+    #[command(
+        name = "describe",
+        about = "compute the PCRs of an enclave image file (and check them against the pinned ones)"
+    )]
+    Describe {
+        /// enclave image file
+        #[arg(short)]
+        file: PathBuf,
+        /// config with the pinned PCRs (`expected_pcr0`, `expected_pcr1`, `expected_pcr2`)
+        #[arg(short)]
+        config_path: Option<PathBuf>,
+    },
+}
+
+/// audit log sub-commands
+#[derive(Debug, Parser)]
+enum CommandAudit {
+    #[command(
+        name = "verify",
+        about = "verify the hash chain of the audit log and the enclave's anchors"
+    )]
+    Verify {
+        /// audit log path
+        #[arg(short)]
+        file: PathBuf,
+        /// tmkms.toml file path (with `expected_consensus_pubkey`)
+        #[arg(short)]
+        config_path: Option<PathBuf>,
+        /// base64-encoded consensus public key the anchors must be signed with
+        /// (instead of the configured `expected_consensus_pubkey`)
+        #[arg(long)]
+        public_key: Option<String>,
+        /// accept records written after the last anchor (not covered yet)
+        #[arg(long)]
+        allow_unanchored_tail: bool,
+    },
+}
+
+/// chain session sub-commands (e.g. to halt one chain for an upgrade)
+#[derive(Debug, Parser)]
+enum CommandChain {
+    #[command(name = "pause", about = "refuse signing for a chain")]
+    Pause {
+        #[arg(long)]
+        chain_id: chain::Id,
+        #[command(flatten)]
+        opt: ChainControlOpt,
+    },
+    #[command(name = "resume", about = "resume signing for a chain")]
+    Resume {
+        #[arg(long)]
+        chain_id: chain::Id,
+        #[command(flatten)]
+        opt: ChainControlOpt,
+    },
+    #[command(name = "stop", about = "stop a chain's session")]
+    Stop {
+        #[arg(long)]
+        chain_id: chain::Id,
+        #[command(flatten)]
+        opt: ChainControlOpt,
+    },
+    #[command(
+        name = "drain",
+        about = "refuse signing for a chain once its in-flight request is handled"
+    )]
+    Drain {
+        #[arg(long)]
+        chain_id: chain::Id,
+        #[command(flatten)]
+        opt: ChainControlOpt,
+    },
+    #[command(
+        name = "maintenance",
+        about = "refuse signing for a chain with a maintenance error (pings are still answered)"
+    )]
+    Maintenance {
+        #[arg(long)]
+        chain_id: chain::Id,
+        #[command(flatten)]
+        opt: ChainControlOpt,
+    },
+    #[command(
+        name = "allow-height-jump",
+        about = "allow a chain's next signing request to exceed `max_height_jump` (once)"
+    )]
+    AllowHeightJump {
+        #[arg(long)]
+        chain_id: chain::Id,
+        #[command(flatten)]
+        opt: ChainControlOpt,
+    },
+    #[command(name = "status", about = "get the status of chain sessions")]
+    Status {
+        #[command(flatten)]
+        opt: ChainControlOpt,
+    },
 }
 
 /// enclave sub-commands
@@ -51,6 +602,46 @@ enum CommandEnclave {
         /// Stop the enclave cid
         #[arg(long)]
         cid: Option<String>,
+        /// tmkms.toml file path (to shut down the enclave's signing sessions gracefully first)
+        #[arg(short)]
+        config_path: Option<PathBuf>,
+    },
+    #[command(
+        name = "start",
+        about = "run the enclave, push the config to it and terminate it on exit"
+    )]
+    StartEnclave {
+        #[command(flatten)]
+        opt: EnclaveOpt,
+        /// tmkms.toml file path
+        #[arg(short, default_value = "tmkms.toml")]
+        config_path: PathBuf,
+        /// how long to wait for the enclave to listen for the config (in seconds)
+        #[arg(long, default_value_t = 30)]
+        ready_timeout: u64,
+        /// log level, default: info, -v: info, -vv: debug, -vvv: trace
+        #[arg(short, action = clap::ArgAction::Count)]
+        v: u32,
+    },
+    #[command(
+        name = "status",
+        about = "print the running enclaves and the statuses of their chains"
+    )]
+    Status {
+        /// tmkms.toml file path
+        #[arg(short, default_value = "tmkms.toml")]
+        config_path: PathBuf,
+    },
+    #[command(
+        name = "log-level",
+        about = "change the log level of the running enclave (without restarting it)"
+    )]
+    LogLevel {
+        /// the level (if not set, the enclave goes back to its startup level and the helper's one)
+        #[arg(value_enum)]
+        level: Option<LogLevel>,
+        #[command(flatten)]
+        opt: ChainControlOpt,
     },
     #[command(name = "vsock-proxy", about = "launch vsock proxy")]
     RunProxy {
@@ -59,6 +650,9 @@ enum CommandEnclave {
         /// log level, default: info, -v: info, -vv: debug, -vvv: trace
         #[arg(short, action = clap::ArgAction::Count)]
         v: u32,
+        /// log format
+        #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+        log_format: LogFormat,
     },
 }
 
@@ -75,12 +669,27 @@ enum CommandHelper {
         pubkey_display: Option<PubkeyDisplay>,
         #[arg(short)]
         bech32_prefix: Option<String>,
-        #[arg(short)]
-        aws_region: String,
-        #[arg(short)]
-        kms_key_id: String,
+        #[command(flatten)]
+        kms: KmsKeyOpt,
+        /// generate a sealed master seed and derive the consensus key from it at this path
+        /// (e.g. `m/44'/118'/0'/0'/0'`)
+        #[arg(long)]
+        derivation_path: Option<DerivationPath>,
         #[arg(long)]
         cid: Option<u32>,
+        /// expected (hex-encoded) PCR0 of the enclave image
+        #[arg(long)]
+        expected_pcr0: Option<String>,
+        /// expected (hex-encoded) PCR1 of the enclave image
+        #[arg(long)]
+        expected_pcr1: Option<String>,
+        /// expected (hex-encoded) PCR2 of the enclave image
+        #[arg(long)]
+        expected_pcr2: Option<String>,
+        /// local development mode: the keys are generated by the enclave binary
+        /// run on the host with `--dev-plaintext` and are NOT sealed
+        #[arg(long)]
+        dev_plaintext: bool,
     },
     #[command(name = "start", about = "start tmkms process")]
     /// start tmkms process (push config + start up proxy and state persistence)
@@ -92,6 +701,158 @@ enum CommandHelper {
         /// log level, default: info, -v: info, -vv: debug, -vvv: trace
         #[arg(short, action = clap::ArgAction::Count)]
         v: u32,
+        /// log format
+        #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+        log_format: LogFormat,
+        /// start from a fresh state (at height 0) even though the previous one is missing
+        #[arg(long)]
+        force_fresh_state: bool,
+    },
+    #[command(
+        name = "key-shares",
+        about = "split the consensus key into threshold shares for future cosigners"
+    )]
+    /// split the consensus key into threshold shares encrypted to future cosigner enclaves
+    /// (the key stays in use, so the validator can later migrate to threshold signing)
+    KeyShares {
+        #[arg(short, default_value = "tmkms.toml")]
+        config_path: PathBuf,
+        /// number of shares needed to sign
+        #[arg(short)]
+        threshold: u16,
+        /// files with base64-encoded attestation documents of the cosigner enclaves
+        #[arg(short, required = true)]
+        recipients: Vec<PathBuf>,
+        /// where to write the encrypted shares
+        #[arg(short, default_value = "key_shares.json")]
+        output: PathBuf,
+        #[arg(long)]
+        cid: Option<u32>,
+    },
+    #[command(
+        name = "state-server",
+        about = "serve as an external state store for another helper's enclave"
+    )]
+    /// persist the states relayed by another helper (its `remote_state_addr`) to a file
+    StateServer {
+        /// state file path
+        #[arg(short)]
+        file: PathBuf,
+        /// address to listen on
+        #[arg(long)]
+        listen: SocketAddr,
+        /// log level, default: info, -v: info, -vv: debug, -vvv: trace
+        #[arg(short, action = clap::ArgAction::Count)]
+        v: u32,
+        /// log format
+        #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+        log_format: LogFormat,
+    },
+    #[command(
+        name = "watermark-server",
+        about = "serve the high watermarks shared by redundant signers"
+    )]
+    /// persist the (height, round, step) reservations of the signers (their `watermark_addr`) to a file
+    WatermarkServer {
+        /// watermark file path
+        #[arg(short)]
+        file: PathBuf,
+        /// key file shared with the signers' `peer_key_path` (generated if it doesn't exist)
+        #[arg(long)]
+        peer_key: PathBuf,
+        /// address to listen on
+        #[arg(long)]
+        listen: SocketAddr,
+        /// log level, default: info, -v: info, -vv: debug, -vvv: trace
+        #[arg(short, action = clap::ArgAction::Count)]
+        v: u32,
+        /// log format
+        #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+        log_format: LogFormat,
+    },
+    #[command(
+        name = "lease-server",
+        about = "grant the lease signers must hold to sign"
+    )]
+    /// grant the leases requested by the signers (their `lease.server_addr`)
+    LeaseServer {
+        /// address to listen on
+        #[arg(long)]
+        listen: SocketAddr,
+        /// key file shared with the signers' `peer_key_path` (generated if it doesn't exist)
+        #[arg(long)]
+        peer_key: PathBuf,
+        /// the longest lease granted (no lease is granted for as long after the start)
+        #[arg(long, default_value_t = 30)]
+        max_lease_secs: u64,
+        /// log level, default: info, -v: info, -vv: debug, -vvv: trace
+        #[arg(short, action = clap::ArgAction::Count)]
+        v: u32,
+        /// log format
+        #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+        log_format: LogFormat,
+    },
+    #[command(
+        name = "approval-server",
+        about = "approve the signatures required by the signing policies (two-person rule)"
+    )]
+    /// approve the signatures of the enclaves (their helpers' `approval.server_addr`),
+    /// but never conflicting ones
+    ApprovalServer {
+        /// approval file path
+        #[arg(short)]
+        file: PathBuf,
+        /// approver key path (generated if it doesn't exist)
+        #[arg(long)]
+        key: PathBuf,
+        /// address to listen on
+        #[arg(long)]
+        listen: SocketAddr,
+        /// log level, default: info, -v: info, -vv: debug, -vvv: trace
+        #[arg(short, action = clap::ArgAction::Count)]
+        v: u32,
+        /// log format
+        #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+        log_format: LogFormat,
+    },
+    #[command(
+        name = "monotonic-server",
+        about = "keep the latest states of the enclaves (to detect rolled back state files)"
+    )]
+    /// persist the latest states sent by the enclaves (their helpers' `monotonic_addr`) to a file
+    MonotonicServer {
+        /// monotonic state file path
+        #[arg(short)]
+        file: PathBuf,
+        /// service key path (generated if it doesn't exist)
+        #[arg(long)]
+        key: PathBuf,
+        /// key file shared with the helpers' `peer_key_path` (generated if it doesn't exist)
+        #[arg(long)]
+        peer_key: PathBuf,
+        /// address to listen on
+        #[arg(long)]
+        listen: SocketAddr,
+        /// log level, default: info, -v: info, -vv: debug, -vvv: trace
+        #[arg(short, action = clap::ArgAction::Count)]
+        v: u32,
+        /// log format
+        #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+        log_format: LogFormat,
+    },
+    #[command(
+        name = "migrate-state",
+        about = "authenticate a state without a MAC (written by an older version or rebuilt)"
+    )]
+    /// the enclave policy needs to allow it (`state_migration`) and the chain needs to be stopped
+    MigrateState {
+        #[arg(short, default_value = "tmkms.toml")]
+        config_path: PathBuf,
+        /// where the enclave's attestation of the migration is written (base64-encoded)
+        #[arg(short, default_value = "state-migration.attestation")]
+        output: PathBuf,
+        #[arg(long)]
+        cid: Option<u32>,
     },
     #[command(name = "launch-all", about = "launch all")]
     LaunchAll {
@@ -104,57 +865,457 @@ enum CommandHelper {
         /// log level, default: info, -v: info, -vv: debug, -vvv: trace
         #[arg(short, action = clap::ArgAction::Count)]
         v: u32,
+        /// start from a fresh state (at height 0) even though the previous one is missing
+        #[arg(long)]
+        force_fresh_state: bool,
     },
 }
 
-fn set_logger(v: u32) -> Result<(), String> {
+fn set_logger(v: u32, log_format: LogFormat) -> Result<(), String> {
     let log_level = match v {
         0 | 1 => Level::INFO,
         2 => Level::DEBUG,
         _ => Level::TRACE,
     };
-    let subscriber = FmtSubscriber::builder().with_max_level(log_level).finish();
-    tracing::subscriber::set_global_default(subscriber)
-        .map_err(|e| format!("setting default subscriber failed: {:?}", e))?;
+    // the forwarded enclave logs are filtered by the enclave (at this level unless it's changed at runtime)
+    enclave_log_server::set_host_level(LevelFilter::from(log_level));
+    let filter = Targets::new()
+        .with_default(log_level)
+        .with_target(ENCLAVE_LOG_TARGET, LevelFilter::TRACE);
+    let builder = FmtSubscriber::builder().with_max_level(LevelFilter::TRACE);
+    match log_format {
+        LogFormat::Text => tracing::subscriber::set_global_default(
+            builder
+                .finish()
+                .with(filter)
+                .with(AlertLayer)
+                .with(SpanLayer::new(OtlpSink)),
+        ),
+        LogFormat::Json => tracing::subscriber::set_global_default(
+            builder
+                .json()
+                .flatten_event(true)
+                .finish()
+                .with(filter)
+                .with(AlertLayer)
+                .with(SpanLayer::new(OtlpSink)),
+        ),
+    }
+    .map_err(|e| format!("setting default subscriber failed: {:?}", e))?;
     Ok(())
 }
 
-fn run() -> Result<(), String> {
+fn run() -> Result<(), CommandError> {
     let opt = TmkmsLight::parse();
     match opt {
         TmkmsLight::Helper(CommandHelper::Init {
             config_dir,
             pubkey_display,
             bech32_prefix,
-            aws_region,
-            kms_key_id,
+            kms,
+            derivation_path,
             cid,
+            expected_pcr0,
+            expected_pcr1,
+            expected_pcr2,
+            dev_plaintext,
         }) => {
+            if dev_plaintext {
+                enable_dev_tcp();
+            }
             init(
                 config_dir,
                 pubkey_display,
                 bech32_prefix,
-                aws_region,
-                kms_key_id,
+                kms,
+                derivation_path,
                 cid,
+                ExpectedPcrs([expected_pcr0, expected_pcr1, expected_pcr2]),
             )?;
         }
         TmkmsLight::Helper(CommandHelper::Start {
             config_path,
             cid,
             v,
+            log_format,
+            force_fresh_state,
         }) => {
-            set_logger(v)?;
-            let config = NitroSignOpt::from_file(config_path)?;
-            if !check_vsock_proxy() {
-                return Err("vsock proxy not started".to_string());
+            set_logger(v, log_format)?;
+            let mut config = NitroSignOpt::from_file(config_path)?;
+            config.force_fresh_state = force_fresh_state;
+            if config.dev_plaintext {
+                tracing::warn!("development mode: the keys are NOT sealed");
+            } else if config.builtin_kms_proxy {
+                KmsProxy::for_regions(&config.aws_region, &config.kms_failover_regions())?.launch();
+            } else if !check_vsock_proxy() {
+                return Err("vsock proxy not started".to_string().into());
+            } else if !config.kms_replicas.is_empty() {
+                tracing::warn!("the KMS replicas can only be reached with `builtin_kms_proxy`");
             }
             let (sender, receiver) = channel();
             ctrlc::set_handler(move || {
                 let _ = sender.send(());
             })
             .map_err(|_| "Error to set Ctrl-C channel".to_string())?;
-            start(&config, cid, receiver)?;
+            start(&config, cid, None, receiver)?;
+        }
+        TmkmsLight::Helper(CommandHelper::KeyShares {
+            config_path,
+            threshold,
+            recipients,
+            output,
+            cid,
+        }) => {
+            let config = NitroSignOpt::from_file(config_path)?;
+            key_shares(&config, threshold, recipients, output, cid)?;
+        }
+        TmkmsLight::Helper(CommandHelper::StateServer {
+            file,
+            listen,
+            v,
+            log_format,
+        }) => {
+            set_logger(v, log_format)?;
+            let (sender, receiver) = channel();
+            ctrlc::set_handler(move || {
+                let _ = sender.send(());
+            })
+            .map_err(|_| "Error to set Ctrl-C channel".to_string())?;
+            state_server(file, listen, receiver)?;
+        }
+        TmkmsLight::Helper(CommandHelper::WatermarkServer {
+            file,
+            peer_key,
+            listen,
+            v,
+            log_format,
+        }) => {
+            set_logger(v, log_format)?;
+            watermark_server(file, peer_key, listen)?;
+        }
+        TmkmsLight::Helper(CommandHelper::LeaseServer {
+            listen,
+            peer_key,
+            max_lease_secs,
+            v,
+            log_format,
+        }) => {
+            set_logger(v, log_format)?;
+            lease_server(listen, peer_key, max_lease_secs)?;
+        }
+        TmkmsLight::Helper(CommandHelper::ApprovalServer {
+            file,
+            key,
+            listen,
+            v,
+            log_format,
+        }) => {
+            set_logger(v, log_format)?;
+            approval_server(file, key, listen)?;
+        }
+        TmkmsLight::Helper(CommandHelper::MigrateState {
+            config_path,
+            output,
+            cid,
+        }) => {
+            let config = NitroSignOpt::from_file(config_path)?;
+            migrate_state(&config, output, cid)?;
+        }
+        TmkmsLight::Helper(CommandHelper::MonotonicServer {
+            file,
+            key,
+            peer_key,
+            listen,
+            v,
+            log_format,
+        }) => {
+            set_logger(v, log_format)?;
+            monotonic_server(file, key, peer_key, listen)?;
+        }
+        TmkmsLight::Chain(CommandChain::Pause { chain_id, opt }) => {
+            let request = NitroRequest::ChainControl(NitroChainControl {
+                chain_id,
+                action: ChainControlAction::Pause,
+            });
+            chain_control(&opt, request)?;
+        }
+        TmkmsLight::Chain(CommandChain::Resume { chain_id, opt }) => {
+            let request = NitroRequest::ChainControl(NitroChainControl {
+                chain_id,
+                action: ChainControlAction::Resume,
+            });
+            chain_control(&opt, request)?;
+        }
+        TmkmsLight::Chain(CommandChain::Stop { chain_id, opt }) => {
+            let request = NitroRequest::ChainControl(NitroChainControl {
+                chain_id,
+                action: ChainControlAction::Stop,
+            });
+            chain_control(&opt, request)?;
+        }
+        TmkmsLight::Chain(CommandChain::Drain { chain_id, opt }) => {
+            let request = NitroRequest::ChainControl(NitroChainControl {
+                chain_id,
+                action: ChainControlAction::Drain,
+            });
+            chain_control(&opt, request)?;
+        }
+        TmkmsLight::Chain(CommandChain::Maintenance { chain_id, opt }) => {
+            let request = NitroRequest::ChainControl(NitroChainControl {
+                chain_id,
+                action: ChainControlAction::Maintenance,
+            });
+            chain_control(&opt, request)?;
+        }
+        TmkmsLight::Chain(CommandChain::AllowHeightJump { chain_id, opt }) => {
+            let request = NitroRequest::ChainControl(NitroChainControl {
+                chain_id,
+                action: ChainControlAction::AllowHeightJump,
+            });
+            chain_control(&opt, request)?;
+        }
+        TmkmsLight::Admin {
+            command,
+            chain_id,
+            config_path,
+        } => {
+            let config = NitroSignOpt::from_file(config_path)?;
+            let admin = config
+                .admin
+                .ok_or_else(|| "`admin` isn't configured".to_owned())?;
+            let chains = admin_request(&admin.socket_path, &AdminRequest { command, chain_id })?;
+            let status = Status::V1(StatusV1 {
+                version: env!("CARGO_PKG_VERSION").to_owned(),
+                chains,
+            });
+            let s = serde_json::to_string_pretty(&status)
+                .map_err(|e| format!("failed to serialize chain statuses: {:?}", e))?;
+            println!("{}", s);
+        }
+        TmkmsLight::Chain(CommandChain::Status { opt }) => {
+            chain_control(&opt, NitroRequest::ChainStatus)?;
+        }
+        TmkmsLight::Attestation(CommandAttestation::Verify {
+            config_path,
+            file,
+            cid,
+        }) => {
+            let config = NitroSignOpt::from_file(config_path)?;
+            attestation_verify(&config, file, cid)?;
+        }
+        TmkmsLight::Attestation(CommandAttestation::Export {
+            config_path,
+            output,
+            key,
+            since,
+        }) => {
+            let config = NitroSignOpt::from_file(config_path)?;
+            attestation_export(&config, &output, &key, since)?;
+        }
+        TmkmsLight::Attestation(CommandAttestation::VerifyBundle { file, public_key }) => {
+            attestation_verify_bundle(&file, public_key)?;
+        }
+        TmkmsLight::Eif(CommandEif::Describe { file, config_path }) => {
+            let config = config_path.map(NitroSignOpt::from_file).transpose()?;
+            eif_describe(&file, config.as_ref())?;
+        }
+        TmkmsLight::Kms(CommandKms::Policy(CommandKmsPolicy::Generate {
+            file,
+            config_path,
+            pcr0,
+            pcr1,
+            pcr2,
+            role_arn,
+            admin_arn,
+        })) => {
+            kms_policy_generate(
+                file,
+                ExpectedPcrs([pcr0, pcr1, pcr2]),
+                config_path,
+                role_arn,
+                admin_arn,
+            )?;
+        }
+        TmkmsLight::Key(CommandKey::Rewrap {
+            key,
+            config_path,
+            kms_key_id,
+            cid,
+        }) => {
+            let config = NitroSignOpt::from_file(config_path)?;
+            rewrap(&config, key, kms_key_id, cid)?;
+        }
+        TmkmsLight::Key(CommandKey::Derive {
+            config_path,
+            derivation_path,
+            pubkey_display,
+            bech32_prefix,
+            cid,
+        }) => {
+            let config = NitroSignOpt::from_file(config_path)?;
+            derive(&config, derivation_path, pubkey_display, bech32_prefix, cid)?;
+        }
+        TmkmsLight::Key(CommandKey::SignPayload {
+            config_path,
+            file,
+            cid,
+        }) => {
+            let config = NitroSignOpt::from_file(config_path)?;
+            sign_payload(&config, file, cid)?;
+        }
+        TmkmsLight::Key(CommandKey::OperatorKeygen { output }) => {
+            operator_keygen(output)?;
+        }
+        TmkmsLight::Key(CommandKey::Backup {
+            config_path,
+            shamir,
+            recipients,
+            output,
+            cid,
+        }) => {
+            let config = NitroSignOpt::from_file(config_path)?;
+            backup(&config, shamir, recipients, output, cid)?;
+        }
+        TmkmsLight::Key(CommandKey::Restore(CommandKeyRestore::Share {
+            backup,
+            file,
+            key,
+            config_path,
+            output,
+        })) => {
+            let config = NitroSignOpt::from_file(config_path)?;
+            restore_share(&config, backup, file, key, output)?;
+        }
+        TmkmsLight::Key(CommandKey::Restore(CommandKeyRestore::Finish {
+            config_path,
+            backup,
+            shares,
+            kms_key_id,
+            pubkey_display,
+            bech32_prefix,
+            cid,
+        })) => {
+            let config = NitroSignOpt::from_file(config_path)?;
+            restore_finish(
+                &config,
+                backup,
+                shares,
+                kms_key_id,
+                pubkey_display,
+                bech32_prefix,
+                cid,
+            )?;
+        }
+        TmkmsLight::Key(CommandKey::Provision(CommandKeyProvision::Begin {
+            config_path,
+            output,
+            cid,
+        })) => {
+            let config = NitroSignOpt::from_file(config_path)?;
+            provision_begin(&config, output, cid)?;
+        }
+        TmkmsLight::Key(CommandKey::Provision(CommandKeyProvision::Seal {
+            file,
+            config_path,
+            output,
+        })) => {
+            let config = NitroSignOpt::from_file(config_path)?;
+            provision_seal(&config, file, output)?;
+        }
+        TmkmsLight::Key(CommandKey::Provision(CommandKeyProvision::Finish {
+            config_path,
+            file,
+            kms_key_id,
+            derivation_path,
+            pubkey_display,
+            bech32_prefix,
+            cid,
+        })) => {
+            let config = NitroSignOpt::from_file(config_path)?;
+            provision_finish(
+                &config,
+                file,
+                kms_key_id,
+                derivation_path,
+                pubkey_display,
+                bech32_prefix,
+                cid,
+            )?;
+        }
+        TmkmsLight::Bench {
+            socket,
+            chain_id,
+            rate,
+            requests,
+            mix,
+            start_height,
+            v,
+        } => {
+            set_logger(v, LogFormat::Text)?;
+            bench(BenchOpt {
+                socket,
+                chain_id,
+                rate,
+                requests,
+                mix,
+                start_height,
+            })?;
+        }
+        TmkmsLight::Replay {
+            capture,
+            state,
+            config_path,
+            v,
+        } => {
+            set_logger(v, LogFormat::Text)?;
+            replay(config_path, capture, state)?;
+        }
+        TmkmsLight::VerifySig {
+            sign_bytes,
+            vote,
+            proposal,
+            chain_id,
+            signature,
+            pubkey,
+        } => {
+            let message = match (sign_bytes, vote, proposal) {
+                (Some(path), _, _) => SignedMessage::SignBytes(path),
+                (_, Some(path), _) => SignedMessage::Vote(path),
+                (_, _, Some(path)) => SignedMessage::Proposal(path),
+                _ => {
+                    return Err("`--sign-bytes`, `--vote` or `--proposal` is required"
+                        .to_owned()
+                        .into())
+                }
+            };
+            verify_sig(message, chain_id, signature, pubkey)?;
+        }
+        TmkmsLight::Status { config_path } => {
+            let config = NitroSignOpt::from_file(config_path)?;
+            let addr = config
+                .health_listen_addr
+                .ok_or_else(|| "`health_listen_addr` isn't configured".to_owned())?;
+            signing_status(addr)?;
+        }
+        TmkmsLight::Audit(CommandAudit::Verify {
+            file,
+            config_path,
+            public_key,
+            allow_unanchored_tail,
+        }) => {
+            let public_key = match public_key {
+                Some(public_key) => public_key,
+                None => {
+                    let config_path = config_path.ok_or_else(|| {
+                        "the expected consensus public key is needed (`--public-key` or `-c`)"
+                            .to_owned()
+                    })?;
+                    NitroSignOpt::from_file(config_path)?
+                        .expected_consensus_pubkey
+                        .ok_or_else(|| "`expected_consensus_pubkey` isn't configured".to_owned())?
+                }
+            };
+            audit_verify(file, &public_key, allow_unanchored_tail)?;
         }
         TmkmsLight::Enclave(CommandEnclave::Info) => {
             let info = describe_enclave()?;
@@ -163,7 +1324,7 @@ fn run() -> Result<(), String> {
             println!("enclave status:\n{}", s);
         }
         TmkmsLight::Enclave(CommandEnclave::RunEnclave { opt, v }) => {
-            set_logger(v)?;
+            set_logger(v, opt.log_format)?;
             let (sender, receiver) = channel();
             ctrlc::set_handler(move || {
                 let _ = sender.send(());
@@ -171,11 +1332,44 @@ fn run() -> Result<(), String> {
             .map_err(|_| "Error to set Ctrl-C channel".to_string())?;
             run_enclave(&opt, receiver)?;
         }
-        TmkmsLight::Enclave(CommandEnclave::StopEnclave { cid }) => {
-            stop_enclave(cid)?;
+        TmkmsLight::Enclave(CommandEnclave::StopEnclave { cid, config_path }) => {
+            let config = config_path.map(NitroSignOpt::from_file).transpose()?;
+            stop_enclave_gracefully(config.as_ref(), cid)?;
+        }
+        TmkmsLight::Enclave(CommandEnclave::StartEnclave {
+            opt,
+            config_path,
+            ready_timeout,
+            v,
+        }) => {
+            set_logger(v, opt.log_format)?;
+            let config = NitroSignOpt::from_file(config_path)?;
+            if config.dev_plaintext {
+                tracing::warn!("development mode: the keys are NOT sealed");
+            } else if config.builtin_kms_proxy {
+                KmsProxy::for_regions(&config.aws_region, &config.kms_failover_regions())?.launch();
+            } else if !check_vsock_proxy() {
+                return Err("vsock proxy not started".to_string().into());
+            }
+            let (sender, receiver) = channel();
+            ctrlc::set_handler(move || {
+                let _ = sender.send(());
+            })
+            .map_err(|_| "Error to set Ctrl-C channel".to_string())?;
+            start_enclave(&config, &opt, Duration::from_secs(ready_timeout), receiver)?;
+        }
+        TmkmsLight::Enclave(CommandEnclave::Status { config_path }) => {
+            let config = NitroSignOpt::from_file(config_path)?;
+            let status = enclave_status(&config)?;
+            let s = serde_json::to_string_pretty(&status)
+                .map_err(|_| "get invalid enclave status".to_string())?;
+            println!("{}", s);
+        }
+        TmkmsLight::Enclave(CommandEnclave::LogLevel { level, opt }) => {
+            set_enclave_log_level(&opt, level)?;
         }
-        TmkmsLight::Enclave(CommandEnclave::RunProxy { opt, v }) => {
-            set_logger(v)?;
+        TmkmsLight::Enclave(CommandEnclave::RunProxy { opt, v, log_format }) => {
+            set_logger(v, log_format)?;
             let (sender, receiver) = channel();
             ctrlc::set_handler(move || {
                 let _ = sender.send(());
@@ -187,10 +1381,12 @@ fn run() -> Result<(), String> {
             tmkms_config,
             enclave_config,
             v,
+            force_fresh_state,
         }) => {
-            set_logger(v)?;
-            let tmkms_config = NitroSignOpt::from_file(tmkms_config)?;
+            let mut tmkms_config = NitroSignOpt::from_file(tmkms_config)?;
+            tmkms_config.force_fresh_state = force_fresh_state;
             let enclave_config = EnclaveConfig::from_file(enclave_config)?;
+            set_logger(v, enclave_config.enclave.log_format)?;
             launch_all(tmkms_config, enclave_config)?;
         }
     };
@@ -200,6 +1396,6 @@ fn run() -> Result<(), String> {
 fn main() {
     if let Err(e) = run() {
         eprintln!("{}", e);
-        std::process::exit(1);
+        std::process::exit(e.exit_code());
     }
 }
@@ -0,0 +1,58 @@
+use crate::mux_server::ChannelListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tmkms_light::metrics::{MetricsEvent, SigningMetrics};
+use tmkms_light::utils::read_u16_payload;
+use tmkms_nitro_helper::channel::ChannelStream;
+use tracing::{error, info, warn};
+
+/// receives the metrics events from the enclave and aggregates them
+/// (served by the health endpoint)
+pub struct MetricsServer {
+    metrics: Arc<Mutex<SigningMetrics>>,
+    vsock_listener: ChannelListener,
+}
+
+impl MetricsServer {
+    /// binds a listener for the enclave on the provided port
+    pub fn new(metrics: Arc<Mutex<SigningMetrics>>, vsock_port: u32) -> Result<Self, String> {
+        let vsock_listener = ChannelListener::bind(vsock_port)
+            .map_err(|e| format!("failed to bind the metrics listener: {:?}", e))?;
+        Ok(Self {
+            metrics,
+            vsock_listener,
+        })
+    }
+
+    fn record(&self, stream: &mut ChannelStream) -> Result<(), String> {
+        let json_raw = read_u16_payload(stream).map_err(|e| format!("{}", e))?;
+        match serde_json::from_slice::<MetricsEvent>(&json_raw) {
+            Ok(event) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default();
+                self.metrics
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .record(&event, now);
+            }
+            Err(e) => warn!("invalid metrics event: {:?}", e),
+        }
+        Ok(())
+    }
+
+    /// serves the enclave connections in a separate thread
+    pub fn launch(self) {
+        thread::spawn(move || {
+            info!("listening for enclave metrics");
+            while let Ok((mut stream, _)) = self.vsock_listener.accept() {
+                info!("vsock metrics connection established");
+                while let Ok(()) = self.record(&mut stream) {}
+                warn!("vsock metrics connection lost");
+            }
+            error!("metrics listener failed");
+        });
+    }
+}
@@ -0,0 +1,136 @@
+use crate::key_utils::load_or_generate_signing_key;
+use crate::peer_auth::{PeerChannel, PeerKey};
+use ed25519_consensus::SigningKey;
+use std::fs;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tempfile::NamedTempFile;
+use tmkms_light::chain::state::{MonotonicRequest, MonotonicResponse, MonotonicStates};
+use tracing::{error, info, warn};
+
+/// the states shared by the connections (and the file they're persisted to)
+struct MonotonicStore {
+    path: PathBuf,
+    states: MonotonicStates,
+    key: SigningKey,
+}
+
+impl MonotonicStore {
+    /// handles the request (and persists an advanced state before acknowledging it)
+    /// and signs the response
+    fn handle(&mut self, request: MonotonicRequest) -> Result<MonotonicResponse, String> {
+        let state = if let MonotonicRequest::Read { .. } = request {
+            self.states.handle(request.clone())?
+        } else {
+            let mut states = self.states.clone();
+            let state = states.handle(request.clone())?;
+            persist(&self.path, &states)?;
+            self.states = states;
+            state
+        };
+        MonotonicResponse::sign(&self.key, &request, state)
+    }
+}
+
+/// write the states into a file
+fn persist(path: &Path, states: &MonotonicStates) -> Result<(), String> {
+    let json = serde_json::to_vec(states).map_err(|e| format!("{:?}", e))?;
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut file = NamedTempFile::new_in(dir)
+        .map_err(|e| format!("failed to write {}: {:?}", path.display(), e))?;
+    file.write_all(&json)
+        .map_err(|e| format!("failed to write {}: {:?}", path.display(), e))?;
+    file.persist(path)
+        .map_err(|e| format!("failed to write {}: {:?}", path.display(), e.error))?;
+    Ok(())
+}
+
+/// keeps the latest state of each chain (which can't go back),
+/// so the enclaves can detect a rolled back state file at startup
+/// (the responses are signed with the service key, which the enclave policy pins;
+/// only the helpers holding the peer key can connect)
+pub struct MonotonicServer {
+    store: Arc<Mutex<MonotonicStore>>,
+    listener: TcpListener,
+    peer_key: PeerKey,
+}
+
+impl MonotonicServer {
+    /// loads the keys and the previous states (if any) and binds the listener
+    pub fn new(
+        path: PathBuf,
+        key_path: &Path,
+        peer_key_path: &Path,
+        listen_addr: SocketAddr,
+    ) -> Result<Self, String> {
+        let key = load_or_generate_signing_key(key_path, "monotonic service key")?;
+        let peer_key = PeerKey::load_or_generate(peer_key_path)?;
+        info!(
+            "monotonic service public key: {}",
+            String::from_utf8_lossy(&subtle_encoding::base64::encode(
+                key.verification_key().to_bytes()
+            ))
+        );
+        let states = match fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map_err(|e| format!("invalid monotonic state file {}: {:?}", path.display(), e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => MonotonicStates::default(),
+            Err(e) => return Err(format!("failed to read {}: {:?}", path.display(), e)),
+        };
+        let listener = TcpListener::bind(listen_addr)
+            .map_err(|e| format!("failed to bind the monotonic state listener: {:?}", e))?;
+        Ok(Self {
+            store: Arc::new(Mutex::new(MonotonicStore { path, states, key })),
+            listener,
+            peer_key,
+        })
+    }
+
+    /// handles a request and sends back the result
+    fn handle(
+        store: &Mutex<MonotonicStore>,
+        channel: &mut PeerChannel<TcpStream>,
+    ) -> Result<(), String> {
+        let json_raw = channel.recv()?;
+        let result = serde_json::from_slice::<MonotonicRequest>(&json_raw)
+            .map_err(|e| format!("invalid monotonic state request: {:?}", e))
+            .and_then(|request| {
+                store
+                    .lock()
+                    .map_err(|_| "monotonic state store poisoned".to_string())?
+                    .handle(request)
+            });
+        if let Err(ref e) = result {
+            warn!("monotonic state request failed: {}", e);
+        }
+        let response = serde_json::to_vec(&result).map_err(|e| format!("{:?}", e))?;
+        channel.send(&response)
+    }
+
+    /// serves the connections (each in a separate thread)
+    pub fn run(self) -> Result<(), String> {
+        for stream in self.listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    info!("monotonic state connection from {:?}", stream.peer_addr());
+                    let store = self.store.clone();
+                    let peer_key = self.peer_key.clone();
+                    thread::spawn(move || {
+                        match PeerChannel::accept(stream, peer_key) {
+                            Ok(mut channel) => {
+                                while let Ok(()) = Self::handle(&store, &mut channel) {}
+                            }
+                            Err(e) => warn!("monotonic state connection failed: {}", e),
+                        }
+                        warn!("monotonic state connection lost");
+                    });
+                }
+                Err(e) => error!("monotonic state connection failed: {}", e),
+            }
+        }
+        Ok(())
+    }
+}
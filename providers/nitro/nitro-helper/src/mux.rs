@@ -0,0 +1,61 @@
+//! Single-port multiplexing of the enclave's channels to the host:
+//! with `enclave_mux_port`, the connection of each channel (state, privval, audit etc.)
+//! is made to that one vsock port and starts with the channel ID (the channel's own vsock port),
+//! so that the helper can hand it over to the channel's listener.
+
+use crate::channel::ChannelStream;
+use crate::VSOCK_HOST_CID;
+use std::io::{self, Read, Write};
+
+/// marks the channel header
+const CHANNEL_MAGIC: [u8; 4] = *b"TMUX";
+
+/// writes the channel header (the magic and the big-endian channel ID)
+pub fn write_channel_id<W: Write>(writer: &mut W, channel: u32) -> io::Result<()> {
+    let mut header = [0u8; 8];
+    header[..4].copy_from_slice(&CHANNEL_MAGIC);
+    header[4..].copy_from_slice(&channel.to_be_bytes());
+    writer.write_all(&header)
+}
+
+/// reads the channel header and returns the channel ID
+pub fn read_channel_id<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header)?;
+    if header[..4] != CHANNEL_MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid channel header",
+        ));
+    }
+    Ok(u32::from_be_bytes([
+        header[4], header[5], header[6], header[7],
+    ]))
+}
+
+/// connects to the host's channel (via the multiplexed port if set)
+pub fn connect_channel(mux_port: Option<u32>, channel: u32) -> io::Result<ChannelStream> {
+    match mux_port {
+        Some(port) => {
+            let mut stream = ChannelStream::connect(VSOCK_HOST_CID, port)?;
+            write_channel_id(&mut stream, channel)?;
+            Ok(stream)
+        }
+        None => ChannelStream::connect(VSOCK_HOST_CID, channel),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_header_roundtrip() {
+        let mut header = vec![];
+        write_channel_id(&mut header, 5555).unwrap();
+        assert_eq!(read_channel_id(&mut header.as_slice()).unwrap(), 5555);
+        header[0] = b'X';
+        assert!(read_channel_id(&mut header.as_slice()).is_err());
+        assert!(read_channel_id(&mut &header[..6]).is_err());
+    }
+}
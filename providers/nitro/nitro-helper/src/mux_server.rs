@@ -0,0 +1,103 @@
+use crate::shared::VSOCK_HOST_CID;
+use std::collections::HashMap;
+use std::io;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use tmkms_nitro_helper::channel::{ChannelAddr, ChannelStream, PortListener};
+use tmkms_nitro_helper::mux::read_channel_id;
+use tracing::{info, warn};
+
+/// how long the enclave has to send the channel header
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// the channel listeners of the multiplexed port (if it's launched)
+static CHANNELS: Mutex<Option<HashMap<u32, Sender<ChannelStream>>>> = Mutex::new(None);
+
+/// accepts the enclave's connections of a channel
+/// (on its own vsock port, or handed over from the multiplexed port)
+pub enum ChannelListener {
+    Port(PortListener),
+    Mux(Receiver<ChannelStream>),
+}
+
+impl ChannelListener {
+    /// registers the channel with the multiplexed port if it's launched,
+    /// otherwise binds a listener on the channel's vsock port
+    pub fn bind(vsock_port: u32) -> io::Result<Self> {
+        let mut channels = CHANNELS.lock().expect("channels lock");
+        match channels.as_mut() {
+            Some(channels) => {
+                // (re)binding the channel replaces its previous listener
+                let (tx, rx) = channel();
+                channels.insert(vsock_port, tx);
+                Ok(ChannelListener::Mux(rx))
+            }
+            None => PortListener::bind(VSOCK_HOST_CID, vsock_port).map(ChannelListener::Port),
+        }
+    }
+
+    /// waits for the next connection of the channel
+    pub fn accept(&self) -> io::Result<(ChannelStream, ChannelAddr)> {
+        match self {
+            ChannelListener::Port(listener) => listener.accept(),
+            ChannelListener::Mux(streams) => {
+                let stream = streams.recv().map_err(|_| {
+                    io::Error::new(io::ErrorKind::BrokenPipe, "multiplexed port stopped")
+                })?;
+                let addr = stream.peer_addr()?;
+                Ok((stream, addr))
+            }
+        }
+    }
+}
+
+/// reads the channel header and hands the connection over to the channel's listener
+fn dispatch(mut stream: ChannelStream) -> Result<(), String> {
+    stream
+        .set_read_timeout(Some(HEADER_TIMEOUT))
+        .map_err(|e| format!("failed to set the header timeout: {:?}", e))?;
+    let channel = read_channel_id(&mut stream)
+        .map_err(|e| format!("failed to read the channel header: {:?}", e))?;
+    stream
+        .set_read_timeout(None)
+        .map_err(|e| format!("failed to reset the read timeout: {:?}", e))?;
+    let channels = CHANNELS.lock().expect("channels lock");
+    let sender = channels
+        .as_ref()
+        .and_then(|channels| channels.get(&channel))
+        .ok_or_else(|| format!("unknown channel {}", channel))?;
+    sender
+        .send(stream)
+        .map_err(|_| format!("channel {} isn't served anymore", channel))
+}
+
+/// binds the multiplexed port; it needs to be launched before the channel listeners are bound
+pub fn launch_mux(vsock_port: u32) -> Result<(), String> {
+    let listener = PortListener::bind(VSOCK_HOST_CID, vsock_port).map_err(|e| {
+        format!(
+            "failed to bind the multiplexed port {}: {:?}",
+            vsock_port, e
+        )
+    })?;
+    CHANNELS
+        .lock()
+        .expect("channels lock")
+        .get_or_insert_with(HashMap::new);
+    info!(
+        "multiplexing the enclave channels on vsock port {}",
+        vsock_port
+    );
+    thread::spawn(move || loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = dispatch(stream) {
+                    warn!("multiplexed connection dropped: {}", e);
+                }
+            }
+            Err(e) => warn!("multiplexed connection failed: {}", e),
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,221 @@
+//! OpenTelemetry trace export: the helper's spans and the ones relayed by the enclave
+//! (request handling, policy check, state persistence, KMS decryption, signing, response)
+//! are batched and posted to an OTLP/HTTP collector (JSON encoding)
+
+use crate::mux_server::ChannelListener;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use tmkms_nitro_helper::span_export::{read_span, SpanRecord, SpanSink};
+use tracing::{debug, error, info, warn};
+
+/// how long the collector has to answer
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// how many spans can be queued for the export (the newer ones are dropped)
+const SPAN_QUEUE_LEN: usize = 4096;
+
+/// settings of the trace export
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct OtlpConfig {
+    /// base URL of the OTLP/HTTP collector (the spans are posted to `{endpoint}/v1/traces`)
+    pub endpoint: String,
+    /// `service.name` of the exported spans
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    /// extra HTTP headers (e.g. for authentication)
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// how often the batched spans are exported
+    #[serde(default = "default_export_interval_secs")]
+    pub export_interval_secs: u64,
+    /// the batch is exported early when it has this many spans
+    #[serde(default = "default_max_batch_len")]
+    pub max_batch_len: usize,
+}
+
+fn default_service_name() -> String {
+    "tmkms-nitro-helper".to_owned()
+}
+
+fn default_export_interval_secs() -> u64 {
+    5
+}
+
+fn default_max_batch_len() -> usize {
+    512
+}
+
+/// queue of the spans for the export (if enabled)
+static SPANS: Mutex<Option<SyncSender<SpanRecord>>> = Mutex::new(None);
+
+/// queues the helper's spans for the export (if enabled)
+pub struct OtlpSink;
+
+impl SpanSink for OtlpSink {
+    fn export(&self, span: SpanRecord) {
+        if let Some(sender) = SPANS.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+            let _ = sender.try_send(span);
+        }
+    }
+}
+
+/// the OTLP/JSON `ExportTraceServiceRequest` of the spans
+fn export_request(service_name: &str, spans: &[SpanRecord]) -> serde_json::Value {
+    let string_value = |key: &str, value: &str| serde_json::json!({ "key": key, "value": { "stringValue": value } });
+    let spans: Vec<_> = spans
+        .iter()
+        .map(|span| {
+            let mut attributes = vec![string_value("code.namespace", &span.target)];
+            attributes.extend(
+                span.attributes
+                    .iter()
+                    .map(|(key, value)| string_value(key, value)),
+            );
+            serde_json::json!({
+                "traceId": span.trace_id,
+                "spanId": span.span_id,
+                "parentSpanId": span.parent_span_id.clone().unwrap_or_default(),
+                "name": span.name,
+                // SPAN_KIND_INTERNAL
+                "kind": 1,
+                "startTimeUnixNano": span.start_unix_nanos.to_string(),
+                "endTimeUnixNano": span.end_unix_nanos.to_string(),
+                "attributes": attributes,
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "resourceSpans": [{
+            "resource": { "attributes": [string_value("service.name", service_name)] },
+            "scopeSpans": [{
+                "scope": { "name": "tmkms-light", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+/// posts the queued spans to the collector
+pub struct OtlpExporter {
+    config: OtlpConfig,
+}
+
+impl OtlpExporter {
+    pub fn new(config: OtlpConfig) -> Result<Self, String> {
+        if config.export_interval_secs == 0 || config.max_batch_len == 0 {
+            return Err(
+                "`otlp.export_interval_secs` and `otlp.max_batch_len` must be positive".to_owned(),
+            );
+        }
+        Ok(Self { config })
+    }
+
+    fn post(&self, spans: &[SpanRecord]) -> Result<(), String> {
+        let url = format!("{}/v1/traces", self.config.endpoint.trim_end_matches('/'));
+        let mut request = ureq::post(&url)
+            .timeout(EXPORT_TIMEOUT)
+            .set("Content-Type", "application/json");
+        for (name, value) in self.config.headers.iter() {
+            request = request.set(name, value);
+        }
+        request
+            .send_string(&export_request(&self.config.service_name, spans).to_string())
+            .map(|_| ())
+            .map_err(|e| format!("failed to export {} spans to {}: {}", spans.len(), url, e))
+    }
+
+    fn run(self, receiver: Receiver<SpanRecord>) {
+        let interval = Duration::from_secs(self.config.export_interval_secs);
+        let mut batch = Vec::with_capacity(self.config.max_batch_len);
+        let mut exported_at = Instant::now();
+        loop {
+            let timeout = interval.saturating_sub(exported_at.elapsed());
+            match receiver.recv_timeout(timeout) {
+                Ok(span) => batch.push(span),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            if batch.len() >= self.config.max_batch_len || exported_at.elapsed() >= interval {
+                if !batch.is_empty() {
+                    // the spans of the failed batches are dropped (not to pile up)
+                    match self.post(&batch) {
+                        Ok(()) => debug!("exported {} spans", batch.len()),
+                        Err(e) => warn!("{}", e),
+                    }
+                    batch.clear();
+                }
+                exported_at = Instant::now();
+            }
+        }
+    }
+
+    /// exports the spans in a separate thread
+    pub fn launch(self) {
+        let (sender, receiver) = sync_channel(SPAN_QUEUE_LEN);
+        *SPANS.lock().unwrap_or_else(|e| e.into_inner()) = Some(sender);
+        thread::spawn(move || self.run(receiver));
+    }
+}
+
+/// receives the spans relayed by the enclave and queues them for the export
+pub struct TraceServer {
+    vsock_listener: ChannelListener,
+}
+
+impl TraceServer {
+    /// binds a listener for the enclave on the provided port
+    pub fn new(vsock_port: u32) -> Result<Self, String> {
+        let vsock_listener = ChannelListener::bind(vsock_port)
+            .map_err(|e| format!("failed to bind the trace listener: {:?}", e))?;
+        Ok(Self { vsock_listener })
+    }
+
+    /// serves the enclave connections in a separate thread
+    pub fn launch(self) {
+        thread::spawn(move || {
+            info!("listening for enclave spans");
+            while let Ok((mut stream, _)) = self.vsock_listener.accept() {
+                while let Ok(span) = read_span(&mut stream) {
+                    OtlpSink.export(span);
+                }
+                debug!("enclave span connection closed");
+            }
+            error!("trace listener failed");
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_otlp_json() {
+        let span = SpanRecord {
+            trace_id: "0af7651916cd43dd8448eb211c80319c".to_owned(),
+            span_id: "b7ad6b7169203331".to_owned(),
+            parent_span_id: None,
+            name: "sign".to_owned(),
+            target: "tmkms_light::session".to_owned(),
+            start_unix_nanos: 1_000,
+            end_unix_nanos: 2_000,
+            attributes: vec![("chain_id".to_owned(), "testchain-1".to_owned())],
+        };
+        let request = export_request("tmkms", &[span]);
+        let resource = &request["resourceSpans"][0];
+        assert_eq!(
+            resource["resource"]["attributes"][0]["value"]["stringValue"],
+            "tmkms"
+        );
+        let span = &resource["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["spanId"], "b7ad6b7169203331");
+        assert_eq!(span["parentSpanId"], "");
+        assert_eq!(span["endTimeUnixNano"], "2000");
+        assert_eq!(span["attributes"][1]["key"], "chain_id");
+    }
+}
@@ -0,0 +1,206 @@
+//! authentication of the messages between the signers (HA) and with the shared services
+//! (watermark, lease and monotonic state): both ends hold the same key, exchange fresh nonces
+//! when connecting and MAC each message with them (so messages can't be forged, altered,
+//! reordered or replayed from another connection)
+
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use tmkms_light::utils::{read_u16_payload, write_u16_payload};
+use tracing::info;
+use zeroize::Zeroizing;
+
+const PEER_AUTH_DOMAIN: &[u8] = b"tmkms-peer-auth-v1";
+const NONCE_LEN: usize = 32;
+const TAG_LEN: usize = 32;
+
+/// the shared key of the signers and the services (base64-encoded 32 bytes in a file)
+#[derive(Clone)]
+pub struct PeerKey(Arc<Zeroizing<[u8; 32]>>);
+
+impl PeerKey {
+    /// reads the key file
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let encoded = Zeroizing::new(
+            fs::read(path).map_err(|e| format!("failed to read {}: {:?}", path.display(), e))?,
+        );
+        let secret = Zeroizing::new(
+            subtle_encoding::base64::decode(String::from_utf8_lossy(&encoded).trim())
+                .map_err(|e| format!("invalid peer key {}: {}", path.display(), e))?,
+        );
+        let key = <[u8; 32]>::try_from(secret.as_slice())
+            .map_err(|_| format!("invalid peer key {}: not 32 bytes", path.display()))?;
+        Ok(Self(Arc::new(Zeroizing::new(key))))
+    }
+
+    /// reads the key file (or generates it if the file doesn't exist,
+    /// so it can be copied to the signers)
+    pub fn load_or_generate(path: &Path) -> Result<Self, String> {
+        if path.exists() {
+            return Self::load(path);
+        }
+        let mut secret = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(&mut secret[..]);
+        let encoded = Zeroizing::new(subtle_encoding::base64::encode(&secret[..]));
+        OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .mode(0o600)
+            .open(path)
+            .and_then(|mut file| file.write_all(&encoded))
+            .map_err(|e| format!("couldn't write `{}`: {:?}", path.display(), e))?;
+        info!("generated the peer key {}", path.display());
+        Ok(Self(Arc::new(secret)))
+    }
+}
+
+/// which end of the connection sent a message
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Client = 0,
+    Server = 1,
+}
+
+/// a connection whose messages (length-prefixed, as `read_u16_payload` / `write_u16_payload`)
+/// are authenticated with the peer key
+pub struct PeerChannel<S: Read + Write> {
+    stream: S,
+    key: PeerKey,
+    side: Side,
+    client_nonce: [u8; NONCE_LEN],
+    server_nonce: [u8; NONCE_LEN],
+    sent: u64,
+    received: u64,
+}
+
+fn read_nonce<S: Read>(stream: &mut S) -> Result<[u8; NONCE_LEN], String> {
+    let nonce = read_u16_payload(stream).map_err(|e| e.to_string())?;
+    <[u8; NONCE_LEN]>::try_from(nonce.as_slice()).map_err(|_| "invalid peer nonce".to_owned())
+}
+
+fn new_nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+impl<S: Read + Write> PeerChannel<S> {
+    /// starts the connection on the connecting side
+    pub fn connect(mut stream: S, key: PeerKey) -> Result<Self, String> {
+        let client_nonce = new_nonce();
+        write_u16_payload(&mut stream, &client_nonce).map_err(|e| e.to_string())?;
+        let server_nonce = read_nonce(&mut stream)?;
+        Ok(Self {
+            stream,
+            key,
+            side: Side::Client,
+            client_nonce,
+            server_nonce,
+            sent: 0,
+            received: 0,
+        })
+    }
+
+    /// starts the connection on the accepting side
+    pub fn accept(mut stream: S, key: PeerKey) -> Result<Self, String> {
+        let client_nonce = read_nonce(&mut stream)?;
+        let server_nonce = new_nonce();
+        write_u16_payload(&mut stream, &server_nonce).map_err(|e| e.to_string())?;
+        Ok(Self {
+            stream,
+            key,
+            side: Side::Server,
+            client_nonce,
+            server_nonce,
+            sent: 0,
+            received: 0,
+        })
+    }
+
+    fn tag(&self, side: Side, seq: u64, payload: &[u8]) -> Hmac<Sha256> {
+        let mut hmac =
+            Hmac::<Sha256>::new_from_slice(&self.key.0[..]).expect("HMAC accepts any key length");
+        hmac.update(PEER_AUTH_DOMAIN);
+        hmac.update(&self.client_nonce);
+        hmac.update(&self.server_nonce);
+        hmac.update(&[side as u8]);
+        hmac.update(&seq.to_be_bytes());
+        hmac.update(payload);
+        hmac
+    }
+
+    /// sends an authenticated message
+    pub fn send(&mut self, payload: &[u8]) -> Result<(), String> {
+        let tag = self
+            .tag(self.side, self.sent, payload)
+            .finalize()
+            .into_bytes();
+        let mut frame = Vec::with_capacity(payload.len() + TAG_LEN);
+        frame.extend_from_slice(payload);
+        frame.extend_from_slice(&tag);
+        write_u16_payload(&mut self.stream, &frame).map_err(|e| e.to_string())?;
+        self.sent += 1;
+        Ok(())
+    }
+
+    /// receives a message (and checks it was sent by the peer holding the key)
+    pub fn recv(&mut self) -> Result<Vec<u8>, String> {
+        let mut frame = read_u16_payload(&mut self.stream).map_err(|e| e.to_string())?;
+        if frame.len() < TAG_LEN {
+            return Err("unauthenticated peer message".to_owned());
+        }
+        let tag = frame.split_off(frame.len() - TAG_LEN);
+        let peer = match self.side {
+            Side::Client => Side::Server,
+            Side::Server => Side::Client,
+        };
+        self.tag(peer, self.received, &frame)
+            .verify_slice(&tag)
+            .map_err(|_| "unauthenticated peer message".to_owned())?;
+        self.received += 1;
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn key(byte: u8) -> PeerKey {
+        PeerKey(Arc::new(Zeroizing::new([byte; 32])))
+    }
+
+    #[test]
+    fn only_peers_with_the_key_are_accepted() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut results = Vec::new();
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().unwrap();
+                let mut channel = PeerChannel::accept(stream, key(1)).unwrap();
+                let result = channel.recv();
+                if let Ok(request) = &result {
+                    channel.send(request).unwrap();
+                }
+                results.push(result);
+            }
+            results
+        });
+        let mut client = PeerChannel::connect(TcpStream::connect(addr).unwrap(), key(1)).unwrap();
+        client.send(b"request").unwrap();
+        assert_eq!(client.recv().unwrap(), b"request");
+        let mut forger = PeerChannel::connect(TcpStream::connect(addr).unwrap(), key(2)).unwrap();
+        forger.send(b"request").unwrap();
+        let results = server.join().unwrap();
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+    }
+}
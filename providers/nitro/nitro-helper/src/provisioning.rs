@@ -0,0 +1,145 @@
+//! One-time provisioning of the consensus key from a BIP-39 mnemonic: the mnemonic is encrypted
+//! (off the host) to an X25519 key the enclave attested to, so only that enclave can read it.
+
+use crate::key_shares::{x25519_public_key, x25519_scalar};
+use bip39::Mnemonic;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use hkdf::Hkdf;
+use rand_core::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use zeroize::{Zeroize, Zeroizing};
+
+/// domain separation for deriving the mnemonic encryption key
+const MNEMONIC_KDF_INFO: &[u8] = b"tmkms-light mnemonic provisioning v1";
+
+/// a mnemonic encrypted to the enclave's attested X25519 key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedMnemonic {
+    /// X25519 public key of the enclave (from its attestation document)
+    pub recipient: [u8; 32],
+    /// ephemeral X25519 public key of the sender
+    pub ephemeral_public_key: [u8; 32],
+    /// ChaCha20Poly1305 nonce
+    pub nonce: [u8; 12],
+    /// the encrypted mnemonic
+    pub ciphertext: Vec<u8>,
+}
+
+/// Possible errors in provisioning a mnemonic
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProvisioningError {
+    /// not a valid BIP-39 (English) mnemonic
+    InvalidMnemonic,
+    /// AEAD encryption or decryption failed
+    EncryptionError,
+}
+
+/// derives the mnemonic encryption key from the X25519 shared secret
+fn mnemonic_key(
+    shared_secret: &MontgomeryPoint,
+    ephemeral: &[u8; 32],
+    recipient: &[u8; 32],
+) -> Key {
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(ephemeral);
+    salt[32..].copy_from_slice(recipient);
+    let hk = Hkdf::<Sha256>::new(Some(&salt), shared_secret.as_bytes());
+    let mut okm = Zeroizing::new([0u8; 32]);
+    hk.expand(MNEMONIC_KDF_INFO, &mut okm[..])
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    *Key::from_slice(&okm[..])
+}
+
+/// Checks the mnemonic (and its checksum) and encrypts it to the enclave's X25519 key
+pub fn seal_mnemonic<R: RngCore + CryptoRng>(
+    csprng: &mut R,
+    phrase: &str,
+    recipient: &[u8; 32],
+) -> Result<SealedMnemonic, ProvisioningError> {
+    let mnemonic = Mnemonic::parse(phrase).map_err(|_| ProvisioningError::InvalidMnemonic)?;
+    let normalized = Zeroizing::new(mnemonic.to_string());
+    let mut ephemeral_secret = [0u8; 32];
+    csprng.fill_bytes(&mut ephemeral_secret);
+    let ephemeral_public_key = x25519_public_key(&ephemeral_secret);
+    let shared_secret = MontgomeryPoint(*recipient) * x25519_scalar(ephemeral_secret);
+    ephemeral_secret.zeroize();
+    let key = mnemonic_key(&shared_secret, &ephemeral_public_key, recipient);
+    let mut nonce = [0u8; 12];
+    csprng.fill_bytes(&mut nonce);
+    let ciphertext = ChaCha20Poly1305::new(&key)
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: normalized.as_bytes(),
+                aad: recipient,
+            },
+        )
+        .map_err(|_| ProvisioningError::EncryptionError)?;
+    Ok(SealedMnemonic {
+        recipient: *recipient,
+        ephemeral_public_key,
+        nonce,
+        ciphertext,
+    })
+}
+
+/// Decrypts the mnemonic with the enclave's X25519 secret
+/// and returns its BIP-39 seed (without a passphrase)
+pub fn open_mnemonic(
+    sealed: &SealedMnemonic,
+    recipient_secret: &[u8; 32],
+) -> Result<Zeroizing<[u8; 64]>, ProvisioningError> {
+    let shared_secret =
+        MontgomeryPoint(sealed.ephemeral_public_key) * x25519_scalar(*recipient_secret);
+    let key = mnemonic_key(
+        &shared_secret,
+        &sealed.ephemeral_public_key,
+        &sealed.recipient,
+    );
+    let plaintext = Zeroizing::new(
+        ChaCha20Poly1305::new(&key)
+            .decrypt(
+                Nonce::from_slice(&sealed.nonce),
+                Payload {
+                    msg: &sealed.ciphertext,
+                    aad: &sealed.recipient,
+                },
+            )
+            .map_err(|_| ProvisioningError::EncryptionError)?,
+    );
+    let phrase = std::str::from_utf8(&plaintext).map_err(|_| ProvisioningError::InvalidMnemonic)?;
+    let mnemonic = Mnemonic::parse(phrase).map_err(|_| ProvisioningError::InvalidMnemonic)?;
+    Ok(Zeroizing::new(mnemonic.to_seed("")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_core::OsRng;
+
+    #[test]
+    fn mnemonic_round_trip() {
+        let phrase = "abandon abandon abandon abandon abandon abandon abandon abandon \
+                      abandon abandon abandon about";
+        let secret = [7u8; 32];
+        let recipient = x25519_public_key(&secret);
+        let sealed = seal_mnemonic(&mut OsRng, phrase, &recipient).unwrap();
+        let seed = open_mnemonic(&sealed, &secret).unwrap();
+        // the BIP-39 test vector seed (without a passphrase)
+        assert_eq!(
+            subtle_encoding::hex::encode(&seed[..4]),
+            b"5eb00bbd".to_vec()
+        );
+        assert_eq!(
+            open_mnemonic(&sealed, &[8u8; 32]).unwrap_err(),
+            ProvisioningError::EncryptionError
+        );
+        assert_eq!(
+            seal_mnemonic(&mut OsRng, "abandon abandon", &recipient).unwrap_err(),
+            ProvisioningError::InvalidMnemonic
+        );
+    }
+}
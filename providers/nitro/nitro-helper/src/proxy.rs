@@ -1,51 +1,184 @@
-use crate::shared::VSOCK_HOST_CID;
+use crate::ha::HaNode;
+use crate::health::HealthState;
+use crate::mux_server::ChannelListener;
+use crate::peer_auth::{PeerChannel, PeerKey};
+use crate::recorder::{Direction, TrafficRecorder};
 use nix::sys::select::{select, FdSet};
+use nix::sys::time::{TimeVal, TimeValLike};
 use std::io::Read;
 use std::io::Write;
+use std::net::{TcpListener, TcpStream};
 use std::os::unix::io::AsRawFd;
-use std::os::unix::net::UnixStream;
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
-use tracing::{error, info, trace};
-use vsock::{VsockAddr, VsockListener};
+use tendermint_config::net;
+use tmkms_light::socket_activation::{self, ActivatedListener};
+use tmkms_light::utils::{read_u16_payload, write_u16_payload};
+use tracing::{debug, error, info, trace};
+
+/// the validator side of the proxy
+pub enum Remote {
+    /// connects to the validator's unix socket
+    Unix(PathBuf),
+    /// connects to a tcp endpoint (e.g. the external state store)
+    Tcp(String),
+    /// accepts the validator's connection on a unix socket
+    UnixListener(UnixListener),
+    /// accepts the validator's connection on a tcp socket
+    TcpListener(TcpListener),
+}
+
+impl Remote {
+    /// binds the listener on the address the validator dials
+    /// (or uses the `privval` socket passed by systemd socket activation)
+    pub fn listen(address: &net::Address) -> Result<Self, String> {
+        match socket_activation::take_listener("privval") {
+            Some(ActivatedListener::Tcp(listener)) => {
+                info!("using the activated privval socket");
+                return Ok(Remote::TcpListener(listener));
+            }
+            Some(ActivatedListener::Unix(listener)) => {
+                info!("using the activated privval socket");
+                return Ok(Remote::UnixListener(listener));
+            }
+            None => {}
+        }
+        match address {
+            net::Address::Unix { path } => UnixListener::bind(path)
+                .map(Remote::UnixListener)
+                .map_err(|e| format!("Could not bind to {}: {:?}", path, e)),
+            net::Address::Tcp { host, port, .. } => TcpListener::bind((host.as_str(), *port))
+                .map(Remote::TcpListener)
+                .map_err(|e| format!("Could not bind to {}:{}: {:?}", host, port, e)),
+        }
+    }
+
+    fn connect(&self) -> Result<Box<dyn Stream>, String> {
+        match self {
+            Remote::Unix(path) => UnixStream::connect(path)
+                .map(|s| Box::new(s) as Box<dyn Stream>)
+                .map_err(|_| format!("Could not connect to {:?}", path)),
+            Remote::Tcp(addr) => TcpStream::connect(addr)
+                .map(|s| Box::new(s) as Box<dyn Stream>)
+                .map_err(|_| format!("Could not connect to {}", addr)),
+            Remote::UnixListener(listener) => {
+                info!("waiting for the validator to connect");
+                listener
+                    .accept()
+                    .map(|(s, _)| Box::new(s) as Box<dyn Stream>)
+                    .map_err(|_| "Could not accept the validator connection".to_owned())
+            }
+            Remote::TcpListener(listener) => {
+                info!("waiting for the validator to connect");
+                listener
+                    .accept()
+                    .map(|(s, addr)| {
+                        info!("Accepted validator connection from {}", addr);
+                        Box::new(s) as Box<dyn Stream>
+                    })
+                    .map_err(|_| "Could not accept the validator connection".to_owned())
+            }
+        }
+    }
+}
+
+/// the validator-side socket
+trait Stream: Read + Write + AsRawFd {}
+
+impl Stream for UnixStream {}
+impl Stream for TcpStream {}
 
 /// Configuration parameters for port listening and remote destination
 pub struct Proxy {
     local_port: u32,
-    remote_addr: PathBuf,
+    remote: Remote,
+    health: Arc<HealthState>,
+    /// only proxies the connection while being the leader (if HA is enabled)
+    ha: Option<Arc<HaNode>>,
+    /// capture file of the relayed privval messages (if set)
+    capture_path: Option<PathBuf>,
+    /// authenticates the relayed messages with the remote service (if set)
+    peer_key: Option<PeerKey>,
 }
 
 impl Proxy {
-    /// creates a new vsock<->uds proxy
-    pub fn new(local_port: u32, remote_addr: PathBuf) -> Self {
+    /// creates a new vsock<->uds/tcp proxy
+    pub fn new(local_port: u32, remote: Remote, health: Arc<HealthState>) -> Self {
         Self {
             local_port,
-            remote_addr,
+            remote,
+            health,
+            ha: None,
+            capture_path: None,
+            peer_key: None,
         }
     }
 
+    /// records the relayed privval messages to the capture file
+    pub fn set_capture(&mut self, capture_path: PathBuf) {
+        self.capture_path = Some(capture_path);
+    }
+
+    /// only proxies the validator connection while being the HA leader
+    pub fn set_ha(&mut self, ha: Arc<HaNode>) {
+        self.ha = Some(ha);
+    }
+
+    /// relays the (length-prefixed) messages of a request-response service
+    /// with the peer key's authentication
+    pub fn set_peer_key(&mut self, peer_key: PeerKey) {
+        self.peer_key = Some(peer_key);
+    }
+
+    fn is_leader(&self) -> bool {
+        self.ha.as_ref().map_or(true, |ha| ha.is_leader())
+    }
+
     /// Creates a listening socket
     /// Returns the file descriptor for it or the appropriate error
-    pub fn sock_listen(&self) -> Result<VsockListener, String> {
+    pub fn sock_listen(&self) -> Result<ChannelListener, String> {
         info!("binding proxy to vsock port: {}", self.local_port);
-        let sockaddr = VsockAddr::new(VSOCK_HOST_CID, self.local_port);
-        let listener = VsockListener::bind(&sockaddr)
-            .map_err(|_| format!("Could not bind to {:?}", sockaddr))?;
-        info!("Bound to {:?}", sockaddr);
+        let listener = ChannelListener::bind(self.local_port)
+            .map_err(|_| format!("Could not bind to vsock port {}", self.local_port))?;
+        info!("Bound to vsock port {}", self.local_port);
         Ok(listener)
     }
 
     /// Accepts an incoming connection coming on listener and handles it on a
     /// different thread
     /// Returns the handle for the new thread or the appropriate error
-    pub fn sock_accept(&self, listener: &VsockListener) -> Result<(), String> {
+    pub fn sock_accept(&self, listener: &ChannelListener) -> Result<(), String> {
         let (mut client, client_addr) = listener
             .accept()
             .map_err(|_| "Could not accept connection")?;
         info!("Accepted connection on {:?}", client_addr);
-        let mut server = UnixStream::connect(&self.remote_addr)
-            .map_err(|_| format!("Could not connect to {:?}", self.remote_addr))?;
+        if !self.is_leader() {
+            debug!("not the leader, closing the connection");
+            thread::sleep(Duration::new(1, 0));
+            return Ok(());
+        }
+        let mut server = self.remote.connect()?;
+        if let Some(peer_key) = &self.peer_key {
+            let mut channel = PeerChannel::connect(server, peer_key.clone())?;
+            while let Ok(()) = relay_authenticated(&mut client, &mut channel) {}
+            info!("Client on {:?} disconnected", client_addr);
+            return Ok(());
+        }
+        let mut recorder = match &self.capture_path {
+            Some(path) => match TrafficRecorder::open(path) {
+                Ok(recorder) => Some(recorder),
+                Err(e) => {
+                    error!("privval traffic isn't recorded: {}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        self.health.set_validator_connected(true);
 
         let client_socket = client.as_raw_fd();
         let server_socket = server.as_raw_fd();
@@ -59,17 +192,39 @@ impl Proxy {
             trace!("proxy local addr: {:?}", client.local_addr());
             trace!("proxy fd: {} {}", client.as_raw_fd(), client_socket);
             trace!("proxy uds/server fd: {}", server_socket);
-            select(None, Some(&mut set), None, None, None).expect("select");
+            // the leadership is checked every second (if HA is enabled)
+            let mut timeout = TimeVal::seconds(1);
+            select(
+                None,
+                Some(&mut set),
+                None,
+                None,
+                self.ha.as_ref().map(|_| &mut timeout),
+            )
+            .expect("select");
+            if !self.is_leader() {
+                info!("lost the leadership, closing the validator connection");
+                break;
+            }
 
             trace!("client -> server");
             if set.contains(client_socket) {
-                disconnected = transfer(&mut client, &mut server);
+                disconnected = transfer(
+                    &mut client,
+                    &mut server,
+                    recorder.as_mut().map(|r| (r, Direction::Response)),
+                );
             }
             trace!("server -> client");
             if set.contains(server_socket) {
-                disconnected = transfer(&mut server, &mut client);
+                disconnected = transfer(
+                    &mut server,
+                    &mut client,
+                    recorder.as_mut().map(|r| (r, Direction::Request)),
+                );
             }
         }
+        self.health.set_validator_connected(false);
         info!("Client on {:?} disconnected", client_addr);
         Ok(())
     }
@@ -93,9 +248,24 @@ impl Proxy {
     }
 }
 
-/// Transfers a chunck of maximum 8KB from src to dst
+/// relays a request to the service and its response back
+fn relay_authenticated<C: Read + Write, S: Read + Write>(
+    client: &mut C,
+    channel: &mut PeerChannel<S>,
+) -> Result<(), String> {
+    let request = read_u16_payload(client).map_err(|e| e.to_string())?;
+    channel.send(&request)?;
+    let response = channel.recv()?;
+    write_u16_payload(client, &response).map_err(|e| e.to_string())
+}
+
+/// Transfers a chunck of maximum 8KB from src to dst (and records it if a recorder is given)
 /// If no error occurs, returns true if the source disconnects and false otherwise
-fn transfer(src: &mut dyn Read, dst: &mut dyn Write) -> bool {
+fn transfer(
+    src: &mut dyn Read,
+    dst: &mut dyn Write,
+    recorder: Option<(&mut TrafficRecorder, Direction)>,
+) -> bool {
     const BUFF_SIZE: usize = 8192;
 
     let mut buffer = [0u8; BUFF_SIZE];
@@ -107,5 +277,8 @@ fn transfer(src: &mut dyn Read, dst: &mut dyn Write) -> bool {
         return true;
     }
     trace!("transfer data: {:02X?}", &buffer[..nbytes]);
+    if let Some((recorder, direction)) = recorder {
+        recorder.record(direction, &buffer[..nbytes]);
+    }
     dst.write_all(&buffer[..nbytes]).is_err()
 }
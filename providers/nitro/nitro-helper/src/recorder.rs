@@ -0,0 +1,200 @@
+//! Capture of the privval messages relayed between the validator and the enclave
+//! (JSON lines with timestamps), so that field incidents can be reproduced with `helper replay`.
+//! Only plain connections (e.g. Unix domain sockets) can be recorded: the encrypted ones
+//! (`tcp://` with the secret connection, Noise or TLS) aren't decodable on the host.
+
+use prost::Message as _;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tendermint_proto::privval::Message as PrivMessage;
+use tracing::warn;
+
+/// larger length prefixes mean the traffic isn't plain privval messages
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// direction of a captured message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// from the validator to the signer
+    Request,
+    /// from the signer to the validator
+    Response,
+}
+
+/// a captured privval message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CapturedMessage {
+    /// milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    pub direction: Direction,
+    /// base64-encoded protobuf message (without its length prefix)
+    pub message: String,
+}
+
+impl CapturedMessage {
+    pub fn decode(&self) -> Result<PrivMessage, String> {
+        let raw = subtle_encoding::base64::decode(&self.message)
+            .map_err(|e| format!("invalid base64 message: {:?}", e))?;
+        PrivMessage::decode(raw.as_slice()).map_err(|e| format!("invalid message: {:?}", e))
+    }
+}
+
+/// reads a capture file
+pub fn read_capture(path: &Path) -> Result<Vec<CapturedMessage>, String> {
+    let file =
+        File::open(path).map_err(|e| format!("failed to open `{}`: {:?}", path.display(), e))?;
+    let mut messages = vec![];
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("failed to read `{}`: {:?}", path.display(), e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let message = serde_json::from_str(&line)
+            .map_err(|e| format!("invalid capture line {}: {}", i + 1, e))?;
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+/// splits a byte stream into the length-delimited messages
+#[derive(Debug, Default)]
+pub struct FrameSplitter {
+    buf: Vec<u8>,
+}
+
+impl FrameSplitter {
+    /// the complete messages after the bytes (an error if they aren't length-delimited messages)
+    pub fn push(&mut self, bytes: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+        self.buf.extend_from_slice(bytes);
+        let mut frames = vec![];
+        loop {
+            let mut len = 0usize;
+            let mut prefix_len = None;
+            for (i, byte) in self.buf.iter().take(10).enumerate() {
+                len |= ((byte & 0x7f) as usize) << (7 * i);
+                if byte & 0x80 == 0 {
+                    prefix_len = Some(i + 1);
+                    break;
+                }
+            }
+            let prefix_len = match prefix_len {
+                Some(prefix_len) => prefix_len,
+                None if self.buf.len() >= 10 => return Err("invalid length prefix".to_owned()),
+                None => return Ok(frames),
+            };
+            if len > MAX_MESSAGE_SIZE {
+                return Err(format!("message length {} is too large", len));
+            }
+            if self.buf.len() < prefix_len + len {
+                return Ok(frames);
+            }
+            let frame = self.buf[prefix_len..prefix_len + len].to_vec();
+            self.buf.drain(..prefix_len + len);
+            frames.push(frame);
+        }
+    }
+}
+
+/// appends the relayed messages of a connection to the capture file
+pub struct TrafficRecorder {
+    file: File,
+    requests: FrameSplitter,
+    responses: FrameSplitter,
+    /// the traffic couldn't be decoded (or written), so it's no longer recorded
+    stopped: bool,
+}
+
+impl TrafficRecorder {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("failed to open `{}`: {:?}", path.display(), e))?;
+        Ok(Self {
+            file,
+            requests: FrameSplitter::default(),
+            responses: FrameSplitter::default(),
+            stopped: false,
+        })
+    }
+
+    /// records the complete messages of the relayed bytes
+    pub fn record(&mut self, direction: Direction, bytes: &[u8]) {
+        if self.stopped {
+            return;
+        }
+        if let Err(e) = self.try_record(direction, bytes) {
+            warn!("stopped recording the privval traffic: {}", e);
+            self.stopped = true;
+        }
+    }
+
+    fn try_record(&mut self, direction: Direction, bytes: &[u8]) -> Result<(), String> {
+        let splitter = match direction {
+            Direction::Request => &mut self.requests,
+            Direction::Response => &mut self.responses,
+        };
+        let frames = splitter
+            .push(bytes)
+            .map_err(|e| format!("not plain privval traffic ({})", e))?;
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        for frame in frames {
+            PrivMessage::decode(frame.as_slice())
+                .map_err(|e| format!("not plain privval traffic ({})", e))?;
+            let message = String::from_utf8(subtle_encoding::base64::encode(&frame))
+                .map_err(|e| format!("encoding the message: {:?}", e))?;
+            let mut line = serde_json::to_string(&CapturedMessage {
+                timestamp_ms,
+                direction,
+                message,
+            })
+            .map_err(|e| format!("serializing the message: {:?}", e))?;
+            line.push('\n');
+            self.file
+                .write_all(line.as_bytes())
+                .map_err(|e| format!("writing the capture: {:?}", e))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tendermint_proto::privval::{message::Sum, PingRequest};
+
+    #[test]
+    fn records_split_messages() {
+        let ping = PrivMessage {
+            sum: Some(Sum::PingRequest(PingRequest {})),
+        };
+        let framed = [
+            ping.encode_length_delimited_to_vec(),
+            ping.encode_length_delimited_to_vec(),
+        ]
+        .concat();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("capture.jsonl");
+        let mut recorder = TrafficRecorder::open(&path).unwrap();
+        recorder.record(Direction::Request, &framed[..1]);
+        recorder.record(Direction::Request, &framed[1..]);
+        let captured = read_capture(&path).unwrap();
+        assert_eq!(captured.len(), 2);
+        assert_eq!(captured[0].direction, Direction::Request);
+        assert_eq!(captured[1].decode().unwrap(), ping);
+
+        // encrypted traffic stops the recording
+        recorder.record(Direction::Response, &[0xff; 16]);
+        recorder.record(Direction::Request, &framed);
+        assert_eq!(read_capture(&path).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,205 @@
+//! Versioned machine-readable records (status outputs and audit log entries).
+//! Each record is tagged with its schema (e.g. `"schema": "tmkms.status.v1"`);
+//! within a version, fields are only ever added (as optional ones),
+//! so that existing parsers keep working across releases.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// status output
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "schema")]
+pub enum Status {
+    #[serde(rename = "tmkms.status.v1")]
+    V1(StatusV1),
+}
+
+/// status of the helper and the enclave's chain sessions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatusV1 {
+    /// version of the reporting helper
+    pub version: String,
+    /// chain sessions running in the enclave
+    pub chains: Vec<ChainStatusV1>,
+}
+
+/// status of a chain session
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainStatusV1 {
+    /// Chain ID of the session
+    pub chain_id: String,
+    /// `running`, `paused`, `maintenance` or `stopped`
+    pub status: String,
+}
+
+/// signing status served by the helper's status API (`/status`)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "schema")]
+pub enum SigningStatus {
+    #[serde(rename = "tmkms.signing-status.v1")]
+    V1(SigningStatusV1),
+}
+
+/// the helper's connections and the enclave's signing statistics
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SigningStatusV1 {
+    /// version of the reporting helper
+    pub version: String,
+    /// seconds since the helper started
+    pub uptime_secs: u64,
+    /// the enclave is connected to the state syncer
+    pub enclave_connected: bool,
+    /// the last state persistence succeeded
+    pub state_persisted: bool,
+    /// the proxied validator connection is established (if it's proxied by the helper)
+    pub validator_connected: Option<bool>,
+    /// seconds since the latest runtime attestation (if attestations are enabled and received)
+    pub attestation_age_secs: Option<u64>,
+    /// statistics since the start of each chain with signed or ping requests
+    /// (empty if `metrics` isn't enabled)
+    pub chains: Vec<ChainSigningStatusV1>,
+}
+
+/// the signing statistics of a chain
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainSigningStatusV1 {
+    pub chain_id: String,
+    /// requests by type (`proposal`, `prevote`, `precommit` or `ping`)
+    pub requests: BTreeMap<String, u64>,
+    pub last_signed_height: i64,
+    pub last_signed_round: i64,
+    /// `proposal`, `prevote` or `precommit` (if anything was signed)
+    pub last_signed_step: Option<String>,
+    /// UNIX seconds when the last signature was recorded
+    pub last_signed_timestamp: u64,
+}
+
+/// audit log entry
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "schema")]
+pub enum AuditRecord {
+    #[serde(rename = "tmkms.audit.v1")]
+    V1(AuditRecordV1),
+    #[serde(rename = "tmkms.audit.refusal.v1")]
+    RefusalV1(AuditRefusalV1),
+    #[serde(rename = "tmkms.audit.anchor.v1")]
+    AnchorV1(AuditAnchorV1),
+}
+
+impl AuditRecord {
+    /// position in the log
+    pub fn sequence(&self) -> u64 {
+        match self {
+            AuditRecord::V1(record) => record.sequence,
+            AuditRecord::RefusalV1(record) => record.sequence,
+            AuditRecord::AnchorV1(record) => record.sequence,
+        }
+    }
+
+    /// hex-encoded hash of the previous record
+    pub fn prev_hash(&self) -> &str {
+        match self {
+            AuditRecord::V1(record) => &record.prev_hash,
+            AuditRecord::RefusalV1(record) => &record.prev_hash,
+            AuditRecord::AnchorV1(record) => &record.prev_hash,
+        }
+    }
+}
+
+/// a signature produced by the KMS
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecordV1 {
+    /// position in the log (starting from 0)
+    pub sequence: u64,
+    /// RFC 3339 time when the signature was produced
+    pub timestamp: String,
+    /// Chain ID of the signed message
+    pub chain_id: String,
+    /// `proposal`, `prevote` or `precommit`
+    pub msg_type: String,
+    pub height: i64,
+    pub round: i64,
+    /// hex-encoded hash of the signed block ID (if any)
+    pub block_id_hash: Option<String>,
+    /// base64-encoded signature
+    pub signature: String,
+    /// hex-encoded hash of the previous record (empty for the first one)
+    pub prev_hash: String,
+}
+
+/// a signing request refused by the KMS (only logged if `dump_refused_sign_bytes` is set)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRefusalV1 {
+    /// position in the log (starting from 0)
+    pub sequence: u64,
+    /// RFC 3339 time when the request was refused
+    pub timestamp: String,
+    /// Chain ID of the request
+    pub chain_id: String,
+    /// `proposal`, `prevote` or `precommit`
+    pub msg_type: String,
+    pub height: i64,
+    pub round: i64,
+    /// the error returned to the validator
+    pub reason: String,
+    /// hex-encoded canonical sign-bytes of the request
+    pub sign_bytes: String,
+    /// hex-encoded hash of the previous record (empty for the first one)
+    pub prev_hash: String,
+}
+
+/// the head of the enclave's chain of the records it sent on a connection
+/// since its previous anchor, signed with the consensus key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditAnchorV1 {
+    /// position in the log (starting from 0)
+    pub sequence: u64,
+    /// RFC 3339 time when the anchor was recorded
+    pub timestamp: String,
+    /// positions of the anchored records
+    pub covers: Vec<u64>,
+    /// hex-encoded head at the previous anchor of the connection (zeros for the first one)
+    pub start: String,
+    /// hex-encoded head after the anchored records
+    pub head: String,
+    /// base64-encoded consensus public key
+    pub public_key: String,
+    /// base64-encoded signature
+    pub signature: String,
+    /// hex-encoded hash of the previous record (empty for the first one)
+    pub prev_hash: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the published v1 forms: these must keep parsing in later releases
+    const STATUS_V1: &str = r#"{"schema":"tmkms.status.v1","version":"0.4.2","chains":[{"chain_id":"testnet-croeseid-4","status":"running"}]}"#;
+    const SIGNING_STATUS_V1: &str = r#"{"schema":"tmkms.signing-status.v1","version":"0.4.2","uptime_secs":60,"enclave_connected":true,"state_persisted":true,"validator_connected":null,"attestation_age_secs":10,"chains":[{"chain_id":"testnet-croeseid-4","requests":{"ping":2,"prevote":1},"last_signed_height":10,"last_signed_round":0,"last_signed_step":"prevote","last_signed_timestamp":1600000000}]}"#;
+    const AUDIT_V1: &str = r#"{"schema":"tmkms.audit.v1","sequence":1,"timestamp":"2021-01-01T00:00:00Z","chain_id":"testnet-croeseid-4","msg_type":"prevote","height":10,"round":0,"block_id_hash":null,"signature":"AA==","prev_hash":"00"}"#;
+
+    #[test]
+    fn status_v1_is_stable() {
+        let status: Status = serde_json::from_str(STATUS_V1).unwrap();
+        assert_eq!(serde_json::to_string(&status).unwrap(), STATUS_V1);
+    }
+
+    #[test]
+    fn signing_status_v1_is_stable() {
+        let status: SigningStatus = serde_json::from_str(SIGNING_STATUS_V1).unwrap();
+        assert_eq!(serde_json::to_string(&status).unwrap(), SIGNING_STATUS_V1);
+    }
+
+    #[test]
+    fn audit_v1_is_stable() {
+        let record: AuditRecord = serde_json::from_str(AUDIT_V1).unwrap();
+        assert_eq!(serde_json::to_string(&record).unwrap(), AUDIT_V1);
+    }
+
+    #[test]
+    fn unknown_fields_are_ignored() {
+        let newer = STATUS_V1.replacen("\"chains\"", "\"uptime\":1,\"chains\"", 1);
+        assert!(serde_json::from_str::<Status>(&newer).is_ok());
+    }
+}
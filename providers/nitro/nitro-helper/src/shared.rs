@@ -1,66 +1,871 @@
+use crate::backoff::BackoffConfig;
+use crate::backup::{BackupShare, KeyBackup};
+use crate::control::AuthenticatedRequest;
+use crate::key_shares::KeyShares;
+use crate::provisioning::SealedMnemonic;
+use crate::slip10::DerivationPath;
 use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::fmt;
 use tendermint::{chain, node};
+use tmkms_light::audit::SignedMsgKind;
+use tmkms_light::chain::state::{consensus, Durability, MacedState};
+use tmkms_light::connection::tls::TlsCredentials;
+use tmkms_light::connection::{ConnectionTimeouts, ProtocolVersion, Transport};
+use tmkms_light::policy::SigningPolicy;
+use tmkms_light::session::SessionStatus;
 
 /// CID for listening on the host
 pub const VSOCK_HOST_CID: u32 = 3;
 
 /// Nitro config to be pushed to the enclave
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(deny_unknown_fields)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NitroConfig {
     /// Chain ID of the Tendermint network this validator is part of
     pub chain_id: chain::Id,
     /// Height at which to stop signing
     pub max_height: Option<tendermint::block::Height>,
+    /// Rules that signing requests must comply with
+    pub signing_policy: SigningPolicy,
+    /// chain IDs the enclave may sign for (any if empty)
+    pub allowed_chain_ids: Vec<chain::Id>,
     /// AWS KMS-encrypted key
+    #[serde(with = "serde_bytes")]
     pub sealed_consensus_key: Vec<u8>,
+    /// path of the consensus key if `sealed_consensus_key` is a master seed
+    pub consensus_key_derivation: Option<DerivationPath>,
     /// AWS KMS-encrypted Ed25519 identity key (if secret connection)
+    #[serde(default, with = "serde_bytes")]
     pub sealed_id_key: Option<Vec<u8>>,
     /// peer id to check with secret connections
     pub peer_id: Option<node::Id>,
+    /// message types that can be signed over the primary validator connection (if restricted)
+    pub validator_msg_types: Option<Vec<SignedMsgKind>>,
+    /// read/write timeouts of the validator and state connections
+    pub timeouts: ConnectionTimeouts,
+    /// secret connection protocol version
+    pub protocol_version: ProtocolVersion,
+    /// encrypted transport of the validator connection
+    pub transport: Transport,
+    /// noise static key of the remote endpoint
+    #[serde(default, with = "serde_bytes")]
+    pub noise_remote_key: Option<Vec<u8>>,
+    /// mutual TLS certificates and key
+    pub tls: Option<TlsCredentials>,
+    /// refuse secret connections whose peer id can't be checked
+    pub require_peer_id: bool,
     /// Vsock port to listen on for state synchronization
     pub enclave_state_port: u32,
+    /// Vsock port the channels to the host are multiplexed on (if any)
+    pub enclave_mux_port: Option<u32>,
+    /// Vsock port relayed to the external state store (if any)
+    pub enclave_remote_state_port: Option<u32>,
+    /// sinks that need to persist a state update before signing
+    pub state_durability: Durability,
+    /// only simulate the signing requests (refused, the state is never persisted)
+    pub dry_run: bool,
+    /// send the sign-bytes of the refused signing requests to the audit log
+    pub dump_refused_sign_bytes: bool,
+    /// the last height committed with the validator's signature (queried by the helper),
+    /// at or below which nothing is signed
+    pub committed_height: Option<u64>,
+    /// Vsock port relayed to the watermark service (if any)
+    pub enclave_watermark_port: Option<u32>,
+    /// name of this signer instance (for the watermark service)
+    pub replica_id: String,
+    /// Vsock port to check the lease with before signing (if any)
+    pub enclave_lease_port: Option<u32>,
+    /// Vsock port relayed to the monotonic state service (if any)
+    pub enclave_monotonic_port: Option<u32>,
     /// Vsock port to forward privval plain traffic to TM over UDS or TCP
     pub enclave_tendermint_conn: u32,
-    /// AWS credentials -- if not set, they'll be obtained from IAM
+    /// validator connections to fail over to (in order) if the primary one can't be established
+    pub failover_conns: Vec<ValidatorConn>,
+    /// Vsock port to send the signature audit records to (if enabled)
+    pub enclave_audit_port: Option<u32>,
+    /// Vsock port to send the metrics events to (if enabled)
+    pub enclave_metrics_port: Option<u32>,
+    /// vsock port to push the runtime attestations to (if enabled)
+    pub enclave_attestation_port: Option<u32>,
+    /// how often the runtime attestations are produced
+    pub attestation_interval_secs: u64,
+    /// vsock port to report the stop at `max_height` to (if it's set)
+    pub enclave_halt_port: Option<u32>,
+    /// vsock port to relay the spans to (if the traces are exported)
+    pub enclave_trace_port: Option<u32>,
+    /// synchronization of the enclave's clock from the host (if enabled)
+    pub time_sync: Option<NitroTimeSync>,
+    /// approval service of the signatures required by the signing policy (if any)
+    pub approval: Option<NitroApproval>,
+    /// backoff of the reconnections to the validator
+    pub reconnect_backoff: BackoffConfig,
+    /// AWS credentials (the ones in the helper's config, or obtained from IAM by the helper)
     pub credentials: AwsCredentials,
     /// AWS region
     pub aws_region: String,
+    /// regions of the KMS key replicas to fail over to (in order)
+    pub kms_failover_regions: Vec<String>,
+}
+
+impl NitroConfig {
+    /// the configured chain is allowed to be signed for
+    pub fn chain_allowed(&self) -> bool {
+        self.allowed_chain_ids.is_empty() || self.allowed_chain_ids.contains(&self.chain_id)
+    }
+}
+
+/// synchronization of the enclave's clock from the host
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NitroTimeSync {
+    /// vsock port of the host's time server
+    pub port: u32,
+    /// key authenticating the host's time (generated at each launch)
+    #[serde(with = "serde_bytes")]
+    pub key: Vec<u8>,
+    /// how often the clock is synchronized
+    pub interval_secs: u64,
+}
+
+/// the approvals of the signatures (two-person rule)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NitroApproval {
+    /// vsock port relayed to the approval service
+    /// (the tokens are verified with the approver key of the enclave policy)
+    pub port: u32,
+}
+
+/// a failover validator connection
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValidatorConn {
+    /// Vsock port of the validator connection
+    pub enclave_tendermint_conn: u32,
+    /// peer id to check with secret connections
+    pub peer_id: Option<node::Id>,
+    /// message types that can be signed over this connection (if restricted)
+    #[serde(default)]
+    pub allowed_msg_types: Option<Vec<SignedMsgKind>>,
+}
+
+/// a replica of an AWS KMS multi-Region key
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KmsReplica {
+    /// AWS region of the replica
+    pub aws_region: String,
+    /// AWS key ARN of the replica
+    pub kms_key_id: String,
+}
+
+/// what a generated key is used for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyPurpose {
+    /// the consensus key
+    #[default]
+    Consensus,
+    /// the secret connection identity key
+    Identity,
+}
+
+impl KeyPurpose {
+    /// as in the attested claim of the generated key
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyPurpose::Consensus => "consensus",
+            KeyPurpose::Identity => "identity",
+        }
+    }
 }
 
 /// configuration sent during key generation
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NitroKeygenConfig {
-    /// AWS credentials -- if not set, they'll be obtained from IAM
+    /// AWS credentials (the ones in the helper's config, or obtained from IAM by the helper)
+    pub credentials: AwsCredentials,
+    /// AWS key id
+    pub kms_key_id: String,
+    /// AWS region
+    pub aws_region: String,
+    /// included in the attestation of the generated key (so an old attestation can't be replayed)
+    #[serde(with = "serde_bytes")]
+    pub nonce: Vec<u8>,
+    /// key replicas to fail over to (in order)
+    pub kms_replicas: Vec<KmsReplica>,
+    /// what the key is used for (included in its attestation)
+    #[serde(default)]
+    pub purpose: KeyPurpose,
+    /// if set, a master seed is generated and sealed instead (the key is derived from it at this path)
+    #[serde(default)]
+    pub derivation_path: Option<DerivationPath>,
+}
+
+/// configuration sent when splitting the consensus key into threshold shares
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NitroKeySharesConfig {
+    /// AWS credentials (the ones in the helper's config, or obtained from IAM by the helper)
+    pub credentials: AwsCredentials,
+    /// AWS region
+    pub aws_region: String,
+    /// regions of the KMS key replicas to fail over to (in order)
+    pub kms_failover_regions: Vec<String>,
+    /// AWS KMS-encrypted consensus key
+    #[serde(with = "serde_bytes")]
+    pub sealed_consensus_key: Vec<u8>,
+    /// path of the consensus key if `sealed_consensus_key` is a master seed
+    pub consensus_key_derivation: Option<DerivationPath>,
+    /// number of shares needed to sign
+    pub threshold: u16,
+    /// attestation documents of the future cosigner enclaves (binding their X25519 public keys),
+    /// which the enclave checks against its policy
+    pub recipient_attestations: Vec<ByteBuf>,
+}
+
+/// configuration sent when re-encrypting a sealed key with another AWS KMS key
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NitroRewrapConfig {
+    /// AWS credentials (the ones in the helper's config, or obtained from IAM by the helper)
+    pub credentials: AwsCredentials,
+    /// AWS region
+    pub aws_region: String,
+    /// regions of the KMS key replicas to fail over to (in order)
+    pub kms_failover_regions: Vec<String>,
+    /// AWS KMS-encrypted key
+    #[serde(with = "serde_bytes")]
+    pub sealed_key: Vec<u8>,
+    /// path of the key if `sealed_key` is a master seed
+    pub derivation_path: Option<DerivationPath>,
+    /// AWS key id to encrypt it with
+    pub kms_key_id: String,
+    /// included in the attestation of the re-encrypted key (so an old attestation can't be replayed)
+    #[serde(default, with = "serde_bytes")]
+    pub nonce: Vec<u8>,
+}
+
+/// configuration sent when deriving a key from a sealed master seed
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NitroDeriveConfig {
+    /// AWS credentials (the ones in the helper's config, or obtained from IAM by the helper)
+    pub credentials: AwsCredentials,
+    /// AWS region
+    pub aws_region: String,
+    /// regions of the KMS key replicas to fail over to (in order)
+    pub kms_failover_regions: Vec<String>,
+    /// AWS KMS-encrypted master seed
+    #[serde(with = "serde_bytes")]
+    pub sealed_seed: Vec<u8>,
+    /// path of the key to derive
+    pub derivation_path: DerivationPath,
+    /// included in the attestation of the derived key (so an old attestation can't be replayed)
+    #[serde(with = "serde_bytes")]
+    pub nonce: Vec<u8>,
+}
+
+/// configuration sent to provision the consensus key from a BIP-39 mnemonic
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NitroMnemonicConfig {
+    /// AWS credentials (the ones in the helper's config, or obtained from IAM by the helper)
+    pub credentials: AwsCredentials,
+    /// AWS key id
+    pub kms_key_id: String,
+    /// AWS region
+    pub aws_region: String,
+    /// the mnemonic encrypted to the key attested by `ProvisionBegin`
+    pub sealed_mnemonic: SealedMnemonic,
+    /// path of the consensus key (derived from the mnemonic's seed)
+    pub derivation_path: DerivationPath,
+    /// included in the attestation of the key (so an old attestation can't be replayed)
+    #[serde(with = "serde_bytes")]
+    pub nonce: Vec<u8>,
+}
+
+/// configuration sent to sign an arbitrary payload (as a proof of possession of the consensus key)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NitroSignPayloadConfig {
+    /// AWS credentials (the ones in the helper's config, or obtained from IAM by the helper)
+    pub credentials: AwsCredentials,
+    /// AWS region
+    pub aws_region: String,
+    /// regions of the KMS key replicas to fail over to (in order)
+    pub kms_failover_regions: Vec<String>,
+    /// AWS KMS-encrypted consensus key
+    #[serde(with = "serde_bytes")]
+    pub sealed_consensus_key: Vec<u8>,
+    /// path of the consensus key if `sealed_consensus_key` is a master seed
+    pub consensus_key_derivation: Option<DerivationPath>,
+    /// the payload (signed with the proof-of-possession domain separation)
+    #[serde(with = "serde_bytes")]
+    pub payload: Vec<u8>,
+}
+
+/// configuration sent to authenticate a state without a MAC
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NitroMigrateStateConfig {
+    /// AWS credentials (the ones in the helper's config, or obtained from IAM by the helper)
+    pub credentials: AwsCredentials,
+    /// AWS region
+    pub aws_region: String,
+    /// regions of the KMS key replicas to fail over to (in order)
+    pub kms_failover_regions: Vec<String>,
+    /// AWS KMS-encrypted consensus key (the state's MAC key is derived from it)
+    #[serde(with = "serde_bytes")]
+    pub sealed_consensus_key: Vec<u8>,
+    /// path of the consensus key if `sealed_consensus_key` is a master seed
+    pub consensus_key_derivation: Option<DerivationPath>,
+    /// the chain of the state
+    pub chain_id: chain::Id,
+    /// the state to authenticate
+    pub state: consensus::State,
+    /// included in the attestation of the migration (so an old attestation can't be replayed)
+    #[serde(with = "serde_bytes")]
+    pub nonce: Vec<u8>,
+}
+
+/// response from authenticating a state without a MAC
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NitroMigrateStateResponse {
+    /// the state with its MAC
+    pub state: MacedState,
+    /// attestation payload (COSE_Sign1) for the chain ID + the authenticated state's digest
+    #[serde(with = "serde_bytes")]
+    pub attestation_doc: Vec<u8>,
+}
+
+/// response from the enclave to the state migration request
+pub type NitroMigrateStateResult = Result<NitroMigrateStateResponse, NitroError>;
+
+/// the claim in the attestation of a state migration
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NitroMigrateStateClaim {
+    pub chain_id: String,
+    /// hex-encoded SHA-256 digest of the authenticated state (as JSON)
+    pub state_digest: String,
+}
+
+impl NitroMigrateStateClaim {
+    /// the claim of the authenticated state
+    pub fn new(chain_id: &chain::Id, state: &MacedState) -> Result<Self, String> {
+        let json = serde_json::to_vec(state).map_err(|e| format!("{:?}", e))?;
+        Ok(Self {
+            chain_id: chain_id.to_string(),
+            state_digest: String::from_utf8(subtle_encoding::hex::encode(Sha256::digest(json)))
+                .unwrap(),
+        })
+    }
+}
+
+/// configuration sent when backing up the consensus key in Shamir shares
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NitroBackupConfig {
+    /// AWS credentials (the ones in the helper's config, or obtained from IAM by the helper)
+    pub credentials: AwsCredentials,
+    /// AWS region
+    pub aws_region: String,
+    /// regions of the KMS key replicas to fail over to (in order)
+    pub kms_failover_regions: Vec<String>,
+    /// AWS KMS-encrypted consensus key
+    #[serde(with = "serde_bytes")]
+    pub sealed_consensus_key: Vec<u8>,
+    /// path of the consensus key if `sealed_consensus_key` is a master seed
+    pub consensus_key_derivation: Option<DerivationPath>,
+    /// number of shares needed to restore the key
+    pub threshold: u8,
+    /// X25519 public keys of the operators (one share for each)
+    pub recipients: Vec<[u8; 32]>,
+    /// included in the attestation of the backup (so an old attestation can't be replayed)
+    #[serde(with = "serde_bytes")]
+    pub nonce: Vec<u8>,
+}
+
+/// configuration sent to restore the consensus key from its backup shares
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NitroRestoreConfig {
+    /// AWS credentials (the ones in the helper's config, or obtained from IAM by the helper)
     pub credentials: AwsCredentials,
     /// AWS key id
     pub kms_key_id: String,
     /// AWS region
     pub aws_region: String,
+    /// the backup the shares are from (its public key is checked)
+    pub backup: KeyBackup,
+    /// the shares re-encrypted to the key attested by `ProvisionBegin`
+    pub shares: Vec<BackupShare>,
+    /// included in the attestation of the key (so an old attestation can't be replayed)
+    #[serde(with = "serde_bytes")]
+    pub nonce: Vec<u8>,
+}
+
+/// control actions for a chain's signing session
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ChainControlAction {
+    /// refuse signing requests (e.g. during the chain upgrade)
+    Pause,
+    /// resume signing
+    Resume,
+    /// stop the session (it can be started again)
+    Stop,
+    /// pause the session and wait until its in-flight request is handled
+    Drain,
+    /// refuse signing requests, reported as a maintenance (e.g. a handover to a backup signer)
+    Maintenance,
+    /// allow the next signing request to exceed the policy's `max_height_jump` (once)
+    AllowHeightJump,
+}
+
+/// request to control a chain's signing session in the enclave
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NitroChainControl {
+    /// Chain ID of the session
+    pub chain_id: chain::Id,
+    /// what to do with the session
+    pub action: ChainControlAction,
+}
+
+/// signing status of a chain in the enclave
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NitroChainStatus {
+    /// Chain ID of the session
+    pub chain_id: chain::Id,
+    /// current status of the session
+    pub status: SessionStatus,
+}
+
+/// kind of a failed enclave request (with a stable numeric code,
+/// which is also the helper's exit status)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum NitroErrorCode {
+    /// the request is malformed or its parameters are invalid
+    InvalidRequest,
+    /// the helper and the enclave speak different protocol versions
+    ProtocolMismatch,
+    /// no AWS credentials, or the KMS decryption couldn't be made with them
+    KmsAccessDenied,
+    /// AWS KMS didn't encrypt the key
+    KmsEncryptFailed,
+    /// AWS KMS couldn't be reached or kept failing the call until the retries were exhausted
+    /// (throttled, a 5xx or denied: the SDK doesn't report which)
+    KmsUnavailable,
+    /// the NSM didn't return an attestation document
+    AttestationFailed,
+    /// the key material is invalid (or isn't the expected key)
+    BadKey,
+    /// the chain has no (running) session
+    UnknownChain,
+    /// the request isn't authenticated with the bound control key (or it's replayed)
+    Unauthenticated,
+    /// a control key was already bound
+    AlreadyBound,
+    /// the enclave policy doesn't allow the request
+    PolicyDenied,
+    /// other failures
+    Internal,
+}
+
+impl NitroErrorCode {
+    /// the numeric code
+    pub fn code(self) -> u8 {
+        match self {
+            NitroErrorCode::InvalidRequest => 10,
+            NitroErrorCode::ProtocolMismatch => 11,
+            NitroErrorCode::KmsAccessDenied => 20,
+            NitroErrorCode::KmsEncryptFailed => 21,
+            NitroErrorCode::KmsUnavailable => 22,
+            NitroErrorCode::AttestationFailed => 30,
+            NitroErrorCode::BadKey => 40,
+            NitroErrorCode::UnknownChain => 50,
+            NitroErrorCode::Unauthenticated => 55,
+            NitroErrorCode::AlreadyBound => 56,
+            NitroErrorCode::PolicyDenied => 57,
+            NitroErrorCode::Internal => 60,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NitroErrorCode::InvalidRequest => "INVALID_REQUEST",
+            NitroErrorCode::ProtocolMismatch => "PROTOCOL_MISMATCH",
+            NitroErrorCode::KmsAccessDenied => "KMS_ACCESS_DENIED",
+            NitroErrorCode::KmsEncryptFailed => "KMS_ENCRYPT_FAILED",
+            NitroErrorCode::KmsUnavailable => "KMS_UNAVAILABLE",
+            NitroErrorCode::AttestationFailed => "ATTESTATION_FAILED",
+            NitroErrorCode::BadKey => "BAD_KEY",
+            NitroErrorCode::UnknownChain => "UNKNOWN_CHAIN",
+            NitroErrorCode::Unauthenticated => "UNAUTHENTICATED",
+            NitroErrorCode::AlreadyBound => "ALREADY_BOUND",
+            NitroErrorCode::PolicyDenied => "POLICY_DENIED",
+            NitroErrorCode::Internal => "INTERNAL",
+        }
+    }
+}
+
+/// a failed enclave request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NitroError {
+    pub code: NitroErrorCode,
+    /// what failed (for the logs)
+    pub message: String,
+}
+
+impl NitroError {
+    pub fn new(code: NitroErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// the NSM didn't return an attestation document
+    pub fn attestation_failed() -> Self {
+        Self::new(
+            NitroErrorCode::AttestationFailed,
+            "failed to obtain an attestation document",
+        )
+    }
 }
 
+impl fmt::Display for NitroError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}, code {})",
+            self.message,
+            self.code.as_str(),
+            self.code.code()
+        )
+    }
+}
+
+impl std::error::Error for NitroError {}
+
+/// response to chain control or status requests
+pub type NitroChainStatusResult = Result<Vec<NitroChainStatus>, NitroError>;
+
+/// log verbosity of the enclave
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for tracing_core::LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => Self::ERROR,
+            LogLevel::Warn => Self::WARN,
+            LogLevel::Info => Self::INFO,
+            LogLevel::Debug => Self::DEBUG,
+            LogLevel::Trace => Self::TRACE,
+        }
+    }
+}
+
+/// response to the log level request
+pub type NitroSetLogLevelResult = Result<(), NitroError>;
+
+/// version of the helper<->enclave protocol (bumped on incompatible changes of the requests)
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// the enclave accepts the start config encrypted to its attested key (`StartSealed`)
+pub const CAPABILITY_SEALED_CONFIG: &str = "sealed-config";
+/// the enclave authenticates the requests with a bound control key (`BindControl`)
+pub const CAPABILITY_CONTROL_KEY: &str = "control-key";
+/// the enclave serves several requests on a connection
+pub const CAPABILITY_MULTI_REQUEST: &str = "multi-request";
+/// the enclave attests the loaded consensus key and watermark after `StartSealed`
+pub const CAPABILITY_STARTUP_ATTESTATION: &str = "startup-attestation";
+/// the enclave accepts any request encrypted to its attested key (`Sealed`)
+pub const CAPABILITY_SEALED_REQUESTS: &str = "sealed-requests";
+
+/// the protocol version and capabilities (exchanged at the start of every connection)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NitroHello {
+    pub version: u32,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+impl NitroHello {
+    /// the version and capabilities of this build
+    pub fn current() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            capabilities: [
+                CAPABILITY_SEALED_CONFIG,
+                CAPABILITY_CONTROL_KEY,
+                CAPABILITY_MULTI_REQUEST,
+                CAPABILITY_STARTUP_ATTESTATION,
+                CAPABILITY_SEALED_REQUESTS,
+            ]
+            .iter()
+            .map(|capability| capability.to_string())
+            .collect(),
+        }
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+/// the enclave's version and capabilities (or the mismatch)
+pub type NitroHelloResult = Result<NitroHello, NitroError>;
+
 /// types of initial requests sent to NE
 #[derive(Debug, Serialize, Deserialize)]
 pub enum NitroRequest {
+    /// the protocol negotiation (the first request of every connection)
+    Hello(NitroHello),
     /// generate a key
     Keygen(NitroKeygenConfig),
     /// start up TMKMS processing
-    Start(NitroConfig),
+    Start(Box<NitroConfig>),
+    /// start up TMKMS processing with the config encrypted to a one-time key: the enclave
+    /// answers with the key's attestation (`NitroAttestResult`) and then reads the `SealedConfig`;
+    /// once the consensus key and the state are loaded, it answers with their attestation
+    /// (`NitroAttestResult` with the `NitroWatermarkClaim` and the same nonce)
+    StartSealed {
+        /// included in the attestation document (so it can't be replayed)
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+    },
+    /// split the consensus key into threshold shares
+    KeyShares(NitroKeySharesConfig),
+    /// re-encrypt a sealed key with another AWS KMS key
+    Rewrap(NitroRewrapConfig),
+    /// attest the public key derived from a sealed master seed
+    Derive(NitroDeriveConfig),
+    /// generate a one-time X25519 key (attested) for a mnemonic to be encrypted to
+    ProvisionBegin {
+        /// included in the attestation document (so it can't be replayed)
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+    },
+    /// seal the seed of the mnemonic encrypted to the one-time key
+    /// (and attest the consensus key derived from it)
+    ProvisionMnemonic(NitroMnemonicConfig),
+    /// sign an arbitrary payload with the consensus key (domain-separated from consensus messages)
+    SignPayload(NitroSignPayloadConfig),
+    /// split the consensus key into Shamir shares encrypted to the operators' keys
+    Backup(NitroBackupConfig),
+    /// reassemble and seal the consensus key from the backup shares re-encrypted
+    /// to the one-time key (and attest it)
+    Restore(NitroRestoreConfig),
+    /// pause, resume or stop a chain's session
+    ChainControl(NitroChainControl),
+    /// get the status of all chains' sessions
+    ChainStatus,
+    /// stop all sessions (after their in-flight requests), zeroize the keys and exit
+    Shutdown,
+    /// change the log level of the running enclave (`None` resets it to the startup one)
+    SetLogLevel(Option<LogLevel>),
+    /// replace the AWS credentials of the running enclave (before they expire)
+    RefreshCredentials(AwsCredentials),
+    /// attest the enclave's measurements (before the credentials and sealed keys are pushed to it)
+    Attest {
+        /// included in the attestation document (so it can't be replayed)
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+    },
+    /// bind the control key authenticating the next requests (once per enclave run): the enclave
+    /// answers with a one-time key's attestation (`NitroAttestResult`), then reads the control key
+    /// encrypted to it (`SealedConfig`) and acknowledges it (`NitroBindControlResult`)
+    BindControl {
+        /// included in the attestation document (so it can't be replayed)
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+    },
+    /// a request with the control key's MAC
+    Authenticated(AuthenticatedRequest),
+    /// a request (with credentials or secrets) encrypted to a one-time key: the enclave answers
+    /// with the key's attestation (`NitroAttestResult`), then reads the encoded request
+    /// encrypted to it (`SealedConfig`) and answers it as if it was sent in the clear
+    Sealed {
+        /// included in the attestation document (so it can't be replayed)
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+    },
+    /// authenticate a state without a MAC (written by a version that didn't authenticate it,
+    /// or rebuilt after a corruption) once, if the enclave policy allows it
+    MigrateState(NitroMigrateStateConfig),
 }
 
+/// a runtime attestation pushed periodically by the enclave
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NitroReattestation {
+    /// Chain ID of the session
+    pub chain_id: chain::Id,
+    /// attestation payload (COSE_Sign1) for the consensus public key + the last persisted state
+    pub attestation_doc: Vec<u8>,
+}
+
+/// the claim (user data) of the startup, runtime and halt attestations:
+/// the session's consensus public key and watermark
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NitroWatermarkClaim {
+    /// base64-encoded consensus public key
+    pub pubkey: String,
+    pub chain_id: String,
+    /// the loaded (or last persisted) height, round and step
+    pub height: u64,
+    pub round: u32,
+    pub step: i8,
+}
+
+/// the final watermark of a session stopped at `max_height`, pushed by the enclave
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NitroHaltReport {
+    /// Chain ID of the session
+    pub chain_id: chain::Id,
+    /// the configured max height
+    pub max_height: u64,
+    /// the last signed (and persisted) height, round and step
+    pub height: u64,
+    pub round: u32,
+    pub step: i8,
+    /// attestation payload (COSE_Sign1) for the consensus public key + the final watermark
+    /// (if it could be obtained)
+    pub attestation_doc: Option<Vec<u8>>,
+}
+
+/// response to the credential refresh request
+pub type NitroRefreshCredentialsResult = Result<(), NitroError>;
+
+/// response to the attestation request: the attestation payload (COSE_Sign1) with the nonce
+pub type NitroAttestResult = Result<ByteBuf, NitroError>;
+
+/// acknowledgement of the bound control key
+pub type NitroBindControlResult = Result<(), NitroError>;
+
+/// response to the shutdown request (sent before the enclave exits)
+pub type NitroShutdownResult = Result<(), NitroError>;
+
 /// response from key generation
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NitroKeygenResponse {
     /// payload returned from AWS KMS
+    #[serde(with = "serde_bytes")]
     pub encrypted_secret: Vec<u8>,
     /// public key for consensus or P2P
+    #[serde(with = "serde_bytes")]
     pub public_key: Vec<u8>,
     /// attestation payload (COSE_Sign1) for the public key + encryption key id
+    #[serde(with = "serde_bytes")]
     pub attestation_doc: Vec<u8>,
 }
 
 /// response from the enclave
-pub type NitroResponse = Result<NitroKeygenResponse, String>;
+pub type NitroResponse = Result<NitroKeygenResponse, NitroError>;
+
+/// response from splitting the consensus key
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NitroKeySharesResponse {
+    /// consensus public key
+    #[serde(with = "serde_bytes")]
+    pub public_key: Vec<u8>,
+    /// encrypted shares with their commitments
+    pub key_shares: KeyShares,
+    /// attestation payload (COSE_Sign1) for the public key + shares digest
+    #[serde(with = "serde_bytes")]
+    pub attestation_doc: Vec<u8>,
+}
+
+/// response from the enclave to the key shares request
+pub type NitroKeySharesResult = Result<NitroKeySharesResponse, NitroError>;
+
+/// response from signing an arbitrary payload
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NitroSignPayloadResponse {
+    /// consensus public key
+    #[serde(with = "serde_bytes")]
+    pub public_key: Vec<u8>,
+    /// signature of the domain-separated payload
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+}
+
+/// response from the enclave to the payload signing request
+pub type NitroSignPayloadResult = Result<NitroSignPayloadResponse, NitroError>;
+
+/// response from backing up the consensus key
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NitroBackupResponse {
+    /// the encrypted shares
+    pub backup: KeyBackup,
+    /// attestation payload (COSE_Sign1) for the public key + backup digest
+    #[serde(with = "serde_bytes")]
+    pub attestation_doc: Vec<u8>,
+}
+
+/// response from the enclave to the backup request
+pub type NitroBackupResult = Result<NitroBackupResponse, NitroError>;
+
+/// response from re-encrypting a sealed key
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NitroRewrapResponse {
+    /// the key encrypted with the new AWS KMS key
+    #[serde(with = "serde_bytes")]
+    pub encrypted_secret: Vec<u8>,
+    /// public key of the re-encrypted key
+    #[serde(with = "serde_bytes")]
+    pub public_key: Vec<u8>,
+    /// attestation payload (COSE_Sign1) binding the old and new ciphertexts (`NitroRewrapClaim`)
+    #[serde(default, with = "serde_bytes")]
+    pub attestation_doc: Vec<u8>,
+}
+
+/// the claim (user data) of the rewrap attestation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NitroRewrapClaim {
+    /// base64-encoded public key of the re-encrypted key
+    pub pubkey: String,
+    /// base64-encoded AWS KMS key id it's now encrypted with
+    pub key_id: String,
+    /// hex-encoded SHA-256 of the old ciphertext
+    pub old: String,
+    /// hex-encoded SHA-256 of the new ciphertext
+    pub new: String,
+}
+
+impl NitroRewrapClaim {
+    pub fn new(public_key: &[u8], kms_key_id: &str, old: &[u8], new: &[u8]) -> Self {
+        let base64 = |bytes: &[u8]| {
+            String::from_utf8_lossy(&subtle_encoding::base64::encode(bytes)).into_owned()
+        };
+        let digest = |bytes: &[u8]| {
+            String::from_utf8_lossy(&subtle_encoding::hex::encode(Sha256::digest(bytes)))
+                .into_owned()
+        };
+        Self {
+            pubkey: base64(public_key),
+            key_id: base64(kms_key_id.as_bytes()),
+            old: digest(old),
+            new: digest(new),
+        }
+    }
+}
+
+/// response from the enclave to the rewrap request
+pub type NitroRewrapResult = Result<NitroRewrapResponse, NitroError>;
+
+/// response from deriving a key from a sealed master seed
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NitroDeriveResponse {
+    /// the derived public key
+    #[serde(with = "serde_bytes")]
+    pub public_key: Vec<u8>,
+    /// attestation payload (COSE_Sign1) for the public key and its path
+    #[serde(with = "serde_bytes")]
+    pub attestation_doc: Vec<u8>,
+}
+
+/// response from the enclave to the derive request
+pub type NitroDeriveResult = Result<NitroDeriveResponse, NitroError>;
 
 /// Credentials, generally obtained from parent instance IAM
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -73,3 +878,29 @@ pub struct AwsCredentials {
     /// SessionToken
     pub aws_session_token: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_wire_format() {
+        let response: NitroResponse = Err(NitroError::new(
+            NitroErrorCode::KmsAccessDenied,
+            "access denied",
+        ));
+        let json = serde_json::to_string(&response).unwrap();
+        assert_eq!(
+            json,
+            r#"{"Err":{"code":"KMS_ACCESS_DENIED","message":"access denied"}}"#
+        );
+        let decoded: NitroResponse = serde_json::from_str(&json).unwrap();
+        let err = decoded.unwrap_err();
+        assert_eq!(err.code, NitroErrorCode::KmsAccessDenied);
+        assert_eq!(err.code.code(), 20);
+        assert_eq!(
+            err.to_string(),
+            "access denied (KMS_ACCESS_DENIED, code 20)"
+        );
+    }
+}
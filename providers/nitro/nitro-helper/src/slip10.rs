@@ -0,0 +1,124 @@
+//! SLIP-0010 derivation of Ed25519 keys from a master seed
+//! (<https://github.com/satoshilabs/slips/blob/master/slip-0010.md>), so one sealed seed
+//! can back the consensus keys of several chains.
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::Sha512;
+use std::fmt;
+use std::str::FromStr;
+use zeroize::Zeroizing;
+
+/// Ed25519 only supports hardened derivation
+const HARDENED: u32 = 0x8000_0000;
+
+/// a derivation path of hardened indices (e.g. `m/44'/118'/0'/0'/0'`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DerivationPath(Vec<u32>);
+
+impl FromStr for DerivationPath {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('/');
+        if parts.next() != Some("m") {
+            return Err(format!("derivation path `{}` doesn't start with `m`", s));
+        }
+        parts
+            .map(|part| {
+                let index = part
+                    .strip_suffix('\'')
+                    .or_else(|| part.strip_suffix('H'))
+                    .ok_or_else(|| format!("`{}` isn't hardened (Ed25519 requires it)", part))?;
+                match index.parse::<u32>() {
+                    Ok(index) if index < HARDENED => Ok(index),
+                    _ => Err(format!("invalid index `{}`", part)),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(DerivationPath)
+    }
+}
+
+impl fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "m")?;
+        for index in &self.0 {
+            write!(f, "/{}'", index)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for DerivationPath {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for DerivationPath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+fn hmac_sha512(key: &[u8], data: &[&[u8]]) -> Zeroizing<[u8; 64]> {
+    let mut mac = Hmac::<Sha512>::new_from_slice(key).expect("HMAC accepts any key length");
+    for chunk in data {
+        mac.update(chunk);
+    }
+    let mut out = Zeroizing::new([0u8; 64]);
+    out.copy_from_slice(&mac.finalize().into_bytes());
+    out
+}
+
+/// the Ed25519 secret key (seed) at the path
+pub fn derive_ed25519(seed: &[u8], path: &DerivationPath) -> Zeroizing<[u8; 32]> {
+    let mut node = hmac_sha512(b"ed25519 seed", &[seed]);
+    for index in &path.0 {
+        let (key, chain_code) = node.split_at(32);
+        node = hmac_sha512(chain_code, &[&[0], key, &(index | HARDENED).to_be_bytes()]);
+    }
+    let mut key = Zeroizing::new([0u8; 32]);
+    key.copy_from_slice(&node[..32]);
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derives_slip10_test_vector() {
+        let seed = subtle_encoding::hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let expected = [
+            (
+                "m",
+                "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7",
+            ),
+            (
+                "m/0'",
+                "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3",
+            ),
+            (
+                "m/0'/1'",
+                "b1d0bad404bf35da785a64ca1ac54b2617211d2777696fbffaf208f746ae84f2",
+            ),
+        ];
+        for (path, key) in expected {
+            let path: DerivationPath = path.parse().unwrap();
+            let derived = derive_ed25519(&seed, &path);
+            assert_eq!(subtle_encoding::hex::encode(&derived[..]), key.as_bytes());
+        }
+        assert!("m/44'/118".parse::<DerivationPath>().is_err());
+        assert!("44'/118'".parse::<DerivationPath>().is_err());
+        assert_eq!(
+            "m/44H/118'/0'"
+                .parse::<DerivationPath>()
+                .unwrap()
+                .to_string(),
+            "m/44'/118'/0'"
+        );
+    }
+}
@@ -0,0 +1,224 @@
+//! span export (for the OTLP traces): the layer records the spans' timings and fields
+//! and passes the closed spans to a sink; the enclave's sink relays them to the helper
+
+use crate::channel::ChannelStream;
+use crate::mux::connect_channel;
+use crate::tracing_layer::{read_log_frame, write_log_frame};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing_core::{
+    field::Visit,
+    span::{Attributes, Id, Record},
+    Field, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan};
+
+/// a closed span (IDs are hex-encoded as in OTLP/JSON)
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpanRecord {
+    pub trace_id: String,
+    pub span_id: String,
+    pub parent_span_id: Option<String>,
+    pub name: String,
+    pub target: String,
+    pub start_unix_nanos: u64,
+    pub end_unix_nanos: u64,
+    pub attributes: Vec<(String, String)>,
+}
+
+/// where the closed spans go
+pub trait SpanSink: Send + Sync + 'static {
+    fn export(&self, span: SpanRecord);
+}
+
+fn unix_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+fn random_id(len: usize) -> String {
+    let mut id = vec![0u8; len];
+    OsRng.fill_bytes(&mut id);
+    id.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// the open span's data
+struct SpanTiming {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    start_unix_nanos: u64,
+    attributes: Vec<(String, String)>,
+}
+
+struct AttributeVisitor<'a>(&'a mut Vec<(String, String)>);
+
+impl Visit for AttributeVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name().to_owned(), value.to_owned()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .push((field.name().to_owned(), format!("{:?}", value)));
+    }
+}
+
+/// records the spans and exports them when they're closed
+pub struct SpanLayer<K: SpanSink> {
+    sink: K,
+}
+
+impl<K: SpanSink> SpanLayer<K> {
+    pub fn new(sink: K) -> Self {
+        Self { sink }
+    }
+}
+
+impl<S, K> tracing_subscriber::Layer<S> for SpanLayer<K>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+    K: SpanSink,
+{
+    fn on_new_span(&self, attrs: &Attributes, id: &Id, ctx: Context<S>) {
+        let span = ctx.span(id).expect("unknown span");
+        let parent = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<SpanTiming>()
+                .map(|timing| (timing.trace_id.clone(), timing.span_id.clone()))
+        });
+        let (trace_id, parent_span_id) = match parent {
+            Some((trace_id, span_id)) => (trace_id, Some(span_id)),
+            None => (random_id(16), None),
+        };
+        let mut attributes = vec![];
+        attrs.record(&mut AttributeVisitor(&mut attributes));
+        span.extensions_mut().insert(SpanTiming {
+            trace_id,
+            span_id: random_id(8),
+            parent_span_id,
+            start_unix_nanos: unix_nanos(),
+            attributes,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record, ctx: Context<S>) {
+        let span = ctx.span(id).expect("unknown span");
+        let mut exts = span.extensions_mut();
+        if let Some(timing) = exts.get_mut::<SpanTiming>() {
+            values.record(&mut AttributeVisitor(&mut timing.attributes));
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<S>) {
+        let span = ctx.span(&id).expect("unknown span");
+        let timing = match span.extensions_mut().remove::<SpanTiming>() {
+            Some(timing) => timing,
+            None => return,
+        };
+        self.sink.export(SpanRecord {
+            trace_id: timing.trace_id,
+            span_id: timing.span_id,
+            parent_span_id: timing.parent_span_id,
+            name: span.name().to_owned(),
+            target: span.metadata().target().to_owned(),
+            start_unix_nanos: timing.start_unix_nanos,
+            end_unix_nanos: unix_nanos(),
+            attributes: timing.attributes,
+        });
+    }
+}
+
+/// the host channel the enclave's spans are relayed to (if enabled)
+static RELAY: Mutex<Option<(Option<u32>, u32)>> = Mutex::new(None);
+
+/// relays the enclave's spans to the helper's port (via the multiplexed port if set)
+pub fn enable_span_relay(mux_port: Option<u32>, port: u32) {
+    *RELAY.lock().unwrap_or_else(|e| e.into_inner()) = Some((mux_port, port));
+}
+
+/// relays the spans to the helper over a single connection (once it's enabled)
+#[derive(Default)]
+pub struct VsockSpanSink {
+    /// reconnected with the next span after a failure
+    conn: Mutex<Option<ChannelStream>>,
+}
+
+impl SpanSink for VsockSpanSink {
+    fn export(&self, span: SpanRecord) {
+        let relay = match *RELAY.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(relay) => relay,
+            None => return,
+        };
+        // no spans are exported while the connection is locked
+        let mut conn = match self.conn.try_lock() {
+            Ok(conn) => conn,
+            Err(_) => return,
+        };
+        if conn.is_none() {
+            *conn = connect_channel(relay.0, relay.1).ok();
+        }
+        if let Some(stream) = conn.as_mut() {
+            let result = serde_json::to_vec(&span)
+                .map_err(io::Error::from)
+                .and_then(|json| write_log_frame(stream, &json));
+            if result.is_err() {
+                *conn = None;
+            }
+        }
+    }
+}
+
+/// reads a span relayed by the enclave
+pub fn read_span<R: io::Read>(reader: &mut R) -> io::Result<SpanRecord> {
+    let json = read_log_frame(reader)?;
+    serde_json::from_slice(&json).map_err(io::Error::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[derive(Clone, Default)]
+    struct Collected(Arc<Mutex<Vec<SpanRecord>>>);
+
+    impl SpanSink for Collected {
+        fn export(&self, span: SpanRecord) {
+            self.0.lock().unwrap().push(span);
+        }
+    }
+
+    #[test]
+    fn exports_nested_spans() {
+        let collected = Collected::default();
+        let subscriber = tracing_subscriber::registry().with(SpanLayer::new(collected.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let request = tracing::info_span!("request", chain_id = "testchain-1");
+            let _request = request.enter();
+            tracing::info_span!("sign").in_scope(|| {});
+        });
+        let spans = collected.0.lock().unwrap();
+        assert_eq!(spans.len(), 2);
+        let (sign, request) = (&spans[0], &spans[1]);
+        assert_eq!(sign.name, "sign");
+        assert_eq!(sign.trace_id, request.trace_id);
+        assert_eq!(sign.parent_span_id.as_ref(), Some(&request.span_id));
+        assert_eq!(
+            request.attributes,
+            vec![("chain_id".to_owned(), "testchain-1".to_owned())]
+        );
+
+        let mut frame = vec![];
+        write_log_frame(&mut frame, &serde_json::to_vec(sign).unwrap()).unwrap();
+        assert_eq!(&read_span(&mut frame.as_slice()).unwrap(), sign);
+    }
+}
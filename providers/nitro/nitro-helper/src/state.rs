@@ -1,83 +1,342 @@
-use crate::shared::VSOCK_HOST_CID;
+use crate::chain_rpc::latest_block_height;
+use crate::ha::HaNode;
+use crate::health::HealthState;
+use crate::mux_server::ChannelListener;
+use crate::state_store::StateStore;
+use serde::{Deserialize, Serialize};
+use std::io::{self, prelude::*};
+use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::os::unix::io::AsRawFd;
-use std::sync::mpsc::{Receiver, TryRecvError};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError};
+use std::sync::Arc;
 use std::thread;
-use std::{
-    fs,
-    io::{self, prelude::*},
-    path::{Path, PathBuf},
-};
-use tempfile::NamedTempFile;
-use tmkms_light::chain::state::{consensus, StateError};
+use std::time::Duration;
+use tmkms_light::chain::state::{consensus, MacedState, StateError, StateErrorDetail};
 use tmkms_light::error::{io_error_wrap, Error};
 use tmkms_light::utils::{read_u16_payload, write_u16_payload};
-use tracing::{debug, info, warn};
-use vsock::{VsockAddr, VsockListener, VsockStream};
+use tmkms_nitro_helper::channel::ChannelStream;
+use tracing::{debug, error, info, info_span, warn};
+
+/// how long a lost connection waits for a pending stop
+const STOP_GRACE: Duration = Duration::from_secs(1);
+
+/// the listener for the enclave's state connections
+enum StateListener {
+    /// from the enclave directly
+    Vsock(ChannelListener),
+    /// relayed by a helper (when serving as an external state store)
+    Tcp(TcpListener),
+}
+
+/// a state connection
+trait StateStream: Read + Write {}
+
+impl StateStream for ChannelStream {}
+impl StateStream for TcpStream {}
+
+impl StateListener {
+    fn accept(&self) -> io::Result<Box<dyn StateStream>> {
+        match self {
+            StateListener::Vsock(listener) => {
+                let (stream, _) = listener.accept()?;
+                debug!("state peer addr: {:?}", stream.peer_addr());
+                debug!("state local addr: {:?}", stream.local_addr());
+                debug!("state fd: {}", stream.as_raw_fd());
+                Ok(Box::new(stream))
+            }
+            StateListener::Tcp(listener) => {
+                let (stream, addr) = listener.accept()?;
+                debug!("state peer addr: {:?}", addr);
+                Ok(Box::new(stream))
+            }
+        }
+    }
+}
+
+/// how a corrupted state (that can't be parsed) is rebuilt at startup
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct StateRecoveryConfig {
+    /// the lowest height that may be signed after the recovery (supplied by the operator)
+    pub height: Option<u64>,
+    /// otherwise, the RPC (`host:port`) of a node whose latest block height (+ `height_margin`)
+    /// is the lowest height that may be signed
+    pub rpc_addr: Option<String>,
+    /// skipped heights above the node's latest block (which the validator may have signed)
+    #[serde(default = "default_recovery_height_margin")]
+    pub height_margin: u64,
+}
+
+fn default_recovery_height_margin() -> u64 {
+    2
+}
+
+impl StateRecoveryConfig {
+    /// the rebuilt state: nothing below the recovery height can be signed
+    fn rebuild(&self) -> Result<MacedState, String> {
+        let height = match (self.height, &self.rpc_addr) {
+            (Some(height), _) => height,
+            (None, Some(rpc_addr)) => latest_block_height(rpc_addr)? + self.height_margin,
+            (None, None) => {
+                return Err("`state_recovery` needs `height` or `rpc_addr`".to_owned());
+            }
+        };
+        let height = height
+            .try_into()
+            .map_err(|e| format!("invalid recovery height: {}", e))?;
+        Ok(MacedState::from(consensus::State {
+            height,
+            ..Default::default()
+        }))
+    }
+}
+
+/// refuses to silently start from a fresh state (at height 0) if the host previously had one
+/// (e.g. the state file was lost in a bad migration), unless the operator forces it,
+/// and quarantines a corrupted state (rebuilding it if `state_recovery` is set)
+#[derive(Debug, Clone)]
+pub struct FreshStateGuard {
+    /// created once the host has a state (`<state_file_path>.initialized`)
+    marker_path: PathBuf,
+    /// a missing state is refused even without the marker
+    require_existing: bool,
+    /// the operator allowed starting from a fresh state (`--force-fresh-state`)
+    force: bool,
+    /// how a corrupted state is rebuilt (if it is)
+    recovery: Option<StateRecoveryConfig>,
+}
+
+impl FreshStateGuard {
+    pub fn new(state_file_path: &Path, require_existing: bool, force: bool) -> Self {
+        let mut marker_path = state_file_path.as_os_str().to_owned();
+        marker_path.push(".initialized");
+        Self {
+            marker_path: marker_path.into(),
+            require_existing,
+            force,
+            recovery: None,
+        }
+    }
+
+    /// rebuilds a corrupted state (instead of refusing to start)
+    pub fn set_recovery(&mut self, recovery: Option<StateRecoveryConfig>) {
+        self.recovery = recovery;
+    }
+
+    /// checks a fresh state may be started from
+    fn check_fresh(&self) -> Result<(), StateError> {
+        if self.force {
+            warn!("starting from a fresh state (forced)");
+            return Ok(());
+        }
+        if self.marker_path.exists() {
+            return Err(StateError::sync_other_error(format!(
+                "no state found, but the host previously had one (`{}` exists); \
+                 restore the state or start with `--force-fresh-state`",
+                self.marker_path.display()
+            )));
+        }
+        if self.require_existing {
+            return Err(StateError::sync_other_error(
+                "no state found and `require_existing_state` is set; \
+                 restore the state or start with `--force-fresh-state`"
+                    .to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// records that the host has a state
+    /// (a failure only disables the protection, e.g. if the remote state's path has no directory)
+    fn mark_initialized(&self) {
+        if !self.marker_path.exists() {
+            if let Err(e) = std::fs::write(&self.marker_path, b"") {
+                warn!(
+                    "failed to create {} (a lost state won't be detected): {}",
+                    self.marker_path.display(),
+                    e
+                );
+            }
+        }
+    }
+}
 
 /// helps the enclave to load the state previously persisted on the host
-/// + to persist new states
+/// + to persist new states (each acknowledged once it's written)
 pub struct StateSyncer {
-    state_file_path: PathBuf,
-    vsock_listener: VsockListener,
-    state: consensus::State,
+    store: Box<dyn StateStore>,
+    listener: StateListener,
+    state: MacedState,
+    health: Arc<HealthState>,
+    /// replicates the states to the other signers (if HA is enabled)
+    ha: Option<Arc<HaNode>>,
 }
 
 impl StateSyncer {
-    /// creates a new state file or loads the previous one
+    /// loads the previous state from the store (or persists the initial one)
     /// and binds a listener for incoming vsock connections from the enclave
     /// on the proxy CID on the provided port
-    pub fn new<P: AsRef<Path>>(path: P, vsock_port: u32) -> Result<Self, StateError> {
-        let state_file_path = path.as_ref().to_owned();
-        let state = match fs::read_to_string(&path) {
-            Ok(state_json) => {
-                let consensus_state: consensus::State =
-                    serde_json::from_str(&state_json).map_err(|e| {
-                        StateError::sync_enc_dec_error(path.as_ref().display().to_string(), e)
-                    })?;
-
-                Ok(consensus_state)
-            }
-            Err(e) if e.kind() == io::ErrorKind::NotFound => {
-                Self::write_initial_state(&state_file_path)
-            }
-            Err(e) => Err(StateError::sync_error(
-                path.as_ref().display().to_string(),
-                e,
-            )),
-        }?;
-
-        let sockaddr = VsockAddr::new(VSOCK_HOST_CID, vsock_port);
-        let vsock_listener = VsockListener::bind(&sockaddr)
+    pub fn new(
+        store: Box<dyn StateStore>,
+        vsock_port: u32,
+        health: Arc<HealthState>,
+        guard: &FreshStateGuard,
+    ) -> Result<Self, StateError> {
+        let vsock_listener = ChannelListener::bind(vsock_port)
             .map_err(|e| StateError::sync_error("vsock".into(), e))?;
+        Self::with_listener(
+            store,
+            StateListener::Vsock(vsock_listener),
+            health,
+            Some(guard),
+        )
+    }
+
+    /// loads the previous state from the store (or persists the initial one)
+    /// and binds a listener for the enclave's state connections relayed over TCP
+    /// (to serve as an external state store)
+    pub fn new_tcp(
+        store: Box<dyn StateStore>,
+        listen_addr: SocketAddr,
+        health: Arc<HealthState>,
+    ) -> Result<Self, StateError> {
+        let tcp_listener = TcpListener::bind(listen_addr)
+            .map_err(|e| StateError::sync_error(listen_addr.to_string(), e))?;
+        Self::with_listener(store, StateListener::Tcp(tcp_listener), health, None)
+    }
+
+    fn with_listener(
+        mut store: Box<dyn StateStore>,
+        listener: StateListener,
+        health: Arc<HealthState>,
+        guard: Option<&FreshStateGuard>,
+    ) -> Result<Self, StateError> {
+        let (state, recovered) = Self::load_or_init(store.as_mut(), guard)?;
+        if recovered {
+            warn!(
+                alert = true,
+                "the rebuilt state has no MAC: authenticate it with `helper migrate-state`"
+            );
+        }
 
         Ok(Self {
-            state_file_path,
-            vsock_listener,
+            store,
+            listener,
             state,
+            health,
+            ha: None,
         })
     }
 
-    /// Write the initial state to the given path on disk
-    fn write_initial_state(path: &Path) -> Result<consensus::State, StateError> {
-        let consensus_state = consensus::State {
+    /// checks the enclave can load the state: it has a MAC, or it's the initial one
+    /// (a state written by an older version or rebuilt needs to be migrated first)
+    pub fn check_authenticated(&self) -> Result<(), String> {
+        if self.state.mac.is_none() && !self.state.is_initial() {
+            return Err(format!(
+                "the state at {} has no MAC (it was written by an older version or rebuilt): \
+                 authenticate it with `helper migrate-state` first",
+                self.state.state
+            ));
+        }
+        Ok(())
+    }
+
+    /// only persists the states replicated to the majority of the HA cluster
+    pub fn set_ha(&mut self, ha: Arc<HaNode>) {
+        self.ha = Some(ha);
+    }
+
+    /// loads the previous state (or persists the initial one if the guard allows it);
+    /// also returns whether a corrupted state was rebuilt
+    fn load_or_init(
+        store: &mut dyn StateStore,
+        guard: Option<&FreshStateGuard>,
+    ) -> Result<(MacedState, bool), StateError> {
+        let loaded = match (store.load(), guard) {
+            (Ok(loaded), _) => loaded,
+            (Err(e), Some(guard)) if matches!(e.detail(), StateErrorDetail::SyncEncDecError(_)) => {
+                return Self::recover(store, guard, e).map(|state| (state, true));
+            }
+            (Err(e), _) => return Err(e),
+        };
+        let state = match loaded {
+            Some(state) => state,
+            None => {
+                if let Some(guard) = guard {
+                    guard.check_fresh()?;
+                }
+                Self::write_initial_state(store)?
+            }
+        };
+        if let Some(guard) = guard {
+            guard.mark_initialized();
+        }
+        Ok((state, false))
+    }
+
+    /// quarantines the corrupted state and rebuilds it (if `state_recovery` is set)
+    fn recover(
+        store: &mut dyn StateStore,
+        guard: &FreshStateGuard,
+        corruption: StateError,
+    ) -> Result<MacedState, StateError> {
+        // the host had a state, so it's never silently replaced by a fresh one afterwards
+        guard.mark_initialized();
+        let quarantined = match store.quarantine()? {
+            Some(path) => format!("moved to {}", path.display()),
+            None => "left in place".to_owned(),
+        };
+        error!(
+            alert = true,
+            "the persisted state is corrupted ({}): {}", quarantined, corruption
+        );
+        let recovery = guard.recovery.as_ref().ok_or_else(|| {
+            StateError::sync_other_error(format!(
+                "the persisted state is corrupted ({}); restore it or set `state_recovery`",
+                quarantined
+            ))
+        })?;
+        let state = recovery.rebuild().map_err(|e| {
+            StateError::sync_other_error(format!("failed to rebuild the state: {}", e))
+        })?;
+        warn!(
+            alert = true,
+            "the state was rebuilt at height {}", state.state.height
+        );
+        store.persist(&state)?;
+        Ok(state)
+    }
+
+    /// Write the initial state to the store
+    fn write_initial_state(store: &mut dyn StateStore) -> Result<MacedState, StateError> {
+        let consensus_state = MacedState::from(consensus::State {
             height: 0u32.into(),
             ..Default::default()
-        };
+        });
 
-        Self::persist_state(path, &consensus_state)?;
+        store.persist(&consensus_state)?;
 
         Ok(consensus_state)
     }
 
-    /// dump the current state to the provided vsock stream
-    fn sync_to_stream(&self, stream: &mut VsockStream) -> Result<(), StateError> {
+    /// dump the current state to the provided stream
+    fn sync_to_stream(&mut self, mut stream: &mut dyn StateStream) -> Result<(), StateError> {
+        if let Some(ha) = &self.ha {
+            // the previous leader may have signed further
+            let watermark = ha.watermark();
+            if watermark.state > self.state.state {
+                self.state = watermark;
+            }
+        }
         let json_raw = serde_json::to_vec(&self.state)
             .map_err(|e| StateError::sync_enc_dec_error("vsock".into(), e))?;
-        write_u16_payload(stream, &json_raw).map_err(|e| StateError::sync_error("vsock".into(), e))
+        write_u16_payload(&mut stream, &json_raw)
+            .map_err(|e| StateError::sync_error("vsock".into(), e))
     }
 
-    /// load state from the provided vsock stream
-    fn sync_from_stream(mut stream: &mut VsockStream) -> Result<consensus::State, Error> {
+    /// load state from the provided stream
+    fn sync_from_stream(mut stream: &mut dyn StateStream) -> Result<MacedState, Error> {
         let json_raw = read_u16_payload(&mut stream)?;
         serde_json::from_slice(&json_raw).map_err(|e| io_error_wrap("parse error".into(), e))
     }
@@ -86,73 +345,129 @@ impl StateSyncer {
     pub fn launch_syncer(mut self, stop_recv: Receiver<()>) -> thread::JoinHandle<()> {
         thread::spawn(move || {
             info!("listening for enclave persistence");
-            for conn in self.vsock_listener.incoming() {
-                match conn {
+            loop {
+                match self.listener.accept() {
                     Ok(mut stream) => {
-                        info!("vsock persistence connection established");
-                        debug!("state peer addr: {:?}", stream.peer_addr());
-                        debug!("state local addr: {:?}", stream.local_addr());
-                        debug!("state fd: {}", stream.as_raw_fd());
+                        info!("persistence connection established");
 
-                        if let Err(e) = self.sync_to_stream(&mut stream) {
+                        if let Err(e) = self.sync_to_stream(stream.as_mut()) {
                             warn!("error serializing to json {}", e);
                         } else {
+                            self.health.set_enclave_connected(true);
                             loop {
-                                if let Ok(consensus_state) = Self::sync_from_stream(&mut stream) {
-                                    self.state = consensus_state;
-                                    if let Err(e) =
-                                        Self::persist_state(&self.state_file_path, &self.state)
-                                    {
-                                        warn!("state persistence failed: {}", e);
+                                match Self::sync_from_stream(stream.as_mut()) {
+                                    Ok(consensus_state) => {
+                                        let span = info_span!(
+                                            "host_state_persist",
+                                            height = consensus_state.state.height.value()
+                                        );
+                                        let _span = span.enter();
+                                        let persisted = match &self.ha {
+                                            Some(ha) => ha
+                                                .replicate(&consensus_state)
+                                                .map_err(StateError::sync_other_error),
+                                            None => Ok(()),
+                                        }
+                                        .and_then(|()| {
+                                            self.state = consensus_state;
+                                            self.store.persist(&self.state)
+                                        });
+                                        if let Err(ref e) = persisted {
+                                            warn!("state persistence failed: {}", e);
+                                        }
+                                        self.health.set_state_persisted(persisted.is_ok());
+                                        let ack: Result<(), String> =
+                                            persisted.map_err(|e| e.to_string());
+                                        if let Err(e) = serde_json::to_vec(&ack)
+                                            .map_err(|e| e.to_string())
+                                            .and_then(|ack_raw| {
+                                                write_u16_payload(&mut stream, &ack_raw)
+                                                    .map_err(|e| e.to_string())
+                                            })
+                                        {
+                                            warn!("failed to acknowledge the state: {}", e);
+                                        }
+                                        match stop_recv.try_recv() {
+                                            Ok(()) | Err(TryRecvError::Disconnected) => {
+                                                warn!("stop state persistence");
+                                                return;
+                                            }
+                                            Err(TryRecvError::Empty) => continue,
+                                        }
                                     }
-                                    match stop_recv.try_recv() {
-                                        Ok(()) | Err(TryRecvError::Disconnected) => {
+                                    Err(e) => {
+                                        warn!("persistence connection lost: {}", e);
+                                        self.health.set_enclave_connected(false);
+                                        // e.g. the enclave exited after it was shut down
+                                        if let Ok(()) | Err(RecvTimeoutError::Disconnected) =
+                                            stop_recv.recv_timeout(STOP_GRACE)
+                                        {
                                             warn!("stop state persistence");
                                             return;
                                         }
-                                        Err(TryRecvError::Empty) => continue,
+                                        break;
                                     }
                                 }
                             }
                         }
                     }
                     Err(e) => {
-                        warn!("Vsock connection failed: {}", e);
+                        warn!("persistence connection failed: {}", e);
                     }
                 }
             }
         })
     }
+}
 
-    /// write the new state into a file on the host
-    fn persist_state(path: &Path, new_state: &consensus::State) -> Result<(), StateError> {
-        debug!(
-            "writing new consensus state to {}: {:?}",
-            path.display(),
-            &new_state
-        );
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state_store::JsonFileStore;
 
-        let json = serde_json::to_string(&new_state)
-            .map_err(|e| StateError::sync_enc_dec_error(path.display().to_string(), e))?;
+    #[test]
+    fn lost_state_is_refused_unless_forced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let guard = FreshStateGuard::new(&path, false, false);
+        let mut store = JsonFileStore::new(&path);
+        // the first start initializes the state
+        let (state, _) = StateSyncer::load_or_init(&mut store, Some(&guard)).unwrap();
+        assert_eq!(state.state.height.value(), 0);
 
-        let state_file_dir = path.parent().unwrap_or_else(|| {
-            panic!("state file cannot be root directory");
-        });
+        std::fs::remove_file(&path).unwrap();
+        assert!(StateSyncer::load_or_init(&mut store, Some(&guard)).is_err());
+        let forced = FreshStateGuard::new(&path, false, true);
+        assert!(StateSyncer::load_or_init(&mut store, Some(&forced)).is_ok());
 
-        let mut state_file = NamedTempFile::new_in(state_file_dir)
-            .map_err(|e| StateError::sync_error(path.display().to_string(), e))?;
-        state_file
-            .write_all(json.as_bytes())
-            .map_err(|e| StateError::sync_error(path.display().to_string(), e))?;
-        state_file
-            .persist(path)
-            .map_err(|e| StateError::sync_error(path.display().to_string(), e.error))?;
-
-        debug!(
-            "successfully wrote new consensus state to {}",
-            path.display(),
+        let other = dir.path().join("other.json");
+        let required = FreshStateGuard::new(&other, true, false);
+        assert!(
+            StateSyncer::load_or_init(&mut JsonFileStore::new(&other), Some(&required)).is_err()
         );
+    }
 
-        Ok(())
+    #[test]
+    fn corrupted_state_is_quarantined_and_rebuilt() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        std::fs::write(&path, b"{\"state\": tru").unwrap();
+        let mut guard = FreshStateGuard::new(&path, false, false);
+        let mut store = JsonFileStore::new(&path);
+        assert!(StateSyncer::load_or_init(&mut store, Some(&guard)).is_err());
+        assert!(!path.exists());
+        // the quarantined state isn't mistaken for a missing one
+        assert!(StateSyncer::load_or_init(&mut store, Some(&guard)).is_err());
+
+        std::fs::write(&path, b"{\"state\": tru").unwrap();
+        guard.set_recovery(Some(StateRecoveryConfig {
+            height: Some(100),
+            rpc_addr: None,
+            height_margin: 2,
+        }));
+        let (state, recovered) = StateSyncer::load_or_init(&mut store, Some(&guard)).unwrap();
+        assert!(recovered);
+        assert_eq!(state.state.height.value(), 100);
+        assert_eq!(store.load().unwrap().unwrap().state.height.value(), 100);
     }
 }
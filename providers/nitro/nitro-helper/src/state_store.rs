@@ -0,0 +1,332 @@
+use crate::dynamodb_store::DynamoDbStore;
+use crate::kv_store::KvStore;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::io::{self, prelude::*};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tempfile::NamedTempFile;
+use tendermint::Time;
+use tmkms_light::chain::state::{MacedState, StateError};
+use tracing::debug;
+
+/// where the host persists the enclave's state
+pub trait StateStore: Send {
+    /// the last persisted state (`None` if there's none yet)
+    fn load(&mut self) -> Result<Option<MacedState>, StateError>;
+    /// persists the new state (durably, before it's acknowledged to the enclave)
+    fn persist(&mut self, new_state: &MacedState) -> Result<(), StateError>;
+    /// moves a corrupted state aside (to keep it for the investigation) and returns where
+    /// (`None` if the backend can't, e.g. a remote one)
+    fn quarantine(&mut self) -> Result<Option<PathBuf>, StateError> {
+        Ok(None)
+    }
+}
+
+/// the state store backend
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum StateBackend {
+    /// the last state in a JSON file (`state_file_path`)
+    #[default]
+    JsonFile,
+    /// the history of all states in an SQLite database
+    Sqlite { path: PathBuf },
+    /// the state shared by the primary and standby signers in Consul's KV store
+    /// (`addr` is its HTTP API's `host:port`)
+    Consul {
+        addr: String,
+        key: String,
+        #[serde(default)]
+        token: Option<String>,
+    },
+    /// the state shared by the primary and standby signers in etcd
+    /// (`addr` is its JSON gateway's `host:port`)
+    Etcd { addr: String, key: String },
+    /// the state in a DynamoDB table (in the item with the `id` partition key);
+    /// the region is from the environment (e.g. `AWS_REGION`) unless set
+    #[serde(rename = "dynamodb")]
+    DynamoDb {
+        table: String,
+        id: String,
+        #[serde(default)]
+        region: Option<String>,
+    },
+}
+
+impl StateBackend {
+    pub fn open(&self, state_file_path: &Path) -> Result<Box<dyn StateStore>, StateError> {
+        match self {
+            StateBackend::JsonFile => Ok(Box::new(JsonFileStore::new(state_file_path))),
+            StateBackend::Sqlite { path } => Ok(Box::new(SqliteStore::open(path)?)),
+            StateBackend::Consul { addr, key, token } => Ok(Box::new(KvStore::consul(
+                addr.clone(),
+                key.clone(),
+                token.clone(),
+            ))),
+            StateBackend::Etcd { addr, key } => {
+                Ok(Box::new(KvStore::etcd(addr.clone(), key.clone())))
+            }
+            StateBackend::DynamoDb { table, id, region } => Ok(Box::new(DynamoDbStore::new(
+                table.clone(),
+                id.clone(),
+                region.clone(),
+            )?)),
+        }
+    }
+}
+
+/// the last state in a JSON file (with its previous generation kept next to it,
+/// for the operator to investigate or restore; it's never loaded)
+pub struct JsonFileStore {
+    path: PathBuf,
+}
+
+impl JsonFileStore {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+        }
+    }
+
+    /// the previous generation of the state file
+    fn previous_generation_path(path: &Path) -> PathBuf {
+        let mut prev = path.as_os_str().to_owned();
+        prev.push(".prev");
+        PathBuf::from(prev)
+    }
+
+    /// hard-links (or copies, if the filesystem can't) the state file as its previous generation
+    fn keep_previous_generation(path: &Path) -> io::Result<()> {
+        let prev = Self::previous_generation_path(path);
+        match fs::remove_file(&prev) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        fs::hard_link(path, &prev).or_else(|_| fs::copy(path, &prev).map(|_| ()))
+    }
+
+    /// reads a state file (`None` if it doesn't exist)
+    fn read_state_file(path: &Path) -> Result<Option<MacedState>, StateError> {
+        match fs::read_to_string(path) {
+            Ok(state_json) => serde_json::from_str(&state_json)
+                .map(Some)
+                .map_err(|e| StateError::sync_enc_dec_error(path.display().to_string(), e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(StateError::sync_error(path.display().to_string(), e)),
+        }
+    }
+}
+
+impl StateStore for JsonFileStore {
+    /// loads the state file; a corrupted one (e.g. truncated after a power loss) is an error
+    /// (it's only replaced by the operator or the `state_recovery` after the quarantine),
+    /// and so is a missing one if its previous generation exists (e.g. removed by mistake)
+    fn load(&mut self) -> Result<Option<MacedState>, StateError> {
+        let path = &self.path;
+        let prev = Self::previous_generation_path(path);
+        match Self::read_state_file(path)? {
+            Some(state) => Ok(Some(state)),
+            None if prev.exists() => Err(StateError::sync_other_error(format!(
+                "{} is missing, but its previous generation {} exists; check and restore it",
+                path.display(),
+                prev.display()
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    /// write the new state into a file on the host
+    /// (into a synced temporary file that atomically replaces it, after the current one
+    /// is linked as the previous generation, so the state file is never missing)
+    fn persist(&mut self, new_state: &MacedState) -> Result<(), StateError> {
+        let path = &self.path;
+        debug!(
+            "writing new consensus state to {}: {:?}",
+            path.display(),
+            &new_state
+        );
+
+        let json = serde_json::to_string(&new_state)
+            .map_err(|e| StateError::sync_enc_dec_error(path.display().to_string(), e))?;
+
+        let state_file_dir = match path.parent() {
+            Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+            Some(dir) => dir,
+            None => panic!("state file cannot be root directory"),
+        };
+
+        let mut state_file = NamedTempFile::new_in(state_file_dir)
+            .map_err(|e| StateError::sync_error(path.display().to_string(), e))?;
+        state_file
+            .write_all(json.as_bytes())
+            .and_then(|_| state_file.as_file().sync_all())
+            .map_err(|e| StateError::sync_error(path.display().to_string(), e))?;
+        if path.exists() {
+            Self::keep_previous_generation(path)
+                .map_err(|e| StateError::sync_error(path.display().to_string(), e))?;
+        }
+        state_file
+            .persist(path)
+            .map_err(|e| StateError::sync_error(path.display().to_string(), e.error))?;
+        // the renames are only durable once the directory is synced
+        fs::File::open(state_file_dir)
+            .and_then(|dir| dir.sync_all())
+            .map_err(|e| StateError::sync_error(state_file_dir.display().to_string(), e))?;
+
+        debug!(
+            "successfully wrote new consensus state to {}",
+            path.display(),
+        );
+
+        Ok(())
+    }
+
+    /// renames the state file and its previous generation (if they exist)
+    /// to `<name>.quarantine-<unix time>`
+    fn quarantine(&mut self) -> Result<Option<PathBuf>, StateError> {
+        let suffix = format!(".quarantine-{}", Time::now().unix_timestamp());
+        let mut quarantined = None;
+        for path in [
+            Self::previous_generation_path(&self.path),
+            self.path.clone(),
+        ] {
+            if !path.exists() {
+                continue;
+            }
+            let mut target = path.as_os_str().to_owned();
+            target.push(&suffix);
+            fs::rename(&path, &target)
+                .map_err(|e| StateError::sync_error(path.display().to_string(), e))?;
+            quarantined = Some(PathBuf::from(target));
+        }
+        Ok(quarantined)
+    }
+}
+
+/// all the persisted states in an SQLite database (the last one being the current state),
+/// so the signing history can be queried
+pub struct SqliteStore {
+    conn: Connection,
+}
+
+fn sqlite_error(e: rusqlite::Error) -> StateError {
+    StateError::sync_other_error(format!("sqlite: {}", e))
+}
+
+impl SqliteStore {
+    /// opens (or creates) the database
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StateError> {
+        let conn = Connection::open(path).map_err(sqlite_error)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             PRAGMA synchronous = FULL;
+             CREATE TABLE IF NOT EXISTS signed_states (
+                 id INTEGER PRIMARY KEY AUTOINCREMENT,
+                 height INTEGER NOT NULL,
+                 round INTEGER NOT NULL,
+                 step INTEGER NOT NULL,
+                 block_id TEXT,
+                 state_json TEXT NOT NULL,
+                 persisted_at TEXT NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS signed_states_height ON signed_states (height);",
+        )
+        .map_err(sqlite_error)?;
+        Ok(Self { conn })
+    }
+}
+
+impl StateStore for SqliteStore {
+    fn load(&mut self) -> Result<Option<MacedState>, StateError> {
+        let state_json: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT state_json FROM signed_states ORDER BY id DESC LIMIT 1",
+                [],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(sqlite_error)?;
+        state_json
+            .map(|json| {
+                serde_json::from_str(&json)
+                    .map_err(|e| StateError::sync_enc_dec_error("sqlite".into(), e))
+            })
+            .transpose()
+    }
+
+    fn persist(&mut self, new_state: &MacedState) -> Result<(), StateError> {
+        let state_json = serde_json::to_string(new_state)
+            .map_err(|e| StateError::sync_enc_dec_error("sqlite".into(), e))?;
+        let state = &new_state.state;
+        self.conn
+            .execute(
+                "INSERT INTO signed_states (height, round, step, block_id, state_json, persisted_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    state.height.value(),
+                    state.round.value(),
+                    state.step,
+                    state.block_id.as_ref().map(|id| id.hash.to_string()),
+                    state_json,
+                    Time::now().to_rfc3339(),
+                ],
+            )
+            .map_err(sqlite_error)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tmkms_light::chain::state::{consensus, StateErrorDetail};
+
+    fn state(height: u32) -> MacedState {
+        MacedState::from(consensus::State {
+            height: height.into(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn never_falls_back_to_the_previous_generation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let mut store = JsonFileStore::new(&path);
+        assert!(store.load().unwrap().is_none());
+        store.persist(&state(1)).unwrap();
+        store.persist(&state(2)).unwrap();
+        assert_eq!(store.load().unwrap().unwrap().state.height, 2u32.into());
+        let prev = JsonFileStore::read_state_file(&JsonFileStore::previous_generation_path(&path));
+        assert_eq!(prev.unwrap().unwrap().state.height, 1u32.into());
+        // truncated
+        let json = fs::read(&path).unwrap();
+        fs::write(&path, &json[..json.len() / 2]).unwrap();
+        assert!(matches!(
+            store.load().unwrap_err().detail(),
+            StateErrorDetail::SyncEncDecError(_)
+        ));
+        // missing (e.g. removed by mistake)
+        fs::remove_file(&path).unwrap();
+        assert!(store.load().is_err());
+    }
+
+    #[test]
+    fn sqlite_keeps_the_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = SqliteStore::open(dir.path().join("state.db")).unwrap();
+        assert!(store.load().unwrap().is_none());
+        store.persist(&state(1)).unwrap();
+        store.persist(&state(2)).unwrap();
+        assert_eq!(store.load().unwrap().unwrap().state.height, 2u32.into());
+        let count: i64 = store
+            .conn
+            .query_row("SELECT COUNT(*) FROM signed_states", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+}
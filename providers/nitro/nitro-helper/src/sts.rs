@@ -0,0 +1,110 @@
+use crate::shared::AwsCredentials;
+use aws_sdk_sts::{model::Tag, Client, Config, Credentials, Region};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::runtime::Builder;
+
+/// a dedicated role for the KMS access, assumed with the instance's (or configured) credentials
+/// before the credentials are pushed to the enclave
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AssumeRoleConfig {
+    /// ARN of the role to assume
+    pub role_arn: String,
+    /// external ID required by the role's trust policy (if any)
+    pub external_id: Option<String>,
+    /// name of the role sessions
+    #[serde(default = "default_session_name")]
+    pub session_name: String,
+    /// lifetime of the role credentials
+    #[serde(default = "default_duration_secs")]
+    pub duration_secs: u32,
+    /// session tags (e.g. for attribute-based conditions in the key policy)
+    #[serde(default)]
+    pub session_tags: BTreeMap<String, String>,
+}
+
+fn default_session_name() -> String {
+    "tmkms-light".to_owned()
+}
+
+fn default_duration_secs() -> u32 {
+    3600
+}
+
+impl AssumeRoleConfig {
+    /// the role credentials (with when they expire)
+    pub fn assume(
+        &self,
+        aws_region: &str,
+        source: &AwsCredentials,
+    ) -> Result<(AwsCredentials, SystemTime), String> {
+        let rt = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| format!("failed to create tokio runtime: {:?}", e))?;
+        let config = Config::builder()
+            .region(Region::new(aws_region.to_owned()))
+            .credentials_provider(Credentials::new(
+                &source.aws_key_id,
+                &source.aws_secret_key,
+                Some(source.aws_session_token.clone()).filter(|token| !token.is_empty()),
+                None,
+                "tmkms-light",
+            ))
+            .build();
+        let mut request = Client::from_conf(config)
+            .assume_role()
+            .role_arn(&self.role_arn)
+            .role_session_name(&self.session_name)
+            .duration_seconds(self.duration_secs as i32)
+            .set_external_id(self.external_id.clone());
+        for (key, value) in &self.session_tags {
+            request = request.tags(Tag::builder().key(key).value(value).build());
+        }
+        let output = rt
+            .block_on(request.send())
+            .map_err(|e| format!("failed to assume {}: {}", self.role_arn, e))?;
+        let credentials = output
+            .credentials()
+            .ok_or_else(|| format!("no credentials for {}", self.role_arn))?;
+        let expiration = credentials
+            .expiration()
+            .map(|expiration| UNIX_EPOCH + Duration::from_secs(expiration.secs().max(0) as u64))
+            .unwrap_or_else(|| SystemTime::now() + Duration::from_secs(self.duration_secs.into()));
+        Ok((
+            AwsCredentials {
+                aws_key_id: credentials.access_key_id().unwrap_or_default().to_owned(),
+                aws_secret_key: credentials
+                    .secret_access_key()
+                    .unwrap_or_default()
+                    .to_owned(),
+                aws_session_token: credentials.session_token().unwrap_or_default().to_owned(),
+            },
+            expiration,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_assume_role_config() {
+        let config: AssumeRoleConfig = toml::from_str(
+            r#"
+            role_arn = "arn:aws:iam::123456789012:role/tmkms-kms"
+            external_id = "validator-1"
+            [session_tags]
+            chain_id = "testchain-1"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.session_name, "tmkms-light");
+        assert_eq!(config.duration_secs, 3600);
+        assert_eq!(config.session_tags["chain_id"], "testchain-1");
+        assert!(toml::from_str::<AssumeRoleConfig>("role_arn = \"r\"\nrole = \"r\"").is_err());
+    }
+}
@@ -0,0 +1,300 @@
+//! supervision of the enclave launched by the helper (`enclave start`):
+//! an enclave that exited or stopped answering the heartbeats is relaunched,
+//! its measurements are checked again and the start config is pushed to it
+
+use crate::command::chain::request_chains;
+use crate::command::nitro_enclave::{
+    describe_enclave, run_enclave_daemon, stop_enclave, wait_for_enclave,
+};
+use crate::command::push_config;
+use crate::config::{EnclaveOpt, NitroSignOpt};
+use crate::key_utils::credential::CredentialsSource;
+use crate::shared::{NitroConfig, NitroRequest};
+use crate::systemd;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+/// settings of the enclave supervision
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SupervisorConfig {
+    /// how often the enclave is checked
+    #[serde(default = "default_heartbeat_interval_secs")]
+    pub heartbeat_interval_secs: u64,
+    /// how long the enclave has to answer a heartbeat
+    #[serde(default = "default_heartbeat_timeout_secs")]
+    pub heartbeat_timeout_secs: u64,
+    /// the enclave is considered hung after this many consecutive missed heartbeats
+    #[serde(default = "default_max_missed_heartbeats")]
+    pub max_missed_heartbeats: u32,
+    /// give up after this many restarts within `restart_window_secs`
+    #[serde(default = "default_max_restarts")]
+    pub max_restarts: u32,
+    /// the period the restart budget applies to
+    #[serde(default = "default_restart_window_secs")]
+    pub restart_window_secs: u64,
+    /// how long the relaunched enclave has to listen for the config
+    #[serde(default = "default_ready_timeout_secs")]
+    pub ready_timeout_secs: u64,
+}
+
+fn default_heartbeat_interval_secs() -> u64 {
+    10
+}
+
+fn default_heartbeat_timeout_secs() -> u64 {
+    5
+}
+
+fn default_max_missed_heartbeats() -> u32 {
+    3
+}
+
+fn default_max_restarts() -> u32 {
+    3
+}
+
+fn default_restart_window_secs() -> u64 {
+    3600
+}
+
+fn default_ready_timeout_secs() -> u64 {
+    30
+}
+
+/// the enclave run by the helper (whose ID changes with every relaunch)
+#[derive(Clone)]
+pub struct EnclaveLaunch {
+    pub opt: EnclaveOpt,
+    pub enclave_id: Arc<Mutex<String>>,
+}
+
+/// the restarts within the window
+#[derive(Debug)]
+pub struct RestartBudget {
+    max_restarts: u32,
+    window: Duration,
+    restarts: VecDeque<Instant>,
+}
+
+impl RestartBudget {
+    pub fn new(max_restarts: u32, window: Duration) -> Self {
+        Self {
+            max_restarts,
+            window,
+            restarts: VecDeque::new(),
+        }
+    }
+
+    /// registers a restart at `now` (`false` if the budget is exhausted)
+    pub fn try_restart(&mut self, now: Instant) -> bool {
+        while matches!(self.restarts.front(), Some(t) if now.duration_since(*t) >= self.window) {
+            self.restarts.pop_front();
+        }
+        if self.restarts.len() >= self.max_restarts as usize {
+            return false;
+        }
+        self.restarts.push_back(now);
+        true
+    }
+}
+
+/// why the enclave is restarted
+enum Failure {
+    Exited,
+    Hung,
+}
+
+/// watches the enclave and relaunches it
+pub struct Supervisor {
+    config: SupervisorConfig,
+    helper_config: NitroSignOpt,
+    launch: EnclaveLaunch,
+    cid: u32,
+    enclave_config: NitroConfig,
+    /// set when the helper shuts the enclave down (so it's not relaunched)
+    stopping: Arc<AtomicBool>,
+}
+
+impl Supervisor {
+    pub fn new(
+        config: SupervisorConfig,
+        helper_config: NitroSignOpt,
+        launch: EnclaveLaunch,
+        cid: u32,
+        enclave_config: NitroConfig,
+        stopping: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            config,
+            helper_config,
+            launch,
+            cid,
+            enclave_config,
+            stopping,
+        }
+    }
+
+    fn alert(&self, message: &str) {
+        error!(
+            chain_id = %self.helper_config.chain_id,
+            alert = true,
+            "[{}] {}",
+            self.helper_config.chain_id,
+            message
+        );
+        systemd::notify(&format!("STATUS={}", message));
+    }
+
+    /// the enclave is still running
+    fn running(&self) -> Result<bool, String> {
+        let enclave_id = self
+            .launch
+            .enclave_id
+            .lock()
+            .expect("enclave id lock")
+            .clone();
+        Ok(describe_enclave()?
+            .iter()
+            .any(|enclave| enclave.enclave_id == enclave_id && enclave.state == "RUNNING"))
+    }
+
+    fn heartbeat(&self) -> Result<(), String> {
+        request_chains(
+            self.cid,
+            self.helper_config.enclave_config_port,
+            &NitroRequest::ChainStatus,
+            Duration::from_secs(self.config.heartbeat_timeout_secs),
+        )
+        .map(|_| ())
+        .map_err(String::from)
+    }
+
+    /// waits until the enclave exits or hangs (`None` if the helper stops it)
+    fn watch(&self) -> Option<Failure> {
+        let mut missed = 0;
+        loop {
+            thread::sleep(Duration::from_secs(self.config.heartbeat_interval_secs));
+            if self.stopping.load(Ordering::SeqCst) {
+                return None;
+            }
+            match self.running() {
+                Ok(false) => return Some(Failure::Exited),
+                Ok(true) => {}
+                Err(e) => warn!("failed to describe the enclave: {}", e),
+            }
+            match self.heartbeat() {
+                Ok(()) => missed = 0,
+                Err(e) => {
+                    missed += 1;
+                    warn!(
+                        "enclave missed a heartbeat ({}/{}): {}",
+                        missed, self.config.max_missed_heartbeats, e
+                    );
+                    if missed >= self.config.max_missed_heartbeats {
+                        return Some(Failure::Hung);
+                    }
+                }
+            }
+        }
+    }
+
+    /// terminates the enclave, runs it again (on the same CID), checks its measurements
+    /// and pushes the start config (with fresh credentials)
+    fn relaunch(&mut self) -> Result<(), String> {
+        let enclave_id = self
+            .launch
+            .enclave_id
+            .lock()
+            .expect("enclave id lock")
+            .clone();
+        if let Err(e) = stop_enclave(Some(enclave_id)) {
+            warn!("failed to terminate the enclave: {}", e);
+        }
+        let opt = &self.launch.opt;
+        let info = run_enclave_daemon(
+            &opt.eif_path,
+            opt.cpu_count,
+            opt.memory_mib,
+            Some(u64::from(self.cid)),
+        )?;
+        *self.launch.enclave_id.lock().expect("enclave id lock") = info.enclave_id;
+        wait_for_enclave(
+            self.cid,
+            self.helper_config.enclave_config_port,
+            Duration::from_secs(self.config.ready_timeout_secs),
+        )?;
+        self.enclave_config.credentials =
+            CredentialsSource::new(&self.helper_config).credentials()?;
+        push_config(&self.helper_config, Some(self.cid), &self.enclave_config)
+    }
+
+    fn run(mut self) {
+        let mut budget = RestartBudget::new(
+            self.config.max_restarts,
+            Duration::from_secs(self.config.restart_window_secs),
+        );
+        while let Some(failure) = self.watch() {
+            let reason = match failure {
+                Failure::Exited => "the enclave exited",
+                Failure::Hung => "the enclave stopped answering heartbeats",
+            };
+            if !budget.try_restart(Instant::now()) {
+                self.alert(&format!(
+                    "{}; the restart budget ({} in {}s) is exhausted, giving up",
+                    reason, self.config.max_restarts, self.config.restart_window_secs
+                ));
+                return;
+            }
+            self.alert(&format!("{}, relaunching it", reason));
+            loop {
+                if self.stopping.load(Ordering::SeqCst) {
+                    return;
+                }
+                match self.relaunch() {
+                    Ok(()) => {
+                        info!("enclave relaunched");
+                        systemd::notify("STATUS=enclave relaunched");
+                        break;
+                    }
+                    Err(e) if budget.try_restart(Instant::now()) => {
+                        self.alert(&format!("failed to relaunch the enclave: {}", e));
+                    }
+                    Err(e) => {
+                        self.alert(&format!(
+                            "failed to relaunch the enclave: {}; the restart budget is exhausted, giving up",
+                            e
+                        ));
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn launch(self) {
+        thread::spawn(move || self.run());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restart_budget_is_per_window() {
+        let mut budget = RestartBudget::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+        assert!(budget.try_restart(start));
+        assert!(budget.try_restart(start + Duration::from_secs(10)));
+        assert!(!budget.try_restart(start + Duration::from_secs(20)));
+        // the first restart is out of the window
+        assert!(budget.try_restart(start + Duration::from_secs(60)));
+        assert!(!budget.try_restart(start + Duration::from_secs(65)));
+    }
+}
@@ -0,0 +1,102 @@
+use crate::health::HealthState;
+use nix::sys::socket::{sendto, socket, AddressFamily, MsgFlags, SockFlag, SockType, UnixAddr};
+use std::env;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// how often the enclave connection is checked when the watchdog isn't enabled
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// sends a state (e.g. `READY=1`) to the service manager
+/// (a no-op if the helper wasn't started by systemd with `NOTIFY_SOCKET`)
+pub fn notify(state: &str) {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) if !path.is_empty() => path,
+        _ => return,
+    };
+    let addr = if let Some(name) = path.strip_prefix('@') {
+        UnixAddr::new_abstract(name.as_bytes())
+    } else {
+        UnixAddr::new(path.as_str())
+    };
+    let result = addr.and_then(|addr| {
+        let fd = socket(
+            AddressFamily::Unix,
+            SockType::Datagram,
+            SockFlag::SOCK_CLOEXEC,
+            None,
+        )?;
+        let sent = sendto(fd, state.as_bytes(), &addr, MsgFlags::empty());
+        let _ = nix::unistd::close(fd);
+        sent
+    });
+    if let Err(e) = result {
+        tracing::warn!("failed to notify systemd ({}): {}", state, e);
+    }
+}
+
+/// the keepalive interval requested with `WatchdogSec=` (half of the timeout)
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    match usec?.parse::<u64>() {
+        Ok(usec) if usec > 0 => Some(Duration::from_micros(usec / 2)),
+        _ => None,
+    }
+}
+
+/// signals `READY=1` once the enclave is connected to the state syncer
+/// and then sends `WATCHDOG=1` keepalives only while it stays connected
+/// (so systemd restarts the signer if the enclave session is lost)
+pub fn launch_watchdog(health: Arc<HealthState>) {
+    if env::var_os("NOTIFY_SOCKET").is_none() {
+        return;
+    }
+    let interval = watchdog_interval(
+        env::var("WATCHDOG_USEC").ok().as_deref(),
+        env::var("WATCHDOG_PID").ok().as_deref(),
+    );
+    thread::spawn(move || {
+        let mut ready = false;
+        loop {
+            if health.report().enclave_connected {
+                if !ready {
+                    tracing::info!("enclave session established, notifying systemd");
+                    notify("READY=1");
+                    ready = true;
+                }
+                if interval.is_some() {
+                    notify("WATCHDOG=1");
+                }
+            } else if ready {
+                tracing::warn!("enclave disconnected, withholding the watchdog keepalives");
+            }
+            thread::sleep(interval.map_or(POLL_INTERVAL, |i| i.min(POLL_INTERVAL)));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watchdog_interval_is_half_the_timeout() {
+        assert_eq!(
+            watchdog_interval(Some("30000000"), None),
+            Some(Duration::from_secs(15))
+        );
+        let pid = std::process::id().to_string();
+        assert_eq!(
+            watchdog_interval(Some("2000000"), Some(&pid)),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(watchdog_interval(Some("2000000"), Some("1")), None);
+        assert_eq!(watchdog_interval(Some("0"), None), None);
+        assert_eq!(watchdog_interval(None, None), None);
+    }
+}
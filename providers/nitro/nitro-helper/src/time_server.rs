@@ -0,0 +1,64 @@
+use crate::mux_server::ChannelListener;
+use rand_core::{OsRng, RngCore};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tmkms_light::utils::{read_u16_payload, write_u16_payload};
+use tmkms_nitro_helper::channel::ChannelStream;
+use tmkms_nitro_helper::time_sync::{TimeSyncRequest, TimeSyncResponse};
+use tracing::{error, info, warn};
+use zeroize::Zeroizing;
+
+/// answers the enclave's time synchronization requests with the host's time
+/// (authenticated with a key generated at each launch and pushed with the enclave's config)
+pub struct TimeServer {
+    key: Zeroizing<Vec<u8>>,
+    vsock_listener: ChannelListener,
+}
+
+impl TimeServer {
+    /// generates the key and binds a listener for the enclave on the provided port
+    pub fn new(vsock_port: u32) -> Result<Self, String> {
+        let mut key = Zeroizing::new(vec![0u8; 32]);
+        OsRng.fill_bytes(&mut key);
+        let vsock_listener = ChannelListener::bind(vsock_port)
+            .map_err(|e| format!("failed to bind the time sync listener: {:?}", e))?;
+        Ok(Self {
+            key,
+            vsock_listener,
+        })
+    }
+
+    /// the key authenticating the answers
+    pub fn key(&self) -> Vec<u8> {
+        self.key.to_vec()
+    }
+
+    fn answer(&self, stream: &mut ChannelStream) -> Result<(), String> {
+        let json_raw = read_u16_payload(stream).map_err(|e| format!("{}", e))?;
+        let request: TimeSyncRequest = serde_json::from_slice(&json_raw)
+            .map_err(|e| format!("invalid time sync request: {:?}", e))?;
+        let unix_nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| format!("invalid host time: {:?}", e))?
+            .as_nanos() as u64;
+        let response = TimeSyncResponse::new(&self.key, &request, unix_nanos);
+        let json_raw = serde_json::to_vec(&response).map_err(|e| format!("{:?}", e))?;
+        write_u16_payload(stream, &json_raw).map_err(|e| format!("{:?}", e))
+    }
+
+    /// serves the enclave connections in a separate thread
+    pub fn launch(self) {
+        thread::spawn(move || {
+            while let Ok((mut stream, _)) = self.vsock_listener.accept() {
+                info!("vsock time sync connection established");
+                let result = loop {
+                    if let Err(e) = self.answer(&mut stream) {
+                        break e;
+                    }
+                };
+                warn!("vsock time sync connection lost: {}", result);
+            }
+            error!("time sync listener failed");
+        });
+    }
+}
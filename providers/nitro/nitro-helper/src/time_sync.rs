@@ -0,0 +1,170 @@
+//! Time synchronization of the enclave (which has no reliable wall clock) from the host:
+//! the enclave periodically requests the host's time with a random nonce, the host's answer
+//! is authenticated with a per-launch key (pushed with the enclave's config), and the
+//! enclave's clock only slews towards the samples (it never jumps after the first one)
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tendermint::Time;
+use tmkms_light::clock::Clock;
+
+/// samples with a longer round trip are discarded (their midpoint is too imprecise)
+pub const MAX_ROUND_TRIP: Duration = Duration::from_secs(1);
+
+/// the largest adjustment of the enclave's clock per sample
+pub const MAX_SLEW: Duration = Duration::from_millis(500);
+
+/// the clock moves by this fraction (1/n) of a sample's deviation
+const SMOOTHING: i128 = 4;
+
+const MAC_DOMAIN: &[u8] = b"tmkms-nitro-time-sync";
+
+/// the enclave's request for the host's time
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeSyncRequest {
+    /// random nonce (so that an old answer can't be replayed)
+    pub nonce: [u8; 16],
+}
+
+/// the host's time
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeSyncResponse {
+    /// nanoseconds since the Unix epoch
+    pub unix_nanos: u64,
+    /// HMAC-SHA256 of the nonce and the time with the time sync key
+    pub mac: Vec<u8>,
+}
+
+fn time_mac(key: &[u8], nonce: &[u8], unix_nanos: u64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(MAC_DOMAIN);
+    mac.update(nonce);
+    mac.update(&unix_nanos.to_be_bytes());
+    mac
+}
+
+impl TimeSyncResponse {
+    /// the host's answer to the request
+    pub fn new(key: &[u8], request: &TimeSyncRequest, unix_nanos: u64) -> Self {
+        let mac = time_mac(key, &request.nonce, unix_nanos)
+            .finalize()
+            .into_bytes()
+            .to_vec();
+        Self { unix_nanos, mac }
+    }
+
+    /// checks the answer is authentic (and to this request)
+    pub fn verify(&self, key: &[u8], request: &TimeSyncRequest) -> Result<(), String> {
+        time_mac(key, &request.nonce, self.unix_nanos)
+            .verify_slice(&self.mac)
+            .map_err(|_| "invalid time sync MAC".to_owned())
+    }
+}
+
+/// the estimated wall clock at a point of the monotonic one
+#[derive(Debug, Clone, Copy)]
+struct Anchor {
+    at: Instant,
+    unix_nanos: i128,
+}
+
+impl Anchor {
+    fn unix_nanos_at(&self, instant: Instant) -> i128 {
+        self.unix_nanos + instant.saturating_duration_since(self.at).as_nanos() as i128
+    }
+}
+
+/// the wall clock synchronized from the host's time samples
+/// (it advances with the monotonic clock between the samples)
+#[derive(Debug, Default)]
+pub struct SyncedClock {
+    anchor: Mutex<Option<Anchor>>,
+}
+
+impl SyncedClock {
+    /// incorporates the host's time received in response to a request sent at `sent`
+    pub fn update(&self, unix_nanos: u64, sent: Instant, received: Instant) -> Result<(), String> {
+        let round_trip = received.saturating_duration_since(sent);
+        if round_trip > MAX_ROUND_TRIP {
+            return Err(format!(
+                "time sample discarded (round trip of {} ms)",
+                round_trip.as_millis()
+            ));
+        }
+        // the host's time is assumed to be read halfway through the round trip
+        let sample = unix_nanos as i128 + (round_trip / 2).as_nanos() as i128;
+        let mut anchor = self.anchor.lock().unwrap_or_else(|e| e.into_inner());
+        let unix_nanos = match *anchor {
+            Some(current) => {
+                let estimate = current.unix_nanos_at(received);
+                let max_slew = MAX_SLEW.as_nanos() as i128;
+                estimate + ((sample - estimate) / SMOOTHING).clamp(-max_slew, max_slew)
+            }
+            None => sample,
+        };
+        *anchor = Some(Anchor {
+            at: received,
+            unix_nanos,
+        });
+        Ok(())
+    }
+}
+
+impl Clock for SyncedClock {
+    fn now(&self) -> Result<Time, String> {
+        let anchor = self
+            .anchor
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .ok_or_else(|| "the clock isn't synchronized yet".to_owned())?;
+        let unix_nanos = anchor.unix_nanos_at(Instant::now());
+        Time::from_unix_timestamp(
+            (unix_nanos / 1_000_000_000) as i64,
+            (unix_nanos % 1_000_000_000) as u32,
+        )
+        .map_err(|e| format!("invalid synchronized time: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn samples_are_authenticated_and_smoothed() {
+        let key = [1u8; 32];
+        let request = TimeSyncRequest { nonce: [2u8; 16] };
+        let response = TimeSyncResponse::new(&key, &request, 1_600_000_000 * SECOND);
+        assert!(response.verify(&key, &request).is_ok());
+        assert!(response.verify(&[3u8; 32], &request).is_err());
+        assert!(response
+            .verify(&key, &TimeSyncRequest { nonce: [4u8; 16] })
+            .is_err());
+
+        let clock = SyncedClock::default();
+        assert!(clock.now().is_err());
+        let sent = Instant::now();
+        clock.update(response.unix_nanos, sent, sent).unwrap();
+        let synced = clock.now().unwrap().unix_timestamp();
+        assert!((1_600_000_000..1_600_000_002).contains(&synced));
+
+        // a host jumping an hour ahead only moves the clock by the maximum slew
+        let now = Instant::now();
+        clock
+            .update((1_600_000_000 + 3600) * SECOND, now, now)
+            .unwrap();
+        let slewed = clock.now().unwrap().unix_timestamp();
+        assert!((1_600_000_000..1_600_000_003).contains(&slewed));
+
+        assert!(clock
+            .update(response.unix_nanos, now, now + Duration::from_secs(2))
+            .is_err());
+    }
+}
@@ -1,17 +1,48 @@
 //! Copyright (c) 2019 Tokio Contributors (licensed under the MIT License)
 //! Modifications Copyright (c) 2021-present Crypto.com (licensed under the Apache License, Version 2.0)
 
+use crate::channel::ChannelStream;
 use std::collections::HashMap;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
 use std::{fmt, io::Write};
 use tracing::warn;
 use tracing_core::{
     event::Event,
     field::Visit,
     span::{Attributes, Id, Record},
-    Field, Level, Metadata, Subscriber,
+    Field, Level, LevelFilter, Metadata, Subscriber,
 };
 use tracing_subscriber::{layer::Context, registry::LookupSpan};
-use vsock::{VsockAddr, VsockStream};
+
+/// upper bound of a forwarded log record
+pub const MAX_LOG_FRAME_LEN: usize = 1 << 20;
+/// how long the log server has to send its level when the enclave connects
+const LEVEL_READ_TIMEOUT: Duration = Duration::from_secs(1);
+/// no runtime level is set
+const NO_LEVEL_OVERRIDE: u8 = u8::MAX;
+
+/// the enclave's log level set at runtime (`NitroRequest::SetLogLevel`)
+static LEVEL_OVERRIDE: AtomicU8 = AtomicU8::new(NO_LEVEL_OVERRIDE);
+
+/// sets the log level at runtime (for both the console and the forwarded logs);
+/// `None` goes back to the startup level and the helper's one
+pub fn set_level_override(level: Option<LevelFilter>) {
+    LEVEL_OVERRIDE.store(
+        level.map_or(NO_LEVEL_OVERRIDE, level_filter_to_byte),
+        Ordering::SeqCst,
+    );
+}
+
+/// the log level set at runtime (if any)
+pub fn level_override() -> Option<LevelFilter> {
+    match LEVEL_OVERRIDE.load(Ordering::SeqCst) {
+        NO_LEVEL_OVERRIDE => None,
+        b => Some(level_filter_from_byte(b)),
+    }
+}
 
 macro_rules! return_from_utf8_error {
     ($res:expr) => {
@@ -37,10 +68,22 @@ macro_rules! break_from_checked_add_overflow {
 }
 pub(crate) use {break_from_checked_add_overflow, return_from_utf8_error};
 
+/// the connection to the helper's log server
+struct LogConnection {
+    stream: ChannelStream,
+    /// the most verbose level the helper logs
+    max_level: LevelFilter,
+}
+
+/// forwards the enclave's events to the helper's log server over a single connection
+/// (at the level the helper logs, which it sends when the enclave connects,
+/// unless a level was set at runtime)
 pub struct Layer {
     cid: u32,
     local_port: u32,
     field_prefix: Option<String>,
+    /// reconnected with the next event after a failure
+    conn: Mutex<Option<LogConnection>>,
 }
 
 impl Layer {
@@ -49,12 +92,12 @@ impl Layer {
             cid,
             local_port,
             field_prefix: None,
+            conn: Mutex::new(None),
         }
     }
 
-    pub fn get_socket(&self) -> Result<VsockStream, String> {
-        let addr = VsockAddr::new(self.cid, self.local_port);
-        VsockStream::connect(&addr).map_err(|e| {
+    pub fn get_socket(&self) -> Result<ChannelStream, String> {
+        ChannelStream::connect(self.cid, self.local_port).map_err(|e| {
             format!(
                 "failed to connect to the enclave server to push log: {:?}",
                 e
@@ -62,6 +105,21 @@ impl Layer {
         })
     }
 
+    fn connect(&self) -> Result<LogConnection, String> {
+        let mut stream = self.get_socket()?;
+        stream
+            .set_read_timeout(Some(LEVEL_READ_TIMEOUT))
+            .map_err(|e| format!("failed to set the log level timeout: {:?}", e))?;
+        let mut level = [0u8; 1];
+        stream
+            .read_exact(&mut level)
+            .map_err(|e| format!("failed to read the helper's log level: {:?}", e))?;
+        Ok(LogConnection {
+            stream,
+            max_level: level_filter_from_byte(level[0]),
+        })
+    }
+
     /// Sets the prefix to apply to names of user-defined fields other than the event `message`
     /// field. Defaults to `Some("F")`.
     pub fn with_field_prefix(mut self, x: Option<String>) -> Self {
@@ -106,6 +164,22 @@ where
     }
 
     fn on_event(&self, event: &Event, ctx: Context<S>) {
+        // no events are logged while the connection is locked (e.g. by a failure),
+        // as they would come back to this layer
+        let mut conn = match self.conn.lock() {
+            Ok(conn) => conn,
+            Err(_) => return,
+        };
+        if conn.is_none() {
+            *conn = self.connect().ok();
+        }
+        let max_level = match conn.as_ref() {
+            Some(c) => level_override().unwrap_or(c.max_level),
+            None => return,
+        };
+        if *event.metadata().level() > max_level {
+            return;
+        }
         let mut buf = Vec::with_capacity(256);
 
         // Record span fields
@@ -126,15 +200,65 @@ where
             self.field_prefix.as_ref().map(|x| &x[..]),
         ));
 
-        if let Ok(socket) = &mut self.get_socket() {
-            // add \n at the end of the buffer
-            if let Err(e) = socket.write_all(&buf) {
-                eprintln!("{:?}", e);
+        if let Some(c) = conn.as_mut() {
+            if let Err(e) = write_log_frame(&mut c.stream, &buf) {
+                eprintln!("failed to forward the log: {:?}", e);
+                *conn = None;
             }
         }
     }
 }
 
+/// encodes the level filter the log server sends to the enclave
+pub fn level_filter_to_byte(filter: LevelFilter) -> u8 {
+    match filter.into_level() {
+        None => 0,
+        Some(Level::ERROR) => 1,
+        Some(Level::WARN) => 2,
+        Some(Level::INFO) => 3,
+        Some(Level::DEBUG) => 4,
+        Some(Level::TRACE) => 5,
+    }
+}
+
+/// decodes the level filter sent by the log server (unknown values turn forwarding off)
+pub fn level_filter_from_byte(b: u8) -> LevelFilter {
+    match b {
+        1 => LevelFilter::ERROR,
+        2 => LevelFilter::WARN,
+        3 => LevelFilter::INFO,
+        4 => LevelFilter::DEBUG,
+        5 => LevelFilter::TRACE,
+        _ => LevelFilter::OFF,
+    }
+}
+
+/// writes a log record prefixed with its (u32 little-endian) length
+pub fn write_log_frame<W: Write>(writer: &mut W, record: &[u8]) -> io::Result<()> {
+    if record.len() > MAX_LOG_FRAME_LEN {
+        return Err(io::ErrorKind::InvalidInput.into());
+    }
+    writer.write_all(&(record.len() as u32).to_le_bytes())?;
+    writer.write_all(record)?;
+    writer.flush()
+}
+
+/// reads a length-prefixed log record
+pub fn read_log_frame<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_LOG_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "log record is too large",
+        ));
+    }
+    let mut record = vec![0u8; len];
+    reader.read_exact(&mut record)?;
+    Ok(record)
+}
+
 struct SpanFields(Vec<u8>);
 
 struct SpanVisitor<'a> {
@@ -287,6 +411,11 @@ impl Default for Log {
 }
 
 impl Log {
+    /// the value of a structured field (without the debug formatting quotes)
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.debug.get(name).map(|v| v.trim_matches('"'))
+    }
+
     pub fn format(&self) -> String {
         let mut s = format!(
             "[{}] {}:{} {}",
@@ -399,6 +528,28 @@ mod tests {
         assert_eq!("TRACE: [] :0 ", run_test(&bytes).unwrap().as_str());
     }
 
+    #[test]
+    fn log_frames_and_levels() {
+        use super::*;
+        let mut frames = vec![];
+        write_log_frame(&mut frames, b"first").unwrap();
+        write_log_frame(&mut frames, b"").unwrap();
+        let mut reader = frames.as_slice();
+        assert_eq!(read_log_frame(&mut reader).unwrap(), b"first");
+        assert!(read_log_frame(&mut reader).unwrap().is_empty());
+        assert!(read_log_frame(&mut reader).is_err());
+        let too_large = ((MAX_LOG_FRAME_LEN + 1) as u32).to_le_bytes();
+        assert!(read_log_frame(&mut &too_large[..]).is_err());
+        for filter in [LevelFilter::OFF, LevelFilter::INFO, LevelFilter::TRACE] {
+            assert_eq!(level_filter_from_byte(level_filter_to_byte(filter)), filter);
+        }
+        assert_eq!(level_filter_from_byte(9), LevelFilter::OFF);
+        set_level_override(Some(LevelFilter::DEBUG));
+        assert_eq!(level_override(), Some(LevelFilter::DEBUG));
+        set_level_override(None);
+        assert_eq!(level_override(), None);
+    }
+
     #[test]
     fn value_len_missing_bytes() {
         let mut bytes: Vec<u8> = vec![];
@@ -447,6 +598,25 @@ mod tests {
         );
     }
 
+    #[test]
+    fn structured_fields() {
+        let mut bytes: Vec<u8> = vec![];
+        bytes.extend_from_slice("MSG_TYPE".as_bytes());
+        bytes.push(0x0a);
+        bytes.extend_from_slice(&u64::to_le_bytes(6));
+        bytes.extend_from_slice("\"vote\"".as_bytes());
+        bytes.push(0x0a);
+        bytes.extend_from_slice("HEIGHT".as_bytes());
+        bytes.push(0x0a);
+        bytes.extend_from_slice(&u64::to_le_bytes(2));
+        bytes.extend_from_slice("42".as_bytes());
+        bytes.push(0x0a);
+        let log = super::Log::from_raw(&bytes).unwrap();
+        assert_eq!(log.field("msg_type"), Some("vote"));
+        assert_eq!(log.field("height"), Some("42"));
+        assert_eq!(log.field("round"), None);
+    }
+
     #[test]
     fn invalid_priority() {
         let mut bytes: Vec<u8> = vec![];
@@ -471,11 +641,7 @@ mod tests {
 
     #[test]
     fn invalid_utf8_key() {
-        let mut bytes: Vec<u8> = vec![];
-        bytes.push(0xe0);
-        bytes.push(0x80);
-        bytes.push(0x80);
-        bytes.push(0x0a);
+        let mut bytes: Vec<u8> = vec![0xe0, 0x80, 0x80, 0x0a];
         bytes.extend_from_slice(&u64::to_le_bytes(5));
         bytes.extend_from_slice("VALUE".as_bytes());
         bytes.push(0x0a);
@@ -496,7 +662,7 @@ mod tests {
     }
 
     fn run_test(bytes: &[u8]) -> std::io::Result<String> {
-        let log = crate::tracing_layer::Log::from_raw(&bytes)?;
+        let log = crate::tracing_layer::Log::from_raw(bytes)?;
         let level = log.level.as_str();
         let formatted = log.format();
         Ok(format!("{level}: {formatted}"))
@@ -0,0 +1,119 @@
+use crate::peer_auth::{PeerChannel, PeerKey};
+use std::fs;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tempfile::NamedTempFile;
+use tmkms_light::chain::state::{WatermarkRequest, Watermarks};
+use tracing::{debug, error, info, warn};
+
+/// the watermarks shared by the replicas' connections (and the file they're persisted to)
+struct WatermarkStore {
+    path: PathBuf,
+    watermarks: Watermarks,
+}
+
+impl WatermarkStore {
+    /// raises the watermark and persists it before acknowledging the request
+    fn reserve(&mut self, request: WatermarkRequest) -> Result<(), String> {
+        let mut watermarks = self.watermarks.clone();
+        watermarks.reserve(request)?;
+        persist(&self.path, &watermarks)?;
+        self.watermarks = watermarks;
+        Ok(())
+    }
+}
+
+/// write the watermarks into a file
+fn persist(path: &Path, watermarks: &Watermarks) -> Result<(), String> {
+    let json = serde_json::to_vec(watermarks).map_err(|e| format!("{:?}", e))?;
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut file = NamedTempFile::new_in(dir)
+        .map_err(|e| format!("failed to write {}: {:?}", path.display(), e))?;
+    file.write_all(&json)
+        .map_err(|e| format!("failed to write {}: {:?}", path.display(), e))?;
+    file.persist(path)
+        .map_err(|e| format!("failed to write {}: {:?}", path.display(), e.error))?;
+    Ok(())
+}
+
+/// serves the (height, round, step) reservations of redundant signers,
+/// so that at most one of them signs at each (height, round, step)
+/// (only to the signers holding the peer key)
+pub struct WatermarkServer {
+    store: Arc<Mutex<WatermarkStore>>,
+    listener: TcpListener,
+    peer_key: PeerKey,
+}
+
+impl WatermarkServer {
+    /// loads the peer key and the previous watermarks (if any) and binds the listener
+    pub fn new(
+        path: PathBuf,
+        peer_key_path: &Path,
+        listen_addr: SocketAddr,
+    ) -> Result<Self, String> {
+        let peer_key = PeerKey::load_or_generate(peer_key_path)?;
+        let watermarks = match fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map_err(|e| format!("invalid watermark file {}: {:?}", path.display(), e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Watermarks::default(),
+            Err(e) => return Err(format!("failed to read {}: {:?}", path.display(), e)),
+        };
+        let listener = TcpListener::bind(listen_addr)
+            .map_err(|e| format!("failed to bind the watermark listener: {:?}", e))?;
+        Ok(Self {
+            store: Arc::new(Mutex::new(WatermarkStore { path, watermarks })),
+            listener,
+            peer_key,
+        })
+    }
+
+    /// handles a reservation and acknowledges the result
+    fn handle(
+        store: &Mutex<WatermarkStore>,
+        channel: &mut PeerChannel<TcpStream>,
+    ) -> Result<(), String> {
+        let json_raw = channel.recv()?;
+        let result = serde_json::from_slice::<WatermarkRequest>(&json_raw)
+            .map_err(|e| format!("invalid watermark request: {:?}", e))
+            .and_then(|request| {
+                debug!("{} requests {}", request.replica_id, request.state);
+                store
+                    .lock()
+                    .map_err(|_| "watermark store poisoned".to_string())?
+                    .reserve(request)
+            });
+        if let Err(ref e) = result {
+            warn!("watermark refused: {}", e);
+        }
+        let ack = serde_json::to_vec(&result).map_err(|e| format!("{:?}", e))?;
+        channel.send(&ack)
+    }
+
+    /// serves the connections (each in a separate thread)
+    pub fn run(self) -> Result<(), String> {
+        for stream in self.listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    info!("watermark connection from {:?}", stream.peer_addr());
+                    let store = self.store.clone();
+                    let peer_key = self.peer_key.clone();
+                    thread::spawn(move || {
+                        match PeerChannel::accept(stream, peer_key) {
+                            Ok(mut channel) => {
+                                while let Ok(()) = Self::handle(&store, &mut channel) {}
+                            }
+                            Err(e) => warn!("watermark connection failed: {}", e),
+                        }
+                        warn!("watermark connection lost");
+                    });
+                }
+                Err(e) => error!("watermark connection failed: {}", e),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,45 @@
+use tracing::Level;
+use tracing::{error, info, warn};
+use vsock::{VsockAddr, VsockListener};
+
+mod sev;
+
+fn main() {
+    let mut env_args = std::env::args().skip(1);
+    let port = env_args
+        .next()
+        .and_then(|x| x.parse::<u32>().ok())
+        .unwrap_or(5050);
+    let log_level = env_args
+        .next()
+        .map(|x| {
+            if x.to_lowercase() == "--verbose" || x.to_lowercase() == "-v" {
+                Level::DEBUG
+            } else {
+                Level::INFO
+            }
+        })
+        .unwrap_or(Level::INFO);
+    let subscriber = tracing_subscriber::FmtSubscriber::builder()
+        .with_max_level(log_level)
+        .finish();
+    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+
+    const VMADDR_CID_ANY: u32 = 0xFFFFFFFF;
+    let addr = VsockAddr::new(VMADDR_CID_ANY, port);
+    let listener = VsockListener::bind(&addr).expect("bind address");
+    info!("waiting for config to be pushed on {}", addr);
+    for conn in listener.incoming() {
+        match conn {
+            Ok(stream) => {
+                info!("got connection on {:?}", addr);
+                if let Err(e) = sev::entry(stream) {
+                    error!("io error {}", e);
+                }
+            }
+            Err(e) => {
+                warn!("connection error {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,152 @@
+/// sealing of the keys with the derived key
+mod seal;
+/// requests to the AMD secure processor
+mod snp;
+
+use ed25519_consensus::SigningKey;
+use rand_core::OsRng;
+use std::io;
+use std::thread;
+use std::time::Duration;
+use tendermint_p2p::secret_connection::PublicKey;
+use tmkms_light::chain::state::{PersistStateSync, StateMacKey};
+use tmkms_light::config::validator::ValidatorConfig;
+use tmkms_light::connection::{self, Connection, PlainConnection};
+use tmkms_light::error::{io_error_wrap, Error};
+use tmkms_light::utils::{read_u16_payload, write_u16_payload};
+use tmkms_light::vsock::state;
+use tmkms_sev_helper::report::keygen_report_data;
+use tmkms_sev_helper::{
+    SevConfig, SevKeygenResponse, SevKeygenResult, SevRequest, SevSealedKey, SEALING_FIELD_SELECT,
+};
+use tracing::{error, info, warn};
+use zeroize::Zeroize;
+
+/// how long the helper's request can take to arrive
+const REQUEST_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// generates a key, seals it to the guest measurement and attests it
+fn keygen(nonce: &[u8]) -> Result<SevKeygenResponse, String> {
+    let signing_key = SigningKey::new(OsRng);
+    let sealing_key = snp::get_derived_key(SEALING_FIELD_SELECT)
+        .map_err(|e| format!("failed to derive the sealing key: {}", e))?;
+    let sealed_key = seal::seal(&mut OsRng, &sealing_key, SEALING_FIELD_SELECT, &signing_key)?;
+    let report = snp::get_report(&keygen_report_data(nonce, &sealed_key.public_key))
+        .map_err(|e| format!("failed to get the attestation report: {}", e))?;
+    Ok(SevKeygenResponse { sealed_key, report })
+}
+
+fn unseal_key(sealed_key: &SevSealedKey) -> Result<SigningKey, Error> {
+    let sealing_key = snp::get_derived_key(sealed_key.field_select)
+        .map_err(|e| Error::io_error("failed to derive the sealing key".into(), e))?;
+    seal::unseal(&sealing_key, sealed_key).map_err(|e| {
+        error!("{}", e);
+        Error::invalid_key_error()
+    })
+}
+
+fn connect(config: &SevConfig, id_keypair: Option<&SigningKey>) -> io::Result<Box<dyn Connection>> {
+    let socket = state::host_connection(config.guest_tendermint_conn, &config.timeouts)?;
+    let connection: Box<dyn Connection> = match id_keypair {
+        Some(identity_key) => {
+            info!("KMS node ID: {}", PublicKey::from(identity_key));
+            Box::new(
+                connection::secret_connection(
+                    socket,
+                    identity_key,
+                    config.peer_id,
+                    config.require_peer_id,
+                    config.protocol_version,
+                )
+                .map_err(|e| {
+                    error!("secret connection failed: {}", e);
+                    io::Error::from(io::ErrorKind::Other)
+                })?,
+            )
+        }
+        None => Box::new(PlainConnection::new(socket)),
+    };
+    info!("connected to validator successfully");
+    Ok(connection)
+}
+
+/// keeps retrying with approx. 1 sec sleep until it manages to connect to tendermint privval endpoint
+fn get_connection(config: &SevConfig, id_keypair: Option<&SigningKey>) -> Box<dyn Connection> {
+    loop {
+        match connect(config, id_keypair) {
+            Ok(conn) => return conn,
+            Err(e) => {
+                error!("tendermint connection error {:?}", e);
+                thread::sleep(Duration::new(1, 0));
+            }
+        }
+    }
+}
+
+fn start(config: SevConfig) -> Result<(), Error> {
+    let keypair = unseal_key(&config.sealed_consensus_key)?;
+    let mut id_keypair = config.sealed_id_key.as_ref().map(unseal_key).transpose()?;
+    let mac_key = StateMacKey::new(&keypair, &config.chain_id);
+    let mut state_holder =
+        state::StateHolder::new(config.guest_state_port, &config.timeouts, mac_key)
+            .map_err(|e| Error::io_error("failed get state connection".into(), e))?;
+    let state = state_holder
+        .load_state()
+        .map_err(|e| io_error_wrap("failed to load initial state".into(), e))?;
+    let conn = get_connection(&config, id_keypair.as_ref());
+    let mut session = tmkms_light::session::Session::new(
+        ValidatorConfig {
+            chain_id: config.chain_id.clone(),
+            max_height: config.max_height,
+            signing_policy: config.signing_policy.clone(),
+        },
+        conn,
+        keypair,
+        state,
+        state_holder,
+    );
+    loop {
+        if let Err(e) = session.request_loop() {
+            if e.is_fatal() {
+                error!(
+                    "[{}] fatal request error, stopping the session: {}",
+                    &config.chain_id, e
+                );
+                break;
+            }
+            warn!("[{}] request error, reconnecting: {}", &config.chain_id, e);
+        }
+        session.reset_connection(get_connection(&config, id_keypair.as_ref()));
+    }
+    // the session zeroizes the consensus key when it's dropped
+    drop(session);
+    if let Some(id_keypair) = id_keypair.as_mut() {
+        id_keypair.zeroize();
+    }
+    Ok(())
+}
+
+/// a simple req-rep handling loop
+pub fn entry(mut stream: vsock::VsockStream) -> Result<(), Error> {
+    stream
+        .set_read_timeout(Some(REQUEST_READ_TIMEOUT))
+        .map_err(|e| Error::io_error("failed to set the request timeout".into(), e))?;
+    let json_raw = read_u16_payload(&mut stream)?;
+    match serde_json::from_slice(&json_raw) {
+        Ok(SevRequest::Keygen { nonce }) => {
+            let response: SevKeygenResult = keygen(&nonce);
+            if let Err(e) = &response {
+                error!("keygen failed: {}", e);
+            }
+            let json = serde_json::to_vec(&response)
+                .map_err(|e| io_error_wrap("failed to serialize the response".into(), e))?;
+            write_u16_payload(&mut stream, &json)
+                .map_err(|e| Error::io_error("failed to write the response".into(), e))?;
+        }
+        Ok(SevRequest::Start(config)) => start(*config)?,
+        Err(e) => {
+            error!("invalid request: {}", e);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_consensus::SigningKey;
+use rand_core::{CryptoRng, RngCore};
+use subtle::ConstantTimeEq;
+use tmkms_sev_helper::SevSealedKey;
+use zeroize::Zeroizing;
+
+/// seals the Ed25519 key with the derived key (and the public key as the associated data)
+pub fn seal<R: RngCore + CryptoRng>(
+    csprng: &mut R,
+    sealing_key: &[u8; 32],
+    field_select: u64,
+    signing_key: &SigningKey,
+) -> Result<SevSealedKey, String> {
+    let public_key = signing_key.verification_key().to_bytes();
+    let mut nonce = [0u8; 12];
+    csprng.fill_bytes(&mut nonce);
+    let ciphertext = ChaCha20Poly1305::new(Key::from_slice(sealing_key))
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: signing_key.as_bytes(),
+                aad: &public_key,
+            },
+        )
+        .map_err(|_| "sealing failed".to_owned())?;
+    Ok(SevSealedKey {
+        field_select,
+        public_key,
+        nonce,
+        ciphertext,
+    })
+}
+
+/// unseals the Ed25519 key with the derived key (and checks it's the sealed public key's)
+pub fn unseal(sealing_key: &[u8; 32], sealed: &SevSealedKey) -> Result<SigningKey, String> {
+    let secret = Zeroizing::new(
+        ChaCha20Poly1305::new(Key::from_slice(sealing_key))
+            .decrypt(
+                Nonce::from_slice(&sealed.nonce),
+                Payload {
+                    msg: &sealed.ciphertext,
+                    aad: &sealed.public_key,
+                },
+            )
+            .map_err(|_| "unsealing failed".to_owned())?,
+    );
+    let signing_key =
+        SigningKey::try_from(secret.as_slice()).map_err(|_| "invalid sealed key".to_owned())?;
+    if signing_key
+        .verification_key()
+        .as_bytes()
+        .ct_eq(&sealed.public_key)
+        .unwrap_u8()
+        == 0
+    {
+        return Err("the sealed key doesn't match its public key".to_owned());
+    }
+    Ok(signing_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_core::OsRng;
+
+    #[test]
+    fn seals_and_unseals() {
+        let signing_key = SigningKey::new(OsRng);
+        let sealed = seal(&mut OsRng, &[1u8; 32], 0b1001, &signing_key).unwrap();
+        assert_eq!(
+            unseal(&[1u8; 32], &sealed).unwrap().verification_key(),
+            signing_key.verification_key()
+        );
+        assert!(unseal(&[2u8; 32], &sealed).is_err());
+        let mut mangled = sealed;
+        mangled.public_key[0] ^= 1;
+        assert!(unseal(&[1u8; 32], &mangled).is_err());
+    }
+}
@@ -0,0 +1,111 @@
+//! requests to the AMD secure processor via the SEV guest driver (`/dev/sev-guest`)
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use tmkms_sev_helper::report::REPORT_LEN;
+use zeroize::{Zeroize, Zeroizing};
+
+const SEV_GUEST_DEVICE: &str = "/dev/sev-guest";
+/// version of the guest messages
+const MSG_VERSION: u8 = 1;
+/// where the report / key starts in the response message (after its status and reserved bytes)
+const RESPONSE_HEADER_LEN: usize = 0x20;
+
+/// `struct snp_guest_request_ioctl`
+#[repr(C)]
+pub struct GuestRequest {
+    msg_version: u8,
+    req_data: u64,
+    resp_data: u64,
+    exitinfo2: u64,
+}
+
+/// `struct snp_report_req`
+#[repr(C)]
+struct ReportRequest {
+    user_data: [u8; 64],
+    vmpl: u32,
+    rsvd: [u8; 28],
+}
+
+/// `struct snp_derived_key_req`
+#[repr(C)]
+struct DerivedKeyRequest {
+    root_key_select: u32,
+    rsvd: u32,
+    guest_field_select: u64,
+    vmpl: u32,
+    guest_svn: u32,
+    tcb_version: u64,
+}
+
+nix::ioctl_readwrite!(snp_get_report, b'S', 0x0, GuestRequest);
+nix::ioctl_readwrite!(snp_get_derived_key, b'S', 0x1, GuestRequest);
+
+fn open_device() -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(SEV_GUEST_DEVICE)
+}
+
+fn status(response: &[u8]) -> io::Result<()> {
+    let status = u32::from_le_bytes([response[0], response[1], response[2], response[3]]);
+    if status != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("SEV firmware status {:#x}", status),
+        ));
+    }
+    Ok(())
+}
+
+/// the attestation report (at VMPL 0) with the report data
+pub fn get_report(report_data: &[u8; 64]) -> io::Result<Vec<u8>> {
+    let device = open_device()?;
+    let mut request = ReportRequest {
+        user_data: *report_data,
+        vmpl: 0,
+        rsvd: [0u8; 28],
+    };
+    let mut response = vec![0u8; 4000];
+    let mut guest_request = GuestRequest {
+        msg_version: MSG_VERSION,
+        req_data: &mut request as *mut ReportRequest as u64,
+        resp_data: response.as_mut_ptr() as u64,
+        exitinfo2: 0,
+    };
+    unsafe { snp_get_report(device.as_raw_fd(), &mut guest_request) }.map_err(io::Error::from)?;
+    status(&response)?;
+    Ok(response[RESPONSE_HEADER_LEN..RESPONSE_HEADER_LEN + REPORT_LEN].to_vec())
+}
+
+/// the key derived from the VCEK and the selected guest fields
+pub fn get_derived_key(guest_field_select: u64) -> io::Result<Zeroizing<[u8; 32]>> {
+    let device = open_device()?;
+    let mut request = DerivedKeyRequest {
+        root_key_select: 0,
+        rsvd: 0,
+        guest_field_select,
+        vmpl: 0,
+        guest_svn: 0,
+        tcb_version: 0,
+    };
+    let mut response = [0u8; 64];
+    let mut guest_request = GuestRequest {
+        msg_version: MSG_VERSION,
+        req_data: &mut request as *mut DerivedKeyRequest as u64,
+        resp_data: response.as_mut_ptr() as u64,
+        exitinfo2: 0,
+    };
+    let result = unsafe { snp_get_derived_key(device.as_raw_fd(), &mut guest_request) }
+        .map_err(io::Error::from)
+        .and_then(|_| status(&response));
+    let mut key = Zeroizing::new([0u8; 32]);
+    if result.is_ok() {
+        key.copy_from_slice(&response[RESPONSE_HEADER_LEN..RESPONSE_HEADER_LEN + 32]);
+    }
+    response.zeroize();
+    result.map(|_| key)
+}
@@ -0,0 +1,169 @@
+use rand_core::{OsRng, RngCore};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use tendermint_config::net;
+use tmkms_light::utils::{print_pubkey, read_u16_payload, write_u16_payload, PubkeyDisplay};
+use tmkms_light::vsock::proxy::launch_proxy;
+use tmkms_light::vsock::state::StateSyncer;
+use tmkms_sev_helper::report::{keygen_report_data, AttestationReport};
+use tmkms_sev_helper::{
+    SevConfig, SevKeygenResult, SevRequest, SevSealedKey, SEALING_FIELD_SELECT,
+};
+use tracing::warn;
+use vsock::{VsockAddr, VsockStream};
+
+use crate::config::SevSignOpt;
+
+fn load_config(config_path: Option<PathBuf>) -> Result<SevSignOpt, String> {
+    let cp = config_path.unwrap_or_else(|| "tmkms.toml".into());
+    let toml_string =
+        fs::read_to_string(cp).map_err(|e| format!("toml config file failed to read: {:?}", e))?;
+    toml::from_str(&toml_string).map_err(|e| format!("toml config file failed to parse: {:?}", e))
+}
+
+fn send_request(config: &SevSignOpt, request: &SevRequest) -> Result<VsockStream, String> {
+    let addr = VsockAddr::new(config.guest_cid, config.guest_port);
+    let mut socket = VsockStream::connect(&addr)
+        .map_err(|e| format!("failed to connect to the guest: {:?}", e))?;
+    let request_raw = serde_json::to_vec(request)
+        .map_err(|e| format!("failed to serialize the request: {:?}", e))?;
+    write_u16_payload(&mut socket, &request_raw)
+        .map_err(|e| format!("failed to write the request: {:?}", e))?;
+    Ok(socket)
+}
+
+/// generates a key in the guest, checks its attestation report and writes it (sealed) to `path`
+fn generate_key(config: &SevSignOpt, path: &Path) -> Result<SevSealedKey, String> {
+    let mut nonce = vec![0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    let mut socket = send_request(
+        config,
+        &SevRequest::Keygen {
+            nonce: nonce.clone(),
+        },
+    )?;
+    let json_raw = read_u16_payload(&mut socket)
+        .map_err(|e| format!("failed to read the keygen response: {:?}", e))?;
+    let response: SevKeygenResult = serde_json::from_slice(&json_raw)
+        .map_err(|e| format!("failed to parse the keygen response: {:?}", e))?;
+    let response = response?;
+    let report = AttestationReport::parse(&response.report)?;
+    let sealed_key = response.sealed_key;
+    if report.report_data != keygen_report_data(&nonce, &sealed_key.public_key) {
+        return Err("the attestation report doesn't bind the generated key".to_owned());
+    }
+    if sealed_key.field_select != SEALING_FIELD_SELECT {
+        return Err("the key isn't sealed to the guest measurement".to_owned());
+    }
+    config.report_policy().verify(&report)?;
+    let json = serde_json::to_vec(&sealed_key)
+        .map_err(|e| format!("failed to serialize the sealed key: {:?}", e))?;
+    OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(&json))
+        .map_err(|e| format!("couldn't write `{}`: {:?}", path.display(), e))?;
+    println!(
+        "guest measurement: {} (SVN {})",
+        report.measurement_hex(),
+        report.guest_svn
+    );
+    Ok(sealed_key)
+}
+
+/// write tmkms.toml + generate keys (sealed in the guest)
+pub fn init(
+    config_path: Option<PathBuf>,
+    pubkey_display: Option<PubkeyDisplay>,
+    bech32_prefix: Option<String>,
+    guest_cid: Option<u32>,
+    expected_measurement: Option<String>,
+) -> Result<(), String> {
+    let cp = config_path.unwrap_or_else(|| "tmkms.toml".into());
+    let mut config = SevSignOpt {
+        expected_measurement,
+        ..Default::default()
+    };
+    if let Some(cid) = guest_cid {
+        config.guest_cid = cid;
+    }
+    if config.expected_measurement.is_none() {
+        warn!("no expected measurement, the guest isn't checked");
+    }
+    let t =
+        toml::to_string_pretty(&config).map_err(|e| format!("config to toml failed: {:?}", e))?;
+    fs::write(cp, t).map_err(|e| format!("failed to write a config: {:?}", e))?;
+    for path in std::iter::once(&config.sealed_consensus_key_path)
+        .chain(config.sealed_id_key_path.iter())
+        .chain(std::iter::once(&config.state_file_path))
+    {
+        fs::create_dir_all(
+            path.parent()
+                .ok_or_else(|| "cannot create a dir in a root directory".to_owned())?,
+        )
+        .map_err(|e| format!("failed to create dirs: {:?}", e))?;
+    }
+    let sealed_key = generate_key(&config, &config.sealed_consensus_key_path)?;
+    let public_key = ed25519_consensus::VerificationKey::try_from(sealed_key.public_key)
+        .map_err(|e| format!("invalid public key: {:?}", e))?;
+    print_pubkey(bech32_prefix, pubkey_display, public_key);
+    if let Some(id_path) = &config.sealed_id_key_path {
+        generate_key(&config, id_path)?;
+    }
+    Ok(())
+}
+
+fn read_sealed_key(path: &Path) -> Result<SevSealedKey, String> {
+    serde_json::from_slice(
+        &fs::read(path).map_err(|e| format!("failed to read `{}`: {:?}", path.display(), e))?,
+    )
+    .map_err(|e| format!("failed to parse `{}`: {:?}", path.display(), e))
+}
+
+/// launches the state syncer and the validator proxy, and pushes the config to the guest
+pub fn start(config_path: Option<PathBuf>) -> Result<(), String> {
+    let config = load_config(config_path)?;
+    // the secret connection (with the identity key) is only used with `tcp://` validators
+    let (peer_id, sealed_id_key) = match &config.address {
+        net::Address::Tcp { peer_id, .. } => {
+            if peer_id.is_none() && config.require_peer_id {
+                return Err(
+                    "`require_peer_id` is set, but the validator address has no peer ID".to_owned(),
+                );
+            }
+            let id_path = config
+                .sealed_id_key_path
+                .as_deref()
+                .ok_or_else(|| "`tcp://` validators need `sealed_id_key_path`".to_owned())?;
+            (*peer_id, Some(read_sealed_key(id_path)?))
+        }
+        net::Address::Unix { .. } => (None, None),
+    };
+    let state_syncer = StateSyncer::new(&config.state_file_path, config.guest_state_port)
+        .map_err(|e| format!("state persistence error: {:?}", e))?;
+    launch_proxy(config.guest_tendermint_conn, config.address.clone())?;
+    let handle = state_syncer.launch_syncer();
+    send_request(
+        &config,
+        &SevRequest::Start(Box::new(SevConfig {
+            chain_id: config.chain_id.clone(),
+            max_height: config.max_height,
+            signing_policy: config.signing_policy.clone(),
+            sealed_consensus_key: read_sealed_key(&config.sealed_consensus_key_path)?,
+            sealed_id_key,
+            peer_id,
+            require_peer_id: config.require_peer_id,
+            protocol_version: config.protocol_version,
+            timeouts: config.timeouts,
+            guest_state_port: config.guest_state_port,
+            guest_tendermint_conn: config.guest_tendermint_conn,
+        })),
+    )?;
+    handle
+        .join()
+        .map_err(|_| "state persistence failed".to_owned())
+}
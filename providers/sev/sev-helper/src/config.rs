@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+use std::{convert::TryFrom, path::PathBuf};
+use tendermint::chain;
+use tendermint_config::net;
+use tmkms_light::connection::{ConnectionTimeouts, ProtocolVersion};
+use tmkms_light::policy::SigningPolicy;
+use tmkms_sev_helper::report::ReportPolicy;
+
+/// helper configuration in toml
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SevSignOpt {
+    /// Address of the validator (`tcp://` or `unix://`)
+    pub address: net::Address,
+    /// Chain ID of the Tendermint network this validator is part of
+    pub chain_id: chain::Id,
+    /// Height at which to stop signing
+    pub max_height: Option<tendermint::block::Height>,
+    /// Path to the sealed consensus key
+    pub sealed_consensus_key_path: PathBuf,
+    /// Path to the sealed Ed25519 identity key (if applicable)
+    pub sealed_id_key_path: Option<PathBuf>,
+    /// Path to chain-specific `priv_validator_state.json` file
+    pub state_file_path: PathBuf,
+    /// Vsock CID of the SEV-SNP guest
+    pub guest_cid: u32,
+    /// Vsock port the guest listens on for requests
+    pub guest_port: u32,
+    /// Vsock port for state synchronization
+    pub guest_state_port: u32,
+    /// Vsock port to forward privval traffic to TM over UDS or TCP
+    pub guest_tendermint_conn: u32,
+    /// Read/write timeouts of the validator and state connections
+    #[serde(default)]
+    pub timeouts: ConnectionTimeouts,
+    /// Secret connection protocol version (`v0.34`, `v0.33` or `legacy`)
+    #[serde(default)]
+    pub protocol_version: ProtocolVersion,
+    /// Refuse the validator connection if its peer ID can't be verified
+    /// (i.e. `peer_id` is required in `tcp://` addresses)
+    #[serde(default)]
+    pub require_peer_id: bool,
+    /// Rules that signing requests must comply with
+    #[serde(default)]
+    pub signing_policy: SigningPolicy,
+    /// Expected (hex-encoded) launch measurement of the guest
+    pub expected_measurement: Option<String>,
+    /// Accept a guest whose policy allows debugging
+    #[serde(default)]
+    pub allow_debug: bool,
+    /// Minimum guest SVN
+    #[serde(default)]
+    pub min_guest_svn: u32,
+}
+
+impl SevSignOpt {
+    /// what the guest's attestation reports need to match
+    pub fn report_policy(&self) -> ReportPolicy {
+        ReportPolicy {
+            expected_measurement: self.expected_measurement.clone(),
+            allow_debug: self.allow_debug,
+            min_guest_svn: self.min_guest_svn,
+        }
+    }
+}
+
+impl Default for SevSignOpt {
+    fn default() -> Self {
+        Self {
+            address: net::Address::Unix {
+                path: "/tmp/validator.socket".into(),
+            },
+            chain_id: chain::Id::try_from("testchain-1".to_owned()).expect("valid chain-id"),
+            max_height: None,
+            sealed_consensus_key_path: "secrets/secret.key".into(),
+            sealed_id_key_path: Some("secrets/id.key".into()),
+            state_file_path: "state/priv_validator_state.json".into(),
+            guest_cid: 3,
+            guest_port: 5050,
+            guest_state_port: 5555,
+            guest_tendermint_conn: 5000,
+            timeouts: ConnectionTimeouts::default(),
+            protocol_version: ProtocolVersion::V0_34,
+            require_peer_id: false,
+            signing_policy: SigningPolicy::default(),
+            expected_measurement: None,
+            allow_debug: false,
+            min_guest_svn: 0,
+        }
+    }
+}
@@ -0,0 +1,4 @@
+pub use shared::*;
+
+pub mod report;
+pub mod shared;
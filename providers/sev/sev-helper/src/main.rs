@@ -0,0 +1,83 @@
+mod command;
+mod config;
+
+use clap::Parser;
+use std::path::PathBuf;
+use tmkms_light::utils::PubkeyDisplay;
+use tracing::{error, Level};
+use tracing_subscriber::FmtSubscriber;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "tmkms-sev-helper",
+    about = "helper (host-side) of the signer running in an AMD SEV-SNP guest"
+)]
+enum TmkmsLight {
+    #[command(name = "init", about = "Create config + keygen")]
+    /// Create config + keygen
+    Init {
+        #[arg(short)]
+        config_path: Option<PathBuf>,
+        #[arg(short)]
+        pubkey_display: Option<PubkeyDisplay>,
+        #[arg(short)]
+        bech32_prefix: Option<String>,
+        /// vsock CID of the guest
+        #[arg(long)]
+        cid: Option<u32>,
+        /// expected (hex-encoded) launch measurement of the guest
+        #[arg(short = 'm', long)]
+        expected_measurement: Option<String>,
+        #[arg(short, action = clap::ArgAction::Count)]
+        v: u32,
+    },
+    #[command(name = "start", about = "start tmkms process")]
+    /// start tmkms process
+    Start {
+        #[arg(short)]
+        config_path: Option<PathBuf>,
+        #[arg(short, action = clap::ArgAction::Count)]
+        v: u32,
+    },
+}
+
+fn set_log(v: u32) {
+    let log_level = match v {
+        0 | 1 => Level::INFO,
+        2 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
+    let subscriber = FmtSubscriber::builder().with_max_level(log_level).finish();
+    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+}
+
+fn main() {
+    let opt = TmkmsLight::parse();
+    let result = match opt {
+        TmkmsLight::Init {
+            config_path,
+            pubkey_display,
+            bech32_prefix,
+            cid,
+            expected_measurement,
+            v,
+        } => {
+            set_log(v);
+            command::init(
+                config_path,
+                pubkey_display,
+                bech32_prefix,
+                cid,
+                expected_measurement,
+            )
+        }
+        TmkmsLight::Start { config_path, v } => {
+            set_log(v);
+            command::start(config_path)
+        }
+    };
+    if let Err(e) = result {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+}
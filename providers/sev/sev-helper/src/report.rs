@@ -0,0 +1,138 @@
+//! SEV-SNP attestation reports (`ATTESTATION_REPORT` in the SNP firmware ABI).
+//! NOTE: this checks the report's fields, but not its signature by the chip's VCEK
+//! (with the VCEK certificate chain from AMD KDS, e.g. `snpguest verify attestation`).
+
+use sha2::{Digest, Sha512};
+use std::convert::TryInto;
+use subtle_encoding::hex;
+
+/// length of the attestation report (including its signature)
+pub const REPORT_LEN: usize = 0x4A0;
+/// the guest can be debugged by the hypervisor
+const POLICY_DEBUG: u64 = 1 << 19;
+/// domain separation of the report data of generated keys
+const KEYGEN_REPORT_DOMAIN: &[u8] = b"tmkms-light sev keygen";
+
+/// the fields of the attestation report that are checked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttestationReport {
+    pub version: u32,
+    pub guest_svn: u32,
+    pub policy: u64,
+    pub vmpl: u32,
+    pub report_data: [u8; 64],
+    pub measurement: [u8; 48],
+    pub chip_id: [u8; 64],
+}
+
+impl AttestationReport {
+    /// parses the raw report
+    pub fn parse(raw: &[u8]) -> Result<Self, String> {
+        if raw.len() != REPORT_LEN {
+            return Err(format!("invalid report length: {}", raw.len()));
+        }
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(raw[offset..offset + 4].try_into().unwrap());
+        Ok(Self {
+            version: u32_at(0x0),
+            guest_svn: u32_at(0x4),
+            policy: u64::from_le_bytes(raw[0x8..0x10].try_into().unwrap()),
+            vmpl: u32_at(0x30),
+            report_data: raw[0x50..0x90].try_into().unwrap(),
+            measurement: raw[0x90..0xC0].try_into().unwrap(),
+            chip_id: raw[0x1A0..0x1E0].try_into().unwrap(),
+        })
+    }
+
+    /// the guest policy allows debugging
+    pub fn debug(&self) -> bool {
+        self.policy & POLICY_DEBUG != 0
+    }
+
+    /// hex-encoded launch measurement
+    pub fn measurement_hex(&self) -> String {
+        String::from_utf8(hex::encode(self.measurement)).expect("hex is ASCII")
+    }
+}
+
+/// the report data of a generated key: SHA-512 of the domain, the request nonce and the public key
+pub fn keygen_report_data(nonce: &[u8], public_key: &[u8; 32]) -> [u8; 64] {
+    let mut hasher = Sha512::new();
+    hasher.update(KEYGEN_REPORT_DOMAIN);
+    hasher.update(nonce);
+    hasher.update(public_key);
+    hasher.finalize().into()
+}
+
+/// what the attestation report of the guest needs to match
+#[derive(Debug, Clone, Default)]
+pub struct ReportPolicy {
+    /// hex-encoded launch measurement
+    pub expected_measurement: Option<String>,
+    /// accept a guest that can be debugged
+    pub allow_debug: bool,
+    /// minimum guest SVN
+    pub min_guest_svn: u32,
+}
+
+impl ReportPolicy {
+    /// checks the report matches the policy
+    pub fn verify(&self, report: &AttestationReport) -> Result<(), String> {
+        if let Some(expected) = &self.expected_measurement {
+            if !report.measurement_hex().eq_ignore_ascii_case(expected) {
+                return Err(format!(
+                    "launch measurement {} doesn't match the expected one",
+                    report.measurement_hex()
+                ));
+            }
+        }
+        if report.debug() && !self.allow_debug {
+            return Err("the guest policy allows debugging".to_owned());
+        }
+        if report.guest_svn < self.min_guest_svn {
+            return Err(format!(
+                "guest SVN {} is below the minimum {}",
+                report.guest_svn, self.min_guest_svn
+            ));
+        }
+        if report.vmpl != 0 {
+            return Err(format!("the report is from VMPL {}", report.vmpl));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_report_against_policy() {
+        let mut raw = vec![0u8; REPORT_LEN];
+        raw[0] = 2;
+        raw[0x4] = 3;
+        raw[0x8..0x10].copy_from_slice(&0x30000u64.to_le_bytes());
+        let report_data = keygen_report_data(b"nonce", &[1u8; 32]);
+        raw[0x50..0x90].copy_from_slice(&report_data);
+        raw[0x90..0xC0].copy_from_slice(&[0xabu8; 48]);
+        let report = AttestationReport::parse(&raw).unwrap();
+        assert_eq!(report.report_data, report_data);
+        assert_eq!(report.guest_svn, 3);
+        assert!(!report.debug());
+        let policy = ReportPolicy {
+            expected_measurement: Some("AB".repeat(48)),
+            min_guest_svn: 3,
+            ..Default::default()
+        };
+        assert!(policy.verify(&report).is_ok());
+        let policy = ReportPolicy {
+            min_guest_svn: 4,
+            ..policy
+        };
+        assert!(policy.verify(&report).is_err());
+        raw[0x8..0x10].copy_from_slice(&(0x30000u64 | POLICY_DEBUG).to_le_bytes());
+        let report = AttestationReport::parse(&raw).unwrap();
+        assert!(ReportPolicy::default().verify(&report).is_err());
+        assert!(AttestationReport::parse(&raw[1..]).is_err());
+    }
+}
@@ -0,0 +1,70 @@
+use serde::{Deserialize, Serialize};
+use tendermint::{chain, node};
+use tmkms_light::connection::{ConnectionTimeouts, ProtocolVersion};
+use tmkms_light::policy::SigningPolicy;
+
+pub use tmkms_light::vsock::VSOCK_HOST_CID;
+
+/// the guest fields the sealing key is derived from (`GUEST_FIELD_SELECT` of the key request):
+/// the guest policy (bit 0) and the launch measurement (bit 3)
+pub const SEALING_FIELD_SELECT: u64 = 0b1001;
+
+/// Ed25519 key sealed (with ChaCha20-Poly1305) by the key derived in the guest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SevSealedKey {
+    /// `GUEST_FIELD_SELECT` of the derived sealing key
+    pub field_select: u64,
+    /// Ed25519 public key (authenticated as the associated data)
+    pub public_key: [u8; 32],
+    pub nonce: [u8; 12],
+    pub ciphertext: Vec<u8>,
+}
+
+/// SEV-SNP config to be pushed to the guest
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SevConfig {
+    /// Chain ID of the Tendermint network this validator is part of
+    pub chain_id: chain::Id,
+    /// Height at which to stop signing
+    pub max_height: Option<tendermint::block::Height>,
+    /// Rules that signing requests must comply with
+    pub signing_policy: SigningPolicy,
+    /// sealed consensus key
+    pub sealed_consensus_key: SevSealedKey,
+    /// sealed Ed25519 identity key (if secret connection)
+    pub sealed_id_key: Option<SevSealedKey>,
+    /// peer id to check with secret connections
+    pub peer_id: Option<node::Id>,
+    /// refuse secret connections whose peer id can't be checked
+    pub require_peer_id: bool,
+    /// secret connection protocol version
+    pub protocol_version: ProtocolVersion,
+    /// read/write timeouts of the validator and state connections
+    pub timeouts: ConnectionTimeouts,
+    /// Vsock port on the host for state synchronization
+    pub guest_state_port: u32,
+    /// Vsock port on the host to forward privval traffic to TM over UDS or TCP
+    pub guest_tendermint_conn: u32,
+}
+
+/// request sent to the guest
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SevRequest {
+    /// generate and seal a new key (its attestation report binds the nonce)
+    Keygen { nonce: Vec<u8> },
+    /// start signing
+    Start(Box<SevConfig>),
+}
+
+/// response to the key generation
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SevKeygenResponse {
+    /// the generated key sealed in the guest
+    pub sealed_key: SevSealedKey,
+    /// SEV-SNP attestation report with the public key and the nonce in its report data
+    pub report: Vec<u8>,
+}
+
+pub type SevKeygenResult = Result<SevKeygenResponse, String>;
@@ -10,7 +10,7 @@ use rand::rngs::OsRng;
 use sgx_isa::{Report, Targetinfo};
 use std::{io, net::TcpStream, thread, time::Duration};
 use subtle::ConstantTimeEq;
-use tendermint_p2p::secret_connection::{self, PublicKey, SecretConnection};
+use tendermint_p2p::secret_connection::{PublicKey, SecretConnection};
 use tmkms_light::{
     connection::{Connection, PlainConnection},
     utils::write_u16_payload,
@@ -26,18 +26,18 @@ fn get_secret_connection(config: &RemoteConnectionConfig) -> io::Result<Box<dyn
         host,
         port,
         sealed_key,
+        protocol_version,
     } = config;
     let socket = TcpStream::connect(format!("{}:{}", host, port))?;
     // TODO: just unseal once in the caller
     if let Ok(identity_key) = keypair_seal::unseal(sealed_key) {
         info!("KMS node ID: {}", PublicKey::from(&identity_key));
 
-        let connection =
-            SecretConnection::new(socket, identity_key, secret_connection::Version::V0_34)
-                .map_err(|e| {
-                    error!("secret connection failed: {}", e);
-                    io::Error::from(io::ErrorKind::Other)
-                })?;
+        let connection = SecretConnection::new(socket, identity_key, (*protocol_version).into())
+            .map_err(|e| {
+                error!("secret connection failed: {}", e);
+                io::Error::from(io::ErrorKind::Other)
+            })?;
         let actual_peer_id = connection.remote_pubkey().peer_id();
 
         // TODO: https://github.com/informalsystems/tendermint-rs/issues/786
@@ -163,6 +163,29 @@ pub fn entry(mut host_response: TcpStream, request: SgxInitRequest) -> io::Resul
                 error!("recovery failed");
             }
         }
+        SgxInitRequest::Reseal { sealed_keys } => {
+            let resealed: Result<Vec<_>, _> = sealed_keys
+                .iter()
+                .map(|sealed_key| keypair_seal::reseal(&mut csprng, sealed_key))
+                .collect();
+            match resealed {
+                Ok(sealed_keys) => {
+                    let response = SgxInitResponse::Resealed { sealed_keys };
+                    match serde_json::to_vec(&response) {
+                        Ok(v) => {
+                            debug!("writing response");
+                            write_u16_payload(&mut host_response, &v)?;
+                        }
+                        Err(e) => {
+                            error!("resealing error: {}", e);
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("resealing failed: {:?}", e);
+                }
+            }
+        }
         SgxInitRequest::Start {
             sealed_key,
             config,
@@ -181,6 +204,10 @@ pub fn entry(mut host_response: TcpStream, request: SgxInitRequest) -> io::Resul
                 );
                 loop {
                     if let Err(e) = session.request_loop() {
+                        if e.is_fatal() {
+                            error!("fatal request error: {}", e);
+                            return Err(io::ErrorKind::Other.into());
+                        }
                         error!("request error: {}", e);
                     }
                     let conn: Box<dyn Connection> = get_connection(secret_connection.as_ref());
@@ -279,4 +306,21 @@ mod tests {
             kp.verification_key()
         );
     }
+
+    #[test]
+    fn test_reseal() {
+        let mut csprng = OsRng {};
+        let kp = SigningKey::new(csprng);
+        let sealed_data = keypair_seal::seal(&mut csprng, &kp).unwrap();
+        let resealed = keypair_seal::reseal(&mut csprng, &sealed_data).unwrap();
+        assert_eq!(
+            resealed.seal_key_request.keyid,
+            sealed_data.seal_key_request.keyid
+        );
+        assert_ne!(resealed.nonce, sealed_data.nonce);
+        assert_eq!(
+            keypair_seal::unseal(&resealed).unwrap().verification_key(),
+            kp.verification_key()
+        );
+    }
 }
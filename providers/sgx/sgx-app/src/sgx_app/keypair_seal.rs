@@ -92,6 +92,16 @@ pub fn unseal_secret(sealed_data: &SealedKeyData) -> Result<Vec<u8>, ErrorCode>
     }
 }
 
+/// Unseals the secret with its original key request (which EGETKEY still accepts
+/// if its CPU SVN and ISV SVN aren't above the current ones) and seals it
+/// for the current CPU SVN and ISV SVN
+pub fn reseal(csprng: &mut OsRng, sealed_data: &SealedKeyData) -> Result<SealedKeyData, ErrorCode> {
+    let mut secret = unseal_secret(sealed_data)?;
+    let resealed = seal_secret(csprng, &secret, sealed_data.seal_key_request.keyid);
+    secret.zeroize();
+    resealed
+}
+
 /// Checks the provided keyrequests
 /// and attempts to unseal the ed25519 keypair with `Aes128GcmSiv`
 pub fn unseal(sealed_data: &SealedKeyData) -> Result<SigningKey, ErrorCode> {
@@ -1,8 +1,8 @@
-use crate::config::RecoverConfig;
+use crate::config::{QuotePolicy, RecoverConfig};
+use crate::dcap::{print_enclave, verify_attestation, QuoteAttestation};
 use crate::shared::{CloudBackupKey, CloudBackupSeal, SealedKeyData};
 use crate::{config, runner::TmkmsSgxSigner};
 use crate::{shared::get_claim, shared::SgxInitResponse, SgxInitRequest};
-use base64::{engine::general_purpose, Engine as _};
 
 use rsa::pkcs1::{EncodeRsaPublicKey, LineEnding};
 use std::fs;
@@ -12,16 +12,17 @@ use tmkms_light::{
     config::validator::ValidatorConfig,
     utils::{print_pubkey, PubkeyDisplay},
 };
-use tracing::debug;
+use tracing::{debug, warn};
 
 /// generate a key wrap for cloud backups
+/// (with DCAP, its quote needs to pass the policy before the wrapping key is written)
 pub fn keywrap(
     enclave_path: PathBuf,
     sealed_output_path: PathBuf,
-    dcap: bool,
+    dcap: Option<(&QuotePolicy, PathBuf)>,
     log_level: String,
 ) -> Result<(), String> {
-    let targetinfo = if dcap {
+    let targetinfo = if dcap.is_some() {
         if dcap_ql::is_loaded() {
             let ti = dcap_ql::target_info().map_err(|e| format!("dcap target info: {:?}", e))?;
             Some(ti)
@@ -55,25 +56,29 @@ pub fn keywrap(
             wrap_pub_key,
             pub_key_report,
         } => {
-            let quote = if dcap {
+            let attestation = if let Some((policy, attestation_path)) = dcap {
                 let q =
                     dcap_ql::quote(&pub_key_report).map_err(|e| format!("dcap quote: {:?}", e))?;
-                Some(q)
+                let attestation = QuoteAttestation::new(&q, &get_claim(&wrap_pub_key));
+                if policy.is_empty() {
+                    warn!("no expected MRENCLAVE or MRSIGNER, the enclave app isn't checked");
+                }
+                let enclave = verify_attestation(&attestation, policy)
+                    .map_err(|e| format!("quote verification failed: {}", e))?;
+                print_enclave(&enclave);
+                Some((attestation, attestation_path))
             } else {
                 None
             };
 
             config::write_sealed_file(sealed_output_path, &wrap_key_sealed)
                 .map_err(|e| format!("failed to write wrapping key: {:?}", e))?;
-            if let Some(quote) = quote {
-                let claim_payload = get_claim(&wrap_pub_key);
-                let encoded_claim = general_purpose::URL_SAFE.encode(claim_payload);
-                let encoded_quote = general_purpose::URL_SAFE.encode(quote);
-                print!("{{\"quote\": \"{}\",", encoded_quote);
-                println!(
-                    "\"runtimeData\": {{ \"data\": \"{}\", \"dataType\": \"Binary\" }}}}",
-                    encoded_claim
-                );
+            if let Some((attestation, attestation_path)) = attestation {
+                let json = serde_json::to_string(&attestation)
+                    .map_err(|e| format!("failed to serialize the quote: {:?}", e))?;
+                fs::write(attestation_path, &json)
+                    .map_err(|e| format!("failed to write the quote: {:?}", e))?;
+                println!("{}", json);
             } else {
                 let pkcs1 = wrap_pub_key
                     .to_pkcs1_pem(LineEnding::default())
@@ -215,6 +220,13 @@ pub fn start(config_path: Option<PathBuf>, log_level: String) -> Result<(), Stri
             .map_err(|e| format!("toml config file failed to read: {:?}", e))?;
         let config: config::SgxSignOpt = toml::from_str(&toml_string)
             .map_err(|e| format!("toml config file failed to parse: {:?}", e))?;
+        if let net::Address::Tcp { peer_id: None, .. } = config.address {
+            if config.require_peer_id {
+                return Err(
+                    "`require_peer_id` is set, but the validator address has no peer ID".to_owned(),
+                );
+            }
+        }
         let tm_conn = match &config.address {
             net::Address::Unix { path } => {
                 debug!(
@@ -227,7 +239,7 @@ pub fn start(config_path: Option<PathBuf>, log_level: String) -> Result<(), Stri
             _ => None,
         };
         let remote = if let (None, Some(path)) = (&tm_conn, config.sealed_id_key_path) {
-            Some((config.address, path))
+            Some((config.address, path, config.protocol_version))
         } else {
             None
         };
@@ -239,6 +251,7 @@ pub fn start(config_path: Option<PathBuf>, log_level: String) -> Result<(), Stri
             ValidatorConfig {
                 chain_id: config.chain_id,
                 max_height: config.max_height,
+                signing_policy: config.signing_policy,
             },
             state,
             remote,
@@ -360,3 +373,106 @@ pub fn recover(recover_config: RecoverConfig, log_level: String) -> Result<(), S
         Ok(())
     }
 }
+
+/// verify the quote (written by `cloud-wrap -d`) against the policy
+pub fn attestation_verify(attestation_path: PathBuf, policy: &QuotePolicy) -> Result<(), String> {
+    let attestation: QuoteAttestation = serde_json::from_slice(
+        &fs::read(attestation_path).map_err(|e| format!("failed to read the quote: {:?}", e))?,
+    )
+    .map_err(|e| format!("failed to parse the quote: {:?}", e))?;
+    if policy.is_empty() {
+        warn!("no expected MRENCLAVE or MRSIGNER, the enclave app isn't checked");
+    }
+    let enclave = verify_attestation(&attestation, policy)?;
+    print_enclave(&enclave);
+    let (_, claim) = attestation.decode()?;
+    println!("attested claim: {}", String::from_utf8_lossy(&claim));
+    Ok(())
+}
+
+/// reseal the consensus key, the id key (if any) and the cloud backup wrapping key (if given)
+/// for the current CPU SVN and enclave ISV SVN, e.g. after a microcode/TCB update
+/// (the previously sealed files are kept with the `.old` suffix)
+pub fn reseal(
+    config_path: Option<PathBuf>,
+    wrap_key_path: Option<PathBuf>,
+    log_level: String,
+) -> Result<(), String> {
+    let cp = config_path.unwrap_or_else(|| "tmkms.toml".into());
+    let toml_string =
+        fs::read_to_string(cp).map_err(|e| format!("toml config file failed to read: {:?}", e))?;
+    let config: config::SgxSignOpt = toml::from_str(&toml_string)
+        .map_err(|e| format!("toml config file failed to parse: {:?}", e))?;
+    let mut paths = vec![config.sealed_consensus_key_path.clone()];
+    paths.extend(
+        config
+            .sealed_id_key_path
+            .clone()
+            .filter(|path| path.exists()),
+    );
+    paths.extend(wrap_key_path);
+    let sealed_keys = paths
+        .iter()
+        .map(|path| {
+            serde_json::from_slice(
+                &fs::read(path)
+                    .map_err(|e| format!("failed to read `{}`: {:?}", path.display(), e))?,
+            )
+            .map_err(|e| format!("failed to parse `{}`: {:?}", path.display(), e))
+        })
+        .collect::<Result<Vec<SealedKeyData>, String>>()?;
+    let keyids: Vec<_> = sealed_keys
+        .iter()
+        .map(|sealed_key| sealed_key.seal_key_request.keyid)
+        .collect();
+    let request = SgxInitRequest::Reseal { sealed_keys };
+    let request_bytes = serde_json::to_vec(&request)
+        .map_err(|e| format!("failed to convert request to json: {:?}", e))?;
+    debug!("launching enclave");
+    let (state_syncer, _, state_stream) = TmkmsSgxSigner::get_state_syncer(&config.state_file_path)
+        .map_err(|e| format!("state persistence error: {:?}", e))?;
+    let enclave_args: Vec<&[u8]> = vec![request_bytes.as_ref(), log_level.as_bytes()];
+    let runner = TmkmsSgxSigner::launch_enclave_app(
+        &config.enclave_path,
+        None,
+        state_syncer,
+        state_stream,
+        &enclave_args,
+    )
+    .map_err(|e| format!("failed to launch the enclave app: {:?}", e))?;
+    debug!("waiting for reseal");
+    let resealed = match runner.get_init_response().map_err(|e| {
+        format!(
+            "failed to reseal keys (if the platform changed, use the cloud backup): {:?}",
+            e
+        )
+    })? {
+        SgxInitResponse::Resealed { sealed_keys } => sealed_keys,
+        _ => return Err("unexpected enclave response".to_owned()),
+    };
+    if resealed.len() != paths.len()
+        || resealed
+            .iter()
+            .zip(keyids.iter())
+            .any(|(sealed_key, keyid)| &sealed_key.seal_key_request.keyid != keyid)
+    {
+        return Err("resealed keys don't match the sealed ones".to_owned());
+    }
+    for (path, sealed_key) in paths.iter().zip(resealed.iter()) {
+        let mut old_path = path.clone().into_os_string();
+        old_path.push(".old");
+        fs::copy(path, &old_path)
+            .map_err(|e| format!("failed to keep `{}`: {:?}", path.display(), e))?;
+        config::write_sealed_file(path, sealed_key)
+            .map_err(|e| format!("failed to write `{}`: {:?}", path.display(), e))?;
+        println!(
+            "resealed `{}` (CPU SVN {}, ISV SVN {})",
+            path.display(),
+            String::from_utf8_lossy(&subtle_encoding::hex::encode(
+                sealed_key.seal_key_request.cpusvn
+            )),
+            sealed_key.seal_key_request.isvsvn
+        );
+    }
+    Ok(())
+}
@@ -6,6 +6,8 @@ use std::{convert::TryFrom, path::PathBuf};
 use std::{fs::OpenOptions, io, os::unix::fs::OpenOptionsExt, path::Path};
 use tendermint::chain;
 use tendermint_config::net;
+use tmkms_light::connection::ProtocolVersion;
+use tmkms_light::policy::SigningPolicy;
 use tmkms_light::utils::PubkeyDisplay;
 use tracing::error;
 
@@ -27,6 +29,16 @@ pub struct SgxSignOpt {
     pub state_file_path: PathBuf,
     /// Path to sgxs + signature files
     pub enclave_path: PathBuf,
+    /// Rules that signing requests must comply with
+    #[serde(default)]
+    pub signing_policy: SigningPolicy,
+    /// Secret connection protocol version (`v0.34`, `v0.33` or `legacy`)
+    #[serde(default)]
+    pub protocol_version: ProtocolVersion,
+    /// Refuse the validator connection if its peer ID can't be verified
+    /// (i.e. `peer_id` is required in `tcp://` addresses)
+    #[serde(default)]
+    pub require_peer_id: bool,
 }
 
 impl Default for SgxSignOpt {
@@ -41,6 +53,9 @@ impl Default for SgxSignOpt {
             sealed_id_key_path: Some("secrets/id.key".into()),
             state_file_path: "state/priv_validator_state.json".into(),
             enclave_path: "enclave/tmkms-light-sgx-app.sgxs".into(),
+            signing_policy: SigningPolicy::default(),
+            protocol_version: ProtocolVersion::V0_34,
+            require_peer_id: false,
         }
     }
 }
@@ -93,3 +108,35 @@ pub struct RecoverConfig {
     #[arg(short)]
     pub recover_consensus_key: bool,
 }
+
+/// what the DCAP quote of the enclave app needs to attest
+/// before its wrapping key is trusted with the cloud backup key
+#[derive(Parser, Debug, Default, Clone)]
+pub struct QuotePolicy {
+    /// expected (hex-encoded) MRENCLAVE of the enclave app
+    #[arg(long)]
+    pub expected_mrenclave: Option<String>,
+    /// expected (hex-encoded) MRSIGNER of the enclave app (its signing key)
+    #[arg(long)]
+    pub expected_mrsigner: Option<String>,
+    /// expected ISV product ID of the enclave app
+    #[arg(long)]
+    pub expected_isvprodid: Option<u16>,
+    /// minimum ISV security version of the enclave app
+    #[arg(long, default_value_t = 0)]
+    pub min_isvsvn: u16,
+    /// accept an enclave app launched in the debug mode
+    #[arg(long)]
+    pub allow_debug: bool,
+    /// URL of a remote verifier the quote is also sent to (e.g. Microsoft Azure Attestation's
+    /// `https://<instance>/attest/SgxEnclave?api-version=2022-08-01`), which needs to accept it
+    #[arg(long)]
+    pub verifier_url: Option<String>,
+}
+
+impl QuotePolicy {
+    /// nothing about the enclave is pinned
+    pub fn is_empty(&self) -> bool {
+        self.expected_mrenclave.is_none() && self.expected_mrsigner.is_none()
+    }
+}
@@ -0,0 +1,343 @@
+//! DCAP (ECDSA) quote checks done by the host tooling before the enclave's wrapping key
+//! is trusted with the cloud backup key: the enclave's report is signed by the attestation key
+//! that the quoting enclave's report binds, and the quoting enclave's report is signed by
+//! the platform's PCK, whose certificate chain leads to the Intel SGX Root CA
+//! (see `tmkms_light::pck`).
+//! NOTE: the platform's TCB status and the PCK certificates' revocation are left to the remote
+//! verifier (e.g. Microsoft Azure Attestation).
+
+use crate::config::QuotePolicy;
+use base64::{engine::general_purpose, Engine as _};
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::convert::TryInto;
+use std::time::{Duration, SystemTime};
+use tmkms_light::pck::{intel_sgx_root_ca, verify_qe_report_with_root};
+
+const HEADER_LEN: usize = 48;
+const REPORT_BODY_LEN: usize = 384;
+/// ECDSA-256-with-P-256 attestation key
+const ATT_KEY_TYPE_ECDSA_P256: u16 = 2;
+/// the enclave was launched in the debug mode
+const ATTRIBUTE_DEBUG: u64 = 0x2;
+/// how long the remote verifier can take to respond
+const VERIFIER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// the quote with the claim it binds (in the format of Microsoft Azure Attestation's requests)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QuoteAttestation {
+    /// URL-safe base64-encoded quote
+    pub quote: String,
+    #[serde(rename = "runtimeData")]
+    pub runtime_data: RuntimeData,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RuntimeData {
+    /// URL-safe base64-encoded claim (its SHA-256 is in the report data)
+    pub data: String,
+    #[serde(rename = "dataType")]
+    pub data_type: String,
+}
+
+impl QuoteAttestation {
+    pub fn new(quote: &[u8], claim: &str) -> Self {
+        Self {
+            quote: general_purpose::URL_SAFE.encode(quote),
+            runtime_data: RuntimeData {
+                data: general_purpose::URL_SAFE.encode(claim),
+                data_type: "Binary".to_owned(),
+            },
+        }
+    }
+
+    /// the decoded quote and claim
+    pub fn decode(&self) -> Result<(Vec<u8>, Vec<u8>), String> {
+        let quote = general_purpose::URL_SAFE
+            .decode(&self.quote)
+            .map_err(|e| format!("invalid quote encoding: {:?}", e))?;
+        let claim = general_purpose::URL_SAFE
+            .decode(&self.runtime_data.data)
+            .map_err(|e| format!("invalid claim encoding: {:?}", e))?;
+        Ok((quote, claim))
+    }
+}
+
+/// the measurements of the quoted enclave
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotedEnclave {
+    pub mrenclave: [u8; 32],
+    pub mrsigner: [u8; 32],
+    pub isvprodid: u16,
+    pub isvsvn: u16,
+    pub debug: bool,
+    pub reportdata: [u8; 64],
+}
+
+impl QuotedEnclave {
+    fn from_report_body(body: &[u8]) -> Self {
+        let u16_at = |offset: usize| u16::from_le_bytes([body[offset], body[offset + 1]]);
+        let flags = u64::from_le_bytes(body[48..56].try_into().expect("8 bytes"));
+        Self {
+            mrenclave: body[64..96].try_into().expect("32 bytes"),
+            mrsigner: body[128..160].try_into().expect("32 bytes"),
+            isvprodid: u16_at(256),
+            isvsvn: u16_at(258),
+            debug: flags & ATTRIBUTE_DEBUG != 0,
+            reportdata: body[320..384].try_into().expect("64 bytes"),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    String::from_utf8(subtle_encoding::hex::encode(bytes)).expect("hex is ASCII")
+}
+
+/// parses the (version 3 ECDSA) quote and checks its signature chain: the enclave's report is
+/// signed by the attestation key that the quoting enclave's report binds, which is signed by
+/// the PCK whose certificate chain leads to the root CA (DER-encoded)
+fn parse_quote_with_root(
+    quote: &[u8],
+    root_ca: &[u8],
+    time: SystemTime,
+) -> Result<QuotedEnclave, String> {
+    let signed_len = HEADER_LEN + REPORT_BODY_LEN;
+    if quote.len() < signed_len + 4 {
+        return Err("quote is too short".to_owned());
+    }
+    let version = u16::from_le_bytes([quote[0], quote[1]]);
+    let att_key_type = u16::from_le_bytes([quote[2], quote[3]]);
+    if version != 3 || att_key_type != ATT_KEY_TYPE_ECDSA_P256 {
+        return Err(format!(
+            "unsupported quote (version {}, attestation key type {})",
+            version, att_key_type
+        ));
+    }
+    let sig_len = u32::from_le_bytes(quote[signed_len..signed_len + 4].try_into().unwrap());
+    let sig_data = &quote[signed_len + 4..];
+    // enclave report signature + attestation key + QE report + QE report signature + QE auth data size
+    let fixed_len = 64 + 64 + REPORT_BODY_LEN + 64 + 2;
+    if sig_data.len() != sig_len as usize || sig_data.len() < fixed_len {
+        return Err("invalid quote signature data length".to_owned());
+    }
+    let report_signature = &sig_data[..64];
+    let attestation_key = &sig_data[64..128];
+    let qe_report = &sig_data[128..128 + REPORT_BODY_LEN];
+    let qe_report_signature = &sig_data[128 + REPORT_BODY_LEN..fixed_len - 2];
+    let auth_data_offset = fixed_len;
+    let auth_data_len =
+        u16::from_le_bytes([sig_data[fixed_len - 2], sig_data[fixed_len - 1]]) as usize;
+    let auth_data = sig_data
+        .get(auth_data_offset..auth_data_offset + auth_data_len)
+        .ok_or_else(|| "invalid QE authentication data length".to_owned())?;
+    // the certification data: its type and size, and the PCK certificate chain
+    let cert_data_offset = auth_data_offset + auth_data_len;
+    let cert_data_header = sig_data
+        .get(cert_data_offset..cert_data_offset + 6)
+        .ok_or_else(|| "no certification data in the quote".to_owned())?;
+    let cert_data_type = u16::from_le_bytes([cert_data_header[0], cert_data_header[1]]);
+    let cert_data_len = u32::from_le_bytes(cert_data_header[2..6].try_into().unwrap()) as usize;
+    let cert_data = sig_data
+        .get(cert_data_offset + 6..cert_data_offset + 6 + cert_data_len)
+        .ok_or_else(|| "invalid certification data length".to_owned())?;
+
+    let mut uncompressed_key = Vec::with_capacity(65);
+    uncompressed_key.push(0x04);
+    uncompressed_key.extend_from_slice(attestation_key);
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, &uncompressed_key)
+        .verify(&quote[..signed_len], report_signature)
+        .map_err(|_| "the enclave report isn't signed by the attestation key".to_owned())?;
+    let mut hasher = Sha256::new();
+    hasher.update(attestation_key);
+    hasher.update(auth_data);
+    let binding = hasher.finalize();
+    if qe_report[320..352] != binding[..] {
+        return Err("the attestation key isn't bound to the quoting enclave's report".to_owned());
+    }
+    verify_qe_report_with_root(
+        cert_data_type,
+        cert_data,
+        qe_report,
+        qe_report_signature,
+        root_ca,
+        time,
+    )?;
+    Ok(QuotedEnclave::from_report_body(
+        &quote[HEADER_LEN..signed_len],
+    ))
+}
+
+/// checks the quote binds the claim and its enclave matches the policy
+/// (and, if configured, that the remote verifier accepts it)
+pub fn verify_attestation(
+    attestation: &QuoteAttestation,
+    policy: &QuotePolicy,
+) -> Result<QuotedEnclave, String> {
+    verify_attestation_with_root(attestation, policy, &intel_sgx_root_ca(), SystemTime::now())
+}
+
+/// checks the quote (with its signature chain up to the root CA) binds the claim
+/// and its enclave matches the policy (and, if configured, that the remote verifier accepts it)
+fn verify_attestation_with_root(
+    attestation: &QuoteAttestation,
+    policy: &QuotePolicy,
+    root_ca: &[u8],
+    time: SystemTime,
+) -> Result<QuotedEnclave, String> {
+    let (quote, claim) = attestation.decode()?;
+    let enclave = parse_quote_with_root(&quote, root_ca, time)?;
+    if enclave.reportdata[..32] != Sha256::digest(claim)[..] {
+        return Err("the quote doesn't bind the claim".to_owned());
+    }
+    if let Some(expected) = &policy.expected_mrenclave {
+        if !hex(&enclave.mrenclave).eq_ignore_ascii_case(expected) {
+            return Err(format!(
+                "MRENCLAVE {} doesn't match the expected one",
+                hex(&enclave.mrenclave)
+            ));
+        }
+    }
+    if let Some(expected) = &policy.expected_mrsigner {
+        if !hex(&enclave.mrsigner).eq_ignore_ascii_case(expected) {
+            return Err(format!(
+                "MRSIGNER {} doesn't match the expected one",
+                hex(&enclave.mrsigner)
+            ));
+        }
+    }
+    if let Some(expected) = policy.expected_isvprodid {
+        if enclave.isvprodid != expected {
+            return Err(format!("unexpected ISV product ID {}", enclave.isvprodid));
+        }
+    }
+    if enclave.isvsvn < policy.min_isvsvn {
+        return Err(format!(
+            "ISV SVN {} is below the minimum {}",
+            enclave.isvsvn, policy.min_isvsvn
+        ));
+    }
+    if enclave.debug && !policy.allow_debug {
+        return Err("the enclave runs in the debug mode".to_owned());
+    }
+    if let Some(url) = &policy.verifier_url {
+        remote_verify(url, attestation)?;
+    }
+    Ok(enclave)
+}
+
+/// sends the quote to the remote verifier, which needs to accept it (and return a token)
+fn remote_verify(url: &str, attestation: &QuoteAttestation) -> Result<(), String> {
+    let body = serde_json::to_string(attestation)
+        .map_err(|e| format!("failed to serialize the attestation: {:?}", e))?;
+    let response = ureq::post(url)
+        .timeout(VERIFIER_TIMEOUT)
+        .set("Content-Type", "application/json")
+        .send_string(&body)
+        .map_err(|e| format!("the remote verifier rejected the quote: {}", e))?;
+    let response: serde_json::Value = serde_json::from_reader(response.into_reader())
+        .map_err(|e| format!("invalid remote verifier response: {:?}", e))?;
+    if response["token"].as_str().is_none() {
+        return Err("the remote verifier returned no attestation token".to_owned());
+    }
+    Ok(())
+}
+
+/// prints the quoted enclave's measurements
+pub fn print_enclave(enclave: &QuotedEnclave) {
+    println!("MRENCLAVE: {}", hex(&enclave.mrenclave));
+    println!("MRSIGNER: {}", hex(&enclave.mrsigner));
+    println!("ISV product ID: {}", enclave.isvprodid);
+    println!("ISV SVN: {}", enclave.isvsvn);
+    println!("debug mode: {}", enclave.debug);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use tmkms_light::pck::{CERT_DATA_PCK_CHAIN, QE_MRSIGNER};
+
+    /// a PCK certificate chain (valid from 2023 to 2123) to a test root CA and the PCK's key
+    const ROOT_CA: &[u8] = include_bytes!("../../../../testdata/pck_root.der");
+    const PCK_CHAIN: &[u8] = include_bytes!("../../../../testdata/pck_chain.pem");
+    const PCK_KEY: &[u8] = include_bytes!("../../../../testdata/pck_leaf.pk8");
+
+    fn report_body(mrenclave: u8, reportdata: &[u8]) -> Vec<u8> {
+        let mut body = vec![0u8; REPORT_BODY_LEN];
+        body[64..96].copy_from_slice(&[mrenclave; 32]);
+        body[256..258].copy_from_slice(&7u16.to_le_bytes());
+        body[258..260].copy_from_slice(&2u16.to_le_bytes());
+        body[320..320 + reportdata.len()].copy_from_slice(reportdata);
+        body
+    }
+
+    fn quote(claim: &str) -> Vec<u8> {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let key =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).unwrap();
+        let attestation_key = &key.public_key().as_ref()[1..];
+        let mut quote = vec![0u8; HEADER_LEN];
+        quote[..2].copy_from_slice(&3u16.to_le_bytes());
+        quote[2..4].copy_from_slice(&ATT_KEY_TYPE_ECDSA_P256.to_le_bytes());
+        quote.extend(report_body(0xab, &Sha256::digest(claim.as_bytes())));
+        let signature = key.sign(&rng, &quote).unwrap();
+        let auth_data = b"qe auth";
+        let mut binding = Sha256::new();
+        binding.update(attestation_key);
+        binding.update(auth_data);
+        let mut qe_report = report_body(0, &binding.finalize());
+        qe_report[128..160].copy_from_slice(&QE_MRSIGNER);
+        let pck = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, PCK_KEY).unwrap();
+        let qe_report_signature = pck.sign(&rng, &qe_report).unwrap();
+        let mut sig_data = signature.as_ref().to_vec();
+        sig_data.extend_from_slice(attestation_key);
+        sig_data.extend(qe_report);
+        sig_data.extend_from_slice(qe_report_signature.as_ref());
+        sig_data.extend_from_slice(&(auth_data.len() as u16).to_le_bytes());
+        sig_data.extend_from_slice(auth_data);
+        sig_data.extend_from_slice(&CERT_DATA_PCK_CHAIN.to_le_bytes());
+        sig_data.extend_from_slice(&(PCK_CHAIN.len() as u32).to_le_bytes());
+        sig_data.extend_from_slice(PCK_CHAIN);
+        quote.extend_from_slice(&(sig_data.len() as u32).to_le_bytes());
+        quote.extend(sig_data);
+        quote
+    }
+
+    #[test]
+    fn verifies_quote_against_policy() {
+        let verify = |attestation: &QuoteAttestation, policy: &QuotePolicy| {
+            verify_attestation_with_root(attestation, policy, ROOT_CA, SystemTime::now())
+        };
+        let claim = "{\"kid\":\"wrapping-key\"}";
+        let attestation = QuoteAttestation::new(&quote(claim), claim);
+        let mut policy = QuotePolicy {
+            expected_mrenclave: Some(hex(&[0xab; 32])),
+            ..Default::default()
+        };
+        let enclave = verify(&attestation, &policy).unwrap();
+        assert_eq!((enclave.isvprodid, enclave.isvsvn), (7, 2));
+
+        policy.min_isvsvn = 3;
+        assert!(verify(&attestation, &policy).is_err());
+        policy.min_isvsvn = 0;
+        policy.expected_mrenclave = Some(hex(&[0xcd; 32]));
+        assert!(verify(&attestation, &policy).is_err());
+
+        // another claim
+        let other = QuoteAttestation::new(&attestation.decode().unwrap().0, "{}");
+        assert!(verify(&other, &QuotePolicy::default()).is_err());
+        // a tampered report
+        let mut tampered = attestation.decode().unwrap().0;
+        tampered[HEADER_LEN + 64] ^= 1;
+        assert!(parse_quote_with_root(&tampered, ROOT_CA, SystemTime::now()).is_err());
+        // a tampered QE report
+        let mut tampered = attestation.decode().unwrap().0;
+        tampered[HEADER_LEN + REPORT_BODY_LEN + 4 + 128] ^= 1;
+        assert!(parse_quote_with_root(&tampered, ROOT_CA, SystemTime::now()).is_err());
+        // not signed by a platform with an Intel PCK certificate
+        assert!(verify_attestation(&attestation, &QuotePolicy::default()).is_err());
+    }
+}
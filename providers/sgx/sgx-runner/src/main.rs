@@ -1,9 +1,10 @@
 mod command;
 mod config;
+mod dcap;
 mod runner;
 mod shared;
 mod state;
-use crate::config::RecoverConfig;
+use crate::config::{QuotePolicy, RecoverConfig};
 use clap::Parser;
 use shared::SgxInitRequest;
 use std::fmt::Debug;
@@ -27,6 +28,11 @@ enum TmkmsLight {
         sealed_wrap_key_path: Option<PathBuf>,
         #[arg(short)]
         dcap: bool,
+        /// where to write the DCAP quote (if `-d`)
+        #[arg(short, default_value = "wrap-attestation.json")]
+        attestation_path: PathBuf,
+        #[command(flatten)]
+        policy: QuotePolicy,
         /// log level, default: info, -v: info, -vv: debug, -vvv: trace
         #[arg(short, action = clap::ArgAction::Count)]
         v: u32,
@@ -58,6 +64,22 @@ enum TmkmsLight {
         #[arg(short, action = clap::ArgAction::Count)]
         v: u32,
     },
+    #[command(
+        name = "reseal",
+        about = "Reseal the keys for the current CPU SVN (e.g. after a microcode update)"
+    )]
+    /// Reseal the sealed keys
+    Reseal {
+        #[arg(short)]
+        config_path: Option<PathBuf>,
+        /// the sealed cloud backup wrapping key (if it needs resealing too)
+        #[arg(short)]
+        wrap_key_path: Option<PathBuf>,
+        #[arg(short, action = clap::ArgAction::Count)]
+        v: u32,
+    },
+    #[command(subcommand)]
+    Attestation(CommandAttestation),
     #[command(name = "start", about = "Start tmkms process")]
     /// start tmkms process
     Start {
@@ -68,6 +90,22 @@ enum TmkmsLight {
     },
 }
 
+/// DCAP attestation sub-commands
+#[derive(Debug, Parser)]
+enum CommandAttestation {
+    #[command(
+        name = "verify",
+        about = "verify the DCAP quote of the enclave app's wrapping key"
+    )]
+    Verify {
+        /// the quote written by `cloud-wrap -d`
+        #[arg(short, default_value = "wrap-attestation.json")]
+        file: PathBuf,
+        #[command(flatten)]
+        policy: QuotePolicy,
+    },
+}
+
 fn set_log(v: u32) -> String {
     let (log_level, log_level_str) = match v {
         0 | 1 => (Level::INFO, "info"),
@@ -86,6 +124,8 @@ fn main() {
             enclave_path,
             sealed_wrap_key_path,
             dcap,
+            attestation_path,
+            policy,
             v,
         } => {
             let log_level_str = set_log(v);
@@ -93,6 +133,7 @@ fn main() {
                 enclave_path.unwrap_or_else(|| "enclave/tmkms-light-sgx-app.sgxs".into());
             let sealed_wrap_key_path =
                 sealed_wrap_key_path.unwrap_or_else(|| "sealed-wrap.key".into());
+            let dcap = dcap.then_some((&policy, attestation_path));
             command::keywrap(enclave_path, sealed_wrap_key_path, dcap, log_level_str)
         }
         TmkmsLight::Init {
@@ -115,6 +156,18 @@ fn main() {
                 log_level_str,
             )
         }
+        TmkmsLight::Reseal {
+            config_path,
+            wrap_key_path,
+            v,
+        } => {
+            let log_level_str = set_log(v);
+            command::reseal(config_path, wrap_key_path, log_level_str)
+        }
+        TmkmsLight::Attestation(CommandAttestation::Verify { file, policy }) => {
+            set_log(0);
+            command::attestation_verify(file, &policy)
+        }
         TmkmsLight::Start { config_path, v } => {
             let log_level_str = set_log(v);
             command::start(config_path, log_level_str)
@@ -14,6 +14,7 @@ use std::{future::Future, io, pin::Pin};
 use tendermint::consensus;
 use tendermint_config::net;
 use tmkms_light::config::validator::ValidatorConfig;
+use tmkms_light::connection::ProtocolVersion;
 use tmkms_light::error::{io_error_wrap, Error};
 use tmkms_light::utils::read_u16_payload;
 use tracing::{debug, error};
@@ -141,7 +142,7 @@ impl TmkmsSgxSigner {
         sealed_key_path: P,
         config: ValidatorConfig,
         initial_state: consensus::State,
-        remote_conn: Option<(net::Address, P)>,
+        remote_conn: Option<(net::Address, P, ProtocolVersion)>,
     ) -> Result<Vec<u8>, Error> {
         let sealed_key: SealedKeyData = serde_json::from_slice(
             &fs::read(sealed_key_path)
@@ -156,6 +157,7 @@ impl TmkmsSgxSigner {
                     port,
                 },
                 id_path,
+                protocol_version,
             )) => {
                 let sealed_id_key: SealedKeyData = serde_json::from_slice(
                     &fs::read(id_path)
@@ -167,6 +169,7 @@ impl TmkmsSgxSigner {
                     host,
                     port,
                     sealed_key: sealed_id_key,
+                    protocol_version,
                 })
             }
             _ => None,
@@ -8,6 +8,7 @@ use std::str::FromStr;
 use tendermint::consensus;
 use tendermint::node;
 use tmkms_light::config::validator::ValidatorConfig;
+use tmkms_light::connection::ProtocolVersion;
 
 /// keyseal is fixed in the enclave app
 pub type AesGcmSivNonce = [u8; 12];
@@ -98,6 +99,10 @@ pub struct RemoteConnectionConfig {
 
     /// Sealed key for TM SecretConnection
     pub sealed_key: SealedKeyData,
+
+    /// SecretConnection protocol version
+    #[serde(default)]
+    pub protocol_version: ProtocolVersion,
 }
 
 /// package for cloud backups
@@ -211,6 +216,9 @@ pub enum SgxInitRequest {
         cloud_backup: CloudBackupKey,
         key_data: CloudBackupKeyData,
     },
+    /// unseal the keys (with their original key requests) and seal them again
+    /// for the current CPU SVN and enclave ISV SVN (e.g. after a microcode/TCB update)
+    Reseal { sealed_keys: Vec<SealedKeyData> },
     /// start the main loop for processing Tendermint privval requests
     Start {
         sealed_key: SealedKeyData,
@@ -241,6 +249,8 @@ pub enum SgxInitResponse {
         /// if requested, keypair encrypted with the provided key
         cloud_backup_key_data: Option<CloudBackupKeyData>,
     },
+    /// response to resealing (in the order of the request)
+    Resealed { sealed_keys: Vec<SealedKeyData> },
 }
 
 /// obtain a json claim for RSA pubkey
@@ -6,7 +6,7 @@ use std::{
     path::{Path, PathBuf},
 };
 use tempfile::NamedTempFile;
-use tmkms_light::chain::state::{consensus, StateError};
+use tmkms_light::chain::state::{consensus, MacedState, StateError};
 use tmkms_light::utils::read_u16_payload;
 use tracing::{debug, warn};
 
@@ -23,10 +23,10 @@ impl StateSyncer {
         let state_file_path = path.as_ref().to_owned();
         let state = match fs::read_to_string(&path) {
             Ok(state_json) => {
-                let consensus_state: consensus::State = serde_json::from_str(&state_json)
+                let state_file: MacedState = serde_json::from_str(&state_json)
                     .map_err(|e| StateError::sync_enc_dec_error("error parsing".into(), e))?;
 
-                Ok(consensus_state)
+                Ok(state_file.state)
             }
             Err(e) if e.kind() == io::ErrorKind::NotFound => {
                 Self::write_initial_state(&state_file_path)
@@ -84,7 +84,7 @@ impl StateSyncer {
             &new_state
         );
 
-        let json = serde_json::to_string(&new_state)
+        let json = serde_json::to_string(&MacedState::from(new_state.clone()))
             .map_err(|e| StateError::sync_enc_dec_error(path.display().to_string(), e))?;
 
         let state_file_dir = path.parent().unwrap_or_else(|| {
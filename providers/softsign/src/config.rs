@@ -2,6 +2,9 @@ use serde::{Deserialize, Serialize};
 use std::{convert::TryFrom, path::PathBuf};
 use tendermint::chain;
 use tendermint_config::net;
+use tmkms_light::connection::tls::TlsConfig;
+use tmkms_light::connection::{ConnectionMode, ProtocolVersion, Transport};
+use tmkms_light::policy::SigningPolicy;
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -18,10 +21,30 @@ pub struct SoftSignOpt {
     pub id_key_path: Option<PathBuf>,
     /// Path to chain-specific `priv_validator_state.json` file
     pub state_file_path: PathBuf,
-    /// Optional timeout value in seconds
+    /// Optional read/write timeout value in seconds (10 by default for `tcp://`)
     pub timeout: Option<u16>,
     /// Retry connection
     pub retry: bool,
+    /// Secret connection protocol version (`v0.34`, `v0.33` or `legacy`)
+    #[serde(default)]
+    pub protocol_version: ProtocolVersion,
+    /// Encrypted transport of `tcp://` connections (`secret_connection` or `noise`)
+    #[serde(default)]
+    pub transport: Transport,
+    /// Base64-encoded Noise static key of the remote endpoint (if applicable)
+    pub noise_remote_key: Option<String>,
+    /// Mutual TLS settings (if `transport = "tls"`)
+    pub tls: Option<TlsConfig>,
+    /// Refuse the validator connection if its peer ID can't be verified
+    /// (i.e. `peer_id` is required in `tcp://` addresses)
+    #[serde(default)]
+    pub require_peer_id: bool,
+    /// Whether to dial the validator or listen for its connection
+    #[serde(default)]
+    pub connection_mode: ConnectionMode,
+    /// Rules that signing requests must comply with
+    #[serde(default)]
+    pub signing_policy: SigningPolicy,
 }
 
 impl Default for SoftSignOpt {
@@ -37,6 +60,13 @@ impl Default for SoftSignOpt {
             state_file_path: "state/priv_validator_state.json".into(),
             timeout: None,
             retry: true,
+            connection_mode: ConnectionMode::Dial,
+            protocol_version: ProtocolVersion::V0_34,
+            require_peer_id: false,
+            transport: Transport::SecretConnection,
+            noise_remote_key: None,
+            tls: None,
+            signing_policy: SigningPolicy::default(),
         }
     }
 }
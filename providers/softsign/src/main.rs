@@ -3,19 +3,24 @@ mod key_utils;
 mod state;
 use clap::Parser;
 use state::StateHolder;
-use std::{fmt::Debug, os::unix::net::UnixStream};
+use std::fmt::Debug;
+use std::net::TcpListener;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
 use std::{fs, path::PathBuf};
-use std::{net::TcpStream, time::Duration};
-use subtle::ConstantTimeEq;
+use subtle_encoding::base64;
 use tendermint_config::net;
-use tendermint_p2p::secret_connection::{self, PublicKey, SecretConnection};
-use tmkms_light::connection::{Connection, PlainConnection};
+use tendermint_p2p::secret_connection::PublicKey;
+use tmkms_light::connection::{
+    self, noise, tls, Connection, ConnectionMode, PlainConnection, Transport,
+};
 use tmkms_light::{
     chain::state::PersistStateSync,
     config::validator::ValidatorConfig,
+    socket_activation::{self, ActivatedListener},
     utils::{print_pubkey, PubkeyDisplay},
 };
-use tracing::{debug, info, warn, Level};
+use tracing::{debug, info, Level};
 use tracing_subscriber::FmtSubscriber;
 
 #[derive(Debug, Parser)]
@@ -29,6 +34,10 @@ enum TmkmsLight {
     Init {
         #[arg(short)]
         config_path: Option<PathBuf>,
+        /// only generate the keys at the paths in the existing config
+        /// (e.g. under Gramine, where the config is a read-only trusted file)
+        #[arg(short)]
+        keys_only: bool,
     },
     #[command(name = "start", about = "start tmkms process")]
     /// start tmkms process
@@ -51,11 +60,20 @@ enum TmkmsLight {
 fn main() {
     let opt = TmkmsLight::parse();
     match opt {
-        TmkmsLight::Init { config_path } => {
+        TmkmsLight::Init {
+            config_path,
+            keys_only,
+        } => {
             let cp = config_path.unwrap_or_else(|| "tmkms.toml".into());
-            let config = config::SoftSignOpt::default();
-            let t = toml::to_string_pretty(&config).expect("config in toml");
-            fs::write(cp, t).expect("written config");
+            let config = if keys_only {
+                let toml_string = fs::read_to_string(cp).expect("toml config file read");
+                toml::from_str(&toml_string).expect("configuration")
+            } else {
+                let config = config::SoftSignOpt::default();
+                let t = toml::to_string_pretty(&config).expect("config in toml");
+                fs::write(cp, t).expect("written config");
+                config
+            };
             fs::create_dir_all(config.consensus_key_path.parent().expect("not root dir"))
                 .expect("create dirs for key storage");
             key_utils::generate_key(config.consensus_key_path).expect("keygen failed");
@@ -109,74 +127,125 @@ fn main() {
                         let identity_key = key_utils::load_base64_ed25519_key(identity_key_path)
                             .expect("id keypair");
                         info!("KMS node ID: {}", PublicKey::from(&identity_key));
-                        let mut msocket;
-                        loop {
-                            msocket = TcpStream::connect(format!("{}:{}", host, port)).ok();
-                            if msocket.is_some() || !config.retry {
-                                break;
-                            }
-                        }
-                        let socket = msocket.expect("tcp connection");
                         let timeout =
                             Duration::from_secs(config.timeout.unwrap_or(DEFAULT_TIMEOUT).into());
-                        socket
-                            .set_read_timeout(Some(timeout))
-                            .expect("read timeout set");
-                        socket
-                            .set_write_timeout(Some(timeout))
-                            .expect("write timeout set");
-
-                        let connection = SecretConnection::new(
-                            socket,
-                            identity_key,
-                            secret_connection::Version::V0_34,
-                        )
-                        .expect("secret connection");
-                        let actual_peer_id = connection.remote_pubkey().peer_id();
-
-                        // TODO: https://github.com/informalsystems/tendermint-rs/issues/786
-                        if let Some(expected_peer_id) = peer_id {
-                            if expected_peer_id.ct_eq(&actual_peer_id).unwrap_u8() == 0 {
-                                panic!(
-                                    "{}:{}: validator peer ID mismatch! (expected {}, got {})",
-                                    host, port, expected_peer_id, actual_peer_id
+                        let socket = match config.connection_mode {
+                            ConnectionMode::Dial => {
+                                let mut msocket;
+                                loop {
+                                    msocket =
+                                        connection::tcp_connect(host, *port, Some(timeout)).ok();
+                                    if msocket.is_some() || !config.retry {
+                                        break;
+                                    }
+                                }
+                                msocket.expect("tcp connection")
+                            }
+                            ConnectionMode::Listen => {
+                                let listener = match socket_activation::take_listener("privval") {
+                                    Some(ActivatedListener::Tcp(listener)) => listener,
+                                    Some(ActivatedListener::Unix(_)) => {
+                                        panic!("the activated privval socket must be a TCP socket")
+                                    }
+                                    None => TcpListener::bind((host.as_str(), *port))
+                                        .expect("tcp listener bound"),
+                                };
+                                info!(
+                                    "[{}@{}] waiting for the validator to connect...",
+                                    &config.chain_id, &config.address
+                                );
+                                connection::tcp_accept(&listener, Some(timeout))
+                                    .expect("tcp connection")
+                            }
+                        };
+                        let connection: Box<dyn Connection> = match config.transport {
+                            Transport::SecretConnection => Box::new(
+                                connection::secret_connection(
+                                    socket,
+                                    &identity_key,
+                                    *peer_id,
+                                    config.require_peer_id,
+                                    config.protocol_version,
+                                )
+                                .unwrap_or_else(|e| panic!("{}:{}: {}", host, port, e)),
+                            ),
+                            Transport::Noise => {
+                                info!(
+                                    "KMS noise public key: {}",
+                                    String::from_utf8(base64::encode(noise::static_public_key(
+                                        &identity_key
+                                    )))
+                                    .unwrap()
                                 );
+                                let remote_key = config.noise_remote_key.as_ref().map(|k| {
+                                    base64::decode(k).expect("base64-encoded noise remote key")
+                                });
+                                Box::new(
+                                    noise::noise_connection(
+                                        socket,
+                                        &identity_key,
+                                        remote_key.as_deref(),
+                                        config.require_peer_id,
+                                    )
+                                    .unwrap_or_else(|e| panic!("{}:{}: {}", host, port, e)),
+                                )
+                            }
+                            Transport::Tls => {
+                                let credentials = config
+                                    .tls
+                                    .as_ref()
+                                    .expect("config error: no `tls` section")
+                                    .load()
+                                    .expect("TLS credentials");
+                                Box::new(
+                                    tls::tls_connection(socket, &credentials, host)
+                                        .unwrap_or_else(|e| panic!("{}:{}: {}", host, port, e)),
+                                )
                             }
-                        }
+                        };
                         info!(
                             "[{}@{}] connected to validator successfully",
                             &config.chain_id, &config.address
                         );
 
-                        if peer_id.is_none() {
-                            // TODO: https://github.com/informalsystems/tendermint-rs/issues/786
-                            warn!(
-                                "[{}@{}]: unverified validator peer ID! ({})",
-                                &config.chain_id,
-                                &config.address,
-                                connection.remote_pubkey().peer_id()
-                            );
-                        }
-
-                        Box::new(connection)
+                        connection
                     }
                     net::Address::Unix { path } => {
-                        if let Some(timeout) = config.timeout {
-                            warn!("timeouts not supported with Unix sockets: {}", timeout);
-                        }
-
                         debug!(
                             "{}: Connecting to socket at {}...",
                             &config.chain_id, &config.address
                         );
-                        let mut msocket;
-                        loop {
-                            msocket = UnixStream::connect(path).ok();
-                            if msocket.is_some() || !config.retry {
-                                break;
+                        let socket = match config.connection_mode {
+                            ConnectionMode::Dial => {
+                                let mut msocket;
+                                loop {
+                                    msocket = UnixStream::connect(path).ok();
+                                    if msocket.is_some() || !config.retry {
+                                        break;
+                                    }
+                                }
+                                msocket.expect("unix socket open")
+                            }
+                            ConnectionMode::Listen => {
+                                let listener = match socket_activation::take_listener("privval") {
+                                    Some(ActivatedListener::Unix(listener)) => listener,
+                                    Some(ActivatedListener::Tcp(_)) => {
+                                        panic!("the activated privval socket must be a unix socket")
+                                    }
+                                    None => UnixListener::bind(path).expect("unix socket bound"),
+                                };
+                                info!(
+                                    "[{}@{}] waiting for the validator to connect...",
+                                    &config.chain_id, &config.address
+                                );
+                                listener.accept().expect("unix socket open").0
                             }
-                        }
-                        let socket = msocket.expect("unix socket open");
+                        };
+                        let timeout = config.timeout.map(|t| Duration::from_secs(t.into()));
+                        socket.set_read_timeout(timeout).expect("read timeout set");
+                        socket
+                            .set_write_timeout(timeout)
+                            .expect("write timeout set");
                         let conn = PlainConnection::new(socket);
 
                         info!(
@@ -191,6 +260,7 @@ fn main() {
                     ValidatorConfig {
                         chain_id: config.chain_id,
                         max_height: config.max_height,
+                        signing_policy: config.signing_policy,
                     },
                     connection,
                     keypair,
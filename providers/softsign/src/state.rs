@@ -1,10 +1,11 @@
+use ed25519_consensus::Signature;
 use std::{
     fs,
     io::{self, prelude::*},
     path::{Path, PathBuf},
 };
 use tempfile::NamedTempFile;
-use tmkms_light::chain::state::{consensus, PersistStateSync, State, StateError};
+use tmkms_light::chain::state::{consensus, MacedState, PersistStateSync, State, StateError};
 use tracing::debug;
 
 pub struct StateHolder {
@@ -35,15 +36,11 @@ impl PersistStateSync for StateHolder {
     fn load_state(&mut self) -> Result<State, StateError> {
         match fs::read_to_string(&self.state_file_path) {
             Ok(state_json) => {
-                let consensus_state: consensus::State =
-                    serde_json::from_str(&state_json).map_err(|e| {
-                        StateError::sync_enc_dec_error(
-                            self.state_file_path.display().to_string(),
-                            e,
-                        )
-                    })?;
+                let state_file: MacedState = serde_json::from_str(&state_json).map_err(|e| {
+                    StateError::sync_enc_dec_error(self.state_file_path.display().to_string(), e)
+                })?;
 
-                Ok(State::from(consensus_state))
+                Ok(State::from(state_file))
             }
             Err(e) if e.kind() == io::ErrorKind::NotFound => self.write_initial_state(),
             Err(e) => Err(StateError::sync_error(
@@ -54,13 +51,28 @@ impl PersistStateSync for StateHolder {
     }
 
     fn persist_state(&mut self, new_state: &consensus::State) -> Result<(), StateError> {
+        self.write_state(&MacedState::from(new_state.clone()))
+    }
+
+    fn persist_signed_state(
+        &mut self,
+        new_state: &consensus::State,
+        sign_bytes: &[u8],
+        signature: &Signature,
+    ) -> Result<(), StateError> {
+        self.write_state(&MacedState::from(new_state.clone()).with_message(sign_bytes, signature))
+    }
+}
+
+impl StateHolder {
+    fn write_state(&mut self, maced_state: &MacedState) -> Result<(), StateError> {
         debug!(
             "writing new consensus state to {}: {:?}",
             self.state_file_path.display(),
-            &new_state
+            &maced_state.state
         );
 
-        let json = serde_json::to_string(&new_state).map_err(|e| {
+        let json = serde_json::to_string(maced_state).map_err(|e| {
             StateError::sync_enc_dec_error(self.state_file_path.display().to_string(), e)
         })?;
 
@@ -0,0 +1,42 @@
+//! TD quotes via the kernel's configfs TSM reports (`/sys/kernel/config/tsm/report`)
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const TSM_REPORT_DIR: &str = "/sys/kernel/config/tsm/report";
+/// the report provider of TDX guests
+const TDX_PROVIDER: &str = "tdx_guest";
+
+fn other_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, message)
+}
+
+fn read_quote(dir: &Path, report_data: &[u8; 64]) -> io::Result<Vec<u8>> {
+    let provider = fs::read_to_string(dir.join("provider"))?;
+    if provider.trim() != TDX_PROVIDER {
+        return Err(other_error(format!(
+            "unexpected report provider {}",
+            provider.trim()
+        )));
+    }
+    fs::write(dir.join("inblob"), report_data)?;
+    let quote = fs::read(dir.join("outblob"))?;
+    // the report was only generated once (i.e. for this report data)
+    let generation = fs::read_to_string(dir.join("generation"))?;
+    if generation.trim() != "1" {
+        return Err(other_error(
+            "the report entry was written concurrently".to_owned(),
+        ));
+    }
+    Ok(quote)
+}
+
+/// the quote of the TD report with the report data
+pub fn get_quote(report_data: &[u8; 64]) -> io::Result<Vec<u8>> {
+    let dir = Path::new(TSM_REPORT_DIR).join(format!("tmkms-{}", std::process::id()));
+    fs::create_dir(&dir)?;
+    let result = read_quote(&dir, report_data);
+    fs::remove_dir(&dir)?;
+    result
+}
@@ -0,0 +1,164 @@
+//! TDX has no sealing key, so the guest gets one from a key broker (on a trusted machine):
+//! the broker checks the TD's quote and derives the sealing key from its master key
+//! and the TD's measurements, so only the same TD (image) can unseal the keys.
+//! The sealing key is encrypted to the X25519 key in the quote's report data.
+
+use crate::quote::{key_request_report_data, parse_quote, QuotePolicy};
+use crate::{KeyRequest, KeyResponse, KeyResult};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use curve25519_dalek::constants::ED25519_BASEPOINT_TABLE;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::scalar::Scalar;
+use hkdf::Hkdf;
+use rand_core::{CryptoRng, RngCore};
+use sha2::Sha256;
+use std::convert::TryInto;
+use zeroize::{Zeroize, Zeroizing};
+
+/// domain separation for deriving the sealing keys from the master key
+const SEALING_KDF_INFO: &[u8] = b"tmkms-light tdx sealing key v1";
+/// domain separation for deriving the sealing key encryption key
+const TRANSPORT_KDF_INFO: &[u8] = b"tmkms-light tdx key transport v1";
+
+fn x25519_scalar(mut secret: [u8; 32]) -> Scalar {
+    secret[0] &= 248;
+    secret[31] &= 127;
+    secret[31] |= 64;
+    let s = Scalar::from_bits(secret);
+    secret.zeroize();
+    s
+}
+
+/// X25519 public key for the given secret
+pub fn x25519_public_key(secret: &[u8; 32]) -> [u8; 32] {
+    let s = x25519_scalar(*secret);
+    (&s * &ED25519_BASEPOINT_TABLE).to_montgomery().to_bytes()
+}
+
+/// derives the sealing key of the TD measurements (see `TdQuote::sealing_identity`)
+pub fn derive_sealing_key(master_key: &[u8; 32], identity: &[u8]) -> Zeroizing<[u8; 32]> {
+    let hk = Hkdf::<Sha256>::new(Some(identity), master_key);
+    let mut okm = Zeroizing::new([0u8; 32]);
+    hk.expand(SEALING_KDF_INFO, &mut okm[..])
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    okm
+}
+
+/// derives the sealing key encryption key from the X25519 shared secret
+fn transport_key(
+    shared_secret: &MontgomeryPoint,
+    ephemeral: &[u8; 32],
+    recipient: &[u8; 32],
+) -> Key {
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(ephemeral);
+    salt[32..].copy_from_slice(recipient);
+    let hk = Hkdf::<Sha256>::new(Some(&salt), shared_secret.as_bytes());
+    let mut okm = Zeroizing::new([0u8; 32]);
+    hk.expand(TRANSPORT_KDF_INFO, &mut okm[..])
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    *Key::from_slice(&okm[..])
+}
+
+/// encrypts the sealing key to the guest's X25519 key
+pub fn encrypt_sealing_key<R: RngCore + CryptoRng>(
+    csprng: &mut R,
+    sealing_key: &[u8; 32],
+    recipient: &[u8; 32],
+) -> Result<KeyResponse, String> {
+    let mut ephemeral_secret = [0u8; 32];
+    csprng.fill_bytes(&mut ephemeral_secret);
+    let ephemeral_public_key = x25519_public_key(&ephemeral_secret);
+    let shared_secret = MontgomeryPoint(*recipient) * x25519_scalar(ephemeral_secret);
+    ephemeral_secret.zeroize();
+    let key = transport_key(&shared_secret, &ephemeral_public_key, recipient);
+    let mut nonce = [0u8; 12];
+    csprng.fill_bytes(&mut nonce);
+    let ciphertext = ChaCha20Poly1305::new(&key)
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: sealing_key,
+                aad: recipient,
+            },
+        )
+        .map_err(|_| "sealing key encryption failed".to_owned())?;
+    Ok(KeyResponse {
+        ephemeral_public_key,
+        nonce,
+        ciphertext,
+    })
+}
+
+/// decrypts the sealing key with the guest's X25519 secret
+pub fn decrypt_sealing_key(
+    secret: &[u8; 32],
+    response: &KeyResponse,
+) -> Result<Zeroizing<[u8; 32]>, String> {
+    let recipient = x25519_public_key(secret);
+    let shared_secret = MontgomeryPoint(response.ephemeral_public_key) * x25519_scalar(*secret);
+    let key = transport_key(&shared_secret, &response.ephemeral_public_key, &recipient);
+    let plaintext = Zeroizing::new(
+        ChaCha20Poly1305::new(&key)
+            .decrypt(
+                Nonce::from_slice(&response.nonce),
+                Payload {
+                    msg: &response.ciphertext,
+                    aad: &recipient,
+                },
+            )
+            .map_err(|_| "sealing key decryption failed".to_owned())?,
+    );
+    let sealing_key: [u8; 32] = plaintext
+        .as_slice()
+        .try_into()
+        .map_err(|_| "invalid sealing key length".to_owned())?;
+    Ok(Zeroizing::new(sealing_key))
+}
+
+/// checks the request's quote (that it binds the X25519 key and matches the policy)
+/// and returns the TD's sealing key encrypted to it
+pub fn handle_request<R: RngCore + CryptoRng>(
+    csprng: &mut R,
+    master_key: &[u8; 32],
+    policy: &QuotePolicy,
+    request: &KeyRequest,
+) -> KeyResult {
+    let quote = parse_quote(&request.quote)?;
+    if quote.report_data != key_request_report_data(&request.public_key) {
+        return Err("the quote doesn't bind the request's key".to_owned());
+    }
+    policy.verify(&quote)?;
+    let sealing_key = derive_sealing_key(master_key, &quote.sealing_identity());
+    encrypt_sealing_key(csprng, &sealing_key, &request.public_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_core::OsRng;
+
+    #[test]
+    fn transports_sealing_key() {
+        let sealing_key = derive_sealing_key(&[1u8; 32], b"td measurements");
+        assert_ne!(
+            *sealing_key,
+            *derive_sealing_key(&[1u8; 32], b"other measurements")
+        );
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        let response =
+            encrypt_sealing_key(&mut OsRng, &sealing_key, &x25519_public_key(&secret)).unwrap();
+        assert_eq!(
+            *decrypt_sealing_key(&secret, &response).unwrap(),
+            *sealing_key
+        );
+        assert!(decrypt_sealing_key(&[2u8; 32], &response).is_err());
+        let request = KeyRequest {
+            quote: vec![0u8; 16],
+            public_key: x25519_public_key(&secret),
+        };
+        assert!(handle_request(&mut OsRng, &[1u8; 32], &QuotePolicy::default(), &request).is_err());
+    }
+}
@@ -0,0 +1,255 @@
+use rand_core::{OsRng, RngCore};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tendermint_config::net;
+use tmkms_light::utils::{print_pubkey, read_u16_payload, write_u16_payload, PubkeyDisplay};
+use tmkms_light::vsock::proxy::launch_proxy;
+use tmkms_light::vsock::state::StateSyncer;
+use tmkms_tdx_helper::broker::handle_request;
+use tmkms_tdx_helper::quote::{keygen_report_data, parse_quote, QuotePolicy};
+use tmkms_tdx_helper::{
+    KeyRequest, KeyResult, TdxConfig, TdxKeygenResult, TdxRequest, TdxSealedKey,
+};
+use tracing::{error, info, warn};
+use vsock::{VsockAddr, VsockStream};
+use zeroize::Zeroizing;
+
+use crate::config::TdxSignOpt;
+
+fn load_config(config_path: Option<PathBuf>) -> Result<TdxSignOpt, String> {
+    let cp = config_path.unwrap_or_else(|| "tmkms.toml".into());
+    let toml_string =
+        fs::read_to_string(cp).map_err(|e| format!("toml config file failed to read: {:?}", e))?;
+    toml::from_str(&toml_string).map_err(|e| format!("toml config file failed to parse: {:?}", e))
+}
+
+fn send_request(config: &TdxSignOpt, request: &TdxRequest) -> Result<VsockStream, String> {
+    let addr = VsockAddr::new(config.guest_cid, config.guest_port);
+    let mut socket = VsockStream::connect(&addr)
+        .map_err(|e| format!("failed to connect to the guest: {:?}", e))?;
+    let request_raw = serde_json::to_vec(request)
+        .map_err(|e| format!("failed to serialize the request: {:?}", e))?;
+    write_u16_payload(&mut socket, &request_raw)
+        .map_err(|e| format!("failed to write the request: {:?}", e))?;
+    Ok(socket)
+}
+
+/// generates a key in the guest, checks its quote and writes it (sealed) to `path`
+fn generate_key(config: &TdxSignOpt, path: &Path) -> Result<TdxSealedKey, String> {
+    let mut nonce = vec![0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    let mut socket = send_request(
+        config,
+        &TdxRequest::Keygen {
+            nonce: nonce.clone(),
+            broker_port: config.guest_broker_port,
+            timeouts: config.timeouts,
+        },
+    )?;
+    let json_raw = read_u16_payload(&mut socket)
+        .map_err(|e| format!("failed to read the keygen response: {:?}", e))?;
+    let response: TdxKeygenResult = serde_json::from_slice(&json_raw)
+        .map_err(|e| format!("failed to parse the keygen response: {:?}", e))?;
+    let response = response?;
+    let quote = parse_quote(&response.quote)?;
+    let sealed_key = response.sealed_key;
+    if quote.report_data != keygen_report_data(&nonce, &sealed_key.public_key) {
+        return Err("the quote doesn't bind the generated key".to_owned());
+    }
+    config.quote_policy().verify(&quote)?;
+    let json = serde_json::to_vec(&sealed_key)
+        .map_err(|e| format!("failed to serialize the sealed key: {:?}", e))?;
+    OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(&json))
+        .map_err(|e| format!("couldn't write `{}`: {:?}", path.display(), e))?;
+    println!("guest MRTD: {}", quote.mrtd_hex());
+    Ok(sealed_key)
+}
+
+/// write tmkms.toml + generate keys (sealed in the guest)
+pub fn init(
+    config_path: Option<PathBuf>,
+    pubkey_display: Option<PubkeyDisplay>,
+    bech32_prefix: Option<String>,
+    guest_cid: Option<u32>,
+    expected_mrtd: Option<String>,
+    broker_address: Option<net::Address>,
+) -> Result<(), String> {
+    let cp = config_path.unwrap_or_else(|| "tmkms.toml".into());
+    let mut config = TdxSignOpt {
+        expected_mrtd,
+        ..Default::default()
+    };
+    if let Some(cid) = guest_cid {
+        config.guest_cid = cid;
+    }
+    if let Some(address) = broker_address {
+        config.broker_address = address;
+    }
+    if config.expected_mrtd.is_none() {
+        warn!("no expected MRTD, the guest isn't checked");
+    }
+    let t =
+        toml::to_string_pretty(&config).map_err(|e| format!("config to toml failed: {:?}", e))?;
+    fs::write(cp, t).map_err(|e| format!("failed to write a config: {:?}", e))?;
+    for path in std::iter::once(&config.sealed_consensus_key_path)
+        .chain(config.sealed_id_key_path.iter())
+        .chain(std::iter::once(&config.state_file_path))
+    {
+        fs::create_dir_all(
+            path.parent()
+                .ok_or_else(|| "cannot create a dir in a root directory".to_owned())?,
+        )
+        .map_err(|e| format!("failed to create dirs: {:?}", e))?;
+    }
+    launch_proxy(config.guest_broker_port, config.broker_address.clone())?;
+    let sealed_key = generate_key(&config, &config.sealed_consensus_key_path)?;
+    let public_key = ed25519_consensus::VerificationKey::try_from(sealed_key.public_key)
+        .map_err(|e| format!("invalid public key: {:?}", e))?;
+    print_pubkey(bech32_prefix, pubkey_display, public_key);
+    if let Some(id_path) = &config.sealed_id_key_path {
+        generate_key(&config, id_path)?;
+    }
+    Ok(())
+}
+
+fn read_sealed_key(path: &Path) -> Result<TdxSealedKey, String> {
+    serde_json::from_slice(
+        &fs::read(path).map_err(|e| format!("failed to read `{}`: {:?}", path.display(), e))?,
+    )
+    .map_err(|e| format!("failed to parse `{}`: {:?}", path.display(), e))
+}
+
+/// launches the state syncer and the validator and key broker proxies,
+/// and pushes the config to the guest
+pub fn start(config_path: Option<PathBuf>) -> Result<(), String> {
+    let config = load_config(config_path)?;
+    // the secret connection (with the identity key) is only used with `tcp://` validators
+    let (peer_id, sealed_id_key) = match &config.address {
+        net::Address::Tcp { peer_id, .. } => {
+            if peer_id.is_none() && config.require_peer_id {
+                return Err(
+                    "`require_peer_id` is set, but the validator address has no peer ID".to_owned(),
+                );
+            }
+            let id_path = config
+                .sealed_id_key_path
+                .as_deref()
+                .ok_or_else(|| "`tcp://` validators need `sealed_id_key_path`".to_owned())?;
+            (*peer_id, Some(read_sealed_key(id_path)?))
+        }
+        net::Address::Unix { .. } => (None, None),
+    };
+    let state_syncer = StateSyncer::new(&config.state_file_path, config.guest_state_port)
+        .map_err(|e| format!("state persistence error: {:?}", e))?;
+    launch_proxy(config.guest_tendermint_conn, config.address.clone())?;
+    launch_proxy(config.guest_broker_port, config.broker_address.clone())?;
+    let handle = state_syncer.launch_syncer();
+    send_request(
+        &config,
+        &TdxRequest::Start(Box::new(TdxConfig {
+            chain_id: config.chain_id.clone(),
+            max_height: config.max_height,
+            signing_policy: config.signing_policy.clone(),
+            sealed_consensus_key: read_sealed_key(&config.sealed_consensus_key_path)?,
+            sealed_id_key,
+            peer_id,
+            require_peer_id: config.require_peer_id,
+            protocol_version: config.protocol_version,
+            timeouts: config.timeouts,
+            guest_state_port: config.guest_state_port,
+            guest_tendermint_conn: config.guest_tendermint_conn,
+            guest_broker_port: config.guest_broker_port,
+        })),
+    )?;
+    handle
+        .join()
+        .map_err(|_| "state persistence failed".to_owned())
+}
+
+/// how long the key broker waits for a request
+const BROKER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// loads the broker's master key (or generates it if it doesn't exist)
+fn load_master_key(path: &Path) -> Result<Zeroizing<[u8; 32]>, String> {
+    let mut master_key = Zeroizing::new([0u8; 32]);
+    match fs::read(path) {
+        Ok(raw) => {
+            let raw = Zeroizing::new(raw);
+            if raw.len() != 32 {
+                return Err(format!("invalid master key in `{}`", path.display()));
+            }
+            master_key.copy_from_slice(&raw);
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            OsRng.fill_bytes(&mut master_key[..]);
+            OpenOptions::new()
+                .create_new(true)
+                .write(true)
+                .mode(0o600)
+                .open(path)
+                .and_then(|mut file| file.write_all(&master_key[..]))
+                .map_err(|e| format!("couldn't write `{}`: {:?}", path.display(), e))?;
+            info!("generated a new master key in `{}`", path.display());
+        }
+        Err(e) => return Err(format!("failed to read `{}`: {:?}", path.display(), e)),
+    }
+    Ok(master_key)
+}
+
+fn serve_key_request(
+    stream: &mut TcpStream,
+    master_key: &[u8; 32],
+    policy: &QuotePolicy,
+) -> Result<(), String> {
+    stream
+        .set_read_timeout(Some(BROKER_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(BROKER_TIMEOUT)))
+        .map_err(|e| format!("failed to set the timeouts: {:?}", e))?;
+    let json_raw =
+        read_u16_payload(stream).map_err(|e| format!("failed to read the request: {:?}", e))?;
+    let response: KeyResult = serde_json::from_slice::<KeyRequest>(&json_raw)
+        .map_err(|e| format!("invalid request: {:?}", e))
+        .and_then(|request| handle_request(&mut OsRng, master_key, policy, &request));
+    match &response {
+        Ok(_) => info!("released a sealing key"),
+        Err(e) => warn!("refused a sealing key request: {}", e),
+    }
+    let json = serde_json::to_vec(&response)
+        .map_err(|e| format!("failed to serialize the response: {:?}", e))?;
+    write_u16_payload(stream, &json).map_err(|e| format!("failed to write the response: {:?}", e))
+}
+
+/// runs the key broker that releases the sealing keys to the TDs whose quotes match the policy
+pub fn key_broker(
+    listen_address: String,
+    master_key_path: PathBuf,
+    policy: QuotePolicy,
+) -> Result<(), String> {
+    let master_key = load_master_key(&master_key_path)?;
+    if policy.expected_mrtd.is_none() {
+        warn!("no expected MRTD, any TD gets the sealing key of its measurements");
+    }
+    let listener = TcpListener::bind(&listen_address)
+        .map_err(|e| format!("could not bind to {}: {:?}", listen_address, e))?;
+    info!("key broker listening on {}", listen_address);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => {
+                if let Err(e) = serve_key_request(&mut stream, &master_key, &policy) {
+                    error!("{}", e);
+                }
+            }
+            Err(e) => warn!("connection error {}", e),
+        }
+    }
+    Ok(())
+}
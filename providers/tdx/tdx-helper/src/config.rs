@@ -0,0 +1,96 @@
+use serde::{Deserialize, Serialize};
+use std::{convert::TryFrom, path::PathBuf};
+use tendermint::chain;
+use tendermint_config::net;
+use tmkms_light::connection::{ConnectionTimeouts, ProtocolVersion};
+use tmkms_light::policy::SigningPolicy;
+use tmkms_tdx_helper::quote::QuotePolicy;
+
+/// helper configuration in toml
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TdxSignOpt {
+    /// Address of the validator (`tcp://` or `unix://`)
+    pub address: net::Address,
+    /// Chain ID of the Tendermint network this validator is part of
+    pub chain_id: chain::Id,
+    /// Height at which to stop signing
+    pub max_height: Option<tendermint::block::Height>,
+    /// Path to the sealed consensus key
+    pub sealed_consensus_key_path: PathBuf,
+    /// Path to the sealed Ed25519 identity key (if applicable)
+    pub sealed_id_key_path: Option<PathBuf>,
+    /// Path to chain-specific `priv_validator_state.json` file
+    pub state_file_path: PathBuf,
+    /// Vsock CID of the TDX guest
+    pub guest_cid: u32,
+    /// Vsock port the guest listens on for requests
+    pub guest_port: u32,
+    /// Vsock port for state synchronization
+    pub guest_state_port: u32,
+    /// Vsock port to forward privval traffic to TM over UDS or TCP
+    pub guest_tendermint_conn: u32,
+    /// Address of the key broker (`tcp://`)
+    pub broker_address: net::Address,
+    /// Vsock port to forward the guest's sealing key requests to the key broker
+    pub guest_broker_port: u32,
+    /// Read/write timeouts of the validator, state and key broker connections
+    #[serde(default)]
+    pub timeouts: ConnectionTimeouts,
+    /// Secret connection protocol version (`v0.34`, `v0.33` or `legacy`)
+    #[serde(default)]
+    pub protocol_version: ProtocolVersion,
+    /// Refuse the validator connection if its peer ID can't be verified
+    /// (i.e. `peer_id` is required in `tcp://` addresses)
+    #[serde(default)]
+    pub require_peer_id: bool,
+    /// Rules that signing requests must comply with
+    #[serde(default)]
+    pub signing_policy: SigningPolicy,
+    /// Expected (hex-encoded) MRTD of the guest
+    pub expected_mrtd: Option<String>,
+    /// Accept a guest that can be debugged
+    #[serde(default)]
+    pub allow_debug: bool,
+}
+
+impl TdxSignOpt {
+    /// what the guest's quotes need to match
+    pub fn quote_policy(&self) -> QuotePolicy {
+        QuotePolicy {
+            expected_mrtd: self.expected_mrtd.clone(),
+            allow_debug: self.allow_debug,
+        }
+    }
+}
+
+impl Default for TdxSignOpt {
+    fn default() -> Self {
+        Self {
+            address: net::Address::Unix {
+                path: "/tmp/validator.socket".into(),
+            },
+            chain_id: chain::Id::try_from("testchain-1".to_owned()).expect("valid chain-id"),
+            max_height: None,
+            sealed_consensus_key_path: "secrets/secret.key".into(),
+            sealed_id_key_path: Some("secrets/id.key".into()),
+            state_file_path: "state/priv_validator_state.json".into(),
+            guest_cid: 3,
+            guest_port: 5050,
+            guest_state_port: 5555,
+            guest_tendermint_conn: 5000,
+            broker_address: net::Address::Tcp {
+                peer_id: None,
+                host: "127.0.0.1".to_owned(),
+                port: 26700,
+            },
+            guest_broker_port: 5600,
+            timeouts: ConnectionTimeouts::default(),
+            protocol_version: ProtocolVersion::V0_34,
+            require_peer_id: false,
+            signing_policy: SigningPolicy::default(),
+            expected_mrtd: None,
+            allow_debug: false,
+        }
+    }
+}
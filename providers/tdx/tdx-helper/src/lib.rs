@@ -0,0 +1,5 @@
+pub use shared::*;
+
+pub mod broker;
+pub mod quote;
+pub mod shared;
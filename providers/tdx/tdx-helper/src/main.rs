@@ -0,0 +1,128 @@
+mod command;
+mod config;
+
+use clap::Parser;
+use std::path::PathBuf;
+use tendermint_config::net;
+use tmkms_light::utils::PubkeyDisplay;
+use tmkms_tdx_helper::quote::QuotePolicy;
+use tracing::{error, Level};
+use tracing_subscriber::FmtSubscriber;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "tmkms-tdx-helper",
+    about = "helper (host-side) of the signer running in an Intel TDX guest"
+)]
+enum TmkmsLight {
+    #[command(name = "init", about = "Create config + keygen")]
+    /// Create config + keygen
+    Init {
+        #[arg(short)]
+        config_path: Option<PathBuf>,
+        #[arg(short)]
+        pubkey_display: Option<PubkeyDisplay>,
+        #[arg(short)]
+        bech32_prefix: Option<String>,
+        /// vsock CID of the guest
+        #[arg(long)]
+        cid: Option<u32>,
+        /// expected (hex-encoded) MRTD of the guest
+        #[arg(short = 'm', long)]
+        expected_mrtd: Option<String>,
+        /// address of the key broker (`tcp://`)
+        #[arg(short = 'k', long)]
+        broker_address: Option<net::Address>,
+        #[arg(short, action = clap::ArgAction::Count)]
+        v: u32,
+    },
+    #[command(name = "start", about = "start tmkms process")]
+    /// start tmkms process
+    Start {
+        #[arg(short)]
+        config_path: Option<PathBuf>,
+        #[arg(short, action = clap::ArgAction::Count)]
+        v: u32,
+    },
+    #[command(
+        name = "key-broker",
+        about = "release sealing keys to attested guests (on a trusted machine)"
+    )]
+    /// release sealing keys to attested guests (on a trusted machine)
+    KeyBroker {
+        /// address to listen on
+        #[arg(short, long, default_value = "0.0.0.0:26700")]
+        listen_address: String,
+        /// path to the master key (generated if it doesn't exist)
+        #[arg(short, long, default_value = "broker-master.key")]
+        master_key_path: PathBuf,
+        /// expected (hex-encoded) MRTD of the guests
+        #[arg(short = 'm', long)]
+        expected_mrtd: Option<String>,
+        /// release the sealing keys to guests that can be debugged
+        #[arg(long)]
+        allow_debug: bool,
+        #[arg(short, action = clap::ArgAction::Count)]
+        v: u32,
+    },
+}
+
+fn set_log(v: u32) {
+    let log_level = match v {
+        0 | 1 => Level::INFO,
+        2 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
+    let subscriber = FmtSubscriber::builder().with_max_level(log_level).finish();
+    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+}
+
+fn main() {
+    let opt = TmkmsLight::parse();
+    let result = match opt {
+        TmkmsLight::Init {
+            config_path,
+            pubkey_display,
+            bech32_prefix,
+            cid,
+            expected_mrtd,
+            broker_address,
+            v,
+        } => {
+            set_log(v);
+            command::init(
+                config_path,
+                pubkey_display,
+                bech32_prefix,
+                cid,
+                expected_mrtd,
+                broker_address,
+            )
+        }
+        TmkmsLight::Start { config_path, v } => {
+            set_log(v);
+            command::start(config_path)
+        }
+        TmkmsLight::KeyBroker {
+            listen_address,
+            master_key_path,
+            expected_mrtd,
+            allow_debug,
+            v,
+        } => {
+            set_log(v);
+            command::key_broker(
+                listen_address,
+                master_key_path,
+                QuotePolicy {
+                    expected_mrtd,
+                    allow_debug,
+                },
+            )
+        }
+    };
+    if let Err(e) = result {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+}
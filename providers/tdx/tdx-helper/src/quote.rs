@@ -0,0 +1,299 @@
+//! TDX (version 4 ECDSA) quotes: the TD report is signed by the attestation key that the
+//! quoting enclave's report binds, and the quoting enclave's report is signed by the platform's
+//! PCK, whose certificate chain leads to the Intel SGX Root CA (see `tmkms_light::pck`).
+//! NOTE: the platform's TCB status and the PCK certificates' revocation aren't checked
+//! (e.g. with Intel's `QVL` / a remote verifier).
+
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+use sha2::{Digest, Sha256, Sha512};
+use std::convert::TryInto;
+use std::time::SystemTime;
+use subtle_encoding::hex;
+use tmkms_light::pck::{intel_sgx_root_ca, verify_qe_report_with_root};
+
+const HEADER_LEN: usize = 48;
+const TD_REPORT_BODY_LEN: usize = 584;
+const QE_REPORT_BODY_LEN: usize = 384;
+/// ECDSA-256-with-P-256 attestation key
+const ATT_KEY_TYPE_ECDSA_P256: u16 = 2;
+/// the quote is of a trust domain
+const TEE_TYPE_TDX: u32 = 0x81;
+/// the certification data is the quoting enclave's report
+const CERT_DATA_QE_REPORT: u16 = 6;
+/// the TD can be debugged by the host
+const TD_ATTRIBUTE_DEBUG: u64 = 1;
+/// domain separation of the report data of generated keys
+const KEYGEN_REPORT_DOMAIN: &[u8] = b"tmkms-light tdx keygen";
+/// domain separation of the report data of sealing key requests
+const KEY_REQUEST_REPORT_DOMAIN: &[u8] = b"tmkms-light tdx key request";
+
+/// the fields of the TD report that are checked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TdQuote {
+    pub tee_tcb_svn: [u8; 16],
+    pub td_attributes: u64,
+    pub mrtd: [u8; 48],
+    pub rtmrs: [[u8; 48]; 4],
+    pub report_data: [u8; 64],
+}
+
+impl TdQuote {
+    fn from_report_body(body: &[u8]) -> Self {
+        let rtmr = |i: usize| {
+            body[328 + i * 48..376 + i * 48]
+                .try_into()
+                .expect("48 bytes")
+        };
+        Self {
+            tee_tcb_svn: body[..16].try_into().expect("16 bytes"),
+            td_attributes: u64::from_le_bytes(body[120..128].try_into().expect("8 bytes")),
+            mrtd: body[136..184].try_into().expect("48 bytes"),
+            rtmrs: [rtmr(0), rtmr(1), rtmr(2), rtmr(3)],
+            report_data: body[520..584].try_into().expect("64 bytes"),
+        }
+    }
+
+    /// the TD can be debugged
+    pub fn debug(&self) -> bool {
+        self.td_attributes & TD_ATTRIBUTE_DEBUG != 0
+    }
+
+    /// hex-encoded build-time measurement of the TD
+    pub fn mrtd_hex(&self) -> String {
+        String::from_utf8(hex::encode(self.mrtd)).expect("hex is ASCII")
+    }
+
+    /// what the sealing key is derived from: MRTD and the firmware, kernel
+    /// and kernel command line measurements (RTMR0-2)
+    pub fn sealing_identity(&self) -> Vec<u8> {
+        let mut identity = self.mrtd.to_vec();
+        for rtmr in &self.rtmrs[..3] {
+            identity.extend_from_slice(rtmr);
+        }
+        identity
+    }
+}
+
+/// parses the quote and checks its signature chain up to the Intel SGX Root CA
+pub fn parse_quote(quote: &[u8]) -> Result<TdQuote, String> {
+    parse_quote_with_root(quote, &intel_sgx_root_ca(), SystemTime::now())
+}
+
+/// parses the quote and checks its signature chain: the TD report is signed by the attestation key
+/// that the quoting enclave's report binds, which is signed by the PCK whose certificate chain
+/// leads to the root CA (DER-encoded)
+fn parse_quote_with_root(
+    quote: &[u8],
+    root_ca: &[u8],
+    time: SystemTime,
+) -> Result<TdQuote, String> {
+    let signed_len = HEADER_LEN + TD_REPORT_BODY_LEN;
+    if quote.len() < signed_len + 4 {
+        return Err("quote is too short".to_owned());
+    }
+    let version = u16::from_le_bytes([quote[0], quote[1]]);
+    let att_key_type = u16::from_le_bytes([quote[2], quote[3]]);
+    let tee_type = u32::from_le_bytes(quote[4..8].try_into().unwrap());
+    if version != 4 || att_key_type != ATT_KEY_TYPE_ECDSA_P256 || tee_type != TEE_TYPE_TDX {
+        return Err(format!(
+            "unsupported quote (version {}, attestation key type {}, TEE type {:#x})",
+            version, att_key_type, tee_type
+        ));
+    }
+    let sig_len = u32::from_le_bytes(quote[signed_len..signed_len + 4].try_into().unwrap());
+    let sig_data = &quote[signed_len + 4..];
+    // TD report signature + attestation key + certification data type and size
+    // + QE report + QE report signature + QE auth data size
+    let fixed_len = 64 + 64 + 6 + QE_REPORT_BODY_LEN + 64 + 2;
+    if sig_data.len() != sig_len as usize || sig_data.len() < fixed_len {
+        return Err("invalid quote signature data length".to_owned());
+    }
+    let report_signature = &sig_data[..64];
+    let attestation_key = &sig_data[64..128];
+    let cert_data_type = u16::from_le_bytes([sig_data[128], sig_data[129]]);
+    if cert_data_type != CERT_DATA_QE_REPORT {
+        return Err(format!(
+            "unsupported certification data type {}",
+            cert_data_type
+        ));
+    }
+    let qe_report = &sig_data[134..134 + QE_REPORT_BODY_LEN];
+    let qe_report_signature = &sig_data[134 + QE_REPORT_BODY_LEN..fixed_len - 2];
+    let auth_data_len =
+        u16::from_le_bytes([sig_data[fixed_len - 2], sig_data[fixed_len - 1]]) as usize;
+    let auth_data = sig_data
+        .get(fixed_len..fixed_len + auth_data_len)
+        .ok_or_else(|| "invalid QE authentication data length".to_owned())?;
+    // the QE report's certification data: its type and size, and the PCK certificate chain
+    let pck_offset = fixed_len + auth_data_len;
+    let pck_header = sig_data
+        .get(pck_offset..pck_offset + 6)
+        .ok_or_else(|| "no PCK certification data in the quote".to_owned())?;
+    let pck_data_type = u16::from_le_bytes([pck_header[0], pck_header[1]]);
+    let pck_data_len = u32::from_le_bytes(pck_header[2..6].try_into().unwrap()) as usize;
+    let pck_data = sig_data
+        .get(pck_offset + 6..pck_offset + 6 + pck_data_len)
+        .ok_or_else(|| "invalid PCK certification data length".to_owned())?;
+
+    let mut uncompressed_key = Vec::with_capacity(65);
+    uncompressed_key.push(0x04);
+    uncompressed_key.extend_from_slice(attestation_key);
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, &uncompressed_key)
+        .verify(&quote[..signed_len], report_signature)
+        .map_err(|_| "the TD report isn't signed by the attestation key".to_owned())?;
+    let mut hasher = Sha256::new();
+    hasher.update(attestation_key);
+    hasher.update(auth_data);
+    let binding = hasher.finalize();
+    if qe_report[320..352] != binding[..] {
+        return Err("the attestation key isn't bound to the quoting enclave's report".to_owned());
+    }
+    verify_qe_report_with_root(
+        pck_data_type,
+        pck_data,
+        qe_report,
+        qe_report_signature,
+        root_ca,
+        time,
+    )?;
+    Ok(TdQuote::from_report_body(&quote[HEADER_LEN..signed_len]))
+}
+
+fn report_data(domain: &[u8], nonce: &[u8], public_key: &[u8; 32]) -> [u8; 64] {
+    let mut hasher = Sha512::new();
+    hasher.update(domain);
+    hasher.update(nonce);
+    hasher.update(public_key);
+    hasher.finalize().into()
+}
+
+/// the report data of a generated key: SHA-512 of the domain, the request nonce and the public key
+pub fn keygen_report_data(nonce: &[u8], public_key: &[u8; 32]) -> [u8; 64] {
+    report_data(KEYGEN_REPORT_DOMAIN, nonce, public_key)
+}
+
+/// the report data of a sealing key request: SHA-512 of the domain and the X25519 public key
+pub fn key_request_report_data(public_key: &[u8; 32]) -> [u8; 64] {
+    report_data(KEY_REQUEST_REPORT_DOMAIN, &[], public_key)
+}
+
+/// what the quote of the TD needs to match
+#[derive(Debug, Clone, Default)]
+pub struct QuotePolicy {
+    /// hex-encoded MRTD
+    pub expected_mrtd: Option<String>,
+    /// accept a TD that can be debugged
+    pub allow_debug: bool,
+}
+
+impl QuotePolicy {
+    /// checks the TD report matches the policy
+    pub fn verify(&self, quote: &TdQuote) -> Result<(), String> {
+        if let Some(expected) = &self.expected_mrtd {
+            if !quote.mrtd_hex().eq_ignore_ascii_case(expected) {
+                return Err(format!(
+                    "MRTD {} doesn't match the expected one",
+                    quote.mrtd_hex()
+                ));
+            }
+        }
+        if quote.debug() && !self.allow_debug {
+            return Err("the TD can be debugged".to_owned());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use tmkms_light::pck::{CERT_DATA_PCK_CHAIN, QE_MRSIGNER};
+
+    /// a PCK certificate chain (valid from 2023 to 2123) to a test root CA and the PCK's key
+    const ROOT_CA: &[u8] = include_bytes!("../../../../testdata/pck_root.der");
+    const PCK_CHAIN: &[u8] = include_bytes!("../../../../testdata/pck_chain.pem");
+    const PCK_KEY: &[u8] = include_bytes!("../../../../testdata/pck_leaf.pk8");
+
+    fn quote(td_attributes: u64, report_data: &[u8; 64]) -> Vec<u8> {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let key =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).unwrap();
+        let attestation_key = &key.public_key().as_ref()[1..];
+        let mut quote = vec![0u8; HEADER_LEN];
+        quote[..2].copy_from_slice(&4u16.to_le_bytes());
+        quote[2..4].copy_from_slice(&ATT_KEY_TYPE_ECDSA_P256.to_le_bytes());
+        quote[4..8].copy_from_slice(&TEE_TYPE_TDX.to_le_bytes());
+        let mut body = vec![0u8; TD_REPORT_BODY_LEN];
+        body[120..128].copy_from_slice(&td_attributes.to_le_bytes());
+        body[136..184].copy_from_slice(&[0xab; 48]);
+        body[328..376].copy_from_slice(&[1; 48]);
+        body[520..584].copy_from_slice(report_data);
+        quote.extend(body);
+        let signature = key.sign(&rng, &quote).unwrap();
+        let auth_data = b"qe auth";
+        let mut binding = Sha256::new();
+        binding.update(attestation_key);
+        binding.update(auth_data);
+        let mut qe_report = vec![0u8; QE_REPORT_BODY_LEN];
+        qe_report[128..160].copy_from_slice(&QE_MRSIGNER);
+        qe_report[320..352].copy_from_slice(&binding.finalize());
+        let pck = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, PCK_KEY).unwrap();
+        let qe_report_signature = pck.sign(&rng, &qe_report).unwrap();
+        let mut sig_data = signature.as_ref().to_vec();
+        sig_data.extend_from_slice(attestation_key);
+        sig_data.extend_from_slice(&CERT_DATA_QE_REPORT.to_le_bytes());
+        let qe_cert_data_len = QE_REPORT_BODY_LEN + 66 + auth_data.len() + 6 + PCK_CHAIN.len();
+        sig_data.extend_from_slice(&(qe_cert_data_len as u32).to_le_bytes());
+        sig_data.extend(qe_report);
+        sig_data.extend_from_slice(qe_report_signature.as_ref());
+        sig_data.extend_from_slice(&(auth_data.len() as u16).to_le_bytes());
+        sig_data.extend_from_slice(auth_data);
+        sig_data.extend_from_slice(&CERT_DATA_PCK_CHAIN.to_le_bytes());
+        sig_data.extend_from_slice(&(PCK_CHAIN.len() as u32).to_le_bytes());
+        sig_data.extend_from_slice(PCK_CHAIN);
+        quote.extend_from_slice(&(sig_data.len() as u32).to_le_bytes());
+        quote.extend(sig_data);
+        quote
+    }
+
+    fn parse(quote: &[u8]) -> Result<TdQuote, String> {
+        parse_quote_with_root(quote, ROOT_CA, SystemTime::now())
+    }
+
+    #[test]
+    fn verifies_quote_against_policy() {
+        let report_data = key_request_report_data(&[7; 32]);
+        let raw = quote(0, &report_data);
+        let td = parse(&raw).unwrap();
+        assert_eq!(td.report_data, report_data);
+        assert_eq!(td.rtmrs[0], [1; 48]);
+        assert_eq!(td.sealing_identity().len(), 48 * 4);
+        let policy = QuotePolicy {
+            expected_mrtd: Some("AB".repeat(48)),
+            ..Default::default()
+        };
+        assert!(policy.verify(&td).is_ok());
+        let policy = QuotePolicy {
+            expected_mrtd: Some("CD".repeat(48)),
+            ..Default::default()
+        };
+        assert!(policy.verify(&td).is_err());
+
+        let debug_td = parse(&quote(TD_ATTRIBUTE_DEBUG, &report_data)).unwrap();
+        assert!(QuotePolicy::default().verify(&debug_td).is_err());
+
+        // the signed TD report was altered
+        let mut mangled = raw;
+        mangled[HEADER_LEN + 136] ^= 1;
+        assert!(parse(&mangled).is_err());
+        // not signed by a platform with an Intel PCK certificate
+        assert!(parse_quote(&quote(0, &report_data)).is_err());
+        // the QE report was altered
+        let mut mangled = quote(0, &report_data);
+        mangled[HEADER_LEN + TD_REPORT_BODY_LEN + 4 + 134] ^= 1;
+        assert!(parse(&mangled).is_err());
+    }
+}
@@ -0,0 +1,95 @@
+use serde::{Deserialize, Serialize};
+use tendermint::{chain, node};
+use tmkms_light::connection::{ConnectionTimeouts, ProtocolVersion};
+use tmkms_light::policy::SigningPolicy;
+
+pub use tmkms_light::vsock::VSOCK_HOST_CID;
+
+/// Ed25519 key sealed (with ChaCha20-Poly1305) by the key released by the key broker
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TdxSealedKey {
+    /// Ed25519 public key (authenticated as the associated data)
+    pub public_key: [u8; 32],
+    pub nonce: [u8; 12],
+    pub ciphertext: Vec<u8>,
+}
+
+/// TDX config to be pushed to the guest
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TdxConfig {
+    /// Chain ID of the Tendermint network this validator is part of
+    pub chain_id: chain::Id,
+    /// Height at which to stop signing
+    pub max_height: Option<tendermint::block::Height>,
+    /// Rules that signing requests must comply with
+    pub signing_policy: SigningPolicy,
+    /// sealed consensus key
+    pub sealed_consensus_key: TdxSealedKey,
+    /// sealed Ed25519 identity key (if secret connection)
+    pub sealed_id_key: Option<TdxSealedKey>,
+    /// peer id to check with secret connections
+    pub peer_id: Option<node::Id>,
+    /// refuse secret connections whose peer id can't be checked
+    pub require_peer_id: bool,
+    /// secret connection protocol version
+    pub protocol_version: ProtocolVersion,
+    /// read/write timeouts of the validator, state and key broker connections
+    pub timeouts: ConnectionTimeouts,
+    /// Vsock port on the host for state synchronization
+    pub guest_state_port: u32,
+    /// Vsock port on the host to forward privval traffic to TM over UDS or TCP
+    pub guest_tendermint_conn: u32,
+    /// Vsock port on the host to forward the sealing key requests to the key broker
+    pub guest_broker_port: u32,
+}
+
+/// request sent to the guest
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TdxRequest {
+    /// generate and seal a new key (its quote binds the nonce);
+    /// the sealing key is requested from the key broker via the vsock port on the host
+    Keygen {
+        nonce: Vec<u8>,
+        broker_port: u32,
+        timeouts: ConnectionTimeouts,
+    },
+    /// start signing
+    Start(Box<TdxConfig>),
+}
+
+/// response to the key generation
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TdxKeygenResponse {
+    /// the generated key sealed in the guest
+    pub sealed_key: TdxSealedKey,
+    /// TDX quote with the public key and the nonce in its report data
+    pub quote: Vec<u8>,
+}
+
+pub type TdxKeygenResult = Result<TdxKeygenResponse, String>;
+
+/// sealing key request sent by the guest to the key broker
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyRequest {
+    /// TDX quote with the X25519 public key in its report data
+    pub quote: Vec<u8>,
+    /// the guest's (ephemeral) X25519 public key the sealing key is encrypted to
+    pub public_key: [u8; 32],
+}
+
+/// the sealing key encrypted to the guest's X25519 key
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyResponse {
+    /// ephemeral X25519 public key of the key broker
+    pub ephemeral_public_key: [u8; 32],
+    /// ChaCha20Poly1305 nonce
+    pub nonce: [u8; 12],
+    /// the encrypted sealing key
+    pub ciphertext: Vec<u8>,
+}
+
+pub type KeyResult = Result<KeyResponse, String>;
@@ -96,6 +96,9 @@ def verify_attestation_doc(attestation_doc, root_cert_pem = None, bech32hrp = "c
         raise Exception("timestamp error")
     time = datetime.fromtimestamp(doc_obj["timestamp"] / 1000)
     print(f"timestamp: {time}")
+    # the nonce generated by the helper for this keygen request
+    if doc_obj.get("nonce"):
+        print(f"nonce: {doc_obj['nonce'].hex()}")
     # PCRs are printed for verification below
     if 'pcrs' not in doc_obj or len(doc_obj["pcrs"]) > 32 or len(doc_obj["pcrs"]) == 0:
         raise Exception("pcrs error")
@@ -115,6 +118,11 @@ def verify_attestation_doc(attestation_doc, root_cert_pem = None, bech32hrp = "c
     pubkeyb32 = bech32_encode(bech32hrp, list(pubkey))
     print("*** VERIFY user_data below (used AWS KMS key and generated pubkey) ***")
     print(f"AWS KMS key id: {key_id}")
+    # "consensus" or "identity" (the secret connection key)
+    print(f"key purpose: {user_data.get('purpose', 'consensus')}")
+    # the key is derived from a sealed master seed at this path
+    if 'path' in user_data:
+        print(f"derivation path: {user_data['path']}")
     print(f"validator pubkey (base64): {pubkeyb64}")
     print(f"validator pubkey (bech32): {pubkeyb32}")
 
@@ -0,0 +1,182 @@
+//! Approval of signatures by a second party (two-person rule): the signatures that the policy
+//! marks as sensitive are only released once an independent approver (e.g. another enclave
+//! or an operator's device) returned a token over their sign-bytes
+
+use crate::audit::SignedMsgKind;
+use crate::chain::state::consensus;
+use crate::utils::{read_u16_payload, write_u16_payload};
+use ed25519_consensus::{Signature, SigningKey, VerificationKey};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::io;
+use tendermint::chain;
+
+/// domain separation of the approval tokens (so that they can't be confused with consensus signatures)
+const APPROVAL_DOMAIN: &[u8] = b"tmkms-approval-v1";
+
+/// a signature waiting for the approval
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApprovalRequest {
+    pub chain_id: chain::Id,
+    pub msg_type: SignedMsgKind,
+    /// the (height, round, step) and block ID to be signed
+    pub state: consensus::State,
+    /// the canonical sign-bytes (the token is bound to them)
+    pub sign_bytes: Vec<u8>,
+}
+
+/// the approver's signature over the request's sign-bytes
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApprovalToken {
+    pub signature: Vec<u8>,
+}
+
+fn approval_message(sign_bytes: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(APPROVAL_DOMAIN.len() + sign_bytes.len());
+    message.extend_from_slice(APPROVAL_DOMAIN);
+    message.extend_from_slice(sign_bytes);
+    message
+}
+
+impl ApprovalToken {
+    /// the approval of the request with the approver's key
+    pub fn sign(approver_key: &SigningKey, request: &ApprovalRequest) -> Self {
+        Self {
+            signature: approver_key
+                .sign(&approval_message(&request.sign_bytes))
+                .to_bytes()
+                .to_vec(),
+        }
+    }
+
+    /// checks the token approves the request
+    pub fn verify(
+        &self,
+        approver_key: &VerificationKey,
+        request: &ApprovalRequest,
+    ) -> Result<(), String> {
+        let signature = Signature::try_from(self.signature.as_slice())
+            .map_err(|e| format!("invalid approval token: {}", e))?;
+        approver_key
+            .verify(&signature, &approval_message(&request.sign_bytes))
+            .map_err(|_| "the approval token doesn't match the request".to_owned())
+    }
+}
+
+/// source of the approvals
+pub trait Approver: Send {
+    /// returns the approval token (or the reason of the refusal)
+    fn request_approval(&mut self, request: &ApprovalRequest) -> Result<ApprovalToken, String>;
+}
+
+/// requests the approvals from an approval service over a connection
+/// (length-prefixed JSON, as the other host services)
+pub struct RemoteApprover<T: io::Read + io::Write + Send> {
+    conn: T,
+}
+
+impl<T: io::Read + io::Write + Send> RemoteApprover<T> {
+    pub fn new(conn: T) -> Self {
+        Self { conn }
+    }
+}
+
+impl<T: io::Read + io::Write + Send> Approver for RemoteApprover<T> {
+    fn request_approval(&mut self, request: &ApprovalRequest) -> Result<ApprovalToken, String> {
+        let json_raw = serde_json::to_vec(request).map_err(|e| format!("{:?}", e))?;
+        write_u16_payload(&mut self.conn, &json_raw)
+            .map_err(|e| format!("approval request failed: {}", e))?;
+        let response_raw = read_u16_payload(&mut self.conn)
+            .map_err(|e| format!("approval response failed: {}", e))?;
+        serde_json::from_slice::<Result<ApprovalToken, String>>(&response_raw)
+            .map_err(|e| format!("invalid approval response: {:?}", e))?
+    }
+}
+
+/// The highest (height, round, step) approved on each chain (as kept by the approver),
+/// so that it never approves conflicting messages
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Approvals(BTreeMap<String, consensus::State>);
+
+impl Approvals {
+    /// records the approval if the request is above the last approved (height, round, step);
+    /// the same (height, round, step) is only approved again for the same block ID
+    pub fn approve(&mut self, request: &ApprovalRequest) -> Result<(), String> {
+        let chain_id = request.chain_id.to_string();
+        if let Some(approved) = self.0.get(&chain_id) {
+            let conflicting = match request.state.cmp(approved) {
+                Ordering::Less => true,
+                Ordering::Equal => request.state.block_id != approved.block_id,
+                Ordering::Greater => false,
+            };
+            if conflicting {
+                return Err(format!(
+                    "{} {} conflicts with the approved {}:{}",
+                    chain_id,
+                    request.state,
+                    approved,
+                    approved.block_id_prefix()
+                ));
+            }
+        }
+        self.0.insert(chain_id, request.state.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tendermint::{block, hash::Algorithm, Hash};
+
+    fn request(height: u32, round: u16, step: i8, block: Option<u8>) -> ApprovalRequest {
+        let block_id = block.map(|b| block::Id {
+            hash: Hash::from_bytes(Algorithm::Sha256, &[b; 32]).unwrap(),
+            part_set_header: Default::default(),
+        });
+        ApprovalRequest {
+            chain_id: "testchain-1".parse().unwrap(),
+            msg_type: SignedMsgKind::Prevote,
+            state: consensus::State {
+                height: height.into(),
+                round: round.into(),
+                step,
+                block_id,
+            },
+            sign_bytes: vec![height as u8, round as u8, step as u8, block.unwrap_or(0)],
+        }
+    }
+
+    #[test]
+    fn tokens_are_bound_to_the_sign_bytes() {
+        let approver_key = SigningKey::from([3u8; 32]);
+        let token = ApprovalToken::sign(&approver_key, &request(5, 0, 1, Some(1)));
+        let verification_key = approver_key.verification_key();
+        assert!(token
+            .verify(&verification_key, &request(5, 0, 1, Some(1)))
+            .is_ok());
+        assert!(token
+            .verify(&verification_key, &request(5, 0, 1, Some(2)))
+            .is_err());
+        assert!(token
+            .verify(
+                &SigningKey::from([4u8; 32]).verification_key(),
+                &request(5, 0, 1, Some(1))
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn conflicting_approvals_are_refused() {
+        let mut approvals = Approvals::default();
+        assert!(approvals.approve(&request(5, 0, 1, Some(1))).is_ok());
+        // re-requested
+        assert!(approvals.approve(&request(5, 0, 1, Some(1))).is_ok());
+        assert!(approvals.approve(&request(5, 0, 1, Some(2))).is_err());
+        assert!(approvals.approve(&request(4, 0, 2, Some(1))).is_err());
+        assert!(approvals.approve(&request(5, 0, 2, Some(2))).is_ok());
+    }
+}
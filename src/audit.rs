@@ -0,0 +1,219 @@
+//! Audit of the produced signatures
+
+use crate::error::Error;
+use ed25519_consensus::{Signature, SigningKey, VerificationKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tendermint::chain;
+
+/// domain separation of the audit anchors' signatures
+pub const AUDIT_ANCHOR_DOMAIN: &[u8] = b"tmkms-light audit anchor v1";
+
+/// type of the signed message
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignedMsgKind {
+    Proposal,
+    Prevote,
+    Precommit,
+}
+
+impl SignedMsgKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SignedMsgKind::Proposal => "proposal",
+            SignedMsgKind::Prevote => "prevote",
+            SignedMsgKind::Precommit => "precommit",
+        }
+    }
+}
+
+/// a signature produced by the session
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SignedMessage {
+    pub chain_id: chain::Id,
+    pub msg_type: SignedMsgKind,
+    pub height: i64,
+    pub round: i64,
+    /// hash of the signed block ID (if any)
+    pub block_id_hash: Option<Vec<u8>>,
+    pub signature: Vec<u8>,
+    /// when it was signed (by the signer's clock, if it's known)
+    #[serde(default)]
+    pub timestamp: Option<tendermint::Time>,
+}
+
+/// a refused signing request with its canonical sign-bytes
+/// (only recorded if enabled, to diagnose "signature mismatch" or "conflicting vote" disputes)
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RefusedMessage {
+    /// Chain ID of the request (which may differ from the session's)
+    pub chain_id: chain::Id,
+    pub msg_type: SignedMsgKind,
+    pub height: i64,
+    pub round: i64,
+    /// the error returned to the validator
+    pub reason: String,
+    pub sign_bytes: Vec<u8>,
+}
+
+/// hashes a length-prefixed field
+fn update_field(hasher: &mut Sha256, field: &[u8]) {
+    hasher.update((field.len() as u64).to_be_bytes());
+    hasher.update(field);
+}
+
+/// digest of a signature record's content (without the timestamp), as chained by the anchors
+pub fn signed_digest(
+    chain_id: &str,
+    msg_type: &str,
+    height: i64,
+    round: i64,
+    block_id_hash: Option<&[u8]>,
+    signature: &[u8],
+) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    update_field(&mut hasher, b"signed");
+    update_field(&mut hasher, chain_id.as_bytes());
+    update_field(&mut hasher, msg_type.as_bytes());
+    hasher.update(height.to_be_bytes());
+    hasher.update(round.to_be_bytes());
+    match block_id_hash {
+        Some(hash) => {
+            hasher.update([1]);
+            update_field(&mut hasher, hash);
+        }
+        None => hasher.update([0]),
+    }
+    update_field(&mut hasher, signature);
+    hasher.finalize().into()
+}
+
+/// digest of a refusal record's content (without the timestamp), as chained by the anchors
+pub fn refused_digest(
+    chain_id: &str,
+    msg_type: &str,
+    height: i64,
+    round: i64,
+    reason: &str,
+    sign_bytes: &[u8],
+) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    update_field(&mut hasher, b"refused");
+    update_field(&mut hasher, chain_id.as_bytes());
+    update_field(&mut hasher, msg_type.as_bytes());
+    hasher.update(height.to_be_bytes());
+    hasher.update(round.to_be_bytes());
+    update_field(&mut hasher, reason.as_bytes());
+    update_field(&mut hasher, sign_bytes);
+    hasher.finalize().into()
+}
+
+impl SignedMessage {
+    pub fn digest(&self) -> [u8; 32] {
+        signed_digest(
+            self.chain_id.as_str(),
+            self.msg_type.as_str(),
+            self.height,
+            self.round,
+            self.block_id_hash.as_deref(),
+            &self.signature,
+        )
+    }
+}
+
+impl RefusedMessage {
+    pub fn digest(&self) -> [u8; 32] {
+        refused_digest(
+            self.chain_id.as_str(),
+            self.msg_type.as_str(),
+            self.height,
+            self.round,
+            &self.reason,
+            &self.sign_bytes,
+        )
+    }
+}
+
+/// the next head of the signer's chain of the records' digests
+pub fn chain_head(head: &[u8; 32], digest: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(head);
+    hasher.update(digest);
+    hasher.finalize().into()
+}
+
+/// the head of the signer's chain of the records sent since its previous anchor,
+/// signed with the consensus key (so the host can't alter, drop or insert records
+/// without the anchors failing to verify)
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AuditAnchor {
+    /// the head at the previous anchor (zeros for the first one of a connection)
+    pub start: [u8; 32],
+    /// the head after the anchored records
+    pub head: [u8; 32],
+    /// number of the anchored records
+    pub records: u64,
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl AuditAnchor {
+    /// the signed message: it starts with a zero byte like the proofs of possession
+    /// (see `possession`), so it can never be a valid consensus signature, and has its own domain tag
+    fn message(start: &[u8; 32], head: &[u8; 32], records: u64) -> Vec<u8> {
+        let mut message = Vec::with_capacity(1 + AUDIT_ANCHOR_DOMAIN.len() + 72);
+        message.push(0);
+        message.extend_from_slice(AUDIT_ANCHOR_DOMAIN);
+        message.extend_from_slice(start);
+        message.extend_from_slice(head);
+        message.extend_from_slice(&records.to_be_bytes());
+        message
+    }
+
+    pub fn sign(key: &SigningKey, start: [u8; 32], head: [u8; 32], records: u64) -> Self {
+        let signature = key.sign(&Self::message(&start, &head, records));
+        Self {
+            start,
+            head,
+            records,
+            public_key: key.verification_key().to_bytes().to_vec(),
+            signature: signature.to_bytes().to_vec(),
+        }
+    }
+
+    /// checks the anchor's signature with its public key
+    pub fn verify(&self) -> Result<(), String> {
+        let public_key = VerificationKey::try_from(self.public_key.as_slice())
+            .map_err(|e| format!("invalid anchor public key: {}", e))?;
+        let signature = Signature::try_from(self.signature.as_slice())
+            .map_err(|e| format!("invalid anchor signature: {}", e))?;
+        public_key
+            .verify(
+                &signature,
+                &Self::message(&self.start, &self.head, self.records),
+            )
+            .map_err(|_| "the anchor's signature doesn't verify".to_owned())
+    }
+}
+
+/// a record sent to the audit sink
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum AuditEntry {
+    Signed(SignedMessage),
+    Refused(RefusedMessage),
+    /// the signed head of the records sent on the connection since the previous anchor
+    Anchor(AuditAnchor),
+}
+
+/// destination of the signature audit records;
+/// the signature is only sent to the validator after it was recorded
+pub trait AuditSink: Send {
+    fn record(&mut self, msg: &SignedMessage) -> Result<(), Error>;
+
+    /// records a refused request (ignored by default)
+    fn record_refusal(&mut self, _msg: &RefusedMessage) -> Result<(), Error> {
+        Ok(())
+    }
+}
@@ -1,20 +1,75 @@
 //! Copyright (c) 2018-2021 Iqlusion Inc. (licensed under the Apache License, Version 2.0)
 //! Modifications Copyright (c) 2021-present Crypto.com (licensed under the Apache License, Version 2.0)
 
+mod dual;
 mod error;
+mod lease;
+mod mac;
+mod priv_validator;
+mod rollback;
+mod watermark;
+pub use self::dual::{DualStateSync, Durability};
 pub use self::error::{StateError, StateErrorDetail};
+pub use self::lease::{LeaseRequest, LeaseStateSync, Leases};
+pub use self::mac::{MacedState, StateMacKey};
+pub use self::rollback::{
+    AntiRollbackStateSync, MonotonicRequest, MonotonicResponse, MonotonicStates,
+};
+pub use self::watermark::{Watermark, WatermarkRequest, WatermarkStateSync, Watermarks};
+use ed25519_consensus::{Signature, VerificationKey};
 pub use tendermint::consensus;
 use tendermint::{proposal::SignProposalRequest, vote::SignVoteRequest};
 /// State tracking for double signing prevention
 #[derive(Debug, Clone)]
 pub struct State {
     consensus_state: consensus::State,
+    /// the last signed message (restored from the persisted state)
+    last_signed: Option<LastSigned>,
+}
+
+/// The sign bytes and signature of the last signed message
+#[derive(Debug, Clone)]
+struct LastSigned {
+    sign_bytes: Vec<u8>,
+    signature: Signature,
+    /// whether it's in the audit log (unknown for a restored one)
+    audited: bool,
 }
 
 /// State persistence over sockets or files
 pub trait PersistStateSync {
     fn load_state(&mut self) -> Result<State, StateError>;
     fn persist_state(&mut self, new_state: &consensus::State) -> Result<(), StateError>;
+
+    /// persists the state with the sign bytes and the signature of the message signed at it
+    /// (as CometBFT's `priv_validator_state.json` has them); only the state by default
+    fn persist_signed_state(
+        &mut self,
+        new_state: &consensus::State,
+        _sign_bytes: &[u8],
+        _signature: &Signature,
+    ) -> Result<(), StateError> {
+        self.persist_state(new_state)
+    }
+}
+
+impl<T: PersistStateSync + ?Sized> PersistStateSync for Box<T> {
+    fn load_state(&mut self) -> Result<State, StateError> {
+        (**self).load_state()
+    }
+
+    fn persist_state(&mut self, new_state: &consensus::State) -> Result<(), StateError> {
+        (**self).persist_state(new_state)
+    }
+
+    fn persist_signed_state(
+        &mut self,
+        new_state: &consensus::State,
+        sign_bytes: &[u8],
+        signature: &Signature,
+    ) -> Result<(), StateError> {
+        (**self).persist_signed_state(new_state, sign_bytes, signature)
+    }
 }
 
 impl State {
@@ -98,13 +153,110 @@ impl State {
         self.check_consensus_state(&new_state)?;
         syncer.persist_state(&new_state)?;
         self.consensus_state = new_state;
+        self.last_signed = None;
+        Ok(())
+    }
+
+    /// Update the state + check, persisting it with the message signed at it
+    /// (the signature is only to be released once this succeeded, and only to be
+    /// returned for a re-request once it's recorded with `record_signature`)
+    pub fn check_update_signed_consensus_state<S: PersistStateSync>(
+        &mut self,
+        new_state: consensus::State,
+        syncer: &mut S,
+        sign_bytes: &[u8],
+        signature: &Signature,
+    ) -> Result<(), StateError> {
+        self.check_consensus_state(&new_state)?;
+        syncer.persist_signed_state(&new_state, sign_bytes, signature)?;
+        self.consensus_state = new_state;
+        self.last_signed = None;
+        Ok(())
+    }
+
+    /// Update the state + check without persisting it (the dry-run mode)
+    pub fn simulate_update_consensus_state(
+        &mut self,
+        new_state: consensus::State,
+    ) -> Result<(), StateError> {
+        self.check_consensus_state(&new_state)?;
+        self.consensus_state = new_state;
+        self.last_signed = None;
         Ok(())
     }
+
+    /// the signature of the last signed message if it's re-requested
+    /// (the same state and sign bytes, e.g. after the validator reconnected),
+    /// and whether it's in the audit log
+    pub fn cached_signature(
+        &self,
+        new_state: &consensus::State,
+        sign_bytes: &[u8],
+    ) -> Option<(Signature, bool)> {
+        match &self.last_signed {
+            Some(last) if new_state == &self.consensus_state && last.sign_bytes == sign_bytes => {
+                Some((last.signature, last.audited))
+            }
+            _ => None,
+        }
+    }
+
+    /// records the message signed at the current state (once it's in the audit log)
+    pub fn record_signature(&mut self, sign_bytes: Vec<u8>, signature: Signature) {
+        self.last_signed = Some(LastSigned {
+            sign_bytes,
+            signature,
+            audited: true,
+        });
+    }
+
+    /// marks the (restored) last signed message as recorded in the audit log
+    pub fn mark_audited(&mut self) {
+        if let Some(last) = self.last_signed.as_mut() {
+            last.audited = true;
+        }
+    }
+
+    /// drops the restored last signed message unless its signature is the key's
+    /// (the sign bytes and the signature aren't covered by the state's MAC)
+    pub fn verify_last_signed(&mut self, public_key: &VerificationKey) {
+        if let Some(last) = &self.last_signed {
+            if public_key
+                .verify(&last.signature, &last.sign_bytes)
+                .is_err()
+            {
+                self.last_signed = None;
+            }
+        }
+    }
 }
 
 impl From<consensus::State> for State {
     fn from(consensus_state: consensus::State) -> Self {
-        Self { consensus_state }
+        Self {
+            consensus_state,
+            last_signed: None,
+        }
+    }
+}
+
+/// the persisted state with the message signed at it (if any)
+impl From<MacedState> for State {
+    fn from(maced: MacedState) -> Self {
+        let last_signed = match (maced.signbytes, maced.signature) {
+            (Some(sign_bytes), Some(signature)) => Signature::try_from(signature.as_slice())
+                .ok()
+                .map(|signature| LastSigned {
+                    sign_bytes,
+                    signature,
+                    audited: false,
+                }),
+            _ => None,
+        };
+        Self {
+            consensus_state: maced.state,
+            last_signed,
+        }
     }
 }
 
@@ -117,6 +269,7 @@ impl From<SignProposalRequest> for State {
                 step: 0,
                 block_id: req.proposal.block_id,
             },
+            last_signed: None,
         }
     }
 }
@@ -130,6 +283,7 @@ impl From<SignVoteRequest> for State {
                 step: if req.vote.is_precommit() { 2 } else { 1 },
                 block_id: req.vote.block_id,
             },
+            last_signed: None,
         }
     }
 }
@@ -145,6 +299,18 @@ mod tests {
     const EXAMPLE_DOUBLE_SIGN_BLOCK_ID: &str =
         "2470A41F3243C6BCD7AD2DFF8A8D83A71D29D307B5326C227F734A1A512FE47D";
 
+    struct NoopSync;
+
+    impl PersistStateSync for NoopSync {
+        fn load_state(&mut self) -> Result<State, StateError> {
+            Ok(State::from(consensus::State::default()))
+        }
+
+        fn persist_state(&mut self, _new_state: &consensus::State) -> Result<(), StateError> {
+            Ok(())
+        }
+    }
+
     /// Macro for compactly expressing a consensus state
     macro_rules! state {
         ($height:expr, $round:expr, $step:expr, $block_id:expr) => {
@@ -169,11 +335,9 @@ mod tests {
         ($name:ident, $old_state:expr, $new_state:expr) => {
             #[test]
             fn $name() {
-                State {
-                    consensus_state: $old_state,
-                }
-                .check_consensus_state(&$new_state)
-                .unwrap();
+                State::from($old_state)
+                    .check_consensus_state(&$new_state)
+                    .unwrap();
             }
         };
     }
@@ -183,11 +347,9 @@ mod tests {
         ($name:ident, $old_state:expr, $new_state:expr) => {
             #[test]
             fn $name() {
-                let err = State {
-                    consensus_state: $old_state,
-                }
-                .check_consensus_state(&$new_state)
-                .expect_err("expected StateErrorKind::DoubleSign but succeeded");
+                let err = State::from($old_state)
+                    .check_consensus_state(&$new_state)
+                    .expect_err("expected StateErrorKind::DoubleSign but succeeded");
 
                 assert!(matches!(
                     err,
@@ -238,4 +400,48 @@ mod tests {
         state!(1, 1, 2, None),
         state!(1, 1, 2, block_id!(EXAMPLE_BLOCK_ID))
     );
+
+    #[test]
+    fn returns_cached_signature_for_rerequested_message() {
+        let new_state = state!(1, 0, 1, block_id!(EXAMPLE_BLOCK_ID));
+        let mut state = State::from(state!(1, 0, 0, None));
+        state
+            .check_update_consensus_state(new_state.clone(), &mut NoopSync)
+            .unwrap();
+        let signature = Signature::from([1u8; 64]);
+        state.record_signature(b"vote".to_vec(), signature);
+        assert_eq!(
+            state.cached_signature(&new_state, b"vote"),
+            Some((signature, true))
+        );
+        assert_eq!(state.cached_signature(&new_state, b"other vote"), None);
+        let next_state = state!(1, 0, 2, block_id!(EXAMPLE_BLOCK_ID));
+        assert_eq!(state.cached_signature(&next_state, b"vote"), None);
+    }
+
+    #[test]
+    fn restores_the_persisted_signature() {
+        let signing_key = ed25519_consensus::SigningKey::from([7u8; 32]);
+        let public_key = signing_key.verification_key();
+        let signed_state = state!(1, 0, 1, block_id!(EXAMPLE_BLOCK_ID));
+        let signature = signing_key.sign(b"vote");
+        let maced = MacedState::from(signed_state.clone()).with_message(b"vote", &signature);
+        let mut state = State::from(maced.clone());
+        state.verify_last_signed(&public_key);
+        // it may not have been audited before the restart
+        assert_eq!(
+            state.cached_signature(&signed_state, b"vote"),
+            Some((signature, false))
+        );
+        state.mark_audited();
+        assert_eq!(
+            state.cached_signature(&signed_state, b"vote"),
+            Some((signature, true))
+        );
+        // a signature the host made up is dropped
+        let forged = maced.with_message(b"vote", &Signature::from([1u8; 64]));
+        let mut state = State::from(forged);
+        state.verify_last_signed(&public_key);
+        assert_eq!(state.cached_signature(&signed_state, b"vote"), None);
+    }
 }
@@ -0,0 +1,135 @@
+use super::{consensus, PersistStateSync, State, StateError};
+use ed25519_consensus::Signature;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// How many state sinks need to succeed for a state update to count as durable
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    /// at least one of the sinks
+    Any,
+    /// all the sinks
+    #[default]
+    All,
+}
+
+/// Persists the state to two sinks (e.g. the host and an external store)
+pub struct DualStateSync<P: PersistStateSync, S: PersistStateSync> {
+    primary: P,
+    secondary: S,
+    durability: Durability,
+}
+
+impl<P: PersistStateSync, S: PersistStateSync> DualStateSync<P, S> {
+    pub fn new(primary: P, secondary: S, durability: Durability) -> Self {
+        Self {
+            primary,
+            secondary,
+            durability,
+        }
+    }
+
+    /// combines the results of the two sinks according to the durability
+    fn combine<T>(
+        &self,
+        primary: Result<T, StateError>,
+        secondary: Result<T, StateError>,
+    ) -> Result<(Option<T>, Option<T>), StateError> {
+        match (primary, secondary, self.durability) {
+            (Ok(p), Ok(s), _) => Ok((Some(p), Some(s))),
+            (Ok(p), Err(e), Durability::Any) => {
+                warn!("secondary state sink failed: {}", e);
+                Ok((Some(p), None))
+            }
+            (Err(e), Ok(s), Durability::Any) => {
+                warn!("primary state sink failed: {}", e);
+                Ok((None, Some(s)))
+            }
+            (Err(e), _, _) | (_, Err(e), Durability::All) => Err(e),
+        }
+    }
+}
+
+impl<P: PersistStateSync, S: PersistStateSync> PersistStateSync for DualStateSync<P, S> {
+    /// loads the states of both sinks and continues from the more advanced one;
+    /// both are required whatever the durability (with `any`, the latest updates may only
+    /// be in the sink that is down, so continuing from the other one could double sign)
+    fn load_state(&mut self) -> Result<State, StateError> {
+        let p = self.primary.load_state()?;
+        let s = self.secondary.load_state()?;
+        let (pc, sc) = (p.consensus_state(), s.consensus_state());
+        if (sc.height, sc.round, sc.step) > (pc.height, pc.round, pc.step) {
+            Ok(s)
+        } else {
+            Ok(p)
+        }
+    }
+
+    fn persist_state(&mut self, new_state: &consensus::State) -> Result<(), StateError> {
+        let primary = self.primary.persist_state(new_state);
+        let secondary = self.secondary.persist_state(new_state);
+        self.combine(primary, secondary).map(|_| ())
+    }
+
+    fn persist_signed_state(
+        &mut self,
+        new_state: &consensus::State,
+        sign_bytes: &[u8],
+        signature: &Signature,
+    ) -> Result<(), StateError> {
+        let primary = self
+            .primary
+            .persist_signed_state(new_state, sign_bytes, signature);
+        let secondary = self
+            .secondary
+            .persist_signed_state(new_state, sign_bytes, signature);
+        self.combine(primary, secondary).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Sink(bool);
+
+    impl PersistStateSync for Sink {
+        fn load_state(&mut self) -> Result<State, StateError> {
+            if self.0 {
+                Ok(State::from(consensus::State::default()))
+            } else {
+                Err(StateError::sync_other_error("down".into()))
+            }
+        }
+
+        fn persist_state(&mut self, _new_state: &consensus::State) -> Result<(), StateError> {
+            if self.0 {
+                Ok(())
+            } else {
+                Err(StateError::sync_other_error("down".into()))
+            }
+        }
+    }
+
+    #[test]
+    fn durability_modes() {
+        let state = consensus::State::default();
+        let mut any = DualStateSync::new(Sink(true), Sink(false), Durability::Any);
+        assert!(any.persist_state(&state).is_ok());
+        let mut all = DualStateSync::new(Sink(true), Sink(false), Durability::All);
+        assert!(all.persist_state(&state).is_err());
+        let mut none = DualStateSync::new(Sink(false), Sink(false), Durability::Any);
+        assert!(none.persist_state(&state).is_err());
+    }
+
+    #[test]
+    fn loading_needs_both_sinks() {
+        let mut any = DualStateSync::new(Sink(true), Sink(false), Durability::Any);
+        assert!(any.load_state().is_err());
+        let mut any = DualStateSync::new(Sink(false), Sink(true), Durability::Any);
+        assert!(any.load_state().is_err());
+        let mut both = DualStateSync::new(Sink(true), Sink(true), Durability::Any);
+        assert!(both.load_state().is_ok());
+    }
+}
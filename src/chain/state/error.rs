@@ -55,5 +55,12 @@ define_error! {
         } |e| {
             format_args!("Error state syncing: {}", e.error_message)
         },
+        InvalidStateMac
+        |_| { "invalid state MAC (the persisted state may have been tampered with)" },
+        UnauthenticatedState{
+            height: Height,
+        } |e| {
+            format_args!("the persisted state at height {} has no MAC", e.height)
+        },
     }
 }
@@ -0,0 +1,130 @@
+use super::{consensus, PersistStateSync, State, StateError};
+use crate::utils::{read_u16_payload, write_u16_payload};
+use ed25519_consensus::Signature;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// A request to acquire or renew a lease
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LeaseRequest {
+    /// the lease name (e.g. the chain id)
+    pub name: String,
+    /// the signer instance requesting it
+    pub holder_id: String,
+    pub duration_secs: u64,
+}
+
+struct Lease {
+    holder_id: String,
+    expires: Instant,
+}
+
+/// Leases kept by the lease service (in memory)
+#[derive(Default)]
+pub struct Leases(HashMap<String, Lease>);
+
+impl Leases {
+    /// grants the lease if it's free, expired or already held by the requester
+    pub fn acquire(&mut self, request: LeaseRequest, now: Instant) -> Result<(), String> {
+        if let Some(lease) = self.0.get(&request.name) {
+            if lease.holder_id != request.holder_id && lease.expires > now {
+                return Err(format!(
+                    "{} lease is held by {} for {:?}",
+                    request.name,
+                    lease.holder_id,
+                    lease.expires - now
+                ));
+            }
+        }
+        self.0.insert(
+            request.name,
+            Lease {
+                holder_id: request.holder_id,
+                expires: now + Duration::from_secs(request.duration_secs),
+            },
+        );
+        Ok(())
+    }
+}
+
+/// Checks that the signer holds the lease (as relayed by the host)
+/// before persisting each state update with the inner syncer
+pub struct LeaseStateSync<S: PersistStateSync, T: io::Read + io::Write> {
+    inner: S,
+    conn: T,
+}
+
+impl<S: PersistStateSync, T: io::Read + io::Write> LeaseStateSync<S, T> {
+    pub fn new(inner: S, conn: T) -> Self {
+        Self { inner, conn }
+    }
+
+    fn check_lease(&mut self, new_state: &consensus::State) -> Result<(), StateError> {
+        let json_raw = serde_json::to_vec(new_state)
+            .map_err(|e| StateError::sync_enc_dec_error("lease".into(), e))?;
+        write_u16_payload(&mut self.conn, &json_raw)
+            .map_err(|e| StateError::sync_error("lease".into(), e))?;
+        let ack_raw = read_u16_payload(&mut self.conn)
+            .map_err(|e| StateError::sync_other_error(e.to_string()))?;
+        let ack: Result<(), String> = serde_json::from_slice(&ack_raw)
+            .map_err(|e| StateError::sync_enc_dec_error("lease".into(), e))?;
+        ack.map_err(StateError::sync_other_error)?;
+        debug!("lease held");
+        Ok(())
+    }
+}
+
+impl<S: PersistStateSync, T: io::Read + io::Write> PersistStateSync for LeaseStateSync<S, T> {
+    fn load_state(&mut self) -> Result<State, StateError> {
+        self.inner.load_state()
+    }
+
+    fn persist_state(&mut self, new_state: &consensus::State) -> Result<(), StateError> {
+        self.check_lease(new_state)?;
+        self.inner.persist_state(new_state)
+    }
+
+    fn persist_signed_state(
+        &mut self,
+        new_state: &consensus::State,
+        sign_bytes: &[u8],
+        signature: &Signature,
+    ) -> Result<(), StateError> {
+        self.check_lease(new_state)?;
+        self.inner
+            .persist_signed_state(new_state, sign_bytes, signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(holder_id: &str) -> LeaseRequest {
+        LeaseRequest {
+            name: "testchain-1".into(),
+            holder_id: holder_id.into(),
+            duration_secs: 10,
+        }
+    }
+
+    #[test]
+    fn single_holder_until_expiry() {
+        let mut leases = Leases::default();
+        let now = Instant::now();
+        assert!(leases.acquire(request("a"), now).is_ok());
+        assert!(leases.acquire(request("b"), now).is_err());
+        assert!(leases
+            .acquire(request("a"), now + Duration::from_secs(5))
+            .is_ok());
+        assert!(leases
+            .acquire(request("b"), now + Duration::from_secs(14))
+            .is_err());
+        assert!(leases
+            .acquire(request("b"), now + Duration::from_secs(15))
+            .is_ok());
+    }
+}
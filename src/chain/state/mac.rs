@@ -0,0 +1,144 @@
+use super::{consensus, priv_validator::PrivValidatorState, StateError};
+use ed25519_consensus::{Signature, SigningKey};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fmt;
+use tendermint::chain;
+use zeroize::Zeroizing;
+
+/// The consensus state with the signer's MAC (as persisted on the host,
+/// in CometBFT's `priv_validator_state.json` schema)
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "PrivValidatorState", into = "PrivValidatorState")]
+pub struct MacedState {
+    pub state: consensus::State,
+    /// the sign bytes of the last signed message
+    pub signbytes: Option<Vec<u8>>,
+    /// the signature of the last signed message
+    pub signature: Option<Vec<u8>>,
+    /// hex-encoded HMAC-SHA256 of the state
+    pub mac: Option<String>,
+}
+
+impl MacedState {
+    /// the state before anything was signed (as written for a new chain)
+    pub fn is_initial(&self) -> bool {
+        self.state.height.value() == 0
+            && self.state.round.value() == 0
+            && self.state.step == 0
+            && self.state.block_id.is_none()
+            && self.signbytes.is_none()
+            && self.signature.is_none()
+    }
+
+    /// with the sign bytes and the signature of the message signed at the state
+    pub fn with_message(mut self, sign_bytes: &[u8], signature: &Signature) -> Self {
+        self.signbytes = Some(sign_bytes.to_vec());
+        self.signature = Some(signature.to_bytes().to_vec());
+        self
+    }
+}
+
+impl From<consensus::State> for MacedState {
+    fn from(state: consensus::State) -> Self {
+        Self {
+            state,
+            ..Default::default()
+        }
+    }
+}
+
+/// The key to authenticate the persisted state with
+/// (derived from the consensus key, so the host can't forge or alter the state)
+#[derive(Clone)]
+pub struct StateMacKey(Zeroizing<[u8; 32]>);
+
+impl fmt::Debug for StateMacKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StateMacKey")
+    }
+}
+
+impl StateMacKey {
+    /// derives the key of the chain's state
+    pub fn new(consensus_key: &SigningKey, chain_id: &chain::Id) -> Self {
+        let seed = Zeroizing::new(consensus_key.to_bytes());
+        let hkdf = Hkdf::<Sha256>::new(Some(b"tmkms-light state mac"), seed.as_ref());
+        let mut key = Zeroizing::new([0u8; 32]);
+        hkdf.expand(chain_id.as_str().as_bytes(), key.as_mut())
+            .expect("valid HKDF output length");
+        Self(key)
+    }
+
+    fn hmac(&self, state: &consensus::State) -> Result<Hmac<Sha256>, StateError> {
+        let json_raw = serde_json::to_vec(state)
+            .map_err(|e| StateError::sync_enc_dec_error("state mac".into(), e))?;
+        let mut hmac =
+            Hmac::<Sha256>::new_from_slice(self.0.as_ref()).expect("HMAC accepts any key length");
+        hmac.update(&json_raw);
+        Ok(hmac)
+    }
+
+    /// the state with its MAC
+    pub fn sign(&self, state: &consensus::State) -> Result<MacedState, StateError> {
+        let tag = self.hmac(state)?.finalize().into_bytes();
+        Ok(MacedState {
+            state: state.clone(),
+            mac: Some(String::from_utf8(subtle_encoding::hex::encode(tag)).unwrap()),
+            ..Default::default()
+        })
+    }
+
+    /// checks the state's MAC (only the initial state, before anything was signed, has none;
+    /// an older state needs to be authenticated by the signer in a separate, attested step)
+    pub fn verify(&self, maced: &MacedState) -> Result<(), StateError> {
+        match &maced.mac {
+            Some(mac) => {
+                let tag = subtle_encoding::hex::decode(mac)
+                    .map_err(|_| StateError::invalid_state_mac())?;
+                self.hmac(&maced.state)?
+                    .verify_slice(&tag)
+                    .map_err(|_| StateError::invalid_state_mac())
+            }
+            None if maced.is_initial() => Ok(()),
+            None => Err(StateError::unauthenticated_state(maced.state.height)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use tendermint::block;
+
+    #[test]
+    fn detects_tampering() {
+        let key = StateMacKey::new(
+            &SigningKey::from([1u8; 32]),
+            &chain::Id::try_from("testchain-1").unwrap(),
+        );
+        let state = consensus::State {
+            height: block::Height::from(10u32),
+            ..Default::default()
+        };
+        let maced = key.sign(&state).unwrap();
+        let json = serde_json::to_string(&maced).unwrap();
+        let parsed: MacedState = serde_json::from_str(&json).unwrap();
+        assert!(key.verify(&parsed).is_ok());
+        let rolled_back = MacedState {
+            state: consensus::State::default(),
+            mac: parsed.mac,
+            ..Default::default()
+        };
+        assert!(key.verify(&rolled_back).is_err());
+        assert!(key.verify(&MacedState::from(state)).is_err());
+        let initial = consensus::State {
+            height: block::Height::from(0u32),
+            ..Default::default()
+        };
+        assert!(key.verify(&MacedState::from(initial)).is_ok());
+    }
+}
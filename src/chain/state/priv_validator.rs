@@ -0,0 +1,167 @@
+use super::{consensus, MacedState};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use tendermint::block;
+
+/// `round` is an `int32` in CometBFT (a number), but it used to be written as a string
+/// (with tmkms-light's own step numbers)
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum RoundRepr {
+    Number(u32),
+    String(String),
+}
+
+impl RoundRepr {
+    fn round(&self) -> Result<block::Round, String> {
+        let round = match self {
+            RoundRepr::Number(round) => *round,
+            RoundRepr::String(round) => {
+                round.parse().map_err(|e| format!("invalid round: {}", e))?
+            }
+        };
+        block::Round::try_from(round).map_err(|e| format!("invalid round: {}", e))
+    }
+}
+
+/// CometBFT's step of tmkms-light's one (proposal = 0, prevote = 1, precommit = 2):
+/// propose = 1, prevote = 2, precommit = 3 (and 0 before anything was signed)
+fn cometbft_step(state: &consensus::State) -> i8 {
+    if state.height.value() == 0 {
+        0
+    } else {
+        state.step + 1
+    }
+}
+
+/// tmkms-light's step of CometBFT's one
+fn tmkms_step(step: i8) -> Result<i8, String> {
+    match step {
+        0 => Ok(0),
+        1..=3 => Ok(step - 1),
+        _ => Err(format!("invalid step: {}", step)),
+    }
+}
+
+/// The state file in CometBFT's `priv_validator_state.json` schema
+/// (`signature` is base64-encoded and `signbytes` hex-encoded),
+/// with tmkms-light's `block_id` and `mac` as extra fields (ignored by CometBFT)
+#[derive(Deserialize, Serialize)]
+pub(super) struct PrivValidatorState {
+    height: block::Height,
+    round: RoundRepr,
+    step: i8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signbytes: Option<String>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "tendermint_proto::serializers::optional"
+    )]
+    block_id: Option<block::Id>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mac: Option<String>,
+}
+
+impl TryFrom<PrivValidatorState> for MacedState {
+    type Error = String;
+
+    fn try_from(file: PrivValidatorState) -> Result<Self, Self::Error> {
+        let signature = file
+            .signature
+            .map(subtle_encoding::base64::decode)
+            .transpose()
+            .map_err(|e| format!("invalid signature: {}", e))?;
+        let signbytes = file
+            .signbytes
+            .map(|bytes| subtle_encoding::hex::decode_upper(bytes.to_ascii_uppercase()))
+            .transpose()
+            .map_err(|e| format!("invalid signbytes: {}", e))?;
+        let step = match file.round {
+            RoundRepr::Number(_) => tmkms_step(file.step)?,
+            RoundRepr::String(_) => file.step,
+        };
+        Ok(MacedState {
+            state: consensus::State {
+                height: file.height,
+                round: file.round.round()?,
+                step,
+                block_id: file.block_id,
+            },
+            signbytes,
+            signature,
+            mac: file.mac,
+        })
+    }
+}
+
+impl From<MacedState> for PrivValidatorState {
+    fn from(maced: MacedState) -> Self {
+        Self {
+            height: maced.state.height,
+            round: RoundRepr::Number(maced.state.round.value()),
+            step: cometbft_step(&maced.state),
+            signature: maced
+                .signature
+                .map(|sig| String::from_utf8(subtle_encoding::base64::encode(sig)).unwrap()),
+            signbytes: maced
+                .signbytes
+                .map(|bytes| String::from_utf8(subtle_encoding::hex::encode_upper(bytes)).unwrap()),
+            block_id: maced.state.block_id,
+            mac: maced.mac,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a `priv_validator_state.json` written by CometBFT after a precommit
+    const COMETBFT_STATE: &str = r#"{
+  "height": "1203",
+  "round": 0,
+  "step": 3,
+  "signature": "t4I9WbGsgeLRHwhrQLQfxP/mnXHTSrHo76mWbVO4QC84+3oKZ7MnCTjwrGQmt8Opfxs0Iahb06EnfCl+NZvrCA==",
+  "signbytes": "25080211B3040000000000002A0B088AC0B9A50610B8DDDF34320B6D792D636861696E2D3130"
+}"#;
+
+    #[test]
+    fn maps_cometbft_state() {
+        let maced: MacedState = serde_json::from_str(COMETBFT_STATE).unwrap();
+        assert_eq!(maced.state.height, block::Height::from(1203u32));
+        assert_eq!(maced.state.round, block::Round::from(0u16));
+        // precommit
+        assert_eq!(maced.state.step, 2);
+        assert_eq!(maced.signature.as_ref().map(Vec::len), Some(64));
+        assert_eq!(maced.signbytes.as_ref().map(Vec::len), Some(38));
+        let written: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&maced).unwrap()).unwrap();
+        let original: serde_json::Value = serde_json::from_str(COMETBFT_STATE).unwrap();
+        assert_eq!(written, original);
+        // the initial state
+        let initial = r#"{"height":"0","round":0,"step":0}"#;
+        let maced: MacedState = serde_json::from_str(initial).unwrap();
+        assert_eq!(maced.state.step, 0);
+        assert_eq!(serde_json::to_string(&maced).unwrap(), initial);
+        // a proposal
+        let maced: MacedState =
+            serde_json::from_str(r#"{"height":"5","round":2,"step":1}"#).unwrap();
+        assert_eq!(maced.state.step, 0);
+        assert!(
+            serde_json::from_str::<MacedState>(r#"{"height":"5","round":0,"step":4}"#).is_err()
+        );
+        // the previous format (with tmkms-light's steps)
+        let maced: MacedState =
+            serde_json::from_str(r#"{"height":"10","round":"1","step":2,"block_id":null}"#)
+                .unwrap();
+        assert_eq!(maced.state.round, block::Round::from(1u16));
+        assert_eq!(maced.state.step, 2);
+        assert_eq!(
+            serde_json::to_string(&maced).unwrap(),
+            r#"{"height":"10","round":1,"step":3}"#
+        );
+    }
+}
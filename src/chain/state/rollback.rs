@@ -0,0 +1,366 @@
+use super::{consensus, MacedState, PersistStateSync, State, StateError, StateMacKey};
+use crate::utils::{read_u16_payload, write_u16_payload};
+use ed25519_consensus::{Signature, SigningKey, VerificationKey};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use tracing::{debug, warn};
+
+/// domain separation of the monotonic state service's responses
+const MONOTONIC_DOMAIN: &[u8] = b"tmkms-monotonic-v1";
+
+/// A request to the monotonic state service
+/// (the nonce, hex-encoded, is chosen by the signer so the response can't be replayed)
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum MonotonicRequest {
+    /// the latest state of the chain
+    Read { chain_id: String, nonce: String },
+    /// raises the chain's state (it can't go back)
+    Advance {
+        chain_id: String,
+        state: MacedState,
+        nonce: String,
+    },
+}
+
+impl MonotonicRequest {
+    fn chain_id(&self) -> &str {
+        match self {
+            MonotonicRequest::Read { chain_id, .. } => chain_id,
+            MonotonicRequest::Advance { chain_id, .. } => chain_id,
+        }
+    }
+
+    fn nonce(&self) -> &str {
+        match self {
+            MonotonicRequest::Read { nonce, .. } => nonce,
+            MonotonicRequest::Advance { nonce, .. } => nonce,
+        }
+    }
+}
+
+/// The chain's latest state, signed by the monotonic state service for the request's nonce
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MonotonicResponse {
+    pub chain_id: String,
+    pub state: Option<MacedState>,
+    pub nonce: String,
+    /// the service's signature (base64-encoded)
+    pub signature: String,
+}
+
+/// what the service signs
+fn response_message(
+    chain_id: &str,
+    state: &Option<MacedState>,
+    nonce: &str,
+) -> Result<Vec<u8>, String> {
+    let json = serde_json::to_vec(&(chain_id, state, nonce)).map_err(|e| format!("{:?}", e))?;
+    let mut message = Vec::with_capacity(MONOTONIC_DOMAIN.len() + json.len());
+    message.extend_from_slice(MONOTONIC_DOMAIN);
+    message.extend_from_slice(&json);
+    Ok(message)
+}
+
+impl MonotonicResponse {
+    /// the response to the request with the service's key
+    pub fn sign(
+        service_key: &SigningKey,
+        request: &MonotonicRequest,
+        state: Option<MacedState>,
+    ) -> Result<Self, String> {
+        let chain_id = request.chain_id().to_owned();
+        let nonce = request.nonce().to_owned();
+        let signature = service_key.sign(&response_message(&chain_id, &state, &nonce)?);
+        Ok(Self {
+            chain_id,
+            state,
+            nonce,
+            signature: String::from_utf8(subtle_encoding::base64::encode(signature.to_bytes()))
+                .unwrap(),
+        })
+    }
+
+    /// checks the response is the service's one to the request and returns the state
+    pub fn verify(
+        self,
+        service_key: &VerificationKey,
+        request: &MonotonicRequest,
+    ) -> Result<Option<MacedState>, String> {
+        if self.chain_id != request.chain_id() || self.nonce != request.nonce() {
+            return Err("the monotonic state response isn't for the request".to_owned());
+        }
+        let signature = subtle_encoding::base64::decode(&self.signature)
+            .ok()
+            .and_then(|signature| Signature::try_from(signature.as_slice()).ok())
+            .ok_or_else(|| "invalid monotonic state response signature".to_owned())?;
+        service_key
+            .verify(
+                &signature,
+                &response_message(&self.chain_id, &self.state, &self.nonce)?,
+            )
+            .map_err(|_| "the monotonic state response isn't signed by the service".to_owned())?;
+        Ok(self.state)
+    }
+}
+
+/// The latest (authenticated) states of all chains (as kept by the monotonic state service)
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct MonotonicStates(BTreeMap<String, MacedState>);
+
+impl MonotonicStates {
+    /// returns the chain's latest state (after the advance, if requested)
+    pub fn handle(&mut self, request: MonotonicRequest) -> Result<Option<MacedState>, String> {
+        match request {
+            MonotonicRequest::Read { chain_id, .. } => Ok(self.0.get(&chain_id).cloned()),
+            MonotonicRequest::Advance {
+                chain_id, state, ..
+            } => {
+                if let Some(current) = self.0.get(&chain_id) {
+                    if state.state < current.state {
+                        return Err(format!(
+                            "{} state can't go back from {} to {}",
+                            chain_id, current.state, state.state
+                        ));
+                    }
+                }
+                self.0.insert(chain_id, state.clone());
+                Ok(Some(state))
+            }
+        }
+    }
+}
+
+/// Keeps the latest state in a monotonic state service (outside of the host),
+/// so a restored older state file is detected (and superseded) at startup;
+/// the service's responses are signed for the signer's nonces, so the host can't forge
+/// or replay them
+pub struct AntiRollbackStateSync<S: PersistStateSync, T: io::Read + io::Write> {
+    inner: S,
+    conn: T,
+    chain_id: String,
+    mac_key: StateMacKey,
+    service_key: VerificationKey,
+}
+
+/// a fresh (hex-encoded) nonce of a request
+fn nonce() -> String {
+    let mut nonce = [0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    String::from_utf8(subtle_encoding::hex::encode(nonce)).unwrap()
+}
+
+impl<S: PersistStateSync, T: io::Read + io::Write> AntiRollbackStateSync<S, T> {
+    pub fn new(
+        inner: S,
+        conn: T,
+        chain_id: String,
+        mac_key: StateMacKey,
+        service_key: VerificationKey,
+    ) -> Self {
+        Self {
+            inner,
+            conn,
+            chain_id,
+            mac_key,
+            service_key,
+        }
+    }
+
+    fn request(&mut self, request: &MonotonicRequest) -> Result<Option<MacedState>, StateError> {
+        let json_raw = serde_json::to_vec(request)
+            .map_err(|e| StateError::sync_enc_dec_error("monotonic".into(), e))?;
+        write_u16_payload(&mut self.conn, &json_raw)
+            .map_err(|e| StateError::sync_error("monotonic".into(), e))?;
+        let response_raw = read_u16_payload(&mut self.conn)
+            .map_err(|e| StateError::sync_other_error(e.to_string()))?;
+        let response: Result<MonotonicResponse, String> = serde_json::from_slice(&response_raw)
+            .map_err(|e| StateError::sync_enc_dec_error("monotonic".into(), e))?;
+        response
+            .and_then(|response| response.verify(&self.service_key, request))
+            .map_err(StateError::sync_other_error)
+    }
+
+    /// advances the monotonic state (before the state is persisted)
+    fn advance(&mut self, new_state: &consensus::State) -> Result<(), StateError> {
+        let request = MonotonicRequest::Advance {
+            chain_id: self.chain_id.clone(),
+            state: self.mac_key.sign(new_state)?,
+            nonce: nonce(),
+        };
+        match self.request(&request)? {
+            Some(advanced) if &advanced.state >= new_state => {
+                debug!("monotonic state advanced");
+                Ok(())
+            }
+            _ => Err(StateError::sync_other_error(
+                "the monotonic state service didn't advance the state".into(),
+            )),
+        }
+    }
+}
+
+impl<S: PersistStateSync, T: io::Read + io::Write> PersistStateSync
+    for AntiRollbackStateSync<S, T>
+{
+    /// loads the state and continues from the monotonic one if it's more advanced
+    /// (the service needs to have the state of a chain that signed before)
+    fn load_state(&mut self) -> Result<State, StateError> {
+        let state = self.inner.load_state()?;
+        let latest = self.request(&MonotonicRequest::Read {
+            chain_id: self.chain_id.clone(),
+            nonce: nonce(),
+        })?;
+        match latest {
+            Some(latest) => {
+                self.mac_key.verify(&latest)?;
+                if &latest.state > state.consensus_state() {
+                    warn!(
+                        "the persisted state ({}) is behind the monotonic one ({}): it was rolled back",
+                        state.consensus_state(),
+                        latest.state
+                    );
+                    Ok(State::from(latest))
+                } else {
+                    Ok(state)
+                }
+            }
+            None if state.consensus_state().height.value() == 0 => Ok(state),
+            None => Err(StateError::sync_other_error(format!(
+                "the monotonic state service has no state of {} (which is at {})",
+                self.chain_id,
+                state.consensus_state()
+            ))),
+        }
+    }
+
+    /// advances the monotonic state before persisting it
+    fn persist_state(&mut self, new_state: &consensus::State) -> Result<(), StateError> {
+        self.advance(new_state)?;
+        self.inner.persist_state(new_state)
+    }
+
+    fn persist_signed_state(
+        &mut self,
+        new_state: &consensus::State,
+        sign_bytes: &[u8],
+        signature: &Signature,
+    ) -> Result<(), StateError> {
+        self.advance(new_state)?;
+        self.inner
+            .persist_signed_state(new_state, sign_bytes, signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_consensus::SigningKey;
+    use std::convert::TryFrom;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use tendermint::{block, chain};
+
+    struct Sink(consensus::State);
+
+    impl PersistStateSync for Sink {
+        fn load_state(&mut self) -> Result<State, StateError> {
+            Ok(State::from(self.0.clone()))
+        }
+
+        fn persist_state(&mut self, new_state: &consensus::State) -> Result<(), StateError> {
+            self.0 = new_state.clone();
+            Ok(())
+        }
+    }
+
+    fn state(height: u32) -> consensus::State {
+        consensus::State {
+            height: block::Height::from(height),
+            ..Default::default()
+        }
+    }
+
+    fn serve(
+        states: &mut MonotonicStates,
+        service_key: &SigningKey,
+        service_conn: &mut UnixStream,
+        replay: Option<&MonotonicResponse>,
+    ) -> MonotonicResponse {
+        let request = read_u16_payload(service_conn).unwrap();
+        let request: MonotonicRequest = serde_json::from_slice(&request).unwrap();
+        let state = states.handle(request.clone()).unwrap();
+        let response = MonotonicResponse::sign(service_key, &request, state).unwrap();
+        let sent: Result<_, String> = Ok(replay.unwrap_or(&response));
+        write_u16_payload(service_conn, &serde_json::to_vec(&sent).unwrap()).unwrap();
+        response
+    }
+
+    #[test]
+    fn supersedes_rolled_back_state() {
+        let chain_id = chain::Id::try_from("testchain-1").unwrap();
+        let mac_key = StateMacKey::new(&SigningKey::from([1u8; 32]), &chain_id);
+        let service_key = SigningKey::from([2u8; 32]);
+        let verification_key = service_key.verification_key();
+        let mut states = MonotonicStates::default();
+        states
+            .handle(MonotonicRequest::Advance {
+                chain_id: chain_id.to_string(),
+                state: mac_key.sign(&state(10)).unwrap(),
+                nonce: nonce(),
+            })
+            .unwrap();
+        let (conn, mut service_conn) = UnixStream::pair().unwrap();
+        let service = thread::spawn(move || {
+            let first = serve(&mut states, &service_key, &mut service_conn, None);
+            // a replayed response
+            serve(&mut states, &service_key, &mut service_conn, Some(&first));
+            // a forged one
+            serve(
+                &mut states,
+                &SigningKey::from([3u8; 32]),
+                &mut service_conn,
+                None,
+            );
+        });
+        let mut sync = AntiRollbackStateSync::new(
+            Sink(state(5)),
+            conn,
+            chain_id.to_string(),
+            mac_key,
+            verification_key,
+        );
+        let loaded = sync.load_state().unwrap();
+        assert_eq!(loaded.consensus_state().height, block::Height::from(10u32));
+        assert!(sync.load_state().is_err());
+        assert!(sync.load_state().is_err());
+        service.join().unwrap();
+    }
+
+    #[test]
+    fn requires_the_state_of_a_chain_that_signed() {
+        let chain_id = chain::Id::try_from("testchain-1").unwrap();
+        let mac_key = StateMacKey::new(&SigningKey::from([1u8; 32]), &chain_id);
+        let service_key = SigningKey::from([2u8; 32]);
+        let verification_key = service_key.verification_key();
+        let (conn, mut service_conn) = UnixStream::pair().unwrap();
+        let service = thread::spawn(move || {
+            let mut states = MonotonicStates::default();
+            serve(&mut states, &service_key, &mut service_conn, None);
+            serve(&mut states, &service_key, &mut service_conn, None);
+        });
+        let mut sync = AntiRollbackStateSync::new(
+            Sink(state(5)),
+            conn,
+            chain_id.to_string(),
+            mac_key,
+            verification_key,
+        );
+        assert!(sync.load_state().is_err());
+        sync.inner.0 = state(0);
+        assert!(sync.load_state().is_ok());
+        service.join().unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+use super::{consensus, PersistStateSync, State, StateError};
+use crate::utils::{read_u16_payload, write_u16_payload};
+use ed25519_consensus::Signature;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::io;
+use tracing::{debug, error};
+
+/// A request to raise a chain's watermark before signing at `state`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct WatermarkRequest {
+    pub chain_id: String,
+    /// the signer instance making the request
+    pub replica_id: String,
+    pub state: consensus::State,
+}
+
+/// The highest (height, round, step) reserved on a chain and by which replica
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Watermark {
+    pub replica_id: String,
+    pub state: consensus::State,
+}
+
+/// Watermarks of all chains (as kept by the watermark service)
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Watermarks(BTreeMap<String, Watermark>);
+
+impl Watermarks {
+    /// raises the chain's watermark if the requested (height, round, step) is above it;
+    /// the same (height, round, step) can only be reserved again by the replica holding it
+    /// (whose own state tracking prevents double signing)
+    pub fn reserve(&mut self, request: WatermarkRequest) -> Result<(), String> {
+        if let Some(current) = self.0.get(&request.chain_id) {
+            // consensus states are ordered by (height, round, step)
+            let held = match request.state.cmp(&current.state) {
+                Ordering::Less => true,
+                Ordering::Equal => current.replica_id != request.replica_id,
+                Ordering::Greater => false,
+            };
+            if held {
+                return Err(format!(
+                    "{} watermark {} is held by {} (requested {} by {})",
+                    request.chain_id,
+                    current.state,
+                    current.replica_id,
+                    request.state,
+                    request.replica_id
+                ));
+            }
+        }
+        self.0.insert(
+            request.chain_id,
+            Watermark {
+                replica_id: request.replica_id,
+                state: request.state,
+            },
+        );
+        Ok(())
+    }
+}
+
+/// Reserves each state update with a watermark service (shared by redundant signers)
+/// before persisting it with the inner syncer, so that at most one replica signs
+/// at each (height, round, step)
+pub struct WatermarkStateSync<S: PersistStateSync, T: io::Read + io::Write> {
+    inner: S,
+    conn: T,
+    chain_id: String,
+    replica_id: String,
+}
+
+impl<S: PersistStateSync, T: io::Read + io::Write> WatermarkStateSync<S, T> {
+    pub fn new(inner: S, conn: T, chain_id: String, replica_id: String) -> Self {
+        Self {
+            inner,
+            conn,
+            chain_id,
+            replica_id,
+        }
+    }
+
+    fn reserve(&mut self, new_state: &consensus::State) -> Result<(), StateError> {
+        let request = WatermarkRequest {
+            chain_id: self.chain_id.clone(),
+            replica_id: self.replica_id.clone(),
+            state: new_state.clone(),
+        };
+        let json_raw = serde_json::to_vec(&request)
+            .map_err(|e| StateError::sync_enc_dec_error("watermark".into(), e))?;
+        write_u16_payload(&mut self.conn, &json_raw)
+            .map_err(|e| StateError::sync_error("watermark".into(), e))?;
+        let ack_raw = read_u16_payload(&mut self.conn)
+            .map_err(|e| StateError::sync_other_error(e.to_string()))?;
+        let ack: Result<(), String> = serde_json::from_slice(&ack_raw)
+            .map_err(|e| StateError::sync_enc_dec_error("watermark".into(), e))?;
+        if let Err(e) = ack {
+            error!(
+                chain_id = %self.chain_id,
+                alert = true,
+                "[{}] watermark refused: {}",
+                self.chain_id,
+                e
+            );
+            return Err(StateError::sync_other_error(e));
+        }
+        debug!("watermark reserved");
+        Ok(())
+    }
+}
+
+impl<S: PersistStateSync, T: io::Read + io::Write> PersistStateSync for WatermarkStateSync<S, T> {
+    fn load_state(&mut self) -> Result<State, StateError> {
+        self.inner.load_state()
+    }
+
+    fn persist_state(&mut self, new_state: &consensus::State) -> Result<(), StateError> {
+        self.reserve(new_state)?;
+        self.inner.persist_state(new_state)
+    }
+
+    fn persist_signed_state(
+        &mut self,
+        new_state: &consensus::State,
+        sign_bytes: &[u8],
+        signature: &Signature,
+    ) -> Result<(), StateError> {
+        self.reserve(new_state)?;
+        self.inner
+            .persist_signed_state(new_state, sign_bytes, signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tendermint::block;
+
+    fn request(replica_id: &str, height: u32, round: u16, step: i8) -> WatermarkRequest {
+        WatermarkRequest {
+            chain_id: "testchain-1".into(),
+            replica_id: replica_id.into(),
+            state: consensus::State {
+                height: block::Height::from(height),
+                round: block::Round::from(round),
+                step,
+                block_id: None,
+            },
+        }
+    }
+
+    #[test]
+    fn at_most_one_replica_per_hrs() {
+        let mut watermarks = Watermarks::default();
+        assert!(watermarks.reserve(request("a", 1, 0, 1)).is_ok());
+        assert!(watermarks.reserve(request("b", 1, 0, 1)).is_err());
+        assert!(watermarks.reserve(request("a", 1, 0, 1)).is_ok());
+        assert!(watermarks.reserve(request("b", 1, 0, 0)).is_err());
+        assert!(watermarks.reserve(request("b", 1, 0, 2)).is_ok());
+        assert!(watermarks.reserve(request("a", 1, 0, 2)).is_err());
+    }
+}
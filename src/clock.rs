@@ -0,0 +1,25 @@
+//! Wall clock of the signer (for the time-based signing policies and the audit records);
+//! a TEE's own clock may not be reliable, so providers can replace the system one
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use tendermint::Time;
+
+/// source of the current time
+pub trait Clock: Send + Sync {
+    /// the current time (an error if it isn't known, e.g. not synchronized yet)
+    fn now(&self) -> Result<Time, String>;
+}
+
+/// the local system clock
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Result<Time, String> {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .and_then(|d| Time::from_unix_timestamp(d.as_secs() as i64, d.subsec_nanos()).ok())
+            .ok_or_else(|| "invalid local time".to_owned())
+    }
+}
@@ -2,6 +2,7 @@
 //! Copyright (c) 2018-2021 Iqlusion Inc. (licensed under the Apache License, Version 2.0)
 //! Modifications Copyright (c) 2021-present Crypto.com (licensed under the Apache License, Version 2.0)
 
+use crate::policy::SigningPolicy;
 use serde::{Deserialize, Serialize};
 use tendermint::chain;
 
@@ -14,4 +15,8 @@ pub struct ValidatorConfig {
 
     /// Height at which to stop signing
     pub max_height: Option<tendermint::block::Height>,
+
+    /// Rules that signing requests must comply with
+    #[serde(default)]
+    pub signing_policy: SigningPolicy,
 }
@@ -2,10 +2,22 @@
 //! Copyright (c) 2018-2021 Iqlusion Inc. (licensed under the Apache License, Version 2.0)
 //! Modifications Copyright (c) 2021-present Crypto.com (licensed under the Apache License, Version 2.0)
 
+use crate::error::Error;
+use ed25519_consensus::SigningKey;
+use serde::{Deserialize, Serialize};
 use std::io;
 use std::marker::{Send, Sync};
-use tendermint_p2p::secret_connection::SecretConnection;
-use tracing::{debug, trace};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tendermint::node;
+use tendermint_p2p::secret_connection::{self, SecretConnection};
+use tracing::{debug, info, trace, warn};
+
+/// Noise protocol transport
+pub mod noise;
+/// mutual TLS transport
+pub mod tls;
 
 /// Connections to a validator
 pub trait Connection: io::Read + io::Write + Sync + Send {}
@@ -54,5 +66,134 @@ where
     }
 }
 
+/// Which side initiates the privval connection
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionMode {
+    /// the KMS dials the validator's `priv_validator_laddr`
+    #[default]
+    Dial,
+    /// the KMS listens on the address and the validator dials it
+    Listen,
+}
+
+/// Encrypted transport used for the validator link
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    /// Tendermint SecretConnection
+    #[default]
+    SecretConnection,
+    /// Noise_XX (the KMS is the initiator)
+    Noise,
+    /// mutual TLS (the KMS is the client)
+    Tls,
+}
+
+/// Read and write timeouts (in seconds) of the connections; blocking if not set
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectionTimeouts {
+    /// after which a read from a hung peer fails (and the connection is reset)
+    pub read_secs: Option<u64>,
+    /// after which a write to a hung peer fails (and the connection is reset)
+    pub write_secs: Option<u64>,
+    /// after which a validator connection without requests (including pings) is reset;
+    /// `read_secs` is then the polling interval
+    pub idle_secs: Option<u64>,
+}
+
+impl ConnectionTimeouts {
+    pub fn read(&self) -> Option<Duration> {
+        self.read_secs.or(self.idle_secs).map(Duration::from_secs)
+    }
+
+    pub fn idle(&self) -> Option<Duration> {
+        self.idle_secs.map(Duration::from_secs)
+    }
+
+    pub fn write(&self) -> Option<Duration> {
+        self.write_secs.map(Duration::from_secs)
+    }
+}
+
+/// Secret connection protocol version (depends on the validator's Tendermint version)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ProtocolVersion {
+    /// Tendermint v0.34 and later
+    #[default]
+    #[serde(rename = "v0.34")]
+    V0_34,
+    /// Tendermint v0.33
+    #[serde(rename = "v0.33")]
+    V0_33,
+    /// pre-Tendermint v0.33
+    #[serde(rename = "legacy")]
+    Legacy,
+}
+
+impl From<ProtocolVersion> for secret_connection::Version {
+    fn from(version: ProtocolVersion) -> Self {
+        match version {
+            ProtocolVersion::V0_34 => secret_connection::Version::V0_34,
+            ProtocolVersion::V0_33 => secret_connection::Version::V0_33,
+            ProtocolVersion::Legacy => secret_connection::Version::Legacy,
+        }
+    }
+}
+
+/// Dials the validator over TCP (the timeout applies to reads and writes)
+pub fn tcp_connect(host: &str, port: u16, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let socket = TcpStream::connect((host, port))?;
+    socket.set_read_timeout(timeout)?;
+    socket.set_write_timeout(timeout)?;
+    Ok(socket)
+}
+
+/// Accepts a validator connection on the TCP listener (the timeout applies to reads and writes)
+pub fn tcp_accept(listener: &TcpListener, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let (socket, addr) = listener.accept()?;
+    debug!("accepted tcp connection from {}", addr);
+    socket.set_read_timeout(timeout)?;
+    socket.set_write_timeout(timeout)?;
+    Ok(socket)
+}
+
+/// Establishes a secret connection over the socket
+/// and checks the validator's peer ID (if provided; it's mandatory with `require_peer_id`)
+pub fn secret_connection<T>(
+    socket: T,
+    identity_key: &SigningKey,
+    peer_id: Option<node::Id>,
+    require_peer_id: bool,
+    version: ProtocolVersion,
+) -> Result<SecretConnection<T>, Error>
+where
+    T: io::Read + io::Write + Send + Sync,
+{
+    let connection = SecretConnection::new(socket, identity_key.clone(), version.into())
+        .map_err(|e| Error::secret_connection_error("secret connection failed".into(), e))?;
+    let actual_peer_id = connection.remote_pubkey().peer_id();
+
+    // TODO: https://github.com/informalsystems/tendermint-rs/issues/786
+    if let Some(expected_peer_id) = peer_id {
+        if expected_peer_id.ct_eq(&actual_peer_id).unwrap_u8() == 0 {
+            return Err(Error::peer_id_mismatch(
+                expected_peer_id.to_string(),
+                actual_peer_id.to_string(),
+            ));
+        }
+    } else if require_peer_id {
+        return Err(Error::unverified_peer_id(actual_peer_id.to_string()));
+    } else {
+        warn!("unverified validator peer ID! ({})", actual_peer_id);
+    }
+    info!(
+        "secret connection with validator {} established",
+        actual_peer_id
+    );
+    Ok(connection)
+}
+
 impl<T> Connection for SecretConnection<T> where T: io::Read + io::Write + Sync + Send {}
 impl<T> Connection for PlainConnection<T> where T: io::Read + io::Write + Sync + Send {}
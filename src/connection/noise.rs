@@ -0,0 +1,220 @@
+//! Noise_XX transport (an alternative to SecretConnection, e.g. for privval-terminating proxies)
+
+use super::Connection;
+use crate::error::Error;
+use ed25519_consensus::SigningKey;
+use sha2::{Digest, Sha512};
+use snow::params::DHChoice;
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use std::io;
+use subtle::ConstantTimeEq;
+use tracing::{info, warn};
+use zeroize::Zeroizing;
+
+/// Noise protocol pattern and primitives
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+/// Maximum Noise message length
+const MAX_MESSAGE_LEN: usize = 65535;
+/// ChaChaPoly authentication tag length
+const TAG_LEN: usize = 16;
+/// Maximum plaintext length of one transport message
+const MAX_PAYLOAD_LEN: usize = MAX_MESSAGE_LEN - TAG_LEN;
+
+/// The Noise static (X25519) secret derived from the Ed25519 identity key
+/// (as in RFC 8032, so the public key is the Montgomery form of the Ed25519 one)
+fn static_secret(identity_key: &SigningKey) -> Zeroizing<[u8; 32]> {
+    let hash = Sha512::digest(identity_key.to_bytes());
+    let mut secret = Zeroizing::new([0u8; 32]);
+    secret.copy_from_slice(&hash[..32]);
+    secret[0] &= 248;
+    secret[31] &= 127;
+    secret[31] |= 64;
+    secret
+}
+
+/// The Noise static public key of the identity key (to be pinned by the remote endpoint)
+pub fn static_public_key(identity_key: &SigningKey) -> Vec<u8> {
+    let secret = static_secret(identity_key);
+    let mut dh = DefaultResolver
+        .resolve_dh(&DHChoice::Curve25519)
+        .expect("curve25519 available");
+    dh.set(secret.as_ref());
+    dh.pubkey().to_vec()
+}
+
+/// Encrypted connection using the Noise protocol
+pub struct NoiseConnection<T> {
+    socket: T,
+    transport: snow::TransportState,
+    /// decrypted data that haven't been read yet
+    read_buf: Vec<u8>,
+    read_pos: usize,
+}
+
+fn read_frame<T: io::Read>(socket: &mut T) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 2];
+    socket.read_exact(&mut len)?;
+    let mut frame = vec![0u8; u16::from_be_bytes(len) as usize];
+    socket.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+fn write_frame<T: io::Write>(socket: &mut T, frame: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(frame.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "noise frame too long"))?;
+    socket.write_all(&len.to_be_bytes())?;
+    socket.write_all(frame)
+}
+
+/// Runs the Noise_XX handshake (as the initiator) over the socket
+/// and checks the remote static key (if provided; it's mandatory with `require_remote_key`)
+pub fn noise_connection<T>(
+    mut socket: T,
+    identity_key: &SigningKey,
+    remote_key: Option<&[u8]>,
+    require_remote_key: bool,
+) -> Result<NoiseConnection<T>, Error>
+where
+    T: io::Read + io::Write + Send + Sync,
+{
+    let secret = static_secret(identity_key);
+    let mut handshake = snow::Builder::new(NOISE_PARAMS.parse().expect("valid noise params"))
+        .local_private_key(secret.as_ref())
+        .build_initiator()
+        .map_err(|e| Error::noise_error("failed to start the handshake".into(), e))?;
+    let mut buf = vec![0u8; MAX_MESSAGE_LEN];
+    let io_err = |e| Error::io_error("noise handshake failed".into(), e);
+    // -> e
+    let len = handshake
+        .write_message(&[], &mut buf)
+        .map_err(|e| Error::noise_error("handshake failed".into(), e))?;
+    write_frame(&mut socket, &buf[..len]).map_err(io_err)?;
+    // <- e, ee, s, es
+    let frame = read_frame(&mut socket).map_err(io_err)?;
+    handshake
+        .read_message(&frame, &mut buf)
+        .map_err(|e| Error::noise_error("handshake failed".into(), e))?;
+    // -> s, se
+    let len = handshake
+        .write_message(&[], &mut buf)
+        .map_err(|e| Error::noise_error("handshake failed".into(), e))?;
+    write_frame(&mut socket, &buf[..len]).map_err(io_err)?;
+
+    let actual_key = handshake
+        .get_remote_static()
+        .map(|k| String::from_utf8(subtle_encoding::base64::encode(k)).unwrap())
+        .unwrap_or_default();
+    if let Some(expected_key) = remote_key {
+        let matches = handshake
+            .get_remote_static()
+            .map(|k| k.ct_eq(expected_key).unwrap_u8() == 1)
+            .unwrap_or(false);
+        if !matches {
+            return Err(Error::peer_id_mismatch(
+                String::from_utf8(subtle_encoding::base64::encode(expected_key)).unwrap(),
+                actual_key,
+            ));
+        }
+    } else if require_remote_key {
+        return Err(Error::unverified_peer_id(actual_key));
+    } else {
+        warn!("unverified noise remote key! ({})", actual_key);
+    }
+    let transport = handshake
+        .into_transport_mode()
+        .map_err(|e| Error::noise_error("handshake failed".into(), e))?;
+    info!("noise connection with {} established", actual_key);
+    Ok(NoiseConnection {
+        socket,
+        transport,
+        read_buf: vec![],
+        read_pos: 0,
+    })
+}
+
+impl<T> io::Read for NoiseConnection<T>
+where
+    T: io::Read,
+{
+    fn read(&mut self, data: &mut [u8]) -> io::Result<usize> {
+        if self.read_pos >= self.read_buf.len() {
+            let frame = read_frame(&mut self.socket)?;
+            let mut plaintext = vec![0u8; frame.len()];
+            let len = self
+                .transport
+                .read_message(&frame, &mut plaintext)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            plaintext.truncate(len);
+            self.read_buf = plaintext;
+            self.read_pos = 0;
+        }
+        let len = data.len().min(self.read_buf.len() - self.read_pos);
+        data[..len].copy_from_slice(&self.read_buf[self.read_pos..self.read_pos + len]);
+        self.read_pos += len;
+        Ok(len)
+    }
+}
+
+impl<T> io::Write for NoiseConnection<T>
+where
+    T: io::Write,
+{
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut frame = vec![0u8; MAX_MESSAGE_LEN];
+        for chunk in data.chunks(MAX_PAYLOAD_LEN) {
+            let len = self
+                .transport
+                .write_message(chunk, &mut frame)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            write_frame(&mut self.socket, &frame[..len])?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+impl<T> Connection for NoiseConnection<T> where T: io::Read + io::Write + Sync + Send {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    #[test]
+    fn handshake_pins_and_transfers() {
+        let kms_key = SigningKey::from([7u8; 32]);
+        let kms_public = static_public_key(&kms_key);
+        let remote_keypair = snow::Builder::new(NOISE_PARAMS.parse().unwrap())
+            .generate_keypair()
+            .unwrap();
+        let (kms_socket, mut remote_socket) = UnixStream::pair().unwrap();
+        let remote_private = remote_keypair.private.clone();
+        let remote = thread::spawn(move || {
+            let mut handshake = snow::Builder::new(NOISE_PARAMS.parse().unwrap())
+                .local_private_key(&remote_private)
+                .build_responder()
+                .unwrap();
+            let mut buf = vec![0u8; MAX_MESSAGE_LEN];
+            let frame = read_frame(&mut remote_socket).unwrap();
+            handshake.read_message(&frame, &mut buf).unwrap();
+            let len = handshake.write_message(&[], &mut buf).unwrap();
+            write_frame(&mut remote_socket, &buf[..len]).unwrap();
+            let frame = read_frame(&mut remote_socket).unwrap();
+            handshake.read_message(&frame, &mut buf).unwrap();
+            assert_eq!(handshake.get_remote_static().unwrap(), &kms_public[..]);
+            let mut transport = handshake.into_transport_mode().unwrap();
+            let frame = read_frame(&mut remote_socket).unwrap();
+            let len = transport.read_message(&frame, &mut buf).unwrap();
+            assert_eq!(&buf[..len], b"ping");
+        });
+        let mut conn =
+            noise_connection(kms_socket, &kms_key, Some(&remote_keypair.public), true).unwrap();
+        conn.write_all(b"ping").unwrap();
+        remote.join().unwrap();
+    }
+}
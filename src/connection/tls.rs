@@ -0,0 +1,144 @@
+//! mutual TLS transport (for validators fronting their privval endpoint with TLS)
+
+use super::Connection;
+use crate::error::Error;
+use rustls::{Certificate, ClientConfig, ClientConnection, PrivateKey, RootCertStore, ServerName};
+use rustls_pemfile::Item;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::info;
+
+/// TLS connection to the validator
+pub type TlsConnection<T> = rustls::StreamOwned<ClientConnection, T>;
+
+impl<T> Connection for TlsConnection<T> where T: io::Read + io::Write + Sync + Send {}
+
+/// Mutual TLS configuration
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// CA certificate(s) (PEM) to verify the endpoint's certificate
+    pub ca_cert_path: PathBuf,
+    /// Client certificate chain (PEM)
+    pub cert_path: PathBuf,
+    /// Client private key (PEM; PKCS#8, RSA or SEC1)
+    pub key_path: PathBuf,
+    /// DNS name the endpoint's certificate SANs must contain (the address host by default)
+    pub server_name: Option<String>,
+}
+
+impl TlsConfig {
+    /// reads the PEM files
+    pub fn load(&self) -> Result<TlsCredentials, Error> {
+        let read = |path: &PathBuf| {
+            fs::read(path).map_err(|e| Error::io_error(format!("failed to read {:?}", path), e))
+        };
+        Ok(TlsCredentials {
+            ca_certs: read(&self.ca_cert_path)?,
+            cert_chain: read(&self.cert_path)?,
+            key: read(&self.key_path)?,
+            server_name: self.server_name.clone(),
+        })
+    }
+}
+
+/// PEM-encoded TLS certificates and key
+#[derive(Clone, Deserialize, Serialize)]
+pub struct TlsCredentials {
+    pub ca_certs: Vec<u8>,
+    pub cert_chain: Vec<u8>,
+    pub key: Vec<u8>,
+    pub server_name: Option<String>,
+}
+
+impl fmt::Debug for TlsCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsCredentials")
+            .field("server_name", &self.server_name)
+            .finish_non_exhaustive()
+    }
+}
+
+fn pem_items(pem: &[u8]) -> Result<Vec<Item>, Error> {
+    rustls_pemfile::read_all(&mut io::BufReader::new(pem))
+        .map_err(|e| Error::io_error("invalid PEM".into(), e))
+}
+
+fn client_config(credentials: &TlsCredentials) -> Result<ClientConfig, Error> {
+    let mut roots = RootCertStore::empty();
+    for item in pem_items(&credentials.ca_certs)? {
+        if let Item::X509Certificate(der) = item {
+            roots
+                .add(&Certificate(der))
+                .map_err(|e| Error::invalid_tls_config_error(format!("invalid CA: {}", e)))?;
+        }
+    }
+    if roots.is_empty() {
+        return Err(Error::invalid_tls_config_error("no CA certificate".into()));
+    }
+    let cert_chain: Vec<Certificate> = pem_items(&credentials.cert_chain)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+    let key = pem_items(&credentials.key)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| Error::invalid_tls_config_error("no private key".into()))?;
+    ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_single_cert(cert_chain, key)
+        .map_err(|e| Error::tls_error("invalid client certificate or key".into(), e))
+}
+
+/// Runs the TLS handshake over the socket, authenticating with the client certificate
+/// and checking that the endpoint's certificate is valid for `server_name` (or `host`)
+pub fn tls_connection<T>(
+    mut socket: T,
+    credentials: &TlsCredentials,
+    host: &str,
+) -> Result<TlsConnection<T>, Error>
+where
+    T: io::Read + io::Write + Send + Sync,
+{
+    let config = client_config(credentials)?;
+    let name = credentials.server_name.as_deref().unwrap_or(host);
+    let server_name = ServerName::try_from(name)
+        .map_err(|_| Error::invalid_tls_config_error(format!("invalid server name: {}", name)))?;
+    let mut connection = ClientConnection::new(Arc::new(config), server_name)
+        .map_err(|e| Error::tls_error("TLS connection failed".into(), e))?;
+    while connection.is_handshaking() {
+        connection
+            .complete_io(&mut socket)
+            .map_err(|e| Error::io_error("TLS handshake failed".into(), e))?;
+    }
+    info!("TLS connection with {} established", name);
+    Ok(rustls::StreamOwned::new(connection, socket))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_missing_ca() {
+        let credentials = TlsCredentials {
+            ca_certs: vec![],
+            cert_chain: vec![],
+            key: vec![],
+            server_name: None,
+        };
+        assert!(client_config(&credentials).is_err());
+    }
+}
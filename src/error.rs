@@ -3,7 +3,7 @@
 //! Modifications Copyright (c) 2021-present Crypto.com (licensed under the Apache License, Version 2.0)
 
 use flex_error::define_error;
-use flex_error::DetailOnly;
+use flex_error::{DetailOnly, DisplayOnly};
 
 define_error! {
     Error {
@@ -22,6 +22,16 @@ define_error! {
             "Access Denied"
         },
 
+        KmsError { error: String }
+        |e| {
+            format_args!("AWS KMS error: {}", e.error)
+        },
+
+        KmsUnavailable { error: String }
+        |e| {
+            format_args!("AWS KMS unavailable: {}", e.error)
+        },
+
         ChainIdError {
             chain_id: String,
         } |e| {
@@ -51,6 +61,24 @@ define_error! {
             e.error.clone()
         },
 
+        ReadTimeout {
+        } |_| {
+            "no message received before the read timeout"
+        },
+
+        IdleTimeout {
+            idle_secs: u64,
+        } |e| {
+            format_args!("no request received for {} seconds", e.idle_secs)
+        },
+
+        PayloadTooLarge {
+            len: u64,
+            max: u64,
+        } |e| {
+            format_args!("payload of {} bytes exceeds the maximum of {} bytes", e.len, e.max)
+        },
+
         PanicError {
         } |_| {
             "internal crash"
@@ -74,6 +102,41 @@ define_error! {
             e.error.clone()
         },
 
+        SecretConnectionError { error: String }
+        [ DetailOnly<tendermint_p2p::error::Error> ]
+        |e| {
+            e.error.clone()
+        },
+
+        PeerIdMismatch {
+            expected: String,
+            actual: String,
+        } |e| {
+            format_args!("validator peer ID mismatch! (expected {}, got {})", e.expected, e.actual)
+        },
+
+        NoiseError { error: String }
+        [ DisplayOnly<snow::Error> ]
+        |e| {
+            e.error.clone()
+        },
+
+        TlsError { error: String }
+        [ DisplayOnly<rustls::Error> ]
+        |e| {
+            e.error.clone()
+        },
+
+        InvalidTlsConfigError { error: String }
+        |e| {
+            format_args!("invalid TLS configuration: {}", e.error)
+        },
+
+        UnverifiedPeerId { actual: String }
+        |e| {
+            format_args!("unverified validator peer ID ({}) refused: no peer ID is configured", e.actual)
+        },
+
         SerializationError {
         }  [ DetailOnly<serde_json::Error> ] |e| {
             format_args!("serialization error: {}", e)
@@ -82,6 +145,116 @@ define_error! {
     }
 }
 
+/// How an error affects the session
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// the connection can be re-established and the request retried (e.g. I/O or protocol errors)
+    Transient,
+    /// retrying can't help (e.g. a bad key or configuration, a double-sign attempt or a policy violation)
+    Fatal,
+}
+
+impl Error {
+    /// whether the session should reconnect or stop after this error
+    pub fn class(&self) -> ErrorClass {
+        use crate::chain::state::StateErrorDetail;
+        match self.detail() {
+            ErrorDetail::AccessError(_)
+            | ErrorDetail::ChainIdError(_)
+            | ErrorDetail::DoubleSign(_)
+            | ErrorDetail::ExceedMaxHeight(_)
+            | ErrorDetail::InvalidKeyError(_)
+            | ErrorDetail::PanicError(_)
+            | ErrorDetail::PeerIdMismatch(_)
+            | ErrorDetail::InvalidTlsConfigError(_)
+            | ErrorDetail::UnverifiedPeerId(_) => ErrorClass::Fatal,
+            // a stale or replayed request (e.g. after a validator restart or a WAL replay)
+            // is routine: only a tampered or unauthenticated state is fatal
+            ErrorDetail::SigningStateError(e) => match e.source.detail() {
+                StateErrorDetail::InvalidStateMac(_)
+                | StateErrorDetail::UnauthenticatedState(_) => ErrorClass::Fatal,
+                _ => ErrorClass::Transient,
+            },
+            _ => ErrorClass::Transient,
+        }
+    }
+
+    pub fn is_fatal(&self) -> bool {
+        self.class() == ErrorClass::Fatal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::state::StateError;
+    use tendermint::block::{Height, Round};
+
+    fn state_error(e: StateError) -> Error {
+        Error::signing_state_error("failed signing vote".into(), e)
+    }
+
+    #[test]
+    fn errors_are_classified() {
+        let height = Height::from(10u32);
+        let round = Round::from(1u16);
+        let cases = [
+            (
+                Error::io_error(
+                    "read".into(),
+                    std::io::Error::from(std::io::ErrorKind::BrokenPipe),
+                ),
+                ErrorClass::Transient,
+            ),
+            (Error::read_timeout(), ErrorClass::Transient),
+            (Error::access_error(), ErrorClass::Fatal),
+            (
+                Error::kms_unavailable("throttled".into()),
+                ErrorClass::Transient,
+            ),
+            (Error::chain_id_error("other".into()), ErrorClass::Fatal),
+            (Error::double_sign(), ErrorClass::Fatal),
+            (Error::invalid_key_error(), ErrorClass::Fatal),
+            (Error::exceed_max_height(11, 10), ErrorClass::Fatal),
+            (
+                state_error(StateError::height_regression_error(
+                    height,
+                    Height::from(9u32),
+                )),
+                ErrorClass::Transient,
+            ),
+            (
+                state_error(StateError::round_regression_error(
+                    height,
+                    round,
+                    Round::from(0u16),
+                )),
+                ErrorClass::Transient,
+            ),
+            (
+                state_error(StateError::step_regression_error(height, round, 2, 1)),
+                ErrorClass::Transient,
+            ),
+            (
+                state_error(StateError::sync_other_error("unreachable".into())),
+                ErrorClass::Transient,
+            ),
+            (
+                state_error(StateError::invalid_state_mac()),
+                ErrorClass::Fatal,
+            ),
+            (
+                state_error(StateError::unauthenticated_state(height)),
+                ErrorClass::Fatal,
+            ),
+        ];
+        for (error, class) in cases {
+            assert_eq!(error.class(), class, "{}", error);
+            assert_eq!(error.is_fatal(), class == ErrorClass::Fatal);
+        }
+    }
+}
+
 /// Wraps IO-related error from a different source into an IO error
 /// as a kind Other
 pub fn io_error_wrap<E: Into<Box<dyn std::error::Error + Send + Sync>>>(
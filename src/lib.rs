@@ -1,7 +1,19 @@
+pub mod approval;
+pub mod audit;
 pub mod chain;
+pub mod clock;
 pub mod config;
 pub mod connection;
 pub mod error;
+pub mod metrics;
+pub mod pck;
+pub mod policy;
+pub mod possession;
+pub mod rate_limit;
 mod rpc;
 pub mod session;
+#[cfg(unix)]
+pub mod socket_activation;
 pub mod utils;
+#[cfg(feature = "vsock")]
+pub mod vsock;
@@ -0,0 +1,187 @@
+//! Metrics of the handled requests
+
+use crate::audit::SignedMsgKind;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use tendermint::chain;
+
+/// a request handled by the session
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum MetricsEvent {
+    /// a signed proposal or vote
+    Signed {
+        chain_id: chain::Id,
+        msg_type: SignedMsgKind,
+        height: i64,
+        round: i64,
+    },
+    /// the validator's ping
+    Ping { chain_id: chain::Id },
+}
+
+/// destination of the session's metrics events
+/// (best effort: a failure is only logged by the sink, it doesn't interrupt the signing)
+pub trait MetricsSink: Send {
+    fn record(&mut self, event: &MetricsEvent);
+}
+
+/// the counters and gauges of a chain
+#[derive(Clone, Debug, Default)]
+struct ChainMetrics {
+    /// requests by type (`proposal`, `prevote`, `precommit` or `ping`)
+    requests: BTreeMap<&'static str, u64>,
+    last_signed_height: i64,
+    last_signed_round: i64,
+    /// type of the last signed message (i.e. the step)
+    last_signed_msg_type: Option<SignedMsgKind>,
+    /// when the last signature was recorded (UNIX seconds)
+    last_signed_timestamp: u64,
+}
+
+/// the statistics of a chain since the metrics started (e.g. for a status API)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChainSigningStats {
+    pub chain_id: String,
+    /// requests by type (`proposal`, `prevote`, `precommit` or `ping`)
+    pub requests: BTreeMap<String, u64>,
+    pub last_signed_height: i64,
+    pub last_signed_round: i64,
+    pub last_signed_msg_type: Option<SignedMsgKind>,
+    /// when the last signature was recorded (UNIX seconds)
+    pub last_signed_timestamp: u64,
+}
+
+/// Metrics aggregated from the events of all the chains' sessions
+#[derive(Clone, Debug, Default)]
+pub struct SigningMetrics(BTreeMap<String, ChainMetrics>);
+
+impl SigningMetrics {
+    /// aggregates the event (received at `now`, in UNIX seconds)
+    pub fn record(&mut self, event: &MetricsEvent, now: u64) {
+        match event {
+            MetricsEvent::Signed {
+                chain_id,
+                msg_type,
+                height,
+                round,
+            } => {
+                let metrics = self.0.entry(chain_id.to_string()).or_default();
+                *metrics.requests.entry(msg_type.as_str()).or_default() += 1;
+                metrics.last_signed_height = *height;
+                metrics.last_signed_round = *round;
+                metrics.last_signed_msg_type = Some(*msg_type);
+                metrics.last_signed_timestamp = now;
+            }
+            MetricsEvent::Ping { chain_id } => {
+                let metrics = self.0.entry(chain_id.to_string()).or_default();
+                *metrics.requests.entry("ping").or_default() += 1;
+            }
+        }
+    }
+
+    /// the statistics of each chain
+    pub fn stats(&self) -> Vec<ChainSigningStats> {
+        self.0
+            .iter()
+            .map(|(chain_id, metrics)| ChainSigningStats {
+                chain_id: chain_id.clone(),
+                requests: metrics
+                    .requests
+                    .iter()
+                    .map(|(msg_type, count)| (msg_type.to_string(), *count))
+                    .collect(),
+                last_signed_height: metrics.last_signed_height,
+                last_signed_round: metrics.last_signed_round,
+                last_signed_msg_type: metrics.last_signed_msg_type,
+                last_signed_timestamp: metrics.last_signed_timestamp,
+            })
+            .collect()
+    }
+
+    fn render_gauge(
+        &self,
+        out: &mut String,
+        name: &str,
+        help: &str,
+        value: impl Fn(&ChainMetrics) -> u64,
+    ) {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge", name, help, name);
+        for (chain_id, metrics) in &self.0 {
+            let _ = writeln!(
+                out,
+                "{}{{chain_id=\"{}\"}} {}",
+                name,
+                chain_id,
+                value(metrics)
+            );
+        }
+    }
+
+    /// the metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP tmkms_requests_total Requests handled by type.\n");
+        out.push_str("# TYPE tmkms_requests_total counter\n");
+        for (chain_id, metrics) in &self.0 {
+            for (msg_type, count) in &metrics.requests {
+                let _ = writeln!(
+                    out,
+                    "tmkms_requests_total{{chain_id=\"{}\",msg_type=\"{}\"}} {}",
+                    chain_id, msg_type, count
+                );
+            }
+        }
+        self.render_gauge(
+            &mut out,
+            "tmkms_last_signed_height",
+            "Height of the last signature.",
+            |m| m.last_signed_height as u64,
+        );
+        self.render_gauge(
+            &mut out,
+            "tmkms_last_signed_round",
+            "Round of the last signature.",
+            |m| m.last_signed_round as u64,
+        );
+        self.render_gauge(
+            &mut out,
+            "tmkms_last_signed_timestamp_seconds",
+            "When the last signature was recorded.",
+            |m| m.last_signed_timestamp,
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn renders_counters_and_gauges() {
+        let chain_id = chain::Id::try_from("testchain-1").unwrap();
+        let mut metrics = SigningMetrics::default();
+        metrics.record(
+            &MetricsEvent::Signed {
+                chain_id: chain_id.clone(),
+                msg_type: SignedMsgKind::Prevote,
+                height: 10,
+                round: 1,
+            },
+            1000,
+        );
+        metrics.record(&MetricsEvent::Ping { chain_id }, 1001);
+        let rendered = metrics.render();
+        assert!(rendered
+            .contains("tmkms_requests_total{chain_id=\"testchain-1\",msg_type=\"prevote\"} 1"));
+        assert!(
+            rendered.contains("tmkms_requests_total{chain_id=\"testchain-1\",msg_type=\"ping\"} 1")
+        );
+        assert!(rendered.contains("tmkms_last_signed_height{chain_id=\"testchain-1\"} 10"));
+        assert!(
+            rendered.contains("tmkms_last_signed_timestamp_seconds{chain_id=\"testchain-1\"} 1000")
+        );
+    }
+}
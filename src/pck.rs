@@ -0,0 +1,183 @@
+//! The quoting enclave's side of the DCAP (ECDSA) quotes of SGX enclaves and TDX trust domains:
+//! the quoting enclave's report is signed by the platform's PCK (Provisioning Certification Key),
+//! whose certificate chain (in the quote's certification data) needs to lead to the pinned
+//! Intel SGX Root CA, and the quoting enclave needs to be Intel's.
+//! NOTE: this doesn't check the PCK certificates' revocation or the platform's TCB status
+//! (they need Intel's collateral, i.e. a remote verifier).
+
+use std::io::BufReader;
+use std::time::SystemTime;
+
+/// the Intel SGX Root CA, from
+/// https://certificates.trustedservices.intel.com/Intel_SGX_Provisioning_Certification_RootCA.pem
+const INTEL_SGX_ROOT_CA: &str = "\
+MIICjzCCAjSgAwIBAgIUImUM1lqdNInzg7SVUr9QGzknBqwwCgYIKoZIzj0EAwIw\
+aDEaMBgGA1UEAwwRSW50ZWwgU0dYIFJvb3QgQ0ExGjAYBgNVBAoMEUludGVsIENv\
+cnBvcmF0aW9uMRQwEgYDVQQHDAtTYW50YSBDbGFyYTELMAkGA1UECAwCQ0ExCzAJ\
+BgNVBAYTAlVTMB4XDTE4MDUyMTEwNDUxMFoXDTQ5MTIzMTIzNTk1OVowaDEaMBgG\
+A1UEAwwRSW50ZWwgU0dYIFJvb3QgQ0ExGjAYBgNVBAoMEUludGVsIENvcnBvcmF0\
+aW9uMRQwEgYDVQQHDAtTYW50YSBDbGFyYTELMAkGA1UECAwCQ0ExCzAJBgNVBAYT\
+AlVTMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEC6nEwMDIYZOj/iPWsCzaEKi7\
+1OiOSLRFhWGjbnBVJfVnkY4u3IjkDYYL0MxO4mqsyYjlBalTVYxFP2sJBK5zlKOB\
+uzCBuDAfBgNVHSMEGDAWgBQiZQzWWp00ifODtJVSv1AbOScGrDBSBgNVHR8ESzBJ\
+MEegRaBDhkFodHRwczovL2NlcnRpZmljYXRlcy50cnVzdGVkc2VydmljZXMuaW50\
+ZWwuY29tL0ludGVsU0dYUm9vdENBLmRlcjAdBgNVHQ4EFgQUImUM1lqdNInzg7SV\
+Ur9QGzknBqwwDgYDVR0PAQH/BAQDAgEGMBIGA1UdEwEB/wQIMAYBAf8CAQEwCgYI\
+KoZIzj0EAwIDSQAwRgIhAOW/5QkR+S9CiSDcNoowLuPRLsWGf/Yi7GSX94BgwTwg\
+AiEA4J0lrHoMs+Xo5o/sX6O9QWxHRAvZUGOdRQ7cvqRXaqI=";
+
+/// MRSIGNER of Intel's quoting enclaves
+pub const QE_MRSIGNER: [u8; 32] = [
+    0x8c, 0x4f, 0x57, 0x75, 0xd7, 0x96, 0x50, 0x3e, 0x96, 0x13, 0x7f, 0x77, 0xc6, 0x8a, 0x82, 0x9a,
+    0x00, 0x56, 0xac, 0x8d, 0xed, 0x70, 0x14, 0x0b, 0x08, 0x1b, 0x09, 0x44, 0x90, 0xc5, 0x7b, 0xff,
+];
+/// the certification data is the PEM-encoded PCK certificate chain (PCK certificate first)
+pub const CERT_DATA_PCK_CHAIN: u16 = 5;
+/// length of the (SGX) report body of the quoting enclave
+const QE_REPORT_LEN: usize = 384;
+
+/// the DER-encoded Intel SGX Root CA
+pub fn intel_sgx_root_ca() -> Vec<u8> {
+    subtle_encoding::base64::decode(INTEL_SGX_ROOT_CA).expect("valid root CA encoding")
+}
+
+/// the fixed-size (r || s) P-256 ECDSA signature in the ASN.1 DER encoding
+fn ecdsa_signature_der(signature: &[u8]) -> Result<Vec<u8>, String> {
+    if signature.len() != 64 {
+        return Err("invalid QE report signature length".to_owned());
+    }
+    let integer = |bytes: &[u8]| {
+        let start = bytes
+            .iter()
+            .position(|b| *b != 0)
+            .unwrap_or(bytes.len() - 1);
+        let pad = bytes[start] & 0x80 != 0;
+        let mut value = vec![0x02, (bytes.len() - start + usize::from(pad)) as u8];
+        if pad {
+            value.push(0);
+        }
+        value.extend_from_slice(&bytes[start..]);
+        value
+    };
+    let (r, s) = (integer(&signature[..32]), integer(&signature[32..]));
+    let mut der = vec![0x30, (r.len() + s.len()) as u8];
+    der.extend_from_slice(&r);
+    der.extend_from_slice(&s);
+    Ok(der)
+}
+
+/// checks the quoting enclave's report is Intel's and signed by the PCK whose certificate chain
+/// (the certification data) leads to the root CA (DER-encoded) at the given time
+pub fn verify_qe_report_with_root(
+    cert_data_type: u16,
+    cert_data: &[u8],
+    qe_report: &[u8],
+    qe_report_signature: &[u8],
+    root_ca: &[u8],
+    time: SystemTime,
+) -> Result<(), String> {
+    if cert_data_type != CERT_DATA_PCK_CHAIN {
+        return Err(format!(
+            "unsupported certification data type {} (not a PCK certificate chain)",
+            cert_data_type
+        ));
+    }
+    if qe_report.len() != QE_REPORT_LEN {
+        return Err("invalid QE report length".to_owned());
+    }
+    let chain = rustls_pemfile::certs(&mut BufReader::new(cert_data))
+        .map_err(|e| format!("invalid PCK certificate chain: {:?}", e))?;
+    let (pck, intermediates) = chain
+        .split_first()
+        .ok_or_else(|| "no PCK certificate in the quote".to_owned())?;
+    let intermediates: Vec<&[u8]> = intermediates.iter().map(|c| c.as_slice()).collect();
+    let anchor = webpki::TrustAnchor::try_from_cert_der(root_ca)
+        .map_err(|e| format!("invalid root CA: {:?}", e))?;
+    let certificate = webpki::EndEntityCert::try_from(pck.as_slice())
+        .map_err(|e| format!("invalid PCK certificate: {:?}", e))?;
+    let time = webpki::Time::try_from(time).map_err(|_| "invalid time".to_owned())?;
+    certificate
+        .verify_is_valid_tls_server_cert(
+            &[&webpki::ECDSA_P256_SHA256],
+            &webpki::TlsServerTrustAnchors(&[anchor]),
+            &intermediates,
+            time,
+        )
+        .map_err(|e| format!("invalid PCK certificate chain: {:?}", e))?;
+    certificate
+        .verify_signature(
+            &webpki::ECDSA_P256_SHA256,
+            qe_report,
+            &ecdsa_signature_der(qe_report_signature)?,
+        )
+        .map_err(|_| "the QE report isn't signed by the PCK".to_owned())?;
+    if qe_report[128..160] != QE_MRSIGNER {
+        return Err("the quoting enclave isn't Intel's".to_owned());
+    }
+    Ok(())
+}
+
+/// checks the quoting enclave's report is Intel's and signed by the PCK whose certificate chain
+/// leads to the Intel SGX Root CA
+pub fn verify_qe_report(
+    cert_data_type: u16,
+    cert_data: &[u8],
+    qe_report: &[u8],
+    qe_report_signature: &[u8],
+) -> Result<(), String> {
+    verify_qe_report_with_root(
+        cert_data_type,
+        cert_data,
+        qe_report,
+        qe_report_signature,
+        &intel_sgx_root_ca(),
+        SystemTime::now(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+
+    /// a PCK certificate chain (valid from 2023 to 2123) to a test root CA and the PCK's key
+    const ROOT_CA: &[u8] = include_bytes!("../testdata/pck_root.der");
+    const PCK_CHAIN: &[u8] = include_bytes!("../testdata/pck_chain.pem");
+    const PCK_KEY: &[u8] = include_bytes!("../testdata/pck_leaf.pk8");
+
+    #[test]
+    fn verifies_the_qe_report_signature_and_pck_chain() {
+        let mut qe_report = vec![0u8; QE_REPORT_LEN];
+        qe_report[128..160].copy_from_slice(&QE_MRSIGNER);
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, PCK_KEY).unwrap();
+        let signature = key.sign(&SystemRandom::new(), &qe_report).unwrap();
+        let verify = |report: &[u8], root: &[u8]| {
+            verify_qe_report_with_root(
+                CERT_DATA_PCK_CHAIN,
+                PCK_CHAIN,
+                report,
+                signature.as_ref(),
+                root,
+                SystemTime::now(),
+            )
+        };
+        assert!(verify(&qe_report, ROOT_CA).is_ok());
+        // not anchored to the Intel root
+        assert!(verify(&qe_report, &intel_sgx_root_ca()).is_err());
+        // the report was changed after it was signed
+        let mut other = qe_report.clone();
+        other[0] ^= 1;
+        assert!(verify(&other, ROOT_CA).is_err());
+        // the certification data isn't a PCK chain
+        assert!(verify_qe_report_with_root(
+            6,
+            PCK_CHAIN,
+            &qe_report,
+            signature.as_ref(),
+            ROOT_CA,
+            SystemTime::now()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,377 @@
+//! Declarative signing policy evaluated before signing
+
+use crate::audit::SignedMsgKind;
+use crate::clock::Clock;
+use crate::rate_limit::RateLimits;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tendermint::{chain, Time};
+
+/// inclusive range of block heights
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeightRange {
+    pub start: u64,
+    /// no upper bound if not set
+    pub end: Option<u64>,
+}
+
+/// time period in which signing is allowed
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeWindow {
+    pub not_before: Option<Time>,
+    pub not_after: Option<Time>,
+}
+
+/// which kinds of messages are signed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SigningMode {
+    /// proposals and votes
+    #[default]
+    All,
+    /// only prevotes and precommits (e.g. if the proposals are built elsewhere)
+    VoteOnly,
+    /// only proposals
+    ProposalOnly,
+}
+
+impl SigningMode {
+    pub fn allows(self, msg_type: SignedMsgKind) -> bool {
+        match self {
+            SigningMode::All => true,
+            SigningMode::VoteOnly => msg_type != SignedMsgKind::Proposal,
+            SigningMode::ProposalOnly => msg_type == SignedMsgKind::Proposal,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SigningMode::All => "all",
+            SigningMode::VoteOnly => "vote-only",
+            SigningMode::ProposalOnly => "proposal-only",
+        }
+    }
+}
+
+/// which signatures need a second party's approval before they're released (two-person rule)
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApprovalPolicy {
+    /// the signatures at or above this height
+    pub min_height: Option<u64>,
+    /// all the proposals
+    #[serde(default)]
+    pub proposals: bool,
+}
+
+impl ApprovalPolicy {
+    pub fn requires_approval(&self, msg_type: SignedMsgKind, height: u64) -> bool {
+        (self.proposals && msg_type == SignedMsgKind::Proposal)
+            || self.min_height.map_or(false, |min| height >= min)
+    }
+
+    /// the rule requiring the approval of the signatures either rule requires it for
+    pub fn union(&self, other: &ApprovalPolicy) -> ApprovalPolicy {
+        ApprovalPolicy {
+            min_height: match (self.min_height, other.min_height) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+            proposals: self.proposals || other.proposals,
+        }
+    }
+}
+
+/// Signing policy (rules that are not set don't restrict signing)
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigningPolicy {
+    /// shorthand restricting the signed message types (to votes or proposals)
+    #[serde(default)]
+    pub mode: SigningMode,
+    /// message types that can be signed
+    pub allowed_msg_types: Option<Vec<SignedMsgKind>>,
+    /// heights at which signing is allowed (any of the ranges)
+    pub allowed_heights: Option<Vec<HeightRange>>,
+    /// maximum round to sign at
+    pub max_round: Option<u32>,
+    /// chain IDs that can be signed for
+    pub allowed_chain_ids: Option<Vec<chain::Id>>,
+    /// periods in which signing is allowed (any of the windows);
+    /// evaluated against the signer's clock
+    pub time_windows: Option<Vec<TimeWindow>>,
+    /// maximum increase of the height from the last signed one (unless allowed once by the operator),
+    /// e.g. against a mixed-up state file or a connection to the wrong network
+    pub max_height_jump: Option<u64>,
+    /// maximum deviation (in seconds) of the votes' and proposals' timestamps
+    /// from the signer's clock (e.g. against a compromised node pre-signing far-future votes)
+    pub max_timestamp_skew_secs: Option<u64>,
+    /// after a (re)start, nothing is signed until the heights of the signing requests
+    /// advanced by this many blocks (e.g. to detect another signer still running)
+    pub startup_delay_blocks: Option<u64>,
+    /// after a (re)start, nothing is signed for this many seconds
+    pub startup_delay_secs: Option<u64>,
+    /// signatures only released with an approver's token (if set)
+    pub approval: Option<ApprovalPolicy>,
+    /// limits of signing requests per message type
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+}
+
+impl SigningPolicy {
+    /// Check the request complies with the policy (returns the violated rule otherwise)
+    pub fn check(
+        &self,
+        chain_id: &chain::Id,
+        msg_type: SignedMsgKind,
+        height: u64,
+        round: u32,
+        clock: &dyn Clock,
+    ) -> Result<(), String> {
+        if !self.mode.allows(msg_type) {
+            return Err(format!(
+                "{} not allowed in the {} mode",
+                msg_type.as_str(),
+                self.mode.as_str()
+            ));
+        }
+        if let Some(ref types) = self.allowed_msg_types {
+            if !types.contains(&msg_type) {
+                return Err(format!("{} not allowed", msg_type.as_str()));
+            }
+        }
+        if let Some(ref ranges) = self.allowed_heights {
+            if !ranges
+                .iter()
+                .any(|r| r.start <= height && r.end.map_or(true, |end| height <= end))
+            {
+                return Err(format!("height {} not allowed", height));
+            }
+        }
+        if let Some(max_round) = self.max_round {
+            if round > max_round {
+                return Err(format!("round {} exceeds {}", round, max_round));
+            }
+        }
+        if let Some(ref chain_ids) = self.allowed_chain_ids {
+            if !chain_ids.contains(chain_id) {
+                return Err(format!("chain {} not allowed", chain_id));
+            }
+        }
+        if let Some(ref windows) = self.time_windows {
+            let now = clock.now()?;
+            if !windows.iter().any(|w| {
+                w.not_before.map_or(true, |t| t <= now) && w.not_after.map_or(true, |t| now <= t)
+            }) {
+                return Err("outside of the allowed time windows".to_owned());
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the request's timestamp is within the allowed skew from the signer's clock
+    /// (the requests without a timestamp aren't restricted)
+    pub fn check_timestamp(
+        &self,
+        timestamp: Option<Time>,
+        clock: &dyn Clock,
+    ) -> Result<(), String> {
+        let (max_skew, timestamp) = match (self.max_timestamp_skew_secs, timestamp) {
+            (Some(max_skew), Some(timestamp)) => (Duration::from_secs(max_skew), timestamp),
+            _ => return Ok(()),
+        };
+        let now = clock.now()?;
+        let skew = timestamp
+            .duration_since(now)
+            .or_else(|_| now.duration_since(timestamp))
+            .map_err(|e| format!("invalid timestamp: {}", e))?;
+        if skew > max_skew {
+            return Err(format!(
+                "timestamp {} deviates from the signer's time by {}s",
+                timestamp,
+                skew.as_secs()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// the cool-down after a (re)start: nothing is signed until all the configured delays passed
+#[derive(Debug)]
+pub struct StartupDelay {
+    started: Instant,
+    blocks: Option<u64>,
+    duration: Option<Duration>,
+    /// the height of the first signing request
+    first_height: Option<u64>,
+    over: bool,
+}
+
+impl StartupDelay {
+    pub fn new(policy: &SigningPolicy, started: Instant) -> Self {
+        Self {
+            started,
+            blocks: policy.startup_delay_blocks,
+            duration: policy.startup_delay_secs.map(Duration::from_secs),
+            first_height: None,
+            over: policy.startup_delay_blocks.is_none() && policy.startup_delay_secs.is_none(),
+        }
+    }
+
+    /// Check the cool-down is over at the request's height
+    pub fn check(&mut self, height: u64, now: Instant) -> Result<(), String> {
+        if self.over {
+            return Ok(());
+        }
+        let first_height = *self.first_height.get_or_insert(height);
+        let remaining_blocks = self
+            .blocks
+            .map_or(0, |blocks| (first_height + blocks).saturating_sub(height));
+        let remaining = self
+            .duration
+            .map_or(Duration::ZERO, |d| d.saturating_sub(now - self.started));
+        if remaining_blocks == 0 && remaining.is_zero() {
+            self.over = true;
+            return Ok(());
+        }
+        Err(format!(
+            "startup delay ({} blocks, {}s remaining)",
+            remaining_blocks,
+            remaining.as_secs()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+
+    fn chain_id() -> chain::Id {
+        "testchain-1".parse().unwrap()
+    }
+
+    #[test]
+    fn empty_policy_allows_everything() {
+        let policy = SigningPolicy::default();
+        assert!(policy
+            .check(&chain_id(), SignedMsgKind::Precommit, 100, 10, &SystemClock)
+            .is_ok());
+    }
+
+    #[test]
+    fn rules_are_enforced() {
+        let policy = SigningPolicy {
+            mode: SigningMode::All,
+            allowed_msg_types: Some(vec![SignedMsgKind::Prevote, SignedMsgKind::Precommit]),
+            allowed_heights: Some(vec![HeightRange {
+                start: 10,
+                end: Some(20),
+            }]),
+            max_round: Some(3),
+            allowed_chain_ids: Some(vec![chain_id()]),
+            time_windows: Some(vec![TimeWindow {
+                not_before: Some(Time::unix_epoch()),
+                not_after: None,
+            }]),
+            max_height_jump: None,
+            max_timestamp_skew_secs: None,
+            startup_delay_blocks: None,
+            startup_delay_secs: None,
+            approval: None,
+            rate_limits: RateLimits::default(),
+        };
+        assert!(policy
+            .check(&chain_id(), SignedMsgKind::Prevote, 15, 0, &SystemClock)
+            .is_ok());
+        assert!(policy
+            .check(&chain_id(), SignedMsgKind::Proposal, 15, 0, &SystemClock)
+            .is_err());
+        assert!(policy
+            .check(&chain_id(), SignedMsgKind::Prevote, 21, 0, &SystemClock)
+            .is_err());
+        assert!(policy
+            .check(&chain_id(), SignedMsgKind::Prevote, 15, 4, &SystemClock)
+            .is_err());
+        assert!(policy
+            .check(
+                &"other-1".parse().unwrap(),
+                SignedMsgKind::Prevote,
+                15,
+                0,
+                &SystemClock
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn vote_only_mode() {
+        let policy: SigningPolicy = serde_json::from_str(r#"{"mode": "vote-only"}"#).unwrap();
+        assert_eq!(policy.mode, SigningMode::VoteOnly);
+        assert!(policy
+            .check(&chain_id(), SignedMsgKind::Prevote, 1, 0, &SystemClock)
+            .is_ok());
+        assert!(policy
+            .check(&chain_id(), SignedMsgKind::Proposal, 1, 0, &SystemClock)
+            .is_err());
+    }
+
+    #[test]
+    fn expired_time_window() {
+        let policy = SigningPolicy {
+            time_windows: Some(vec![TimeWindow {
+                not_before: None,
+                not_after: Some(Time::unix_epoch()),
+            }]),
+            ..Default::default()
+        };
+        assert!(policy
+            .check(&chain_id(), SignedMsgKind::Prevote, 1, 0, &SystemClock)
+            .is_err());
+    }
+
+    #[test]
+    fn far_future_timestamp() {
+        let policy = SigningPolicy {
+            max_timestamp_skew_secs: Some(30),
+            ..Default::default()
+        };
+        let now = SystemClock.now().unwrap();
+        assert!(policy.check_timestamp(Some(now), &SystemClock).is_ok());
+        assert!(policy.check_timestamp(None, &SystemClock).is_ok());
+        assert!(policy
+            .check_timestamp(
+                Some((now + Duration::from_secs(3600)).unwrap()),
+                &SystemClock
+            )
+            .is_err());
+        assert!(policy
+            .check_timestamp(
+                Some((now - Duration::from_secs(3600)).unwrap()),
+                &SystemClock
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn startup_delay() {
+        let policy = SigningPolicy {
+            startup_delay_blocks: Some(2),
+            startup_delay_secs: Some(10),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        let mut delay = StartupDelay::new(&policy, started);
+        assert!(delay.check(100, started).is_err());
+        assert!(delay.check(102, started).is_err());
+        assert!(delay.check(101, started + Duration::from_secs(10)).is_err());
+        assert!(delay.check(102, started + Duration::from_secs(10)).is_ok());
+        // only once after the restart
+        assert!(delay.check(102, started).is_ok());
+        assert!(StartupDelay::new(&SigningPolicy::default(), started)
+            .check(1, started)
+            .is_ok());
+    }
+}
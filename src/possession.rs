@@ -0,0 +1,61 @@
+//! Proof of possession of the consensus key: signatures of arbitrary payloads
+//! (e.g. for a chain registration or a key rotation proposal) that can never be
+//! valid consensus signatures.
+//!
+//! Consensus sign-bytes are length-delimited protobuf, so their first byte (the varint length)
+//! determines their total length. The signed message starts with a zero byte (which would be
+//! an empty sign-bytes message of length 1) followed by the domain tag and the payload,
+//! so it's always longer than any sign-bytes starting with that byte.
+
+use ed25519_consensus::{Signature, SigningKey, VerificationKey};
+
+/// domain separation of the proof-of-possession signatures
+pub const PROOF_OF_POSSESSION_DOMAIN: &[u8] = b"tmkms-light proof of possession v1";
+
+/// the message actually signed for the payload
+pub fn proof_of_possession_message(payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(1 + PROOF_OF_POSSESSION_DOMAIN.len() + payload.len());
+    message.push(0);
+    message.extend_from_slice(PROOF_OF_POSSESSION_DOMAIN);
+    message.extend_from_slice(payload);
+    message
+}
+
+/// signs the payload (domain-separated)
+pub fn sign_proof_of_possession(key: &SigningKey, payload: &[u8]) -> Signature {
+    key.sign(&proof_of_possession_message(payload))
+}
+
+/// checks the payload's proof-of-possession signature
+pub fn verify_proof_of_possession(
+    public_key: &VerificationKey,
+    payload: &[u8],
+    signature: &Signature,
+) -> bool {
+    public_key
+        .verify(signature, &proof_of_possession_message(payload))
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_domain_separated_payloads() {
+        let key = SigningKey::from([7u8; 32]);
+        let payload = b"register validator";
+        let signature = sign_proof_of_possession(&key, payload);
+        assert!(verify_proof_of_possession(
+            &key.verification_key(),
+            payload,
+            &signature
+        ));
+        // not a signature of the raw payload
+        assert!(key.verification_key().verify(&signature, payload).is_err());
+        // a zero length prefix means a 1-byte sign-bytes message
+        let message = proof_of_possession_message(b"");
+        assert_eq!(message[0], 0);
+        assert!(message.len() > 1);
+    }
+}
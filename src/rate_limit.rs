@@ -0,0 +1,116 @@
+//! Rate limiting of signing requests (token buckets per message type)
+
+use crate::audit::SignedMsgKind;
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+/// up to `requests` in a burst, refilled at `requests` per `period_secs`
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub period_secs: u32,
+}
+
+/// rate limits per message type (not limited if not set)
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimits {
+    pub proposal: Option<RateLimit>,
+    pub prevote: Option<RateLimit>,
+    pub precommit: Option<RateLimit>,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        let capacity = f64::from(limit.requests);
+        Self {
+            capacity,
+            tokens: capacity,
+            refill_per_sec: capacity / f64::from(limit.period_secs.max(1)),
+            last_refill: now,
+        }
+    }
+
+    fn try_acquire(&mut self, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Token bucket state of the configured limits
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    proposal: Option<TokenBucket>,
+    prevote: Option<TokenBucket>,
+    precommit: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: &RateLimits) -> Self {
+        let now = Instant::now();
+        Self {
+            proposal: limits.proposal.as_ref().map(|l| TokenBucket::new(l, now)),
+            prevote: limits.prevote.as_ref().map(|l| TokenBucket::new(l, now)),
+            precommit: limits.precommit.as_ref().map(|l| TokenBucket::new(l, now)),
+        }
+    }
+
+    /// Takes a token for the message type (returns false if the limit is hit)
+    pub fn try_acquire(&mut self, msg_type: SignedMsgKind) -> bool {
+        self.try_acquire_at(msg_type, Instant::now())
+    }
+
+    fn try_acquire_at(&mut self, msg_type: SignedMsgKind, now: Instant) -> bool {
+        let bucket = match msg_type {
+            SignedMsgKind::Proposal => self.proposal.as_mut(),
+            SignedMsgKind::Prevote => self.prevote.as_mut(),
+            SignedMsgKind::Precommit => self.precommit.as_mut(),
+        };
+        bucket.map_or(true, |b| b.try_acquire(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn bucket_is_refilled() {
+        let limits = RateLimits {
+            prevote: Some(RateLimit {
+                requests: 2,
+                period_secs: 2,
+            }),
+            ..Default::default()
+        };
+        let mut limiter = RateLimiter::new(&limits);
+        let start = Instant::now();
+        assert!(limiter.try_acquire_at(SignedMsgKind::Prevote, start));
+        assert!(limiter.try_acquire_at(SignedMsgKind::Prevote, start));
+        assert!(!limiter.try_acquire_at(SignedMsgKind::Prevote, start));
+        // other types aren't limited
+        assert!(limiter.try_acquire_at(SignedMsgKind::Precommit, start));
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.try_acquire_at(SignedMsgKind::Prevote, later));
+        assert!(!limiter.try_acquire_at(SignedMsgKind::Prevote, later));
+    }
+}
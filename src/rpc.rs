@@ -5,7 +5,7 @@
 use crate::error::Error;
 use prost::Message as _;
 use std::convert::TryFrom;
-use std::io::Read;
+use std::io::{self, Read};
 use tendermint::proposal::{SignProposalRequest, SignedProposalResponse};
 use tendermint::public_key::{PubKeyRequest, PublicKey};
 use tendermint::vote::{SignVoteRequest, SignedVoteResponse};
@@ -32,12 +32,12 @@ pub enum Request {
 }
 
 impl Request {
-    /// Read a request from the given readable
-    pub fn read(conn: &mut impl Read) -> Result<Self, Error> {
-        let msg = read_msg(conn)?;
+    /// Read a request from the given readable (into the reused buffer)
+    pub fn read(conn: &mut impl Read, buf: &mut Vec<u8>) -> Result<Self, Error> {
+        read_msg(conn, buf)?;
 
         // Parse Protobuf-encoded request message
-        let msg = PrivMessage::decode_length_delimited(msg.as_ref())
+        let msg = PrivMessage::decode_length_delimited(buf.as_slice())
             .map_err(|e| Error::protocol_error("malformed message packet".into(), e.into()))?
             .sum;
 
@@ -91,6 +91,12 @@ pub enum DoubleSignErrorType {
     Proposal,
 }
 
+/// possible options for refused signing requests
+pub enum SignErrorType {
+    Vote,
+    Proposal,
+}
+
 /// possible options for chain id error
 pub enum ChainIdErrorType {
     Pubkey,
@@ -134,6 +140,18 @@ impl Response {
         }
     }
 
+    /// signing refused (e.g. the session is paused)
+    pub fn signing_refused(req_type: SignErrorType, reason: &str) -> Self {
+        let error = RemoteSignerError {
+            code: 3,
+            description: format!("signing refused: {}", reason),
+        };
+        match req_type {
+            SignErrorType::Vote => Self::SignedVoteError(error),
+            SignErrorType::Proposal => Self::SignedProposalError(error),
+        }
+    }
+
     /// invalid chain id error
     pub fn invalid_chain_id(req_type: ChainIdErrorType, chain_id: &tendermint::chain::Id) -> Self {
         let error = RemoteSignerError {
@@ -147,9 +165,9 @@ impl Response {
         }
     }
 
-    /// Encode response to bytes
-    pub fn encode(self) -> Result<Vec<u8>, Error> {
-        let mut buf = Vec::new();
+    /// Encode response to bytes (into the reused buffer)
+    pub fn encode(self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        buf.clear();
 
         let msg = match self {
             Response::SignedVote(resp) => Sum::SignedVoteResponse(resp.into()),
@@ -181,19 +199,20 @@ impl Response {
         };
 
         PrivMessage { sum: Some(msg) }
-            .encode_length_delimited(&mut buf)
-            .map_err(|e| Error::protocol_error("failed to encode response".into(), e.into()))?;
-        Ok(buf)
+            .encode_length_delimited(buf)
+            .map_err(|e| Error::protocol_error("failed to encode response".into(), e.into()))
     }
 }
 
 /// Read a message from a Secret Connection
 // TODO(tarcieri): extract this into Secret Connection
-fn read_msg(conn: &mut impl Read) -> Result<Vec<u8>, Error> {
-    let mut buf = vec![0; DATA_MAX_SIZE];
-    let buf_read = conn
-        .read(&mut buf)
-        .map_err(|e| Error::io_error("read msg failed".into(), e))?;
+fn read_msg(conn: &mut impl Read, buf: &mut Vec<u8>) -> Result<(), Error> {
+    buf.resize(DATA_MAX_SIZE, 0);
+    let buf_read = conn.read(buf).map_err(|e| match e.kind() {
+        // nothing was read (so it can be retried)
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Error::read_timeout(),
+        _ => Error::io_error("read msg failed".into(), e),
+    })?;
     buf.truncate(buf_read);
-    Ok(buf)
+    Ok(())
 }
@@ -2,16 +2,186 @@
 //! Modifications Copyright (c) 2021-present Crypto.com (licensed under the Apache License, Version 2.0)
 
 use crate::{
-    chain::state::{PersistStateSync, State, StateError, StateErrorDetail},
+    approval::{ApprovalRequest, Approver},
+    audit::{AuditSink, RefusedMessage, SignedMessage, SignedMsgKind},
+    chain::state::{consensus, PersistStateSync, State, StateError, StateErrorDetail},
+    clock::{Clock, SystemClock},
     config::validator::ValidatorConfig,
     connection::Connection,
-    error::Error,
-    rpc::{ChainIdErrorType, DoubleSignErrorType, Request, Response},
+    error::{Error, ErrorDetail},
+    metrics::{MetricsEvent, MetricsSink},
+    policy::StartupDelay,
+    rate_limit::RateLimiter,
+    rpc::{ChainIdErrorType, DoubleSignErrorType, Request, Response, SignErrorType},
 };
-use ed25519_consensus::SigningKey;
-use std::time::Instant;
+use ed25519_consensus::{Signature, SigningKey, VerificationKey};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tendermint::proposal::SignProposalRequest;
+use tendermint::vote::SignVoteRequest;
+use tendermint_p2p::secret_connection::DATA_MAX_SIZE;
 use tendermint_proto::privval::PingResponse;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, info_span, warn};
+use zeroize::Zeroize;
+
+/// the signed message type of the vote
+fn vote_kind(vote: &tendermint::Vote) -> SignedMsgKind {
+    match vote.vote_type {
+        tendermint::vote::Type::Prevote => SignedMsgKind::Prevote,
+        tendermint::vote::Type::Precommit => SignedMsgKind::Precommit,
+    }
+}
+
+/// the refusal reason of the signing requests in the dry-run mode
+pub const DRY_RUN_REFUSAL: &str = "dry run: signing disabled";
+
+/// the request type (in the request spans)
+fn request_name(request: &Request) -> &'static str {
+    match request {
+        Request::SignProposal(_) => "proposal",
+        Request::SignVote(req) => vote_kind(&req.vote).as_str(),
+        Request::ShowPublicKey(_) => "pubkey",
+        Request::ReplyPing(_) => "ping",
+    }
+}
+
+/// the signing request without its refusal reason (with the request's canonical sign-bytes)
+fn refused_message(request: &Request) -> Option<RefusedMessage> {
+    let (chain_id, msg_type, height, round, sign_bytes) = match request {
+        Request::SignProposal(req) => (
+            &req.chain_id,
+            SignedMsgKind::Proposal,
+            req.proposal.height,
+            req.proposal.round,
+            req.to_signable_vec(),
+        ),
+        Request::SignVote(req) => (
+            &req.chain_id,
+            vote_kind(&req.vote),
+            req.vote.height,
+            req.vote.round,
+            req.to_signable_vec(),
+        ),
+        _ => return None,
+    };
+    Some(RefusedMessage {
+        chain_id: chain_id.clone(),
+        msg_type,
+        height: height.into(),
+        round: round.value().into(),
+        reason: String::new(),
+        sign_bytes: sign_bytes.ok()?,
+    })
+}
+
+/// the error of a refused signing request (the dry-run refusals aren't ones)
+fn refusal_reason(response: &Response) -> Option<&str> {
+    match response {
+        Response::SignedVoteError(e) | Response::SignedProposalError(e)
+            if !e.description.ends_with(DRY_RUN_REFUSAL) =>
+        {
+            Some(&e.description)
+        }
+        _ => None,
+    }
+}
+
+/// Signing status of a session
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionStatus {
+    /// requests are signed
+    #[default]
+    Running,
+    /// signing requests are refused (other requests are still answered)
+    Paused,
+    /// as `Paused`, but reported as a maintenance (e.g. a planned handover to a backup signer)
+    Maintenance,
+    /// the request loop exits on the next request
+    Stopped,
+}
+
+impl SessionStatus {
+    /// why signing requests are refused in this status (if they are)
+    pub fn signing_refusal(self) -> Option<&'static str> {
+        match self {
+            SessionStatus::Running => None,
+            SessionStatus::Paused => Some("session paused"),
+            SessionStatus::Maintenance => Some("signer in maintenance"),
+            SessionStatus::Stopped => Some("session stopped"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ControlState {
+    status: RwLock<SessionStatus>,
+    /// a request is being handled (e.g. signed and its state persisted)
+    in_flight: AtomicBool,
+    /// the operator allowed the next height jump over `max_height_jump`
+    allow_height_jump: AtomicBool,
+}
+
+/// Handle to control a session from outside of its request loop
+#[derive(Clone, Debug, Default)]
+pub struct SessionControl(Arc<ControlState>);
+
+/// marks the session's request as handled when dropped
+struct InFlight(Arc<ControlState>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.store(false, Ordering::SeqCst);
+    }
+}
+
+impl SessionControl {
+    /// current status of the session
+    pub fn status(&self) -> SessionStatus {
+        *self.0.status.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// changes the status of the session
+    pub fn set_status(&self, status: SessionStatus) {
+        *self.0.status.write().unwrap_or_else(|e| e.into_inner()) = status;
+    }
+
+    /// a request is being handled
+    pub fn in_flight(&self) -> bool {
+        self.0.in_flight.load(Ordering::SeqCst)
+    }
+
+    fn begin_request(&self) -> InFlight {
+        self.0.in_flight.store(true, Ordering::SeqCst);
+        InFlight(self.0.clone())
+    }
+
+    /// allows the next signing request to exceed the policy's `max_height_jump` (once)
+    pub fn allow_next_height_jump(&self) {
+        self.0.allow_height_jump.store(true, Ordering::SeqCst);
+    }
+
+    /// consumes the operator's override of the height jump check
+    fn take_height_jump_override(&self) -> bool {
+        self.0.allow_height_jump.swap(false, Ordering::SeqCst)
+    }
+
+    /// pauses the session and waits until its in-flight request (if any) is handled
+    /// (returns `false` if it isn't within the timeout)
+    pub fn drain(&self, timeout: Duration) -> bool {
+        self.set_status(SessionStatus::Paused);
+        let started = Instant::now();
+        while self.in_flight() {
+            if started.elapsed() >= timeout {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+}
 
 /// Encrypted or plain session with a validator node
 pub struct Session<S: PersistStateSync> {
@@ -29,50 +199,435 @@ pub struct Session<S: PersistStateSync> {
 
     /// consensus state persistence
     state_syncer: S,
+
+    /// external control of the signing status
+    control: SessionControl,
+
+    /// signature audit records (if enabled)
+    audit_sink: Option<Box<dyn AuditSink>>,
+
+    /// request metrics (if enabled)
+    metrics_sink: Option<Box<dyn MetricsSink>>,
+
+    /// signing request rate limits
+    rate_limiter: RateLimiter,
+
+    /// the cool-down after the session started
+    startup_delay: StartupDelay,
+
+    /// message types that can be signed over the current connection (if restricted)
+    connection_msg_types: Option<Vec<SignedMsgKind>>,
+
+    /// after which an idle connection is torn down (if set)
+    idle_timeout: Option<Duration>,
+
+    /// when the last request was received
+    last_request: Instant,
+
+    /// whether the signing requests are only simulated (never signed nor persisted)
+    dry_run: bool,
+
+    /// whether the sign-bytes of the refused requests are sent to the audit sink
+    dump_refused_sign_bytes: bool,
+
+    /// the last height committed with the validator's signature (according to the chain),
+    /// at or below which nothing is signed
+    committed_height: Option<u64>,
+
+    /// approver of the signatures that need a second party's approval (and its key)
+    approver: Option<(Box<dyn Approver>, VerificationKey)>,
+
+    /// wall clock of the time-based policies and the audit records
+    clock: Arc<dyn Clock>,
+
+    /// reused buffers of the requests and responses (no per-request allocations)
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
 }
 
 impl<S: PersistStateSync> Session<S> {
     pub fn reset_connection(&mut self, connection: Box<dyn Connection>) {
         self.connection = connection;
+        self.last_request = Instant::now();
     }
 
     pub fn new(
         config: ValidatorConfig,
         connection: Box<dyn Connection>,
         signing_key: SigningKey,
-        state: State,
+        mut state: State,
         state_syncer: S,
     ) -> Self {
+        state.verify_last_signed(&signing_key.verification_key());
+        let rate_limiter = RateLimiter::new(&config.signing_policy.rate_limits);
+        let startup_delay = StartupDelay::new(&config.signing_policy, Instant::now());
         Self {
             config,
             connection,
             signing_key,
             state,
             state_syncer,
+            control: SessionControl::default(),
+            audit_sink: None,
+            metrics_sink: None,
+            rate_limiter,
+            startup_delay,
+            connection_msg_types: None,
+            idle_timeout: None,
+            last_request: Instant::now(),
+            dry_run: false,
+            dump_refused_sign_bytes: false,
+            committed_height: None,
+            approver: None,
+            clock: Arc::new(SystemClock),
+            read_buf: Vec::with_capacity(DATA_MAX_SIZE),
+            write_buf: Vec::with_capacity(DATA_MAX_SIZE),
         }
     }
 
-    /// Check chain id matches the configured one
+    /// tears down the connection when no request (including the validator's pings) arrives in time;
+    /// the connection's read timeout determines how often it's checked
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.idle_timeout = Some(idle_timeout);
+    }
+
+    /// handles the signing requests up to the state update (decoding, the checks and the double
+    /// signing prevention against the in-memory state), but refuses them instead of signing
+    /// and never persists the state (e.g. for a shadow deployment validating the config
+    /// and the connectivity before a cutover)
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// records every produced signature in the sink before it's sent to the validator
+    pub fn set_audit_sink(&mut self, audit_sink: Box<dyn AuditSink>) {
+        self.audit_sink = Some(audit_sink);
+    }
+
+    /// replaces the system clock (e.g. with one synchronized from outside of a TEE)
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// sends the canonical sign-bytes of the refused signing requests to the audit sink
+    /// (a debug facility for the signature mismatch or conflicting vote disputes)
+    pub fn set_dump_refused_sign_bytes(&mut self, dump_refused_sign_bytes: bool) {
+        self.dump_refused_sign_bytes = dump_refused_sign_bytes;
+    }
+
+    /// restricts the message types signed over the current connection
+    /// (e.g. a failover validator that only votes); kept until it's changed
+    pub fn set_connection_msg_types(&mut self, msg_types: Option<Vec<SignedMsgKind>>) {
+        self.connection_msg_types = msg_types;
+    }
+
+    /// refuses to sign at or below the last height the chain committed with the validator's
+    /// signature (an independent backstop to the persisted state, e.g. queried from a node's RPC)
+    pub fn set_committed_height(&mut self, committed_height: u64) {
+        self.committed_height = Some(committed_height);
+    }
+
+    /// requests the approvals of the signatures required by the policy's two-person rule
+    /// (without an approver, they're refused)
+    pub fn set_approver(&mut self, approver: Box<dyn Approver>, approver_key: VerificationKey) {
+        self.approver = Some((approver, approver_key));
+    }
+
+    /// Record the refused request (if auditing is enabled; a failure doesn't stop the session)
+    fn audit_refusal(&mut self, msg: RefusedMessage) {
+        if let Some(sink) = self.audit_sink.as_mut() {
+            if let Err(e) = sink.record_refusal(&msg) {
+                warn!(
+                    "[{}] failed to record the refused request: {}",
+                    &self.config.chain_id, e
+                );
+            }
+        }
+    }
+
+    /// the audit record of a signed proposal
+    fn proposal_record(&self, req: &SignProposalRequest, signature: &Signature) -> SignedMessage {
+        SignedMessage {
+            chain_id: req.chain_id.clone(),
+            msg_type: SignedMsgKind::Proposal,
+            height: req.proposal.height.into(),
+            round: req.proposal.round.value().into(),
+            block_id_hash: req.proposal.block_id.map(|id| id.hash.as_bytes().to_vec()),
+            signature: signature.to_bytes().to_vec(),
+            timestamp: self.clock.now().ok(),
+        }
+    }
+
+    /// the audit record of a signed vote
+    fn vote_record(&self, req: &SignVoteRequest, signature: &Signature) -> SignedMessage {
+        SignedMessage {
+            chain_id: req.chain_id.clone(),
+            msg_type: vote_kind(&req.vote),
+            height: req.vote.height.into(),
+            round: req.vote.round.value().into(),
+            block_id_hash: req.vote.block_id.map(|id| id.hash.as_bytes().to_vec()),
+            signature: signature.to_bytes().to_vec(),
+            timestamp: self.clock.now().ok(),
+        }
+    }
+
+    /// Record the produced signature (if auditing is enabled)
+    fn audit(&mut self, msg: SignedMessage) -> Result<(), Error> {
+        if let Some(sink) = self.audit_sink.as_mut() {
+            sink.record(&msg)?;
+        }
+        Ok(())
+    }
+
+    /// sends an event for every signature and ping to the sink
+    pub fn set_metrics_sink(&mut self, metrics_sink: Box<dyn MetricsSink>) {
+        self.metrics_sink = Some(metrics_sink);
+    }
+
+    /// Record the handled request (if metrics are enabled)
+    fn record_metrics(&mut self, event: MetricsEvent) {
+        if let Some(sink) = self.metrics_sink.as_mut() {
+            sink.record(&event);
+        }
+    }
+
+    /// handle to pause, resume or stop this session
+    pub fn control(&self) -> SessionControl {
+        self.control.clone()
+    }
+
+    /// Check chain id matches the configured one (alerting on a mismatch,
+    /// e.g. a misconfigured or malicious validator node)
     fn check_chain_id(&self, chain_id: &tendermint::chain::Id) -> Result<(), Error> {
         if chain_id == &self.config.chain_id {
             Ok(())
         } else {
+            error!(
+                chain_id = %self.config.chain_id,
+                alert = true,
+                "[{}] request for chain {} refused",
+                &self.config.chain_id,
+                chain_id
+            );
             Err(Error::chain_id_error(chain_id.to_string()))
         }
     }
 
     /// If a max block height is configured, ensure the block we're signing
-    /// doesn't exceed it
-    fn check_max_height(&self, request_height: i64) -> Result<(), Error> {
+    /// doesn't exceed it (the requests at the max height are still signed,
+    /// the first one above it is refused and the session stops after answering it)
+    fn check_max_height(&mut self, request_height: i64) -> Result<(), Error> {
         if let Some(max_height) = self.config.max_height {
             if request_height > max_height.value() as i64 {
+                info!(
+                    "[{}] max height {} reached, stopping the session at h/r/s {}",
+                    &self.config.chain_id,
+                    max_height,
+                    self.state.consensus_state()
+                );
                 return Err(Error::exceed_max_height(request_height, max_height.into()));
             }
         }
         Ok(())
     }
 
-    /// Main request loop
+    /// the last signed (and persisted) consensus state
+    pub fn consensus_state(&self) -> &consensus::State {
+        self.state.consensus_state()
+    }
+
+    /// Check signing isn't paused or in maintenance
+    fn check_signing_allowed(&self) -> Result<(), &'static str> {
+        match self.control.status().signing_refusal() {
+            Some(reason) => Err(reason),
+            None => Ok(()),
+        }
+    }
+
+    /// Check the request complies with the signing policy
+    fn check_policy(
+        &self,
+        chain_id: &tendermint::chain::Id,
+        msg_type: SignedMsgKind,
+        height: u64,
+        round: u32,
+        timestamp: Option<tendermint::Time>,
+    ) -> Result<(), String> {
+        let _span = info_span!("policy_check").entered();
+        let policy = &self.config.signing_policy;
+        policy
+            .check(chain_id, msg_type, height, round, self.clock.as_ref())
+            .and_then(|_| match &self.connection_msg_types {
+                Some(types) if !types.contains(&msg_type) => Err(format!(
+                    "{} not allowed over this connection",
+                    msg_type.as_str()
+                )),
+                _ => Ok(()),
+            })
+            .and_then(|_| policy.check_timestamp(timestamp, self.clock.as_ref()))
+            .map_err(|reason| {
+                warn!(
+                    "[{}] signing policy violation: {}",
+                    &self.config.chain_id, reason
+                );
+                format!("policy violation: {}", reason)
+            })
+    }
+
+    /// Check the height doesn't jump too far from the last signed one
+    /// (a fresh state at height 0 isn't checked)
+    fn check_height_jump(&self, height: u64) -> Result<(), String> {
+        let max_jump = match self.config.signing_policy.max_height_jump {
+            Some(max_jump) => max_jump,
+            None => return Ok(()),
+        };
+        let last_height = self.state.consensus_state().height.value();
+        if last_height == 0 || height <= last_height.saturating_add(max_jump) {
+            return Ok(());
+        }
+        if self.control.take_height_jump_override() {
+            warn!(
+                "[{}] height jump from {} to {} allowed by the operator",
+                &self.config.chain_id, last_height, height
+            );
+            return Ok(());
+        }
+        error!(
+            chain_id = %self.config.chain_id,
+            alert = true,
+            "[{}] height jump from {} to {} exceeds {} (wrong state file or network?)",
+            &self.config.chain_id,
+            last_height,
+            height,
+            max_jump
+        );
+        Err(format!(
+            "height jump from {} to {} exceeds {}",
+            last_height, height, max_jump
+        ))
+    }
+
+    /// Check the height is above the last one committed with the validator's signature
+    fn check_committed_height(&self, height: u64) -> Result<(), String> {
+        match self.committed_height {
+            Some(committed) if height <= committed => {
+                error!(
+                    chain_id = %self.config.chain_id,
+                    alert = true,
+                    "[{}] request at height {}, but the chain committed height {} with our signature \
+                     (stale state?)",
+                    &self.config.chain_id,
+                    height,
+                    committed
+                );
+                Err(format!(
+                    "height {} is at or below the committed height {}",
+                    height, committed
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the cool-down after the start is over
+    fn check_startup_delay(&mut self, height: u64) -> Result<(), String> {
+        self.startup_delay
+            .check(height, Instant::now())
+            .map_err(|reason| {
+                info!("[{}] signing refused: {}", &self.config.chain_id, reason);
+                reason
+            })
+    }
+
+    /// Check the request is within the rate limit of its type
+    fn check_rate_limit(&mut self, msg_type: SignedMsgKind) -> Result<(), String> {
+        if self.rate_limiter.try_acquire(msg_type) {
+            Ok(())
+        } else {
+            warn!(
+                "[{}] {} rate limit exceeded",
+                &self.config.chain_id,
+                msg_type.as_str()
+            );
+            Err("rate limit exceeded".to_owned())
+        }
+    }
+
+    /// Check the approver approved the signature (if the policy requires it)
+    fn check_approval(
+        &mut self,
+        chain_id: &tendermint::chain::Id,
+        msg_type: SignedMsgKind,
+        new_state: &consensus::State,
+        signable_bytes: &[u8],
+    ) -> Result<(), String> {
+        let required = self
+            .config
+            .signing_policy
+            .approval
+            .as_ref()
+            .map_or(false, |a| {
+                a.requires_approval(msg_type, new_state.height.value())
+            });
+        if !required || self.dry_run {
+            return Ok(());
+        }
+        let _span = info_span!("approval").entered();
+        let (approver, approver_key) = self
+            .approver
+            .as_mut()
+            .ok_or_else(|| "approval required, but no approver is set".to_owned())?;
+        let request = ApprovalRequest {
+            chain_id: chain_id.clone(),
+            msg_type,
+            state: new_state.clone(),
+            sign_bytes: signable_bytes.to_vec(),
+        };
+        approver
+            .request_approval(&request)
+            .and_then(|token| token.verify(approver_key, &request))
+            .map_err(|reason| {
+                error!(
+                    chain_id = %self.config.chain_id,
+                    alert = true,
+                    "[{}] {} at h/r/s {} not approved: {}",
+                    &self.config.chain_id,
+                    msg_type.as_str(),
+                    new_state,
+                    reason
+                );
+                format!("not approved: {}", reason)
+            })
+    }
+
+    /// Sign the bytes and persist the new consensus state (with the signed message);
+    /// the signature is only returned once the state was persisted
+    /// (in the dry-run mode, the state update is only simulated and nothing is signed)
+    fn persist_and_sign(
+        &mut self,
+        new_state: &consensus::State,
+        signable_bytes: &[u8],
+    ) -> Result<Option<(Signature, Duration)>, StateError> {
+        if self.dry_run {
+            self.state
+                .simulate_update_consensus_state(new_state.clone())?;
+            return Ok(None);
+        }
+        let started_at = Instant::now();
+        let signature = info_span!("sign").in_scope(|| self.signing_key.sign(signable_bytes));
+        let signing_time = started_at.elapsed();
+        info_span!("state_persist").in_scope(|| {
+            self.state.check_update_signed_consensus_state(
+                new_state.clone(),
+                &mut self.state_syncer,
+                signable_bytes,
+                &signature,
+            )
+        })?;
+        Ok(Some((signature, signing_time)))
+    }
+
+    /// Main request loop (returns when the session is stopped)
     pub fn request_loop(&mut self) -> Result<(), Error> {
         while self.handle_request()? {}
         Ok(())
@@ -80,63 +635,171 @@ impl<S: PersistStateSync> Session<S> {
 
     /// Handle an incoming request from the validator
     fn handle_request(&mut self) -> Result<bool, Error> {
-        let request = Request::read(&mut self.connection)?;
+        let request = match Request::read(&mut self.connection, &mut self.read_buf) {
+            Err(e) if matches!(e.detail(), ErrorDetail::ReadTimeout(_)) => {
+                if self.control.status() == SessionStatus::Stopped {
+                    info!("[{}] session stopped", &self.config.chain_id);
+                    return Ok(false);
+                }
+                let idle_timeout = match self.idle_timeout {
+                    Some(t) => t,
+                    None => return Err(e),
+                };
+                if self.last_request.elapsed() < idle_timeout {
+                    return Ok(true);
+                }
+                warn!(
+                    "[{}] validator connection idle for {}s, recycling it",
+                    &self.config.chain_id,
+                    idle_timeout.as_secs()
+                );
+                return Err(Error::idle_timeout(idle_timeout.as_secs()));
+            }
+            r => r?,
+        };
+        self.last_request = Instant::now();
         debug!(
             "[{}] received request: {:?}",
             &self.config.chain_id, &request
         );
+        if self.control.status() == SessionStatus::Stopped {
+            info!("[{}] session stopped", &self.config.chain_id);
+            return Ok(false);
+        }
+        let _in_flight = self.control.begin_request();
+        // spans of the request handling (exported as traces if enabled)
+        let _request_span = info_span!(
+            "request",
+            chain_id = %self.config.chain_id,
+            request = request_name(&request)
+        )
+        .entered();
+        let mut max_height_error = None;
+        let refused = if self.dump_refused_sign_bytes {
+            refused_message(&request)
+        } else {
+            None
+        };
         let response = match request {
             Request::SignProposal(req) => {
                 if self.check_chain_id(&req.chain_id).is_err() {
                     Response::invalid_chain_id(ChainIdErrorType::Proposal, &req.chain_id)
+                } else if let Err(reason) = self.check_signing_allowed() {
+                    Response::signing_refused(SignErrorType::Proposal, reason)
+                } else if let Err(reason) = self.check_policy(
+                    &req.chain_id,
+                    SignedMsgKind::Proposal,
+                    req.proposal.height.value(),
+                    req.proposal.round.value(),
+                    req.proposal.timestamp,
+                ) {
+                    Response::signing_refused(SignErrorType::Proposal, &reason)
+                } else if let Err(reason) = self.check_height_jump(req.proposal.height.value()) {
+                    Response::signing_refused(SignErrorType::Proposal, &reason)
+                } else if let Err(reason) = self.check_committed_height(req.proposal.height.value())
+                {
+                    Response::signing_refused(SignErrorType::Proposal, &reason)
+                } else if let Err(reason) = self.check_startup_delay(req.proposal.height.value()) {
+                    Response::signing_refused(SignErrorType::Proposal, &reason)
+                } else if let Err(reason) = self.check_rate_limit(SignedMsgKind::Proposal) {
+                    Response::signing_refused(SignErrorType::Proposal, &reason)
+                } else if let Err(e) = self.check_max_height(req.proposal.height.into()) {
+                    max_height_error = Some(e);
+                    Response::signing_refused(SignErrorType::Proposal, "max height reached")
                 } else {
-                    self.check_max_height(req.proposal.height.into())?;
                     let request_state = State::from(req.clone());
                     let req_cs = request_state.consensus_state();
-                    match self
-                        .state
-                        .check_update_consensus_state(req_cs.clone(), &mut self.state_syncer)
+                    let signable_bytes = req.to_signable_vec().map_err(|e| {
+                        Error::signing_tendermint_error(
+                            "can't get proposal signable bytes".into(),
+                            e,
+                        )
+                    })?;
+                    if let Some((signature, audited)) =
+                        self.state.cached_signature(req_cs, &signable_bytes)
                     {
-                        Ok(_) => {
-                            let signable_bytes = req.to_signable_vec().map_err(|e| {
-                                Error::signing_tendermint_error(
-                                    "can't get proposal signable bytes".into(),
-                                    e,
-                                )
-                            })?;
-                            let started_at = Instant::now();
-                            let signature = self.signing_key.sign(&signable_bytes);
-                            info!(
-                                "[{}] signed:{} at h/r/s {} ({} ms)",
-                                &self.config.chain_id,
-                                req_cs.block_id_prefix(),
-                                req_cs,
-                                started_at.elapsed().as_millis(),
-                            );
-                            Response::proposal_response(req, signature)
-                        }
-                        Err(StateError(StateErrorDetail::DoubleSignError(_), _)) => {
-                            // Report double signing error back to the validator
-                            let original_block_id = self.state.consensus_state().block_id_prefix();
-
-                            error!(
-                                "[{}] attempted double sign at h/r/s: {} ({} != {})",
-                                &self.config.chain_id,
-                                req_cs,
-                                original_block_id,
-                                req_cs.block_id_prefix()
-                            );
-
-                            Response::double_sign(
-                                DoubleSignErrorType::Proposal,
-                                req_cs.height.into(),
-                            )
+                        info!(
+                            "[{}] proposal re-requested at h/r/s {}, returning the previous signature",
+                            &self.config.chain_id, req_cs,
+                        );
+                        if !audited {
+                            // restored with the state: the audit may not have completed
+                            self.audit(self.proposal_record(&req, &signature))?;
+                            self.state.mark_audited();
                         }
-                        Err(e) => {
-                            return Err(Error::signing_state_error(
-                                "failed signing proposal".into(),
-                                e,
-                            ))
+                        Response::proposal_response(req, signature)
+                    } else if let Err(reason) = self.check_approval(
+                        &req.chain_id,
+                        SignedMsgKind::Proposal,
+                        req_cs,
+                        &signable_bytes,
+                    ) {
+                        Response::signing_refused(SignErrorType::Proposal, &reason)
+                    } else {
+                        match self.persist_and_sign(req_cs, &signable_bytes) {
+                            Ok(None) => {
+                                info!(
+                                    "[{}] dry run: would have signed:{} at h/r/s {}",
+                                    &self.config.chain_id,
+                                    req_cs.block_id_prefix(),
+                                    req_cs,
+                                );
+                                Response::signing_refused(SignErrorType::Proposal, DRY_RUN_REFUSAL)
+                            }
+                            Ok(Some((signature, signing_time))) => {
+                                info!(
+                                    chain_id = %self.config.chain_id,
+                                    height = req_cs.height.value(),
+                                    round = req_cs.round.value(),
+                                    step = req_cs.step,
+                                    msg_type = "proposal",
+                                    "[{}] signed:{} at h/r/s {} ({} ms)",
+                                    &self.config.chain_id,
+                                    req_cs.block_id_prefix(),
+                                    req_cs,
+                                    signing_time.as_millis(),
+                                );
+                                self.audit(self.proposal_record(&req, &signature))?;
+                                // only returned for a re-request once it's in the audit log
+                                self.state.record_signature(signable_bytes, signature);
+                                self.record_metrics(MetricsEvent::Signed {
+                                    chain_id: req.chain_id.clone(),
+                                    msg_type: SignedMsgKind::Proposal,
+                                    height: req_cs.height.into(),
+                                    round: req_cs.round.value().into(),
+                                });
+                                Response::proposal_response(req, signature)
+                            }
+                            Err(StateError(StateErrorDetail::DoubleSignError(_), _)) => {
+                                // Report double signing error back to the validator
+                                let original_block_id =
+                                    self.state.consensus_state().block_id_prefix();
+
+                                error!(
+                                    chain_id = %self.config.chain_id,
+                                    alert = true,
+                                    height = req_cs.height.value(),
+                                    round = req_cs.round.value(),
+                                    step = req_cs.step,
+                                    msg_type = "proposal",
+                                    "[{}] attempted double sign at h/r/s: {} ({} != {})",
+                                    &self.config.chain_id,
+                                    req_cs,
+                                    original_block_id,
+                                    req_cs.block_id_prefix()
+                                );
+
+                                Response::double_sign(
+                                    DoubleSignErrorType::Proposal,
+                                    req_cs.height.into(),
+                                )
+                            }
+                            Err(e) => {
+                                return Err(Error::signing_state_error(
+                                    "failed signing proposal".into(),
+                                    e,
+                                ))
+                            }
                         }
                     }
                 }
@@ -144,54 +807,129 @@ impl<S: PersistStateSync> Session<S> {
             Request::SignVote(req) => {
                 if self.check_chain_id(&req.chain_id).is_err() {
                     Response::invalid_chain_id(ChainIdErrorType::Vote, &req.chain_id)
+                } else if let Err(reason) = self.check_signing_allowed() {
+                    Response::signing_refused(SignErrorType::Vote, reason)
+                } else if let Err(reason) = self.check_policy(
+                    &req.chain_id,
+                    vote_kind(&req.vote),
+                    req.vote.height.value(),
+                    req.vote.round.value(),
+                    req.vote.timestamp,
+                ) {
+                    Response::signing_refused(SignErrorType::Vote, &reason)
+                } else if let Err(reason) = self.check_height_jump(req.vote.height.value()) {
+                    Response::signing_refused(SignErrorType::Vote, &reason)
+                } else if let Err(reason) = self.check_committed_height(req.vote.height.value()) {
+                    Response::signing_refused(SignErrorType::Vote, &reason)
+                } else if let Err(reason) = self.check_startup_delay(req.vote.height.value()) {
+                    Response::signing_refused(SignErrorType::Vote, &reason)
+                } else if let Err(reason) = self.check_rate_limit(vote_kind(&req.vote)) {
+                    Response::signing_refused(SignErrorType::Vote, &reason)
+                } else if let Err(e) = self.check_max_height(req.vote.height.into()) {
+                    max_height_error = Some(e);
+                    Response::signing_refused(SignErrorType::Vote, "max height reached")
                 } else {
-                    self.check_max_height(req.vote.height.into())?;
                     let request_state = State::from(req.clone());
                     let req_cs = request_state.consensus_state();
-                    match self
-                        .state
-                        .check_update_consensus_state(req_cs.clone(), &mut self.state_syncer)
+                    let signable_bytes = req.to_signable_vec().map_err(|e| {
+                        Error::signing_tendermint_error("cannot get vote signable bytes".into(), e)
+                    })?;
+                    if let Some((signature, audited)) =
+                        self.state.cached_signature(req_cs, &signable_bytes)
                     {
-                        Ok(_) => {
-                            let signable_bytes = req.to_signable_vec().map_err(|e| {
-                                Error::signing_tendermint_error(
-                                    "cannot get vote signable bytes".into(),
-                                    e,
-                                )
-                            })?;
-                            let started_at = Instant::now();
-                            let signature = self.signing_key.sign(&signable_bytes);
-                            info!(
-                                "[{}] signed:{} at h/r/s {} ({} ms)",
-                                &self.config.chain_id,
-                                req_cs.block_id_prefix(),
-                                req_cs,
-                                started_at.elapsed().as_millis(),
-                            );
-                            Response::vote_response(req, signature)
+                        info!(
+                            "[{}] vote re-requested at h/r/s {}, returning the previous signature",
+                            &self.config.chain_id, req_cs,
+                        );
+                        if !audited {
+                            // restored with the state: the audit may not have completed
+                            self.audit(self.vote_record(&req, &signature))?;
+                            self.state.mark_audited();
                         }
-                        Err(StateError(StateErrorDetail::DoubleSignError(_), _)) => {
-                            // Report double signing error back to the validator
-                            let original_block_id = self.state.consensus_state().block_id_prefix();
-
-                            error!(
-                                "[{}] attempted double sign at h/r/s: {} ({} != {})",
-                                &self.config.chain_id,
-                                req_cs,
-                                original_block_id,
-                                req_cs.block_id_prefix()
-                            );
-
-                            Response::double_sign(DoubleSignErrorType::Vote, req_cs.height.into())
-                        }
-                        Err(e) => {
-                            return Err(Error::signing_state_error("failed signing vote".into(), e))
+                        Response::vote_response(req, signature)
+                    } else if let Err(reason) = self.check_approval(
+                        &req.chain_id,
+                        vote_kind(&req.vote),
+                        req_cs,
+                        &signable_bytes,
+                    ) {
+                        Response::signing_refused(SignErrorType::Vote, &reason)
+                    } else {
+                        match self.persist_and_sign(req_cs, &signable_bytes) {
+                            Ok(None) => {
+                                info!(
+                                    "[{}] dry run: would have signed:{} at h/r/s {}",
+                                    &self.config.chain_id,
+                                    req_cs.block_id_prefix(),
+                                    req_cs,
+                                );
+                                Response::signing_refused(SignErrorType::Vote, DRY_RUN_REFUSAL)
+                            }
+                            Ok(Some((signature, signing_time))) => {
+                                info!(
+                                    chain_id = %self.config.chain_id,
+                                    height = req_cs.height.value(),
+                                    round = req_cs.round.value(),
+                                    step = req_cs.step,
+                                    msg_type = vote_kind(&req.vote).as_str(),
+                                    "[{}] signed:{} at h/r/s {} ({} ms)",
+                                    &self.config.chain_id,
+                                    req_cs.block_id_prefix(),
+                                    req_cs,
+                                    signing_time.as_millis(),
+                                );
+                                self.audit(self.vote_record(&req, &signature))?;
+                                // only returned for a re-request once it's in the audit log
+                                self.state.record_signature(signable_bytes, signature);
+                                self.record_metrics(MetricsEvent::Signed {
+                                    chain_id: req.chain_id.clone(),
+                                    msg_type: vote_kind(&req.vote),
+                                    height: req_cs.height.into(),
+                                    round: req_cs.round.value().into(),
+                                });
+                                Response::vote_response(req, signature)
+                            }
+                            Err(StateError(StateErrorDetail::DoubleSignError(_), _)) => {
+                                // Report double signing error back to the validator
+                                let original_block_id =
+                                    self.state.consensus_state().block_id_prefix();
+
+                                error!(
+                                    chain_id = %self.config.chain_id,
+                                    alert = true,
+                                    height = req_cs.height.value(),
+                                    round = req_cs.round.value(),
+                                    step = req_cs.step,
+                                    msg_type = vote_kind(&req.vote).as_str(),
+                                    "[{}] attempted double sign at h/r/s: {} ({} != {})",
+                                    &self.config.chain_id,
+                                    req_cs,
+                                    original_block_id,
+                                    req_cs.block_id_prefix()
+                                );
+
+                                Response::double_sign(
+                                    DoubleSignErrorType::Vote,
+                                    req_cs.height.into(),
+                                )
+                            }
+                            Err(e) => {
+                                return Err(Error::signing_state_error(
+                                    "failed signing vote".into(),
+                                    e,
+                                ))
+                            }
                         }
                     }
                 }
             }
             // non-signable requests:
-            Request::ReplyPing(_) => Response::Ping(PingResponse {}),
+            Request::ReplyPing(_) => {
+                self.record_metrics(MetricsEvent::Ping {
+                    chain_id: self.config.chain_id.clone(),
+                });
+                Response::Ping(PingResponse {})
+            }
             Request::ShowPublicKey(ref req) => {
                 if self.check_chain_id(&req.chain_id).is_err() {
                     Response::invalid_chain_id(ChainIdErrorType::Pubkey, &req.chain_id)
@@ -207,12 +945,44 @@ impl<S: PersistStateSync> Session<S> {
             "[{}] sending response: {:?}",
             &self.config.chain_id, &response
         );
+        if let (Some(mut msg), Some(reason)) = (refused, refusal_reason(&response)) {
+            msg.reason = reason.to_owned();
+            self.audit_refusal(msg);
+        }
 
-        let response_bytes = response.encode()?;
-        self.connection
-            .write_all(&response_bytes)
-            .map_err(|e| Error::io_error("write response failed".into(), e))?;
+        response.encode(&mut self.write_buf)?;
+        info_span!("emit").in_scope(|| {
+            self.connection
+                .write_all(&self.write_buf)
+                .map_err(|e| Error::io_error("write response failed".into(), e))
+        })?;
 
-        Ok(true)
+        match max_height_error {
+            Some(e) => Err(e),
+            None => Ok(true),
+        }
+    }
+}
+
+impl<S: PersistStateSync> Drop for Session<S> {
+    /// zeroizes the consensus key
+    fn drop(&mut self) {
+        self.signing_key.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maintenance_refuses_signing() {
+        assert_eq!(SessionStatus::Running.signing_refusal(), None);
+        assert_eq!(
+            SessionStatus::Maintenance.signing_refusal(),
+            Some("signer in maintenance")
+        );
+        let status: SessionStatus = serde_json::from_str(r#""maintenance""#).unwrap();
+        assert_eq!(status, SessionStatus::Maintenance);
     }
 }
@@ -0,0 +1,87 @@
+//! Listening sockets passed by systemd socket activation (`LISTEN_FDS`)
+
+use std::env;
+use std::net::TcpListener;
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::sync::Mutex;
+
+/// the first passed file descriptor (`SD_LISTEN_FDS_START`)
+const LISTEN_FDS_START: RawFd = 3;
+
+/// the passed sockets (by their `FileDescriptorName=`) not taken yet
+/// (`None` until the environment is read)
+static LISTEN_FDS: Mutex<Option<Vec<(String, RawFd)>>> = Mutex::new(None);
+
+/// a passed listening socket
+#[derive(Debug)]
+pub enum ActivatedListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// the passed sockets (if they are for this process)
+fn parse_listen_fds(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    names: Option<&str>,
+) -> Vec<(String, RawFd)> {
+    if pid.and_then(|pid| pid.parse::<u32>().ok()) != Some(std::process::id()) {
+        return vec![];
+    }
+    let count = fds.and_then(|fds| fds.parse::<RawFd>().ok()).unwrap_or(0);
+    let mut names = names.unwrap_or_default().split(':');
+    (0..count)
+        .map(|i| {
+            let name = names.next().unwrap_or("unknown").to_owned();
+            (name, LISTEN_FDS_START + i)
+        })
+        .collect()
+}
+
+/// takes the passed socket named `name` (with `FileDescriptorName=` in the socket unit);
+/// each socket can only be taken once
+pub fn take_listener(name: &str) -> Option<ActivatedListener> {
+    let mut listen_fds = LISTEN_FDS.lock().unwrap_or_else(|e| e.into_inner());
+    let fds = listen_fds.get_or_insert_with(|| {
+        let fds = parse_listen_fds(
+            env::var("LISTEN_PID").ok().as_deref(),
+            env::var("LISTEN_FDS").ok().as_deref(),
+            env::var("LISTEN_FDNAMES").ok().as_deref(),
+        );
+        // not inherited by the child processes (e.g. the enclave CLI)
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+        fds
+    });
+    let index = fds.iter().position(|(fd_name, _)| fd_name == name)?;
+    let (_, fd) = fds.remove(index);
+    // SAFETY: the descriptor was passed by the service manager for this process
+    // and it's only taken once
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    if listener.local_addr().is_ok() {
+        Some(ActivatedListener::Tcp(listener))
+    } else {
+        // SAFETY: as above (the descriptor isn't an IP socket)
+        Some(ActivatedListener::Unix(unsafe {
+            UnixListener::from_raw_fd(listener.into_raw_fd())
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_passed_sockets() {
+        let pid = std::process::id().to_string();
+        assert_eq!(
+            parse_listen_fds(Some(&pid), Some("2"), Some("privval:metrics")),
+            vec![("privval".to_owned(), 3), ("metrics".to_owned(), 4)]
+        );
+        assert!(parse_listen_fds(Some("1"), Some("2"), Some("privval:metrics")).is_empty());
+        assert!(parse_listen_fds(None, None, None).is_empty());
+    }
+}
@@ -1,5 +1,6 @@
 use std::io::{self, Read, Write};
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 use tracing::{debug, trace};
 
 use crate::error::Error;
@@ -52,38 +53,85 @@ pub fn print_pubkey(
     }
 }
 
+/// Limits on the received u16-sized payloads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PayloadLimits {
+    /// larger payloads are refused before allocating them
+    pub max_len: u16,
+    /// the time the rest of a payload can take to arrive once its length was read
+    /// (only enforced if the stream has a read timeout, which is then the polling interval)
+    pub partial_read_timeout: Option<Duration>,
+}
+
+impl Default for PayloadLimits {
+    fn default() -> Self {
+        Self {
+            max_len: u16::MAX,
+            partial_read_timeout: Some(Duration::from_secs(10)),
+        }
+    }
+}
+
 /// Read u16-size payload (for vsock)
 pub fn read_u16_payload<S: Read>(stream: &mut S) -> Result<Vec<u8>, Error> {
+    read_u16_payload_with_limits(stream, &PayloadLimits::default())
+}
+
+/// Read u16-size payload (for vsock) within the limits
+pub fn read_u16_payload_with_limits<S: Read>(
+    stream: &mut S,
+    limits: &PayloadLimits,
+) -> Result<Vec<u8>, Error> {
+    let mut payload = Vec::new();
+    read_u16_payload_into(stream, limits, &mut payload)?;
+    Ok(payload)
+}
+
+/// Read u16-size payload (for vsock) within the limits into the buffer
+/// (its allocation is reused, so that a long-lived buffer avoids per-payload allocations)
+pub fn read_u16_payload_into<S: Read>(
+    stream: &mut S,
+    limits: &PayloadLimits,
+    payload: &mut Vec<u8>,
+) -> Result<(), Error> {
+    payload.clear();
     let mut len_b = [0u8; 2];
     stream
         .read_exact(&mut len_b)
         .map_err(|e| Error::io_error("Error reading length".to_owned(), e))?;
 
-    let l = (u16::from_le_bytes(len_b)) as usize;
-    if l > 0 {
-        let mut state_raw = vec![0u8; l];
-        let mut total = 0;
-
-        while let Ok(n) = stream.read(&mut state_raw[total..]) {
-            total += n;
-            // no more data to read
-            if n == 0 || total >= l {
-                break;
+    let l = u16::from_le_bytes(len_b);
+    if l > limits.max_len {
+        return Err(Error::payload_too_large(l.into(), limits.max_len.into()));
+    }
+    if l == 0 {
+        trace!("read empty payload");
+        return Ok(());
+    }
+    let started = Instant::now();
+    payload.resize(l as usize, 0);
+    let mut total = 0;
+    while total < payload.len() {
+        match stream.read(&mut payload[total..]) {
+            Ok(0) => {
+                return Err(Error::io_error(
+                    "Truncated payload".to_owned(),
+                    io::Error::from(io::ErrorKind::UnexpectedEof),
+                ));
             }
+            Ok(n) => total += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) && limits
+                    .partial_read_timeout
+                    .map_or(false, |t| started.elapsed() < t) => {}
+            Err(e) => return Err(Error::io_error("Error reading payload".to_owned(), e)),
         }
-
-        if total == 0 {
-            return Err(Error::io_error(
-                "Zero length".to_owned(),
-                std::io::Error::from(std::io::ErrorKind::UnexpectedEof),
-            ));
-        }
-        state_raw.resize(total, 0);
-        Ok(state_raw)
-    } else {
-        trace!("read empty payload");
-        Ok(Vec::default())
     }
+    Ok(())
 }
 
 /// Write u16-sized payload (for vsock)
@@ -100,3 +148,37 @@ pub fn write_u16_payload<S: Write>(stream: &mut S, data: &[u8]) -> io::Result<()
     debug!("successfully wrote u16-sized payload");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn u16_payload_limits() {
+        let mut data = vec![];
+        write_u16_payload(&mut data, &[1u8; 100]).unwrap();
+        let limits = PayloadLimits {
+            max_len: 50,
+            partial_read_timeout: None,
+        };
+        assert!(read_u16_payload_with_limits(&mut &data[..], &limits).is_err());
+        assert_eq!(read_u16_payload(&mut &data[..]).unwrap(), vec![1u8; 100]);
+        // truncated
+        assert!(read_u16_payload(&mut &data[..50]).is_err());
+    }
+
+    #[test]
+    fn u16_payload_reuses_buffer() {
+        let mut data = vec![];
+        write_u16_payload(&mut data, &[1u8; 100]).unwrap();
+        write_u16_payload(&mut data, &[2u8; 10]).unwrap();
+        let mut reader = &data[..];
+        let mut buf = Vec::with_capacity(200);
+        let allocation = buf.as_ptr();
+        read_u16_payload_into(&mut reader, &PayloadLimits::default(), &mut buf).unwrap();
+        assert_eq!(buf, vec![1u8; 100]);
+        read_u16_payload_into(&mut reader, &PayloadLimits::default(), &mut buf).unwrap();
+        assert_eq!(buf, vec![2u8; 10]);
+        assert_eq!(buf.as_ptr(), allocation);
+    }
+}
@@ -0,0 +1,10 @@
+//! Vsock channels between a confidential VM guest (SEV, TDX) and its host helper
+
+pub mod proxy;
+pub mod state;
+
+/// CID of the host (from the guest)
+pub const VSOCK_HOST_CID: u32 = 2;
+
+/// for listening on the host
+const VMADDR_CID_ANY: u32 = 0xFFFFFFFF;
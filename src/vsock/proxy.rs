@@ -0,0 +1,89 @@
+//! The host's proxy of the guest's connections to the validator
+
+use nix::sys::select::{select, FdSet};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::thread;
+use std::time::Duration;
+use tendermint_config::net;
+use tracing::{error, info};
+use vsock::{VsockAddr, VsockListener};
+
+use super::VMADDR_CID_ANY;
+
+/// the validator-side socket
+trait Stream: Read + Write + AsRawFd {}
+
+impl Stream for UnixStream {}
+impl Stream for TcpStream {}
+
+fn connect(address: &net::Address) -> Result<Box<dyn Stream>, String> {
+    match address {
+        net::Address::Unix { path } => UnixStream::connect(path)
+            .map(|s| Box::new(s) as Box<dyn Stream>)
+            .map_err(|e| format!("could not connect to {}: {:?}", path, e)),
+        net::Address::Tcp { host, port, .. } => TcpStream::connect((host.as_str(), *port))
+            .map(|s| Box::new(s) as Box<dyn Stream>)
+            .map_err(|e| format!("could not connect to {}:{}: {:?}", host, port, e)),
+    }
+}
+
+/// forwards the guest's connections on the vsock port to the validator
+pub fn launch_proxy(vsock_port: u32, address: net::Address) -> Result<(), String> {
+    let listener = VsockListener::bind(&VsockAddr::new(VMADDR_CID_ANY, vsock_port))
+        .map_err(|e| format!("could not bind to vsock port {}: {:?}", vsock_port, e))?;
+    thread::spawn(move || loop {
+        let (mut client, client_addr) = match listener.accept() {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("connection failed {}", e);
+                thread::sleep(Duration::new(1, 0));
+                continue;
+            }
+        };
+        info!("accepted connection on {:?}", client_addr);
+        let mut server = match connect(&address) {
+            Ok(server) => server,
+            Err(e) => {
+                error!("{}", e);
+                thread::sleep(Duration::new(1, 0));
+                continue;
+            }
+        };
+        let client_socket = client.as_raw_fd();
+        let server_socket = server.as_raw_fd();
+        let mut disconnected = false;
+        while !disconnected {
+            let mut set = FdSet::new();
+            set.insert(client_socket);
+            set.insert(server_socket);
+            if select(None, Some(&mut set), None, None, None).is_err() {
+                break;
+            }
+            if set.contains(client_socket) {
+                disconnected = transfer(&mut client, &mut server);
+            }
+            if set.contains(server_socket) {
+                disconnected = disconnected || transfer(&mut server, &mut client);
+            }
+        }
+        info!("client on {:?} disconnected", client_addr);
+    });
+    Ok(())
+}
+
+/// Transfers a chunck of maximum 8KB from src to dst
+/// If no error occurs, returns true if the source disconnects and false otherwise
+fn transfer(src: &mut dyn Read, dst: &mut dyn Write) -> bool {
+    const BUFF_SIZE: usize = 8192;
+
+    let mut buffer = [0u8; BUFF_SIZE];
+
+    let nbytes = src.read(&mut buffer).unwrap_or(0);
+    if nbytes == 0 {
+        return true;
+    }
+    dst.write_all(&buffer[..nbytes]).is_err()
+}
@@ -0,0 +1,191 @@
+//! The state persisted on the host: the host's syncer and the guest's holder that uses it
+
+use crate::chain::state::{
+    consensus, MacedState, PersistStateSync, State, StateError, StateMacKey,
+};
+use crate::connection::ConnectionTimeouts;
+use crate::utils::{read_u16_payload, write_u16_payload};
+use ed25519_consensus::Signature;
+use std::fs;
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+use std::thread;
+use tempfile::NamedTempFile;
+use tracing::{debug, info, warn};
+use vsock::{VsockAddr, VsockListener, VsockStream};
+
+use super::{VMADDR_CID_ANY, VSOCK_HOST_CID};
+
+/// helps the guest to load the state previously persisted on the host
+/// + to persist new states (each acknowledged once it's written)
+/// (the states are authenticated with a MAC, so the host can't alter them)
+pub struct StateSyncer {
+    state_file_path: PathBuf,
+    listener: VsockListener,
+    state: MacedState,
+}
+
+impl StateSyncer {
+    /// loads the previous state from the file (or persists the initial one)
+    /// and binds a listener for the guest's state connections on the provided port
+    pub fn new<P: AsRef<Path>>(path: P, vsock_port: u32) -> Result<Self, StateError> {
+        let state_file_path = path.as_ref().to_owned();
+        let state = match fs::read_to_string(&state_file_path) {
+            Ok(state_json) => serde_json::from_str(&state_json).map_err(|e| {
+                StateError::sync_enc_dec_error(state_file_path.display().to_string(), e)
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let state = MacedState::from(consensus::State {
+                    height: 0u32.into(),
+                    ..Default::default()
+                });
+                persist(&state_file_path, &state)?;
+                state
+            }
+            Err(e) => {
+                return Err(StateError::sync_error(
+                    state_file_path.display().to_string(),
+                    e,
+                ))
+            }
+        };
+        let listener = VsockListener::bind(&VsockAddr::new(VMADDR_CID_ANY, vsock_port))
+            .map_err(|e| StateError::sync_error("vsock".into(), e))?;
+        Ok(Self {
+            state_file_path,
+            listener,
+            state,
+        })
+    }
+
+    fn sync(&mut self, stream: &mut VsockStream) -> Result<(), String> {
+        let json_raw = serde_json::to_vec(&self.state).map_err(|e| e.to_string())?;
+        write_u16_payload(stream, &json_raw).map_err(|e| e.to_string())?;
+        loop {
+            let json_raw = read_u16_payload(stream).map_err(|e| e.to_string())?;
+            let state: MacedState = serde_json::from_slice(&json_raw).map_err(|e| e.to_string())?;
+            let persisted = persist(&self.state_file_path, &state).map_err(|e| e.to_string());
+            if persisted.is_ok() {
+                self.state = state;
+            }
+            let ack_raw = serde_json::to_vec(&persisted).map_err(|e| e.to_string())?;
+            write_u16_payload(stream, &ack_raw).map_err(|e| e.to_string())?;
+        }
+    }
+
+    /// keeps serving the guest's state connections
+    pub fn launch_syncer(mut self) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            info!("listening for guest persistence");
+            loop {
+                match self.listener.accept() {
+                    Ok((mut stream, _)) => {
+                        info!("persistence connection established");
+                        if let Err(e) = self.sync(&mut stream) {
+                            warn!("persistence connection lost: {}", e);
+                        }
+                    }
+                    Err(e) => warn!("persistence connection failed: {}", e),
+                }
+            }
+        })
+    }
+}
+
+/// writes the state to the file (atomically)
+fn persist(path: &Path, state: &MacedState) -> Result<(), StateError> {
+    let json = serde_json::to_string(state)
+        .map_err(|e| StateError::sync_enc_dec_error(path.display().to_string(), e))?;
+    let state_file_dir = path.parent().unwrap_or_else(|| {
+        panic!("state file cannot be root directory");
+    });
+    let mut state_file = NamedTempFile::new_in(state_file_dir)
+        .map_err(|e| StateError::sync_error(path.display().to_string(), e))?;
+    state_file
+        .write_all(json.as_bytes())
+        .map_err(|e| StateError::sync_error(path.display().to_string(), e))?;
+    state_file
+        .persist(path)
+        .map_err(|e| StateError::sync_error(path.display().to_string(), e.error))?;
+    Ok(())
+}
+
+/// connects to the host via the provided vsock port
+pub fn host_connection(vsock_port: u32, timeouts: &ConnectionTimeouts) -> io::Result<VsockStream> {
+    let addr = VsockAddr::new(VSOCK_HOST_CID, vsock_port);
+    let conn = vsock::VsockStream::connect(&addr)?;
+    conn.set_read_timeout(timeouts.read())?;
+    conn.set_write_timeout(timeouts.write())?;
+    Ok(conn)
+}
+
+/// as the state is persisted on the host, this is a helper that communicates with it
+/// to load the latest state on the start up + to update it after each signing
+/// (the states are authenticated with a MAC, so the host can't alter them)
+pub struct StateHolder {
+    state_conn: VsockStream,
+    mac_key: StateMacKey,
+}
+
+impl StateHolder {
+    /// connects to the host via the vsock port specified in the configuration
+    pub fn new(
+        vsock_port: u32,
+        timeouts: &ConnectionTimeouts,
+        mac_key: StateMacKey,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            state_conn: host_connection(vsock_port, timeouts)?,
+            mac_key,
+        })
+    }
+}
+
+impl PersistStateSync for StateHolder {
+    /// loads the initial state (and checks its MAC)
+    fn load_state(&mut self) -> Result<State, StateError> {
+        let json_raw = read_u16_payload(&mut self.state_conn)
+            .map_err(|e| StateError::sync_other_error(e.to_string()))?;
+        let maced_state: MacedState = serde_json::from_slice(&json_raw)
+            .map_err(|e| StateError::sync_enc_dec_error("vsock".into(), e))?;
+        self.mac_key.verify(&maced_state)?;
+        Ok(State::from(maced_state))
+    }
+
+    /// sends the update state to be persisted on the host
+    fn persist_state(&mut self, new_state: &consensus::State) -> Result<(), StateError> {
+        let maced_state = self.mac_key.sign(new_state)?;
+        self.send_state(&maced_state)
+    }
+
+    /// sends the update state with the signed message to be persisted on the host
+    fn persist_signed_state(
+        &mut self,
+        new_state: &consensus::State,
+        sign_bytes: &[u8],
+        signature: &Signature,
+    ) -> Result<(), StateError> {
+        let maced_state = self
+            .mac_key
+            .sign(new_state)?
+            .with_message(sign_bytes, signature);
+        self.send_state(&maced_state)
+    }
+}
+
+impl StateHolder {
+    fn send_state(&mut self, maced_state: &MacedState) -> Result<(), StateError> {
+        let json_raw = serde_json::to_vec(maced_state)
+            .map_err(|e| StateError::sync_enc_dec_error("vsock".into(), e))?;
+        write_u16_payload(&mut self.state_conn, &json_raw)
+            .map_err(|e| StateError::sync_error("vsock".into(), e))?;
+        // the state only counts as persisted once the host acknowledges it
+        let ack_raw = read_u16_payload(&mut self.state_conn)
+            .map_err(|e| StateError::sync_other_error(e.to_string()))?;
+        let ack: Result<(), String> = serde_json::from_slice(&ack_raw)
+            .map_err(|e| StateError::sync_enc_dec_error("vsock".into(), e))?;
+        ack.map_err(StateError::sync_other_error)?;
+        debug!("successfully wrote new consensus state to state connection");
+        Ok(())
+    }
+}